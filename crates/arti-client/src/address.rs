@@ -187,6 +187,42 @@ impl TorAddr {
     }
 }
 
+/// The longest hostname that we'll ask an exit to resolve.
+///
+/// (This is the longest name that DNS can represent.)
+const MAX_RESOLVE_HOSTNAME_LEN: usize = 255;
+
+/// Give an error if `hostname` isn't something that we should ask an exit to
+/// resolve, given the rules in `cfg`.
+///
+/// We reject names that are empty, too long, or contain characters (like
+/// NUL) that can't appear in a hostname, and `.onion` names, which have no
+/// addresses to resolve to.
+///
+/// If `hostname` is already an IP address, return it, since there's nothing
+/// to resolve.
+pub(crate) fn check_resolve_target(
+    hostname: &str,
+    cfg: &crate::config::ClientAddrConfig,
+) -> Result<Option<IpAddr>, ErrorDetail> {
+    let host: Host = hostname.parse()?;
+    match host {
+        Host::Ip(ip) => Ok(Some(ip)),
+        Host::Hostname(ref name) => {
+            if name.len() > MAX_RESOLVE_HOSTNAME_LEN || !is_valid_hostname(name) {
+                return Err(ErrorDetail::InvalidHostname);
+            }
+            if name.to_lowercase().ends_with(".onion") {
+                return Err(ErrorDetail::OnionAddressResolveRequest);
+            }
+            if !cfg.allow_local_addrs && host.is_local() {
+                return Err(ErrorDetail::LocalAddress);
+            }
+            Ok(None)
+        }
+    }
+}
+
 impl std::fmt::Display for TorAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.host {
@@ -389,6 +425,34 @@ mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
 
+    #[test]
+    fn resolve_targets() {
+        let cfg = crate::config::ClientAddrConfig::default();
+        let check = |s: &str| check_resolve_target(s, &cfg);
+
+        assert!(matches!(check("www.torproject.org"), Ok(None)));
+        assert!(matches!(check("192.0.2.7"), Ok(Some(_))));
+
+        assert!(matches!(check(""), Err(ErrorDetail::InvalidHostname)));
+        assert!(matches!(
+            check("www.torproject\0.org"),
+            Err(ErrorDetail::InvalidHostname)
+        ));
+        let long = format!("{}.com", "a".repeat(252));
+        assert!(matches!(check(&long[..255]), Ok(None)));
+        assert!(matches!(check(&long), Err(ErrorDetail::InvalidHostname)));
+
+        assert!(matches!(
+            check("eweiibe6tdjsdprb4px6rqrzzcsi22m4koia44kc5pcjr7nec2rlxyad.onion"),
+            Err(ErrorDetail::OnionAddressResolveRequest)
+        ));
+        assert!(matches!(
+            check("www.example.ONION"),
+            Err(ErrorDetail::OnionAddressResolveRequest)
+        ));
+        assert!(matches!(check("localhost"), Err(ErrorDetail::LocalAddress)));
+    }
+
     #[test]
     fn validate_hostname() {
         // Valid hostname tests
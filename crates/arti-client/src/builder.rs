@@ -4,6 +4,7 @@
 
 use crate::{err::ErrorDetail, BootstrapBehavior, Result, TorClient, TorClientConfig};
 use std::sync::Arc;
+use std::time::Duration;
 use tor_dirmgr::DirMgrConfig;
 use tor_rtcompat::Runtime;
 
@@ -38,6 +39,27 @@ impl<R: Runtime> DirProviderBuilder<R> for DirMgrBuilder {
     }
 }
 
+/// A DirProviderBuilder that constructs an offline DirMgr, which only ever
+/// loads the directory from the cache.
+#[derive(Clone, Debug)]
+struct OfflineDirMgrBuilder {
+    /// How long past its expiration we will use a directory from the cache.
+    max_staleness: Duration,
+}
+
+impl<R: Runtime> DirProviderBuilder<R> for OfflineDirMgrBuilder {
+    fn build(
+        &self,
+        runtime: R,
+        _circmgr: Arc<tor_circmgr::CircMgr<R>>,
+        config: DirMgrConfig,
+    ) -> Result<Arc<dyn tor_dirmgr::DirProvider + Send + Sync + 'static>> {
+        let dirmgr = tor_dirmgr::DirMgr::create_offline(config, runtime, self.max_staleness)
+            .map_err(ErrorDetail::from)?;
+        Ok(Arc::new(dirmgr))
+    }
+}
+
 /// An object for constructing a [`TorClient`].
 ///
 /// Returned by [`TorClient::builder()`].
@@ -114,12 +136,24 @@ impl<R: Runtime> TorClientBuilder<R> {
     /// This option is useful if you wish to have control over the bootstrap
     /// process (for example, you might wish to avoid initiating network
     /// connections until explicit user confirmation is given).
+    ///
+    /// With [`BootstrapBehavior::Offline`], the client never fetches
+    /// directory information from the network, and ignores any
+    /// directory provider set with `dirmgr_builder`.
     pub fn create_unbootstrapped(self) -> Result<TorClient<R>> {
+        let offline_builder;
+        let dirmgr_builder: &dyn DirProviderBuilder<R> = match self.bootstrap_behavior {
+            BootstrapBehavior::Offline { max_staleness } => {
+                offline_builder = OfflineDirMgrBuilder { max_staleness };
+                &offline_builder
+            }
+            _ => self.dirmgr_builder.as_ref(),
+        };
         TorClient::create_inner(
             self.runtime,
             self.config,
             self.bootstrap_behavior,
-            self.dirmgr_builder.as_ref(),
+            dirmgr_builder,
         )
         .map_err(ErrorDetail::into)
     }
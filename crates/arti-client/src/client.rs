@@ -7,9 +7,10 @@
 use crate::address::IntoTorAddr;
 
 use crate::config::{ClientAddrConfig, StreamTimeoutConfig, TorClientConfig};
-use tor_circmgr::{DirInfo, IsolationToken, StreamIsolationBuilder, TargetPort};
+use tor_circmgr::{DirInfo, IsolationToken, StreamIsolation, StreamIsolationBuilder, TargetPort};
 use tor_config::MutCfg;
 use tor_dirmgr::DirEvent;
+use tor_error::HasKind;
 use tor_persist::{FsStateMgr, StateMgr};
 use tor_proto::circuit::ClientCirc;
 use tor_proto::stream::{DataStream, IpVersionPreference, StreamParameters};
@@ -25,7 +26,8 @@ use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
 use crate::err::ErrorDetail;
-use crate::{status, util, TorClientBuilder};
+use crate::events::{EventHub, TorEvent, TorEvents};
+use crate::{events, status, util, TorClientBuilder};
 use tracing::{debug, error, info, warn};
 
 /// An active client session on the Tor network.
@@ -74,6 +76,10 @@ pub struct TorClient<R: Runtime> {
     /// unobserved status change when the next status change occurs.)
     status_receiver: status::BootstrapEvents,
 
+    /// Hub that publishes [`TorEvent`]s to everybody who has called
+    /// [`TorClient::events`].
+    events: Arc<EventHub>,
+
     /// mutex used to prevent two tasks from trying to bootstrap at once.
     bootstrap_in_progress: Arc<AsyncMutex<()>>,
 
@@ -97,6 +103,26 @@ pub enum BootstrapBehavior {
     /// network) before calling [`bootstrap`](TorClient::bootstrap) will fail, and
     /// return an error that has kind [`ErrorKind::BootstrapRequired`](crate::ErrorKind::BootstrapRequired).
     Manual,
+    /// Never fetch directory information from the network: bootstrap the client from whatever
+    /// directory is in the cache, and never replace it.
+    ///
+    /// The cached directory is accepted even if its consensus expired up to `max_staleness`
+    /// ago.  If there is no complete directory in the cache, creating or bootstrapping the
+    /// client fails with an error of kind
+    /// [`ErrorKind::NoCachedDirectory`](crate::ErrorKind::NoCachedDirectory); if the cached
+    /// directory is staler than that, the error has kind
+    /// [`ErrorKind::DirectoryExpired`](crate::ErrorKind::DirectoryExpired).
+    ///
+    /// Circuits are still built over the network as usual, using the cached directory.  As with
+    /// [`OnDemand`](BootstrapBehavior::OnDemand), the client bootstraps itself when it is
+    /// first used.
+    Offline {
+        /// How long past its expiration we will use a directory from the cache.
+        ///
+        /// There is no default value: callers must decide how stale a directory they are
+        /// willing to trust.
+        max_staleness: Duration,
+    },
 }
 
 impl Default for BootstrapBehavior {
@@ -114,6 +140,16 @@ pub struct StreamPrefs {
     isolation: StreamIsolationPreference,
     /// Whether to return the stream optimistically.
     optimistic_stream: bool,
+    /// Whether the stream needs a circuit made of Stable relays.
+    long_lived: bool,
+    /// If present, how long a read on the stream may wait for data.
+    read_timeout: Option<Duration>,
+    /// If present, how long to wait for the exit to open the stream,
+    /// instead of our configured `connect_timeout`.
+    connect_timeout: Option<Duration>,
+    /// How eagerly to send this stream's data, compared to other streams on
+    /// the same circuit.
+    priority: u8,
 }
 
 /// Record of how we are isolating connections
@@ -195,6 +231,60 @@ impl StreamPrefs {
         self
     }
 
+    /// Indicate whether connections with these preferences are expected
+    /// to stay open for a long time.
+    ///
+    /// Long-lived connections (like SSH or IMAP sessions) are only put on
+    /// circuits whose relays all have the Stable flag, so that they are
+    /// less likely to be interrupted when a relay goes down.  By default,
+    /// connections are not treated as long-lived.
+    pub fn long_lived(&mut self, long_lived: bool) -> &mut Self {
+        self.long_lived = long_lived;
+        self
+    }
+
+    /// Make reads on streams opened with these preferences fail if they
+    /// wait longer than `timeout` for data.
+    ///
+    /// A read that times out returns an error of kind
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut), and the stream stays
+    /// usable: any data that arrives later is returned by the next read.
+    /// See [`DataStream::set_read_timeout`] for details.
+    ///
+    /// By default, there is no read timeout.
+    pub fn read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Wait at most `timeout` for the exit relay to open streams made with
+    /// these preferences.
+    ///
+    /// This overrides the `connect_timeout` in our [stream timeout
+    /// configuration](crate::config::StreamTimeoutConfig) for these streams
+    /// only.  If the exit hasn't answered in time, the connection attempt
+    /// fails with an error of kind
+    /// [`RemoteNetworkTimeout`](crate::ErrorKind::RemoteNetworkTimeout).
+    ///
+    /// By default, we use the configured timeout.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give streams opened with these preferences a scheduling priority.
+    ///
+    /// When several streams share a circuit, data from streams with a
+    /// higher priority is sent first, and gets a bigger share of the
+    /// circuit's bandwidth, though every stream still gets a turn.  See
+    /// [`StreamParameters::priority`] for details.
+    ///
+    /// The default priority is 0, the lowest.
+    pub fn priority(&mut self, priority: u8) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
     /// Return a TargetPort to describe what kind of exit policy our
     /// target circuit needs to support.
     fn wrap_target_port(&self, port: u16) -> TargetPort {
@@ -209,7 +299,8 @@ impl StreamPrefs {
         let mut params = StreamParameters::default();
         params
             .ip_version(self.ip_ver_pref)
-            .optimistic(self.optimistic_stream);
+            .optimistic(self.optimistic_stream)
+            .priority(self.priority);
         params
     }
 
@@ -341,15 +432,20 @@ impl<R: Runtime> TorClient<R> {
     ) -> StdResult<Self, ErrorDetail> {
         let circ_cfg = config.get_circmgr_config()?;
         let dir_cfg = config.get_dirmgr_config()?;
-        let statemgr = FsStateMgr::from_path(config.storage.expand_state_dir()?)?;
+        let statemgr = if config.storage.journal_state() {
+            FsStateMgr::from_path_with_journal(config.storage.expand_state_dir()?)?
+        } else {
+            FsStateMgr::from_path(config.storage.expand_state_dir()?)?
+        };
         let addr_cfg = config.address_filter.clone();
-        let timeout_cfg = config.stream_timeouts;
 
         let (status_sender, status_receiver) = postage::watch::channel();
         let status_receiver = status::BootstrapEvents {
             inner: status_receiver,
         };
         let chanmgr = Arc::new(tor_chanmgr::ChanMgr::new(runtime.clone()));
+        configure_transports(&runtime, &chanmgr, &config)?;
+        let timeout_cfg = config.stream_timeouts;
         let circmgr =
             tor_circmgr::CircMgr::new(circ_cfg, statemgr.clone(), &runtime, Arc::clone(&chanmgr))
                 .map_err(ErrorDetail::CircMgrSetup)?;
@@ -360,14 +456,28 @@ impl<R: Runtime> TorClient<R> {
 
         let conn_status = chanmgr.bootstrap_events();
         let dir_status = dirmgr.bootstrap_events();
+        let circ_status = circmgr.bootstrap_events();
         runtime
             .spawn(status::report_status(
                 status_sender,
                 conn_status,
                 dir_status,
+                circ_status,
             ))
             .map_err(|e| ErrorDetail::from_spawn("top-level status reporter", e))?;
 
+        let events = Arc::new(EventHub::new());
+        runtime
+            .spawn(events::publish_events(
+                runtime.clone(),
+                Arc::downgrade(&events),
+                status_receiver.clone(),
+                dirmgr.events(),
+                dirmgr.download_errors(),
+                circmgr.circ_events(),
+            ))
+            .map_err(|e| ErrorDetail::from_spawn("event publisher", e))?;
+
         runtime
             .spawn(continually_expire_channels(
                 runtime.clone(),
@@ -422,6 +532,7 @@ impl<R: Runtime> TorClient<R> {
             timeoutcfg: Arc::new(timeout_cfg.into()),
             reconfigure_lock: Arc::new(Mutex::new(())),
             status_receiver,
+            events,
             bootstrap_in_progress: Arc::new(AsyncMutex::new(())),
             should_bootstrap: autobootstrap,
         })
@@ -489,13 +600,18 @@ impl<R: Runtime> TorClient<R> {
     /// Initiate a bootstrap by calling `bootstrap` (which is idempotent, so attempts to
     /// bootstrap twice will just do nothing).
     ///
+    /// ## For `BootstrapBehavior::Offline` clients
+    ///
+    /// As for `BootstrapBehavior::Ondemand` clients: bootstrapping only loads the directory
+    /// from the cache.
+    ///
     /// ## For `BootstrapBehavior::Manual` clients
     ///
     /// Check whether a bootstrap is in progress; if one is, wait until it finishes
     /// and then return. (Otherwise, return immediately.)
     async fn wait_for_bootstrap(&self) -> StdResult<(), ErrorDetail> {
         match self.should_bootstrap {
-            BootstrapBehavior::OnDemand => {
+            BootstrapBehavior::OnDemand | BootstrapBehavior::Offline { .. } => {
                 self.bootstrap_inner().await?;
             }
             BootstrapBehavior::Manual => {
@@ -557,6 +673,10 @@ impl<R: Runtime> TorClient<R> {
         if state_cfg != self.statemgr.path() {
             how.cannot_change("storage.state_dir").map_err(wrap_err)?;
         }
+        if new_config.storage.journal_state() != self.statemgr.has_journal() {
+            how.cannot_change("storage.journal_state")
+                .map_err(wrap_err)?;
+        }
 
         self.circmgr.reconfigure(&circ_cfg, how).map_err(wrap_err)?;
         self.dirmgr.reconfigure(&dir_cfg, how).map_err(wrap_err)?;
@@ -586,6 +706,13 @@ impl<R: Runtime> TorClient<R> {
     ///
     /// (Connections made with clones of the returned `TorClient` may
     /// share circuits with each other.)
+    ///
+    /// This isolation compounds with any isolation group set in a
+    /// connection's [`StreamPrefs`]: two connections can share a circuit
+    /// only if they come from the same isolated client _and_ have the same
+    /// isolation group.  Passing the same [`IsolationToken`] to connections
+    /// made with two different isolated clients will not let those
+    /// connections share a circuit.
     #[must_use]
     pub fn isolated_client(&self) -> TorClient<R> {
         let mut result = self.clone();
@@ -670,6 +797,20 @@ impl<R: Runtime> TorClient<R> {
         &self,
         target: A,
         prefs: &StreamPrefs,
+    ) -> crate::Result<DataStream> {
+        let result = self.connect_impl(target, prefs).await;
+        if let Err(e) = &result {
+            self.events
+                .publish(&TorEvent::StreamFailed(e.kind()), self.runtime.now());
+        }
+        result
+    }
+
+    /// Implementation for [`connect_with_prefs`](TorClient::connect_with_prefs).
+    async fn connect_impl<A: IntoTorAddr>(
+        &self,
+        target: A,
+        prefs: &StreamPrefs,
     ) -> crate::Result<DataStream> {
         let addr = target.into_tor_addr().map_err(wrap_err)?;
         addr.enforce_config(&self.addrcfg.get())?;
@@ -683,14 +824,22 @@ impl<R: Runtime> TorClient<R> {
         info!("Got a circuit for {}:{}", addr, port);
 
         let stream_future = circ.begin_stream(&addr, port, Some(prefs.stream_parameters()));
+        let connect_timeout = prefs
+            .connect_timeout
+            .unwrap_or_else(|| self.timeoutcfg.get().connect_timeout);
         // This timeout is needless but harmless for optimistic streams.
-        let stream = self
+        let mut stream = self
             .runtime
-            .timeout(self.timeoutcfg.get().connect_timeout, stream_future)
+            .timeout(connect_timeout, stream_future)
             .await
             .map_err(|_| ErrorDetail::ExitTimeout)?
             .map_err(wrap_err)?;
 
+        if let Some(timeout) = prefs.read_timeout {
+            let runtime = self.runtime.clone();
+            stream.set_read_timeout(timeout, move |d| runtime.sleep(d));
+        }
+
         Ok(stream)
     }
 
@@ -720,31 +869,67 @@ impl<R: Runtime> TorClient<R> {
         result
     }
 
+    /// Perform a remote DNS lookup of `hostname`, using a circuit chosen
+    /// according to this client's stream preferences.
+    ///
     /// On success, return a list of IP addresses.
+    ///
+    /// Names that are empty, longer than 255 bytes, or not valid hostnames
+    /// are rejected without using the network, as are `.onion` addresses,
+    /// since those don't resolve to anything.  If `hostname` is already an
+    /// IP address, it is returned as-is.
     pub async fn resolve(&self, hostname: &str) -> crate::Result<Vec<IpAddr>> {
         self.resolve_with_prefs(hostname, &self.connect_prefs).await
     }
 
-    /// On success, return a list of IP addresses, but use prefs.
+    /// Perform a remote DNS lookup of `hostname`, using a circuit chosen
+    /// according to `prefs`.
+    ///
+    /// On success, return a list of IP addresses.  See
+    /// [`resolve`](TorClient::resolve) for the names that are rejected locally.
     pub async fn resolve_with_prefs(
         &self,
         hostname: &str,
         prefs: &StreamPrefs,
     ) -> crate::Result<Vec<IpAddr>> {
-        let addr = (hostname, 0).into_tor_addr().map_err(wrap_err)?;
-        addr.enforce_config(&self.addrcfg.get()).map_err(wrap_err)?;
+        let answers = self.resolve_impl(hostname, prefs).await?;
+        Ok(answers.into_iter().map(|(ip, _)| ip).collect())
+    }
+
+    /// Perform a remote DNS lookup of `hostname`, as with
+    /// [`resolve`](TorClient::resolve), but also return the TTL (in seconds)
+    /// that the exit reported for each address.
+    ///
+    /// If `hostname` is already an IP address, it is returned with a TTL of
+    /// `u32::MAX`.
+    pub async fn resolve_with_ttl(&self, hostname: &str) -> crate::Result<Vec<(IpAddr, u32)>> {
+        self.resolve_impl(hostname, &self.connect_prefs).await
+    }
+
+    /// Helper: Perform a remote DNS lookup of `hostname` on a circuit chosen
+    /// according to `prefs`, and return the addresses with their TTLs.
+    async fn resolve_impl(
+        &self,
+        hostname: &str,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<Vec<(IpAddr, u32)>> {
+        if let Some(ip) =
+            crate::address::check_resolve_target(hostname, &self.addrcfg.get()).map_err(wrap_err)?
+        {
+            return Ok(vec![(ip, u32::MAX)]);
+        }
 
         let circ = self.get_or_launch_exit_circ(&[], prefs).await?;
 
-        let resolve_future = circ.resolve(hostname);
-        let addrs = self
+        let resolve_future = circ.resolve_with_ttl(hostname);
+        let answers = self
             .runtime
             .timeout(self.timeoutcfg.get().resolve_timeout, resolve_future)
             .await
             .map_err(|_| ErrorDetail::ExitTimeout)?
             .map_err(wrap_err)?;
 
-        Ok(addrs)
+        Ok(answers)
     }
 
     /// Perform a remote DNS reverse lookup with the provided IP address.
@@ -754,7 +939,8 @@ impl<R: Runtime> TorClient<R> {
         self.resolve_ptr_with_prefs(addr, &self.connect_prefs).await
     }
 
-    /// Perform a remote DNS reverse lookup with the provided IP address.
+    /// Perform a remote DNS reverse lookup with the provided IP address,
+    /// using a circuit chosen according to `prefs`.
     ///
     /// On success, return a list of hostnames.
     pub async fn resolve_ptr_with_prefs(
@@ -762,10 +948,28 @@ impl<R: Runtime> TorClient<R> {
         addr: IpAddr,
         prefs: &StreamPrefs,
     ) -> crate::Result<Vec<String>> {
+        let answers = self.resolve_ptr_impl(addr, prefs).await?;
+        Ok(answers.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Perform a remote DNS reverse lookup, as with
+    /// [`resolve_ptr`](TorClient::resolve_ptr), but also return the TTL (in
+    /// seconds) that the exit reported for each hostname.
+    pub async fn resolve_ptr_with_ttl(&self, addr: IpAddr) -> crate::Result<Vec<(String, u32)>> {
+        self.resolve_ptr_impl(addr, &self.connect_prefs).await
+    }
+
+    /// Helper: Perform a remote DNS reverse lookup of `addr` on a circuit
+    /// chosen according to `prefs`, and return the hostnames with their TTLs.
+    async fn resolve_ptr_impl(
+        &self,
+        addr: IpAddr,
+        prefs: &StreamPrefs,
+    ) -> crate::Result<Vec<(String, u32)>> {
         let circ = self.get_or_launch_exit_circ(&[], prefs).await?;
 
-        let resolve_ptr_future = circ.resolve_ptr(addr);
-        let hostnames = self
+        let resolve_ptr_future = circ.resolve_ptr_with_ttl(addr);
+        let answers = self
             .runtime
             .timeout(
                 self.timeoutcfg.get().resolve_ptr_timeout,
@@ -775,7 +979,7 @@ impl<R: Runtime> TorClient<R> {
             .map_err(|_| ErrorDetail::ExitTimeout)?
             .map_err(wrap_err)?;
 
-        Ok(hostnames)
+        Ok(answers)
     }
 
     /// Return a reference to this this client's directory manager.
@@ -807,6 +1011,20 @@ impl<R: Runtime> TorClient<R> {
         &self.runtime
     }
 
+    /// Return the isolation to use for a stream made through this client
+    /// with the given preferences.
+    fn stream_isolation(&self, prefs: &StreamPrefs) -> StreamIsolation {
+        let mut b = StreamIsolationBuilder::new();
+        // Always consider our client_isolation.
+        b.owner_token(self.client_isolation);
+        // Consider stream isolation too, if it's set.
+        if let Some(tok) = prefs.isolation_group() {
+            b.stream_token(tok);
+        }
+        // Failure should be impossible with this builder.
+        b.build().expect("Failed to construct StreamIsolation")
+    }
+
     /// Get or launch an exit-suitable circuit with a given set of
     /// exit ports.
     async fn get_or_launch_exit_circ(
@@ -822,21 +1040,11 @@ impl<R: Runtime> TorClient<R> {
                 action: "launch a circuit",
             })?;
 
-        let isolation = {
-            let mut b = StreamIsolationBuilder::new();
-            // Always consider our client_isolation.
-            b.owner_token(self.client_isolation);
-            // Consider stream isolation too, if it's set.
-            if let Some(tok) = prefs.isolation_group() {
-                b.stream_token(tok);
-            }
-            // Failure should be impossible with this builder.
-            b.build().expect("Failed to construct StreamIsolation")
-        };
+        let isolation = self.stream_isolation(prefs);
 
         let circ = self
             .circmgr
-            .get_or_launch_exit(dir.as_ref().into(), exit_ports, isolation)
+            .get_or_launch_exit(dir.as_ref().into(), exit_ports, isolation, prefs.long_lived)
             .await
             .map_err(|cause| ErrorDetail::ObtainExitCircuit {
                 cause,
@@ -863,6 +1071,17 @@ impl<R: Runtime> TorClient<R> {
     pub fn bootstrap_events(&self) -> status::BootstrapEvents {
         self.status_receiver.clone()
     }
+
+    /// Return a stream of [`TorEvent`]s describing bootstrap progress,
+    /// circuits, failed streams, and more.
+    ///
+    /// The stream only yields events that happen after this call.  It never
+    /// slows down the client: if the caller doesn't keep up, or if too many
+    /// events of one kind happen at once, some events are dropped.  Use
+    /// [`TorEvents::dropped`] to see how many.
+    pub fn events(&self) -> TorEvents {
+        self.events.subscribe()
+    }
 }
 
 /// Alias for TorError::from(Error)
@@ -873,6 +1092,50 @@ where
     ErrorDetail::from(err).into()
 }
 
+/// Tell `chanmgr` about the pluggable transports in `config`, and make sure
+/// that every bridge that names a transport is reached through it.
+///
+/// The transport binaries aren't launched until a bridge needs them.
+fn configure_transports<R: Runtime>(
+    runtime: &R,
+    chanmgr: &tor_chanmgr::ChanMgr<R>,
+    config: &TorClientConfig,
+) -> StdResult<(), ErrorDetail> {
+    use tor_chanmgr::transport::{ManagedTransport, PtLaunchInfo, PtTarget};
+
+    let state_dir = config.storage.expand_state_dir()?.join("pt_state");
+    for transport in &config.transports {
+        let path = transport
+            .path
+            .path()
+            .map_err(|e| tor_config::ConfigBuildError::Invalid {
+                field: "transports.path".to_owned(),
+                problem: e.to_string(),
+            })?;
+        let info = PtLaunchInfo::new(
+            path,
+            transport.arguments.clone(),
+            transport.protocols.clone(),
+            state_dir.clone(),
+        );
+        let helper = Arc::new(ManagedTransport::new(runtime.clone(), info));
+        for name in &transport.protocols {
+            chanmgr.register_transport(name, helper.clone());
+        }
+    }
+
+    for bridge in config.path_rules.bridges() {
+        if let Some(name) = bridge.transport() {
+            let params = bridge
+                .params()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            chanmgr.add_transport_route(PtTarget::new(name.to_owned(), *bridge.addr(), params));
+        }
+    }
+    Ok(())
+}
+
 /// Whenever a [`DirEvent::NewConsensus`] arrives on `events`, update
 /// `circmgr` with the consensus parameters from `dirmgr`.
 ///
@@ -1086,6 +1349,91 @@ mod test {
         });
     }
 
+    #[test]
+    fn offline_client_needs_cache() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let result = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Offline {
+                    max_staleness: Duration::from_secs(3600),
+                })
+                .create_unbootstrapped();
+            assert!(result.is_err());
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::NoCachedDirectory);
+        });
+    }
+
+    #[test]
+    fn resolve_rejects_locally() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+
+            // These all fail before we need a circuit.
+            for bad in &["", "nul\0.example.com", "example.onion"] {
+                let result = client.resolve(bad).await;
+                assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidStreamTarget);
+            }
+            // An IP address needs no resolving.
+            let ip: IpAddr = "192.0.2.7".parse().unwrap();
+            assert_eq!(client.resolve("192.0.2.7").await.unwrap(), vec![ip]);
+            // But a real name needs a circuit.
+            let result = client.resolve("www.torproject.org").await;
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::BootstrapRequired);
+        });
+    }
+
+    #[test]
+    fn isolated_client_compounds() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let state_dir = tempfile::tempdir().unwrap();
+            let cache_dir = tempfile::tempdir().unwrap();
+            let cfg = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
+                .build()
+                .unwrap();
+            let client = TorClient::with_runtime(rt)
+                .config(cfg)
+                .bootstrap_behavior(BootstrapBehavior::Manual)
+                .create_unbootstrapped()
+                .unwrap();
+            let clone = client.clone();
+            let isolated = client.isolated_client();
+            let isolated_clone = isolated.clone();
+
+            let default_prefs = StreamPrefs::new();
+            let mut group_prefs = StreamPrefs::new();
+            group_prefs.new_isolation_group();
+            let iso = |c: &TorClient<_>, p: &StreamPrefs| c.stream_isolation(p);
+
+            // Clones share isolation; isolated clients don't.
+            assert_eq!(iso(&client, &default_prefs), iso(&clone, &default_prefs));
+            assert_eq!(
+                iso(&isolated, &default_prefs),
+                iso(&isolated_clone, &default_prefs)
+            );
+            assert_ne!(iso(&client, &default_prefs), iso(&isolated, &default_prefs));
+
+            // An isolation group separates streams on the same client...
+            assert_ne!(iso(&client, &default_prefs), iso(&client, &group_prefs));
+            assert_eq!(iso(&client, &group_prefs), iso(&clone, &group_prefs));
+            // ... but doesn't undo the isolation between clients.
+            assert_ne!(iso(&client, &group_prefs), iso(&isolated, &group_prefs));
+        });
+    }
+
     #[test]
     fn unbootstrapped_client_unusable() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
@@ -22,8 +22,9 @@ pub use tor_config::{CfgPath, ConfigBuildError, Reconfigure};
 /// Types for configuring how Tor circuits are built.
 pub mod circ {
     pub use tor_circmgr::{
-        CircMgrConfig, CircMgrConfigBuilder, CircuitTiming, CircuitTimingBuilder, PathConfig,
-        PathConfigBuilder, PreemptiveCircuitConfig, PreemptiveCircuitConfigBuilder,
+        BridgeConfig, BridgeParseError, CircMgrConfig, CircMgrConfigBuilder, CircuitTiming,
+        CircuitTimingBuilder, PathConfig, PathConfigBuilder, PreemptiveCircuitConfig,
+        PreemptiveCircuitConfigBuilder,
     };
 }
 
@@ -139,6 +140,49 @@ impl StreamTimeoutConfig {
     }
 }
 
+/// Configuration for how strictly we check the validity periods of
+/// directory documents.
+///
+/// This type is immutable once constructed. To create an object of this type,
+/// use [`DirToleranceConfigBuilder`].
+///
+/// You can replace this configuration on a running Arti client.  Doing so
+/// will take effect the next time we check a document.
+#[derive(Debug, Clone, Builder, Deserialize, Eq, PartialEq)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct DirToleranceConfig {
+    /// How far may our clock disagree with the directory authorities' before
+    /// we stop accepting a consensus or authority certificate that they say
+    /// is not yet valid, or no longer valid?
+    #[builder(default)]
+    #[serde(with = "humantime_serde", default)]
+    pub(crate) clock_skew_tolerance: Duration,
+}
+
+#[allow(clippy::unwrap_used)]
+impl Default for DirToleranceConfig {
+    fn default() -> Self {
+        DirToleranceConfigBuilder::default().build().unwrap()
+    }
+}
+
+impl From<DirToleranceConfig> for DirToleranceConfigBuilder {
+    fn from(cfg: DirToleranceConfig) -> DirToleranceConfigBuilder {
+        let mut builder = DirToleranceConfigBuilder::default();
+        builder.clock_skew_tolerance(cfg.clock_skew_tolerance);
+        builder
+    }
+}
+
+impl DirToleranceConfig {
+    /// Return a new [`DirToleranceConfigBuilder`].
+    pub fn builder() -> DirToleranceConfigBuilder {
+        DirToleranceConfigBuilder::default()
+    }
+}
+
 /// Return the default stream timeout
 fn default_connect_timeout() -> Duration {
     Duration::new(10, 0)
@@ -181,6 +225,12 @@ pub struct StorageConfig {
     #[builder(setter(into), default = "default_state_dir()")]
     #[serde(default = "default_state_dir")]
     state_dir: CfgPath,
+    /// If true, keep a journal of important changes to our persistent state
+    /// (such as newly confirmed guards) between the times that we save it,
+    /// so that we don't lose those changes if we crash.
+    #[builder(default)]
+    #[serde(default)]
+    journal_state: bool,
 }
 
 /// Return the default cache directory.
@@ -206,7 +256,7 @@ impl StorageConfig {
     }
 
     /// Try to expand `state_dir` to be a path buffer.
-    pub(crate) fn expand_state_dir(&self) -> Result<PathBuf, ConfigBuildError> {
+    pub fn expand_state_dir(&self) -> Result<PathBuf, ConfigBuildError> {
         self.state_dir
             .path()
             .map_err(|e| ConfigBuildError::Invalid {
@@ -214,8 +264,13 @@ impl StorageConfig {
                 problem: e.to_string(),
             })
     }
+    /// Return true if we should keep a journal of changes to our
+    /// persistent state.
+    pub(crate) fn journal_state(&self) -> bool {
+        self.journal_state
+    }
     /// Try to expand `cache_dir` to be a path buffer.
-    pub(crate) fn expand_cache_dir(&self) -> Result<PathBuf, ConfigBuildError> {
+    pub fn expand_cache_dir(&self) -> Result<PathBuf, ConfigBuildError> {
         self.cache_dir
             .path()
             .map_err(|e| ConfigBuildError::Invalid {
@@ -228,7 +283,10 @@ impl StorageConfig {
 impl From<StorageConfig> for StorageConfigBuilder {
     fn from(cfg: StorageConfig) -> StorageConfigBuilder {
         let mut builder = StorageConfigBuilder::default();
-        builder.state_dir(cfg.state_dir).cache_dir(cfg.cache_dir);
+        builder
+            .state_dir(cfg.state_dir)
+            .cache_dir(cfg.cache_dir)
+            .journal_state(cfg.journal_state);
         builder
     }
 }
@@ -273,6 +331,33 @@ impl From<SystemConfig> for SystemConfigBuilder {
     }
 }
 
+/// Configuration for a pluggable transport binary.
+///
+/// Bridges whose lines name one of this binary's `protocols` are reached
+/// through it, rather than directly.
+#[derive(Deserialize, Debug, Clone, Builder, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[non_exhaustive]
+pub struct TransportConfig {
+    /// Names of the transports that this binary provides (e.g. `obfs4`).
+    #[builder(setter(into))]
+    pub protocols: Vec<String>,
+    /// Location of the binary.
+    pub path: CfgPath,
+    /// Command-line arguments to pass to the binary.
+    #[builder(setter(into), default)]
+    #[serde(default)]
+    pub arguments: Vec<String>,
+}
+
+impl TransportConfig {
+    /// Return a new TransportConfigBuilder.
+    pub fn builder() -> TransportConfigBuilder {
+        TransportConfigBuilder::default()
+    }
+}
+
 /// A configuration used to bootstrap a [`TorClient`](crate::TorClient).
 ///
 /// In order to connect to the Tor network, Arti needs to know a few
@@ -310,12 +395,19 @@ pub struct TorClientConfig {
     /// Information about when and how often to download directory information
     download_schedule: dir::DownloadScheduleConfig,
 
+    /// Information about how strictly to check directory documents'
+    /// validity periods.
+    directory_tolerance: DirToleranceConfig,
+
     /// Facility to override network parameters from the values set in the
     /// consensus.
     override_net_params: HashMap<String, i32>,
 
     /// Information about how to build paths through the network.
-    path_rules: circ::PathConfig,
+    pub(crate) path_rules: circ::PathConfig,
+
+    /// Pluggable transport binaries that we can use to reach bridges.
+    pub(crate) transports: Vec<TransportConfig>,
 
     /// Information about preemptive circuits.
     preemptive_circuits: circ::PreemptiveCircuitConfig,
@@ -353,6 +445,7 @@ impl TorClientConfig {
         dircfg.network_config(self.tor_network.clone());
         dircfg.schedule_config(self.download_schedule.clone());
         dircfg.cache_path(self.storage.expand_cache_dir()?);
+        dircfg.clock_skew_tolerance(self.directory_tolerance.clock_skew_tolerance);
         for (k, v) in &self.override_net_params {
             dircfg.override_net_param(k.clone(), *v);
         }
@@ -382,10 +475,14 @@ pub struct TorClientConfigBuilder {
     storage: StorageConfigBuilder,
     /// Inner builder for the `download_schedule` section.
     download_schedule: dir::DownloadScheduleConfigBuilder,
+    /// Inner builder for the `directory_tolerance` section.
+    directory_tolerance: DirToleranceConfigBuilder,
     /// Inner builder for the `override_net_params` section.
     override_net_params: HashMap<String, i32>,
     /// Inner builder for the `path_rules` section.
     path_rules: circ::PathConfigBuilder,
+    /// Inner list for the `transports` section.
+    transports: Vec<TransportConfig>,
     /// Inner builder for the `circuit_timing` section.
     preemptive_circuits: circ::PreemptiveCircuitConfigBuilder,
     /// Inner builder for the `circuit_timing` section.
@@ -410,11 +507,16 @@ impl TorClientConfigBuilder {
             .download_schedule
             .build()
             .map_err(|e| e.within("download_schedule"))?;
+        let directory_tolerance = self
+            .directory_tolerance
+            .build()
+            .map_err(|e| e.within("directory_tolerance"))?;
         let override_net_params = self.override_net_params.clone();
         let path_rules = self
             .path_rules
             .build()
             .map_err(|e| e.within("path_rules"))?;
+        let transports = self.transports.clone();
         let preemptive_circuits = self
             .preemptive_circuits
             .build()
@@ -437,8 +539,10 @@ impl TorClientConfigBuilder {
             tor_network,
             storage,
             download_schedule,
+            directory_tolerance,
             override_net_params,
             path_rules,
+            transports,
             preemptive_circuits,
             circuit_timing,
             address_filter,
@@ -491,6 +595,14 @@ impl TorClientConfigBuilder {
         &mut self.download_schedule
     }
 
+    /// Return a mutable reference to a [`DirToleranceConfigBuilder`].
+    ///
+    /// This section is used to loosen Arti's checks on whether directory
+    /// documents are currently valid, for use when our clock may be wrong.
+    pub fn directory_tolerance(&mut self) -> &mut DirToleranceConfigBuilder {
+        &mut self.directory_tolerance
+    }
+
     /// Return a mutable reference to a [`HashMap`] of network parameters
     /// that should be used to override those specified in the consensus
     /// directory.
@@ -515,6 +627,14 @@ impl TorClientConfigBuilder {
         &mut self.path_rules
     }
 
+    /// Return a mutable reference to the list of [`TransportConfig`]s.
+    ///
+    /// This section lists the pluggable transport binaries that we can use
+    /// to reach bridges configured in `path_rules`.
+    pub fn transports(&mut self) -> &mut Vec<TransportConfig> {
+        &mut self.transports
+    }
+
     /// Return a mutable reference to a [`PreemptiveCircuitConfigBuilder`](circ::PreemptiveCircuitConfigBuilder).
     ///
     /// This section overrides Arti's rules for preemptive circuits.
@@ -562,8 +682,10 @@ impl From<TorClientConfig> for TorClientConfigBuilder {
             tor_network,
             storage,
             download_schedule,
+            directory_tolerance,
             override_net_params,
             path_rules,
+            transports,
             preemptive_circuits,
             circuit_timing,
             address_filter,
@@ -575,8 +697,10 @@ impl From<TorClientConfig> for TorClientConfigBuilder {
             tor_network: tor_network.into(),
             storage: storage.into(),
             download_schedule: download_schedule.into(),
+            directory_tolerance: directory_tolerance.into(),
             override_net_params,
             path_rules: path_rules.into(),
+            transports,
             preemptive_circuits: preemptive_circuits.into(),
             circuit_timing: circuit_timing.into(),
             address_filter: address_filter.into(),
@@ -626,6 +750,7 @@ mod test {
         bld.download_schedule()
             .retry_certs(DownloadSchedule::new(10, sec, 3))
             .retry_microdescs(DownloadSchedule::new(30, 10 * sec, 9));
+        bld.directory_tolerance().clock_skew_tolerance(300 * sec);
         bld.override_net_params()
             .insert("wombats-per-quokka".to_owned(), 7);
         bld.path_rules()
@@ -143,6 +143,10 @@ enum ErrorDetail {
     #[error("Rejecting .onion address as unsupported.")]
     OnionAddressNotSupported,
 
+    /// Asked to resolve a .onion address, which has no IP addresses.
+    #[error("Rejecting .onion address: resolving onion addresses is meaningless.")]
+    OnionAddressResolveRequest,
+
     /// Unusable target address.
     #[error("Could not parse target address: {0}")]
     Address(#[from] crate::address::TorAddrError),
@@ -251,6 +255,7 @@ impl tor_error::HasKind for ErrorDetail {
             E::Reconfigure(e) => e.kind(),
             E::Spawn { cause, .. } => cause.kind(),
             E::OnionAddressNotSupported => EK::NotImplemented,
+            E::OnionAddressResolveRequest => EK::InvalidStreamTarget,
             E::Address(_) | E::InvalidHostname => EK::InvalidStreamTarget,
             E::LocalAddress => EK::ForbiddenStreamTarget,
         }
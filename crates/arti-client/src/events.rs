@@ -0,0 +1,639 @@
+//! Code to publish a single stream of events about what a client is doing.
+//!
+//! A [`TorClient`](crate::TorClient) learns about bootstrap progress, new
+//! directory information, and circuits from several different places.
+//! Embedders who want to watch all of those can call
+//! [`TorClient::events`](crate::TorClient::events) to get one
+//! [`TorEvents`] stream that carries every kind of [`TorEvent`].
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use tor_checkable::TimeValidityError;
+use tor_circmgr::CircEvent;
+use tor_dirmgr::DirEvent;
+use tor_error::ErrorKind;
+use tor_rtcompat::SleepProvider;
+
+use crate::status::{BootstrapStatus, CircHealthStatus};
+
+pub use tor_proto::circuit::UniqId;
+
+/// Something that has happened to a [`TorClient`](crate::TorClient).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum TorEvent {
+    /// The client's bootstrap status has changed.
+    BootstrapProgress(BootstrapStatus),
+    /// We have a new consensus directory that we can use.
+    NewConsensus,
+    /// We finished building a circuit.
+    CircuitBuilt(UniqId),
+    /// A circuit that we built has closed.
+    CircuitClosed(UniqId),
+    /// We were unable to open a stream that an application asked for.
+    StreamFailed(ErrorKind),
+    /// Our summary of how well circuit builds are going has changed.
+    HealthChanged(Option<CircHealthStatus>),
+    /// A directory server sent us a document that looked like it came from
+    /// the wrong time, which suggests that our clock may be wrong.
+    ClockSkewDetected(ClockSkew),
+}
+
+/// How far our clock seems to be from the rest of the network's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClockSkew {
+    /// Our clock seems to be ahead of the network's by at least this much.
+    Fast(Duration),
+    /// Our clock seems to be behind the network's by at least this much.
+    Slow(Duration),
+}
+
+impl ClockSkew {
+    /// Return the clock skew suggested by `err`, if it says that we
+    /// rejected a downloaded document for being outside its validity period.
+    ///
+    /// A document that isn't valid yet suggests that our clock is slow; one
+    /// that has expired suggests that our clock is fast.  A single such
+    /// error is only a hint: the server could simply be misbehaving.
+    pub(crate) fn from_dir_error(err: &tor_dirmgr::Error) -> Option<Self> {
+        match err {
+            tor_dirmgr::Error::UntimelyObject {
+                source: tor_dirmgr::DocSource::DirServer { .. },
+                cause,
+            } => match cause {
+                TimeValidityError::NotYetValid(d) => Some(ClockSkew::Slow(*d)),
+                TimeValidityError::Expired(d) => Some(ClockSkew::Fast(*d)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A kind of [`TorEvent`], used to rate-limit each kind separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum EventKind {
+    /// [`TorEvent::BootstrapProgress`]
+    Bootstrap,
+    /// [`TorEvent::NewConsensus`]
+    Consensus,
+    /// [`TorEvent::CircuitBuilt`] and [`TorEvent::CircuitClosed`]
+    Circuit,
+    /// [`TorEvent::StreamFailed`]
+    StreamFailed,
+    /// [`TorEvent::HealthChanged`]
+    Health,
+    /// [`TorEvent::ClockSkewDetected`]
+    ClockSkew,
+}
+
+impl EventKind {
+    /// Return the kind of `event`.
+    fn of(event: &TorEvent) -> Self {
+        match event {
+            TorEvent::BootstrapProgress(_) => EventKind::Bootstrap,
+            TorEvent::NewConsensus => EventKind::Consensus,
+            TorEvent::CircuitBuilt(_) | TorEvent::CircuitClosed(_) => EventKind::Circuit,
+            TorEvent::StreamFailed(_) => EventKind::StreamFailed,
+            TorEvent::HealthChanged(_) => EventKind::Health,
+            TorEvent::ClockSkewDetected(_) => EventKind::ClockSkew,
+        }
+    }
+
+    /// Return the largest number of events of this kind that we'll publish
+    /// in a single [`RATE_WINDOW`].
+    fn limit(self) -> usize {
+        match self {
+            // We build and close lots of circuits while bootstrapping and
+            // while measuring timeouts.
+            EventKind::Circuit => 50,
+            // (A broken network can make every stream fail at once, so
+            // StreamFailed needs a limit too.)
+            EventKind::Bootstrap
+            | EventKind::Consensus
+            | EventKind::StreamFailed
+            | EventKind::Health
+            | EventKind::ClockSkew => 10,
+        }
+    }
+
+    /// Return true if events of this kind report a state that replaces the
+    /// previous one.
+    ///
+    /// When we rate-limit such events, we hold on to the latest one and send
+    /// it once the limit allows, so that subscribers don't get stuck with a
+    /// stale state.
+    fn coalesces(self) -> bool {
+        matches!(self, EventKind::Bootstrap | EventKind::Health)
+    }
+}
+
+/// How long is each window over which we count events for rate limiting?
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How many events can wait for each subscriber before we start dropping
+/// new ones?
+const EVENT_BUFFER: usize = 256;
+
+/// A count of how many events of one kind we've published recently.
+#[derive(Debug, Clone, Default)]
+struct RateWindow {
+    /// When did the current window start?
+    start: Option<Instant>,
+    /// How many events have we published in the current window?
+    count: usize,
+}
+
+impl RateWindow {
+    /// Return true if we can publish another event at `now`, given that we
+    /// allow `limit` events per window.
+    fn admit(&mut self, now: Instant, limit: usize) -> bool {
+        match self.start {
+            Some(start) if now < start + RATE_WINDOW => {}
+            _ => {
+                self.start = Some(now);
+                self.count = 0;
+            }
+        }
+        if self.count < limit {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A single subscriber to an [`EventHub`].
+struct Subscriber {
+    /// Sender for the subscriber's buffer.
+    sender: mpsc::Sender<TorEvent>,
+    /// Number of events that this subscriber has missed.
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Mutable state for an [`EventHub`].
+#[derive(Default)]
+struct HubInner {
+    /// Everybody who is listening.
+    subscribers: Vec<Subscriber>,
+    /// Recent event counts, for rate limiting.
+    windows: HashMap<EventKind, RateWindow>,
+    /// The latest rate-limited event of each kind that
+    /// [coalesces](EventKind::coalesces), waiting for its window to reopen.
+    pending: BTreeMap<EventKind, TorEvent>,
+}
+
+impl HubInner {
+    /// Send `event` to every subscriber.
+    fn send(&mut self, event: &TorEvent) {
+        for s in self.subscribers.iter_mut() {
+            if s.sender.try_send(event.clone()).is_err() {
+                s.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record that every subscriber has missed an event.
+    fn drop_event(&self) {
+        for s in self.subscribers.iter() {
+            s.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Send every pending event whose rate limit lets us send it at `now`.
+    fn flush(&mut self, now: Instant) {
+        let windows = &mut self.windows;
+        let ready: Vec<EventKind> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|kind| windows.entry(*kind).or_default().admit(now, kind.limit()))
+            .collect();
+        for kind in ready {
+            if let Some(event) = self.pending.remove(&kind) {
+                self.send(&event);
+            }
+        }
+    }
+}
+
+/// An object that publishes [`TorEvent`]s to every subscribed [`TorEvents`]
+/// stream.
+///
+/// Publishing never blocks.  Events that exceed their kind's rate limit are
+/// dropped for everybody, except for bootstrap and health events: of those,
+/// we keep the latest one and send it when the limit allows, dropping only
+/// the ones it replaces.  Events that don't fit in a subscriber's buffer are
+/// dropped for that subscriber.  Either way, the subscriber's
+/// [`TorEvents::dropped`] count goes up.
+#[derive(Default)]
+pub(crate) struct EventHub {
+    /// The state of this hub.
+    inner: Mutex<HubInner>,
+}
+
+impl EventHub {
+    /// Construct a new hub with no subscribers.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a new stream that will receive the events we publish from now
+    /// on.
+    pub(crate) fn subscribe(&self) -> TorEvents {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        self.inner
+            .lock()
+            .expect("event hub lock poisoned")
+            .subscribers
+            .push(Subscriber {
+                sender,
+                dropped: Arc::clone(&dropped),
+            });
+        TorEvents {
+            inner: receiver,
+            dropped,
+        }
+    }
+
+    /// Publish `event`, which happened at `now`, to every subscriber.
+    pub(crate) fn publish(&self, event: &TorEvent, now: Instant) {
+        let kind = EventKind::of(event);
+        let mut inner = self.inner.lock().expect("event hub lock poisoned");
+        // Forget about any streams that have been dropped.
+        inner.subscribers.retain(|s| !s.sender.is_closed());
+        if inner.subscribers.is_empty() {
+            return;
+        }
+        inner.flush(now);
+
+        let admitted = inner
+            .windows
+            .entry(kind)
+            .or_default()
+            .admit(now, kind.limit());
+        if admitted {
+            inner.send(event);
+        } else if kind.coalesces() {
+            // This event is newer than anything already waiting, which is
+            // now stale.
+            if inner.pending.insert(kind, event.clone()).is_some() {
+                inner.drop_event();
+            }
+        } else {
+            inner.drop_event();
+        }
+    }
+
+    /// Send any rate-limited events whose window has reopened by `now`.
+    pub(crate) fn flush(&self, now: Instant) {
+        self.inner
+            .lock()
+            .expect("event hub lock poisoned")
+            .flush(now);
+    }
+
+    /// Return the time at which the earliest of our rate-limited events can
+    /// be sent, if we have any.
+    pub(crate) fn next_flush(&self) -> Option<Instant> {
+        let inner = self.inner.lock().expect("event hub lock poisoned");
+        inner
+            .pending
+            .keys()
+            .filter_map(|kind| inner.windows.get(kind)?.start)
+            .map(|start| start + RATE_WINDOW)
+            .min()
+    }
+}
+
+/// A stream of [`TorEvent`]s from a [`TorClient`](crate::TorClient).
+///
+/// This stream yields events in the order they happened, except that a
+/// rate-limited bootstrap or health event can arrive a little late.  It can
+/// miss events, either because too many of one kind happened at once, or
+/// because the reader fell too far behind; [`TorEvents::dropped`] says how
+/// many.
+pub struct TorEvents {
+    /// The receiver that implements this stream.
+    inner: mpsc::Receiver<TorEvent>,
+    /// Number of events that this stream has missed.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl TorEvents {
+    /// Return the number of events that this stream has missed so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for TorEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TorEvents")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for TorEvents {
+    type Item = TorEvent;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Run forever, turning events from our various sources into
+/// [`TorEvent`]s and publishing them on `hub`.
+///
+/// Exits when all the sources are closed, or when `hub` is dropped.
+pub(crate) async fn publish_events<R: SleepProvider>(
+    runtime: R,
+    hub: Weak<EventHub>,
+    bootstrap: impl Stream<Item = BootstrapStatus> + Unpin,
+    dir_events: impl Stream<Item = DirEvent> + Unpin,
+    dir_errors: impl Stream<Item = tor_dirmgr::Error> + Unpin,
+    circ_events: impl Stream<Item = CircEvent> + Unpin,
+) {
+    /// Internal enumeration to combine incoming events.
+    enum Event {
+        /// A bootstrap status change
+        Bootstrap(BootstrapStatus),
+        /// A directory event
+        Dir(DirEvent),
+        /// A problem with a downloaded directory document
+        DirError(tor_dirmgr::Error),
+        /// A circuit event
+        Circ(CircEvent),
+    }
+    let mut stream = futures::stream::select(
+        futures::stream::select(bootstrap.map(Event::Bootstrap), dir_events.map(Event::Dir)),
+        futures::stream::select(
+            dir_errors.map(Event::DirError),
+            circ_events.map(Event::Circ),
+        ),
+    );
+
+    let mut health = None;
+    loop {
+        let flush_at = match hub.upgrade() {
+            Some(h) => h.next_flush(),
+            None => break,
+        };
+        let event = match flush_at {
+            // Some rate-limited events are waiting: wake up in time to send
+            // them, even if nothing else happens.
+            Some(when) => {
+                let delay = when.saturating_duration_since(runtime.now());
+                match future::select(stream.next(), Box::pin(runtime.sleep(delay))).await {
+                    Either::Left((event, _)) => event,
+                    Either::Right(((), _)) => {
+                        if let Some(hub) = hub.upgrade() {
+                            hub.flush(runtime.now());
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => stream.next().await,
+        };
+        let event = match event {
+            Some(e) => e,
+            None => break,
+        };
+        let hub = match hub.upgrade() {
+            Some(h) => h,
+            None => break,
+        };
+        let now = runtime.now();
+        match event {
+            Event::Bootstrap(status) => {
+                let new_health = status.circuit_health();
+                hub.publish(&TorEvent::BootstrapProgress(status), now);
+                if new_health != health {
+                    health = new_health;
+                    hub.publish(&TorEvent::HealthChanged(health), now);
+                }
+            }
+            Event::Dir(DirEvent::NewConsensus) => hub.publish(&TorEvent::NewConsensus, now),
+            Event::DirError(e) => {
+                if let Some(skew) = ClockSkew::from_dir_error(&e) {
+                    hub.publish(&TorEvent::ClockSkewDetected(skew), now);
+                }
+            }
+            Event::Circ(CircEvent::Built(id)) => hub.publish(&TorEvent::CircuitBuilt(id), now),
+            Event::Circ(CircEvent::Closed(id)) => hub.publish(&TorEvent::CircuitClosed(id), now),
+            Event::Dir(_) | Event::Circ(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Return every event that `events` currently has waiting.
+    fn drain(events: &mut TorEvents) -> Vec<TorEvent> {
+        let mut v = Vec::new();
+        while let Ok(Some(e)) = events.inner.try_next() {
+            v.push(e);
+        }
+        v
+    }
+
+    #[test]
+    fn skew_from_dir_error() {
+        use tor_dirmgr::{DocSource, Error};
+        let untimely = |source, cause| Error::UntimelyObject { source, cause };
+        let min = Duration::from_secs(60);
+
+        let e = untimely(DocSource::DirServer {}, TimeValidityError::NotYetValid(min));
+        assert_eq!(ClockSkew::from_dir_error(&e), Some(ClockSkew::Slow(min)));
+        let e = untimely(DocSource::DirServer {}, TimeValidityError::Expired(min));
+        assert_eq!(ClockSkew::from_dir_error(&e), Some(ClockSkew::Fast(min)));
+
+        // Stale documents in our cache say nothing about our clock.
+        let e = untimely(DocSource::LocalCache, TimeValidityError::Expired(min));
+        assert_eq!(ClockSkew::from_dir_error(&e), None);
+        assert_eq!(ClockSkew::from_dir_error(&Error::ManagerDropped), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let hub = EventHub::new();
+        let mut events = hub.subscribe();
+        let t0 = Instant::now();
+
+        // A burst of stream failures gets cut off at the limit...
+        for _ in 0..25 {
+            hub.publish(&TorEvent::StreamFailed(ErrorKind::TorNetworkTimeout), t0);
+        }
+        // ...but that doesn't affect other kinds of event.
+        hub.publish(&TorEvent::NewConsensus, t0);
+        let got = drain(&mut events);
+        assert_eq!(got.len(), 11);
+        assert!(matches!(got[10], TorEvent::NewConsensus));
+        assert_eq!(events.dropped(), 15);
+
+        // Once the window is over, we can send again.
+        hub.publish(
+            &TorEvent::StreamFailed(ErrorKind::TorNetworkTimeout),
+            t0 + RATE_WINDOW / 2,
+        );
+        hub.publish(
+            &TorEvent::StreamFailed(ErrorKind::TorNetworkTimeout),
+            t0 + RATE_WINDOW,
+        );
+        let got = drain(&mut events);
+        assert_eq!(got.len(), 1);
+        assert!(matches!(
+            got[0],
+            TorEvent::StreamFailed(ErrorKind::TorNetworkTimeout)
+        ));
+        assert_eq!(events.dropped(), 16);
+    }
+
+    #[test]
+    fn coalesce() {
+        let hub = EventHub::new();
+        let mut events = hub.subscribe();
+        let t0 = Instant::now();
+
+        // Health changes past the limit aren't sent yet...
+        for _ in 0..14 {
+            hub.publish(&TorEvent::HealthChanged(None), t0);
+        }
+        hub.publish(&TorEvent::HealthChanged(Some(CircHealthStatus::Broken)), t0);
+        assert_eq!(drain(&mut events).len(), 10);
+        // ...and only the ones that got replaced count as dropped.
+        assert_eq!(events.dropped(), 4);
+        assert_eq!(hub.next_flush(), Some(t0 + RATE_WINDOW));
+
+        // Nothing goes out until the window reopens.
+        hub.flush(t0 + RATE_WINDOW / 2);
+        assert!(drain(&mut events).is_empty());
+
+        // Then the latest state arrives.
+        hub.flush(t0 + RATE_WINDOW);
+        let got = drain(&mut events);
+        assert_eq!(got.len(), 1);
+        assert!(matches!(
+            got[0],
+            TorEvent::HealthChanged(Some(CircHealthStatus::Broken))
+        ));
+        assert_eq!(hub.next_flush(), None);
+        assert_eq!(events.dropped(), 4);
+
+        // Publishing anything else also sends whatever is ready.
+        for _ in 0..11 {
+            hub.publish(
+                &TorEvent::BootstrapProgress(BootstrapStatus::default()),
+                t0 + RATE_WINDOW * 2,
+            );
+        }
+        assert_eq!(drain(&mut events).len(), 10);
+        hub.publish(&TorEvent::NewConsensus, t0 + RATE_WINDOW * 3);
+        let got = drain(&mut events);
+        assert_eq!(got.len(), 2);
+        assert!(matches!(got[0], TorEvent::BootstrapProgress(_)));
+        assert!(matches!(got[1], TorEvent::NewConsensus));
+        assert_eq!(events.dropped(), 4);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let hub = EventHub::new();
+        let mut slow = hub.subscribe();
+        let mut fast = hub.subscribe();
+        let t0 = Instant::now();
+
+        // Use a different window for each event, so that nothing gets
+        // rate-limited.
+        let n = EVENT_BUFFER * 2;
+        for i in 0..n {
+            hub.publish(&TorEvent::NewConsensus, t0 + RATE_WINDOW * i as u32);
+            assert_eq!(drain(&mut fast).len(), 1);
+        }
+        assert_eq!(fast.dropped(), 0);
+
+        let got = drain(&mut slow).len();
+        assert!(got >= EVENT_BUFFER);
+        assert_eq!(got + slow.dropped(), n);
+
+        // Dropped streams get forgotten.
+        drop(slow);
+        hub.publish(&TorEvent::NewConsensus, t0);
+        assert_eq!(hub.inner.lock().unwrap().subscribers.len(), 1);
+    }
+
+    #[test]
+    fn fan_in() {
+        /// A runtime that only knows what time it is.
+        #[derive(Clone)]
+        struct Clock(Instant);
+        impl SleepProvider for Clock {
+            type SleepFuture = futures::future::Pending<()>;
+            fn sleep(&self, _: Duration) -> Self::SleepFuture {
+                futures::future::pending()
+            }
+            fn now(&self) -> Instant {
+                self.0
+            }
+        }
+
+        let hub = Arc::new(EventHub::new());
+        let mut events = hub.subscribe();
+
+        let bootstrap = futures::stream::iter(vec![BootstrapStatus::default()]);
+        let dir = futures::stream::iter(vec![DirEvent::NewDescriptors, DirEvent::NewConsensus]);
+        let skewed = tor_dirmgr::Error::UntimelyObject {
+            source: tor_dirmgr::DocSource::DirServer {},
+            cause: TimeValidityError::Expired(Duration::from_secs(3600)),
+        };
+        let dir_errors = futures::stream::iter(vec![tor_dirmgr::Error::ManagerDropped, skewed]);
+        let circ = futures::stream::iter(Vec::<CircEvent>::new());
+
+        block_on(publish_events(
+            Clock(Instant::now()),
+            Arc::downgrade(&hub),
+            bootstrap,
+            dir,
+            dir_errors,
+            circ,
+        ));
+
+        let got = drain(&mut events);
+        assert_eq!(got.len(), 3);
+        assert!(got
+            .iter()
+            .any(|e| matches!(e, TorEvent::BootstrapProgress(_))));
+        assert!(got.iter().any(|e| matches!(e, TorEvent::NewConsensus)));
+        // Only the untimely document tells us anything about our clock.
+        assert!(got.iter().any(|e| matches!(
+            e,
+            TorEvent::ClockSkewDetected(ClockSkew::Fast(d)) if *d == Duration::from_secs(3600)
+        )));
+        // The health didn't change from "unknown", so we don't report it.
+        assert!(!got.iter().any(|e| matches!(e, TorEvent::HealthChanged(_))));
+        assert_eq!(events.dropped(), 0);
+
+        // Once the hub is gone, the stream ends.
+        drop(hub);
+        assert!(block_on(events.next()).is_none());
+    }
+}
@@ -160,6 +160,11 @@
 //! this crate. This can be used to embed Arti in custom environments where you want
 //! lots of control over how it uses the network.
 //!
+//! If you build with more than one TLS implementation, you choose among them
+//! when you construct the client, by picking a runtime: for example,
+//! `TorClient::with_runtime(TokioRustlsRuntime::create()?)` uses `rustls`,
+//! which avoids any dependency on OpenSSL at run time.
+//!
 //! [**View the `tor_rtcompat` crate documentation**](tor_rtcompat) for more about these features.
 //!
 //! # Feature flags
@@ -175,6 +180,15 @@
 //!   `static-sqlite` feature.  We'll look for better solutions here in the future.)
 //! * `static-sqlite` -- link with a static version of sqlite.
 //! * `static-native-tls` -- link with a static version of `native-tls`. Enables `native-tls`.
+//! * `dirfetch` (default) -- download directory information from the network.
+//! * `snapshot-only` -- build a minimal client, without `dirfetch`, that
+//!   bootstraps from a directory snapshot in its cache and never downloads
+//!   directory information itself.  Use with `--no-default-features`.  This
+//!   is the default feature set minus `dirfetch`, so it also leaves out
+//!   support for consensus diffs and for xz- and zstd-compressed documents.
+//!   Nothing in the client will replace an expired snapshot: once the
+//!   snapshot has expired, the client refuses to build circuits, and
+//!   bootstrapping fails with a "directory expired" error.
 //! * `experimental-api` -- build with experimental, unstable API support. Note
 //!   that these APIs are NOT covered by semantic versioning guarantees: we might
 //!   break them or remove them between patch versions.
@@ -221,6 +235,7 @@ mod client;
 mod util;
 
 pub mod config;
+pub mod events;
 pub mod status;
 
 pub use address::{DangerouslyIntoTorAddr, IntoTorAddr, TorAddr, TorAddrError};
@@ -6,9 +6,12 @@ use std::{borrow::Cow, fmt, time::SystemTime};
 use derive_more::Display;
 use futures::{Stream, StreamExt};
 use tor_chanmgr::{ConnBlockage, ConnStatus, ConnStatusEvents};
+use tor_circmgr::{CircStatus, CircStatusEvents};
 use tor_dirmgr::DirBootstrapStatus;
 use tracing::debug;
 
+pub use tor_circmgr::HealthStatus as CircHealthStatus;
+
 /// Information about how ready a [`crate::TorClient`] is to handle requests.
 ///
 /// Note that this status does not change monotonically: a `TorClient` can
@@ -27,6 +30,8 @@ pub struct BootstrapStatus {
     conn_status: ConnStatus,
     /// Status for our directory information.
     dir_status: DirBootstrapStatus,
+    /// Status for our ability to build circuits.
+    circ_status: CircStatus,
 }
 
 impl BootstrapStatus {
@@ -36,7 +41,9 @@ impl BootstrapStatus {
     /// 0 is defined as "just started"; 1 is defined as "ready to use."
     pub fn as_frac(&self) -> f32 {
         // Coefficients chosen arbitrarily.
-        self.conn_status.frac() * 0.15 + self.dir_status.frac_at(SystemTime::now()) * 0.85
+        self.conn_status.frac() * 0.15
+            + self.dir_status.frac_at(SystemTime::now()) * 0.80
+            + self.circ_status.frac() * 0.05
     }
 
     /// Return true if the status indicates that the client is ready for
@@ -44,11 +51,24 @@ impl BootstrapStatus {
     ///
     /// For the purposes of this function, the client is "ready for traffic" if,
     /// as far as we know, we can start acting on a new client request immediately.
+    /// (We don't require that a circuit has been built yet: a request can
+    /// launch one itself.)
     pub fn ready_for_traffic(&self) -> bool {
         let now = SystemTime::now();
         self.conn_status.usable() && self.dir_status.usable_at(now)
     }
 
+    /// Return how well the client's recent circuit builds have gone, once it
+    /// has been able to build a circuit.
+    ///
+    /// A client that is [`ready_for_traffic`](BootstrapStatus::ready_for_traffic)
+    /// may still find that most of its circuit builds are failing; an
+    /// application can use this to tell its user that their Tor connection
+    /// is degraded.
+    pub fn circuit_health(&self) -> Option<CircHealthStatus> {
+        self.circ_status.health()
+    }
+
     /// If the client is unable to make forward progress for some reason, return
     /// that reason.
     ///
@@ -86,6 +106,11 @@ impl BootstrapStatus {
     fn apply_dir_status(&mut self, status: DirBootstrapStatus) {
         self.dir_status = status;
     }
+
+    /// Adjust this status based on new circuit-status information.
+    fn apply_circ_status(&mut self, status: CircStatus) {
+        self.circ_status = status;
+    }
 }
 
 /// A reason why a client believes it is stuck.
@@ -138,8 +163,8 @@ impl fmt::Display for BootstrapStatus {
         } else {
             write!(
                 f,
-                "{}%: {}; {}",
-                percent, &self.conn_status, &self.dir_status
+                "{}%: {}; {}; {}",
+                percent, &self.conn_status, &self.dir_status, &self.circ_status
             )
         }
     }
@@ -158,6 +183,7 @@ pub(crate) async fn report_status(
     mut sender: postage::watch::Sender<BootstrapStatus>,
     conn_status: ConnStatusEvents,
     dir_status: impl Stream<Item = DirBootstrapStatus> + Unpin,
+    circ_status: CircStatusEvents,
 ) {
     /// Internal enumeration to combine incoming status changes.
     enum Event {
@@ -165,15 +191,20 @@ pub(crate) async fn report_status(
         Conn(ConnStatus),
         /// A directory status change
         Dir(DirBootstrapStatus),
+        /// A circuit status change
+        Circ(CircStatus),
     }
-    let mut stream =
-        futures::stream::select(conn_status.map(Event::Conn), dir_status.map(Event::Dir));
+    let mut stream = futures::stream::select(
+        futures::stream::select(conn_status.map(Event::Conn), dir_status.map(Event::Dir)),
+        circ_status.map(Event::Circ),
+    );
 
     while let Some(event) = stream.next().await {
         let mut b = sender.borrow_mut();
         match event {
             Event::Conn(e) => b.apply_conn_status(e),
             Event::Dir(e) => b.apply_dir_status(e),
+            Event::Circ(e) => b.apply_circ_status(e),
         }
         debug!("{}", *b);
     }
@@ -52,9 +52,10 @@ mod options;
 
 pub use cmdline::CmdLine;
 pub use options::{
-    ApplicationConfig, ApplicationConfigBuilder, ArtiConfig, ArtiConfigBuilder, LogRotation,
-    LogfileConfig, LogfileConfigBuilder, LoggingConfig, LoggingConfigBuilder, ProxyConfig,
-    ProxyConfigBuilder,
+    ApplicationConfig, ApplicationConfigBuilder, ArtiConfig, ArtiConfigBuilder, ListenAddr,
+    LogRotation, LogfileConfig, LogfileConfigBuilder, LoggingConfig, LoggingConfigBuilder,
+    ProxyConfig, ProxyConfigBuilder, SocksListenerConfig, SocksListenerConfigBuilder,
+    SocksValidationMode,
 };
 use tor_config::CfgPath;
 
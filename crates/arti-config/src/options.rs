@@ -3,14 +3,16 @@
 use arti_client::config::{
     circ,
     dir::{self, DownloadScheduleConfig, NetworkConfig},
-    ClientAddrConfig, ClientAddrConfigBuilder, StorageConfig, StorageConfigBuilder,
-    StreamTimeoutConfig, StreamTimeoutConfigBuilder, SystemConfig, SystemConfigBuilder,
-    TorClientConfig, TorClientConfigBuilder,
+    ClientAddrConfig, ClientAddrConfigBuilder, DirToleranceConfig, DirToleranceConfigBuilder,
+    StorageConfig, StorageConfigBuilder, StreamTimeoutConfig, StreamTimeoutConfigBuilder,
+    SystemConfig, SystemConfigBuilder, TorClientConfig, TorClientConfigBuilder, TransportConfig,
 };
 use derive_builder::Builder;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use tor_config::{CfgPath, ConfigBuildError};
 
 /// Default options to use for our configuration.
@@ -31,12 +33,28 @@ pub struct ApplicationConfig {
     #[serde(default)]
     #[builder(default)]
     watch_configuration: bool,
+
+    /// If true, we allow Arti to run as root (or as another user with uid
+    /// 0).  Otherwise, we refuse to start when we find that we're root.
+    #[serde(default)]
+    #[builder(default)]
+    allow_running_as_root: bool,
+
+    /// If true, we refuse to start when our state or cache directory can be
+    /// accessed by other users.  Otherwise, we remove their access and warn
+    /// about it.
+    #[serde(default)]
+    #[builder(default)]
+    strict_directory_permissions: bool,
 }
 
 impl From<ApplicationConfig> for ApplicationConfigBuilder {
     fn from(cfg: ApplicationConfig) -> Self {
         let mut builder = ApplicationConfigBuilder::default();
-        builder.watch_configuration(cfg.watch_configuration);
+        builder
+            .watch_configuration(cfg.watch_configuration)
+            .allow_running_as_root(cfg.allow_running_as_root)
+            .strict_directory_permissions(cfg.strict_directory_permissions);
         builder
     }
 }
@@ -46,6 +64,17 @@ impl ApplicationConfig {
     pub fn watch_configuration(&self) -> bool {
         self.watch_configuration
     }
+
+    /// Return true if we're configured to allow running as root.
+    pub fn allow_running_as_root(&self) -> bool {
+        self.allow_running_as_root
+    }
+
+    /// Return true if we should refuse to start (rather than warning) when
+    /// our storage directories are accessible by other users.
+    pub fn strict_directory_permissions(&self) -> bool {
+        self.strict_directory_permissions
+    }
 }
 
 /// Structure to hold our logging configuration options
@@ -194,6 +223,73 @@ pub struct ProxyConfig {
     #[serde(default = "default_socks_port")]
     #[builder(default = "default_socks_port()")]
     socks_port: Option<u16>,
+    /// Port to listen on for incoming DNS queries.
+    ///
+    /// If this is not set, we don't answer DNS queries.
+    #[serde(default)]
+    #[builder(default)]
+    dns_port: Option<u16>,
+    /// Address to listen on for incoming DNS queries.
+    ///
+    /// If this is not set, we listen at localhost, on both IPv4 and IPv6.
+    #[serde(default)]
+    #[builder(default)]
+    dns_listen: Option<IpAddr>,
+    /// If true, SOCKS connections from different client addresses never
+    /// share a circuit.
+    #[serde(default = "default_socks_isolation")]
+    #[builder(default = "default_socks_isolation()")]
+    socks_isolate_client_addr: bool,
+    /// If true, SOCKS connections that arrive on different listening
+    /// sockets never share a circuit.
+    #[serde(default = "default_socks_isolation")]
+    #[builder(default = "default_socks_isolation()")]
+    socks_isolate_listener: bool,
+    /// The largest total number of bytes of buffers that all our SOCKS
+    /// connections may use at once to relay data toward the Tor network.
+    #[serde(default = "default_socks_buffer_pool_cap")]
+    #[builder(default = "default_socks_buffer_pool_cap()")]
+    socks_buffer_pool_cap: usize,
+    /// Additional addresses to listen on for incoming SOCKS connections.
+    ///
+    /// Unlike `socks_port`, these can be non-localhost addresses or Unix
+    /// domain sockets.
+    #[serde(default)]
+    #[builder(default)]
+    socks_listen: Vec<SocksListenerConfig>,
+    /// If true, we keep running when we can't bind to some of the addresses
+    /// in `socks_listen`, so long as we can bind to at least one.
+    /// Otherwise, any failure to bind is fatal.
+    #[serde(default)]
+    #[builder(default)]
+    socks_listen_best_effort: bool,
+    /// How strictly to check the SOCKS handshakes that clients send us.
+    #[serde(default)]
+    #[builder(default)]
+    socks_validation: SocksValidationMode,
+}
+
+/// How strictly our SOCKS proxy checks the handshakes that clients send.
+///
+/// Some SOCKS clients are sloppy about parts of the protocol: rejecting their
+/// requests breaks real applications, but accepting them can hide bugs.
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+#[serde(rename_all = "lowercase")]
+pub enum SocksValidationMode {
+    /// Ignore nonzero reserved bytes and unrecognized authentication
+    /// methods, but reject requests that are too long.
+    Normal,
+    /// Reject anything questionable.
+    Strict,
+    /// Accept anything that we can make sense of.
+    Lenient,
+}
+
+impl Default for SocksValidationMode {
+    fn default() -> Self {
+        Self::Normal
+    }
 }
 
 /// Return the default value for `socks_port`
@@ -202,6 +298,17 @@ fn default_socks_port() -> Option<u16> {
     Some(9150)
 }
 
+/// Return the default value for `socks_isolate_client_addr` and
+/// `socks_isolate_listener`.
+fn default_socks_isolation() -> bool {
+    true
+}
+
+/// Return the default value for `socks_buffer_pool_cap`.
+fn default_socks_buffer_pool_cap() -> usize {
+    32 * 1024 * 1024
+}
+
 impl Default for ProxyConfig {
     fn default() -> Self {
         Self::builder().build().expect("Default builder failed")
@@ -219,16 +326,170 @@ impl ProxyConfig {
     pub fn socks_port(&self) -> Option<u16> {
         self.socks_port
     }
+
+    /// Return the configured DNS port for this proxy configuration,
+    /// if one is enabled.
+    pub fn dns_port(&self) -> Option<u16> {
+        self.dns_port
+    }
+
+    /// Return the configured address for the DNS port to listen on, if
+    /// one is set.
+    pub fn dns_listen(&self) -> Option<IpAddr> {
+        self.dns_listen
+    }
+
+    /// Return true if SOCKS connections from different client addresses
+    /// should be kept on separate circuits.
+    pub fn socks_isolate_client_addr(&self) -> bool {
+        self.socks_isolate_client_addr
+    }
+
+    /// Return true if SOCKS connections that arrive on different listening
+    /// sockets should be kept on separate circuits.
+    pub fn socks_isolate_listener(&self) -> bool {
+        self.socks_isolate_listener
+    }
+
+    /// Return the largest number of bytes of buffers that our SOCKS
+    /// connections may use at once.
+    pub fn socks_buffer_pool_cap(&self) -> usize {
+        self.socks_buffer_pool_cap
+    }
+
+    /// Return the additional SOCKS listeners for this proxy configuration.
+    pub fn socks_listen(&self) -> &[SocksListenerConfig] {
+        &self.socks_listen
+    }
+
+    /// Return true if we should keep running when we can bind to some, but
+    /// not all, of our configured SOCKS listeners.
+    pub fn socks_listen_best_effort(&self) -> bool {
+        self.socks_listen_best_effort
+    }
+
+    /// Return how strictly we should check incoming SOCKS handshakes.
+    pub fn socks_validation(&self) -> SocksValidationMode {
+        self.socks_validation
+    }
 }
 
 impl From<ProxyConfig> for ProxyConfigBuilder {
     fn from(cfg: ProxyConfig) -> ProxyConfigBuilder {
         let mut builder = ProxyConfigBuilder::default();
         builder.socks_port(cfg.socks_port);
+        builder.dns_port(cfg.dns_port);
+        builder.dns_listen(cfg.dns_listen);
+        builder.socks_isolate_client_addr(cfg.socks_isolate_client_addr);
+        builder.socks_isolate_listener(cfg.socks_isolate_listener);
+        builder.socks_buffer_pool_cap(cfg.socks_buffer_pool_cap);
+        builder.socks_listen(cfg.socks_listen);
+        builder.socks_listen_best_effort(cfg.socks_listen_best_effort);
+        builder.socks_validation(cfg.socks_validation);
         builder
     }
 }
 
+/// Configuration for a single SOCKS listener.
+#[derive(Deserialize, Debug, Clone, Builder, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+pub struct SocksListenerConfig {
+    /// The address to listen on.
+    address: ListenAddr,
+    /// If present, overrides `socks_isolate_client_addr` for connections
+    /// to this listener.
+    #[serde(default)]
+    #[builder(default)]
+    isolate_client_addr: Option<bool>,
+    /// If present, overrides `socks_isolate_listener` for connections to
+    /// this listener.
+    #[serde(default)]
+    #[builder(default)]
+    isolate_listener: Option<bool>,
+}
+
+impl SocksListenerConfig {
+    /// Return a new [`SocksListenerConfigBuilder`].
+    pub fn builder() -> SocksListenerConfigBuilder {
+        SocksListenerConfigBuilder::default()
+    }
+
+    /// Return the address that this listener should bind to.
+    pub fn address(&self) -> &ListenAddr {
+        &self.address
+    }
+
+    /// Return this listener's override for `socks_isolate_client_addr`, if
+    /// it has one.
+    pub fn isolate_client_addr(&self) -> Option<bool> {
+        self.isolate_client_addr
+    }
+
+    /// Return this listener's override for `socks_isolate_listener`, if it
+    /// has one.
+    pub fn isolate_listener(&self) -> Option<bool> {
+        self.isolate_listener
+    }
+}
+
+/// An address that a proxy can listen on.
+///
+/// In configuration files, this is written either as an address and port
+/// (`"192.168.0.5:9150"`, `"[::1]:9150"`) or as `unix:` followed by the path
+/// to a Unix domain socket (`"unix:${ARTI_LOCAL_DATA}/socks.sock"`).
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(try_from = "String")]
+#[non_exhaustive]
+pub enum ListenAddr {
+    /// A TCP address and port.
+    Tcp(SocketAddr),
+    /// The path to a Unix domain socket.
+    ///
+    /// These are only supported on Unix-like platforms.
+    Unix(CfgPath),
+}
+
+impl FromStr for ListenAddr {
+    type Err = ConfigBuildError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(ConfigBuildError::Invalid {
+                    field: "address".to_owned(),
+                    problem: "empty Unix socket path".to_owned(),
+                });
+            }
+            Ok(ListenAddr::Unix(CfgPath::new(path.to_owned())))
+        } else {
+            s.parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| ConfigBuildError::Invalid {
+                    field: "address".to_owned(),
+                    problem: format!("{:?} is neither an IP:port nor unix:PATH", s),
+                })
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = ConfigBuildError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
 /// Structure to hold Arti's configuration options, whether from a
 /// configuration file or the command line.
 //
@@ -265,6 +526,10 @@ pub struct ArtiConfig {
     /// Information about when and how often to download directory information
     download_schedule: DownloadScheduleConfig,
 
+    /// Information about how strictly to check directory documents'
+    /// validity periods.
+    directory_tolerance: DirToleranceConfig,
+
     /// Facility to override network parameters from the values set in the
     /// consensus.
     #[serde(default)]
@@ -273,6 +538,10 @@ pub struct ArtiConfig {
     /// Information about how to build paths through the network.
     path_rules: circ::PathConfig,
 
+    /// Pluggable transport binaries that we can use to reach bridges.
+    #[serde(default)]
+    transports: Vec<TransportConfig>,
+
     /// Information about preemptive circuits
     preemptive_circuits: circ::PreemptiveCircuitConfig,
 
@@ -303,20 +572,24 @@ impl From<ArtiConfig> for TorClientConfigBuilder {
             storage,
             address_filter,
             path_rules,
+            transports,
             preemptive_circuits,
             circuit_timing,
             override_net_params,
             download_schedule,
+            directory_tolerance,
             tor_network,
             ..
         } = cfg;
         *builder.storage() = storage.into();
         *builder.address_filter() = address_filter.into();
         *builder.path_rules() = path_rules.into();
+        *builder.transports() = transports;
         *builder.preemptive_circuits() = preemptive_circuits.into();
         *builder.circuit_timing() = circuit_timing.into();
         *builder.override_net_params() = override_net_params;
         *builder.download_schedule() = download_schedule.into();
+        *builder.directory_tolerance() = directory_tolerance.into();
         *builder.tor_network() = tor_network.into();
         builder
     }
@@ -348,6 +621,11 @@ impl ArtiConfig {
     pub fn proxy(&self) -> &ProxyConfig {
         &self.proxy
     }
+
+    /// Return the [`StorageConfig`] for this configuration.
+    pub fn storage(&self) -> &StorageConfig {
+        &self.storage
+    }
 }
 
 /// Builder object used to construct an ArtiConfig.
@@ -370,10 +648,14 @@ pub struct ArtiConfigBuilder {
     tor_network: dir::NetworkConfigBuilder,
     /// Builder for the download_schedule section.
     download_schedule: dir::DownloadScheduleConfigBuilder,
+    /// Builder for the directory_tolerance section.
+    directory_tolerance: DirToleranceConfigBuilder,
     /// In-progress object for the override_net_params section.
     override_net_params: HashMap<String, i32>,
     /// Builder for the path_rules section.
     path_rules: circ::PathConfigBuilder,
+    /// In-progress object for the transports section.
+    transports: Vec<TransportConfig>,
     /// Builder for the preemptive_circuits section.
     preemptive_circuits: circ::PreemptiveCircuitConfigBuilder,
     /// Builder for the circuit_timing section.
@@ -404,11 +686,16 @@ impl ArtiConfigBuilder {
             .download_schedule
             .build()
             .map_err(|e| e.within("download_schedule"))?;
+        let directory_tolerance = self
+            .directory_tolerance
+            .build()
+            .map_err(|e| e.within("directory_tolerance"))?;
         let override_net_params = self.override_net_params.clone();
         let path_rules = self
             .path_rules
             .build()
             .map_err(|e| e.within("path_rules"))?;
+        let transports = self.transports.clone();
         let preemptive_circuits = self
             .preemptive_circuits
             .build()
@@ -433,8 +720,10 @@ impl ArtiConfigBuilder {
             tor_network,
             storage,
             download_schedule,
+            directory_tolerance,
             override_net_params,
             path_rules,
+            transports,
             preemptive_circuits,
             circuit_timing,
             address_filter,
@@ -490,6 +779,14 @@ impl ArtiConfigBuilder {
         &mut self.download_schedule
     }
 
+    /// Return a mutable reference to a [`DirToleranceConfigBuilder`].
+    ///
+    /// This section is used to loosen Arti's checks on whether directory
+    /// documents are currently valid, for use when our clock may be wrong.
+    pub fn directory_tolerance(&mut self) -> &mut DirToleranceConfigBuilder {
+        &mut self.directory_tolerance
+    }
+
     /// Return a mutable reference to a [`HashMap`] of network parameters
     /// that should be used to override those specified in the consensus
     /// directory.
@@ -514,6 +811,14 @@ impl ArtiConfigBuilder {
         &mut self.path_rules
     }
 
+    /// Return a mutable reference to the list of [`TransportConfig`]s.
+    ///
+    /// This section lists the pluggable transport binaries that we can use
+    /// to reach bridges configured in `path_rules`.
+    pub fn transports(&mut self) -> &mut Vec<TransportConfig> {
+        &mut self.transports
+    }
+
     /// Return a mutable reference to a [`PreemptiveCircuitConfigBuilder`](circ::PreemptiveCircuitConfigBuilder).
     ///
     /// This section overrides Arti's rules for preemptive circuits.
@@ -563,8 +868,10 @@ impl From<ArtiConfig> for ArtiConfigBuilder {
             storage: cfg.storage.into(),
             tor_network: cfg.tor_network.into(),
             download_schedule: cfg.download_schedule.into(),
+            directory_tolerance: cfg.directory_tolerance.into(),
             override_net_params: cfg.override_net_params,
             path_rules: cfg.path_rules.into(),
+            transports: cfg.transports,
             preemptive_circuits: cfg.preemptive_circuits.into(),
             circuit_timing: cfg.circuit_timing.into(),
             address_filter: cfg.address_filter.into(),
@@ -628,7 +935,19 @@ mod test {
             .unwrap();
 
         let mut bld = ArtiConfig::builder();
-        bld.proxy().socks_port(Some(9999));
+        bld.proxy()
+            .socks_port(Some(9999))
+            .dns_port(Some(9998))
+            .dns_listen(Some("127.0.0.2".parse().unwrap()))
+            .socks_isolate_client_addr(false)
+            .socks_isolate_listener(false)
+            .socks_buffer_pool_cap(1024 * 1024)
+            .socks_listen(vec![SocksListenerConfig::builder()
+                .address("unix:/var/run/arti.sock".parse().unwrap())
+                .isolate_listener(Some(true))
+                .build()
+                .unwrap()])
+            .socks_listen_best_effort(true);
         bld.logging().console("warn");
         bld.tor_network()
             .authorities(vec![auth])
@@ -639,6 +958,7 @@ mod test {
         bld.download_schedule()
             .retry_certs(DownloadSchedule::new(10, sec, 3))
             .retry_microdescs(DownloadSchedule::new(30, 10 * sec, 9));
+        bld.directory_tolerance().clock_skew_tolerance(300 * sec);
         bld.override_net_params()
             .insert("wombats-per-quokka".to_owned(), 7);
         bld.path_rules()
@@ -655,6 +975,9 @@ mod test {
             .request_max_retries(22)
             .request_loyalty(3600 * sec);
         bld.address_filter().allow_local_addrs(true);
+        bld.application()
+            .allow_running_as_root(true)
+            .strict_directory_permissions(true);
 
         let val = bld.build().unwrap();
 
@@ -665,4 +988,47 @@ mod test {
 
         assert_ne!(val, ArtiConfig::default());
     }
+
+    #[test]
+    fn socks_listen() {
+        let a: ListenAddr = "127.0.0.1:9150".parse().unwrap();
+        assert_eq!(a, ListenAddr::Tcp("127.0.0.1:9150".parse().unwrap()));
+        assert_eq!(a.to_string(), "127.0.0.1:9150");
+        let a: ListenAddr = "unix:/tmp/socks".parse().unwrap();
+        assert_eq!(a, ListenAddr::Unix(CfgPath::new("/tmp/socks".to_owned())));
+        assert_eq!(a.to_string(), "unix:/tmp/socks");
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost:9150".parse::<ListenAddr>().is_err());
+
+        let cfg: ProxyConfig = toml::from_str(
+            r#"
+            socks_listen_best_effort = true
+            [[socks_listen]]
+            address = "[::1]:9050"
+            [[socks_listen]]
+            address = "unix:/tmp/socks"
+            isolate_client_addr = false
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.socks_listen_best_effort());
+        assert_eq!(cfg.socks_port(), Some(9150));
+        let l = cfg.socks_listen();
+        assert_eq!(l.len(), 2);
+        assert_eq!(
+            l[0].address(),
+            &ListenAddr::Tcp("[::1]:9050".parse().unwrap())
+        );
+        assert_eq!(l[0].isolate_client_addr(), None);
+        assert_eq!(l[1].isolate_client_addr(), Some(false));
+        assert_eq!(l[1].isolate_listener(), None);
+
+        let bad: Result<ProxyConfig, _> = toml::from_str(
+            r#"
+            [[socks_listen]]
+            address = "nowhere"
+            "#,
+        );
+        assert!(bad.is_err());
+    }
 }
@@ -0,0 +1,427 @@
+//! Copy data between a SOCKS connection and a Tor stream.
+//!
+//! A busy proxy can have thousands of connections open at once, most of
+//! which are idle.  So that those don't cost us much memory, we only give a
+//! connection a large buffer while it's actually moving data: buffers come
+//! from a [`BufferPool`] with a fixed total size, and go back to it whenever
+//! the connection goes idle.
+
+use futures::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use futures::{poll, task::Poll};
+use std::io::Result as IoResult;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The size of the buffer that every connection has, even when it is idle.
+const SMALL_BUF_LEN: usize = 256;
+
+/// The largest buffer that we'll give a single connection from the pool.
+const MAX_BUF_LEN: usize = 16 * 1024;
+
+/// A limit on the total size of the buffers that our copy loops may hold.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    /// The largest total number of bytes that can be in use at once.
+    cap: usize,
+    /// The number of bytes currently in use.
+    in_use: AtomicUsize,
+}
+
+impl BufferPool {
+    /// Make a new pool that will let at most `cap` bytes of buffers exist at
+    /// once.
+    pub(crate) fn new(cap: usize) -> Self {
+        BufferPool {
+            cap,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of bytes of buffers that are currently in use.
+    #[cfg(test)]
+    fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::SeqCst)
+    }
+
+    /// Try to take a buffer of `len` bytes from this pool.
+    ///
+    /// Return None if that would put us over our limit.
+    fn take(self: &Arc<Self>, len: usize) -> Option<PooledBuf> {
+        let mut cur = self.in_use.load(Ordering::SeqCst);
+        loop {
+            let new = cur.checked_add(len).filter(|n| *n <= self.cap)?;
+            match self
+                .in_use
+                .compare_exchange(cur, new, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => cur = actual,
+            }
+        }
+        Some(PooledBuf {
+            buf: vec![0; len],
+            pool: Arc::clone(self),
+        })
+    }
+}
+
+/// A buffer that counts against a [`BufferPool`] until it is dropped.
+struct PooledBuf {
+    /// The buffer itself.
+    buf: Vec<u8>,
+    /// The pool that this buffer counts against.
+    pool: Arc<BufferPool>,
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(self.buf.len(), Ordering::SeqCst);
+    }
+}
+
+/// Copy all the data from `reader` into `writer` until we encounter an EOF or
+/// an error.
+///
+/// Unlike as futures::io::copy(), this function is meant for use with
+/// interactive readers and writers, where the reader might pause for
+/// a while, but where we want to send data on the writer as soon as
+/// it is available.
+///
+/// This function assumes that the writer might need to be flushed for
+/// any buffered data to be sent.  It tries to minimize the number of
+/// flushes, however, by only flushing the writer when the reader has no data.
+///
+/// While the reader keeps filling our buffer, we try to replace it with a
+/// bigger one from `pool`.  Whenever the reader has no data, we give any
+/// buffer we took back to `pool`.
+pub(crate) async fn copy_interactive<R, W>(
+    mut reader: R,
+    mut writer: W,
+    pool: Arc<BufferPool>,
+) -> IoResult<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut small = [0_u8; SMALL_BUF_LEN];
+    let mut big: Option<PooledBuf> = None;
+
+    // At this point we could just loop, calling read().await,
+    // write_all().await, and flush().await.  But we want to be more
+    // clever than that: we only want to flush when the reader is
+    // stalled.  That way we can pack our data into as few cells as
+    // possible, but flush it immediately whenever there's no more
+    // data coming.
+    let loop_result: IoResult<()> = loop {
+        let buf = match big.as_mut() {
+            Some(b) => &mut b.buf[..],
+            None => &mut small[..],
+        };
+        match poll!(reader.read(buf)) {
+            Poll::Ready(Err(e)) => break Err(e),
+            Poll::Ready(Ok(0)) => break Ok(()), // EOF
+            Poll::Ready(Ok(n)) => {
+                writer.write_all(&buf[..n]).await?;
+                if n == buf.len() && n < MAX_BUF_LEN {
+                    // The reader filled our buffer: try to get a bigger
+                    // one.  We give back the one we have first, so that its
+                    // room in the pool counts toward the new one.  If the
+                    // pool is still out of room, we go back to our small
+                    // buffer.
+                    let len = (n * 4).min(MAX_BUF_LEN);
+                    drop(big.take());
+                    big = pool.take(len);
+                }
+                continue;
+            }
+            Poll::Pending => {
+                // We're idle: give back our buffer, and flush.
+                big = None;
+                writer.flush().await?;
+            }
+        }
+
+        // The reader has nothing for us, so we should wait on it.
+        match reader.read(&mut small[..]).await {
+            Err(e) => break Err(e),
+            Ok(0) => break Ok(()),
+            Ok(n) => writer.write_all(&small[..n]).await?,
+        }
+    };
+
+    finish(writer, loop_result).await
+}
+
+/// Copy all the data from `reader` into `writer` until we encounter an EOF or
+/// an error, as [`copy_interactive`] does.
+///
+/// Because `reader` keeps its own buffer, we write straight from that buffer
+/// and never need one of our own.
+pub(crate) async fn copy_buffered_interactive<R, W>(mut reader: R, mut writer: W) -> IoResult<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // See copy_interactive() for why we only flush when the reader stalls.
+    let loop_result: IoResult<()> = loop {
+        match poll!(reader.fill_buf()) {
+            Poll::Ready(Err(e)) => break Err(e),
+            Poll::Ready(Ok([])) => break Ok(()), // EOF
+            Poll::Ready(Ok(data)) => {
+                writer.write_all(data).await?;
+                let n = data.len();
+                reader.consume_unpin(n);
+                continue;
+            }
+            Poll::Pending => writer.flush().await?,
+        }
+
+        // The reader has nothing for us, so we should wait on it.
+        let data = match reader.fill_buf().await {
+            Err(e) => break Err(e),
+            Ok([]) => break Ok(()),
+            Ok(data) => data,
+        };
+        writer.write_all(data).await?;
+        let n = data.len();
+        reader.consume_unpin(n);
+    };
+
+    finish(writer, loop_result).await
+}
+
+/// Helper: Flush or close `writer` after a copy loop ends with
+/// `loop_result`, and return the result of the copy.
+async fn finish<W>(mut writer: W, loop_result: IoResult<()>) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Make sure that we flush any lingering data if we can.
+    //
+    // If there is a difference between closing and dropping, then we
+    // only want to do a "proper" close if the reader closed cleanly.
+    let flush_result = if loop_result.is_ok() {
+        writer.close().await
+    } else {
+        writer.flush().await
+    };
+
+    loop_result.or(flush_result)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::executor::LocalPool;
+    use futures::task::{Context, LocalSpawnExt};
+    use futures::FutureExt;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    /// A reader that never has any data.
+    struct IdleReader;
+
+    impl AsyncRead for IdleReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            Poll::Pending
+        }
+    }
+
+    /// A reader that yields `remaining` bytes of data as fast as it's
+    /// asked for them.
+    struct BusyReader {
+        /// How many more bytes to yield.
+        remaining: usize,
+        /// The largest buffer that we've been asked to fill.
+        biggest_buf: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for BusyReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            self.biggest_buf.fetch_max(buf.len(), Ordering::SeqCst);
+            let n = buf.len().min(self.remaining);
+            buf[..n].iter_mut().for_each(|b| *b = 7);
+            self.remaining -= n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// A writer that counts its bytes, and checks that our pool never goes
+    /// over its limit.
+    ///
+    /// Every other write yields to the executor, so that busy connections
+    /// take turns.
+    #[derive(Default)]
+    struct CheckingWriter {
+        /// The pool to check.
+        pool: Option<Arc<BufferPool>>,
+        /// How many bytes we've written.
+        written: Arc<Mutex<usize>>,
+        /// If true, our next write will yield.
+        yield_next: bool,
+    }
+
+    impl AsyncWrite for CheckingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            self.yield_next = !self.yield_next;
+            if !self.yield_next {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if let Some(pool) = &self.pool {
+                assert!(pool.in_use() <= pool.cap);
+            }
+            *self.written.lock().unwrap() += buf.len();
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn idle_and_busy() {
+        const N_IDLE: usize = 10_000;
+        const N_BUSY: usize = 20;
+        const BYTES: usize = 1024 * 1024;
+        let cap = MAX_BUF_LEN * 4;
+
+        let pool = Arc::new(BufferPool::new(cap));
+        let mut executor = LocalPool::new();
+        let spawner = executor.spawner();
+
+        // Lots of idle connections don't take anything from the pool.
+        for _ in 0..N_IDLE {
+            let writer = CheckingWriter::default();
+            spawner
+                .spawn_local(copy_interactive(IdleReader, writer, Arc::clone(&pool)).map(|_| ()))
+                .unwrap();
+        }
+        executor.run_until_stalled();
+        assert_eq!(pool.in_use(), 0);
+
+        // Busy connections share the pool without going over its limit, and
+        // the ones that get there first can use full-sized buffers.
+        let biggest_buf = Arc::new(AtomicUsize::new(0));
+        let written: Vec<Arc<Mutex<usize>>> = (0..N_BUSY).map(|_| Default::default()).collect();
+        let mut busy = Vec::new();
+        for w in &written {
+            let reader = BusyReader {
+                remaining: BYTES,
+                biggest_buf: Arc::clone(&biggest_buf),
+            };
+            let writer = CheckingWriter {
+                pool: Some(Arc::clone(&pool)),
+                written: Arc::clone(w),
+                ..Default::default()
+            };
+            busy.push(
+                spawner
+                    .spawn_local_with_handle(copy_interactive(reader, writer, Arc::clone(&pool)))
+                    .unwrap(),
+            );
+        }
+        // (The busy tasks keep waking themselves, so run_until_stalled()
+        // might give up on them early: wait for them explicitly.)
+        for r in executor.run_until(futures::future::join_all(busy)) {
+            r.unwrap();
+        }
+        for w in &written {
+            assert_eq!(*w.lock().unwrap(), BYTES);
+        }
+        assert_eq!(biggest_buf.load(Ordering::SeqCst), MAX_BUF_LEN);
+        // When they're done, they give their buffers back.
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    #[test]
+    fn grow_in_full_pool() {
+        let copy = |cap| {
+            let pool = Arc::new(BufferPool::new(cap));
+            let biggest_buf = Arc::new(AtomicUsize::new(0));
+            let reader = BusyReader {
+                remaining: 1024 * 1024,
+                biggest_buf: Arc::clone(&biggest_buf),
+            };
+            futures::executor::block_on(copy_interactive(
+                reader,
+                futures::io::sink(),
+                Arc::clone(&pool),
+            ))
+            .unwrap();
+            assert_eq!(pool.in_use(), 0);
+            biggest_buf.load(Ordering::SeqCst)
+        };
+
+        // We give back our 1024-byte buffer before we ask for a 4096-byte
+        // one, so a pool with room for only 4096 bytes can grant it.
+        assert_eq!(copy(4096), 4096);
+        // If the pool can't give us a bigger buffer, we carry on with the
+        // ones that it can.
+        assert_eq!(copy(1024), 1024);
+        assert_eq!(copy(0), SMALL_BUF_LEN);
+    }
+
+    /// Not really a test: measure how fast `copy_interactive` moves data
+    /// when the pool has room for full-sized buffers, and when it has none.
+    ///
+    /// Run it with
+    /// `cargo test -p arti --release -- --ignored --nocapture copy_throughput`.
+    #[test]
+    #[ignore]
+    fn copy_throughput() {
+        const BYTES: usize = 256 * 1024 * 1024;
+        for &cap in &[MAX_BUF_LEN, 0] {
+            let pool = Arc::new(BufferPool::new(cap));
+            let reader = BusyReader {
+                remaining: BYTES,
+                biggest_buf: Arc::default(),
+            };
+            let written = Arc::new(Mutex::new(0));
+            let writer = CheckingWriter {
+                written: Arc::clone(&written),
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            futures::executor::block_on(copy_interactive(reader, writer, pool)).unwrap();
+            let secs = start.elapsed().as_secs_f64();
+            assert_eq!(*written.lock().unwrap(), BYTES);
+            println!(
+                "pool with room for {} bytes: {:.0} MB/s",
+                cap,
+                BYTES as f64 / secs / 1e6
+            );
+        }
+    }
+
+    #[test]
+    fn buffered() {
+        let data: Vec<u8> = (0..10_000_u32).map(|x| x as u8).collect();
+        let reader = futures::io::BufReader::with_capacity(100, &data[..]);
+        let mut out = Vec::new();
+        futures::executor::block_on(copy_buffered_interactive(
+            reader,
+            futures::io::Cursor::new(&mut out),
+        ))
+        .unwrap();
+        assert_eq!(out, data);
+    }
+}
@@ -0,0 +1,740 @@
+//! Implement a simple DNS port that answers queries over Tor.
+//!
+//! A DNS port is launched with [`run_dns_resolver()`], which listens for
+//! UDP datagrams, and answers each A, AAAA, or PTR query in it by asking
+//! an exit relay to do the lookup for us.
+//!
+//! We only understand as much of the DNS protocol as we need for that:
+//! one question per query, no EDNS, and no compression in the questions
+//! we receive.
+
+use futures::task::SpawnExt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use arti_client::{ErrorKind, HasKind, TorClient};
+use tor_rtcompat::{Runtime, SleepProviderExt, UdpProvider, UdpSocket};
+
+use anyhow::{anyhow, Result};
+
+/// The largest DNS message we'll send over UDP.
+///
+/// (Without EDNS, clients don't expect anything larger.)
+const MAX_RESPONSE_LEN: usize = 512;
+
+/// How long to wait for an answer to a single query before giving up.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The largest number of queries we'll try to answer at once.
+///
+/// Each lookup can need a new circuit, so we don't want a burst of queries
+/// to turn into an unbounded number of them.  We answer any queries beyond
+/// this limit with SERVFAIL.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// The smallest TTL that we'll report for an answer, in seconds.
+const MIN_TTL: u32 = 60;
+
+/// The largest TTL that we'll report for an answer, in seconds.
+const MAX_TTL: u32 = 60 * 60;
+
+/// The QTYPE for an IPv4 address.
+const TYPE_A: u16 = 1;
+/// The QTYPE for a domain name pointer.
+const TYPE_PTR: u16 = 12;
+/// The QTYPE for an IPv6 address.
+const TYPE_AAAA: u16 = 28;
+/// The QCLASS for the Internet.
+const CLASS_IN: u16 = 1;
+
+/// A DNS response code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rcode {
+    /// No error.
+    NoError = 0,
+    /// The query was malformed.
+    FormErr = 1,
+    /// We couldn't answer the query.
+    ServFail = 2,
+    /// The name doesn't exist.
+    NxDomain = 3,
+    /// We don't support this kind of query.
+    NotImp = 4,
+    /// We won't answer this query.
+    Refused = 5,
+}
+
+/// The kind of lookup that a query asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lookup {
+    /// Look up the IPv4 addresses for a name.
+    A(String),
+    /// Look up the IPv6 addresses for a name.
+    Aaaa(String),
+    /// Look up the names for an address.
+    Ptr(IpAddr),
+}
+
+/// A single DNS question, as we received it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    /// The name that is being asked about, in dotted form, without a
+    /// trailing dot.
+    name: String,
+    /// The type of record that's wanted.
+    qtype: u16,
+    /// The class of record that's wanted.
+    qclass: u16,
+    /// The encoded question, so that we can echo it back.
+    raw: Vec<u8>,
+}
+
+/// A DNS query that we've decoded from a datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Query {
+    /// The ID that the client chose for this query.
+    id: u16,
+    /// True if the client asked for recursion.  (We echo this back.)
+    recursion_desired: bool,
+    /// The question in this query, if it had exactly one we could use.
+    question: Option<Question>,
+}
+
+/// What we should do about a datagram that we've received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    /// Ignore it.
+    Drop,
+    /// Answer it right away with the given response code.
+    Reply(Query, Rcode),
+    /// Look something up, and answer with the result.
+    Resolve(Query, Lookup),
+}
+
+/// A minimal cursor over a DNS message.
+struct Reader<'a> {
+    /// The bytes we haven't read yet.
+    remaining: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Remove and return the next `n` bytes, if there are that many.
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.remaining.len() < n {
+            return None;
+        }
+        let (first, rest) = self.remaining.split_at(n);
+        self.remaining = rest;
+        Some(first)
+    }
+    /// Remove and return a big-endian u16.
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// Decode the question at the start of `r`.
+///
+/// Return None if it's malformed, or if it uses name compression.
+fn decode_question(r: &mut Reader<'_>) -> Option<Question> {
+    let start = r.remaining;
+    let mut labels = Vec::new();
+    let mut name_len = 0_usize;
+    loop {
+        let len = usize::from(*r.take(1)?.first()?);
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            // Either a compression pointer or a reserved label type.
+            return None;
+        }
+        name_len += len + 1;
+        if name_len > 255 {
+            return None;
+        }
+        let label = std::str::from_utf8(r.take(len)?).ok()?;
+        if label.contains('.') {
+            return None;
+        }
+        labels.push(label);
+    }
+    let qtype = r.u16()?;
+    let qclass = r.u16()?;
+    let raw = start[..start.len() - r.remaining.len()].to_vec();
+    Some(Question {
+        name: labels.join("."),
+        qtype,
+        qclass,
+        raw,
+    })
+}
+
+/// Decide what to do with the datagram `msg`.
+fn decode_query(msg: &[u8]) -> Action {
+    let mut r = Reader { remaining: msg };
+    let header = match (r.u16(), r.u16(), r.u16(), r.take(6)) {
+        (Some(id), Some(flags), Some(qdcount), Some(_)) => (id, flags, qdcount),
+        _ => return Action::Drop,
+    };
+    let (id, flags, qdcount) = header;
+    if flags & 0x8000 != 0 {
+        // This is a response, not a query.
+        return Action::Drop;
+    }
+    let mut query = Query {
+        id,
+        recursion_desired: flags & 0x0100 != 0,
+        question: None,
+    };
+    let opcode = (flags >> 11) & 0xf;
+    if opcode != 0 {
+        return Action::Reply(query, Rcode::NotImp);
+    }
+    match qdcount {
+        0 => return Action::Reply(query, Rcode::FormErr),
+        1 => {}
+        _ => return Action::Reply(query, Rcode::Refused),
+    }
+    let question = match decode_question(&mut r) {
+        Some(q) => q,
+        None => return Action::Drop,
+    };
+    let lookup = match (question.qclass, question.qtype) {
+        (CLASS_IN, TYPE_A) => Some(Lookup::A(question.name.clone())),
+        (CLASS_IN, TYPE_AAAA) => Some(Lookup::Aaaa(question.name.clone())),
+        (CLASS_IN, TYPE_PTR) => reverse_name_to_addr(&question.name).map(Lookup::Ptr),
+        _ => {
+            query.question = Some(question);
+            return Action::Reply(query, Rcode::NotImp);
+        }
+    };
+    query.question = Some(question);
+    match lookup {
+        Some(lookup) => Action::Resolve(query, lookup),
+        // A PTR query for a name that doesn't encode an address.
+        None => Action::Reply(query, Rcode::NxDomain),
+    }
+}
+
+/// Convert a reverse-lookup name like `4.3.2.1.in-addr.arpa` into the
+/// address it describes.
+fn reverse_name_to_addr(name: &str) -> Option<IpAddr> {
+    let name = name.to_ascii_lowercase();
+    if let Some(v4) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0_u8; 4];
+        let mut n = 0;
+        for part in v4.rsplit('.') {
+            // Each label must be a plain decimal octet.
+            if n == 4 || part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            octets[n] = part.parse().ok()?;
+            n += 1;
+        }
+        (n == 4).then(|| Ipv4Addr::from(octets).into())
+    } else if let Some(v6) = name.strip_suffix(".ip6.arpa") {
+        let mut octets = [0_u8; 16];
+        let mut n = 0;
+        for part in v6.rsplit('.') {
+            if n == 32 || part.len() != 1 {
+                return None;
+            }
+            let nibble = u8::from_str_radix(part, 16).ok()?;
+            octets[n / 2] |= if n % 2 == 0 { nibble << 4 } else { nibble };
+            n += 1;
+        }
+        (n == 32).then(|| Ipv6Addr::from(octets).into())
+    } else {
+        None
+    }
+}
+
+/// Encode `name` as a sequence of DNS labels onto `out`.
+///
+/// Return None if `name` can't be encoded.
+fn encode_name(name: &str, out: &mut Vec<u8>) -> Option<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() > 253 {
+        return None;
+    }
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+    }
+    out.push(0);
+    Some(())
+}
+
+/// Clamp a TTL that an exit gave us into the range we're willing to report.
+fn clamp_ttl(ttl: u32) -> u32 {
+    ttl.clamp(MIN_TTL, MAX_TTL)
+}
+
+/// A single answer to put in a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Answer {
+    /// An address, with its TTL.
+    Addr(IpAddr, u32),
+    /// A hostname, with its TTL.
+    Name(String, u32),
+}
+
+/// Encode a response to `query` with the response code `rcode`, and as many
+/// of `answers` as will fit.
+///
+/// If we have to leave any answers out, we set the TC (truncated) bit.
+fn encode_response(query: &Query, rcode: Rcode, answers: &[Answer]) -> Vec<u8> {
+    let mut flags: u16 = 0x8000 | 0x0080; // QR and RA.
+    if query.recursion_desired {
+        flags |= 0x0100;
+    }
+    flags |= rcode as u16;
+
+    let mut out = Vec::with_capacity(MAX_RESPONSE_LEN);
+    out.extend_from_slice(&query.id.to_be_bytes());
+    out.extend_from_slice(&flags.to_be_bytes());
+    let qdcount: u16 = if query.question.is_some() { 1 } else { 0 };
+    out.extend_from_slice(&qdcount.to_be_bytes());
+    // We fill in the answer count once we know it.
+    out.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    let question = match &query.question {
+        Some(q) => q,
+        None => return out,
+    };
+    out.extend_from_slice(&question.raw);
+
+    let mut n_answers: u16 = 0;
+    for answer in answers {
+        let mut rr = Vec::new();
+        // The name is always the one from the question, which starts at
+        // offset 12.
+        rr.extend_from_slice(&[0xc0, 0x0c]);
+        let (rtype, ttl, rdata) = match answer {
+            Answer::Addr(IpAddr::V4(a), ttl) => (TYPE_A, ttl, a.octets().to_vec()),
+            Answer::Addr(IpAddr::V6(a), ttl) => (TYPE_AAAA, ttl, a.octets().to_vec()),
+            Answer::Name(name, ttl) => {
+                let mut rdata = Vec::new();
+                if encode_name(name, &mut rdata).is_none() {
+                    debug!("Skipping an answer that isn't a valid DNS name");
+                    continue;
+                }
+                (TYPE_PTR, ttl, rdata)
+            }
+        };
+        rr.extend_from_slice(&rtype.to_be_bytes());
+        rr.extend_from_slice(&CLASS_IN.to_be_bytes());
+        rr.extend_from_slice(&clamp_ttl(*ttl).to_be_bytes());
+        rr.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        rr.extend_from_slice(&rdata);
+
+        if out.len() + rr.len() > MAX_RESPONSE_LEN {
+            // Leave out the answers that don't fit, and set TC so that the
+            // client knows it didn't get all of them.
+            let truncated = flags | 0x0200;
+            out[2..4].copy_from_slice(&truncated.to_be_bytes());
+            break;
+        }
+        out.extend_from_slice(&rr);
+        n_answers += 1;
+    }
+    out[6..8].copy_from_slice(&n_answers.to_be_bytes());
+    out
+}
+
+/// Return the DNS response code to use when a lookup fails with an error
+/// of kind `kind`.
+fn failure_rcode(kind: ErrorKind) -> Rcode {
+    match kind {
+        ErrorKind::RemoteHostNotFound | ErrorKind::InvalidStreamTarget => Rcode::NxDomain,
+        ErrorKind::ForbiddenStreamTarget => Rcode::Refused,
+        _ => Rcode::ServFail,
+    }
+}
+
+/// Perform `lookup` over Tor, and return the response code and answers to
+/// send back.
+async fn resolve<R: Runtime>(tor_client: &TorClient<R>, lookup: &Lookup) -> (Rcode, Vec<Answer>) {
+    let result = match lookup {
+        Lookup::A(name) | Lookup::Aaaa(name) => {
+            let want_v4 = matches!(lookup, Lookup::A(_));
+            tor_client.resolve_with_ttl(name).await.map(|addrs| {
+                addrs
+                    .into_iter()
+                    .filter(|(addr, _)| addr.is_ipv4() == want_v4)
+                    .map(|(addr, ttl)| Answer::Addr(addr, ttl))
+                    .collect()
+            })
+        }
+        Lookup::Ptr(addr) => tor_client.resolve_ptr_with_ttl(*addr).await.map(|names| {
+            names
+                .into_iter()
+                .map(|(name, ttl)| Answer::Name(name, ttl))
+                .collect()
+        }),
+    };
+    match result {
+        Ok(answers) => (Rcode::NoError, answers),
+        Err(e) => {
+            debug!("DNS lookup failed: {}", e);
+            (failure_rcode(e.kind()), Vec::new())
+        }
+    }
+}
+
+/// Decrements a shared count of in-flight queries when dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Try to reserve one of the `MAX_IN_FLIGHT` slots in `count`.
+    fn try_new(count: &Arc<AtomicUsize>) -> Option<Self> {
+        let prev = count.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(Arc::clone(count));
+        (prev < MAX_IN_FLIGHT).then_some(guard)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answer a single datagram `packet` that we received from `addr` on
+/// `socket`.
+async fn handle_dns_req<R, U>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    socket: Arc<U>,
+    packet: Vec<u8>,
+    addr: SocketAddr,
+    _in_flight: InFlight,
+) where
+    R: Runtime,
+    U: UdpSocket + Send + Sync + 'static,
+{
+    let response = match decode_query(&packet) {
+        Action::Drop => {
+            debug!("Dropping a malformed DNS query");
+            return;
+        }
+        Action::Reply(query, rcode) => encode_response(&query, rcode, &[]),
+        Action::Resolve(query, lookup) => {
+            let (rcode, answers) = runtime
+                .timeout(QUERY_TIMEOUT, resolve(&tor_client, &lookup))
+                .await
+                .unwrap_or((Rcode::ServFail, Vec::new()));
+            encode_response(&query, rcode, &answers)
+        }
+    };
+    if let Err(e) = socket.send(&response, &addr).await {
+        warn!("Unable to send DNS response: {}", e);
+    }
+}
+
+/// Run a DNS port: listen on `dns_port` at `listen_addr`, and answer
+/// queries using `tor_client`.
+///
+/// If `listen_addr` is None, we listen at localhost, on both IPv4 and IPv6.
+pub(crate) async fn run_dns_resolver<R>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    listen_addr: Option<IpAddr>,
+    dns_port: u16,
+) -> Result<()>
+where
+    R: Runtime + UdpProvider,
+{
+    let mut sockets = Vec::new();
+
+    // As with the SOCKS port, we default to both IPv4 and IPv6 localhost.
+    let listen_addrs: Vec<IpAddr> = match listen_addr {
+        Some(a) => vec![a],
+        None => vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+    };
+    for listen_addr in &listen_addrs {
+        let addr: SocketAddr = (*listen_addr, dns_port).into();
+        match runtime.bind(&addr).await {
+            Ok(socket) => {
+                info!("Listening on {:?}.", addr);
+                sockets.push(Arc::new(socket));
+            }
+            Err(e) => warn!("Can't listen on {:?}: {}", addr, e),
+        }
+    }
+    if sockets.is_empty() {
+        error!("Couldn't open any DNS listeners.");
+        return Err(anyhow!("Couldn't open DNS listeners"));
+    }
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let loops = sockets.into_iter().map(|socket| {
+        serve_dns_socket(
+            runtime.clone(),
+            tor_client.clone(),
+            socket,
+            Arc::clone(&in_flight),
+        )
+    });
+    futures::future::try_join_all(loops).await?;
+
+    Ok(())
+}
+
+/// Receive queries on `socket` forever, and answer each one in a new task.
+///
+/// Uses `in_flight` to count the queries that we're currently answering.
+async fn serve_dns_socket<R, U>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    socket: Arc<U>,
+    in_flight: Arc<AtomicUsize>,
+) -> Result<()>
+where
+    R: Runtime,
+    U: UdpSocket + Send + Sync + 'static,
+{
+    let mut buf = [0_u8; 1500];
+    loop {
+        let (len, addr) = match socket.recv(&mut buf[..]).await {
+            Ok(r) => r,
+            Err(e) => {
+                // These are usually just the OS telling us about an earlier
+                // send that failed; keep going.
+                debug!("Error receiving on DNS port: {}", e);
+                continue;
+            }
+        };
+        let packet = buf[..len].to_vec();
+        let guard = match InFlight::try_new(&in_flight) {
+            Some(g) => g,
+            None => {
+                debug!("Too many DNS queries in flight; refusing one.");
+                if let Action::Resolve(query, _) | Action::Reply(query, _) = decode_query(&packet) {
+                    let response = encode_response(&query, Rcode::ServFail, &[]);
+                    let _ = socket.send(&response, &addr).await;
+                }
+                continue;
+            }
+        };
+        runtime.spawn(handle_dns_req(
+            runtime.clone(),
+            tor_client.clone(),
+            Arc::clone(&socket),
+            packet,
+            addr,
+            guard,
+        ))?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    /// Build a query with the given header fields and question.
+    fn query_bytes(id: u16, flags: u16, qdcount: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&flags.to_be_bytes());
+        out.extend_from_slice(&qdcount.to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        for _ in 0..qdcount {
+            encode_name(name, &mut out).unwrap();
+            out.extend_from_slice(&qtype.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn decode_supported() {
+        let q = query_bytes(0x1234, 0x0100, 1, "www.torproject.org", TYPE_A);
+        match decode_query(&q) {
+            Action::Resolve(query, Lookup::A(name)) => {
+                assert_eq!(query.id, 0x1234);
+                assert!(query.recursion_desired);
+                assert_eq!(name, "www.torproject.org");
+                assert_eq!(query.question.unwrap().raw, &q[12..]);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        let q = query_bytes(7, 0, 1, "example.com.", TYPE_AAAA);
+        assert!(matches!(
+            decode_query(&q),
+            Action::Resolve(_, Lookup::Aaaa(name)) if name == "example.com"
+        ));
+
+        let q = query_bytes(7, 0, 1, "4.3.2.1.in-addr.arpa", TYPE_PTR);
+        assert!(matches!(
+            decode_query(&q),
+            Action::Resolve(_, Lookup::Ptr(addr)) if addr == IpAddr::from([1, 2, 3, 4])
+        ));
+    }
+
+    #[test]
+    fn decode_rejected() {
+        // MX isn't supported.
+        let q = query_bytes(1, 0, 1, "example.com", 15);
+        assert!(matches!(decode_query(&q), Action::Reply(_, Rcode::NotImp)));
+        // Neither are other opcodes.
+        let q = query_bytes(1, 2 << 11, 1, "example.com", TYPE_A);
+        assert!(matches!(decode_query(&q), Action::Reply(_, Rcode::NotImp)));
+        // Multiple questions get refused.
+        let q = query_bytes(1, 0, 2, "example.com", TYPE_A);
+        assert!(matches!(decode_query(&q), Action::Reply(_, Rcode::Refused)));
+        // No questions at all is an error.
+        let q = query_bytes(1, 0, 0, "", TYPE_A);
+        assert!(matches!(decode_query(&q), Action::Reply(_, Rcode::FormErr)));
+        // A PTR query that doesn't name an address.
+        let q = query_bytes(1, 0, 1, "example.com", TYPE_PTR);
+        assert!(matches!(
+            decode_query(&q),
+            Action::Reply(_, Rcode::NxDomain)
+        ));
+        // Responses get ignored.
+        let q = query_bytes(1, 0x8000, 1, "example.com", TYPE_A);
+        assert_eq!(decode_query(&q), Action::Drop);
+    }
+
+    #[test]
+    fn decode_malformed() {
+        let q = query_bytes(1, 0, 1, "www.example.com", TYPE_A);
+        // Every truncation of a valid query is dropped.
+        for n in 0..q.len() {
+            assert_eq!(decode_query(&q[..n]), Action::Drop);
+        }
+        // So are compression pointers in the question.
+        let mut q2 = q[..12].to_vec();
+        q2.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        assert_eq!(decode_query(&q2), Action::Drop);
+        // And over-long names.
+        let mut q3 = q[..12].to_vec();
+        for _ in 0..5 {
+            q3.push(63);
+            q3.extend_from_slice(&[b'a'; 63]);
+        }
+        q3.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert_eq!(decode_query(&q3), Action::Drop);
+        // Random junk doesn't make us panic.
+        for i in 0..=255_u8 {
+            let junk: Vec<u8> = (0..40_u8).map(|j| j.wrapping_mul(i) ^ 0x55).collect();
+            let _ = decode_query(&junk);
+        }
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name_to_addr("1.0.0.127.IN-ADDR.ARPA"),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+        let v6 = "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa";
+        assert_eq!(
+            reverse_name_to_addr(v6),
+            Some("4321:0:1:2:3:4:567:89ab".parse().unwrap())
+        );
+        assert_eq!(reverse_name_to_addr("2.1.in-addr.arpa"), None);
+        assert_eq!(reverse_name_to_addr("5.4.3.2.1.in-addr.arpa"), None);
+        assert_eq!(reverse_name_to_addr("4.3.2.256.in-addr.arpa"), None);
+        assert_eq!(reverse_name_to_addr("4.3.2.+1.in-addr.arpa"), None);
+        assert_eq!(reverse_name_to_addr("1.2.ip6.arpa"), None);
+        assert_eq!(reverse_name_to_addr("example.com"), None);
+    }
+
+    #[test]
+    fn encode() {
+        let q = query_bytes(0xabcd, 0x0100, 1, "example.com", TYPE_A);
+        let query = match decode_query(&q) {
+            Action::Resolve(query, _) => query,
+            x => panic!("{:?}", x),
+        };
+        let answers = vec![
+            Answer::Addr(IpAddr::from([10, 0, 0, 1]), 1),
+            Answer::Addr(IpAddr::from([10, 0, 0, 2]), 1_000_000),
+        ];
+        let resp = encode_response(&query, Rcode::NoError, &answers);
+        // Header: same ID, QR|RD|RA (but not TC), one question, two answers.
+        assert_eq!(
+            &resp[..12],
+            &[0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]
+        );
+        let rest = &resp[q.len()..];
+        assert_eq!(
+            rest,
+            &[
+                0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1, // first
+                0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 10, 0, 0, 2, // second
+            ][..]
+        );
+
+        // A PTR answer.
+        let resp = encode_response(
+            &query,
+            Rcode::NoError,
+            &[Answer::Name("a.example".into(), 300)],
+        );
+        assert_eq!(
+            &resp[q.len()..],
+            &[
+                0xc0, 0x0c, 0, 12, 0, 1, 0, 0, 1, 44, 0, 11, 1, b'a', 7, b'e', b'x', b'a', b'm',
+                b'p', b'l', b'e', 0
+            ][..]
+        );
+
+        // An error, with no question.
+        let q = query_bytes(5, 0, 2, "example.com", TYPE_A);
+        let query = match decode_query(&q) {
+            Action::Reply(query, rcode) => {
+                assert_eq!(rcode, Rcode::Refused);
+                query
+            }
+            x => panic!("{:?}", x),
+        };
+        let resp = encode_response(&query, Rcode::Refused, &[]);
+        assert_eq!(&resp[..], &[0, 5, 0x80, 0x85, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_truncates() {
+        let q = query_bytes(1, 0, 1, "example.com", TYPE_AAAA);
+        let query = match decode_query(&q) {
+            Action::Resolve(query, _) => query,
+            x => panic!("{:?}", x),
+        };
+        let answers: Vec<_> = (0..100_u16)
+            .map(|i| Answer::Addr(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, i]), 600))
+            .collect();
+        let resp = encode_response(&query, Rcode::NoError, &answers);
+        assert!(resp.len() <= MAX_RESPONSE_LEN);
+        let flags = u16::from_be_bytes([resp[2], resp[3]]);
+        assert_eq!(flags, 0x8280); // QR|TC|RA
+        let n_answers = u16::from_be_bytes([resp[6], resp[7]]);
+        assert_eq!(usize::from(n_answers), (MAX_RESPONSE_LEN - q.len()) / 28);
+        assert_eq!(resp.len(), q.len() + usize::from(n_answers) * 28);
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let guards: Vec<_> = (0..MAX_IN_FLIGHT)
+            .map(|_| InFlight::try_new(&count).unwrap())
+            .collect();
+        assert!(InFlight::try_new(&count).is_none());
+        assert_eq!(count.load(Ordering::SeqCst), MAX_IN_FLIGHT);
+        drop(guards);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(InFlight::try_new(&count).is_some());
+    }
+}
@@ -62,7 +62,8 @@
 //!
 //! There are many missing features.  Among them: there's no onion
 //! service support yet. There's no anti-censorship support.  You
-//! can't be a relay.  There isn't any kind of proxy besides SOCKS.
+//! can't be a relay.  There isn't any kind of proxy besides SOCKS (and
+//! a DNS port that can only answer A, AAAA, and PTR queries).
 //!
 //! See the [README
 //! file](https://gitlab.torproject.org/tpo/core/arti/-/blob/main/README.md)
@@ -101,15 +102,18 @@
 #![warn(clippy::unseparated_literal_suffix)]
 #![deny(clippy::unwrap_used)]
 
+mod copy;
+mod dns;
 mod exit;
 mod process;
 mod proxy;
+mod safety;
 mod trace;
 mod watch_cfg;
 
 use arti_client::{TorClient, TorClientConfig};
 use arti_config::{default_config_file, ArtiConfig};
-use tor_rtcompat::{BlockOn, Runtime};
+use tor_rtcompat::{BlockOn, UdpProvider};
 
 use anyhow::{Context, Result};
 use clap::{App, AppSettings, Arg, SubCommand};
@@ -118,9 +122,14 @@ use tracing::{info, warn};
 use std::convert::TryInto;
 
 /// Run the main loop of the proxy.
-async fn run<R: Runtime>(
+///
+/// We accept SOCKS connections on each of `socks_listeners`.  If `dns_port`
+/// is set, we also answer DNS queries on that port, at the address that
+/// `arti_config` says to listen on.
+async fn run<R: proxy::ProxyRuntime + UdpProvider>(
     runtime: R,
-    socks_port: u16,
+    socks_listeners: Vec<proxy::SocksListener>,
+    dns_port: Option<u16>,
     config_sources: arti_config::ConfigurationSources,
     arti_config: arti_config::ArtiConfig,
     client_config: TorClientConfig,
@@ -133,23 +142,75 @@ async fn run<R: Runtime>(
         .config(client_config)
         .bootstrap_behavior(OnDemand)
         .create_unbootstrapped()?;
+    let dns_listen = arti_config.proxy().dns_listen();
+    let socks_buffer_pool_cap = arti_config.proxy().socks_buffer_pool_cap();
     if arti_config.application().watch_configuration() {
         watch_cfg::watch_for_config_changes(config_sources, arti_config, client.clone())?;
     }
     futures::select!(
         r = exit::wait_for_ctrl_c().fuse()
             => r.context("waiting for termination signal"),
-        r = proxy::run_socks_proxy(runtime, client.clone(), socks_port).fuse()
+        r = proxy::run_socks_proxy(
+            runtime.clone(),
+            client.clone(),
+            socks_listeners,
+            socks_buffer_pool_cap,
+        ).fuse()
             => r.context("SOCKS proxy failure"),
+        r = async {
+            match dns_port {
+                Some(port) => {
+                    dns::run_dns_resolver(runtime, client.clone(), dns_listen, port).await
+                }
+                None => futures::future::pending::<Result<()>>().await,
+            }
+        }.fuse()
+            => r.context("DNS resolver failure"),
         r = async {
             client.bootstrap().await?;
-            info!("Sufficiently bootstrapped; system SOCKS now functional.");
             futures::future::pending::<Result<()>>().await
         }.fuse()
             => r.context("bootstrap"),
+        r = async {
+            log_bootstrap_events(client.bootstrap_events()).await;
+            futures::future::pending::<Result<()>>().await
+        }.fuse()
+            => r.context("bootstrap status"),
     )
 }
 
+/// Log changes in our bootstrap status, until `events` is closed.
+///
+/// We only log a status when the percentage changes, or when we become stuck,
+/// so that fetching each batch of microdescriptors doesn't produce a new line.
+async fn log_bootstrap_events(mut events: arti_client::status::BootstrapEvents) {
+    use futures::StreamExt;
+    let mut last_percent = None;
+    let mut was_blocked = false;
+    let mut was_ready = false;
+    while let Some(status) = events.next().await {
+        let blocked = status.blocked();
+        if let Some(problem) = &blocked {
+            if !was_blocked {
+                warn!("Bootstrap is stuck: {}", problem);
+            }
+        } else {
+            let percent = (status.as_frac() * 100.0).round() as u32;
+            if last_percent != Some(percent) {
+                info!("Bootstrapped {}", status);
+                last_percent = Some(percent);
+            }
+        }
+        was_blocked = blocked.is_some();
+
+        let ready = status.ready_for_traffic();
+        if ready && !was_ready {
+            info!("Sufficiently bootstrapped; system SOCKS now functional.");
+        }
+        was_ready = ready;
+    }
+}
+
 fn main() {
     main_main().unwrap_or_else(tor_error::report_and_exit);
 }
@@ -216,6 +277,13 @@ fn main_main() -> Result<()> {
                             .value_name("PORT")
                             .help("Port to listen on for SOCKS connections (overrides the port in the config if specified).")
                     )
+                    .arg(
+                        Arg::with_name("dns-port")
+                            .short("d")
+                            .takes_value(true)
+                            .value_name("PORT")
+                            .help("Port to listen on for DNS queries (overrides the port in the config if specified).")
+                    )
             )
             .setting(AppSettings::SubcommandRequiredElseHelp)
             .get_matches();
@@ -246,27 +314,38 @@ fn main_main() -> Result<()> {
 
     let _log_guards = trace::setup_logging(config.logging(), matches.value_of("loglevel"))?;
 
+    safety::check_not_root(config.application().allow_running_as_root())?;
+
     if let Some(proxy_matches) = matches.subcommand_matches("proxy") {
-        let socks_port = match (
-            proxy_matches.value_of("socks-port"),
-            config.proxy().socks_port(),
-        ) {
-            (Some(p), _) => p.parse().expect("Invalid port specified"),
-            (None, Some(s)) => s,
-            (None, None) => {
-                warn!(
-                "No SOCKS port set; specify -p PORT or use the `socks_port` configuration option."
+        let socks_port = match proxy_matches.value_of("socks-port") {
+            Some(p) => Some(p.parse().expect("Invalid port specified")),
+            None => config.proxy().socks_port(),
+        };
+        let socks_listeners = proxy::SocksListener::from_config(config.proxy(), socks_port);
+        if socks_listeners.is_empty() {
+            warn!(
+                "No SOCKS port set; specify -p PORT or use the `socks_port` or `socks_listen` configuration options."
             );
-                return Ok(());
-            }
+            return Ok(());
+        }
+
+        let dns_port = match proxy_matches.value_of("dns-port") {
+            Some(p) => Some(p.parse().expect("Invalid port specified")),
+            None => config.proxy().dns_port(),
         };
 
         let client_config = config.tor_client_config()?;
 
+        {
+            let strict = config.application().strict_directory_permissions();
+            let storage = config.storage();
+            safety::check_private_dir(&storage.expand_state_dir()?, strict)?;
+            safety::check_private_dir(&storage.expand_cache_dir()?, strict)?;
+        }
+
         info!(
-            "Starting Arti {} in SOCKS proxy mode on port {}...",
-            env!("CARGO_PKG_VERSION"),
-            socks_port
+            "Starting Arti {} in SOCKS proxy mode...",
+            env!("CARGO_PKG_VERSION")
         );
 
         process::use_max_file_limit(&client_config);
@@ -277,16 +356,23 @@ fn main_main() -> Result<()> {
             } else if #[cfg(all(feature="tokio", feature="rustls"))] {
                 use tor_rtcompat::tokio::TokioRustlsRuntime as ChosenRuntime;
             } else if #[cfg(all(feature="async-std", feature="native-tls"))] {
-                use tor_rtcompat::tokio::TokioRustlsRuntime as ChosenRuntime;
+                use tor_rtcompat::async_std::AsyncStdNativeTlsRuntime as ChosenRuntime;
             } else if #[cfg(all(feature="async-std", feature="rustls"))] {
-                use tor_rtcompat::tokio::TokioRustlsRuntime as ChosenRuntime;
+                use tor_rtcompat::async_std::AsyncStdRustlsRuntime as ChosenRuntime;
             }
         }
 
         let runtime = ChosenRuntime::create()?;
 
         let rt_copy = runtime.clone();
-        rt_copy.block_on(run(runtime, socks_port, cfg_sources, config, client_config))?;
+        rt_copy.block_on(run(
+            runtime,
+            socks_listeners,
+            dns_port,
+            cfg_sources,
+            config,
+            client_config,
+        ))?;
         Ok(())
     } else {
         panic!("Subcommand added to clap subcommand list, but not yet implemented")
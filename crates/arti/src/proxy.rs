@@ -5,19 +5,21 @@
 
 use futures::future::FutureExt;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error as IoError};
-use futures::stream::StreamExt;
+use futures::stream::{LocalBoxStream, StreamExt};
 use futures::task::SpawnExt;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Result as IoResult;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{self, Arc};
 use std::time::{Duration, Instant};
 use tracing::{error, info, warn};
 
+use crate::copy::{copy_buffered_interactive, copy_interactive, BufferPool};
 use arti_client::{ErrorKind, HasKind, IsolationToken, StreamPrefs, TorClient};
+use arti_config::{ListenAddr, ProxyConfig, SocksListenerConfig, SocksValidationMode};
 use tor_rtcompat::{Runtime, TcpListener};
-use tor_socksproto::{SocksAddr, SocksAuth, SocksCmd, SocksRequest};
+use tor_socksproto::{SocksAddr, SocksAuth, SocksCmd, SocksRequest, SocksValidation};
 
 use anyhow::{anyhow, Context, Result};
 
@@ -45,8 +47,234 @@ fn stream_preference(req: &SocksRequest, addr: &str) -> StreamPrefs {
 ///
 /// Composed of an usize (representing which listener socket accepted
 /// the connection, the source IpAddr of the client, and the
-/// authentication string provided by the client).
-type IsolationKey = (usize, IpAddr, SocksAuth);
+/// authentication string provided by the client).  The first two are
+/// `None` when we've been configured not to isolate on them.
+type IsolationKey = (Option<usize>, Option<IpAddr>, SocksAuth);
+
+/// Which properties of an incoming SOCKS connection, other than its
+/// authentication, decide which circuits it may share.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SocksIsolation {
+    /// If true, connections from different client addresses never share a
+    /// circuit.
+    client_addr: bool,
+    /// If true, connections that arrive on different listeners never share
+    /// a circuit.
+    listener: bool,
+}
+
+impl SocksIsolation {
+    /// Return the isolation settings from a given proxy configuration.
+    pub(crate) fn from_config(config: &ProxyConfig) -> Self {
+        SocksIsolation {
+            client_addr: config.socks_isolate_client_addr(),
+            listener: config.socks_isolate_listener(),
+        }
+    }
+
+    /// Return these isolation settings, as overridden by the configuration
+    /// for a single listener.
+    fn with_overrides(self, config: &SocksListenerConfig) -> Self {
+        SocksIsolation {
+            client_addr: config.isolate_client_addr().unwrap_or(self.client_addr),
+            listener: config.isolate_listener().unwrap_or(self.listener),
+        }
+    }
+
+    /// Return the parts of an [`IsolationKey`] that describe a connection
+    /// from `source` that arrived on the listener with id `listener_id`.
+    ///
+    /// `source` is None for connections that have no IP address, like
+    /// those on Unix domain sockets.
+    fn connection_info(&self, listener_id: usize, source: Option<IpAddr>) -> ConnIsolationInfo {
+        (
+            self.listener.then_some(listener_id),
+            source.filter(|_| self.client_addr),
+        )
+    }
+}
+
+/// A place where our SOCKS proxy should listen for connections.
+#[derive(Debug, Clone)]
+pub(crate) struct SocksListener {
+    /// The address to listen on.
+    addr: ListenAddr,
+    /// How to isolate the connections that arrive on this listener.
+    isolation: SocksIsolation,
+    /// How strictly to check the handshakes of connections that arrive on
+    /// this listener.
+    validation: SocksValidation,
+    /// Whether we can run without this listener.
+    need: ListenerNeed,
+}
+
+/// Whether we can run without a given [`SocksListener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListenerNeed {
+    /// We can't run unless we can listen here.
+    Required,
+    /// This is one of the localhost listeners for `socks_port`: we can't run
+    /// unless we can listen on at least one of them.
+    Localhost,
+    /// We can run without this listener.
+    Optional,
+}
+
+impl SocksListener {
+    /// Return every listener that `config` tells us to open.
+    ///
+    /// If `socks_port` is present, we listen on that port at localhost, as
+    /// well as at the addresses in `config.socks_listen()`.  We only need
+    /// one of the localhost listeners to work, since not every host has
+    /// IPv6 (or IPv4) configured, but we do need one.  The other listeners
+    /// are required unless the configuration says that they're best-effort.
+    pub(crate) fn from_config(config: &ProxyConfig, socks_port: Option<u16>) -> Vec<Self> {
+        let isolation = SocksIsolation::from_config(config);
+        let validation = socks_validation(config.socks_validation());
+        let localhosts: [IpAddr; 2] = [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
+        let localhost = socks_port.into_iter().flat_map(|port| {
+            localhosts.iter().map(move |ip| SocksListener {
+                addr: ListenAddr::Tcp((*ip, port).into()),
+                isolation,
+                validation,
+                need: ListenerNeed::Localhost,
+            })
+        });
+        let configured = config.socks_listen().iter().map(|l| SocksListener {
+            addr: l.address().clone(),
+            isolation: isolation.with_overrides(l),
+            validation,
+            need: if config.socks_listen_best_effort() {
+                ListenerNeed::Optional
+            } else {
+                ListenerNeed::Required
+            },
+        });
+        localhost.chain(configured).collect()
+    }
+}
+
+/// Return the SOCKS handshake checks that correspond to `mode`.
+fn socks_validation(mode: SocksValidationMode) -> SocksValidation {
+    match mode {
+        SocksValidationMode::Strict => SocksValidation::strict(),
+        SocksValidationMode::Lenient => SocksValidation::lenient(),
+        _ => SocksValidation::default(),
+    }
+}
+
+/// A runtime that our SOCKS proxy can use.
+///
+/// On platforms with Unix domain sockets, the runtime must be able to
+/// listen on them.
+#[cfg(unix)]
+pub(crate) trait ProxyRuntime: Runtime + tor_rtcompat::UnixProvider {}
+#[cfg(unix)]
+impl<R: Runtime + tor_rtcompat::UnixProvider> ProxyRuntime for R {}
+
+/// A runtime that our SOCKS proxy can use.
+#[cfg(not(unix))]
+pub(crate) trait ProxyRuntime: Runtime {}
+#[cfg(not(unix))]
+impl<R: Runtime> ProxyRuntime for R {}
+
+/// A connection accepted by any kind of listener.
+trait SocksStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static {}
+impl<S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static> SocksStream for S {}
+
+/// The connections that arrive on a single listener, along with their
+/// client IP addresses (if they have any).
+type IncomingConns = LocalBoxStream<'static, IoResult<(Box<dyn SocksStream>, Option<IpAddr>)>>;
+
+/// A Unix domain socket file that we remove when we stop listening on it.
+#[cfg(unix)]
+struct UnixSocketGuard(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Couldn't remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Listen on the Unix domain socket at `path`, which only our own user may
+/// connect to.
+///
+/// If there's a stale socket at `path` that nobody is listening on, we
+/// replace it.  We remove the socket again when the returned stream is
+/// dropped.
+///
+/// The socket never appears at `path` with looser permissions: we create
+/// it inside a new directory that only we can use, restrict it, and only
+/// then move it into place.
+#[cfg(unix)]
+async fn listen_unix<R: ProxyRuntime>(
+    runtime: &R,
+    path: &std::path::Path,
+) -> Result<IncomingConns> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use tor_rtcompat::UnixListener;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists, and is not a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "Something is already listening on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path).context("Removing stale socket")?;
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file name", path.display()))?;
+    let mut private_dir_name = std::ffi::OsString::from(".");
+    private_dir_name.push(name);
+    private_dir_name.push(format!(".{}", std::process::id()));
+    let private_dir = path.with_file_name(private_dir_name);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("Creating {}", private_dir.display()))?;
+    let private_path = private_dir.join("s");
+
+    let listener = match runtime.listen_unix(&private_path).await {
+        Ok(listener) => {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))
+                .context("Restricting socket permissions")
+                .and_then(|()| {
+                    std::fs::rename(&private_path, path).context("Moving socket into place")
+                })
+                .map(|()| listener)
+        }
+        Err(e) => Err(e.into()),
+    };
+    // If we failed, the socket may still be in the private directory.
+    let _ = std::fs::remove_file(&private_path);
+    if let Err(e) = std::fs::remove_dir(&private_dir) {
+        warn!("Couldn't remove {}: {}", private_dir.display(), e);
+    }
+    let listener = listener?;
+    let guard = UnixSocketGuard(path.to_owned());
+
+    Ok(listener
+        .incoming()
+        .map(move |r| {
+            // The stream owns the guard, so the socket lives as long as it.
+            let _guard = &guard;
+            r.map(|s| (Box::new(s) as Box<dyn SocksStream>, None))
+        })
+        .boxed_local())
+}
+
+/// The parts of an [`IsolationKey`] that we know as soon as we accept a
+/// connection.
+type ConnIsolationInfo = (Option<usize>, Option<IpAddr>);
 
 /// Shared and garbage-collected Map used to isolate connections.
 struct IsolationMap {
@@ -98,36 +326,46 @@ impl IsolationMap {
     }
 }
 
-/// Given a just-received TCP connection `S` on a SOCKS port, handle the
-/// SOCKS handshake and relay the connection over the Tor network.
+/// Return the isolation token to use for `request`, which arrived on a
+/// connection described by `isolation_info`.
 ///
-/// Uses `isolation_map` to decide which circuits circuits this connection
-/// may use.  Requires that `isolation_info` is a pair listing the listener
-/// id and the source address for the socks request.
-async fn handle_socks_conn<R, S>(
-    runtime: R,
-    tor_client: TorClient<R>,
-    socks_stream: S,
-    isolation_map: Arc<IsolationMap>,
-    isolation_info: (usize, IpAddr),
-) -> Result<()>
+/// Two requests get the same token (and may thus share a circuit) only if
+/// they have the same SOCKS authentication and the same `isolation_info`.
+/// Requests without authentication all count as having the same
+/// authentication.
+fn request_isolation(
+    isolation_map: &IsolationMap,
+    isolation_info: ConnIsolationInfo,
+    request: &SocksRequest,
+    now: Instant,
+) -> IsolationToken {
+    let (listener, ip) = isolation_info;
+    let auth = request.auth().clone();
+    isolation_map.get_or_create((listener, ip, auth), now)
+}
+
+/// Run the SOCKS handshake for a new connection, reading from `socks_r` and
+/// replying on `socks_w`, and return the request that the client made.
+///
+/// The handshake is checked as strictly as `validation` says.  Returns None
+/// if the handshake finished, but didn't give us a request.
+async fn read_socks_request<R, W>(
+    socks_r: &mut R,
+    socks_w: &mut W,
+    validation: SocksValidation,
+) -> Result<Option<SocksRequest>>
 where
-    R: Runtime,
-    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
-    // Part 1: Perform the SOCKS handshake, to learn where we are
-    // being asked to connect, and what we're being asked to do once
-    // we connect there.
-    //
     // The SOCKS handshake can require multiple round trips (SOCKS5
     // always does) so we we need to run this part of the process in a
     // loop.
-    let mut handshake = tor_socksproto::SocksHandshake::new();
+    let mut handshake = tor_socksproto::SocksHandshake::with_validation(validation);
 
-    let (mut socks_r, mut socks_w) = socks_stream.split();
     let mut inbuf = [0_u8; 1024];
     let mut n_read = 0;
-    let request = loop {
+    loop {
         // Read some more stuff.
         n_read += socks_r
             .read(&mut inbuf[n_read..])
@@ -138,39 +376,7 @@ where
         let action = match handshake.handshake(&inbuf[..n_read]) {
             Err(_) => continue, // Message truncated.
             Ok(Err(e)) => {
-                if let tor_socksproto::Error::BadProtocol(version) = e {
-                    // check for HTTP methods: CONNECT, DELETE, GET, HEAD, OPTION, PUT, POST, PATCH and
-                    // TRACE.
-                    // To do so, check the first byte of the connection, which happen to be placed
-                    // where SOCKs version field is.
-                    if [b'C', b'D', b'G', b'H', b'O', b'P', b'T'].contains(&version) {
-                        let payload = br#"HTTP/1.0 501 Tor is not an HTTP Proxy
-Content-Type: text/html; charset=utf-8
-
-<!DOCTYPE html>
-<html>
-<head>
-<title>This is a SOCKS Proxy, Not An HTTP Proxy</title>
-</head>
-<body>
-<h1>This is a SOCKs proxy, not an HTTP proxy.</h1>
-<p>
-It appears you have configured your web browser to use this Tor port as
-an HTTP proxy.
-</p><p>
-This is not correct: This port is configured as a SOCKS proxy, not
-an HTTP proxy. If you need an HTTP proxy tunnel, wait for Arti to
-add support for it in place of, or in addition to, socks_port.
-Please configure your client accordingly.
-</p>
-<p>
-See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-when-arti-get-a-user-documentation">https://gitlab.torproject.org/tpo/core/arti</a> for more information.
-</p>
-</body>
-</html>"#;
-                        socks_w.write_all(payload).await?;
-                    }
-                }
+                reply_to_handshake_error(socks_w, &handshake, &e).await?;
                 return Err(e.into());
             }
             Ok(Ok(action)) => action,
@@ -182,12 +388,41 @@ See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-wh
             n_read -= action.drain;
         }
         if !action.reply.is_empty() {
-            write_all_and_flush(&mut socks_w, &action.reply).await?;
+            write_all_and_flush(socks_w, &action.reply).await?;
         }
         if action.finished {
-            break handshake.into_request();
+            return Ok(handshake.into_request());
         }
-    };
+    }
+}
+
+/// Given a just-received TCP connection `S` on a SOCKS port, handle the
+/// SOCKS handshake and relay the connection over the Tor network.
+///
+/// Uses `isolation_map` to decide which circuits circuits this connection
+/// may use.  Requires that `isolation_info` is a pair listing the listener
+/// id and the source address for the socks request, to the extent that
+/// we're configured to isolate on them.  Checks the SOCKS handshake as
+/// strictly as `validation` says.  Takes buffers for relaying traffic from
+/// `buffers`.
+async fn handle_socks_conn<R, S>(
+    runtime: R,
+    tor_client: TorClient<R>,
+    socks_stream: S,
+    isolation_map: Arc<IsolationMap>,
+    isolation_info: ConnIsolationInfo,
+    validation: SocksValidation,
+    buffers: Arc<BufferPool>,
+) -> Result<()>
+where
+    R: Runtime,
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    // Part 1: Perform the SOCKS handshake, to learn where we are
+    // being asked to connect, and what we're being asked to do once
+    // we connect there.
+    let (mut socks_r, mut socks_w) = socks_stream.split();
+    let request = read_socks_request(&mut socks_r, &mut socks_w, validation).await?;
     let request = match request {
         Some(r) => r,
         None => {
@@ -210,9 +445,8 @@ See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-wh
     // to determine the stream's isolation properties.  (Our current
     // rule is that two streams may only share a circuit if they have
     // the same values for all of these properties.)
-    let auth = request.auth().clone();
-    let (source_address, ip) = isolation_info;
-    let isolation_token = isolation_map.get_or_create((source_address, ip, auth), Instant::now());
+    let isolation_token =
+        request_isolation(&isolation_map, isolation_info, &request, Instant::now());
 
     // Determine whether we want to ask for IPv4/IPv6 addresses.
     let mut prefs = stream_preference(&request, &addr);
@@ -255,41 +489,19 @@ See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-wh
 
             // Finally, spawn two background tasks to relay traffic between
             // the socks stream and the tor stream.
-            runtime.spawn(copy_interactive(socks_r, tor_w).map(|_| ()))?;
-            runtime.spawn(copy_interactive(tor_r, socks_w).map(|_| ()))?;
+            //
+            // The tor stream keeps each cell's data in its own buffer, so we
+            // can write straight from there.  In the other direction, we need
+            // buffers of our own, which we borrow from `buffers` only while
+            // data is moving.
+            runtime.spawn(copy_interactive(socks_r, tor_w, buffers).map(|_| ()))?;
+            runtime.spawn(copy_buffered_interactive(tor_r, socks_w).map(|_| ()))?;
         }
         SocksCmd::RESOLVE => {
-            // We've been asked to perform a regular hostname lookup.
-            // (This is a tor-specific SOCKS extension.)
-            let addrs = tor_client.resolve_with_prefs(&addr, &prefs).await?;
-            if let Some(addr) = addrs.first() {
-                let reply = request.reply(
-                    tor_socksproto::SocksStatus::SUCCEEDED,
-                    Some(&SocksAddr::Ip(*addr)),
-                );
-                write_all_and_flush(&mut socks_w, &reply[..]).await?;
-            }
+            handle_socks_resolve(&tor_client, &request, &addr, &prefs, &mut socks_w).await?;
         }
         SocksCmd::RESOLVE_PTR => {
-            // We've been asked to perform a reverse hostname lookup.
-            // (This is a tor-specific SOCKS extension.)
-            let addr: IpAddr = match addr.parse() {
-                Ok(ip) => ip,
-                Err(e) => {
-                    let reply =
-                        request.reply(tor_socksproto::SocksStatus::ADDRTYPE_NOT_SUPPORTED, None);
-                    write_all_and_close(&mut socks_w, &reply[..]).await?;
-                    return Err(anyhow!(e));
-                }
-            };
-            let hosts = tor_client.resolve_ptr_with_prefs(addr, &prefs).await?;
-            if let Some(host) = hosts.into_iter().next() {
-                let reply = request.reply(
-                    tor_socksproto::SocksStatus::SUCCEEDED,
-                    Some(&SocksAddr::Hostname(host.try_into()?)),
-                );
-                write_all_and_flush(&mut socks_w, &reply[..]).await?;
-            }
+            handle_socks_resolve_ptr(&tor_client, &request, &addr, &prefs, &mut socks_w).await?;
         }
         _ => {
             // We don't support this SOCKS command.
@@ -305,6 +517,175 @@ See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-wh
     Ok(())
 }
 
+/// Answer a SOCKS RESOLVE `request` for the hostname `addr`, writing our
+/// reply to `writer`.
+async fn handle_socks_resolve<R, W>(
+    tor_client: &TorClient<R>,
+    request: &SocksRequest,
+    addr: &str,
+    prefs: &StreamPrefs,
+    writer: &mut W,
+) -> Result<()>
+where
+    R: Runtime,
+    W: AsyncWrite + Unpin,
+{
+    // We've been asked to perform a regular hostname lookup.
+    // (This is a tor-specific SOCKS extension.)
+    //
+    // If `addr` is already an IP address, the client just gives it
+    // back without touching the network.
+    let addrs = match tor_client.resolve_with_prefs(addr, prefs).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            let reply = request.reply(resolve_failure_status(e.kind()), None);
+            write_all_and_close(writer, &reply[..]).await?;
+            return Err(anyhow!(e));
+        }
+    };
+    match first_reply_addr(request, &addrs) {
+        Some(addr) => {
+            let reply = request.reply(
+                tor_socksproto::SocksStatus::SUCCEEDED,
+                Some(&SocksAddr::Ip(addr)),
+            );
+            write_all_and_flush(writer, &reply[..]).await?;
+        }
+        None => {
+            let reply = request.reply(tor_socksproto::SocksStatus::HOST_UNREACHABLE, None);
+            write_all_and_close(writer, &reply[..]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer a SOCKS RESOLVE_PTR `request` for the address `addr`, writing our
+/// reply to `writer`.
+async fn handle_socks_resolve_ptr<R, W>(
+    tor_client: &TorClient<R>,
+    request: &SocksRequest,
+    addr: &str,
+    prefs: &StreamPrefs,
+    writer: &mut W,
+) -> Result<()>
+where
+    R: Runtime,
+    W: AsyncWrite + Unpin,
+{
+    // We've been asked to perform a reverse hostname lookup.
+    // (This is a tor-specific SOCKS extension.)
+    let addr: IpAddr = match addr.parse() {
+        Ok(ip) => ip,
+        Err(e) => {
+            let reply = request.reply(tor_socksproto::SocksStatus::ADDRTYPE_NOT_SUPPORTED, None);
+            write_all_and_close(writer, &reply[..]).await?;
+            return Err(anyhow!(e));
+        }
+    };
+    let hosts = match tor_client.resolve_ptr_with_prefs(addr, prefs).await {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            let reply = request.reply(resolve_failure_status(e.kind()), None);
+            write_all_and_close(writer, &reply[..]).await?;
+            return Err(anyhow!(e));
+        }
+    };
+    // Answer with the first hostname that fits in a SOCKS reply.
+    match hosts.into_iter().find_map(|h| h.try_into().ok()) {
+        Some(host) => {
+            let reply = request.reply(
+                tor_socksproto::SocksStatus::SUCCEEDED,
+                Some(&SocksAddr::Hostname(host)),
+            );
+            write_all_and_flush(writer, &reply[..]).await?;
+        }
+        None => {
+            let reply = request.reply(tor_socksproto::SocksStatus::HOST_UNREACHABLE, None);
+            write_all_and_close(writer, &reply[..]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The page we send to a client that tries to use us as an HTTP proxy.
+const NOT_AN_HTTP_PROXY_PAYLOAD: &[u8] = br#"HTTP/1.0 501 Tor is not an HTTP Proxy
+Content-Type: text/html; charset=utf-8
+
+<!DOCTYPE html>
+<html>
+<head>
+<title>This is a SOCKS Proxy, Not An HTTP Proxy</title>
+</head>
+<body>
+<h1>This is a SOCKs proxy, not an HTTP proxy.</h1>
+<p>
+It appears you have configured your web browser to use this Tor port as
+an HTTP proxy.
+</p><p>
+This is not correct: This port is configured as a SOCKS proxy, not
+an HTTP proxy. If you need an HTTP proxy tunnel, wait for Arti to
+add support for it in place of, or in addition to, socks_port.
+Please configure your client accordingly.
+</p>
+<p>
+See <a href="https://gitlab.torproject.org/tpo/core/arti/#todo-need-to-change-when-arti-get-a-user-documentation">https://gitlab.torproject.org/tpo/core/arti</a> for more information.
+</p>
+</body>
+</html>"#;
+
+/// Tell a client whose SOCKS handshake failed with `err` what went wrong,
+/// as best we can.
+///
+/// A client that seems to be speaking HTTP gets an explanatory page.  A
+/// client whose request we refused gets a refusal, in whichever version of
+/// SOCKS it's speaking.
+async fn reply_to_handshake_error<W>(
+    writer: &mut W,
+    handshake: &tor_socksproto::SocksHandshake,
+    err: &tor_socksproto::Error,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if let tor_socksproto::Error::BadProtocol(version) = err {
+        // check for HTTP methods: CONNECT, DELETE, GET, HEAD, OPTION, PUT, POST, PATCH and
+        // TRACE.
+        // To do so, check the first byte of the connection, which happen to be placed
+        // where SOCKs version field is.
+        if [b'C', b'D', b'G', b'H', b'O', b'P', b'T'].contains(version) {
+            writer.write_all(NOT_AN_HTTP_PROXY_PAYLOAD).await?;
+        }
+    }
+    if let Some(reply) = handshake.failure_reply() {
+        write_all_and_close(writer, reply).await?;
+    }
+    Ok(())
+}
+
+/// Return the SOCKS status to report when a RESOLVE or RESOLVE_PTR request
+/// fails with an error of kind `kind`.
+///
+/// If the exit told us that the lookup failed for good, the host is
+/// unreachable.  Anything else, including a timeout or a failure that the
+/// exit says is transient, is a general failure: the client may try again.
+fn resolve_failure_status(kind: ErrorKind) -> tor_socksproto::SocksStatus {
+    match kind {
+        ErrorKind::RemoteHostNotFound => tor_socksproto::SocksStatus::HOST_UNREACHABLE,
+        _ => tor_socksproto::SocksStatus::GENERAL_FAILURE,
+    }
+}
+
+/// Return the first address in `addrs` that we can put in a reply to
+/// `request`.
+///
+/// (A SOCKS4 reply only has room for an IPv4 address.)
+fn first_reply_addr(request: &SocksRequest, addrs: &[IpAddr]) -> Option<IpAddr> {
+    addrs
+        .iter()
+        .find(|a| request.version() != tor_socksproto::SocksVersion::V4 || a.is_ipv4())
+        .copied()
+}
+
 /// write_all the data to the writer & flush the writer if write_all is successful.
 async fn write_all_and_flush<W>(writer: &mut W, buf: &[u8]) -> Result<()>
 where
@@ -335,65 +716,6 @@ where
         .context("Error while closing SOCKS stream")
 }
 
-/// Copy all the data from `reader` into `writer` until we encounter an EOF or
-/// an error.
-///
-/// Unlike as futures::io::copy(), this function is meant for use with
-/// interactive readers and writers, where the reader might pause for
-/// a while, but where we want to send data on the writer as soon as
-/// it is available.
-///
-/// This function assumes that the writer might need to be flushed for
-/// any buffered data to be sent.  It tries to minimize the number of
-/// flushes, however, by only flushing the writer when the reader has no data.
-async fn copy_interactive<R, W>(mut reader: R, mut writer: W) -> IoResult<()>
-where
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
-{
-    use futures::{poll, task::Poll};
-
-    let mut buf = [0_u8; 1024];
-
-    // At this point we could just loop, calling read().await,
-    // write_all().await, and flush().await.  But we want to be more
-    // clever than that: we only want to flush when the reader is
-    // stalled.  That way we can pack our data into as few cells as
-    // possible, but flush it immediately whenever there's no more
-    // data coming.
-    let loop_result: IoResult<()> = loop {
-        let mut read_future = reader.read(&mut buf[..]);
-        match poll!(&mut read_future) {
-            Poll::Ready(Err(e)) => break Err(e),
-            Poll::Ready(Ok(0)) => break Ok(()), // EOF
-            Poll::Ready(Ok(n)) => {
-                writer.write_all(&buf[..n]).await?;
-                continue;
-            }
-            Poll::Pending => writer.flush().await?,
-        }
-
-        // The read future is pending, so we should wait on it.
-        match read_future.await {
-            Err(e) => break Err(e),
-            Ok(0) => break Ok(()),
-            Ok(n) => writer.write_all(&buf[..n]).await?,
-        }
-    };
-
-    // Make sure that we flush any lingering data if we can.
-    //
-    // If there is a difference between closing and dropping, then we
-    // only want to do a "proper" close if the reader closed cleanly.
-    let flush_result = if loop_result.is_ok() {
-        writer.close().await
-    } else {
-        writer.flush().await
-    };
-
-    loop_result.or(flush_result)
-}
-
 /// Return true if a given IoError, when received from accept, is a fatal
 /// error.
 fn accept_err_is_fatal(err: &IoError) -> bool {
@@ -415,58 +737,94 @@ fn accept_err_is_fatal(err: &IoError) -> bool {
     }
 }
 
-/// Launch a SOCKS proxy to listen on a given localhost port, and run
-/// indefinitely.
+/// Start listening at `addr`, and return a stream of the connections that
+/// arrive there.
+async fn open_listener<R: ProxyRuntime>(runtime: &R, addr: &ListenAddr) -> Result<IncomingConns> {
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let listener = runtime.listen(addr).await?;
+            Ok(listener
+                .incoming()
+                .map(|r| r.map(|(s, a)| (Box::new(s) as Box<dyn SocksStream>, Some(a.ip()))))
+                .boxed_local())
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => listen_unix(runtime, &path.path()?).await,
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(anyhow!(
+            "Unix domain sockets are not supported on this platform"
+        )),
+        _ => Err(anyhow!("Unsupported kind of listen address")),
+    }
+}
+
+/// Launch a SOCKS proxy on each of `listeners`, and run indefinitely.
 ///
 /// Requires a `runtime` to use for launching tasks and handling
 /// timeouts, and a `tor_client` to use in connecting over the Tor
-/// network.
-pub(crate) async fn run_socks_proxy<R: Runtime>(
+/// network.  Connections may only share circuits as allowed by the
+/// isolation settings of the listeners they arrive on, and may use at
+/// most `buffer_pool_cap` bytes of buffers between them to relay data.
+///
+/// Fails if we can't open a listener that is required, if we can't open
+/// any of the localhost listeners for the SOCKS port, or if we can't open
+/// any listeners at all.  Any Unix domain sockets we create are removed
+/// when this future returns or is dropped.
+pub(crate) async fn run_socks_proxy<R: ProxyRuntime>(
     runtime: R,
     tor_client: TorClient<R>,
-    socks_port: u16,
+    listeners: Vec<SocksListener>,
+    buffer_pool_cap: usize,
 ) -> Result<()> {
-    let mut listeners = Vec::new();
-
-    // We actually listen on two ports: one for ipv4 and one for ipv6.
-    let localhosts: [IpAddr; 2] = [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
-
-    // Try to bind to the SOCKS ports.
-    for localhost in &localhosts {
-        let addr: SocketAddr = (*localhost, socks_port).into();
-        match runtime.listen(&addr).await {
-            Ok(listener) => {
-                info!("Listening on {:?}.", addr);
-                listeners.push(listener);
+    let mut opened = Vec::new();
+    let want_localhost = listeners.iter().any(|l| l.need == ListenerNeed::Localhost);
+    let mut got_localhost = false;
+
+    // Try to bind to each of our addresses.
+    for listener in listeners {
+        match open_listener(&runtime, &listener.addr).await {
+            Ok(incoming) => {
+                info!("Listening on {}.", listener.addr);
+                got_localhost |= listener.need == ListenerNeed::Localhost;
+                opened.push((incoming, listener.isolation, listener.validation));
             }
-            Err(e) => warn!("Can't listen on {:?}: {}", addr, e),
+            Err(e) if listener.need == ListenerNeed::Required => {
+                error!("Can't listen on {}: {}", listener.addr, e);
+                return Err(e.context(format!("Couldn't listen on {}", listener.addr)));
+            }
+            Err(e) => warn!("Can't listen on {}: {}", listener.addr, e),
         }
     }
+    // The SOCKS port is configured, so somebody expects to find us there.
+    if want_localhost && !got_localhost {
+        error!("Couldn't listen on the SOCKS port at localhost.");
+        return Err(anyhow!("Couldn't listen on the SOCKS port at localhost"));
+    }
     // We weren't able to bind any ports: There's nothing to do.
-    if listeners.is_empty() {
+    if opened.is_empty() {
         error!("Couldn't open any listeners.");
         return Err(anyhow!("Couldn't open listeners"));
     }
 
-    // Create a stream of (incoming socket, listener_id) pairs, selected
-    // across all the listeners.
-    let mut incoming = futures::stream::select_all(
-        listeners
-            .into_iter()
-            .map(TcpListener::incoming)
-            .enumerate()
-            .map(|(listener_id, incoming_conns)| {
-                incoming_conns.map(move |socket| (socket, listener_id))
-            }),
-    );
+    // Create a stream of (incoming socket, listener_id, isolation,
+    // validation) tuples, selected across all the listeners.
+    let mut incoming = futures::stream::select_all(opened.into_iter().enumerate().map(
+        |(listener_id, (incoming_conns, isolation, validation))| {
+            incoming_conns.map(move |socket| (socket, listener_id, isolation, validation))
+        },
+    ));
 
     // Make a new IsolationMap; We'll use this to register which incoming
     // connections can and cannot share a circuit.
     let isolation_map = Arc::new(IsolationMap::new());
 
+    // All our connections share this limit on how much memory they can use
+    // for relaying data.
+    let buffers = Arc::new(BufferPool::new(buffer_pool_cap));
+
     // Loop over all incoming connections.  For each one, call
     // handle_socks_conn() in a new task.
-    while let Some((stream, sock_id)) = incoming.next().await {
+    while let Some((stream, sock_id, isolation, validation)) = incoming.next().await {
         let (stream, addr) = match stream {
             Ok((s, a)) => (s, a),
             Err(err) => {
@@ -481,13 +839,16 @@ pub(crate) async fn run_socks_proxy<R: Runtime>(
         let client_ref = tor_client.clone();
         let runtime_copy = runtime.clone();
         let isolation_map_ref = Arc::clone(&isolation_map);
+        let buffers_ref = Arc::clone(&buffers);
         runtime.spawn(async move {
             let res = handle_socks_conn(
                 runtime_copy,
                 client_ref,
                 stream,
                 isolation_map_ref,
-                (sock_id, addr.ip()),
+                isolation.connection_info(sock_id, addr),
+                validation,
+                buffers_ref,
             )
             .await;
             if let Err(e) = res {
@@ -508,10 +869,14 @@ mod test {
     fn test_isomap() {
         let m = IsolationMap::new();
 
-        let k1 = (6, "10.0.0.1".parse().unwrap(), SocksAuth::NoAuth);
+        let k1 = (
+            Some(6),
+            Some("10.0.0.1".parse().unwrap()),
+            SocksAuth::NoAuth,
+        );
         let k2 = (
-            6,
-            "10.0.0.1".parse().unwrap(),
+            Some(6),
+            Some("10.0.0.1".parse().unwrap()),
             SocksAuth::Socks4(vec![1, 2, 3]),
         );
 
@@ -534,4 +899,199 @@ mod test {
         assert_ne!(tok3, tok2);
         assert_ne!(tok3, tok1);
     }
+
+    /// Run a complete SOCKS handshake on `msgs`, and return the request.
+    fn request_from(msgs: &[&[u8]]) -> SocksRequest {
+        let mut h = tor_socksproto::SocksHandshake::new();
+        for msg in msgs {
+            let _action = h.handshake(msg).unwrap().unwrap();
+        }
+        h.into_request().unwrap()
+    }
+
+    #[test]
+    fn resolve_replies() {
+        use tor_socksproto::SocksStatus;
+
+        // SOCKS4a RESOLVE of "example.com"
+        let req4 = request_from(&[b"\x04\xf0\x00\x00\x00\x00\x00\x01\x00example.com\x00"]);
+        // SOCKS5 RESOLVE of "example.com"
+        let req5 = request_from(&[b"\x05\x01\x00", b"\x05\xf0\x00\x03\x0bexample.com\x00\x00"]);
+        assert_eq!(req4.command(), SocksCmd::RESOLVE);
+        assert_eq!(req5.command(), SocksCmd::RESOLVE);
+
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        let v4: IpAddr = "192.0.2.7".parse().unwrap();
+        let v4b: IpAddr = "192.0.2.8".parse().unwrap();
+        let answers = [v6, v4, v4b];
+
+        // SOCKS5 can take the first answer; SOCKS4 needs the first IPv4 one.
+        assert_eq!(first_reply_addr(&req5, &answers), Some(v6));
+        assert_eq!(first_reply_addr(&req4, &answers), Some(v4));
+        assert_eq!(first_reply_addr(&req4, &[v6]), None);
+        assert_eq!(first_reply_addr(&req5, &[]), None);
+
+        // The answer goes in the BND.ADDR field.
+        assert_eq!(
+            req4.reply(SocksStatus::SUCCEEDED, Some(&SocksAddr::Ip(v4))),
+            b"\x00\x5a\x00\x00\xc0\x00\x02\x07"
+        );
+        assert_eq!(
+            req5.reply(SocksStatus::SUCCEEDED, Some(&SocksAddr::Ip(v4))),
+            b"\x05\x00\x00\x01\xc0\x00\x02\x07\x00\x00"
+        );
+
+        assert_eq!(
+            resolve_failure_status(ErrorKind::RemoteHostNotFound),
+            SocksStatus::HOST_UNREACHABLE
+        );
+        assert_eq!(
+            resolve_failure_status(ErrorKind::TransientFailure),
+            SocksStatus::GENERAL_FAILURE
+        );
+        assert_eq!(
+            resolve_failure_status(ErrorKind::RemoteNetworkTimeout),
+            SocksStatus::GENERAL_FAILURE
+        );
+    }
+
+    #[test]
+    fn auth_isolation() {
+        /// Return a SOCKS5 CONNECT request to example.com:80, authenticated
+        /// with `user` and `pass`.
+        fn connect_as(user: &[u8], pass: &[u8]) -> SocksRequest {
+            let mut auth = vec![1, user.len() as u8];
+            auth.extend_from_slice(user);
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass);
+            request_from(&[
+                b"\x05\x01\x02",
+                &auth,
+                b"\x05\x01\x00\x03\x0bexample.com\x00\x50",
+            ])
+        }
+        let noauth =
+            || request_from(&[b"\x05\x01\x00", b"\x05\x01\x00\x03\x0bexample.com\x00\x50"]);
+
+        let m = IsolationMap::new();
+        let now = Instant::now();
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let all = SocksIsolation {
+            client_addr: true,
+            listener: true,
+        };
+        let info = all.connection_info(0, Some(ip1));
+
+        let alice = request_isolation(&m, info, &connect_as(b"alice", b"pw"), now);
+        let bob = request_isolation(&m, info, &connect_as(b"bob", b"pw"), now);
+        let alice2 = request_isolation(&m, info, &connect_as(b"alice", b"pw2"), now);
+        assert_ne!(alice, bob);
+        assert_ne!(alice, alice2);
+        assert_eq!(
+            alice,
+            request_isolation(&m, info, &connect_as(b"alice", b"pw"), now)
+        );
+
+        // Requests without auth isolate together, but not with anybody else.
+        let anon = request_isolation(&m, info, &noauth(), now);
+        assert_eq!(anon, request_isolation(&m, info, &noauth(), now));
+        assert_ne!(anon, alice);
+
+        // Client address and listener isolate when configured to...
+        assert_ne!(
+            anon,
+            request_isolation(&m, all.connection_info(0, Some(ip2)), &noauth(), now)
+        );
+        assert_ne!(
+            anon,
+            request_isolation(&m, all.connection_info(1, Some(ip1)), &noauth(), now)
+        );
+
+        // ...and not otherwise.
+        let none = SocksIsolation {
+            client_addr: false,
+            listener: false,
+        };
+        let anon = request_isolation(&m, none.connection_info(0, Some(ip1)), &noauth(), now);
+        assert_eq!(
+            anon,
+            request_isolation(&m, none.connection_info(1, Some(ip2)), &noauth(), now)
+        );
+        assert_ne!(
+            anon,
+            request_isolation(
+                &m,
+                none.connection_info(1, Some(ip2)),
+                &connect_as(b"alice", b"pw"),
+                now
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let dir = tempfile::TempDir::new().unwrap();
+            let path = dir.path().join("socks.sock");
+
+            let incoming = listen_unix(&rt, &path).await.unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            // We cleaned up the directory where we created the socket.
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+            assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+            // We can't listen twice in one place...
+            assert!(listen_unix(&rt, &path).await.is_err());
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+            // ...and the socket goes away when we stop listening.
+            drop(incoming);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn listeners_from_config() {
+        let unix = SocksListenerConfig::builder()
+            .address("unix:/tmp/arti.sock".parse().unwrap())
+            .isolate_client_addr(Some(false))
+            .build()
+            .unwrap();
+        let mut bld = ProxyConfig::builder();
+        bld.socks_isolate_listener(false).socks_listen(vec![unix]);
+        let cfg = bld.build().unwrap();
+
+        let listeners = SocksListener::from_config(&cfg, Some(9999));
+        assert_eq!(listeners.len(), 3);
+        assert_eq!(listeners[0].addr.to_string(), "127.0.0.1:9999");
+        assert_eq!(listeners[1].addr.to_string(), "[::1]:9999");
+        assert_eq!(listeners[0].need, ListenerNeed::Localhost);
+        assert_eq!(listeners[1].need, ListenerNeed::Localhost);
+        assert!(listeners[0].isolation.client_addr);
+        assert!(!listeners[0].isolation.listener);
+        assert_eq!(listeners[2].addr.to_string(), "unix:/tmp/arti.sock");
+        assert_eq!(listeners[2].need, ListenerNeed::Required);
+        assert!(!listeners[2].isolation.client_addr);
+        assert!(!listeners[2].isolation.listener);
+        assert_eq!(listeners[2].validation, SocksValidation::default());
+
+        // Without a port, we only listen where we're told; with best-effort,
+        // nothing is required.
+        bld.socks_listen_best_effort(true);
+        let cfg = bld.build().unwrap();
+        let listeners = SocksListener::from_config(&cfg, None);
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].need, ListenerNeed::Optional);
+
+        // The validation mode applies to every listener.
+        bld.socks_validation(SocksValidationMode::Strict);
+        let cfg = bld.build().unwrap();
+        let listeners = SocksListener::from_config(&cfg, Some(9999));
+        assert!(listeners
+            .iter()
+            .all(|l| l.validation == SocksValidation::strict()));
+    }
 }
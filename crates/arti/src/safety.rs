@@ -0,0 +1,207 @@
+//! Checks that we run at startup to catch unsafe ways of running Arti.
+//!
+//! We refuse to run as root (unless configured otherwise), and we make sure
+//! that our state and cache directories can't be read by other users: they
+//! hold information, like our choice of guards, that could help an attacker
+//! to deanonymize us.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use tracing::warn;
+
+/// Return the effective user ID of this process, if there is such a thing
+/// on this platform.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)] // Other platforms have no uid.
+fn current_uid() -> Option<u32> {
+    // SAFETY: geteuid() has no preconditions, and can't fail.
+    Some(unsafe { libc::geteuid() })
+}
+
+/// Return the effective user ID of this process, if there is such a thing
+/// on this platform.
+#[cfg(not(unix))]
+fn current_uid() -> Option<u32> {
+    None
+}
+
+/// Refuse to run as root, unless `allow_root` is true.
+pub(crate) fn check_not_root(allow_root: bool) -> Result<()> {
+    check_not_root_with(current_uid, allow_root)
+}
+
+/// Implementation for `check_not_root`: find our uid with `get_uid`.
+fn check_not_root_with<F>(get_uid: F, allow_root: bool) -> Result<()>
+where
+    F: FnOnce() -> Option<u32>,
+{
+    match get_uid() {
+        Some(0) if allow_root => {
+            warn!("Running as root, since allow_running_as_root is set. This is not a good idea.");
+            Ok(())
+        }
+        Some(0) => Err(anyhow!(
+            "Refusing to run as root. (If you really want to, set application.allow_running_as_root.)"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Make sure that the directory at `path` exists, and can't be accessed by
+/// other users.
+///
+/// If the directory doesn't exist, we create it with restrictive
+/// permissions.  If it exists but other users can access it, we return an
+/// error when `strict` is true.  Otherwise, we try to remove the other
+/// users' access, and warn about what we did.
+pub(crate) fn check_private_dir(path: &Path, strict: bool) -> Result<()> {
+    if !path.exists() {
+        return create_private_dir(path)
+            .with_context(|| format!("Unable to create directory {}", path.display()));
+    }
+    let problem = match permission_problem(path)
+        .with_context(|| format!("Unable to check permissions on {}", path.display()))?
+    {
+        Some(problem) => problem,
+        None => return Ok(()),
+    };
+    if strict {
+        return Err(anyhow!(
+            "{}. (Fix its permissions, or unset application.strict_directory_permissions.)",
+            problem
+        ));
+    }
+    match make_private(path) {
+        Ok(()) => warn!(
+            "{}. Removed access for other users; Tor usage stored there before now may have leaked.",
+            problem
+        ),
+        // We might not own the directory, or it might be on a filesystem
+        // that can't represent its permissions.  Either way, nothing we do
+        // here is going to fix it.
+        Err(e) => warn!(
+            "{}, and we couldn't fix it: {}. Other users may be able to learn about your Tor usage.",
+            problem, e
+        ),
+    }
+    Ok(())
+}
+
+/// Remove access for everybody but its owner from the directory at `path`.
+#[cfg(unix)]
+fn make_private(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o700))
+}
+
+/// Remove access for everybody but its owner from the directory at `path`.
+///
+/// On this platform, we don't know how to do that: see [`permission_problem`].
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn make_private(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Create a directory at `path` (and its parents), such that only its
+/// owner can access it.
+#[cfg(unix)]
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+}
+
+/// Create a directory at `path` (and its parents).
+///
+/// On this platform, we don't try to restrict who can access it.
+#[cfg(not(unix))]
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)
+}
+
+/// If users other than the owner of the directory at `path` can access it,
+/// return a description of the problem.
+#[cfg(unix)]
+fn permission_problem(path: &Path) -> std::io::Result<Option<String>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    Ok(if mode & 0o077 != 0 {
+        Some(format!(
+            "Directory {} is accessible by other users (mode {:o})",
+            path.display(),
+            mode
+        ))
+    } else {
+        None
+    })
+}
+
+/// If users other than the owner of the directory at `path` can access it,
+/// return a description of the problem.
+///
+/// TODO: On Windows, we should inspect the directory's ACL.  For now we
+/// don't check anything on non-unix platforms.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn permission_problem(_path: &Path) -> std::io::Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn root_gate() {
+        assert!(check_not_root_with(|| Some(1000), false).is_ok());
+        assert!(check_not_root_with(|| Some(1000), true).is_ok());
+        assert!(check_not_root_with(|| None, false).is_ok());
+        assert!(check_not_root_with(|| Some(0), false).is_err());
+        assert!(check_not_root_with(|| Some(0), true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_dirs() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mode_of = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        // A missing directory gets created, with restrictive permissions.
+        let new_dir = tmp.path().join("a").join("b");
+        check_private_dir(&new_dir, true).unwrap();
+        assert!(new_dir.is_dir());
+        assert_eq!(mode_of(&new_dir), 0o700);
+
+        // Existing directories are checked.  In strict mode, they aren't
+        // changed.
+        for (mode, ok) in &[
+            (0o700, true),
+            (0o500, true),
+            (0o750, false),
+            (0o705, false),
+            (0o755, false),
+            (0o777, false),
+        ] {
+            let dir = tmp.path().join(format!("mode-{:o}", mode));
+            fs::create_dir(&dir).unwrap();
+            fs::set_permissions(&dir, fs::Permissions::from_mode(*mode)).unwrap();
+
+            assert_eq!(permission_problem(&dir).unwrap().is_none(), *ok);
+            assert_eq!(check_private_dir(&dir, true).is_ok(), *ok);
+            assert_eq!(mode_of(&dir), *mode);
+
+            // When we aren't strict, we fix the permissions instead.
+            assert!(check_private_dir(&dir, false).is_ok());
+            assert_eq!(mode_of(&dir), mode & 0o700);
+            assert!(permission_problem(&dir).unwrap().is_none());
+        }
+    }
+}
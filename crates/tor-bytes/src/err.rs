@@ -3,6 +3,12 @@
 use thiserror::Error;
 
 /// Error type for decoding Tor objects from bytes.
+///
+/// When a [`Reader`](crate::Reader) detects an error, it records how far
+/// into its input it was, wrapping the error in [`Error::Positioned`].  Use
+/// [`Error::without_position`] to find out what kind of error it was, and
+/// [`Error::offset`] and [`Error::context`] to find out where.  (Errors
+/// compare equal whether or not they have a position.)
 #[derive(Error, Debug, Clone)]
 #[non_exhaustive]
 pub enum Error {
@@ -26,12 +32,108 @@ pub enum Error {
     /// unwrap() from within parsing code.
     #[error("bug")]
     Bug(#[from] tor_error::Bug),
+    /// Another error, along with the place in the input where it happened.
+    #[error("{err} at offset {offset}{}", DisplayContext(*.context))]
+    Positioned {
+        /// The error itself.  This is never another `Positioned` error, or
+        /// a `Bug`.
+        err: Box<Error>,
+        /// The number of bytes that the reader had consumed when the error
+        /// occurred.
+        offset: usize,
+        /// A description of what we were reading, if we know.
+        context: Option<&'static str>,
+    },
+}
+
+/// Helper to display the `context` of an [`Error::Positioned`].
+struct DisplayContext(
+    /// The context to display.
+    Option<&'static str>,
+);
+
+impl std::fmt::Display for DisplayContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(context) => write!(f, " while reading {}", context),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error {
+    /// Return this error without any position information.
+    ///
+    /// Use this to find out which kind of error happened; for example, to
+    /// tell whether an object was truncated.
+    pub fn without_position(&self) -> &Error {
+        match self {
+            Error::Positioned { err, .. } => err,
+            _ => self,
+        }
+    }
+
+    /// Return the offset in the input at which this error happened, if we
+    /// know it.
+    ///
+    /// The offset is counted from the start of the [`Reader`](crate::Reader)
+    /// that found the error.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Error::Positioned { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// Return a description of what we were reading when this error
+    /// happened, if we know.
+    pub fn context(&self) -> Option<&'static str> {
+        match self {
+            Error::Positioned { context, .. } => *context,
+            _ => None,
+        }
+    }
+
+    /// Record that this error happened at `offset`, unless we already know
+    /// where it happened.
+    ///
+    /// (We never give a position to a `Bug`.)
+    pub(crate) fn at_offset(self, offset: usize) -> Self {
+        match self {
+            Error::Positioned { .. } | Error::Bug(_) => self,
+            err => Error::Positioned {
+                err: Box::new(err),
+                offset,
+                context: None,
+            },
+        }
+    }
+
+    /// Record that this error happened at `offset` while reading `what`,
+    /// unless we already know those things.
+    ///
+    /// If the error already has a position, we keep it, since it is closer
+    /// to the real problem than `offset`; likewise with its context.
+    pub(crate) fn in_context(self, offset: usize, what: &'static str) -> Self {
+        match self.at_offset(offset) {
+            Error::Positioned {
+                err,
+                offset,
+                context,
+            } => Error::Positioned {
+                err,
+                offset,
+                context: context.or(Some(what)),
+            },
+            other => other,
+        }
+    }
 }
 
 impl PartialEq for Error {
     fn eq(&self, other: &Self) -> bool {
         use Error::*;
-        match (self, other) {
+        match (self.without_position(), other.without_position()) {
             (Truncated, Truncated) => true,
             (ExtraneousBytes, ExtraneousBytes) => true,
             (BadMessage(a), BadMessage(b)) => a == b,
@@ -40,3 +142,56 @@ impl PartialEq for Error {
         }
     }
 }
+
+/// Error type for encoding Tor objects to bytes.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EncodeError {
+    /// We tried to write an object with a length prefix, but the object was
+    /// too long for its length to fit in the prefix.
+    #[error("object too long for its length prefix")]
+    BadLengthValue,
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let e = Error::Truncated.at_offset(37);
+        assert_eq!(e.offset(), Some(37));
+        assert_eq!(e.context(), None);
+        assert!(matches!(e.without_position(), Error::Truncated));
+        assert_eq!(e, Error::Truncated);
+        assert_eq!(
+            e.to_string(),
+            "object truncated (or not fully present) at offset 37"
+        );
+
+        // The innermost position and context win.
+        let e = e
+            .in_context(40, "ed25519 cert extension")
+            .in_context(0, "ed25519 cert")
+            .at_offset(12);
+        assert_eq!(e.offset(), Some(37));
+        assert_eq!(e.context(), Some("ed25519 cert extension"));
+        assert_eq!(
+            e.to_string(),
+            "object truncated (or not fully present) at offset 37 while reading ed25519 cert extension"
+        );
+
+        let e = Error::BadMessage("nope").in_context(5, "widget");
+        assert_eq!(
+            e.to_string(),
+            "bad object: nope at offset 5 while reading widget"
+        );
+        assert_ne!(e, Error::Truncated);
+
+        // Bugs don't get positions.
+        let e = Error::from(tor_error::internal!("oops")).at_offset(3);
+        assert!(matches!(e, Error::Bug(_)));
+        assert_eq!(e.offset(), None);
+    }
+}
@@ -23,6 +23,20 @@ impl Writer for Vec<u8> {
         let new_len = self.len() + n;
         self.resize(new_len, 0);
     }
+    fn write_nested_u8len<F>(&mut self, f: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+    {
+        // specialize to fill in the length afterwards, instead of copying
+        writer::write_nested_in_place(self, LengthSlot::reserve_u8, f)
+    }
+    fn write_nested_u16len<F>(&mut self, f: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+    {
+        // specialize to fill in the length afterwards, instead of copying
+        writer::write_nested_in_place(self, LengthSlot::reserve_u16, f)
+    }
 }
 
 impl Writer for bytes::BytesMut {
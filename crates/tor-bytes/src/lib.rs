@@ -41,6 +41,10 @@
 //! * [`Writeable`]: Trait for an object that can be encoded onto a [`Writer`]
 //! * [`Readable`]: Trait for an object that can be decoded from a [`Reader`].
 //!
+//! For secret data, [`SecretBuf`] is a [`Writer`] that clears its contents
+//! when it is dropped or reallocated, and [`Reader::extract_secret`] decodes
+//! an object into a wrapper that clears it on drop.
+//!
 //! Every object you want to encode or decode should implement
 //! [`Writeable`] or [`Readable`] respectively.
 //!
@@ -80,17 +84,22 @@
 mod err;
 mod impls;
 mod reader;
+mod secretbuf;
 mod writer;
 
-pub use err::Error;
+pub use err::{EncodeError, Error};
 pub use reader::Reader;
-pub use writer::Writer;
+pub use secretbuf::SecretBuf;
+pub use writer::{LengthSlot, Writer};
 
 use arrayref::array_ref;
 
 /// Result type returned by this crate.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Result type returned by this crate's encoding functions.
+pub type EncodeResult<T> = std::result::Result<T, EncodeError>;
+
 /// Trait for an object that can be encoded onto a Writer by reference.
 ///
 /// Implement this trait in order to make an object writeable.
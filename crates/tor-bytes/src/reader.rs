@@ -8,6 +8,10 @@ use arrayref::array_ref;
 /// Unlike io::Read, this object has a simpler error type, and is designed
 /// for in-memory parsing only.
 ///
+/// When a Reader's method fails, the error records the offset at which
+/// the reader found the problem: see [`Error::offset`].  To say what was
+/// being read, use [`Reader::with_context`].
+///
 /// The methods in [`Reader`] should never panic, with one exception:
 /// the `extract` and `extract_n` methods will panic if the underlying
 /// [`Readable`] object's `take_from` method panics.
@@ -90,7 +94,7 @@ impl<'a> Reader<'a> {
     /// not enough bytes to skip.
     pub fn advance(&mut self, n: usize) -> Result<()> {
         if n > self.remaining() {
-            return Err(Error::Truncated);
+            return Err(self.error_at(Error::Truncated));
         }
         self.off += n;
         Ok(())
@@ -101,7 +105,7 @@ impl<'a> Reader<'a> {
     /// if there were extra bytes.
     pub fn should_be_exhausted(&self) -> Result<()> {
         if self.remaining() != 0 {
-            return Err(Error::ExtraneousBytes);
+            return Err(self.error_at(Error::ExtraneousBytes));
         }
         Ok(())
     }
@@ -121,7 +125,7 @@ impl<'a> Reader<'a> {
     /// bytes, returns Err(Error::Truncated).
     pub fn peek(&self, n: usize) -> Result<&'a [u8]> {
         if self.remaining() < n {
-            return Err(Error::Truncated);
+            return Err(self.error_at(Error::Truncated));
         }
 
         Ok(&self.b[self.off..(n + self.off)])
@@ -227,7 +231,7 @@ impl<'a> Reader<'a> {
         let pos = self.b[self.off..]
             .iter()
             .position(|b| *b == term)
-            .ok_or(Error::Truncated)?;
+            .ok_or_else(|| self.error_at(Error::Truncated))?;
         let result = self.take(pos)?;
         self.advance(1)?;
         Ok(result)
@@ -235,15 +239,33 @@ impl<'a> Reader<'a> {
     /// Try to decode and remove a Readable from this reader, using its
     /// take_from() method.
     ///
-    /// On failure, consumes nothing.
+    /// On failure, consumes nothing.  If the Readable's error doesn't say
+    /// where it happened, we record the offset that the Readable had read
+    /// up to when it failed.
     pub fn extract<E: Readable>(&mut self) -> Result<E> {
         let off_orig = self.off;
-        let result = E::take_from(self);
-        if result.is_err() {
+        E::take_from(self).map_err(|e| {
             // We encountered an error; we should rewind.
+            let e = self.error_at(e);
             self.off = off_orig;
-        }
-        result
+            e
+        })
+    }
+
+    /// Try to decode and remove a Readable that holds secret data from this
+    /// reader, using its take_from() method.
+    ///
+    /// The result is wrapped in [`Zeroizing`](zeroize::Zeroizing), so that it
+    /// gets cleared when it is dropped.  Note that this only clears the
+    /// returned object: the bytes it was decoded from are still in the
+    /// reader's underlying slice, and need to be cleared separately if they
+    /// are secret too.
+    ///
+    /// On failure, consumes nothing.
+    pub fn extract_secret<E: Readable + zeroize::Zeroize>(
+        &mut self,
+    ) -> Result<zeroize::Zeroizing<E>> {
+        self.extract().map(zeroize::Zeroizing::new)
     }
 
     /// Try to decode and remove `n` Readables from this reader, using the
@@ -258,6 +280,7 @@ impl<'a> Reader<'a> {
                 Ok(item) => result.push(item),
                 Err(e) => {
                     // Encountered an error; we should rewind.
+                    let e = self.error_at(e);
                     self.off = off_orig;
                     return Err(e);
                 }
@@ -265,6 +288,53 @@ impl<'a> Reader<'a> {
         }
         Ok(result)
     }
+
+    /// Record that `err` happened at this reader's current offset, unless
+    /// it already says where it happened.
+    ///
+    /// Use this when a [`Readable`] finds a problem in what it has read, so
+    /// that the error says where the problem was.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::{Error,Reader};
+    /// let mut r = Reader::from_slice(b"\x00\x07");
+    /// r.take_u8()?;
+    /// let e = r.error_at(Error::BadMessage("lucky number"));
+    /// assert_eq!(e.offset(), Some(1));
+    /// assert_eq!(e.to_string(), "bad object: lucky number at offset 1");
+    /// # tor_bytes::Result::Ok(())
+    /// ```
+    pub fn error_at(&self, err: Error) -> Error {
+        err.at_offset(self.off)
+    }
+
+    /// Call `f` on this reader, and label any error it returns as having
+    /// happened while reading `what`.
+    ///
+    /// If `f` fails with an error that doesn't say where it happened, we
+    /// record the offset that `f` had read up to.  Unlike
+    /// [`Reader::extract`], this doesn't rewind the reader on failure.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::Reader;
+    /// let mut r = Reader::from_slice(b"\x00\x02\x05");
+    /// let e = r
+    ///     .with_context("widget", |r| {
+    ///         let len = r.take_u16()?;
+    ///         r.take(len.into())
+    ///     })
+    ///     .unwrap_err();
+    /// assert_eq!(e.to_string(),
+    ///            "object truncated (or not fully present) at offset 2 while reading widget");
+    /// ```
+    pub fn with_context<T, F>(&mut self, what: &'static str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self).map_err(|e| e.in_context(self.off, what))
+    }
 }
 
 #[cfg(test)]
@@ -460,4 +530,80 @@ mod tests {
         assert_eq!(les.unwrap_err(), Error::Truncated);
         assert_eq!(r.remaining(), 28);
     }
+
+    #[test]
+    fn error_positions() {
+        let mut r = Reader::from_slice(&b"\x03abc\x09def"[..]);
+        let len = r.take_u8().unwrap();
+        r.take(len.into()).unwrap();
+
+        // Every failure says where it happened, and still compares equal
+        // to the plain error.
+        let e = r.take_u64().unwrap_err();
+        assert_eq!(e, Error::Truncated);
+        assert!(matches!(e.without_position(), Error::Truncated));
+        assert_eq!(e.offset(), Some(4));
+        assert_eq!(r.should_be_exhausted().unwrap_err().offset(), Some(4));
+        assert_eq!(r.take_until(b'z').unwrap_err().offset(), Some(4));
+
+        // A failed extract() rewinds, but reports where the Readable gave up.
+        struct LenEnc;
+        impl Readable for LenEnc {
+            fn take_from(b: &mut Reader<'_>) -> Result<Self> {
+                let length = b.take_u8()?;
+                if length == 0 {
+                    return Err(b.error_at(Error::BadMessage("empty")));
+                }
+                b.advance(length.into())?;
+                Ok(LenEnc)
+            }
+        }
+        let e = r.extract::<LenEnc>().err().unwrap();
+        assert_eq!(e.offset(), Some(5));
+        assert_eq!(r.consumed(), 4);
+        let e = r.extract_n::<LenEnc>(1).err().unwrap();
+        assert_eq!(e.offset(), Some(5));
+        assert_eq!(r.consumed(), 4);
+        let mut r0 = Reader::from_slice(&b"\x00"[..]);
+        let e = r0.extract::<LenEnc>().err().unwrap();
+        assert_eq!(e, Error::BadMessage("empty"));
+        assert_eq!(e.offset(), Some(1));
+
+        // with_context() labels the failure, keeping the inner position.
+        let e = r
+            .with_context("test object", |r| {
+                r.take_u8()?;
+                r.take(16)
+            })
+            .unwrap_err();
+        assert_eq!(e.offset(), Some(5));
+        assert_eq!(e.context(), Some("test object"));
+        assert_eq!(
+            e.to_string(),
+            "object truncated (or not fully present) at offset 5 while reading test object"
+        );
+        // Errors from elsewhere get the reader's offset.
+        let e = r
+            .with_context("test object", |r| -> Result<()> {
+                r.take(2)?;
+                Err(Error::BadMessage("no"))
+            })
+            .unwrap_err();
+        assert_eq!(e.offset(), Some(7));
+    }
+
+    #[test]
+    fn extract_secret() {
+        let bytes = b"\x00\x00\x01\x02\x03";
+        let mut r = Reader::from_slice(&bytes[..]);
+
+        let x = r.extract_secret::<u32>().unwrap();
+        assert_eq!(*x, 0x0102);
+        assert_eq!(r.remaining(), 1);
+
+        // Make sure that we don't advance on a failing extract_secret().
+        let x = r.extract_secret::<u32>();
+        assert_eq!(x.unwrap_err(), Error::Truncated);
+        assert_eq!(r.remaining(), 1);
+    }
 }
@@ -0,0 +1,157 @@
+//! Define a wrapper for `Vec<u8>` that will act as Writer, but zeroize its
+//! contents on drop or reallocation.
+
+use crate::Writer;
+use zeroize::Zeroize;
+
+/// A [`Writer`] used for accumulating secret data, which gets cleared on drop.
+///
+/// Unlike `Zeroizing<Vec<u8>>`, this type makes sure that we always zeroize
+/// the contents of the buffer, even if the buffer has to be reallocated in
+/// order to grow.
+///
+/// We use this for cases when we're building the input to a key derivation
+/// function (KDF), and want to ensure that we don't expose the values we feed
+/// to it.
+///
+/// This struct is expected to have additional overhead beyond what `Vec<u8>`
+/// has; use it only for secret material.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretBuf(Vec<u8>);
+
+/// The default size of our buffer.
+///
+/// This is based on the size of a typical secret input in `tor-proto`.
+const DEFAULT_CAPACITY: usize = 384;
+
+impl SecretBuf {
+    /// Construct a new empty [`SecretBuf`]
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Construct a new empty [`SecretBuf`] with a specified capacity.
+    ///
+    /// This buffer will not have to be reallocated until it uses `capacity`
+    /// bytes.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+
+    /// Truncate this buffer to a given length.
+    pub fn truncate(&mut self, new_len: usize) {
+        // Vec::truncate doesn't clear the bytes it removes, so we do.
+        if new_len < self.0.len() {
+            self.0[new_len..].zeroize();
+        }
+        self.0.truncate(new_len);
+    }
+
+    /// Add all the bytes from `slice` to the end of this vector.
+    pub fn extend_from_slice(&mut self, slice: &[u8]) {
+        let new_len = self.0.len() + slice.len();
+        if new_len > self.0.capacity() {
+            // We will need to reallocate.  Do it ourselves, so that we can
+            // clear the old buffer afterwards.
+            let new_capacity = std::cmp::max(self.0.capacity() * 2, new_len);
+            let mut new_vec = Vec::with_capacity(new_capacity);
+            new_vec.extend_from_slice(&self.0[..]);
+
+            let mut old_vec = std::mem::replace(&mut self.0, new_vec);
+            old_vec.zeroize();
+        }
+        self.0.extend_from_slice(slice);
+        debug_assert_eq!(self.0.len(), new_len);
+    }
+}
+
+impl Default for SecretBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Zeroize for SecretBuf {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<Vec<u8>> for SecretBuf {
+    fn from(v: Vec<u8>) -> Self {
+        Self(v)
+    }
+}
+
+impl std::fmt::Debug for SecretBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never show the contents of a secret.
+        write!(f, "SecretBuf([{} bytes])", self.0.len())
+    }
+}
+
+impl AsMut<[u8]> for SecretBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+
+impl AsRef<[u8]> for SecretBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl std::ops::Deref for SecretBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl std::ops::DerefMut for SecretBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+
+impl Writer for SecretBuf {
+    fn write_all(&mut self, b: &[u8]) {
+        self.extend_from_slice(b);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn simple_case() {
+        // We can't check the zeroization from here; see tests/secretbuf.rs
+        // for that.  Here we just check that the data is correct.
+
+        let mut buf1 = SecretBuf::default();
+        let mut buf2 = Vec::new();
+        let xyz = b"Nine hundred pounds of sifted flax";
+
+        // This is enough to be sure that we'll reallocate.
+        for _ in 0..200 {
+            buf1.write_all(xyz);
+            buf2.write_all(xyz);
+        }
+        assert_eq!(&buf1[..], &buf2[..]);
+
+        buf1.truncate(10);
+        buf2.truncate(10);
+        assert_eq!(&buf1[..], &buf2[..]);
+        assert_eq!(format!("{:?}", buf1), "SecretBuf([10 bytes])");
+    }
+}
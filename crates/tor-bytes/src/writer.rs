@@ -2,6 +2,8 @@
 
 use crate::Writeable;
 use crate::WriteableOnce;
+use crate::{EncodeError, EncodeResult};
+use std::convert::TryFrom;
 
 /// A byte-oriented trait for writing to small arrays.
 ///
@@ -80,10 +82,166 @@ pub trait Writer {
     fn write_and_consume<E: WriteableOnce>(&mut self, e: E) {
         e.write_into(self);
     }
+    /// Write a one-byte length, followed by whatever `f` writes onto the
+    /// writer it is given, such that the length is the number of bytes that
+    /// `f` wrote.
+    ///
+    /// Returns an error (and writes nothing) if `f` fails, or if it writes
+    /// more than 255 bytes.  Calls to this function and
+    /// [`write_nested_u16len`](Writer::write_nested_u16len) can be nested
+    /// inside `f`.
+    ///
+    /// On a `Vec<u8>`, `f` writes directly onto the vector, and we fill in
+    /// the length afterwards.  Other writers can only append, so there `f`
+    /// writes into a temporary buffer, which we then copy onto this writer.
+    /// (Don't use this for secret data: the temporary buffer isn't cleared.)
+    ///
+    /// # Example
+    /// ```
+    /// use tor_bytes::Writer;
+    /// let mut w: Vec<u8> = Vec::new();
+    /// w.write_nested_u8len(|w| {
+    ///     w.write_u16(7);
+    ///     w.write_nested_u8len(|w| {
+    ///         w.write_all(b"hi");
+    ///         Ok(())
+    ///     })
+    /// })?;
+    /// assert_eq!(w, &[5, 0, 7, 2, b'h', b'i']);
+    /// # tor_bytes::EncodeResult::Ok(())
+    /// ```
+    fn write_nested_u8len<F>(&mut self, f: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+    {
+        let body = nested_body(f)?;
+        let len = u8::try_from(body.len()).map_err(|_| EncodeError::BadLengthValue)?;
+        self.write_u8(len);
+        self.write_all(&body[..]);
+        Ok(())
+    }
+    /// Write a two-byte length, followed by whatever `f` writes onto the
+    /// writer it is given, as [`write_nested_u8len`](Writer::write_nested_u8len)
+    /// does.
+    ///
+    /// Returns an error (and writes nothing) if `f` fails, or if it writes
+    /// more than 65535 bytes.
+    fn write_nested_u16len<F>(&mut self, f: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+    {
+        let body = nested_body(f)?;
+        let len = u16::try_from(body.len()).map_err(|_| EncodeError::BadLengthValue)?;
+        self.write_u16(len);
+        self.write_all(&body[..]);
+        Ok(())
+    }
+}
+
+/// Helper for the `write_nested_*` functions: return the bytes that `f`
+/// writes.
+fn nested_body<F>(f: F) -> EncodeResult<Vec<u8>>
+where
+    F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+{
+    let mut body = Vec::new();
+    f(&mut body)?;
+    Ok(body)
+}
+
+/// Helper for the `write_nested_*` functions on `Vec<u8>`: reserve a length
+/// field with `reserve`, let `f` write onto `v`, and then fill in the
+/// length.
+///
+/// If anything goes wrong, we remove everything we wrote.
+pub(crate) fn write_nested_in_place<F>(
+    v: &mut Vec<u8>,
+    reserve: fn(&mut Vec<u8>) -> LengthSlot,
+    f: F,
+) -> EncodeResult<()>
+where
+    F: FnOnce(&mut Vec<u8>) -> EncodeResult<()>,
+{
+    let orig_len = v.len();
+    let slot = reserve(v);
+    let body_start = v.len();
+    let r = f(v).and_then(|()| slot.finish(v, body_start));
+    if r.is_err() {
+        v.truncate(orig_len);
+    }
+    r
+}
+
+/// A length field that has been reserved in a `Vec<u8>`, but not yet
+/// filled in.
+///
+/// Most code should use [`Writer::write_nested_u8len`] or
+/// [`Writer::write_nested_u16len`] instead.  This type is for the cases
+/// where the length doesn't cover everything written after it: for
+/// example, when some header fields come between a length and the body
+/// that it measures.
+///
+/// # Example
+/// ```
+/// use tor_bytes::{LengthSlot, Writer};
+/// let mut w: Vec<u8> = Vec::new();
+/// let slot = LengthSlot::reserve_u16(&mut w);
+/// w.write_u8(9); // Not counted in the length.
+/// let body_start = w.len();
+/// w.write_all(b"body");
+/// slot.finish(&mut w, body_start)?;
+/// assert_eq!(w, &[0, 4, 9, b'b', b'o', b'd', b'y']);
+/// # tor_bytes::EncodeResult::Ok(())
+/// ```
+#[derive(Debug)]
+#[must_use = "a LengthSlot stays zero until you call finish()"]
+pub struct LengthSlot {
+    /// The position of the length field within the vector.
+    pos: usize,
+    /// The width of the length field, in bytes: 1 or 2.
+    width: usize,
+}
+
+impl LengthSlot {
+    /// Reserve a one-byte length field at the end of `v`.
+    pub fn reserve_u8(v: &mut Vec<u8>) -> Self {
+        let pos = v.len();
+        v.write_u8(0);
+        LengthSlot { pos, width: 1 }
+    }
+    /// Reserve a two-byte length field at the end of `v`.
+    pub fn reserve_u16(v: &mut Vec<u8>) -> Self {
+        let pos = v.len();
+        v.write_u16(0);
+        LengthSlot { pos, width: 2 }
+    }
+    /// Fill in this length field with the number of bytes that have been
+    /// written to `v` since the offset `body_start`.
+    ///
+    /// Returns an error if that number doesn't fit in the field.  In that
+    /// case the field stays zero, and `v` is otherwise unchanged: the
+    /// caller will probably want to truncate it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `v` isn't the vector that this field was reserved in, or
+    /// if `body_start` is after the end of `v`.
+    pub fn finish(self, v: &mut [u8], body_start: usize) -> EncodeResult<()> {
+        let len = v.len() - body_start;
+        let slot = &mut v[self.pos..self.pos + self.width];
+        if self.width == 1 {
+            slot[0] = u8::try_from(len).map_err(|_| EncodeError::BadLengthValue)?;
+        } else {
+            let len = u16::try_from(len).map_err(|_| EncodeError::BadLengthValue)?;
+            slot.copy_from_slice(&len.to_be_bytes());
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::unwrap_used)]
     use super::*;
     #[test]
     fn write_ints() {
@@ -112,6 +270,77 @@ mod tests {
         assert_eq!(&v[..], &b"They're good dogs, Bront"[..]);
     }
 
+    #[test]
+    fn nested() {
+        let mut b = bytes::BytesMut::new();
+        b.write_u8(9);
+        b.write_nested_u16len(|w| {
+            w.write_u8(1);
+            w.write_nested_u8len(|w| {
+                w.write_all(b"abc");
+                w.write_nested_u8len(|_| Ok(()))
+            })?;
+            w.write_u8(2);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(&b[..], &[9, 0, 7, 1, 4, b'a', b'b', b'c', 0, 2]);
+
+        // Exactly as long as the prefix allows.
+        let mut v = Vec::new();
+        v.write_nested_u8len(|w| {
+            w.write_zeros(255);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(v.len(), 256);
+        assert_eq!(v[0], 255);
+        let mut v = Vec::new();
+        v.write_nested_u16len(|w| {
+            w.write_zeros(65535);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(v.len(), 65537);
+        assert_eq!(&v[..2], &[255, 255]);
+
+        // Too long: nothing gets written, even by the outer writes.
+        let mut v = vec![7];
+        let r = v.write_nested_u16len(|w| {
+            w.write_u8(1);
+            w.write_nested_u8len(|w| {
+                w.write_zeros(256);
+                Ok(())
+            })
+        });
+        assert_eq!(r, Err(EncodeError::BadLengthValue));
+        let r = v.write_nested_u16len(|w| {
+            w.write_zeros(65536);
+            Ok(())
+        });
+        assert_eq!(r, Err(EncodeError::BadLengthValue));
+        assert_eq!(&v[..], &[7]);
+    }
+
+    #[test]
+    fn length_slot() {
+        let mut v = vec![7];
+        let slot = LengthSlot::reserve_u8(&mut v);
+        let slot2 = LengthSlot::reserve_u16(&mut v);
+        v.write_all(b"xy");
+        let body_start = v.len();
+        v.write_all(b"abc");
+        slot2.finish(&mut v, body_start).unwrap();
+        slot.finish(&mut v, 2).unwrap();
+        assert_eq!(&v[..], &[7, 7, 0, 3, b'x', b'y', b'a', b'b', b'c']);
+
+        let mut v = Vec::new();
+        let slot = LengthSlot::reserve_u8(&mut v);
+        v.write_zeros(256);
+        assert_eq!(slot.finish(&mut v, 1), Err(EncodeError::BadLengthValue));
+        assert_eq!(v[0], 0);
+    }
+
     #[test]
     fn writeable() {
         struct Sequence(u8);
@@ -0,0 +1,95 @@
+//! Best-effort check that `SecretBuf` doesn't leave secrets behind in
+//! memory that it gives back to the allocator.
+//!
+//! We install a global allocator that looks at every block before it is
+//! freed, and notes whether it still contains a marker pattern.  This lives
+//! in its own test binary so that the allocator doesn't affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tor_bytes::{SecretBuf, Writer};
+
+/// A pattern that we write into our buffers, and look for in freed memory.
+const MARKER: &[u8; 16] = b"!sEcReT-mArKeR!\x7f";
+
+/// True if we should check freed blocks for `MARKER`.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// True if we've seen `MARKER` in a freed block while watching.
+static FOUND: AtomicBool = AtomicBool::new(false);
+
+/// An allocator that delegates to `System`, but inspects blocks on free.
+///
+/// We deliberately don't override `realloc`: the default implementation
+/// goes through `alloc` and `dealloc`, so it lets us see the old block too.
+struct CheckingAlloc;
+
+unsafe impl GlobalAlloc for CheckingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if WATCHING.load(Ordering::SeqCst) {
+            let block = std::slice::from_raw_parts(ptr, layout.size());
+            if block.windows(MARKER.len()).any(|w| w == &MARKER[..]) {
+                FOUND.store(true, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOC: CheckingAlloc = CheckingAlloc;
+
+/// Run `f` while watching freed blocks, and return true if any of them
+/// still held `MARKER`.
+fn leaks_marker<F: FnOnce()>(f: F) -> bool {
+    FOUND.store(false, Ordering::SeqCst);
+    WATCHING.store(true, Ordering::SeqCst);
+    f();
+    WATCHING.store(false, Ordering::SeqCst);
+    FOUND.load(Ordering::SeqCst)
+}
+
+// Everything is in one test function, so that the test harness can't run
+// two checks at once and confuse our global state.
+#[test]
+fn freed_memory_is_cleared() {
+    // First, make sure that our hook actually works: a plain Vec leaves the
+    // marker behind, both when it grows and when it's dropped.
+    assert!(leaks_marker(|| {
+        let mut v: Vec<u8> = Vec::with_capacity(16);
+        for _ in 0..50 {
+            v.write_all(MARKER);
+        }
+    }));
+
+    // A SecretBuf that's dropped without growing.
+    assert!(!leaks_marker(|| {
+        let mut b = SecretBuf::with_capacity(64);
+        b.write_all(MARKER);
+        assert_eq!(&b[..], &MARKER[..]);
+    }));
+
+    // A SecretBuf that gets reallocated several times.
+    assert!(!leaks_marker(|| {
+        let mut b = SecretBuf::with_capacity(16);
+        for _ in 0..50 {
+            b.write_all(MARKER);
+        }
+        assert_eq!(b.len(), MARKER.len() * 50);
+    }));
+
+    // A SecretBuf that's truncated, then grows.
+    assert!(!leaks_marker(|| {
+        let mut b = SecretBuf::with_capacity(32);
+        b.write_all(MARKER);
+        b.write_all(MARKER);
+        b.truncate(3);
+        for _ in 0..10 {
+            b.write_all(MARKER);
+        }
+    }));
+}
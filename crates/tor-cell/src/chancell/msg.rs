@@ -3,6 +3,8 @@
 use super::{ChanCmd, RawCellBody, CELL_DATA_LEN};
 use std::convert::TryInto;
 use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use tor_bytes::{self, Error, Readable, Reader, Result, Writer};
 
 use caret::caret_int;
@@ -142,6 +144,12 @@ impl ChanMsg {
             ChanCmd::DESTROY => Destroy(r.extract()?),
             ChanCmd::NETINFO => Netinfo(r.extract()?),
             ChanCmd::VERSIONS => Versions(r.extract()?),
+            // padding-spec.txt says that we have to ignore any
+            // PADDING_NEGOTIATE version that we don't recognize, so we
+            // don't treat one as an error.
+            ChanCmd::PADDING_NEGOTIATE if r.peek(1)? != [PADDING_NEGOTIATE_VERSION] => {
+                Unrecognized(unrecognized_with_cmd(cmd, r)?)
+            }
             ChanCmd::PADDING_NEGOTIATE => PaddingNegotiate(r.extract()?),
             ChanCmd::CERTS => Certs(r.extract()?),
             ChanCmd::AUTH_CHALLENGE => AuthChallenge(r.extract()?),
@@ -778,18 +786,32 @@ impl Readable for Versions {
     }
 }
 
+/// The only version of PaddingNegotiate that we know how to handle.
+const PADDING_NEGOTIATE_VERSION: u8 = 0;
+
+caret_int! {
+    /// What a PaddingNegotiate message asks the other side to do.
+    pub struct PaddingNegotiateCmd(u8) {
+        /// Stop sending padding.
+        STOP = 1,
+        /// Start sending padding.
+        START = 2,
+    }
+}
+
 /// A PaddingNegotiate message is used to negotiate channel padding.
 ///
-/// TODO: say more once we implement channel padding.
+/// A client sends this message to ask the relay at the other end of a
+/// channel to start or stop sending it padding cells, and to suggest
+/// how long the relay should let the channel sit idle before it sends
+/// one.  See padding-spec.txt for details.
 #[derive(Clone, Debug)]
 pub struct PaddingNegotiate {
     /// Whether to start or stop padding
-    command: u8,
+    command: PaddingNegotiateCmd,
     /// Suggested lower-bound value for inter-packet timeout in msec.
-    // TODO(nickm) is that right?
     ito_low_ms: u16,
     /// Suggested upper-bound value for inter-packet timeout in msec.
-    // TODO(nickm) is that right?
     ito_high_ms: u16,
 }
 impl PaddingNegotiate {
@@ -798,21 +820,58 @@ impl PaddingNegotiate {
     /// If `start` is true, this is a message to enable padding. Otherwise
     /// this is a message to disable padding.
     pub fn new(start: bool, ito_low_ms: u16, ito_high_ms: u16) -> Self {
-        let command = if start { 2 } else { 1 };
+        let command = if start {
+            PaddingNegotiateCmd::START
+        } else {
+            PaddingNegotiateCmd::STOP
+        };
         Self {
             command,
             ito_low_ms,
             ito_high_ms,
         }
     }
+    /// Create a new PaddingNegotiate message to enable padding, with an
+    /// inter-packet timeout chosen from `ito`.
+    ///
+    /// Timeouts longer than `u16::MAX` milliseconds are reduced to
+    /// `u16::MAX` milliseconds.  If the range is empty, its lower bound
+    /// is reduced to its upper bound.
+    pub fn start(ito: RangeInclusive<Duration>) -> Self {
+        /// Convert `d` to milliseconds, saturating at `u16::MAX`.
+        fn saturating_ms(d: Duration) -> u16 {
+            d.as_millis().try_into().unwrap_or(u16::MAX)
+        }
+        let ito_high_ms = saturating_ms(*ito.end());
+        let ito_low_ms = saturating_ms(*ito.start()).min(ito_high_ms);
+        Self::new(true, ito_low_ms, ito_high_ms)
+    }
+    /// Create a new PaddingNegotiate message to disable padding.
+    pub fn stop() -> Self {
+        Self::new(false, 0, 0)
+    }
+    /// Return what this message asks the other side to do.
+    pub fn command(&self) -> PaddingNegotiateCmd {
+        self.command
+    }
+    /// Return the suggested lower bound for the inter-packet timeout,
+    /// in milliseconds.
+    pub fn ito_low_ms(&self) -> u16 {
+        self.ito_low_ms
+    }
+    /// Return the suggested upper bound for the inter-packet timeout,
+    /// in milliseconds.
+    pub fn ito_high_ms(&self) -> u16 {
+        self.ito_high_ms
+    }
 }
 impl Body for PaddingNegotiate {
     fn into_message(self) -> ChanMsg {
         ChanMsg::PaddingNegotiate(self)
     }
     fn write_body_onto<W: Writer + ?Sized>(self, w: &mut W) {
-        w.write_u8(0); // version
-        w.write_u8(self.command);
+        w.write_u8(PADDING_NEGOTIATE_VERSION);
+        w.write_u8(self.command.into());
         w.write_u16(self.ito_low_ms);
         w.write_u16(self.ito_high_ms);
     }
@@ -820,14 +879,19 @@ impl Body for PaddingNegotiate {
 impl Readable for PaddingNegotiate {
     fn take_from(r: &mut Reader<'_>) -> Result<Self> {
         let v = r.take_u8()?;
-        if v != 0 {
+        if v != PADDING_NEGOTIATE_VERSION {
             return Err(Error::BadMessage(
                 "Unrecognized padding negotiation version",
             ));
         }
-        let command = r.take_u8()?;
+        let command = r.take_u8()?.into();
         let ito_low_ms = r.take_u16()?;
         let ito_high_ms = r.take_u16()?;
+        if command == PaddingNegotiateCmd::START && ito_low_ms > ito_high_ms {
+            return Err(Error::BadMessage(
+                "Padding timeout lower bound is above upper bound",
+            ));
+        }
         Ok(PaddingNegotiate {
             command,
             ito_low_ms,
@@ -34,7 +34,7 @@ impl HasKind for Error {
         use Error as E;
         use ErrorKind as EK;
         match self {
-            E::BytesErr(ByE::Truncated) => EK::Internal,
+            E::BytesErr(e) if matches!(e.without_position(), ByE::Truncated) => EK::Internal,
             E::BytesErr(_) => EK::TorProtocolViolation,
             E::Internal(_) => EK::Internal,
             E::ChanProto(_) => EK::TorProtocolViolation,
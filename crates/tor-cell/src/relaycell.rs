@@ -10,6 +10,7 @@ use caret::caret_int;
 use rand::{CryptoRng, Rng};
 
 pub mod msg;
+pub mod onion;
 
 caret_int! {
     /// A command that identifies the type of a relay cell
@@ -3,6 +3,10 @@
 //! Relay messages are sent along circuits, inside RELAY or RELAY_EARLY
 //! cells.
 
+use super::onion::{
+    EstablishIntro, EstablishRendezvous, IntroEstablished, Introduce1, Introduce2, IntroduceAck,
+    Rendezvous1, Rendezvous2,
+};
 use super::RelayCmd;
 use crate::chancell::msg::{DestroyReason, TAP_C_HANDSHAKE_LEN, TAP_S_HANDSHAKE_LEN};
 use crate::chancell::CELL_DATA_LEN;
@@ -51,9 +55,27 @@ pub enum RelayMsg {
     /// Start a directory stream
     BeginDir,
 
+    /// Onion services: ask a relay to be an introduction point
+    EstablishIntro(EstablishIntro),
+    /// Onion services: response to an EstablishIntro message
+    IntroEstablished(IntroEstablished),
+    /// Onion services: introduction from a client to an introduction point
+    Introduce1(Introduce1),
+    /// Onion services: introduction from an introduction point to a service
+    Introduce2(Introduce2),
+    /// Onion services: response to an Introduce1 message
+    IntroduceAck(IntroduceAck),
+    /// Onion services: ask a relay to be a rendezvous point
+    EstablishRendezvous(EstablishRendezvous),
+    /// Onion services: response to an EstablishRendezvous message
+    RendezvousEstablished,
+    /// Onion services: join a service's circuit to a client's
+    Rendezvous1(Rendezvous1),
+    /// Onion services: tell a client that its circuit has been joined
+    Rendezvous2(Rendezvous2),
+
     /// An unrecognized command.
     Unrecognized(Unrecognized),
-    // No hs for now.
 }
 
 /// Internal: traits in common different cell bodies.
@@ -92,6 +114,15 @@ impl RelayMsg {
             Resolve(_) => RelayCmd::RESOLVE,
             Resolved(_) => RelayCmd::RESOLVED,
             BeginDir => RelayCmd::BEGIN_DIR,
+            EstablishIntro(_) => RelayCmd::ESTABLISH_INTRO,
+            IntroEstablished(_) => RelayCmd::INTRO_ESTABLISHED,
+            Introduce1(_) => RelayCmd::INTRODUCE1,
+            Introduce2(_) => RelayCmd::INTRODUCE2,
+            IntroduceAck(_) => RelayCmd::INTRODUCE_ACK,
+            EstablishRendezvous(_) => RelayCmd::ESTABLISH_RENDEZVOUS,
+            RendezvousEstablished => RelayCmd::RENDEZVOUS_ESTABLISHED,
+            Rendezvous1(_) => RelayCmd::RENDEZVOUS1,
+            Rendezvous2(_) => RelayCmd::RENDEZVOUS2,
             Unrecognized(u) => u.cmd(),
         }
     }
@@ -113,6 +144,21 @@ impl RelayMsg {
             RelayCmd::RESOLVE => RelayMsg::Resolve(Resolve::decode_from_reader(r)?),
             RelayCmd::RESOLVED => RelayMsg::Resolved(Resolved::decode_from_reader(r)?),
             RelayCmd::BEGIN_DIR => RelayMsg::BeginDir,
+            RelayCmd::ESTABLISH_INTRO => {
+                RelayMsg::EstablishIntro(EstablishIntro::decode_from_reader(r)?)
+            }
+            RelayCmd::INTRO_ESTABLISHED => {
+                RelayMsg::IntroEstablished(IntroEstablished::decode_from_reader(r)?)
+            }
+            RelayCmd::INTRODUCE1 => RelayMsg::Introduce1(Introduce1::decode_from_reader(r)?),
+            RelayCmd::INTRODUCE2 => RelayMsg::Introduce2(Introduce2::decode_from_reader(r)?),
+            RelayCmd::INTRODUCE_ACK => RelayMsg::IntroduceAck(IntroduceAck::decode_from_reader(r)?),
+            RelayCmd::ESTABLISH_RENDEZVOUS => {
+                RelayMsg::EstablishRendezvous(EstablishRendezvous::decode_from_reader(r)?)
+            }
+            RelayCmd::RENDEZVOUS_ESTABLISHED => RelayMsg::RendezvousEstablished,
+            RelayCmd::RENDEZVOUS1 => RelayMsg::Rendezvous1(Rendezvous1::decode_from_reader(r)?),
+            RelayCmd::RENDEZVOUS2 => RelayMsg::Rendezvous2(Rendezvous2::decode_from_reader(r)?),
 
             _ => RelayMsg::Unrecognized(Unrecognized::decode_with_cmd(c, r)?),
         })
@@ -136,6 +182,15 @@ impl RelayMsg {
             Resolve(b) => b.encode_onto(w),
             Resolved(b) => b.encode_onto(w),
             BeginDir => (),
+            EstablishIntro(b) => b.encode_onto(w),
+            IntroEstablished(b) => b.encode_onto(w),
+            Introduce1(b) => b.encode_onto(w),
+            Introduce2(b) => b.encode_onto(w),
+            IntroduceAck(b) => b.encode_onto(w),
+            EstablishRendezvous(b) => b.encode_onto(w),
+            RendezvousEstablished => (),
+            Rendezvous1(b) => b.encode_onto(w),
+            Rendezvous2(b) => b.encode_onto(w),
             Unrecognized(b) => b.encode_onto(w),
         }
     }
@@ -182,7 +237,7 @@ impl From<IpVersionPreference> for BeginFlags {
             Ipv4Only => 0.into(),
             Ipv4Preferred => BeginFlags::IPV6_OKAY,
             Ipv6Preferred => BeginFlags::IPV6_OKAY | BeginFlags::IPV6_PREFERRED,
-            Ipv6Only => BeginFlags::IPV4_NOT_OKAY,
+            Ipv6Only => BeginFlags::IPV4_NOT_OKAY | BeginFlags::IPV6_OKAY,
         }
     }
 }
@@ -813,9 +868,11 @@ impl Body for Extend2 {
             w.write(ls);
         }
         w.write_u16(self.handshake_type);
-        let handshake_len: u16 = self.handshake.len().try_into().expect("Handshake too long");
-        w.write_u16(handshake_len);
-        w.write_all(&self.handshake[..]);
+        w.write_nested_u16len(|w| {
+            w.write_all(&self.handshake[..]);
+            Ok(())
+        })
+        .expect("Handshake too long");
     }
 }
 
@@ -852,9 +909,11 @@ impl Body for Extended2 {
         })
     }
     fn encode_onto(self, w: &mut Vec<u8>) {
-        let handshake_len: u16 = self.handshake.len().try_into().expect("Handshake too long");
-        w.write_u16(handshake_len);
-        w.write_all(&self.handshake[..]);
+        w.write_nested_u16len(|w| {
+            w.write_all(&self.handshake[..]);
+            Ok(())
+        })
+        .expect("Handshake too long");
     }
 }
 
@@ -966,6 +1025,19 @@ pub enum ResolvedVal {
     /// A DNS lookup response that we didn't recognize
     Unrecognized(u8, Vec<u8>),
 }
+
+/// An error that an exit reported in response to a Resolve message.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ResolveError {
+    /// The lookup failed, but trying again later might succeed.
+    #[error("transient error while resolving address")]
+    Transient,
+    /// The lookup failed, and trying again won't help.
+    #[error("nontransient error while resolving address")]
+    Nontransient,
+}
+
 /// Indicates a hostname response
 const RES_HOSTNAME: u8 = 0;
 /// Indicates an IPv4 response
@@ -1097,6 +1169,39 @@ impl Resolved {
     pub fn into_answers(self) -> Vec<(ResolvedVal, u32)> {
         self.answers
     }
+
+    /// Consume this Resolved message, returning either the successful
+    /// answers it contains (with their TTL values), or the error that it
+    /// reports.
+    ///
+    /// Every answer in the returned list is either a
+    /// [`ResolvedVal::Ip`] or a [`ResolvedVal::Hostname`]; answers of
+    /// unrecognized types are discarded.  If the message contains any
+    /// such answer, we ignore any error answers that it also contains,
+    /// as Tor does.  Otherwise, we return the first error answer, if
+    /// there is one.
+    ///
+    /// A message with no answers at all yields an empty list.
+    pub fn into_results(self) -> std::result::Result<Vec<(ResolvedVal, u32)>, ResolveError> {
+        let mut first_err = None;
+        let mut found = Vec::new();
+        for (val, ttl) in self.answers {
+            match val {
+                ResolvedVal::Ip(_) | ResolvedVal::Hostname(_) => found.push((val, ttl)),
+                ResolvedVal::TransientError => {
+                    first_err.get_or_insert(ResolveError::Transient);
+                }
+                ResolvedVal::NontransientError => {
+                    first_err.get_or_insert(ResolveError::Nontransient);
+                }
+                ResolvedVal::Unrecognized(_, _) => {}
+            }
+        }
+        match first_err {
+            Some(e) if found.is_empty() => Err(e),
+            _ => Ok(found),
+        }
+    }
 }
 impl Body for Resolved {
     fn into_message(self) -> RelayMsg {
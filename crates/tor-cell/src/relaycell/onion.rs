@@ -0,0 +1,777 @@
+//! Encoding and decoding for the relay messages used by onion services.
+//!
+//! These messages are defined in
+//! [rend-spec-v3.txt](https://spec.torproject.org/rend-spec-v3).  They
+//! are only used on circuits to introduction points and rendezvous
+//! points, and never on ordinary exit circuits.
+//!
+//! We only support the v3 forms of these messages: the legacy (v2)
+//! forms of ESTABLISH_INTRO and INTRODUCE1 are rejected when we decode
+//! them.
+//!
+//! This module only handles the framing of these messages.  It does not
+//! compute or check any of the MACs or signatures that they carry, and
+//! it does not encrypt or decrypt the body of an INTRODUCE message.
+
+use super::msg::{Body, RelayMsg};
+use caret::caret_int;
+use std::convert::TryInto;
+use tor_bytes::{Error, Readable, Reader, Result, Writeable, Writer};
+use tor_linkspec::LinkSpec;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+/// The length of a rendezvous cookie.
+pub const REND_COOKIE_LEN: usize = 20;
+
+caret_int! {
+    /// The type of an authentication key in an onion service message.
+    ///
+    /// Types 0 and 1 are reserved for legacy onion services; we don't
+    /// support them.
+    pub struct AuthKeyType(u8) {
+        /// An Ed25519 key, used with SHA3-256.
+        ED25519_SHA3_256 = 2,
+    }
+}
+
+impl AuthKeyType {
+    /// Return true if this key type is one of the types reserved for
+    /// legacy messages.
+    pub fn is_legacy(self) -> bool {
+        u8::from(self) < 2
+    }
+}
+
+/// An authentication key for an introduction point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthKey {
+    /// An Ed25519 key.
+    Ed25519(Ed25519Identity),
+    /// A key of a type that we don't recognize.
+    Unrecognized(AuthKeyType, Vec<u8>),
+}
+
+impl AuthKey {
+    /// Return the type of this key.
+    pub fn key_type(&self) -> AuthKeyType {
+        match self {
+            AuthKey::Ed25519(_) => AuthKeyType::ED25519_SHA3_256,
+            AuthKey::Unrecognized(t, _) => *t,
+        }
+    }
+}
+
+impl Readable for AuthKey {
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let key_type: AuthKeyType = r.take_u8()?.into();
+        if key_type.is_legacy() {
+            return Err(Error::BadMessage("legacy auth key types are not supported"));
+        }
+        let len = r.take_u16()? as usize;
+        let body = r.take(len)?;
+        Ok(match key_type {
+            AuthKeyType::ED25519_SHA3_256 => AuthKey::Ed25519(
+                Ed25519Identity::from_bytes(body)
+                    .ok_or(Error::BadMessage("wrong length for ed25519 auth key"))?,
+            ),
+            _ => AuthKey::Unrecognized(key_type, body.into()),
+        })
+    }
+}
+
+impl Writeable for AuthKey {
+    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
+        w.write_u8(self.key_type().into());
+        let body = match self {
+            AuthKey::Ed25519(id) => id.as_bytes(),
+            AuthKey::Unrecognized(_, body) => &body[..],
+        };
+        w.write_nested_u16len(|w| {
+            w.write_all(body);
+            Ok(())
+        })
+        .expect("Auth key too long");
+    }
+}
+
+caret_int! {
+    /// The type of an extension in an onion service message.
+    pub struct HsExtType(u8) {
+        /// Denial-of-service parameters for an introduction point.
+        ///
+        /// (Only used in ESTABLISH_INTRO.)
+        DOS_PARAMS = 1,
+    }
+}
+
+/// An extension field in an onion service message.
+///
+/// Several onion service messages have room for a list of these; we
+/// don't interpret any of them yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsExtension {
+    /// The type of this extension.
+    ext_type: HsExtType,
+    /// The body of this extension.
+    body: Vec<u8>,
+}
+
+impl HsExtension {
+    /// Construct a new extension of type `ext_type`.
+    ///
+    /// Return an error if `body` is too long to encode.
+    pub fn new(ext_type: HsExtType, body: Vec<u8>) -> crate::Result<Self> {
+        if body.len() > u8::MAX as usize {
+            return Err(crate::Error::CantEncode);
+        }
+        Ok(HsExtension { ext_type, body })
+    }
+    /// Return the type of this extension.
+    pub fn ext_type(&self) -> HsExtType {
+        self.ext_type
+    }
+    /// Return the body of this extension.
+    pub fn body(&self) -> &[u8] {
+        &self.body[..]
+    }
+}
+
+impl Readable for HsExtension {
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let ext_type = r.take_u8()?.into();
+        let len = r.take_u8()? as usize;
+        let body = r.take(len)?.into();
+        Ok(HsExtension { ext_type, body })
+    }
+}
+
+impl Writeable for HsExtension {
+    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
+        w.write_u8(self.ext_type.into());
+        w.write_nested_u8len(|w| {
+            w.write_all(&self.body[..]);
+            Ok(())
+        })
+        .expect("Extension too long");
+    }
+}
+
+/// Helper: read a one-byte count of extensions, and then that many
+/// extensions.
+fn take_extensions(r: &mut Reader<'_>) -> Result<Vec<HsExtension>> {
+    let n = r.take_u8()?;
+    r.extract_n(n as usize)
+}
+
+/// Helper: write a one-byte count of extensions, and then the extensions.
+fn write_extensions<W: Writer + ?Sized>(w: &mut W, extensions: &[HsExtension]) {
+    let n: u8 = extensions.len().try_into().expect("Too many extensions");
+    w.write_u8(n);
+    for ext in extensions {
+        w.write(ext);
+    }
+}
+
+/// An EstablishIntro message asks a relay to act as an introduction
+/// point for an onion service.
+///
+/// The service sends this message on a circuit to the relay, and the
+/// relay answers with an IntroEstablished message.
+#[derive(Debug, Clone)]
+pub struct EstablishIntro {
+    /// The key that identifies this introduction point.
+    auth_key: AuthKey,
+    /// Extensions for this message.
+    extensions: Vec<HsExtension>,
+    /// A MAC of the preceding fields, keyed with the circuit's
+    /// key-exchange material.
+    handshake_auth: [u8; 32],
+    /// A signature of all the preceding fields, made with `auth_key`.
+    sig: Vec<u8>,
+}
+
+impl EstablishIntro {
+    /// Construct a new EstablishIntro message.
+    pub fn new(
+        auth_key: AuthKey,
+        extensions: Vec<HsExtension>,
+        handshake_auth: [u8; 32],
+        sig: Vec<u8>,
+    ) -> Self {
+        EstablishIntro {
+            auth_key,
+            extensions,
+            handshake_auth,
+            sig,
+        }
+    }
+    /// Return the key that identifies this introduction point.
+    pub fn auth_key(&self) -> &AuthKey {
+        &self.auth_key
+    }
+    /// Return the extensions on this message.
+    pub fn extensions(&self) -> &[HsExtension] {
+        &self.extensions[..]
+    }
+    /// Return the MAC from this message.
+    pub fn handshake_auth(&self) -> &[u8; 32] {
+        &self.handshake_auth
+    }
+    /// Return the signature from this message.
+    pub fn sig(&self) -> &[u8] {
+        &self.sig[..]
+    }
+}
+
+impl Body for EstablishIntro {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::EstablishIntro(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        let auth_key = r.extract()?;
+        let extensions = take_extensions(r)?;
+        let handshake_auth = r.extract()?;
+        let sig_len = r.take_u16()?;
+        let sig = r.take(sig_len as usize)?.into();
+        Ok(EstablishIntro {
+            auth_key,
+            extensions,
+            handshake_auth,
+            sig,
+        })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        w.write(&self.auth_key);
+        write_extensions(w, &self.extensions);
+        w.write_all(&self.handshake_auth[..]);
+        w.write_nested_u16len(|w| {
+            w.write_all(&self.sig[..]);
+            Ok(())
+        })
+        .expect("Signature too long");
+    }
+}
+
+/// An IntroEstablished message tells an onion service that a relay has
+/// agreed to be its introduction point.
+#[derive(Debug, Clone, Default)]
+pub struct IntroEstablished {
+    /// Extensions for this message.
+    extensions: Vec<HsExtension>,
+}
+
+impl IntroEstablished {
+    /// Construct a new IntroEstablished message with no extensions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Return the extensions on this message.
+    pub fn extensions(&self) -> &[HsExtension] {
+        &self.extensions[..]
+    }
+}
+
+impl Body for IntroEstablished {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::IntroEstablished(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        // Older versions of Tor send an empty body here.
+        let extensions = if r.remaining() == 0 {
+            Vec::new()
+        } else {
+            take_extensions(r)?
+        };
+        Ok(IntroEstablished { extensions })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        write_extensions(w, &self.extensions);
+    }
+}
+
+/// The contents of an Introduce1 or Introduce2 message.
+///
+/// A client sends these contents to an introduction point in an
+/// Introduce1 message; the introduction point relays them to the
+/// service in an Introduce2 message.
+#[derive(Debug, Clone)]
+pub struct Introduce {
+    /// The key of the introduction point that this message is for.
+    auth_key: AuthKey,
+    /// Extensions for this message.
+    extensions: Vec<HsExtension>,
+    /// The encrypted part of the message, for the service to decrypt.
+    ///
+    /// (This is the client's ephemeral public key, the encrypted
+    /// [`IntroduceInner`], and a MAC.)
+    encrypted: Vec<u8>,
+}
+
+impl Introduce {
+    /// Construct the contents of a new Introduce1 or Introduce2 message.
+    pub fn new(auth_key: AuthKey, extensions: Vec<HsExtension>, encrypted: Vec<u8>) -> Self {
+        Introduce {
+            auth_key,
+            extensions,
+            encrypted,
+        }
+    }
+    /// Return the key of the introduction point that this message is
+    /// for.
+    pub fn auth_key(&self) -> &AuthKey {
+        &self.auth_key
+    }
+    /// Return the extensions on this message.
+    pub fn extensions(&self) -> &[HsExtension] {
+        &self.extensions[..]
+    }
+    /// Return the encrypted part of this message.
+    pub fn encrypted(&self) -> &[u8] {
+        &self.encrypted[..]
+    }
+    /// Encode these contents onto the end of `w`.
+    fn encode_onto(&self, w: &mut Vec<u8>) {
+        // This is where a legacy introduction would put a key digest.
+        w.write_zeros(20);
+        w.write(&self.auth_key);
+        write_extensions(w, &self.extensions);
+        w.write_all(&self.encrypted[..]);
+    }
+}
+
+impl Readable for Introduce {
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        // Legacy introductions put a key digest here; v3 ones use zeros.
+        let legacy_key_id: [u8; 20] = r.extract()?;
+        if legacy_key_id != [0; 20] {
+            return Err(Error::BadMessage(
+                "legacy INTRODUCE messages are not supported",
+            ));
+        }
+        let auth_key = r.extract()?;
+        let extensions = take_extensions(r)?;
+        let encrypted = r.take(r.remaining())?.into();
+        Ok(Introduce {
+            auth_key,
+            extensions,
+            encrypted,
+        })
+    }
+}
+
+/// An Introduce1 message asks an introduction point to pass an
+/// introduction on to an onion service.
+#[derive(Debug, Clone)]
+pub struct Introduce1(
+    /// The contents of this message.
+    Introduce,
+);
+
+impl Introduce1 {
+    /// Construct a new Introduce1 message.
+    pub fn new(body: Introduce) -> Self {
+        Introduce1(body)
+    }
+    /// Return the contents of this message.
+    pub fn body(&self) -> &Introduce {
+        &self.0
+    }
+    /// Consume this message and return its contents.
+    pub fn into_body(self) -> Introduce {
+        self.0
+    }
+}
+
+impl Body for Introduce1 {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::Introduce1(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(Introduce1(r.extract()?))
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        self.0.encode_onto(w);
+    }
+}
+
+/// An Introduce2 message is an introduction that an introduction point
+/// has passed on to an onion service.
+#[derive(Debug, Clone)]
+pub struct Introduce2(
+    /// The contents of this message.
+    Introduce,
+);
+
+impl Introduce2 {
+    /// Construct a new Introduce2 message.
+    pub fn new(body: Introduce) -> Self {
+        Introduce2(body)
+    }
+    /// Return the contents of this message.
+    pub fn body(&self) -> &Introduce {
+        &self.0
+    }
+    /// Consume this message and return its contents.
+    pub fn into_body(self) -> Introduce {
+        self.0
+    }
+}
+
+impl From<Introduce1> for Introduce2 {
+    fn from(msg: Introduce1) -> Self {
+        Introduce2(msg.0)
+    }
+}
+
+impl Body for Introduce2 {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::Introduce2(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(Introduce2(r.extract()?))
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        self.0.encode_onto(w);
+    }
+}
+
+caret_int! {
+    /// The type of an onion key in an [`IntroduceInner`].
+    pub struct OnionKeyType(u8) {
+        /// A curve25519 key for the ntor handshake.
+        NTOR = 1,
+    }
+}
+
+/// The plaintext of the encrypted part of an Introduce message.
+///
+/// This tells the service which rendezvous point to use, and how to
+/// extend a circuit to it.
+#[derive(Debug, Clone)]
+pub struct IntroduceInner {
+    /// The cookie that the client gave the rendezvous point.
+    cookie: [u8; REND_COOKIE_LEN],
+    /// Extensions for this message.
+    extensions: Vec<HsExtension>,
+    /// The type of the rendezvous point's onion key.
+    onion_key_type: OnionKeyType,
+    /// The rendezvous point's onion key.
+    onion_key: Vec<u8>,
+    /// Link specifiers for the rendezvous point.
+    linkspec: Vec<LinkSpec>,
+}
+
+impl IntroduceInner {
+    /// Construct a new IntroduceInner.
+    pub fn new(
+        cookie: [u8; REND_COOKIE_LEN],
+        extensions: Vec<HsExtension>,
+        onion_key_type: OnionKeyType,
+        onion_key: Vec<u8>,
+        linkspec: Vec<LinkSpec>,
+    ) -> Self {
+        IntroduceInner {
+            cookie,
+            extensions,
+            onion_key_type,
+            onion_key,
+            linkspec,
+        }
+    }
+    /// Return the rendezvous cookie.
+    pub fn cookie(&self) -> &[u8; REND_COOKIE_LEN] {
+        &self.cookie
+    }
+    /// Return the extensions on this message.
+    pub fn extensions(&self) -> &[HsExtension] {
+        &self.extensions[..]
+    }
+    /// Return the type of the rendezvous point's onion key.
+    pub fn onion_key_type(&self) -> OnionKeyType {
+        self.onion_key_type
+    }
+    /// Return the rendezvous point's onion key.
+    pub fn onion_key(&self) -> &[u8] {
+        &self.onion_key[..]
+    }
+    /// Return the link specifiers for the rendezvous point.
+    pub fn linkspecs(&self) -> &[LinkSpec] {
+        &self.linkspec[..]
+    }
+}
+
+impl Readable for IntroduceInner {
+    /// Decode an IntroduceInner, ignoring any padding that follows it.
+    fn take_from(r: &mut Reader<'_>) -> Result<Self> {
+        let cookie = r.extract()?;
+        let extensions = take_extensions(r)?;
+        let onion_key_type: OnionKeyType = r.take_u8()?.into();
+        let onion_key_len = r.take_u16()? as usize;
+        if onion_key_type == OnionKeyType::NTOR && onion_key_len != 32 {
+            return Err(Error::BadMessage("wrong length for ntor onion key"));
+        }
+        let onion_key = r.take(onion_key_len)?.into();
+        let n_linkspec = r.take_u8()?;
+        let linkspec = r.extract_n(n_linkspec as usize)?;
+        // The rest is padding.
+        r.take(r.remaining())?;
+        Ok(IntroduceInner {
+            cookie,
+            extensions,
+            onion_key_type,
+            onion_key,
+            linkspec,
+        })
+    }
+}
+
+impl Writeable for IntroduceInner {
+    /// Encode this IntroduceInner, without any padding.
+    ///
+    /// Callers should pad the output before they encrypt it, so that
+    /// different rendezvous points can't be told apart by length.
+    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
+        w.write_all(&self.cookie[..]);
+        write_extensions(w, &self.extensions);
+        w.write_u8(self.onion_key_type.into());
+        w.write_nested_u16len(|w| {
+            w.write_all(&self.onion_key[..]);
+            Ok(())
+        })
+        .expect("Onion key too long");
+        let n_linkspec: u8 = self.linkspec.len().try_into().expect("Too many linkspecs");
+        w.write_u8(n_linkspec);
+        for ls in &self.linkspec {
+            w.write(ls);
+        }
+    }
+}
+
+caret_int! {
+    /// The status code in an IntroduceAck message.
+    pub struct IntroduceAckStatus(u16) {
+        /// The introduction point relayed the message to the service.
+        SUCCESS = 0,
+        /// The introduction point didn't recognize the service.
+        NOT_RECOGNIZED = 1,
+        /// The Introduce1 message was malformed.
+        BAD_MESSAGE_FORMAT = 2,
+        /// The introduction point couldn't relay the message.
+        CANT_RELAY = 3,
+    }
+}
+
+/// An IntroduceAck message tells a client whether an introduction point
+/// relayed its Introduce1 message.
+#[derive(Debug, Clone)]
+pub struct IntroduceAck {
+    /// Whether the introduction was relayed.
+    status: IntroduceAckStatus,
+    /// Extensions for this message.
+    extensions: Vec<HsExtension>,
+}
+
+impl IntroduceAck {
+    /// Construct a new IntroduceAck message with no extensions.
+    pub fn new(status: IntroduceAckStatus) -> Self {
+        IntroduceAck {
+            status,
+            extensions: Vec::new(),
+        }
+    }
+    /// Return the status from this message.
+    pub fn status(&self) -> IntroduceAckStatus {
+        self.status
+    }
+    /// Return the extensions on this message.
+    pub fn extensions(&self) -> &[HsExtension] {
+        &self.extensions[..]
+    }
+}
+
+impl Body for IntroduceAck {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::IntroduceAck(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        // Legacy introduction points acknowledge success with an empty
+        // body.
+        if r.remaining() == 0 {
+            return Ok(IntroduceAck::new(IntroduceAckStatus::SUCCESS));
+        }
+        let status = r.take_u16()?.into();
+        let extensions = take_extensions(r)?;
+        Ok(IntroduceAck { status, extensions })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        w.write_u16(self.status.into());
+        write_extensions(w, &self.extensions);
+    }
+}
+
+/// An EstablishRendezvous message asks a relay to act as a rendezvous
+/// point for a client.
+#[derive(Debug, Clone)]
+pub struct EstablishRendezvous {
+    /// A cookie that the service will use to find this circuit.
+    cookie: [u8; REND_COOKIE_LEN],
+}
+
+impl EstablishRendezvous {
+    /// Construct a new EstablishRendezvous message.
+    pub fn new(cookie: [u8; REND_COOKIE_LEN]) -> Self {
+        EstablishRendezvous { cookie }
+    }
+    /// Return the rendezvous cookie.
+    pub fn cookie(&self) -> &[u8; REND_COOKIE_LEN] {
+        &self.cookie
+    }
+}
+
+impl Body for EstablishRendezvous {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::EstablishRendezvous(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(EstablishRendezvous {
+            cookie: r.extract()?,
+        })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        w.write_all(&self.cookie[..]);
+    }
+}
+
+/// A Rendezvous1 message asks a rendezvous point to join a service's
+/// circuit to a client's.
+#[derive(Debug, Clone)]
+pub struct Rendezvous1 {
+    /// The cookie that the client gave the rendezvous point.
+    cookie: [u8; REND_COOKIE_LEN],
+    /// The service's half of the handshake, to be passed on to the
+    /// client.
+    handshake_info: Vec<u8>,
+}
+
+impl Rendezvous1 {
+    /// Construct a new Rendezvous1 message.
+    pub fn new(cookie: [u8; REND_COOKIE_LEN], handshake_info: Vec<u8>) -> Self {
+        Rendezvous1 {
+            cookie,
+            handshake_info,
+        }
+    }
+    /// Return the rendezvous cookie.
+    pub fn cookie(&self) -> &[u8; REND_COOKIE_LEN] {
+        &self.cookie
+    }
+    /// Return the service's handshake information.
+    pub fn handshake_info(&self) -> &[u8] {
+        &self.handshake_info[..]
+    }
+}
+
+impl Body for Rendezvous1 {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::Rendezvous1(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        let cookie = r.extract()?;
+        let handshake_info = r.take(r.remaining())?.into();
+        Ok(Rendezvous1 {
+            cookie,
+            handshake_info,
+        })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        w.write_all(&self.cookie[..]);
+        w.write_all(&self.handshake_info[..]);
+    }
+}
+
+/// A Rendezvous2 message passes a service's handshake on to a client,
+/// once a rendezvous point has joined their circuits.
+#[derive(Debug, Clone)]
+pub struct Rendezvous2 {
+    /// The service's half of the handshake.
+    handshake_info: Vec<u8>,
+}
+
+impl Rendezvous2 {
+    /// Construct a new Rendezvous2 message.
+    pub fn new(handshake_info: Vec<u8>) -> Self {
+        Rendezvous2 { handshake_info }
+    }
+    /// Return the service's handshake information.
+    pub fn handshake_info(&self) -> &[u8] {
+        &self.handshake_info[..]
+    }
+}
+
+impl From<Rendezvous1> for Rendezvous2 {
+    fn from(msg: Rendezvous1) -> Self {
+        Rendezvous2 {
+            handshake_info: msg.handshake_info,
+        }
+    }
+}
+
+impl Body for Rendezvous2 {
+    fn into_message(self) -> RelayMsg {
+        RelayMsg::Rendezvous2(self)
+    }
+    fn decode_from_reader(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(Rendezvous2 {
+            handshake_info: r.take(r.remaining())?.into(),
+        })
+    }
+    fn encode_onto(self, w: &mut Vec<u8>) {
+        w.write_all(&self.handshake_info[..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn introduce_inner() {
+        let inner = IntroduceInner::new(
+            [7; REND_COOKIE_LEN],
+            vec![HsExtension::new(33.into(), b"hi".to_vec()).unwrap()],
+            OnionKeyType::NTOR,
+            vec![9; 32],
+            vec![
+                LinkSpec::OrPort("127.0.0.1".parse().unwrap(), 9001),
+                LinkSpec::RsaId([4; 20].into()),
+            ],
+        );
+        let mut encoded = Vec::new();
+        encoded.write(&inner);
+        // Padding is ignored.
+        encoded.write_zeros(50);
+
+        let mut r = Reader::from_slice(&encoded[..]);
+        let decoded: IntroduceInner = r.extract().unwrap();
+        assert_eq!(decoded.cookie(), &[7; REND_COOKIE_LEN]);
+        assert_eq!(decoded.extensions(), inner.extensions());
+        assert_eq!(decoded.onion_key_type(), OnionKeyType::NTOR);
+        assert_eq!(decoded.onion_key(), &[9; 32][..]);
+        assert_eq!(decoded.linkspecs(), inner.linkspecs());
+
+        // An ntor key has to be the right length.
+        let bad = IntroduceInner::new([7; 20], vec![], OnionKeyType::NTOR, vec![9; 31], vec![]);
+        let mut encoded = Vec::new();
+        encoded.write(&bad);
+        let mut r = Reader::from_slice(&encoded[..]);
+        assert!(r.extract::<IntroduceInner>().is_err());
+    }
+
+    #[test]
+    fn extension_too_long() {
+        assert!(HsExtension::new(1.into(), vec![0; 255]).is_ok());
+        assert!(HsExtension::new(1.into(), vec![0; 256]).is_err());
+    }
+}
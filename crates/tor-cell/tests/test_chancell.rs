@@ -80,6 +80,12 @@ fn test_simple_cells() {
         0x20201122.into(),
     );
 
+    fcell(
+        "00000000 0c 00 02 05DC 251C",
+        msg::PaddingNegotiate::new(true, 1500, 9500).into(),
+        0.into(),
+    );
+
     // Now try some accessors.
     let m = decode(
         "20201122 03 666f72206120636f64652063616c6c656420706561636574696d65",
@@ -7,6 +7,7 @@ use tor_bytes::Error as BytesError;
 use tor_cell::chancell::{msg, ChanCmd};
 
 use std::net::IpAddr;
+use std::time::Duration;
 
 use hex_literal::hex;
 
@@ -393,9 +394,57 @@ fn test_padding_negotiate() {
         "00 02 0100 0200",
         &msg::PaddingNegotiate::new(true, 256, 512).into(),
     );
+    fbody(
+        cmd,
+        "00 02 05DC 251C",
+        &msg::PaddingNegotiate::start(Duration::from_millis(1500)..=Duration::from_millis(9500))
+            .into(),
+    );
+    fbody(
+        cmd,
+        "00 01 0000 0000",
+        &msg::PaddingNegotiate::stop().into(),
+    );
+
+    // Timeouts that are too long for the cell saturate.
+    fbody(
+        cmd,
+        "00 02 EA60 FFFF",
+        &msg::PaddingNegotiate::start(Duration::from_secs(60)..=Duration::from_secs(3600)).into(),
+    );
+    // An empty range turns into a single value.
+    fbody(
+        cmd,
+        "00 02 03E8 03E8",
+        &msg::PaddingNegotiate::start(Duration::from_secs(5)..=Duration::from_secs(1)).into(),
+    );
+
+    let (_, m) = test_decode(cmd, "00 02 05DC 251C", true);
+    if let msg::ChanMsg::PaddingNegotiate(m) = m {
+        assert_eq!(m.command(), msg::PaddingNegotiateCmd::START);
+        assert_eq!(m.ito_low_ms(), 1500);
+        assert_eq!(m.ito_high_ms(), 9500);
+    } else {
+        panic!("that wasn't a padding_negotiate");
+    }
 
+    // When starting, the timeouts have to be in order.
     assert_eq!(
-        decode_err(cmd, "90 0303", true),
-        BytesError::BadMessage("Unrecognized padding negotiation version")
+        decode_err(cmd, "00 02 0200 0100", true),
+        BytesError::BadMessage("Padding timeout lower bound is above upper bound")
+    );
+    // ... but when stopping, we don't care about them.
+    fbody(
+        cmd,
+        "00 01 0200 0100",
+        &msg::PaddingNegotiate::new(false, 512, 256).into(),
+    );
+
+    // We have to ignore versions that we don't know about, so we decode
+    // them as unrecognized messages.
+    fbody(
+        cmd,
+        "90 0303",
+        &msg::Unrecognized::new(cmd, unhex("90 0303", true)).into(),
     );
 }
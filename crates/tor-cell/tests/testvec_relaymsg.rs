@@ -4,9 +4,10 @@ use tor_bytes::Error as BytesError;
 /// Except where noted, these were taken by instrumenting Tor
 /// 0.4.5.0-alpha-dev to dump all of its cells to the logs, and
 /// running in a chutney network with "test-network-all".
-use tor_cell::relaycell::{msg, RelayCmd};
+use tor_cell::relaycell::{msg, onion, RelayCmd};
 use tor_llcrypto::pk::rsa::RsaIdentity;
 
+use std::convert::TryInto;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use hex_literal::hex;
@@ -108,6 +109,18 @@ fn test_begin() {
     ));
 }
 
+#[test]
+fn test_begin_flags() {
+    use msg::{BeginFlags, IpVersionPreference as P};
+
+    assert_eq!(BeginFlags::from(P::Ipv4Only).bits(), 0);
+    assert_eq!(BeginFlags::from(P::Ipv4Preferred).bits(), 1);
+    assert_eq!(BeginFlags::from(P::Ipv6Preferred).bits(), 5);
+    // An IPv6-only stream has to say that IPv6 is okay, or the exit
+    // won't be able to use any address at all.
+    assert_eq!(BeginFlags::from(P::Ipv6Only).bits(), 3);
+}
+
 #[test]
 fn test_begindir() {
     let cmd = RelayCmd::BEGIN_DIR;
@@ -192,8 +205,6 @@ fn test_extend2() {
     let cmd = RelayCmd::EXTEND2;
     assert_eq!(Into::<u8>::into(cmd), 14_u8);
 
-    // TODO: test one with more link specifiers.
-
     let body =
         "02
          00 06 7F0000011388
@@ -221,6 +232,20 @@ fn test_extend2() {
     } else {
         panic!("that wasn't an extend2");
     }
+
+    // One with a link specifier that we don't recognize.  (Not from Tor.)
+    let ls = vec![
+        addr.into(),
+        tor_linkspec::LinkSpec::Unrecognized(77, b"strange".to_vec()),
+    ];
+    msg(
+        cmd,
+        "02
+         00 06 7F0000011388
+         4D 07 737472616E6765
+         0002 0003 010203",
+        &msg::Extend2::new(ls, 2, vec![1, 2, 3]).into(),
+    );
 }
 
 #[test]
@@ -289,31 +314,234 @@ PADDING_NEGOTIATED, 42, "0002010000000001"
 PADDING_NEGOTIATED, 42, "0002010100000001"
 */
 
-/*  For onion services only:
+#[test]
+fn test_establish_intro() {
+    let cmd = RelayCmd::ESTABLISH_INTRO;
+    assert_eq!(Into::<u8>::into(cmd), 32_u8);
+
+    let auth_key = hex!("0450EFF847E40C180888F5EB9179F9B59F043385834B4C373C328E12FDFF46B4");
+    let handshake_auth = hex!("546514E3BA58E95409828A235B6390B3729B4CB8E8607024081C860E1A0D40DC");
+    let sig = hex!(
+        "AF031A801FE9822853D4674C5061B0352F7E2487415E25E25554C0DBF88146BC
+         A9EBD2BD62338ADF3CC217658110EF38DB505C77B2FB38A1C0AF3C22C948F604"
+    );
+    msg(
+        cmd,
+        "02 0020 0450EFF847E40C180888F5EB9179F9B59F043385834B4C373C328E12FDFF46B4
+         00
+         546514E3BA58E95409828A235B6390B3729B4CB8E8607024081C860E1A0D40DC
+         0040
+         AF031A801FE9822853D4674C5061B0352F7E2487415E25E25554C0DBF88146BC
+         A9EBD2BD62338ADF3CC217658110EF38DB505C77B2FB38A1C0AF3C22C948F604",
+        &onion::EstablishIntro::new(
+            onion::AuthKey::Ed25519(auth_key.into()),
+            vec![],
+            handshake_auth,
+            sig.to_vec(),
+        )
+        .into(),
+    );
+
+    // With an extension. (Not from Tor.)
+    let dos = onion::HsExtension::new(
+        onion::HsExtType::DOS_PARAMS,
+        hex!("01 01 0000000000000019").to_vec(),
+    )
+    .unwrap();
+    msg(
+        cmd,
+        "02 0020 0450EFF847E40C180888F5EB9179F9B59F043385834B4C373C328E12FDFF46B4
+         01 01 0A 01010000000000000019
+         546514E3BA58E95409828A235B6390B3729B4CB8E8607024081C860E1A0D40DC
+         0002 ABCD",
+        &onion::EstablishIntro::new(
+            onion::AuthKey::Ed25519(auth_key.into()),
+            vec![dos],
+            handshake_auth,
+            vec![0xAB, 0xCD],
+        )
+        .into(),
+    );
 
-ESTABLISH_INTRO, 32, "008C30818902818100D2419B56BFB89D35EE9EB6FD328EDE897C29DA6DF68E589812D2EEC030C55A56FB010E06097A0A93EEDD8DE351A32DAAF5C7B232DC22E549EF25E8CF5E338C1C12C7828624E61B2700E931B7D532951E8907A477720B087840B7AD9D487D9F1AFBEAEAD2A7C3D9D1EB0E579FFEB9AC2BAA181FE76397D299C469B46969906BD9020301000169529D1D09554CA1C45083A7DDC96BAE22146FC09BD3B9266D17CDEE66EB2D0B7ABBC828ED300BEC8851A2178AFE0FC671D7CC7A7C0A36BE854BBD6AAD7AF4C44F32B804788B5EDA2C0AB041E61AC6C901DCB212356E8D2A00463D6A5B17C1A2DAA409A8E926FAF6592A8C7CF2B45FD8C4A218595016BF52098878FD6B1EDB11D91D32D1B62DED57AB67AB69886E1374B56CFB9E"
+    // An extension that claims to run off the end of the message.
+    msg_error(
+        cmd,
+        "02 0020 0450EFF847E40C180888F5EB9179F9B59F043385834B4C373C328E12FDFF46B4
+         01 01 0B 01010000000000000019",
+        BytesError::Truncated,
+    );
+    // An ed25519 key of the wrong length.
+    msg_error(
+        cmd,
+        "02 0004 0450EFF8 00",
+        BytesError::BadMessage("wrong length for ed25519 auth key"),
+    );
 
-    ESTABLISH_INTRO, 32, "0200200450EFF847E40C180888F5EB9179F9B59F043385834B4C373C328E12FDFF46B400546514E3BA58E95409828A235B6390B3729B4CB8E8607024081C860E1A0D40DC0040AF031A801FE9822853D4674C5061B0352F7E2487415E25E25554C0DBF88146BCA9EBD2BD62338ADF3CC217658110EF38DB505C77B2FB38A1C0AF3C22C948F604"
+    // A legacy ESTABLISH_INTRO from a v2 onion service.
+    msg_error(
+        cmd,
+        "008C30818902818100D2419B56BFB89D35EE9EB6FD328EDE897C29DA6DF68E58
+         9812D2EEC030C55A56FB010E06097A0A93EEDD8DE351A32DAAF5C7B232DC22E5
+         49EF25E8CF5E338C1C12C7828624E61B2700E931B7D532951E8907A477720B08
+         7840B7AD9D487D9F1AFBEAEAD2A7C3D9D1EB0E579FFEB9AC2BAA181FE76397D2
+         99C469B46969906BD9020301000169529D1D09554CA1C45083A7DDC96BAE2214
+         6FC09BD3B9266D17CDEE66EB2D0B7ABBC828ED300BEC8851A2178AFE0FC671D7
+         CC7A7C0A36BE854BBD6AAD7AF4C44F32B804788B5EDA2C0AB041E61AC6C901DC
+         B212356E8D2A00463D6A5B17C1A2DAA409A8E926FAF6592A8C7CF2B45FD8C4A2
+         18595016BF52098878FD6B1EDB11D91D32D1B62DED57AB67AB69886E1374B56C
+         FB9E",
+        BytesError::BadMessage("legacy auth key types are not supported"),
+    );
+}
 
-ESTABLISH_RENDEZVOUS, 33, "4AA2BAF815CEBB4B922B5BF6F545AEE0DDBE1254"
+#[test]
+fn test_intro_established() {
+    let cmd = RelayCmd::INTRO_ESTABLISHED;
+    assert_eq!(Into::<u8>::into(cmd), 38_u8);
 
-INTRODUCE1, 34, "000000000000000000000000000000000000000002002011AAA1BA28353424061A63326C3BFF4FE4EC7EC24BB5EB740E9D167DEF103206001E99C1B9B0C24E6C63E9A182C33A8969DF94D08FD9FE98D82E21B262AE6CBC4E58971D6A38426C5D52519AB03377274AF74D2332C8FB599BFCBC5F2B02ECDAC4025EE24B63A824101E29EB9917B1C3A72E16FA2336DC81D0D483D31B9181F7CF628272FA7988301ADCE3B400880C25633718503B10C9F8909DFA50AEEDB90D7FD9D0090DBFAC4E61D527F97FD8C0A8BAB3DABF9A5E3C7FCD5FF843905F2C401743F5019B04A2F1427D3B2343A388846B7EACDD9FD46A24776D0DDDE3696A90EE36DC4732A95BFAFF871AEDE3BB5DD86047905B716148A27FF3C8A5B0F5282DD5430DA7E2A421D5274281EAC6C0EAE94EB17996EE81953FB700DD6D1DC4B7"
+    msg(cmd, "00", &onion::IntroEstablished::new().into());
+    // (Not from Tor.)
+    msg_noncanonical(cmd, "", "00", &onion::IntroEstablished::new().into());
+}
 
-INTRODUCE1, 34, "3AA272C26715FC3EEFE93C1CEE9ED90ACF702221338BE4032FF7CC8D7B8CF0EFB08FF51C3BCE0A289047ADF9BE51129E5FA81D40307B69577DCC3A899827D4287B00BDD661CA90BE2045D0A86FFEBF4EB9D3135B971B40D587D82F04E00EB3ED0CE3BBD70B2FF32D3BF08AD1A56EFC7C16461D7487506BC58319BFEFE936AAB18B0ACD308EF7F830370CC52A6115819EFE2BED3FC1234EDDF21321AE9D495AD7923BB0B6F275F19B636010D4E46468C366E20C5581730896BB6E2E684C75412E25A27924ED5F682122C0A7F26099E97531212558CD5E289C9CB72D1E09304037EC6A856B50B65642C20E307C4F201392C70764F3DB8AB4F6BC02C546D5FBAD4C3A4426347EECA864A990201E4AA8538104A9ABCA33716E900A7FE6A480FD40735E36C473B943E1BAA7A201397608E0C2C6B96D453B22B1C2A337463FD6BA89E9636FF6AFF0DD19FD121AB4F6605D8102E814FF1C7A31F3A07B1C49FEEC3A89299E60138349C3555D61884F35F3947A49D0E8359D72F7B6D0FCD1D8A489E668AE8A6DB3461A24109323E9BC19FF72413E"
+#[test]
+fn test_introduce() {
+    let cmd = RelayCmd::INTRODUCE1;
+    assert_eq!(Into::<u8>::into(cmd), 34_u8);
+
+    let body = "000000000000000000000000000000000000000002002011AAA1BA2835342406
+         1A63326C3BFF4FE4EC7EC24BB5EB740E9D167DEF103206001E99C1B9B0C24E6C
+         63E9A182C33A8969DF94D08FD9FE98D82E21B262AE6CBC4E58971D6A38426C5D
+         52519AB03377274AF74D2332C8FB599BFCBC5F2B02ECDAC4025EE24B63A82410
+         1E29EB9917B1C3A72E16FA2336DC81D0D483D31B9181F7CF628272FA7988301A
+         DCE3B400880C25633718503B10C9F8909DFA50AEEDB90D7FD9D0090DBFAC4E61
+         D527F97FD8C0A8BAB3DABF9A5E3C7FCD5FF843905F2C401743F5019B04A2F142
+         7D3B2343A388846B7EACDD9FD46A24776D0DDDE3696A90EE36DC4732A95BFAFF
+         871AEDE3BB5DD86047905B716148A27FF3C8A5B0F5282DD5430DA7E2A421D527
+         4281EAC6C0EAE94EB17996EE81953FB700DD6D1DC4B7";
+    let auth_key = hex!("11AAA1BA28353424061A63326C3BFF4FE4EC7EC24BB5EB740E9D167DEF103206");
+    // The encrypted part starts after the legacy key ID, the auth key, and
+    // the (empty) list of extensions.
+    let encrypted = unhex(body)[20 + 1 + 2 + 32 + 1..].to_vec();
+    let intro = onion::Introduce::new(
+        onion::AuthKey::Ed25519(auth_key.into()),
+        vec![],
+        encrypted.clone(),
+    );
+    msg(cmd, body, &onion::Introduce1::new(intro.clone()).into());
 
-INTRODUCE_ACK, 40, ""
-INTRODUCE_ACK, 40, "000000"
+    let message = decode(cmd, &unhex(body)[..]).unwrap();
+    if let msg::RelayMsg::Introduce1(message) = message {
+        assert_eq!(
+            message.body().auth_key(),
+            &onion::AuthKey::Ed25519(auth_key.into())
+        );
+        assert_eq!(message.body().encrypted(), &encrypted[..]);
 
-INTRO_ESTABLISHED, 38, "00"
+        // An introduction point passes the same body on to the service.
+        let cmd = RelayCmd::INTRODUCE2;
+        assert_eq!(Into::<u8>::into(cmd), 35_u8);
+        msg(cmd, body, &onion::Introduce2::from(message).into());
+    } else {
+        panic!("that wasn't an introduce1");
+    }
 
-RENDEZVOUS1, 36, "1757F14E8378746756B5F91D7898084F646DCF5E537657DE71774B44A3A9840BB3D881F24792B74758D67B7B95468537CF9C706992F76AA87E0AC278C3B75B4D747251F7C92CC2FB900249A316771D82B95F669A85C38AC0AD511D00661DA38B060BD297FCC6719EAD00C0301619E20B48B1F150FA42311511E5E683C34C8CD81273683190B018239554956BDB1B8AAE0951D2B5C32CF0EB2B4CC498FABE347DF1B6F273D0E976FE"
+    // A key type that we don't recognize. (Not from Tor.)
+    msg(
+        cmd,
+        "0000000000000000000000000000000000000000
+         09 0003 010203
+         01 05 00
+         FFFF",
+        &onion::Introduce1::new(onion::Introduce::new(
+            onion::AuthKey::Unrecognized(9.into(), vec![1, 2, 3]),
+            vec![onion::HsExtension::new(5.into(), vec![]).unwrap()],
+            vec![0xFF, 0xFF],
+        ))
+        .into(),
+    );
 
+    // A legacy INTRODUCE1 for a v2 onion service.
+    msg_error(
+        cmd,
+        "3AA272C26715FC3EEFE93C1CEE9ED90ACF702221338BE4032FF7CC8D7B8CF0EF
+         B08FF51C3BCE0A289047ADF9BE51129E5FA81D40307B69577DCC3A899827D428
+         7B00BDD661CA90BE2045D0A86FFEBF4EB9D3135B971B40D587D82F04E00EB3ED
+         0CE3BBD70B2FF32D3BF08AD1A56EFC7C16461D7487506BC58319BFEFE936AAB1
+         8B0ACD308EF7F830370CC52A6115819EFE2BED3FC1234EDDF21321AE9D495AD7
+         923BB0B6F275F19B636010D4E46468C366E20C5581730896BB6E2E684C75412E
+         25A27924ED5F682122C0A7F26099E97531212558CD5E289C9CB72D1E09304037
+         EC6A856B50B65642C20E307C4F201392C70764F3DB8AB4F6BC02C546D5FBAD4C
+         3A4426347EECA864A990201E4AA8538104A9ABCA33716E900A7FE6A480FD4073
+         5E36C473B943E1BAA7A201397608E0C2C6B96D453B22B1C2A337463FD6BA89E9
+         636FF6AFF0DD19FD121AB4F6605D8102E814FF1C7A31F3A07B1C49FEEC3A8929
+         9E60138349C3555D61884F35F3947A49D0E8359D72F7B6D0FCD1D8A489E668AE
+         8A6DB3461A24109323E9BC19FF72413E",
+        BytesError::BadMessage("legacy INTRODUCE messages are not supported"),
+    );
+}
 
-RENDEZVOUS2, 37, "2CBF819E67317EE501880FBE18515C440FB2F6AEA5D7B4349EFC478A714C237309C0FB63B35DC820513A0DEBED469B3607C06A2B7875B6394019C1081954AA1F77C141C7F4B9772D4026D3F2567CE4BAAC589E4DEACE285A33F12BEFF16FEF6120DBB2E0B1BCF78B2E765DB23464EABA3FC6C5126D551BAA32F7A179AB1BD888E0CB8F1E9CA0D8CE6583C144A551D564652829BA"
+#[test]
+fn test_introduce_ack() {
+    let cmd = RelayCmd::INTRODUCE_ACK;
+    assert_eq!(Into::<u8>::into(cmd), 40_u8);
+
+    let success = onion::IntroduceAck::new(onion::IntroduceAckStatus::SUCCESS);
+    msg(cmd, "000000", &success.clone().into());
+    // Legacy introduction points send an empty body.
+    msg_noncanonical(cmd, "", "000000", &success.into());
+    // (Not from Tor.)
+    msg(
+        cmd,
+        "0002 00",
+        &onion::IntroduceAck::new(onion::IntroduceAckStatus::BAD_MESSAGE_FORMAT).into(),
+    );
+    msg_error(cmd, "0000 02 01 00", BytesError::Truncated);
+}
 
-RENDEZVOUS_ESTABLISHED, 39, ""
+#[test]
+fn test_rendezvous() {
+    let cmd = RelayCmd::ESTABLISH_RENDEZVOUS;
+    assert_eq!(Into::<u8>::into(cmd), 33_u8);
+    let cookie = hex!("4AA2BAF815CEBB4B922B5BF6F545AEE0DDBE1254");
+    msg(
+        cmd,
+        "4AA2BAF815CEBB4B922B5BF6F545AEE0DDBE1254",
+        &onion::EstablishRendezvous::new(cookie).into(),
+    );
 
- */
+    let cmd = RelayCmd::RENDEZVOUS_ESTABLISHED;
+    assert_eq!(Into::<u8>::into(cmd), 39_u8);
+    msg(cmd, "", &msg::RelayMsg::RendezvousEstablished);
+
+    let cmd = RelayCmd::RENDEZVOUS1;
+    assert_eq!(Into::<u8>::into(cmd), 36_u8);
+    let body = "1757F14E8378746756B5F91D7898084F646DCF5E537657DE71774B44A3A9840B
+         B3D881F24792B74758D67B7B95468537CF9C706992F76AA87E0AC278C3B75B4D
+         747251F7C92CC2FB900249A316771D82B95F669A85C38AC0AD511D00661DA38B
+         060BD297FCC6719EAD00C0301619E20B48B1F150FA42311511E5E683C34C8CD8
+         1273683190B018239554956BDB1B8AAE0951D2B5C32CF0EB2B4CC498FABE347D
+         F1B6F273D0E976FE";
+    let body = unhex(body);
+    let (cookie, handshake_info) = body.split_at(20);
+    let rend1 = onion::Rendezvous1::new(cookie.try_into().unwrap(), handshake_info.to_vec());
+    msg(cmd, &hex::encode(&body), &rend1.clone().into());
+
+    // The rendezvous point passes the handshake on to the client.
+    let cmd = RelayCmd::RENDEZVOUS2;
+    assert_eq!(Into::<u8>::into(cmd), 37_u8);
+    msg(
+        cmd,
+        &hex::encode(handshake_info),
+        &onion::Rendezvous2::from(rend1).into(),
+    );
+    let body = "2CBF819E67317EE501880FBE18515C440FB2F6AEA5D7B4349EFC478A714C2373
+         09C0FB63B35DC820513A0DEBED469B3607C06A2B7875B6394019C1081954AA1F
+         77C141C7F4B9772D4026D3F2567CE4BAAC589E4DEACE285A33F12BEFF16FEF61
+         20DBB2E0B1BCF78B2E765DB23464EABA3FC6C5126D551BAA32F7A179AB1BD888
+         E0CB8F1E9CA0D8CE6583C144A551D564652829BA";
+    msg(cmd, body, &onion::Rendezvous2::new(unhex(body)).into());
+}
 
 #[test]
 fn test_resolve() {
@@ -410,6 +638,60 @@ fn test_resolved() {
         "04 03 010203 00000001",
         BytesError::BadMessage("Wrong length for RESOLVED answer"),
     );
+    msg_error(
+        cmd,
+        "06 04 01020304 00000001",
+        BytesError::BadMessage("Wrong length for RESOLVED answer"),
+    );
+    msg_error(cmd, "04 04 7f000001 0000", BytesError::Truncated);
+}
+
+#[test]
+fn test_resolved_results() {
+    use msg::{ResolveError, ResolvedVal};
+    let cmd = RelayCmd::RESOLVED;
+    let results = |s: &str| match decode(cmd, &unhex(s)[..]).unwrap() {
+        msg::RelayMsg::Resolved(r) => r.into_results(),
+        _ => panic!("wrong message type"),
+    };
+
+    // A reverse lookup, with a hostname answer.
+    assert_eq!(
+        results("00 12 7777772e746f7270726f6a6563742e6f7267 00000258"),
+        Ok(vec![(
+            ResolvedVal::Hostname("www.torproject.org".into()),
+            600
+        )])
+    );
+
+    // Addresses of both types, and something we don't recognize.
+    assert_eq!(
+        results(
+            "04 04 7f000001 00000E10
+             63 02 abcd 00000001
+             06 10 12340000000000000000000000005678 00000080"
+        ),
+        Ok(vec![
+            (ResolvedVal::Ip("127.0.0.1".parse().unwrap()), 3600),
+            (ResolvedVal::Ip("1234::5678".parse().unwrap()), 128),
+        ])
+    );
+
+    // Errors alone are reported as errors; the first one wins.
+    assert_eq!(results("F0 00 00000E10"), Err(ResolveError::Transient));
+    assert_eq!(
+        results("F1 00 00000E10 F0 00 00000E10"),
+        Err(ResolveError::Nontransient)
+    );
+
+    // But if there are any answers, we ignore the errors.
+    assert_eq!(
+        results("F0 00 00000E10 04 04 7f000002 00001c20 F1 00 00000001"),
+        Ok(vec![(ResolvedVal::Ip("127.0.0.2".parse().unwrap()), 7200)])
+    );
+
+    // Nothing at all is not an error.
+    assert_eq!(results(""), Ok(vec![]));
 }
 
 #[test]
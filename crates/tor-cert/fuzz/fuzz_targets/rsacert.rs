@@ -1,7 +1,19 @@
 #![no_main]
 use libfuzzer_sys::fuzz_target;
 use tor_cert::rsa::RsaCrosscert;
+use tor_cert::CrosscertDecodeError as E;
 
 fuzz_target!(|data: &[u8]| {
-    let _ = RsaCrosscert::decode(data);
+    match RsaCrosscert::decode_detailed(data) {
+        Ok(_) => {}
+        Err(E::TooShort(_))
+        | Err(E::BadSubjectKey(_))
+        | Err(E::BadExpiration(_))
+        | Err(E::BadSigLen(_))
+        | Err(E::TrailingData(_)) => {}
+        Err(e) => panic!(
+            "unexpected error from RsaCrosscert::decode_detailed: {:?}",
+            e
+        ),
+    }
 });
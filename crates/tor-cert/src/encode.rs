@@ -0,0 +1,399 @@
+//! Code for constructing and signing new Ed25519 certificates.
+//!
+//! Clients only ever need to decode certificates; relays, onion services,
+//! and tests need to make them too.
+
+use crate::{
+    expiry_from_hours, CertExt, CertType, CertifiedKey, Ed25519Cert, ExtType, SignedWithEd25519Ext,
+    UnrecognizedExt,
+};
+use signature::Signer;
+use tor_bytes::Writer;
+use tor_llcrypto::pk::ed25519;
+
+use std::convert::TryFrom;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// An error that occurred while trying to construct a new certificate.
+#[derive(Clone, Debug, Error)]
+#[non_exhaustive]
+pub enum CertEncodeError {
+    /// A field that every certificate needs wasn't set.
+    #[error("Certificate has no {0}")]
+    MissingField(&'static str),
+    /// The certificate would already be expired.
+    #[error("Certificate would already be expired")]
+    AlreadyExpired,
+    /// The expiration time can't be represented in a certificate.
+    #[error("Certificate expiration time is out of range")]
+    ExpirationOutOfRange,
+    /// The certificate would have more than 255 extensions.
+    #[error("Too many extensions on certificate")]
+    TooManyExtensions,
+    /// An extension's body would be longer than 65535 bytes.
+    #[error("Certificate extension body is too long")]
+    ExtensionTooLong,
+    /// The key we were asked to sign with isn't the one named in the
+    /// certificate's signed-with-ed25519-key extension.
+    #[error("Signing key does not match the key in the certificate")]
+    KeyMismatch,
+    /// The private key couldn't make a signature.
+    #[error("Unable to sign certificate")]
+    SigningFailed,
+    /// The signature would be too long to encode in the certificate.
+    #[error("Certificate signature is too long")]
+    SignatureTooLong,
+    /// We couldn't parse the certificate that we encoded.
+    ///
+    /// This can happen if one of its unrecognized extensions uses the type
+    /// of an extension that we do recognize, but has an invalid body.
+    #[error("Encoded certificate is not well-formed")]
+    Unparseable(#[source] tor_bytes::Error),
+}
+
+/// A builder for a new [`Ed25519Cert`].
+///
+/// Set the certificate's type, expiration time, and subject key (all of
+/// which are required), along with any optional extensions, and then call
+/// [`encode_and_sign`](Ed25519CertConstructor::encode_and_sign).
+///
+/// # Example
+///
+/// ```
+/// use tor_cert::{CertType, CertifiedKey, Ed25519Cert};
+/// use tor_llcrypto::pk::ed25519;
+/// use std::time::{Duration, SystemTime};
+///
+/// # let secret = ed25519::SecretKey::from_bytes(&[7; 32]).unwrap();
+/// # let public = (&secret).into();
+/// # let signing_keypair = ed25519::Keypair { secret, public };
+/// # let subject_key = ed25519::PublicKey::from(&ed25519::SecretKey::from_bytes(&[8; 32]).unwrap());
+/// let (_encoded, cert) = Ed25519Cert::constructor()
+///     .cert_type(CertType::IDENTITY_V_SIGNING)
+///     .expiration(SystemTime::now() + Duration::from_secs(86400))
+///     .cert_key(CertifiedKey::Ed25519(subject_key))
+///     .signing_key(signing_keypair.public)
+///     .encode_and_sign(&signing_keypair, SystemTime::now())
+///     .unwrap();
+/// assert_eq!(cert.subject_key().as_ed25519(), Some(&subject_key));
+/// ```
+#[derive(Clone, Default)]
+pub struct Ed25519CertConstructor {
+    /// The type of the certificate.
+    cert_type: Option<CertType>,
+    /// The time at which the certificate should expire.  (We round this up
+    /// to the next hour.)
+    expiration: Option<SystemTime>,
+    /// The key or object being certified.
+    cert_key: Option<CertifiedKey>,
+    /// The key to put in a signed-with-ed25519-key extension, if any.
+    signed_with: Option<ed25519::PublicKey>,
+    /// Extensions that we don't otherwise know how to make, to include
+    /// verbatim.
+    unrecognized: Vec<UnrecognizedExt>,
+}
+
+impl Ed25519CertConstructor {
+    /// Set the type of the certificate.
+    pub fn cert_type(&mut self, cert_type: CertType) -> &mut Self {
+        self.cert_type = Some(cert_type);
+        self
+    }
+
+    /// Set the time at which the certificate expires.
+    ///
+    /// Certificates can only express their expiration time in hours, so
+    /// this is rounded up to the next hour.
+    pub fn expiration(&mut self, expiration: SystemTime) -> &mut Self {
+        self.expiration = Some(expiration);
+        self
+    }
+
+    /// Set the key or object that the certificate certifies.
+    pub fn cert_key(&mut self, cert_key: CertifiedKey) -> &mut Self {
+        self.cert_key = Some(cert_key);
+        self
+    }
+
+    /// Include `key` in the certificate, as the key that signed it.
+    ///
+    /// This must be the public half of the keypair passed to
+    /// [`encode_and_sign`](Ed25519CertConstructor::encode_and_sign).
+    pub fn signing_key(&mut self, key: ed25519::PublicKey) -> &mut Self {
+        self.signed_with = Some(key);
+        self
+    }
+
+    /// Add an extension of type `ext_type`, with the body `body`.
+    ///
+    /// This is meant for extensions that this crate doesn't otherwise know
+    /// how to make, and for testing how parsers handle extensions that they
+    /// don't recognize.  If `affects_validation` is set, parsers that don't
+    /// recognize the extension must reject the certificate.
+    pub fn unrecognized_extension(
+        &mut self,
+        ext_type: ExtType,
+        affects_validation: bool,
+        body: Vec<u8>,
+    ) -> &mut Self {
+        let flags = if affects_validation {
+            UnrecognizedExt::AFFECTS_VALIDATION
+        } else {
+            0
+        };
+        self.unrecognized.push(UnrecognizedExt {
+            flags,
+            ext_type,
+            body,
+        });
+        self
+    }
+
+    /// Encode the certificate, sign it with `skey`, and return the encoded
+    /// certificate along with the certificate itself.
+    ///
+    /// The returned certificate is the one that [`Ed25519Cert::decode`]
+    /// finds in the encoded bytes.  In particular, it only knows its signing
+    /// key if we included a signed-with-ed25519-key extension.
+    ///
+    /// Returns an error if any required field is missing, if the
+    /// certificate would be expired at `now`, or if it can't be encoded.
+    pub fn encode_and_sign(
+        &self,
+        skey: &ed25519::Keypair,
+        now: SystemTime,
+    ) -> Result<(Vec<u8>, Ed25519Cert), CertEncodeError> {
+        let cert_type = self
+            .cert_type
+            .ok_or(CertEncodeError::MissingField("certificate type"))?;
+        let expiration = self
+            .expiration
+            .ok_or(CertEncodeError::MissingField("expiration time"))?;
+        let cert_key = self
+            .cert_key
+            .clone()
+            .ok_or(CertEncodeError::MissingField("certified key"))?;
+
+        if expiration <= now {
+            return Err(CertEncodeError::AlreadyExpired);
+        }
+        let exp_hours = hours_rounding_up(expiration)?;
+
+        let mut extensions = Vec::new();
+        if let Some(pk) = self.signed_with {
+            if pk != skey.public {
+                return Err(CertEncodeError::KeyMismatch);
+            }
+            extensions.push(CertExt::SignedWithEd25519(SignedWithEd25519Ext { pk }));
+        }
+        for ext in &self.unrecognized {
+            extensions.push(CertExt::Unrecognized(ext.clone()));
+        }
+        let n_exts =
+            u8::try_from(extensions.len()).map_err(|_| CertEncodeError::TooManyExtensions)?;
+
+        let mut w = Vec::new();
+        w.write_u8(1); // Version
+        w.write_u8(cert_type.into());
+        w.write_u32(exp_hours);
+        w.write_u8(cert_key.key_type().into());
+        w.write_all(cert_key.as_bytes());
+        w.write_u8(n_exts);
+        for e in &extensions {
+            e.encode_onto(&mut w)
+                .map_err(|_| CertEncodeError::ExtensionTooLong)?;
+        }
+        let signature = skey.sign(&w[..]);
+        w.write(&signature);
+
+        let cert = Ed25519Cert::decode(&w[..])
+            .map_err(CertEncodeError::Unparseable)?
+            .cert
+            .cert;
+        Ok((w, cert))
+    }
+}
+
+/// Convert `when` into a number of hours since the epoch, rounding up.
+pub(crate) fn hours_rounding_up(when: SystemTime) -> Result<u32, CertEncodeError> {
+    let since_epoch = when
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| CertEncodeError::AlreadyExpired)?;
+    let secs = since_epoch.as_secs();
+    let mut hours = secs / 3600;
+    if secs % 3600 != 0 || since_epoch.subsec_nanos() != 0 {
+        hours += 1;
+    }
+    let hours = u32::try_from(hours).map_err(|_| CertEncodeError::ExpirationOutOfRange)?;
+    if expiry_from_hours(hours).is_none() {
+        return Err(CertEncodeError::ExpirationOutOfRange);
+    }
+    Ok(hours)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::CertError;
+    use std::time::Duration;
+    use tor_checkable::{SelfSigned, Timebound};
+
+    /// Return a keypair made from a secret key of all `b`.
+    fn keypair(b: u8) -> ed25519::Keypair {
+        let secret = ed25519::SecretKey::from_bytes(&[b; 32]).unwrap();
+        let public = (&secret).into();
+        ed25519::Keypair { secret, public }
+    }
+
+    #[test]
+    fn round_trip() {
+        let signer = keypair(1);
+        let subject = keypair(2).public;
+        let now = SystemTime::now();
+        let expiration = now + Duration::from_secs(30 * 86400);
+
+        let (encoded, cert) = Ed25519Cert::constructor()
+            .cert_type(CertType::IDENTITY_V_SIGNING)
+            .expiration(expiration)
+            .cert_key(CertifiedKey::Ed25519(subject))
+            .signing_key(signer.public)
+            .encode_and_sign(&signer, now)
+            .unwrap();
+        // The expiration time gets rounded up to the hour.
+        assert!(cert.expiry() >= expiration);
+        assert!(cert.expiry() < expiration + Duration::from_secs(3600));
+        assert_eq!(cert.signing_key(), Some(&signer.public));
+
+        // The parser can find the signing key in the certificate, and the
+        // signature checks out.
+        let decoded = Ed25519Cert::decode(&encoded)
+            .unwrap()
+            .check_key(&None)
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .check_valid_at(&now)
+            .unwrap();
+        assert_eq!(decoded.cert_type(), CertType::IDENTITY_V_SIGNING);
+        assert_eq!(decoded.subject_key().as_ed25519(), Some(&subject));
+        assert_eq!(decoded.signing_key(), Some(&signer.public));
+        assert_eq!(decoded.expiry(), cert.expiry());
+    }
+
+    #[test]
+    fn extensions() {
+        let signer = keypair(3);
+        let now = SystemTime::now();
+        let expiration = now + Duration::from_secs(86400);
+        let mut constructor = Ed25519Cert::constructor();
+        constructor
+            .cert_type(CertType::SIGNING_V_TLS_CERT)
+            .expiration(expiration)
+            .cert_key(CertifiedKey::X509Sha256Digest([9; 32]))
+            .unrecognized_extension(77.into(), false, b"hello world".to_vec());
+
+        // Without a signed-with-ed25519-key extension, we need to know the
+        // signing key already.
+        let (encoded, made) = constructor.encode_and_sign(&signer, now).unwrap();
+        assert_eq!(made.signing_key(), None);
+        assert!(Ed25519Cert::decode(&encoded)
+            .unwrap()
+            .check_key(&None)
+            .is_err());
+        let cert = Ed25519Cert::decode(&encoded)
+            .unwrap()
+            .check_key(&Some(signer.public))
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .dangerously_assume_timely();
+        assert_eq!(cert.subject_key().as_bytes(), &[9; 32]);
+        assert!(!cert.signing_key_included());
+        let exts: Vec<_> = cert.unrecognized_extensions().collect();
+        assert_eq!(exts.len(), 1);
+        assert_eq!(exts[0].ext_type(), 77.into());
+        assert!(!exts[0].affects_validation());
+        assert_eq!(exts[0].body(), b"hello world");
+
+        // An unrecognized extension that affects validation makes us
+        // reject the certificate when we check it.
+        constructor.unrecognized_extension(78.into(), true, Vec::new());
+        let (encoded, _) = constructor.encode_and_sign(&signer, now).unwrap();
+        assert!(matches!(
+            Ed25519Cert::decode(&encoded)
+                .unwrap()
+                .check_key(&Some(signer.public)),
+            Err(CertError::UnrecognizedCriticalExtension(_))
+        ));
+
+        // Extensions have a maximum length.
+        constructor.unrecognized_extension(79.into(), false, vec![0; 65536]);
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, now),
+            Err(CertEncodeError::ExtensionTooLong)
+        ));
+    }
+
+    #[test]
+    fn refused() {
+        let signer = keypair(4);
+        let now = SystemTime::now();
+        let in_a_day = now + Duration::from_secs(86400);
+        let mut constructor = Ed25519Cert::constructor();
+        constructor
+            .cert_type(CertType::IDENTITY_V_SIGNING)
+            .cert_key(CertifiedKey::Ed25519(keypair(5).public));
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, now),
+            Err(CertEncodeError::MissingField(_))
+        ));
+
+        // We won't make a certificate that has already expired.
+        constructor.expiration(SystemTime::UNIX_EPOCH + Duration::from_secs(86400));
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, now),
+            Err(CertEncodeError::AlreadyExpired)
+        ));
+
+        // We won't claim that some other key signed the certificate.
+        constructor
+            .expiration(in_a_day)
+            .signing_key(keypair(6).public);
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, now),
+            Err(CertEncodeError::KeyMismatch)
+        ));
+        constructor.signing_key(signer.public);
+        assert!(constructor.encode_and_sign(&signer, now).is_ok());
+
+        // Whether the certificate has expired depends on the time we're
+        // given.
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, in_a_day + Duration::from_secs(7200)),
+            Err(CertEncodeError::AlreadyExpired)
+        ));
+
+        // We won't make a certificate that we couldn't parse ourselves.
+        constructor
+            .signing_key(signer.public)
+            .unrecognized_extension(ExtType::SIGNED_WITH_ED25519_KEY, false, vec![1, 2, 3]);
+        assert!(matches!(
+            constructor.encode_and_sign(&signer, now),
+            Err(CertEncodeError::Unparseable(_))
+        ));
+    }
+
+    #[test]
+    fn rounding() {
+        let hour = Duration::from_secs(3600);
+        let t = SystemTime::UNIX_EPOCH + hour * 500_000;
+        assert_eq!(hours_rounding_up(t).unwrap(), 500_000);
+        assert_eq!(
+            hours_rounding_up(t + Duration::from_nanos(1)).unwrap(),
+            500_001
+        );
+        assert_eq!(hours_rounding_up(t + hour - hour / 2).unwrap(), 500_001);
+    }
+}
@@ -0,0 +1,84 @@
+//! Define an error type for the tor-cert crate.
+
+use crate::{CertType, ExtType, KeyType};
+use thiserror::Error;
+
+/// An error that occurred while checking a certificate.
+///
+/// (For errors that occur while making a new certificate, see
+/// [`CertEncodeError`](crate::CertEncodeError).)
+#[derive(Clone, Debug, Error)]
+#[non_exhaustive]
+pub enum CertError {
+    /// The certificate, or some part of it, couldn't be decoded.
+    #[error("Unable to decode certificate")]
+    BytesErr(#[from] tor_bytes::Error),
+    /// The certificate's signature didn't verify.
+    #[error("Invalid signature on certificate")]
+    BadSignature,
+    /// We don't know which key was supposed to sign the certificate: it
+    /// has no signed-with-ed25519-key extension, and we weren't given one.
+    #[error("Missing signing key for certificate")]
+    MissingSigningKey,
+    /// The key that we were given doesn't match the one in the
+    /// certificate's signed-with-ed25519-key extension.
+    #[error("Certificate was signed with an unexpected key")]
+    SigningKeyMismatch,
+    /// The certificate certifies a kind of key that we can't use here.
+    #[error("Certificate has wrong subject key type {0}")]
+    WrongSubjectKeyType(KeyType),
+    /// The certificate has a type that we can't use here.
+    #[error("Unsupported certificate type {0}")]
+    UnsupportedCertType(CertType),
+    /// The certificate has an extension that we don't recognize, and whose
+    /// flags say that we must understand it in order to validate the
+    /// certificate.
+    #[error("Unrecognized certificate extension {0} affects validation")]
+    UnrecognizedCriticalExtension(ExtType),
+    /// The certificate isn't valid at the time we checked it.
+    #[error("Certificate is not valid at this time")]
+    Untimely(#[from] tor_checkable::TimeValidityError),
+}
+
+/// An error that occurred while decoding an RSA->Ed25519 crosscert, saying
+/// which part of the certificate was wrong.
+///
+/// See [`RsaCrosscert::decode_detailed`](crate::rsa::RsaCrosscert::decode_detailed).
+/// Every variant wraps the underlying decoding error.
+#[derive(Clone, Debug, Error)]
+#[non_exhaustive]
+pub enum CrosscertDecodeError {
+    /// The input ended before the certificate's fixed-length fields, or
+    /// before its signature length.
+    #[error("RSA->Ed crosscert is too short")]
+    TooShort(#[source] tor_bytes::Error),
+    /// The certified Ed25519 key is invalid, or isn't in its one canonical
+    /// form.
+    #[error("RSA->Ed crosscert has a bad subject key")]
+    BadSubjectKey(#[source] tor_bytes::Error),
+    /// The expiration time can't be represented on this platform.
+    #[error("RSA->Ed crosscert has a bad expiration time")]
+    BadExpiration(#[source] tor_bytes::Error),
+    /// The signature is shorter than its length byte claims, or too short
+    /// to be a real signature.
+    #[error("RSA->Ed crosscert has a bad signature length")]
+    BadSigLen(#[source] tor_bytes::Error),
+    /// There were bytes left over after the certificate.
+    #[error("RSA->Ed crosscert has trailing data")]
+    TrailingData(#[source] tor_bytes::Error),
+}
+
+impl CrosscertDecodeError {
+    /// Return the underlying decoding error, forgetting which part of the
+    /// certificate it was about.
+    pub fn into_bytes_error(self) -> tor_bytes::Error {
+        use CrosscertDecodeError as E;
+        match self {
+            E::TooShort(e)
+            | E::BadSubjectKey(e)
+            | E::BadExpiration(e)
+            | E::BadSigLen(e)
+            | E::TrailingData(e) => e,
+        }
+    }
+}
@@ -7,6 +7,10 @@
 //! authenticating Tor channels.  (Eventually, support for onion service
 //! certificate support will get added too.)
 //!
+//! Mostly we decode certificates, but [`Ed25519CertConstructor`] and
+//! [`RsaCrosscert::encode_and_sign`](rsa::RsaCrosscert::encode_and_sign)
+//! can make new ones as well.
+//!
 //! This crate is part of
 //! [Arti](https://gitlab.torproject.org/tpo/core/arti/), a project to
 //! implement [Tor](https://www.torproject.org/) in Rust.
@@ -80,12 +84,18 @@
 #![warn(clippy::unseparated_literal_suffix)]
 #![deny(clippy::unwrap_used)]
 
+mod encode;
+mod err;
 pub mod rsa;
 
+pub use encode::{CertEncodeError, Ed25519CertConstructor};
+pub use err::{CertError, CrosscertDecodeError};
+pub use rsa::CrosscertCache;
+
 use caret::caret_int;
 use signature::Verifier;
 use tor_bytes::{Error, Result};
-use tor_bytes::{Readable, Reader};
+use tor_bytes::{LengthSlot, Readable, Reader, Writer};
 use tor_llcrypto::pk::*;
 
 use std::time;
@@ -149,6 +159,18 @@ caret_int! {
     }
 }
 
+/// Convert an expiration time in hours since the epoch into a
+/// `SystemTime`.
+///
+/// Returns `None` if the resulting time can't be represented on this
+/// platform.  (A `u32` count of hours always fits in a `u64` count of
+/// seconds, but adding that to `UNIX_EPOCH` can overflow a `SystemTime`
+/// on platforms with a narrower time representation.)
+pub(crate) fn expiry_from_hours(exp_hours: u32) -> Option<time::SystemTime> {
+    let d = time::Duration::from_secs(u64::from(exp_hours) * 3600);
+    time::SystemTime::UNIX_EPOCH.checked_add(d)
+}
+
 caret_int! {
     /// Identifiers for the type of key or object getting signed.
     pub struct KeyType(u8) {
@@ -174,7 +196,6 @@ pub struct Ed25519Cert {
     /// The key or object being certified.
     cert_key: CertifiedKey,
     /// A list of extensions.
-    #[allow(unused)]
     extensions: Vec<CertExt>,
     /// The key that signed this cert.
     ///
@@ -184,6 +205,7 @@ pub struct Ed25519Cert {
 }
 
 /// One of the data types that can be certified by an Ed25519Cert.
+#[derive(Clone)]
 #[non_exhaustive]
 pub enum CertifiedKey {
     /// An Ed25519 public key, signed directly.
@@ -197,6 +219,7 @@ pub enum CertifiedKey {
 }
 
 /// A key whose type we didn't recognize.
+#[derive(Clone)]
 pub struct UnrecognizedKey {
     /// Actual type of the key.
     key_type: KeyType,
@@ -233,6 +256,12 @@ impl CertifiedKey {
             _ => None,
         }
     }
+    /// If this is an Ed25519 public key, return it.  Otherwise, return
+    /// an error saying what kind of key it is instead.
+    pub fn require_ed25519(&self) -> std::result::Result<&ed25519::PublicKey, CertError> {
+        self.as_ed25519()
+            .ok_or_else(|| CertError::WrongSubjectKeyType(self.key_type()))
+    }
     /// Try to extract a CertifiedKey from a Reader, given that we have
     /// already read its type as `key_type`.
     fn from_reader(key_type: KeyType, r: &mut Reader<'_>) -> Result<Self> {
@@ -249,6 +278,7 @@ impl CertifiedKey {
 }
 
 /// An extension in a Tor certificate.
+#[derive(Clone)]
 enum CertExt {
     /// Indicates which Ed25519 public key signed this cert.
     SignedWithEd25519(SignedWithEd25519Ext),
@@ -257,17 +287,48 @@ enum CertExt {
 }
 
 /// Any unrecognized extension on a Tor certificate.
-#[allow(unused)]
-struct UnrecognizedExt {
-    /// True iff this extension must be understand in order to validate the
-    /// certificate.
-    affects_validation: bool,
+///
+/// We keep these around so that callers who know about other kinds of
+/// extension (like those used by onion services) can look at them.
+#[derive(Clone, Debug)]
+pub struct UnrecognizedExt {
+    /// The flags on this extension.
+    ///
+    /// The only flag defined so far is [`UnrecognizedExt::AFFECTS_VALIDATION`].
+    flags: u8,
     /// The type of the extension
     ext_type: ExtType,
     /// The body of the extension.
     body: Vec<u8>,
 }
 
+impl UnrecognizedExt {
+    /// Flag indicating that an extension must be understood in order to
+    /// validate the certificate.
+    pub const AFFECTS_VALIDATION: u8 = 1;
+
+    /// Return the type of this extension.
+    pub fn ext_type(&self) -> ExtType {
+        self.ext_type
+    }
+    /// Return the flags on this extension.
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+    /// Return true if this extension must be understood in order to
+    /// validate the certificate.
+    ///
+    /// Since we don't understand it, we reject any certificate that has
+    /// such an extension when we check it.
+    pub fn affects_validation(&self) -> bool {
+        self.flags & Self::AFFECTS_VALIDATION != 0
+    }
+    /// Return the body of this extension.
+    pub fn body(&self) -> &[u8] {
+        &self.body[..]
+    }
+}
+
 impl CertExt {
     /// Return the identifier code for this Extension.
     fn ext_id(&self) -> ExtType {
@@ -276,63 +337,40 @@ impl CertExt {
             CertExt::Unrecognized(u) => u.ext_type,
         }
     }
-}
 
-/*
-impl Writeable for CertExt {
-    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
+    /// Encode this extension onto `w`.
+    ///
+    /// Returns an error if the body is too long for the extension's length
+    /// field.  (That field counts only the body, and not the type and flags
+    /// that come between it and the body.)
+    fn encode_onto(&self, w: &mut Vec<u8>) -> tor_bytes::EncodeResult<()> {
+        let len = LengthSlot::reserve_u16(w);
+        w.write_u8(self.ext_id().into());
         match self {
-            CertExt::SignedWithEd25519(pk) => pk.write_onto(w),
-            CertExt::Unrecognized(u) => u.write_onto(w),
+            CertExt::SignedWithEd25519(ext) => {
+                // flags = 0.
+                w.write_u8(0);
+                let body_start = w.len();
+                w.write_all(ext.pk.as_bytes());
+                len.finish(w, body_start)
+            }
+            CertExt::Unrecognized(u) => {
+                w.write_u8(u.flags);
+                let body_start = w.len();
+                w.write_all(&u.body[..]);
+                len.finish(w, body_start)
+            }
         }
     }
 }
- */
 
 /// Extension indicating that a key that signed a given certificate.
+#[derive(Clone)]
 struct SignedWithEd25519Ext {
     /// The key that signed the certificate including this extension.
     pk: ed25519::PublicKey,
 }
 
-/*
-impl Writeable for SignedWithEd25519Ext {
-    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
-        // body length
-        w.write_u16(32);
-        // Signed-with-ed25519-key-extension
-        w.write_u8(ExtType::SIGNED_WITH_ED25519_KEY.into());
-        // flags = 0.
-        w.write_u8(0);
-        // body
-        w.write_all(self.pk.as_bytes());
-    }
-}
-*/
-
-/*
-impl UnrecognizedExt {
-    /// Assert that there is no problem with the internal representation
-    /// of this object.
-    fn assert_rep_ok(&self) {
-        assert!(self.body.len() <= std::u16::MAX as usize);
-    }
-}
-*/
-
-/*
-impl Writeable for UnrecognizedExt {
-    fn write_onto<B: Writer + ?Sized>(&self, w: &mut B) {
-        self.assert_rep_ok();
-        w.write_u16(self.body.len() as u16);
-        w.write_u8(self.ext_type.into());
-        let flags = if self.affects_validation { 1 } else { 0 };
-        w.write_u8(flags);
-        w.write_all(&self.body[..]);
-    }
-}
-*/
-
 impl Readable for CertExt {
     fn take_from(b: &mut Reader<'_>) -> Result<Self> {
         let len = b.take_u16()?;
@@ -350,50 +388,24 @@ impl Readable for CertExt {
                         .map_err(|_| Error::BadMessage("invalid Ed25519 public key"))?,
                 })
             }
-            _ => {
-                if (flags & 1) != 0 {
-                    return Err(Error::BadMessage(
-                        "unrecognized certificate extension, with 'affects_validation' flag set.",
-                    ));
-                }
-                CertExt::Unrecognized(UnrecognizedExt {
-                    affects_validation: false,
-                    ext_type,
-                    body: body.into(),
-                })
-            }
+            // We reject unrecognized extensions that affect validation
+            // when we check the certificate, not here: see
+            // KeyUnknownCert::check_key.
+            _ => CertExt::Unrecognized(UnrecognizedExt {
+                flags,
+                ext_type,
+                body: body.into(),
+            }),
         })
     }
 }
 
 impl Ed25519Cert {
-    /*
-        /// Helper: Assert that there is nothing wrong with the
-        /// internal structure of this certificate.
-        fn assert_rep_ok(&self) {
-            assert!(self.extensions.len() <= std::u8::MAX as usize);
-        }
-
-        /// Encode a certificate into a new vector, signing the result
-        /// with `keypair`.
-        pub fn encode_and_sign(&self, skey: &ed25519::Keypair) -> Vec<u8> {
-            self.assert_rep_ok();
-            let mut w = Vec::new();
-            w.write_u8(1); // Version
-            w.write_u8(self.cert_type.into());
-            w.write_u32(self.exp_hours);
-            w.write_u8(self.cert_key.key_type().into());
-            w.write_all(self.cert_key.as_bytes());
-
-            for e in self.extensions.iter() {
-                w.write(e);
-            }
-
-            let signature = skey.sign(&w[..]);
-            w.write(&signature);
-            w
-        }
-    */
+    /// Return a new [`Ed25519CertConstructor`], to build and sign a new
+    /// certificate.
+    pub fn constructor() -> Ed25519CertConstructor {
+        Ed25519CertConstructor::default()
+    }
 
     /// Try to decode a certificate from a byte slice.
     ///
@@ -413,6 +425,11 @@ impl Ed25519Cert {
         }
         let cert_type = r.take_u8()?.into();
         let exp_hours = r.take_u32()?;
+        if expiry_from_hours(exp_hours).is_none() {
+            return Err(Error::BadMessage(
+                "Certificate expiration time out of range",
+            ));
+        }
         let mut cert_key_type = r.take_u8()?.into();
 
         // This is a workaround for a tor bug: the key type is
@@ -426,7 +443,7 @@ impl Ed25519Cert {
         let n_exts = r.take_u8()?;
         let mut extensions = Vec::new();
         for _ in 0..n_exts {
-            let e: CertExt = r.extract()?;
+            let e: CertExt = r.with_context("ed25519 cert extension", |r| r.extract())?;
             extensions.push(e);
         }
 
@@ -460,9 +477,12 @@ impl Ed25519Cert {
     }
 
     /// Return the time at which this certificate becomes expired
+    ///
+    /// We reject certificates with unrepresentable expiration times when
+    /// decoding them; if one shows up here anyway, we treat it as having
+    /// expired at the epoch rather than panicking.
     pub fn expiry(&self) -> std::time::SystemTime {
-        let d = std::time::Duration::new(u64::from(self.exp_hours) * 3600, 0);
-        std::time::SystemTime::UNIX_EPOCH + d
+        expiry_from_hours(self.exp_hours).unwrap_or(std::time::SystemTime::UNIX_EPOCH)
     }
 
     /// Return true iff this certificate will be expired at the time `when`.
@@ -481,6 +501,26 @@ impl Ed25519Cert {
         self.signed_with.as_ref()
     }
 
+    /// Return true if this certificate names its own signing key in a
+    /// signed-with-ed25519-key extension.
+    ///
+    /// If this returns false, the signing key (if any) was supplied by
+    /// whoever checked the certificate.
+    pub fn signing_key_included(&self) -> bool {
+        self.extensions
+            .iter()
+            .any(|e| matches!(e, CertExt::SignedWithEd25519(_)))
+    }
+
+    /// Return an iterator over the extensions on this certificate that we
+    /// don't recognize, in the order they appear.
+    pub fn unrecognized_extensions(&self) -> impl Iterator<Item = &UnrecognizedExt> + '_ {
+        self.extensions.iter().filter_map(|e| match e {
+            CertExt::Unrecognized(u) => Some(u),
+            CertExt::SignedWithEd25519(_) => None,
+        })
+    }
+
     /// Return the type of this certificate.
     pub fn cert_type(&self) -> CertType {
         self.cert_type
@@ -503,19 +543,45 @@ impl KeyUnknownCert {
     pub fn peek_subject_key(&self) -> &CertifiedKey {
         &self.cert.cert.cert_key
     }
+    /// Return the unrecognized extensions of the underlying cert.
+    pub fn peek_unrecognized_extensions(&self) -> impl Iterator<Item = &UnrecognizedExt> + '_ {
+        self.cert.cert.unrecognized_extensions()
+    }
+
+    /// Give an error if the type of the underlying cert isn't `cert_type`.
+    pub fn check_cert_type(self, cert_type: CertType) -> std::result::Result<Self, CertError> {
+        if self.peek_cert_type() != cert_type {
+            return Err(CertError::UnsupportedCertType(self.peek_cert_type()));
+        }
+        Ok(self)
+    }
 
     /// Check whether a given pkey is (or might be) a key that has correctly
     /// signed this certificate.
     ///
     /// On success, we can check whether the certificate is well-signed;
     /// otherwise, we can't check the certificate.
-    pub fn check_key(self, pkey: &Option<ed25519::PublicKey>) -> Result<UncheckedCert> {
+    ///
+    /// We also give an error here if the certificate has an extension
+    /// that we don't recognize, but which affects validation.
+    pub fn check_key(
+        self,
+        pkey: &Option<ed25519::PublicKey>,
+    ) -> std::result::Result<UncheckedCert, CertError> {
+        if let Some(ext) = self
+            .cert
+            .cert
+            .unrecognized_extensions()
+            .find(|e| e.affects_validation())
+        {
+            return Err(CertError::UnrecognizedCriticalExtension(ext.ext_type()));
+        }
         let real_key = match (pkey, self.cert.cert.signed_with) {
             (Some(a), Some(b)) if a == &b => b,
-            (Some(_), Some(_)) => return Err(Error::BadMessage("Mismatched public key on cert")),
+            (Some(_), Some(_)) => return Err(CertError::SigningKeyMismatch),
             (Some(a), None) => *a,
             (None, Some(b)) => b,
-            (None, None) => return Err(Error::BadMessage("Missing public key on cert")),
+            (None, None) => return Err(CertError::MissingSigningKey),
         };
         Ok(UncheckedCert {
             cert: Ed25519Cert {
@@ -544,11 +610,34 @@ pub struct UncheckedCert {
     signature: ed25519::Signature,
 }
 
+/// The default for how far in the future a certificate's expiration time can
+/// be before we decide the certificate is bogus.
+///
+/// Tor's certificates typically last for days or weeks; we allow a year.
+pub const DEFAULT_MAX_LIFETIME: time::Duration = time::Duration::from_secs(365 * 86400);
+
 /// A certificate that has been parsed and signature-checked, but whose
 /// timeliness has not been checked.
 pub struct SigCheckedCert {
     /// The certificate that might or might not be timely
     cert: Ed25519Cert,
+    /// The longest we'll allow between the time we check this certificate
+    /// and its expiration time.
+    max_lifetime: time::Duration,
+}
+
+impl SigCheckedCert {
+    /// Reject this certificate, when checking its timeliness, if it expires
+    /// more than `max_lifetime` after the time we check it at.
+    ///
+    /// (By default, we use [`DEFAULT_MAX_LIFETIME`].)
+    #[must_use]
+    pub fn with_max_lifetime(self, max_lifetime: time::Duration) -> Self {
+        SigCheckedCert {
+            max_lifetime,
+            ..self
+        }
+    }
 }
 
 impl UncheckedCert {
@@ -556,12 +645,10 @@ impl UncheckedCert {
     /// been checked, and a signature to validate.
     pub fn dangerously_split(
         self,
-    ) -> Result<(SigCheckedCert, ed25519::ValidatableEd25519Signature)> {
+    ) -> std::result::Result<(SigCheckedCert, ed25519::ValidatableEd25519Signature), CertError>
+    {
         use tor_checkable::SelfSigned;
-        let signing_key = self
-            .cert
-            .signed_with
-            .ok_or(Error::BadMessage("Missing public key on cert"))?;
+        let signing_key = self.cert.signed_with.ok_or(CertError::MissingSigningKey)?;
         let signature =
             ed25519::ValidatableEd25519Signature::new(signing_key, self.signature, &self.text[..]);
         Ok((self.dangerously_assume_wellsigned(), signature))
@@ -581,38 +668,47 @@ impl UncheckedCert {
 }
 
 impl tor_checkable::SelfSigned<SigCheckedCert> for UncheckedCert {
-    type Error = Error;
+    type Error = CertError;
 
-    fn is_well_signed(&self) -> Result<()> {
-        let pubkey = &self
-            .cert
-            .signed_with
-            .ok_or(Error::BadMessage("Certificate was not in fact self-signed"))?;
+    fn is_well_signed(&self) -> std::result::Result<(), CertError> {
+        let pubkey = &self.cert.signed_with.ok_or(CertError::MissingSigningKey)?;
 
         pubkey
             .verify(&self.text[..], &self.signature)
-            .map_err(|_| Error::BadMessage("Invalid certificate signature"))?;
+            .map_err(|_| CertError::BadSignature)?;
 
         Ok(())
     }
 
     fn dangerously_assume_wellsigned(self) -> SigCheckedCert {
-        SigCheckedCert { cert: self.cert }
+        SigCheckedCert {
+            cert: self.cert,
+            max_lifetime: DEFAULT_MAX_LIFETIME,
+        }
     }
 }
 
 impl tor_checkable::Timebound<Ed25519Cert> for SigCheckedCert {
     type Error = tor_checkable::TimeValidityError;
     fn is_valid_at(&self, t: &time::SystemTime) -> std::result::Result<(), Self::Error> {
+        let expiry = self.cert.expiry();
         if self.cert.is_expired_at(*t) {
-            let expiry = self.cert.expiry();
-            Err(Self::Error::Expired(
+            return Err(Self::Error::Expired(
                 t.duration_since(expiry)
                     .expect("certificate expiry time inconsistent"),
-            ))
-        } else {
-            Ok(())
+            ));
+        }
+        // A certificate that lasts for decades is almost certainly
+        // malformed, and we shouldn't trust it for that long.  (If `t` is so
+        // late that we can't add to it, nothing can expire after the limit.)
+        if let Some(limit) = t.checked_add(self.max_lifetime) {
+            if let Ok(excess) = expiry.duration_since(limit) {
+                if excess > time::Duration::from_secs(0) {
+                    return Err(Self::Error::ExpiresTooLate(excess));
+                }
+            }
         }
+        Ok(())
     }
 
     fn dangerously_assume_timely(self) -> Ed25519Cert {
@@ -626,6 +722,57 @@ mod test {
     use super::*;
     use hex_literal::hex;
 
+    #[test]
+    fn max_expiry() {
+        // A v1 signing-key cert with exp_hours = u32::MAX, no extensions,
+        // and an all-zero signature.
+        let mut c = hex!("01 04").to_vec();
+        c.extend_from_slice(&u32::MAX.to_be_bytes());
+        c.push(0x01);
+        c.extend_from_slice(&hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9"
+        ));
+        c.push(0);
+        c.extend_from_slice(&[0; 64]);
+
+        let far_future = time::SystemTime::UNIX_EPOCH
+            .checked_add(time::Duration::from_secs(u64::from(u32::MAX) * 3600));
+        assert_eq!(expiry_from_hours(u32::MAX), far_future);
+        match Ed25519Cert::decode(&c) {
+            Ok(cert) => {
+                let cert = cert.cert.cert;
+                assert_eq!(Some(cert.expiry()), far_future);
+                assert!(!cert.is_expired_at(time::SystemTime::now()));
+            }
+            Err(e) => {
+                assert!(far_future.is_none());
+                assert_eq!(
+                    e,
+                    Error::BadMessage("Certificate expiration time out of range")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_ext_position() {
+        // A cert whose only extension is cut off in the middle of its body.
+        let mut c = hex!("01 04 00000001 01").to_vec();
+        c.extend_from_slice(&hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9"
+        ));
+        c.extend_from_slice(&hex!("01 0009 99 10 6578"));
+
+        let e = Ed25519Cert::decode(&c).err().unwrap();
+        assert_eq!(e, Error::Truncated);
+        assert_eq!(e.offset(), Some(44));
+        assert_eq!(e.context(), Some("ed25519 cert extension"));
+        assert_eq!(
+            e.to_string(),
+            "object truncated (or not fully present) at offset 44 while reading ed25519 cert extension"
+        );
+    }
+
     #[test]
     fn parse_unrecognized_ext() -> Result<()> {
         // case one: a flag is set but we don't know it
@@ -637,16 +784,50 @@ mod test {
         assert_eq!(e.ext_id(), 0x99.into());
 
         // case two: we've been told to ignore the cert if we can't
-        // handle the extension.
+        // handle the extension.  We still parse it, but remember the flag,
+        // so that we can reject the cert when we check it.
         let b = hex!("0009 99 11 657874656e73696f6e");
         let mut r = Reader::from_slice(&b);
-        let e: Result<CertExt> = r.extract();
-        assert!(e.is_err());
+        let e: CertExt = r.extract()?;
+        r.should_be_exhausted()?;
+        match e {
+            CertExt::Unrecognized(u) => {
+                assert_eq!(u.ext_type(), 0x99.into());
+                assert_eq!(u.flags(), 0x11);
+                assert!(u.affects_validation());
+                assert_eq!(u.body(), b"extension");
+            }
+            CertExt::SignedWithEd25519(_) => panic!("parsed as the wrong extension"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_ext() -> Result<()> {
+        // Re-encoding an extension gives back exactly the bytes it came from.
+        let unrecognized = hex!("0009 99 11 657874656e73696f6e");
+        let signed_with = hex!(
+            "0020 04 00
+             DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9"
+        );
+        for b in &[&unrecognized[..], &signed_with[..]] {
+            let e: CertExt = Reader::from_slice(b).extract()?;
+            let mut w = Vec::new();
+            e.encode_onto(&mut w).unwrap();
+            assert_eq!(&w[..], *b);
+        }
+
+        // A body that doesn't fit in the length field is an error.
+        let e = CertExt::Unrecognized(UnrecognizedExt {
+            flags: 0,
+            ext_type: 0x99.into(),
+            body: vec![0; 65536],
+        });
+        let mut w = Vec::new();
         assert_eq!(
-            e.err().unwrap(),
-            Error::BadMessage(
-                "unrecognized certificate extension, with 'affects_validation' flag set."
-            )
+            e.encode_onto(&mut w),
+            Err(tor_bytes::EncodeError::BadLengthValue)
         );
 
         Ok(())
@@ -671,4 +852,55 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn max_lifetime() {
+        use tor_checkable::{SelfSigned, TimeValidityError, Timebound};
+        let hour = time::Duration::from_secs(3600);
+        let day = hour * 24;
+
+        // A v1 signing-key cert expiring at hour 500000 after the epoch, no
+        // extensions, and an all-zero signature.  It certifies the same key
+        // that we pretend signed it: any valid point will do.
+        let key_bytes = hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9");
+        let signing_key = ed25519::PublicKey::from_bytes(&key_bytes).unwrap();
+        let exp_hours: u32 = 500_000;
+        let mut c = hex!("01 04").to_vec();
+        c.extend_from_slice(&exp_hours.to_be_bytes());
+        c.push(0x01);
+        c.extend_from_slice(&key_bytes);
+        c.push(0);
+        c.extend_from_slice(&[0; 64]);
+        let checked = || {
+            Ed25519Cert::decode(&c)
+                .unwrap()
+                .check_key(&Some(signing_key))
+                .unwrap()
+                .dangerously_assume_wellsigned()
+        };
+        let expiry = time::SystemTime::UNIX_EPOCH + hour * exp_hours;
+
+        // Expiring in a week, or in exactly the maximum lifetime, is fine.
+        let cert = checked();
+        assert!(cert.is_valid_at(&(expiry - day * 7)).is_ok());
+        assert!(cert.is_valid_at(&(expiry - DEFAULT_MAX_LIFETIME)).is_ok());
+        // Expiring any later than that is not.
+        assert_eq!(
+            cert.is_valid_at(&(expiry - DEFAULT_MAX_LIFETIME - hour)),
+            Err(TimeValidityError::ExpiresTooLate(hour))
+        );
+        // Expired certs are still expired.
+        assert_eq!(
+            cert.is_valid_at(&(expiry + day)),
+            Err(TimeValidityError::Expired(day))
+        );
+
+        // We can make the limit tighter.
+        let cert = checked().with_max_lifetime(day * 2);
+        assert!(cert.is_valid_at(&(expiry - day)).is_ok());
+        assert_eq!(
+            cert.is_valid_at(&(expiry - day * 7)),
+            Err(TimeValidityError::ExpiresTooLate(day * 5))
+        );
+    }
 }
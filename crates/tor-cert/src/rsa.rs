@@ -2,12 +2,42 @@
 //!
 //! These are used in the Tor link handshake to prove that a given ed25519
 //! key speaks for a given (deprecated) RSA identity.
+//!
+//! # Key hygiene
+//!
+//! Code in this module that creates crosscerts must take the RSA private
+//! key by reference, and must never store it in any longer-lived object.
+//! Intermediate values of the signing operation (like the digest from
+//! [`crosscert_digest`]) are kept in [`Zeroizing`] buffers, so that they
+//! are cleared once we're done with them.
 
-use tor_bytes::Reader;
-use tor_checkable::{timed::TimerangeBound, ExternallySigned};
+use crate::encode::hours_rounding_up;
+use crate::{CertEncodeError, CertError, CrosscertDecodeError};
+use tor_bytes::{Reader, Writer};
+use tor_checkable::{timed::TimerangeBound, ExternallySigned, TimeSource, Timebound};
 use tor_llcrypto as ll;
 
 use digest::Digest;
+use rand_core::{CryptoRng, RngCore};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
+
+/// The shortest signature that we'll accept on an RSA->Ed25519 crosscert.
+///
+/// Tor's RSA identity keys are 1024 bits long, so their signatures are 128
+/// bytes long.  Anything shorter can't possibly verify, so we reject it when
+/// decoding rather than waiting for the signature check to fail.
+const MIN_SIGNATURE_LEN: usize = 128;
+
+/// The length of the part of an RSA->Ed25519 crosscert that is signed: an
+/// Ed25519 key and a 4-byte expiration time.
+const SIGNED_PORTION_LEN: usize = 32 + 4;
+
+/// The domain-separation string that we prepend to an RSA->Ed25519
+/// crosscert's signed portion before hashing it.
+const CROSSCERT_PREFIX: &[u8] = b"Tor TLS RSA/Ed25519 cross-certificate";
 
 /// A RSA->Ed25519 cross-certificate
 ///
@@ -16,6 +46,7 @@ use digest::Digest;
 ///
 /// (There is no converse type for certifying Ed25519 identity keys with
 /// RSA identity keys, since the RSA identity keys are too weak to trust.)
+#[derive(Clone)]
 #[must_use]
 pub struct RsaCrosscert {
     /// The key that is being certified
@@ -31,9 +62,11 @@ pub struct RsaCrosscert {
 
 impl RsaCrosscert {
     /// Return the time at which this certificate becomes expired
+    ///
+    /// As with [`Ed25519Cert::expiry`](crate::Ed25519Cert::expiry), an
+    /// unrepresentable expiration time is treated as already expired.
     pub fn expiry(&self) -> std::time::SystemTime {
-        let d = std::time::Duration::new(u64::from(self.exp_hours) * 3600, 0);
-        std::time::SystemTime::UNIX_EPOCH + d
+        crate::expiry_from_hours(self.exp_hours).unwrap_or(std::time::SystemTime::UNIX_EPOCH)
     }
 
     /// Return true if the subject key in this certificate matches `other`
@@ -41,38 +74,293 @@ impl RsaCrosscert {
         &self.subject_key == other
     }
 
+    /// Return a borrowed view of this certificate.
+    fn as_borrowed(&self) -> RsaCrosscertRef<'_> {
+        RsaCrosscertRef {
+            subject_key: self.subject_key,
+            exp_hours: self.exp_hours,
+            digest: self.digest,
+            signature: &self.signature[..],
+        }
+    }
+
     /// Decode a slice of bytes into an RSA crosscert.
+    ///
+    /// The slice must hold exactly one crosscert.
     pub fn decode(bytes: &[u8]) -> tor_bytes::Result<UncheckedRsaCrosscert> {
+        Self::decode_with_prefix(bytes, CROSSCERT_PREFIX)
+    }
+
+    /// Decode a slice of bytes into an RSA crosscert, as
+    /// [`decode`](RsaCrosscert::decode) does, but on failure say which part
+    /// of the certificate was wrong.
+    ///
+    /// This never panics, whatever its input: every failure is one of the
+    /// variants of [`CrosscertDecodeError`].  That makes it suitable for
+    /// fuzzing.
+    pub fn decode_detailed(bytes: &[u8]) -> Result<UncheckedRsaCrosscert, CrosscertDecodeError> {
+        Self::decode_detailed_with_prefix(bytes, CROSSCERT_PREFIX)
+    }
+
+    /// Decode a slice of bytes into an RSA crosscert whose signature was
+    /// made over `prefix` and the signed portion, rather than over the
+    /// standard domain-separation string.
+    ///
+    /// The slice must hold exactly one crosscert.
+    pub(crate) fn decode_with_prefix(
+        bytes: &[u8],
+        prefix: &[u8],
+    ) -> tor_bytes::Result<UncheckedRsaCrosscert> {
+        Self::decode_detailed_with_prefix(bytes, prefix)
+            .map_err(CrosscertDecodeError::into_bytes_error)
+    }
+
+    /// As [`decode_with_prefix`](RsaCrosscert::decode_with_prefix), but say
+    /// which part of the certificate was wrong.
+    fn decode_detailed_with_prefix(
+        bytes: &[u8],
+        prefix: &[u8],
+    ) -> Result<UncheckedRsaCrosscert, CrosscertDecodeError> {
+        let mut r = Reader::from_slice(bytes);
+        let cc = RsaCrosscertRef::decode_one_with_prefix(&mut r, prefix)?.to_owned_cert();
+        r.should_be_exhausted()
+            .map_err(CrosscertDecodeError::TrailingData)?;
+        Ok(cc)
+    }
+
+    /// Decode a single RSA crosscert from the front of `r`.
+    ///
+    /// On success, `r` is left positioned just after the crosscert, so that
+    /// several concatenated crosscerts can be decoded one after another.
+    pub fn decode_one(r: &mut Reader<'_>) -> tor_bytes::Result<UncheckedRsaCrosscert> {
+        Ok(RsaCrosscertRef::decode_one(r)?.to_owned_cert())
+    }
+
+    /// Make a new crosscert in which `rsa_key` certifies `subject_key`
+    /// until `expiration`, and return the encoded certificate along with
+    /// the certificate itself.
+    ///
+    /// The expiration time is rounded up to the next hour.  `rng` is used
+    /// for blinding the RSA signing operation.
+    ///
+    /// Returns an error if the certificate would be expired at `now`, or
+    /// if the signature can't be made or encoded.
+    pub fn encode_and_sign<R>(
+        subject_key: &ll::pk::ed25519::PublicKey,
+        expiration: SystemTime,
+        rsa_key: &ll::pk::rsa::PrivateKey,
+        now: SystemTime,
+        rng: &mut R,
+    ) -> Result<(Vec<u8>, RsaCrosscert), CertEncodeError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        if expiration <= now {
+            return Err(CertEncodeError::AlreadyExpired);
+        }
+        let exp_hours = hours_rounding_up(expiration)?;
+
+        let mut w = Vec::new();
+        w.write(subject_key);
+        w.write_u32(exp_hours);
+        debug_assert_eq!(w.len(), SIGNED_PORTION_LEN);
+
+        let digest = crosscert_digest(CROSSCERT_PREFIX, &w[..]);
+        let signature = rsa_key
+            .sign(rng, &digest[..])
+            .map_err(|_| CertEncodeError::SigningFailed)?;
+        let siglen =
+            u8::try_from(signature.len()).map_err(|_| CertEncodeError::SignatureTooLong)?;
+        w.write_u8(siglen);
+        w.write_all(&signature[..]);
+
+        let cert = RsaCrosscert {
+            subject_key: *subject_key,
+            exp_hours,
+            digest: *digest,
+            signature,
+        };
+        Ok((w, cert))
+    }
+}
+
+/// A RSA->Ed25519 cross-certificate that borrows its signature from the
+/// buffer it was decoded from.
+///
+/// Decoding one of these doesn't copy the signature, which makes it a
+/// better choice than [`RsaCrosscert`] when we only need to check a
+/// certificate and then throw it away.  (Checking the RSA signature still
+/// allocates.)  Use [`to_owned_cert`](RsaCrosscertRef::to_owned_cert) to
+/// get an [`UncheckedRsaCrosscert`] if you need to keep it around.
+///
+/// Nothing about this type has been checked: call
+/// [`verify`](RsaCrosscertRef::verify) before trusting it.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct RsaCrosscertRef<'a> {
+    /// The key that is being certified
+    subject_key: ll::pk::ed25519::PublicKey,
+    /// The expiration time of this certificate, in hours since the
+    /// unix epoch.
+    exp_hours: u32,
+    /// The digest of the signed part of the certificate (for checking)
+    digest: [u8; 32],
+    /// The (alleged) signature on the certificate.
+    signature: &'a [u8],
+}
+
+impl<'a> RsaCrosscertRef<'a> {
+    /// Decode a slice of bytes into a borrowed RSA crosscert.
+    ///
+    /// The slice must hold exactly one crosscert.
+    pub fn decode(bytes: &'a [u8]) -> tor_bytes::Result<Self> {
         let mut r = Reader::from_slice(bytes);
-        let signed_portion = r.peek(36)?; // TODO(nickm): a bit ugly.
-        let subject_key = r.extract()?;
-        let exp_hours = r.take_u32()?;
-        let siglen = r.take_u8()?;
-        let signature = r.take(siglen as usize)?.into();
-
-        let mut d = ll::d::Sha256::new();
-        d.update(&b"Tor TLS RSA/Ed25519 cross-certificate"[..]);
-        d.update(signed_portion);
-        let digest = d.finalize().into();
-
-        let cc = RsaCrosscert {
+        let cc = Self::decode_one(&mut r)?;
+        r.should_be_exhausted()?;
+        Ok(cc)
+    }
+
+    /// Decode a single borrowed RSA crosscert from the front of `r`.
+    ///
+    /// As with [`RsaCrosscert::decode_one`], `r` is left positioned just
+    /// after the crosscert.
+    pub fn decode_one(r: &mut Reader<'a>) -> tor_bytes::Result<Self> {
+        Self::decode_one_with_prefix(r, CROSSCERT_PREFIX)
+            .map_err(CrosscertDecodeError::into_bytes_error)
+    }
+
+    /// Decode a single borrowed RSA crosscert from the front of `r`, using
+    /// `prefix` as its domain-separation string.
+    ///
+    /// On failure, say which part of the certificate was wrong.
+    fn decode_one_with_prefix(
+        r: &mut Reader<'a>,
+        prefix: &[u8],
+    ) -> Result<Self, CrosscertDecodeError> {
+        use CrosscertDecodeError as E;
+        // The signature covers everything up to and including the
+        // expiration time.
+        let signed_portion = r.peek(SIGNED_PORTION_LEN).map_err(E::TooShort)?;
+        let subject_key = r.extract().map_err(E::BadSubjectKey)?;
+        // This certificate binds the subject key to an identity, so there
+        // must be exactly one way to write that key.
+        if !ll::pk::ed25519::is_strictly_valid(&subject_key) {
+            return Err(E::BadSubjectKey(tor_bytes::Error::BadMessage(
+                "RSA->Ed crosscert subject key is not strictly valid",
+            )));
+        }
+        let exp_hours = r.take_u32().map_err(E::TooShort)?;
+        if crate::expiry_from_hours(exp_hours).is_none() {
+            return Err(E::BadExpiration(tor_bytes::Error::BadMessage(
+                "RSA->Ed crosscert expiration time out of range",
+            )));
+        }
+        let siglen = r.take_u8().map_err(E::TooShort)?;
+        let signature = r.take(siglen as usize).map_err(E::BadSigLen)?;
+        if signature.len() < MIN_SIGNATURE_LEN {
+            return Err(E::BadSigLen(tor_bytes::Error::BadMessage(
+                "RSA->Ed crosscert signature too short",
+            )));
+        }
+
+        // This digest isn't secret when we're checking a certificate, so
+        // it's fine to copy it out.
+        let digest = *crosscert_digest(prefix, signed_portion);
+
+        Ok(RsaCrosscertRef {
             subject_key,
             exp_hours,
             digest,
             signature,
-        };
+        })
+    }
+
+    /// Return the time at which this certificate becomes expired
+    ///
+    /// See [`RsaCrosscert::expiry`].
+    pub fn expiry(&self) -> std::time::SystemTime {
+        crate::expiry_from_hours(self.exp_hours).unwrap_or(std::time::SystemTime::UNIX_EPOCH)
+    }
+
+    /// Return true if the subject key in this certificate matches `other`
+    pub fn subject_key_matches(&self, other: &ll::pk::ed25519::PublicKey) -> bool {
+        &self.subject_key == other
+    }
+
+    /// Check whether `key` made a valid signature on this certificate.
+    ///
+    /// This only checks the signature: callers still need to check the
+    /// expiration time and the subject key.
+    pub fn verify(&self, key: &ll::pk::rsa::PublicKey) -> Result<(), CertError> {
+        key.verify(&self.digest[..], self.signature)
+            .map_err(|_| CertError::BadSignature)
+    }
 
-        Ok(UncheckedRsaCrosscert(cc))
+    /// Copy this certificate into an [`UncheckedRsaCrosscert`] that
+    /// doesn't borrow from anything.
+    ///
+    /// The result still needs its signature checked, even if you have
+    /// already called [`verify`](RsaCrosscertRef::verify) on this object.
+    pub fn to_owned_cert(&self) -> UncheckedRsaCrosscert {
+        UncheckedRsaCrosscert(RsaCrosscert {
+            subject_key: self.subject_key,
+            exp_hours: self.exp_hours,
+            digest: self.digest,
+            signature: self.signature.into(),
+        })
     }
 }
 
+/// Compute the digest that the RSA key signs, for a crosscert whose signed
+/// portion (subject key and expiration time) is `signed_portion`, and
+/// whose domain-separation string is `prefix`.
+fn crosscert_digest(prefix: &[u8], signed_portion: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut d = ll::d::Sha256::new();
+    d.update(prefix);
+    d.update(signed_portion);
+    Zeroizing::new(d.finalize().into())
+}
+
 /// An RsaCrosscert whose signature has not been checked.
 pub struct UncheckedRsaCrosscert(RsaCrosscert);
 
+impl UncheckedRsaCrosscert {
+    /// Return the first key in `keys` that made a valid signature on this
+    /// certificate, if any.
+    ///
+    /// This is useful when we don't know which of several RSA identity keys
+    /// signed the certificate (as during key rotation): it lets us check
+    /// each one without having to clone or consume the certificate.
+    pub fn find_valid_key<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a ll::pk::rsa::PublicKey>,
+    ) -> Option<&'a ll::pk::rsa::PublicKey> {
+        keys.into_iter().find(|k| self.is_well_signed(k).is_ok())
+    }
+
+    /// Check that `key` made a valid signature on this certificate, and
+    /// that the certificate hasn't expired according to `clock`.
+    ///
+    /// This is the same as calling
+    /// [`check_signature`](ExternallySigned::check_signature) and then
+    /// checking the result against the current time, except that the
+    /// caller decides what the current time is: for instance, by passing a
+    /// fixed `SystemTime` to validate a stored certificate as of when it
+    /// was received.  Use [`SystemClock`](tor_checkable::SystemClock) for
+    /// the system's idea of the time.
+    pub fn check_with_clock(
+        self,
+        key: &ll::pk::rsa::PublicKey,
+        clock: &dyn TimeSource,
+    ) -> Result<RsaCrosscert, CertError> {
+        Ok(self.check_signature(key)?.check_valid_with_clock(clock)?)
+    }
+}
+
 impl ExternallySigned<TimerangeBound<RsaCrosscert>> for UncheckedRsaCrosscert {
     type Key = ll::pk::rsa::PublicKey;
     type KeyHint = ();
-    type Error = tor_bytes::Error;
+    type Error = CertError;
 
     fn key_is_correct(&self, _k: &Self::Key) -> Result<(), Self::KeyHint> {
         // there is no way to check except for trying to verify the signature
@@ -80,15 +368,378 @@ impl ExternallySigned<TimerangeBound<RsaCrosscert>> for UncheckedRsaCrosscert {
     }
 
     fn is_well_signed(&self, k: &Self::Key) -> Result<(), Self::Error> {
-        k.verify(&self.0.digest[..], &self.0.signature[..])
-            .map_err(|_| {
-                tor_bytes::Error::BadMessage("Invalid signature on RSA->Ed identity crosscert")
-            })?;
-        Ok(())
+        self.0.as_borrowed().verify(k)
     }
 
     fn dangerously_assume_wellsigned(self) -> TimerangeBound<RsaCrosscert> {
-        let expiration = self.0.expiry();
-        TimerangeBound::new(self.0, ..expiration)
+        self.0.into_timerange_bound()
+    }
+}
+
+impl RsaCrosscert {
+    /// Wrap this certificate so that it is only usable before it expires.
+    fn into_timerange_bound(self) -> TimerangeBound<RsaCrosscert> {
+        let expiration = self.expiry();
+        TimerangeBound::new(self, ..expiration)
+    }
+}
+
+/// The number of crosscerts that a [`CrosscertCache`] remembers by default.
+pub const DEFAULT_CROSSCERT_CACHE_SIZE: usize = 256;
+
+/// The key for an entry in a [`CrosscertCache`]: the digest of a
+/// crosscert's signed portion, a digest of its signature, and the identity
+/// of the RSA key that signed it.
+type CrosscertCacheKey = ([u8; 32], [u8; 32], ll::pk::rsa::RsaIdentity);
+
+/// A bounded cache of RSA->Ed25519 crosscerts whose signatures we have
+/// already checked.
+///
+/// We see the same relays' crosscerts over and over again as we build
+/// circuits, and checking an RSA signature is comparatively slow.  This
+/// cache remembers which certificates we have found to be well-signed, so
+/// that checking one again is just a lookup.  (The caller still needs to
+/// check the timeliness of the result.)
+///
+/// Entries are keyed by the digest of the certificate's signed portion,
+/// by a digest of its signature, and by the identity of the RSA key.
+/// Together these cover every byte of the certificate, so a certificate
+/// that matches an entry is exactly the one we checked: in particular, one
+/// with a tampered signature never gets the benefit of a cached check.
+///
+/// When the cache is full, we forget the least recently used entry.
+pub struct CrosscertCache {
+    /// The largest number of entries that we'll hold.
+    capacity: usize,
+    /// The certificates we've checked, along with the last time (as
+    /// a value of `next_stamp`) that we used each one.
+    entries: HashMap<CrosscertCacheKey, (RsaCrosscert, u64)>,
+    /// Index from the last time each entry was used to its key, so we can
+    /// find the least recently used one.
+    by_use: BTreeMap<u64, CrosscertCacheKey>,
+    /// A counter that we increment every time we use an entry.
+    next_stamp: u64,
+}
+
+impl CrosscertCache {
+    /// Make a new empty cache that holds at most `capacity` certificates.
+    ///
+    /// A cache with a capacity of zero never remembers anything.
+    pub fn new(capacity: usize) -> Self {
+        CrosscertCache {
+            capacity,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            next_stamp: 0,
+        }
+    }
+
+    /// Return the largest number of certificates this cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the number of certificates in this cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return true if this cache holds no certificates.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check whether `key` made a valid signature on `cert`, consulting
+    /// and updating this cache as needed.
+    ///
+    /// This has the same result as using
+    /// [`check_signature`](tor_checkable::ExternallySigned::check_signature),
+    /// except that it doesn't consume `cert`.
+    pub fn check_signature(
+        &mut self,
+        cert: &UncheckedRsaCrosscert,
+        key: &ll::pk::rsa::PublicKey,
+    ) -> Result<TimerangeBound<RsaCrosscert>, CertError> {
+        let sig_digest = ll::d::Sha256::digest(&cert.0.signature[..]).into();
+        let cache_key = (cert.0.digest, sig_digest, key.to_rsa_identity());
+        if let Some(cc) = self.lookup(&cache_key) {
+            return Ok(cc.into_timerange_bound());
+        }
+        cert.is_well_signed(key)?;
+        self.insert(cache_key, cert.0.clone());
+        Ok(cert.0.clone().into_timerange_bound())
+    }
+
+    /// Return a copy of the entry for `key`, if any, and mark it as
+    /// recently used.
+    fn lookup(&mut self, key: &CrosscertCacheKey) -> Option<RsaCrosscert> {
+        let stamp = self.next_stamp;
+        let (cc, last_used) = self.entries.get_mut(key)?;
+        self.next_stamp += 1;
+        self.by_use.remove(last_used);
+        *last_used = stamp;
+        self.by_use.insert(stamp, *key);
+        Some(cc.clone())
+    }
+
+    /// Remember that `cc` is well-signed, evicting the least recently used
+    /// entry if we need to make room.
+    fn insert(&mut self, key: CrosscertCacheKey, cc: RsaCrosscert) {
+        if self.capacity == 0 || self.entries.contains_key(&key) {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self.by_use.keys().next().copied();
+            if let Some(key) = oldest.and_then(|stamp| self.by_use.remove(&stamp)) {
+                self.entries.remove(&key);
+            }
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.entries.insert(key, (cc, stamp));
+        self.by_use.insert(stamp, key);
+    }
+}
+
+impl Default for CrosscertCache {
+    fn default() -> Self {
+        CrosscertCache::new(DEFAULT_CROSSCERT_CACHE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use hex_literal::hex;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn max_expiry() {
+        // A crosscert with exp_hours = u32::MAX and an all-zero signature.
+        let mut c =
+            hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9").to_vec();
+        c.extend_from_slice(&u32::MAX.to_be_bytes());
+        c.push(128);
+        c.extend_from_slice(&[0; 128]);
+
+        let far_future =
+            SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(u64::from(u32::MAX) * 3600));
+        match RsaCrosscert::decode(&c) {
+            Ok(cc) => {
+                // This platform can represent the time: make sure it is
+                // the one we expect.
+                assert_eq!(Some(cc.0.expiry()), far_future);
+            }
+            Err(e) => {
+                // This platform can't: decoding must fail cleanly.
+                assert!(far_future.is_none());
+                assert_eq!(
+                    e,
+                    tor_bytes::Error::BadMessage("RSA->Ed crosscert expiration time out of range")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn alternate_prefix() {
+        let mut c =
+            hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9").to_vec();
+        c.extend_from_slice(&500_000_u32.to_be_bytes());
+        c.push(128);
+        c.extend_from_slice(&[7; 128]);
+        let signed_portion = &c[..SIGNED_PORTION_LEN];
+
+        let standard = RsaCrosscert::decode(&c).unwrap().0;
+        let prefix = b"Tor TLS RSA/Ed25519 cross-certificate v2";
+        let alternate = RsaCrosscert::decode_with_prefix(&c, &prefix[..]).unwrap().0;
+
+        // Only the digest depends on the prefix.
+        assert_eq!(standard.subject_key, alternate.subject_key);
+        assert_eq!(standard.exp_hours, alternate.exp_hours);
+        assert_eq!(standard.signature, alternate.signature);
+        assert_eq!(
+            standard.digest,
+            *crosscert_digest(CROSSCERT_PREFIX, signed_portion)
+        );
+        assert_eq!(alternate.digest, *crosscert_digest(prefix, signed_portion));
+        assert_ne!(standard.digest, alternate.digest);
+
+        // The same checks on length apply either way.
+        assert!(RsaCrosscert::decode_with_prefix(&c[..c.len() - 1], &prefix[..]).is_err());
+    }
+
+    #[test]
+    fn decode_stages() {
+        use CrosscertDecodeError as E;
+        let key = hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9");
+        let cc = |key: &[u8], exp_hours: u32, siglen: u8, sig: &[u8]| {
+            let mut c = key.to_vec();
+            c.extend_from_slice(&exp_hours.to_be_bytes());
+            c.push(siglen);
+            c.extend_from_slice(sig);
+            c
+        };
+        let good = cc(&key, 500_000, 128, &[7; 128]);
+        assert!(RsaCrosscert::decode_detailed(&good).is_ok());
+
+        let stage = |c: &[u8]| RsaCrosscert::decode_detailed(c).err().unwrap();
+        assert!(matches!(stage(&good[..20]), E::TooShort(_)));
+        assert!(matches!(stage(&good[..SIGNED_PORTION_LEN]), E::TooShort(_)));
+        // The identity point isn't a strictly valid key.
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        assert!(matches!(
+            stage(&cc(&identity, 500_000, 128, &[7; 128])),
+            E::BadSubjectKey(_)
+        ));
+        if crate::expiry_from_hours(u32::MAX).is_none() {
+            assert!(matches!(
+                stage(&cc(&key, u32::MAX, 128, &[7; 128])),
+                E::BadExpiration(_)
+            ));
+        }
+        assert!(matches!(stage(&good[..good.len() - 1]), E::BadSigLen(_)));
+        assert!(matches!(
+            stage(&cc(&key, 500_000, 64, &[7; 64])),
+            E::BadSigLen(_)
+        ));
+        let mut long = good.clone();
+        long.push(0);
+        let e = stage(&long);
+        assert!(matches!(e, E::TrailingData(_)));
+
+        // decode() reports the same errors, without the stage.
+        assert_eq!(
+            RsaCrosscert::decode(&long).err(),
+            Some(e.into_bytes_error())
+        );
+        assert_eq!(
+            RsaCrosscert::decode(&good[..20]).err(),
+            Some(tor_bytes::Error::Truncated)
+        );
+
+        // Whatever we feed it, we don't panic.
+        let mut rng = rand::thread_rng();
+        for n in 0..good.len() + 4 {
+            let mut c = good.clone();
+            c.resize(n, 0);
+            for _ in 0..8 {
+                let _ = RsaCrosscert::decode_detailed(&c);
+                rand::RngCore::fill_bytes(&mut rng, &mut c[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn strict_subject_key() {
+        let cc = |key: [u8; 32]| {
+            let mut c = key.to_vec();
+            c.extend_from_slice(&500_000_u32.to_be_bytes());
+            c.push(128);
+            c.extend_from_slice(&[7; 128]);
+            RsaCrosscert::decode(&c)
+        };
+        let bad = || {
+            Some(tor_bytes::Error::BadMessage(
+                "RSA->Ed crosscert subject key is not strictly valid",
+            ))
+        };
+
+        assert!(cc(hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9"
+        ))
+        .is_ok());
+        // The identity point, which has small order.
+        assert_eq!(
+            cc(hex!(
+                "0100000000000000000000000000000000000000000000000000000000000000"
+            ))
+            .err(),
+            bad()
+        );
+        // The identity point again, but encoded non-canonically.
+        assert_eq!(
+            cc(hex!(
+                "0100000000000000000000000000000000000000000000000000000000000080"
+            ))
+            .err(),
+            bad()
+        );
+    }
+
+    #[test]
+    fn cache_eviction() {
+        let key = hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9");
+        let cert = |n: u8| {
+            let mut c = key.to_vec();
+            c.extend_from_slice(&500_000_u32.to_be_bytes());
+            c.push(128);
+            c.extend_from_slice(&[n; 128]);
+            RsaCrosscert::decode(&c).unwrap().0
+        };
+        let id = |n: u8| ll::pk::rsa::RsaIdentity::from([n; 20]);
+
+        let mut cache = CrosscertCache::new(2);
+        assert!(cache.is_empty());
+        cache.insert(([1; 32], [0; 32], id(1)), cert(1));
+        cache.insert(([2; 32], [0; 32], id(1)), cert(2));
+        assert_eq!(cache.len(), 2);
+        // The same digest with a different key is a different entry.
+        assert!(cache.lookup(&([1; 32], [0; 32], id(2))).is_none());
+        // So is the same digest with a different signature.
+        assert!(cache.lookup(&([1; 32], [1; 32], id(1))).is_none());
+
+        // Using the first entry makes the second one the oldest...
+        assert_eq!(
+            cache.lookup(&([1; 32], [0; 32], id(1))).unwrap().signature[0],
+            1
+        );
+        // ...so it's the one that gets evicted.
+        cache.insert(([3; 32], [0; 32], id(1)), cert(3));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&([2; 32], [0; 32], id(1))).is_none());
+        assert!(cache.lookup(&([1; 32], [0; 32], id(1))).is_some());
+        assert!(cache.lookup(&([3; 32], [0; 32], id(1))).is_some());
+
+        // A cache with no room remembers nothing.
+        let mut cache = CrosscertCache::new(0);
+        cache.insert(([1; 32], [0; 32], id(1)), cert(1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn several() {
+        // Three crosscerts with different expiration times and signature
+        // lengths, back to back.
+        let key = hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9");
+        let mut bundle = Vec::new();
+        for (i, siglen) in [128_u8, 200, 130].iter().enumerate() {
+            bundle.extend_from_slice(&key);
+            bundle.extend_from_slice(&(500_000 + i as u32).to_be_bytes());
+            bundle.push(*siglen);
+            bundle.extend(std::iter::repeat(0).take((*siglen).into()));
+        }
+
+        let mut r = Reader::from_slice(&bundle);
+        for i in 0..3_u32 {
+            let cc = RsaCrosscert::decode_one(&mut r).unwrap();
+            assert_eq!(cc.0.exp_hours, 500_000 + i);
+            // Each digest covers only its own certificate.
+            let start = (i as usize) * (SIGNED_PORTION_LEN + 1) + [0, 128, 328][i as usize];
+            let signed = &bundle[start..start + SIGNED_PORTION_LEN];
+            assert_eq!(cc.0.digest, *crosscert_digest(CROSSCERT_PREFIX, signed));
+        }
+        assert_eq!(r.remaining(), 0);
+        assert!(RsaCrosscert::decode_one(&mut r).is_err());
+
+        // decode() insists on exactly one.
+        assert_eq!(
+            RsaCrosscert::decode(&bundle).err(),
+            Some(tor_bytes::Error::ExtraneousBytes)
+        );
+        let first_len = SIGNED_PORTION_LEN + 1 + 128;
+        assert!(RsaCrosscert::decode(&bundle[..first_len]).is_ok());
     }
 }
@@ -1,6 +1,6 @@
 use tor_bytes::Error;
-//use tor_cert::rsa::RsaCrosscert;
-use tor_cert::Ed25519Cert;
+use tor_cert::rsa::RsaCrosscert;
+use tor_cert::{CertError, CertType, Ed25519Cert, KeyType};
 use tor_llcrypto::pk::ed25519;
 //use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
 
@@ -31,21 +31,52 @@ fn cant_parse() {
         )),
         Error::BadMessage("wrong length on Ed25519 key")
     );
+}
 
-    assert_eq!(
-        decode_err(&hex!(
-            "
-       01 04 0006CC2A 01
-       F82294B866A31F01FC5D0DA8572850A9B929545C3266558D7D2316E3B74172B0
-       01 0020 09 01
-       DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
-       FF1A5203FA27F86EF7528D89A0845D2520166E340754FFEA2AAE0F612B7CE5DA
-       094A0236CDAC45034B0B6842C18E7F6B51B93A3CF7E60663B8AD061C30A62602"
-        )),
-        Error::BadMessage(
-            "unrecognized certificate extension, with 'affects_validation' flag set."
-        )
+#[test]
+fn critical_extension() {
+    let signing_key = ed25519::PublicKey::from_bytes(&hex!(
+        "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9"
+    ))
+    .unwrap();
+
+    // An unrecognized extension without the affects-validation flag is
+    // fine: we can still look at it.
+    let c = hex!(
+        "01 04 0006CC2A 01
+         F82294B866A31F01FC5D0DA8572850A9B929545C3266558D7D2316E3B74172B0
+         01 0020 09 00
+         DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+         FF1A5203FA27F86EF7528D89A0845D2520166E340754FFEA2AAE0F612B7CE5DA
+         094A0236CDAC45034B0B6842C18E7F6B51B93A3CF7E60663B8AD061C30A62602"
     );
+    let cert = Ed25519Cert::decode(&c[..]).unwrap();
+    {
+        let exts: Vec<_> = cert.peek_unrecognized_extensions().collect();
+        assert_eq!(exts.len(), 1);
+        assert_eq!(exts[0].ext_type(), 9.into());
+        assert_eq!(exts[0].flags(), 0);
+        assert_eq!(exts[0].body(), signing_key.as_bytes());
+    }
+    assert!(cert.check_key(&Some(signing_key)).is_ok());
+
+    // With the flag set, the cert still decodes, but we can't check it.
+    let c = hex!(
+        "01 04 0006CC2A 01
+         F82294B866A31F01FC5D0DA8572850A9B929545C3266558D7D2316E3B74172B0
+         01 0020 09 01
+         DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+         FF1A5203FA27F86EF7528D89A0845D2520166E340754FFEA2AAE0F612B7CE5DA
+         094A0236CDAC45034B0B6842C18E7F6B51B93A3CF7E60663B8AD061C30A62602"
+    );
+    let cert = Ed25519Cert::decode(&c[..]).unwrap();
+    assert!(cert
+        .peek_unrecognized_extensions()
+        .all(|e| e.affects_validation()));
+    assert!(matches!(
+        cert.check_key(&Some(signing_key)).err().unwrap(),
+        CertError::UnrecognizedCriticalExtension(t) if t == 9.into()
+    ));
 }
 
 #[test]
@@ -67,10 +98,10 @@ fn mismatched_signing_key() {
 
     // We give the wrong key to check_key, so it will tell us that
     // wasn't what the cert contained.
-    assert_eq!(
+    assert!(matches!(
         cert.check_key(&Some(not_that_key)).err().unwrap(),
-        Error::BadMessage("Mismatched public key on cert")
-    );
+        CertError::SigningKeyMismatch
+    ));
 
     // from testvec_certs.
     let c = hex!(
@@ -84,8 +115,78 @@ fn mismatched_signing_key() {
 
     // We give no key to check_key, which will tell us that there wasn't
     // a signing-key extension in the cert.
-    assert_eq!(
+    assert!(matches!(
         cert.check_key(&None).err().unwrap(),
-        Error::BadMessage("Missing public key on cert")
+        CertError::MissingSigningKey
+    ));
+
+    // This is a signing->TLS cert, so it's the wrong type for an identity
+    // cert, and the key it certifies isn't an ed25519 key.
+    let cert = Ed25519Cert::decode(&c[..]).unwrap();
+    assert!(matches!(
+        cert.check_cert_type(CertType::IDENTITY_V_SIGNING)
+            .err()
+            .unwrap(),
+        CertError::UnsupportedCertType(t) if t == CertType::SIGNING_V_TLS_CERT
+    ));
+    let cert = Ed25519Cert::decode(&c[..]).unwrap();
+    assert!(matches!(
+        cert.peek_subject_key().require_ed25519().err().unwrap(),
+        CertError::WrongSubjectKeyType(t) if t == KeyType::SHA256_OF_X509
+    ));
+}
+
+#[test]
+fn cant_parse_rsa_crosscert() {
+    fn decode_err(inp: &[u8]) -> Error {
+        RsaCrosscert::decode(inp).err().unwrap()
+    }
+
+    // Too short to hold the key and expiration time.
+    assert_eq!(
+        decode_err(&hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9 0006DA"
+        )),
+        Error::Truncated
+    );
+
+    // Signature shorter than its length byte claims.
+    assert_eq!(
+        decode_err(&hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+             0006DA3A 04 5CF600"
+        )),
+        Error::Truncated
+    );
+
+    // Extra bytes after the signature.
+    let mut c = hex!(
+        "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+         0006DA3A 80"
+    )
+    .to_vec();
+    c.extend_from_slice(&[0x5C; 128]);
+    c.push(0);
+    assert_eq!(decode_err(&c[..]), Error::ExtraneousBytes);
+
+    // Empty signature.
+    assert_eq!(
+        decode_err(&hex!(
+            "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+             0006DA3A 00"
+        )),
+        Error::BadMessage("RSA->Ed crosscert signature too short")
+    );
+
+    // Signature too short to come from a 1024-bit RSA key.
+    let mut c = hex!(
+        "DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9
+         0006DA3A 7F"
+    )
+    .to_vec();
+    c.extend_from_slice(&[0x5C; 127]);
+    assert_eq!(
+        decode_err(&c[..]),
+        Error::BadMessage("RSA->Ed crosscert signature too short")
     );
 }
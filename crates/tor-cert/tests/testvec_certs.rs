@@ -1,6 +1,6 @@
-use tor_cert::rsa::RsaCrosscert;
-use tor_cert::{Ed25519Cert, KeyType};
-use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
+use tor_cert::rsa::{RsaCrosscert, RsaCrosscertRef};
+use tor_cert::{CertError, CrosscertCache, Ed25519Cert, KeyType};
+use tor_checkable::{ExternallySigned, SelfSigned, SystemClock, TimeValidityError, Timebound};
 
 use std::time::{Duration, SystemTime};
 
@@ -40,6 +40,8 @@ fn test_valid_ed() {
     assert_eq!(cert.subject_key().key_type(), KeyType::ED25519_KEY);
     assert_eq!(cert.subject_key().as_ed25519(), Some(&signing_key));
     assert_eq!(cert.signing_key().unwrap(), &identity_key);
+    assert!(cert.signing_key_included());
+    assert_eq!(cert.unrecognized_extensions().count(), 0);
     assert_eq!(cert.cert_type(), 4.into());
     assert_eq!(
         cert.expiry(),
@@ -69,6 +71,7 @@ fn test_valid_ed() {
     assert_eq!(cert.subject_key().as_bytes(), &tls_cert_digest[..]);
     assert_eq!(cert.subject_key().as_ed25519(), None);
     assert_eq!(cert.signing_key().unwrap(), &signing_key);
+    assert!(!cert.signing_key_included());
     assert_eq!(cert.cert_type(), 5.into());
     assert_eq!(
         cert.expiry(),
@@ -96,6 +99,17 @@ fn test_valid_rsa_cc() {
          DCAF04BE0A72D548A27BF2E77BD876ECFE5E1BE622350DA6BF31F6E306ED8964
          88DD5B39409B23FC3EB7B2C9F7328EB18DA36D54D80575899EA6507CCBFCDF1F"
     );
+    // The borrowed form can be checked without being copied.
+    let cert_ref = RsaCrosscertRef::decode(&c[..]).unwrap();
+    assert!(cert_ref.verify(&pk).is_ok());
+    assert!(cert_ref.verify(&wrong_pk).is_err());
+    assert!(cert_ref.subject_key_matches(&ed_identity));
+    assert_eq!(
+        cert_ref.expiry(),
+        SystemTime::UNIX_EPOCH + Duration::new(0x6DA3A * 3600, 0)
+    );
+    assert!(cert_ref.to_owned_cert().is_well_signed(&pk).is_ok());
+
     let cert = RsaCrosscert::decode(&c[..]).unwrap();
 
     // This returns correct for all keys.
@@ -105,6 +119,62 @@ fn test_valid_rsa_cc() {
     // But it isn't well-signed with the wrong pk.
     assert!(cert.is_well_signed(&wrong_pk).is_err());
 
+    // We can find the right key from a list of candidates.
+    let found = cert.find_valid_key(vec![&wrong_pk, &pk]).unwrap();
+    assert!(std::ptr::eq(found, &pk));
+    assert!(cert.find_valid_key(vec![&wrong_pk]).is_none());
+    assert!(cert.find_valid_key(Vec::new()).is_none());
+
+    // The cache only remembers certificates that are well-signed with the
+    // key we asked about.
+    let mut cache = CrosscertCache::new(4);
+    assert!(cache.check_signature(&cert, &wrong_pk).is_err());
+    assert!(cache.is_empty());
+    for _ in 0..2 {
+        let cached = cache
+            .check_signature(&cert, &pk)
+            .unwrap()
+            .check_valid_at(&notional_time)
+            .unwrap();
+        assert!(cached.subject_key_matches(&ed_identity));
+        assert_eq!(cache.len(), 1);
+    }
+    assert!(cache.check_signature(&cert, &wrong_pk).is_err());
+
+    // A copy of the certificate with a tampered signature doesn't match
+    // the cached entry, so it gets checked, and fails.
+    let mut tampered = c.to_vec();
+    *tampered.last_mut().unwrap() ^= 0x01;
+    let tampered = RsaCrosscert::decode(&tampered[..]).unwrap();
+    assert!(cache.check_signature(&tampered, &pk).is_err());
+    assert_eq!(cache.len(), 1);
+
+    // We can check the signature and the time together, with whatever
+    // clock we like.
+    let expiry = cert_ref.expiry();
+    let checked = RsaCrosscert::decode(&c[..])
+        .unwrap()
+        .check_with_clock(&pk, &notional_time)
+        .unwrap();
+    assert!(checked.subject_key_matches(&ed_identity));
+    assert!(matches!(
+        RsaCrosscert::decode(&c[..])
+            .unwrap()
+            .check_with_clock(&pk, &(expiry + Duration::from_secs(1))),
+        Err(CertError::Untimely(TimeValidityError::Expired(_)))
+    ));
+    assert!(matches!(
+        RsaCrosscert::decode(&c[..])
+            .unwrap()
+            .check_with_clock(&wrong_pk, &notional_time),
+        Err(CertError::BadSignature)
+    ));
+    // This certificate expired long ago.
+    assert!(RsaCrosscert::decode(&c[..])
+        .unwrap()
+        .check_with_clock(&pk, &SystemClock)
+        .is_err());
+
     let cert = cert
         .check_signature(&pk)
         .unwrap()
@@ -112,3 +182,65 @@ fn test_valid_rsa_cc() {
         .unwrap();
     assert!(cert.subject_key_matches(&ed_identity));
 }
+
+#[test]
+fn test_make_rsa_cc() {
+    // This key is taken from a chutney network, as in tor-llcrypto's tests.
+    let sk = "
+MIICXQIBAAKBgQDVJ7bGPW6B05wyipTOFX3M3AROsa2MIQycniJIe0z63m1AQb0Q
+Rpplfj2CvADPYqw7apkkflc7VMEMR/XchJsKzNoDHspvbl3IVnf3bexJ/yTS/LK1
+iH+xJaogR0QRm7ZBf0XuaW+N/Bwvwhsrro6eN6GdwlGKLCTn2P1/rA9GlQIDAQAB
+AoGADZDgfg9s2BBqsYDGZbNSdVZPY97FB9UWo2UhE3HdfV3ooB1O9hk4PFtjeM2U
+U56ZDZMEOiFcVedX/fsad7Vs1I5VUxwZqXdhRgqJllD5RPifSSpt3lnYNE0O/WN5
+DJIUR2yxJ2cXj2D2MUh56T5RnqC17lXWEOmUUlM7u2/po8ECQQD6HVmhcclRWWP8
+/IuSu8rD/2kjjuoAhg1ptfSyzSIp0ipS7xYcru13dDkFG8WllosEt0eu+FIL+Vnz
+VtxyBErRAkEA2iu5yiRSVRHOud2SHtn1wUx2M8pSdB3XtCQjkpiwhsSij20eYFVv
+clS5A6E0D4txRK4flnwYqVTdxlh271fohQJBAMKZ+XX6oWeRBJH/MN1/DZmH7RcE
+iB7WLjN0pipEHvOpGNMkQPEaTZsmq4LFA/f9dLa7n6OMg9HbNdh2WdjAbDECQFqT
+9tG23LvW5dYC6KyIX2C+ZwC/ihYNYcW3j1FItVluf/M+IXNrZRa5mAqqvduKUB9s
+j07B/Ncold7IUbCy9aUCQQDew08R5vjl8n+I44u/KIZ4RR1ntggrnDfKnCD8nNR4
+LnZEGsos1BCJkS31lYl7Jae1QVooa6522Rz8ORo+GfbZ";
+    let sk: Vec<u8> = sk
+        .lines()
+        .flat_map(|line| base64::decode(line).unwrap())
+        .collect();
+    let sk = tor_llcrypto::pk::rsa::PrivateKey::from_der(&sk[..]).unwrap();
+    let pk = sk.to_public_key();
+
+    let ed_identity = hex!("DCB604DB2034B00FD16986D4ADB9D16B21CB4E4457A33DEC0F538903683E96E9");
+    let ed_identity = tor_llcrypto::pk::ed25519::PublicKey::from_bytes(&ed_identity[..]).unwrap();
+
+    let now = SystemTime::now();
+    let expiration = now + Duration::from_secs(86400);
+    let mut rng = rand::thread_rng();
+    let (encoded, made) =
+        RsaCrosscert::encode_and_sign(&ed_identity, expiration, &sk, now, &mut rng).unwrap();
+    assert!(made.subject_key_matches(&ed_identity));
+    // The expiration time gets rounded up to the next hour.
+    assert!(made.expiry() >= expiration);
+    assert!(made.expiry() < expiration + Duration::from_secs(3600));
+    assert_eq!(encoded.len(), 32 + 4 + 1 + 128);
+
+    // What we made decodes to the same thing, and is well-signed.
+    let cert = RsaCrosscert::decode(&encoded[..])
+        .unwrap()
+        .check_signature(&pk)
+        .unwrap()
+        .check_valid_at(&now)
+        .unwrap();
+    assert!(cert.subject_key_matches(&ed_identity));
+    assert_eq!(cert.expiry(), made.expiry());
+
+    // A truncated signature is rejected.
+    let mut truncated = encoded[..encoded.len() - 1].to_vec();
+    assert!(RsaCrosscert::decode(&truncated[..]).is_err());
+    // ...even if we fix up its length to match.
+    truncated[36] -= 1;
+    assert!(RsaCrosscert::decode(&truncated[..]).is_err());
+
+    // We won't make a certificate that has already expired.
+    assert!(RsaCrosscert::encode_and_sign(&ed_identity, now, &sk, now, &mut rng).is_err());
+    assert!(
+        RsaCrosscert::encode_and_sign(&ed_identity, expiration, &sk, expiration, &mut rng).is_err()
+    );
+}
@@ -1,14 +1,16 @@
 //! Implement a concrete type to build channels.
 
 use std::io;
-use std::sync::Mutex;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
-use crate::{event::ChanMgrEventSender, Error};
+use crate::{event::ChanMgrEventSender, transport::TransportRegistry, Error};
 
 use std::time::Duration;
 use tor_error::{bad_api_usage, internal};
 use tor_linkspec::{ChanTarget, OwnedChanTarget};
 use tor_llcrypto::pk;
+use tor_proto::channel::{HandshakeTimeouts, UnverifiedChannel, VerifiedChannel};
 use tor_rtcompat::{tls::TlsConnector, Runtime, TlsProvider};
 
 use async_trait::async_trait;
@@ -25,18 +27,38 @@ pub(crate) struct ChanBuilder<R: Runtime> {
     event_sender: Mutex<ChanMgrEventSender>,
     /// Object to build TLS connections.
     tls_connector: <R as TlsProvider<R::TcpStream>>::Connector,
+    /// Pluggable transports, and the addresses we must reach through them.
+    transports: Arc<TransportRegistry<R::TcpStream>>,
+    /// How long to wait for progress in each phase of the channel handshake.
+    handshake_timeouts: Mutex<HandshakeTimeouts>,
 }
 
 impl<R: Runtime> ChanBuilder<R> {
     /// Construct a new ChanBuilder.
-    pub(crate) fn new(runtime: R, event_sender: ChanMgrEventSender) -> Self {
+    pub(crate) fn new(
+        runtime: R,
+        event_sender: ChanMgrEventSender,
+        transports: Arc<TransportRegistry<R::TcpStream>>,
+    ) -> Self {
+        if let Some(warning) = <R as TlsProvider<R::TcpStream>>::fingerprint_warning(&runtime) {
+            tracing::warn!("TLS fingerprinting risk: {}", warning);
+        }
         let tls_connector = runtime.tls_connector();
         ChanBuilder {
             runtime,
             event_sender: Mutex::new(event_sender),
             tls_connector,
+            transports,
+            handshake_timeouts: Mutex::new(HandshakeTimeouts::default()),
         }
     }
+
+    /// Change the timeouts that we use for the channel handshake.
+    ///
+    /// Handshakes that are already in progress keep their old timeouts.
+    pub(crate) fn set_handshake_timeouts(&self, timeouts: HandshakeTimeouts) {
+        *self.handshake_timeouts.lock().expect("Lock poisoned") = timeouts;
+    }
 }
 
 #[async_trait]
@@ -56,17 +78,41 @@ impl<R: Runtime> crate::mgr::ChannelFactory for ChanBuilder<R> {
     }
 }
 
+/// The type of TLS stream that a [`ChanBuilder`] uses for its channels.
+type TlsStream<R> = <R as TlsProvider<<R as tor_rtcompat::TcpProvider>::TcpStream>>::TlsStream;
+
 impl<R: Runtime> ChanBuilder<R> {
+    /// Build a channel to the bridge at `addr`.
+    ///
+    /// Since we don't know a bridge's Ed25519 identity until we have
+    /// fetched its descriptor, we only check the RSA identity here, and
+    /// only if one is given.
+    pub(crate) async fn build_bridge_channel(
+        &self,
+        addr: &SocketAddr,
+        rsa_id: Option<&pk::rsa::RsaIdentity>,
+    ) -> crate::Result<tor_proto::channel::Channel> {
+        use tor_rtcompat::SleepProviderExt;
+
+        // TODO: Use the same timeout as build_channel, once that one is
+        // configurable.
+        let five_seconds = std::time::Duration::new(5, 0);
+
+        self.runtime
+            .timeout(five_seconds, async {
+                let (chan, peer_cert) = self.connect_unverified(addr).await?;
+                let now = self.runtime.wallclock();
+                let chan = chan.check_bridge(rsa_id, &peer_cert, Some(now))?;
+                self.finish_channel(chan).await
+            })
+            .await?
+    }
+
     /// As build_channel, but don't include a timeout.
     async fn build_channel_notimeout(
         &self,
         target: &OwnedChanTarget,
     ) -> crate::Result<tor_proto::channel::Channel> {
-        use tor_proto::channel::ChannelBuilder;
-        use tor_rtcompat::tls::CertifiedConn;
-
-        // 1. Negotiate the TLS connection.
-
         // TODO: This just uses the first address. Instead we could be
         // smarter, or use "happy eyeballs", or whatever.  Maybe we will
         // want to refactor as we do so?
@@ -74,6 +120,26 @@ impl<R: Runtime> ChanBuilder<R> {
             Error::UnusableTarget(bad_api_usage!("No addresses for chosen relay"))
         })?;
 
+        let (chan, peer_cert) = self.connect_unverified(addr).await?;
+        let now = self.runtime.wallclock();
+        let chan = chan.check(target, &peer_cert, Some(now))?;
+        self.finish_channel(chan).await
+    }
+
+    /// Negotiate a TLS connection with `addr`, and read the relay's half
+    /// of the channel handshake.
+    ///
+    /// Return the unverified channel, along with the certificate that the
+    /// relay presented during the TLS handshake.
+    async fn connect_unverified(
+        &self,
+        addr: &SocketAddr,
+    ) -> crate::Result<(UnverifiedChannel<TlsStream<R>>, Vec<u8>)> {
+        use tor_proto::channel::ChannelBuilder;
+        use tor_rtcompat::tls::CertifiedConn;
+
+        // 1. Negotiate the TLS connection.
+
         tracing::info!("Negotiating TLS with {}", addr);
 
         {
@@ -91,12 +157,19 @@ impl<R: Runtime> ChanBuilder<R> {
             }
         };
 
-        // Establish a TCP connection.
-        let stream = self
-            .runtime
-            .connect(addr)
-            .await
-            .map_err(map_ioe("connect"))?;
+        // Establish a TCP connection, or a connection through a pluggable
+        // transport if this address belongs to a bridge that uses one.
+        let stream = match self.transports.route_for(addr)? {
+            Some((helper, pt_target)) => helper
+                .connect(&pt_target)
+                .await
+                .map_err(map_ioe("connect via pluggable transport"))?,
+            None => self
+                .runtime
+                .connect(addr)
+                .await
+                .map_err(map_ioe("connect"))?,
+        };
 
         {
             self.event_sender
@@ -124,12 +197,37 @@ impl<R: Runtime> ChanBuilder<R> {
                 .record_tls_finished();
         }
 
-        // 2. Set up the channel.
+        // 2. Start the channel handshake.
         let mut builder = ChannelBuilder::new();
         builder.set_declared_addr(*addr);
-        let chan = builder.launch(tls).connect().await?;
-        let now = self.runtime.wallclock();
-        let chan = chan.check(target, &peer_cert, Some(now))?;
+        let timeouts = self
+            .handshake_timeouts
+            .lock()
+            .expect("Lock poisoned")
+            .clone();
+        let chan = match builder
+            .launch(tls)
+            .connect_with_timeouts(timeouts, |d| self.runtime.sleep(d))
+            .await
+        {
+            Ok(chan) => chan,
+            Err(e) => {
+                self.event_sender
+                    .lock()
+                    .expect("Lock poisoned")
+                    .record_handshake_failure();
+                return Err(e.into());
+            }
+        };
+        Ok((chan, peer_cert))
+    }
+
+    /// Finish the handshake on a verified channel, and launch a task to
+    /// run its reactor.
+    async fn finish_channel(
+        &self,
+        chan: VerifiedChannel<TlsStream<R>>,
+    ) -> crate::Result<tor_proto::channel::Channel> {
         let (chan, reactor) = chan.finish().await?;
 
         {
@@ -139,7 +237,7 @@ impl<R: Runtime> ChanBuilder<R> {
                 .record_handshake_done();
         }
 
-        // 3. Launch a task to run the channel reactor.
+        // Launch a task to run the channel reactor.
         self.runtime
             .spawn(async {
                 let _ = reactor.run().await;
@@ -219,7 +317,7 @@ mod test {
 
             // Create the channelbuilder that we want to test.
             let (snd, _rcv) = crate::event::channel();
-            let builder = ChanBuilder::new(client_rt, snd);
+            let builder = ChanBuilder::new(client_rt, snd, Arc::new(TransportRegistry::new()));
 
             let (r1, r2): (Result<Channel>, Result<LocalStream>) = futures::join!(
                 async {
@@ -246,5 +344,208 @@ mod test {
         })
     }
 
+    // Make sure that we can build a channel to a bridge whose Ed25519
+    // identity we don't know.
+    #[test]
+    fn build_bridge() -> Result<()> {
+        use crate::testing::msgs;
+        let orport: SocketAddr = msgs::ADDR.parse().unwrap();
+        let ed: Ed25519Identity = msgs::ED_ID.into();
+        let rsa: RsaIdentity = msgs::RSA_ID.into();
+        let wrong_rsa: RsaIdentity = [0x99; 20].into();
+        let client_addr = "192.0.2.17".parse().unwrap();
+        let tls_cert = msgs::X509_CERT.into();
+        let now = SystemTime::UNIX_EPOCH + Duration::new(msgs::NOW, 0);
+
+        test_with_one_runtime!(|rt| async move {
+            let network = MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address(client_addr)
+                .runtime(rt.clone());
+            let client_rt = MockSleepRuntime::new(client_rt);
+            let relay_rt = network
+                .builder()
+                .add_address(orport.ip())
+                .runtime(rt.clone());
+            let lis = relay_rt.mock_net().listen_tls(&orport, tls_cert).unwrap();
+            client_rt.jump_to(now);
+
+            let (snd, _rcv) = crate::event::channel();
+            let builder = ChanBuilder::new(client_rt, snd, Arc::new(TransportRegistry::new()));
+
+            for (rsa_id, should_work) in
+                [(None, true), (Some(&rsa), true), (Some(&wrong_rsa), false)]
+            {
+                let (r1, r2): (Result<Channel>, Result<LocalStream>) =
+                    futures::join!(builder.build_bridge_channel(&orport, rsa_id), async {
+                        let (mut con, _) = lis.accept().await.expect("accept failed");
+                        crate::testing::answer_channel_req(&mut con)
+                            .await
+                            .expect("answer failed");
+                        Ok(con)
+                    });
+                if should_work {
+                    // We learn the bridge's identities from its handshake.
+                    let chan = r1.unwrap();
+                    assert_eq!(chan.ident(), &ed);
+                    assert_eq!(chan.peer_rsa_id(), &rsa);
+                } else {
+                    assert!(matches!(r1, Err(Error::Proto(_))));
+                }
+                r2.unwrap();
+            }
+            Ok(())
+        })
+    }
+
+    // A relay that accepts our connection and then stalls during the Tor
+    // handshake makes us give up, and is recorded as a handshake failure.
+    #[test]
+    fn build_stalled() {
+        use crate::testing::msgs;
+        use tor_proto::channel::HandshakePhase;
+        let orport: SocketAddr = msgs::ADDR.parse().unwrap();
+        let ed: Ed25519Identity = msgs::ED_ID.into();
+        let rsa: RsaIdentity = msgs::RSA_ID.into();
+        let client_addr = "192.0.2.17".parse().unwrap();
+        let tls_cert = msgs::X509_CERT.into();
+        let target = OwnedChanTarget::new(vec![orport], ed, rsa);
+        let sec = Duration::from_secs(1);
+
+        test_with_one_runtime!(|rt| async move {
+            let network = MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address(client_addr)
+                .runtime(rt.clone());
+            let client_rt = MockSleepRuntime::new(client_rt);
+            let relay_rt = network
+                .builder()
+                .add_address(orport.ip())
+                .runtime(rt.clone());
+            let lis = relay_rt.mock_net().listen_tls(&orport, tls_cert).unwrap();
+
+            let (snd, _rcv) = crate::event::channel();
+            let builder =
+                ChanBuilder::new(client_rt.clone(), snd, Arc::new(TransportRegistry::new()));
+            builder.set_handshake_timeouts(HandshakeTimeouts::new(sec, sec, sec));
+
+            let (r1, _con) = futures::join!(builder.build_channel(&target), async {
+                // Accept the connection, but never answer.
+                let (con, _) = lis.accept().await.expect("accept failed");
+                client_rt.advance(sec * 2).await;
+                con
+            });
+            assert!(matches!(
+                r1,
+                Err(Error::Proto(tor_proto::Error::HandshakePhaseTimeout(
+                    HandshakePhase::Versions
+                )))
+            ));
+            assert_eq!(
+                builder.event_sender.lock().unwrap().n_handshake_failures(),
+                1
+            );
+        });
+    }
+
+    // A bridge that uses a transport we don't have must not be contacted
+    // directly.
+    #[test]
+    fn build_unknown_transport() {
+        use crate::transport::PtTarget;
+        let orport: SocketAddr = "192.0.2.9:443".parse().unwrap();
+        let target = OwnedChanTarget::new(vec![orport], [7; 32].into(), [8; 20].into());
+
+        test_with_one_runtime!(|rt| async move {
+            let transports = Arc::new(TransportRegistry::new());
+            transports.add_route(PtTarget::new("obfs4".into(), orport, vec![]));
+            let (snd, _rcv) = crate::event::channel();
+            let builder = ChanBuilder::new(rt, snd, transports);
+
+            let r = builder.build_channel(&target).await;
+            assert!(matches!(r, Err(Error::NoSuchTransport(t)) if t == "obfs4"));
+        });
+    }
+
+    // A bridge that uses a pluggable transport is reached only through
+    // that transport, even though it's listed nowhere: the address in its
+    // bridge line is never contacted directly.
+    #[test]
+    fn build_transport_bridge() -> Result<()> {
+        use crate::testing::msgs;
+        use crate::transport::{PtTarget, TransportImplHelper};
+
+        /// A "transport" that ignores the bridge's address, and connects
+        /// to a fixed relay instead.
+        struct Redirect<R> {
+            /// The runtime to connect with.
+            runtime: R,
+            /// Where the transport actually connects.
+            relay: SocketAddr,
+            /// Every target we were asked to connect to.
+            seen: Mutex<Vec<PtTarget>>,
+        }
+        #[async_trait]
+        impl<R: Runtime> TransportImplHelper<R::TcpStream> for Redirect<R> {
+            async fn connect(&self, target: &PtTarget) -> io::Result<R::TcpStream> {
+                self.seen.lock().unwrap().push(target.clone());
+                self.runtime.connect(&self.relay).await
+            }
+        }
+
+        let orport: SocketAddr = msgs::ADDR.parse().unwrap();
+        let bridge_addr: SocketAddr = "198.51.100.7:443".parse().unwrap();
+        let ed: Ed25519Identity = msgs::ED_ID.into();
+        let rsa: RsaIdentity = msgs::RSA_ID.into();
+        let client_addr = "192.0.2.17".parse().unwrap();
+        let tls_cert = msgs::X509_CERT.into();
+        let now = SystemTime::UNIX_EPOCH + Duration::new(msgs::NOW, 0);
+        let params = vec![("cert".to_owned(), "abc".to_owned())];
+
+        test_with_one_runtime!(|rt| async move {
+            let network = MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address(client_addr)
+                .runtime(rt.clone());
+            let client_rt = MockSleepRuntime::new(client_rt);
+            let relay_rt = network
+                .builder()
+                .add_address(orport.ip())
+                .runtime(rt.clone());
+            let lis = relay_rt.mock_net().listen_tls(&orport, tls_cert).unwrap();
+            client_rt.jump_to(now);
+
+            let transport = Arc::new(Redirect {
+                runtime: client_rt.clone(),
+                relay: orport,
+                seen: Mutex::new(Vec::new()),
+            });
+            let transports = Arc::new(TransportRegistry::new());
+            transports.register("obfs4", transport.clone());
+            let target = PtTarget::new("obfs4".into(), bridge_addr, params);
+            transports.add_route(target.clone());
+            let (snd, _rcv) = crate::event::channel();
+            let builder = ChanBuilder::new(client_rt, snd, transports);
+
+            let (r1, r2): (Result<Channel>, Result<LocalStream>) =
+                futures::join!(builder.build_bridge_channel(&bridge_addr, None), async {
+                    let (mut con, _) = lis.accept().await.expect("accept failed");
+                    crate::testing::answer_channel_req(&mut con)
+                        .await
+                        .expect("answer failed");
+                    Ok(con)
+                });
+            let chan = r1.unwrap();
+            assert_eq!(chan.ident(), &ed);
+            assert_eq!(chan.peer_rsa_id(), &rsa);
+            r2.unwrap();
+            assert_eq!(&transport.seen.lock().unwrap()[..], &[target]);
+            Ok(())
+        })
+    }
+
     // TODO: Write tests for timeout logic, once there is smarter logic.
 }
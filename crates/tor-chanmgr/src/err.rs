@@ -42,6 +42,10 @@ pub enum Error {
         source: Arc<std::io::Error>,
     },
 
+    /// A bridge needs a pluggable transport that we don't have.
+    #[error("No pluggable transport configured for {0:?}")]
+    NoSuchTransport(String),
+
     /// Unable to spawn task
     #[error("unable to spawn {spawning}")]
     Spawn {
@@ -78,6 +82,7 @@ impl tor_error::HasKind for Error {
             E::Spawn { cause, .. } => cause.kind(),
             E::Proto(e) => e.kind(),
             E::PendingFailed => EK::TorAccessFailed,
+            E::NoSuchTransport(_) => EK::InvalidConfig,
             E::UnusableTarget(_) | E::Internal(_) => EK::Internal,
         }
     }
@@ -188,6 +188,16 @@ struct ChanMgrStatus {
     /// If we get to this point, we can successfully talk to something that
     /// holds the private key that it's supposed to.
     last_chan_success: Option<Instant>,
+
+    /// How many times has a relay failed the inner Tor handshake, or
+    /// stalled partway through it, after we finished TLS with it?
+    n_handshake_failures: usize,
+
+    /// When (if ever) did a relay last fail the inner Tor handshake?
+    //
+    // TODO: We don't use this information yet either: a single relay that
+    // stalls tells us little about our own connection.
+    last_handshake_failure: Option<Instant>,
 }
 
 impl ChanMgrStatus {
@@ -201,6 +211,8 @@ impl ChanMgrStatus {
             last_tcp_success: None,
             last_tls_success: None,
             last_chan_success: None,
+            n_handshake_failures: 0,
+            last_handshake_failure: None,
         }
     }
 
@@ -256,6 +268,13 @@ impl ChanMgrStatus {
     fn record_handshake_done(&mut self, now: Instant) {
         self.last_chan_success = Some(now);
     }
+
+    /// Note that a relay failed the Tor handshake, or took too long to
+    /// finish it.
+    fn record_handshake_failure(&mut self, now: Instant) {
+        self.n_handshake_failures += 1;
+        self.last_handshake_failure = Some(now);
+    }
 }
 
 /// Object that manages information about a `ChanMgr`'s status, and sends
@@ -323,6 +342,20 @@ impl ChanMgrEventSender {
         self.mgr_status.record_handshake_done(now);
         self.push_at(now);
     }
+
+    /// Note that a relay failed the Tor handshake, or took too long to
+    /// finish it.
+    pub(crate) fn record_handshake_failure(&mut self) {
+        let now = Instant::now();
+        self.mgr_status.record_handshake_failure(now);
+        self.push_at(now);
+    }
+
+    /// For testing: return how many relays have failed the Tor handshake.
+    #[cfg(test)]
+    pub(crate) fn n_handshake_failures(&self) -> usize {
+        self.mgr_status.n_handshake_failures
+    }
 }
 
 /// Create a new channel for sending connectivity status events to other crates.
@@ -444,6 +477,15 @@ mod test {
         assert_eq!(s.online, Some(true));
         assert_eq!(s.tls_works, Some(false));
 
+        // A relay that fails the handshake is counted, but doesn't tell
+        // us anything about our own connection.
+        ms.record_handshake_failure(start + sec * 2);
+        assert_eq!(ms.n_handshake_failures, 1);
+        assert_eq!(ms.last_handshake_failure, Some(start + sec * 2));
+        let s = ms.conn_status_at(start + sec * 3);
+        assert_eq!(s.online, Some(true));
+        assert!(s.tls_works.is_none());
+
         // If the handshake succeeded, we can notice that too.
         ms.record_handshake_done(start + sec * 2);
         let s = ms.conn_status_at(start + sec * 3);
@@ -8,7 +8,8 @@
 //!
 //! In Tor, a channel is a connection to a Tor relay.  It can be
 //! direct via TLS, or indirect via TLS over a pluggable transport.
-//! (For now, only direct channels are supported.)
+//! Bridges that use a pluggable transport are reached through a
+//! [`transport::TransportImplHelper`] registered for that transport.
 //!
 //! Since a channel can be used for more than one circuit, it's
 //! important to reuse channels when possible.  This crate implements
@@ -51,10 +52,14 @@ mod event;
 mod mgr;
 #[cfg(test)]
 mod testing;
+pub mod transport;
 
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tor_linkspec::{ChanTarget, OwnedChanTarget};
-use tor_proto::channel::Channel;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_proto::channel::{Channel, HandshakeTimeouts};
 
 pub use err::Error;
 
@@ -76,20 +81,47 @@ pub struct ChanMgr<R: Runtime> {
 
     /// Stream of [`ConnStatus`] events.
     bootstrap_status: event::ConnStatusEvents,
+
+    /// The pluggable transports we know about, shared with our builder.
+    transports: Arc<transport::TransportRegistry<R::TcpStream>>,
 }
 
 impl<R: Runtime> ChanMgr<R> {
     /// Construct a new channel manager.
     pub fn new(runtime: R) -> Self {
         let (sender, receiver) = event::channel();
-        let builder = builder::ChanBuilder::new(runtime, sender);
+        let transports = Arc::new(transport::TransportRegistry::new());
+        let builder = builder::ChanBuilder::new(runtime, sender, Arc::clone(&transports));
         let mgr = mgr::AbstractChanMgr::new(builder);
         ChanMgr {
             mgr,
             bootstrap_status: receiver,
+            transports,
         }
     }
 
+    /// Use `helper` to connect to every bridge that uses the pluggable
+    /// transport called `name`.
+    ///
+    /// Replaces any helper previously registered for `name`.
+    pub fn register_transport(
+        &self,
+        name: &str,
+        helper: Arc<dyn transport::TransportImplHelper<R::TcpStream>>,
+    ) {
+        self.transports.register(name, helper);
+    }
+
+    /// Make sure that every connection to `target.addr()` goes through the
+    /// pluggable transport `target.transport()`.
+    ///
+    /// Once a route is added, we never connect to its address directly: if
+    /// no helper is registered for its transport, attempts to connect to
+    /// it will fail.
+    pub fn add_transport_route(&self, target: transport::PtTarget) {
+        self.transports.add_route(target);
+    }
+
     /// Try to get a suitable channel to the provided `target`,
     /// launching one if one does not exist.
     ///
@@ -107,6 +139,34 @@ impl<R: Runtime> ChanMgr<R> {
         Ok(chan)
     }
 
+    /// Build a new channel to the bridge at `addr`.
+    ///
+    /// We don't need to know the bridge's Ed25519 identity in advance: once
+    /// the channel is open, its `peer_ed25519_id()` tells us what it is.
+    /// If `rsa_id` is provided, the bridge must have that RSA identity.
+    ///
+    /// Unlike [`ChanMgr::get_or_launch`], this function always builds a new
+    /// channel, and never gives that channel to any other caller.  It is
+    /// meant for talking to a bridge before we have its descriptor.
+    pub async fn launch_bridge_channel(
+        &self,
+        addr: &SocketAddr,
+        rsa_id: Option<&RsaIdentity>,
+    ) -> Result<Channel> {
+        self.mgr
+            .connector()
+            .build_bridge_channel(addr, rsa_id)
+            .await
+    }
+
+    /// Change how long we wait for progress in each phase of the handshake
+    /// on new channels.
+    ///
+    /// Handshakes that are already in progress keep their old timeouts.
+    pub fn set_handshake_timeouts(&self, timeouts: HandshakeTimeouts) {
+        self.mgr.connector().set_handshake_timeouts(timeouts);
+    }
+
     /// Return a stream of [`ConnStatus`] events to tell us about changes
     /// in our ability to connect to the internet.
     ///
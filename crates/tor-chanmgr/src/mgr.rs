@@ -83,6 +83,11 @@ impl<CF: ChannelFactory> AbstractChanMgr<CF> {
         }
     }
 
+    /// Return the object that this channel manager uses to build channels.
+    pub(crate) fn connector(&self) -> &CF {
+        &self.connector
+    }
+
     /// Remove every unusable entry from this channel manager.
     #[cfg(test)]
     pub(crate) fn remove_unusable_entries(&self) -> Result<()> {
@@ -0,0 +1,377 @@
+//! Support for connecting to bridges via pluggable transports.
+//!
+//! A pluggable transport ("PT") disguises the traffic between a client
+//! and a bridge.  When a bridge line names a transport, we must never
+//! connect to the bridge's address directly: instead, we ask the
+//! [`TransportImplHelper`] registered for that transport to make the
+//! connection for us, and then run TLS over whatever stream it returns.
+//!
+//! The usual helper is a [`ManagedTransport`], which launches an external
+//! PT binary and talks to it over SOCKS, as described in `pt-spec.txt`.
+
+mod managed;
+
+pub use managed::{ManagedTransport, PtLaunchInfo};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Error, Result};
+
+/// The address of a bridge that we reach through a pluggable transport,
+/// along with the transport's name and its per-bridge parameters.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PtTarget {
+    /// The name of the transport (e.g. `obfs4`).
+    transport: String,
+    /// The address that the transport should connect to.
+    addr: SocketAddr,
+    /// Per-bridge parameters for the transport, in order.
+    params: Vec<(String, String)>,
+}
+
+impl PtTarget {
+    /// Construct a new `PtTarget`.
+    pub fn new(transport: String, addr: SocketAddr, params: Vec<(String, String)>) -> Self {
+        PtTarget {
+            transport,
+            addr,
+            params,
+        }
+    }
+
+    /// Return the name of the transport to use.
+    pub fn transport(&self) -> &str {
+        &self.transport
+    }
+
+    /// Return the address the transport should connect to.
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Return the per-bridge parameters for this transport.
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+}
+
+/// An object that knows how to reach a bridge through some pluggable
+/// transport.
+///
+/// The stream type `S` is the same as the runtime's TCP stream type, so
+/// that we can run TLS over it exactly as we would over a direct
+/// connection.
+#[async_trait]
+pub trait TransportImplHelper<S: Send + 'static>: Send + Sync {
+    /// Open a connection to `target.addr()` using this transport.
+    async fn connect(&self, target: &PtTarget) -> io::Result<S>;
+}
+
+/// Registry of the transports we know about, and of which addresses must
+/// be reached through them.
+pub(crate) struct TransportRegistry<S> {
+    /// Map from transport name to the helper that implements it.
+    helpers: Mutex<HashMap<String, Arc<dyn TransportImplHelper<S>>>>,
+    /// Map from bridge address to the transport we must use to reach it.
+    routes: Mutex<HashMap<SocketAddr, PtTarget>>,
+}
+
+impl<S: Send + 'static> TransportRegistry<S> {
+    /// Construct a new empty registry.
+    pub(crate) fn new() -> Self {
+        TransportRegistry {
+            helpers: Mutex::new(HashMap::new()),
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Use `helper` for every bridge that uses the transport `name`.
+    pub(crate) fn register(&self, name: &str, helper: Arc<dyn TransportImplHelper<S>>) {
+        self.helpers
+            .lock()
+            .expect("Lock poisoned")
+            .insert(name.to_owned(), helper);
+    }
+
+    /// Make sure that connections to `target.addr()` go through
+    /// `target.transport()`.
+    pub(crate) fn add_route(&self, target: PtTarget) {
+        self.routes
+            .lock()
+            .expect("Lock poisoned")
+            .insert(target.addr, target);
+    }
+
+    /// Find out how we must connect to `addr`.
+    ///
+    /// Return `Ok(None)` if we can connect directly.  If `addr` belongs to
+    /// a bridge with a transport that we have no helper for, return an
+    /// error: we must not fall back to a direct connection.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn route_for(
+        &self,
+        addr: &SocketAddr,
+    ) -> Result<Option<(Arc<dyn TransportImplHelper<S>>, PtTarget)>> {
+        let target = match self.routes.lock()?.get(addr) {
+            Some(target) => target.clone(),
+            None => return Ok(None),
+        };
+        let helper = self
+            .helpers
+            .lock()?
+            .get(target.transport())
+            .cloned()
+            .ok_or_else(|| Error::NoSuchTransport(target.transport().to_owned()))?;
+        Ok(Some((helper, target)))
+    }
+}
+
+/// Encode `params` as SOCKS5 username and password fields, as described
+/// in section 3.5 of `pt-spec.txt`.
+///
+/// The parameters are joined into a single `k=v;k=v` string, with any
+/// `\`, `=`, or `;` escaped with a backslash.  The first 255 bytes go in the
+/// username; the rest (or a single NUL, if nothing is left) go in the
+/// password.
+fn encode_socks_args(params: &[(String, String)]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    /// Append `s` to `out`, escaping characters that are special in PT args.
+    fn escape_into(out: &mut String, s: &str) {
+        for c in s.chars() {
+            if matches!(c, '\\' | '=' | ';') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+    }
+
+    let mut joined = String::new();
+    for (k, v) in params {
+        if !joined.is_empty() {
+            joined.push(';');
+        }
+        escape_into(&mut joined, k);
+        joined.push('=');
+        escape_into(&mut joined, v);
+    }
+    let mut user = joined.into_bytes();
+    if user.len() > 255 * 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too many pluggable transport parameters to fit in SOCKS auth",
+        ));
+    }
+    let pass = if user.len() > 255 {
+        user.split_off(255)
+    } else {
+        vec![0]
+    };
+    Ok((user, pass))
+}
+
+/// Return an error for a SOCKS proxy that misbehaved.
+fn socks_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SOCKS proxy error: {}", msg))
+}
+
+/// Perform a SOCKS5 handshake on `stream`, asking the proxy to connect to
+/// `target` and passing it `params` in the authentication fields.
+async fn socks5_connect<S>(
+    stream: &mut S,
+    target: &SocketAddr,
+    params: &[(String, String)],
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let auth = if params.is_empty() {
+        None
+    } else {
+        Some(encode_socks_args(params)?)
+    };
+
+    // Negotiate an authentication method.
+    let method = if auth.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(socks_err("unacceptable authentication method"));
+    }
+
+    // Send the username and password, if we have them.
+    if let Some((user, pass)) = auth {
+        let mut msg = Vec::with_capacity(3 + user.len() + pass.len());
+        msg.push(1);
+        msg.push(user.len() as u8);
+        msg.extend_from_slice(&user);
+        msg.push(pass.len() as u8);
+        msg.extend_from_slice(&pass);
+        stream.write_all(&msg).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(socks_err("authentication rejected"));
+        }
+    }
+
+    // Ask for a connection.
+    let mut msg = vec![5, 1, 0];
+    match target {
+        SocketAddr::V4(a) => {
+            msg.push(1);
+            msg.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            msg.push(4);
+            msg.extend_from_slice(&a.ip().octets());
+        }
+    }
+    msg.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&msg).await?;
+    stream.flush().await?;
+
+    let mut header = [0_u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != 5 {
+        return Err(socks_err("bad version in reply"));
+    }
+    if header[1] != 0 {
+        return Err(socks_err(&format!(
+            "connection failed with code {}",
+            header[1]
+        )));
+    }
+    // Discard the bound address and port.
+    let addr_len = match header[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0_u8; 1];
+            stream.read_exact(&mut len).await?;
+            usize::from(len[0])
+        }
+        _ => return Err(socks_err("unrecognized address type in reply")),
+    };
+    let mut bound = vec![0_u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use tor_rtmock::io::stream_pair;
+
+    fn p(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn socks_args() {
+        let (user, pass) = encode_socks_args(&p(&[("cert", "abc"), ("iat-mode", "0")])).unwrap();
+        assert_eq!(&user[..], b"cert=abc;iat-mode=0");
+        assert_eq!(pass, vec![0]);
+
+        let (user, _) = encode_socks_args(&p(&[("k", "a;b=c\\d")])).unwrap();
+        assert_eq!(&user[..], b"k=a\\;b\\=c\\\\d");
+
+        // Long arguments spill into the password.
+        let long = "x".repeat(300);
+        let (user, pass) = encode_socks_args(&p(&[("k", &long)])).unwrap();
+        assert_eq!(user.len(), 255);
+        assert_eq!(pass.len(), 302 - 255);
+        assert!(user.starts_with(b"k=xxx"));
+
+        // Too long to fit at all.
+        let too_long = "x".repeat(600);
+        assert!(encode_socks_args(&p(&[("k", &too_long)])).is_err());
+    }
+
+    #[test]
+    fn socks_handshake() {
+        futures::executor::block_on(async {
+            let (mut client, mut proxy) = stream_pair();
+            let target: SocketAddr = "192.0.2.7:443".parse().unwrap();
+            let params = p(&[("cert", "abc")]);
+
+            let proxy_side = async {
+                let mut buf = [0_u8; 3];
+                proxy.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [5, 1, 2]);
+                proxy.write_all(&[5, 2]).await.unwrap();
+
+                let mut auth = vec![0_u8; 2 + 8 + 2];
+                proxy.read_exact(&mut auth).await.unwrap();
+                assert_eq!(&auth[..], b"\x01\x08cert=abc\x01\x00");
+                proxy.write_all(&[1, 0]).await.unwrap();
+
+                let mut req = [0_u8; 10];
+                proxy.read_exact(&mut req).await.unwrap();
+                assert_eq!(req, [5, 1, 0, 1, 192, 0, 2, 7, 1, 187]);
+                proxy
+                    .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                    .await
+                    .unwrap();
+                proxy.flush().await.unwrap();
+            };
+            let (r, ()) = futures::join!(socks5_connect(&mut client, &target, &params), proxy_side);
+            r.unwrap();
+
+            // A refused connection is an error.
+            let (mut client, mut proxy) = stream_pair();
+            let proxy_side = async {
+                let mut buf = [0_u8; 3];
+                proxy.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [5, 1, 0]);
+                proxy.write_all(&[5, 0]).await.unwrap();
+                let mut req = [0_u8; 10];
+                proxy.read_exact(&mut req).await.unwrap();
+                proxy
+                    .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                proxy.flush().await.unwrap();
+            };
+            let (r, ()) = futures::join!(socks5_connect(&mut client, &target, &[]), proxy_side);
+            assert!(r.is_err());
+        });
+    }
+
+    #[test]
+    fn routes() {
+        struct NoConnect;
+        #[async_trait]
+        impl TransportImplHelper<()> for NoConnect {
+            async fn connect(&self, _target: &PtTarget) -> io::Result<()> {
+                Err(io::Error::new(io::ErrorKind::Other, "nope"))
+            }
+        }
+
+        let reg = TransportRegistry::<()>::new();
+        let direct: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+        let bridged: SocketAddr = "192.0.2.2:9001".parse().unwrap();
+        let target = PtTarget::new("obfs4".into(), bridged, p(&[("cert", "abc")]));
+        reg.add_route(target.clone());
+
+        assert!(reg.route_for(&direct).unwrap().is_none());
+        // No helper yet: we must not connect directly.
+        assert!(matches!(
+            reg.route_for(&bridged),
+            Err(Error::NoSuchTransport(t)) if t == "obfs4"
+        ));
+
+        reg.register("obfs4", Arc::new(NoConnect));
+        let (_, found) = reg.route_for(&bridged).unwrap().unwrap();
+        assert_eq!(found, target);
+        assert!(reg.route_for(&direct).unwrap().is_none());
+    }
+}
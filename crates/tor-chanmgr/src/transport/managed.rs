@@ -0,0 +1,333 @@
+//! Launch and talk to external pluggable transport binaries.
+//!
+//! This implements the client side of the "managed proxy" protocol from
+//! `pt-spec.txt`: we launch the binary with a set of `TOR_PT_*` environment
+//! variables, read `CMETHOD` lines from its standard output to learn where
+//! its SOCKS listeners are, and then connect through those listeners.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::StreamExt;
+use tor_rtcompat::{Runtime, SleepProviderExt};
+use tracing::{debug, info, warn};
+
+use super::{socks5_connect, PtTarget, TransportImplHelper};
+
+/// How long do we give a pluggable transport to tell us its methods?
+const PT_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Information about how to launch a pluggable transport binary.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PtLaunchInfo {
+    /// Path to the binary.
+    path: PathBuf,
+    /// Command-line arguments to pass to the binary.
+    arguments: Vec<String>,
+    /// The transports that we want the binary to provide.
+    protocols: Vec<String>,
+    /// A directory where the binary can keep its state.
+    state_dir: PathBuf,
+}
+
+impl PtLaunchInfo {
+    /// Construct a new `PtLaunchInfo` to run the binary at `path` with
+    /// `arguments`, asking it for `protocols` and letting it store its
+    /// state in `state_dir`.
+    pub fn new(
+        path: PathBuf,
+        arguments: Vec<String>,
+        protocols: Vec<String>,
+        state_dir: PathBuf,
+    ) -> Self {
+        PtLaunchInfo {
+            path,
+            arguments,
+            protocols,
+            state_dir,
+        }
+    }
+
+    /// Return the transports that this binary provides.
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+}
+
+/// A message that a managed pluggable transport sent us on its standard
+/// output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum PtMessage {
+    /// The transport supports the given protocol version.
+    Version(String),
+    /// The transport offers `name` via a SOCKS5 proxy at `addr`.
+    Method {
+        /// Name of the transport.
+        name: String,
+        /// Address of the SOCKS proxy for the transport.
+        addr: SocketAddr,
+    },
+    /// The transport has finished listing its methods.
+    MethodsDone,
+    /// The transport reported a fatal problem.
+    Error(String),
+    /// A line we don't need to act on (like LOG or STATUS).
+    Other,
+}
+
+/// Parse a single line of output from a managed pluggable transport.
+fn parse_pt_line(line: &str) -> PtMessage {
+    let mut words = line.split_whitespace();
+    let keyword = words.next().unwrap_or("");
+    let rest = || {
+        line.split_once(' ')
+            .map_or("", |(_, rest)| rest)
+            .trim()
+            .to_owned()
+    };
+    match keyword {
+        "VERSION" => PtMessage::Version(rest()),
+        "CMETHODS" if words.next() == Some("DONE") => PtMessage::MethodsDone,
+        "CMETHOD" => {
+            let (name, proto, addr) = match (words.next(), words.next(), words.next()) {
+                (Some(n), Some(p), Some(a)) => (n, p, a),
+                _ => return PtMessage::Error(format!("Malformed CMETHOD line: {:?}", line)),
+            };
+            if proto != "socks5" {
+                return PtMessage::Error(format!(
+                    "Transport {} uses unsupported proxy type {}",
+                    name, proto
+                ));
+            }
+            match addr.parse() {
+                Ok(addr) => PtMessage::Method {
+                    name: name.to_owned(),
+                    addr,
+                },
+                Err(_) => PtMessage::Error(format!("Bad address in CMETHOD line: {:?}", line)),
+            }
+        }
+        "ENV-ERROR" | "VERSION-ERROR" | "CMETHOD-ERROR" | "PROXY-ERROR" => {
+            PtMessage::Error(format!("{} {}", keyword, rest()))
+        }
+        _ => PtMessage::Other,
+    }
+}
+
+/// A running pluggable transport process.
+struct RunningPt {
+    /// The child process.
+    ///
+    /// We hold its stdin open: the transport exits when it closes.
+    ///
+    /// This is only `None` once we have started dropping the process.
+    child: Option<Child>,
+    /// Map from transport name to the SOCKS proxy address for that transport.
+    methods: HashMap<String, SocketAddr>,
+}
+
+impl RunningPt {
+    /// Return true if this process is still running.
+    fn is_running(&mut self) -> bool {
+        match self.child.as_mut() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+}
+
+impl Drop for RunningPt {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            // Waiting for the process to exit blocks, and we may be on an
+            // executor thread, so we reap it from a thread of its own.
+            std::thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+    }
+}
+
+/// A [`TransportImplHelper`] that launches an external pluggable transport
+/// binary, and connects through it.
+///
+/// The binary is launched when it is first needed, and relaunched if it
+/// has exited by the time we need it again.
+pub struct ManagedTransport<R: Runtime> {
+    /// Runtime used to make connections to the transport's SOCKS proxy.
+    runtime: R,
+    /// How to launch the binary.
+    info: PtLaunchInfo,
+    /// The running process, if we have launched it.
+    running: Mutex<Option<RunningPt>>,
+}
+
+impl<R: Runtime> ManagedTransport<R> {
+    /// Construct a new `ManagedTransport` that will launch a binary as
+    /// described in `info`.
+    pub fn new(runtime: R, info: PtLaunchInfo) -> Self {
+        ManagedTransport {
+            runtime,
+            info,
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Return the address of the SOCKS proxy for `transport`, launching
+    /// (or relaunching) the binary if needed.
+    async fn proxy_addr(&self, transport: &str) -> io::Result<SocketAddr> {
+        let mut running = self.running.lock().await;
+        let need_launch = match running.as_mut() {
+            Some(pt) => {
+                let exited = !pt.is_running();
+                if exited {
+                    warn!(
+                        "Pluggable transport {} exited; restarting it.",
+                        self.info.path.display()
+                    );
+                }
+                exited
+            }
+            None => true,
+        };
+        if need_launch {
+            *running = None;
+            *running = Some(self.launch().await?);
+        }
+        let pt = running.as_ref().expect("No running transport after launch");
+        pt.methods.get(transport).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Pluggable transport {} does not provide {}",
+                    self.info.path.display(),
+                    transport
+                ),
+            )
+        })
+    }
+
+    /// Launch the binary, and wait for it to tell us its methods.
+    async fn launch(&self) -> io::Result<RunningPt> {
+        info!("Launching pluggable transport {}", self.info.path.display());
+        let mut child = Command::new(&self.info.path)
+            .args(&self.info.arguments)
+            .env("TOR_PT_MANAGED_TRANSPORT_VER", "1")
+            .env("TOR_PT_STATE_LOCATION", &self.info.state_dir)
+            .env("TOR_PT_CLIENT_TRANSPORTS", self.info.protocols.join(","))
+            .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "No stdout for pluggable transport")
+        })?;
+
+        // Reading from the child's stdout blocks, so we do it in a thread of
+        // its own.  We keep reading after startup (and discard what we read)
+        // so that the transport never blocks writing its log messages.
+        let (tx, mut rx) = mpsc::unbounded();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        let _ = tx.unbounded_send(line);
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut pt = RunningPt {
+            child: Some(child),
+            methods: HashMap::new(),
+        };
+        let read_methods = async {
+            while let Some(line) = rx.next().await {
+                debug!("Pluggable transport said: {}", line);
+                match parse_pt_line(&line) {
+                    PtMessage::Version(v) if v != "1" => {
+                        return Err(format!("Unsupported protocol version {}", v));
+                    }
+                    PtMessage::Method { name, addr } => {
+                        pt.methods.insert(name, addr);
+                    }
+                    PtMessage::MethodsDone => return Ok(()),
+                    PtMessage::Error(e) => warn!("Pluggable transport error: {}", e),
+                    PtMessage::Version(_) | PtMessage::Other => {}
+                }
+            }
+            Err("Exited before listing its methods".to_owned())
+        };
+        let outcome = self
+            .runtime
+            .timeout(PT_STARTUP_TIMEOUT, read_methods)
+            .await
+            .unwrap_or_else(|_| Err("Timed out while starting up".to_owned()));
+        outcome.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Pluggable transport {}: {}", self.info.path.display(), e),
+            )
+        })?;
+        Ok(pt)
+    }
+}
+
+#[async_trait]
+impl<R: Runtime> TransportImplHelper<R::TcpStream> for ManagedTransport<R> {
+    async fn connect(&self, target: &PtTarget) -> io::Result<R::TcpStream> {
+        let proxy = self.proxy_addr(target.transport()).await?;
+        let mut stream = self.runtime.connect(&proxy).await?;
+        socks5_connect(&mut stream, target.addr(), target.params()).await?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse_pt_line("VERSION 1"), PtMessage::Version("1".into()));
+        assert_eq!(
+            parse_pt_line("CMETHOD obfs4 socks5 127.0.0.1:4321"),
+            PtMessage::Method {
+                name: "obfs4".into(),
+                addr: "127.0.0.1:4321".parse().unwrap()
+            }
+        );
+        assert_eq!(parse_pt_line("CMETHODS DONE"), PtMessage::MethodsDone);
+        assert_eq!(
+            parse_pt_line("LOG SEVERITY=notice MESSAGE=\"hello\""),
+            PtMessage::Other
+        );
+        assert_eq!(
+            parse_pt_line("CMETHOD-ERROR meek no such thing"),
+            PtMessage::Error("CMETHOD-ERROR meek no such thing".into())
+        );
+        assert!(matches!(
+            parse_pt_line("CMETHOD obfs4 socks4 127.0.0.1:4321"),
+            PtMessage::Error(_)
+        ));
+        assert!(matches!(
+            parse_pt_line("CMETHOD obfs4 socks5"),
+            PtMessage::Error(_)
+        ));
+        assert!(matches!(
+            parse_pt_line("CMETHOD obfs4 socks5 localhost"),
+            PtMessage::Error(_)
+        ));
+    }
+}
@@ -89,11 +89,44 @@ pub enum TimeValidityError {
     /// The object is expired
     #[error("has been expired for {0:?}")]
     Expired(time::Duration),
+    /// The object claims to stay valid for implausibly long
+    #[error("expires {0:?} later than we allow")]
+    ExpiresTooLate(time::Duration),
     /// The object isn't timely, and we don't know why, or won't say.
     #[error("is not currently valid")]
     Unspecified,
 }
 
+/// A source of the current time, for deciding whether a [`Timebound`]
+/// object is valid.
+///
+/// Code that checks objects against an injected `TimeSource`, rather than
+/// against the system clock, can check them as of some other time: for
+/// instance, against a consensus that we trust more than our own clock, or
+/// at a fixed time in tests.
+pub trait TimeSource {
+    /// Return the current time, according to this source.
+    fn now(&self) -> time::SystemTime;
+}
+
+/// A [`TimeSource`] that uses the system clock.
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(clippy::exhaustive_structs)] // A unit struct: there's nothing to add.
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
+}
+
+/// A fixed `SystemTime` is a [`TimeSource`] whose clock has stopped.
+impl TimeSource for time::SystemTime {
+    fn now(&self) -> time::SystemTime {
+        *self
+    }
+}
+
 /// A Timebound object is one that is only valid for a given range of time.
 ///
 /// It's better to wrap things in a TimeBound than to give them an is_valid()
@@ -122,6 +155,12 @@ pub trait Timebound<T>: Sized {
         self.check_valid_at(&time::SystemTime::now())
     }
 
+    /// Unwrap this Timebound object if it is valid at the time given by
+    /// `clock`.
+    fn check_valid_with_clock(self, clock: &dyn TimeSource) -> Result<T, Self::Error> {
+        self.check_valid_at(&clock.now())
+    }
+
     /// Unwrap this object if it is valid at the provided time t.
     /// If no time is provided, check the object at the current time.
     fn check_valid_at_opt(self, t: Option<time::SystemTime>) -> Result<T, Self::Error> {
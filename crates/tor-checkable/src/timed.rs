@@ -50,6 +50,29 @@ fn unwrap_bound(b: Bound<&'_ time::SystemTime>) -> Option<time::SystemTime> {
     }
 }
 
+/// Helper: move `t` by `d` using `adjust` (which adds or subtracts), going
+/// as far as we can if the whole of `d` would overflow.
+fn saturating_adjust<F>(t: time::SystemTime, d: time::Duration, adjust: F) -> time::SystemTime
+where
+    F: Fn(&time::SystemTime, time::Duration) -> Option<time::SystemTime>,
+{
+    if let Some(t2) = adjust(&t, d) {
+        return t2;
+    }
+    // Get as close to the limit as we can.  Whenever a step overflows, we
+    // halve it: since twice that step overflowed, each size of step can
+    // only succeed once.
+    let mut t = t;
+    let mut step = d / 2;
+    while step > time::Duration::from_secs(0) {
+        match adjust(&t, step) {
+            Some(t2) => t = t2,
+            None => step /= 2,
+        }
+    }
+    t
+}
+
 impl<T> TimerangeBound<T> {
     /// Construct a new TimerangeBound object from a given object and range.
     ///
@@ -67,18 +90,39 @@ impl<T> TimerangeBound<T> {
 
     /// Adjust this time-range bound to tolerate an expiration time farther
     /// in the future.
+    ///
+    /// If the new expiration time can't be represented, we use the latest
+    /// time that we can represent instead.
     #[must_use]
     pub fn extend_tolerance(self, d: time::Duration) -> Self {
-        let end = self.end.map(|t| t + d);
+        let end = self
+            .end
+            .map(|t| saturating_adjust(t, d, time::SystemTime::checked_add));
         Self { end, ..self }
     }
     /// Adjust this time-range bound to tolerate an initial validity
     /// time farther in the past.
+    ///
+    /// If the new initial validity time can't be represented, we use the
+    /// earliest time that we can represent instead.
     #[must_use]
     pub fn extend_pre_tolerance(self, d: time::Duration) -> Self {
-        let start = self.start.map(|t| t - d);
+        let start = self
+            .start
+            .map(|t| saturating_adjust(t, d, time::SystemTime::checked_sub));
         Self { start, ..self }
     }
+    /// Adjust this time-range bound to tolerate a clock that is up to `pre`
+    /// behind, or up to `post` ahead of, the clock that set its bounds.
+    ///
+    /// This is the same as calling [`extend_pre_tolerance`](Self::extend_pre_tolerance)
+    /// with `pre` and [`extend_tolerance`](Self::extend_tolerance) with `post`.
+    /// Nothing applies a tolerance on its own: callers that want to allow
+    /// for clock skew must ask for it.
+    #[must_use]
+    pub fn extend_tolerances(self, pre: time::Duration, post: time::Duration) -> Self {
+        self.extend_pre_tolerance(pre).extend_tolerance(post)
+    }
 }
 
 impl<T> crate::Timebound<T> for TimerangeBound<T> {
@@ -108,6 +152,7 @@ impl<T> crate::Timebound<T> for TimerangeBound<T> {
 
 #[cfg(test)]
 mod test {
+    #![allow(clippy::unwrap_used)]
     use super::*;
     use crate::{TimeValidityError, Timebound};
     use std::time::{Duration, SystemTime};
@@ -150,6 +195,26 @@ mod test {
         assert_eq!(tr.start, Some(tor_v0_0_2pre13 - 5 * one_day));
         assert_eq!(tr.end, Some(tor_v0_4_4_5 + 2 * one_day));
 
+        let tr = TimerangeBound::new((), tor_v0_0_2pre13..tor_v0_4_4_5)
+            .extend_tolerances(one_day, 3 * one_day);
+        assert_eq!(tr.start, Some(tor_v0_0_2pre13 - one_day));
+        assert_eq!(tr.end, Some(tor_v0_4_4_5 + 3 * one_day));
+        assert_eq!(
+            tr.is_valid_at(&today),
+            Err(TimeValidityError::Expired(4 * one_day))
+        );
+
+        // Tolerances too large to represent go as far as they can, but the
+        // bounds stay.
+        let tr = TimerangeBound::new((), tor_v0_0_2pre13..tor_v0_4_4_5)
+            .extend_tolerances(Duration::MAX, Duration::MAX);
+        let (start, end) = (tr.start.unwrap(), tr.end.unwrap());
+        assert!(start < SystemTime::UNIX_EPOCH - 1000 * 365 * one_day);
+        assert!(end > SystemTime::UNIX_EPOCH + 1000 * 365 * one_day);
+        assert!(start.checked_sub(one_day).is_none());
+        assert!(end.checked_add(one_day).is_none());
+        assert!(tr.is_valid_at(&today).is_ok());
+
         let tr = TimerangeBound::new((), tor_v0_4_4_5..);
         assert_eq!(tr.start, Some(tor_v0_4_4_5));
         assert_eq!(tr.end, None);
@@ -180,4 +245,29 @@ mod test {
         let tr = TimerangeBound::new("hello world", ..za);
         assert!(tr.check_valid_now().is_err());
     }
+
+    #[test]
+    fn test_clocks() {
+        use crate::{SystemClock, TimeSource};
+        let one_day = Duration::new(86400, 0);
+        let cz_sk = SystemTime::UNIX_EPOCH + one_day * 8401;
+        let eu = SystemTime::UNIX_EPOCH + one_day * 8705;
+        let za = SystemTime::UNIX_EPOCH + one_day * 8882;
+
+        // A stopped clock always gives the same answer.
+        let clock: &dyn TimeSource = &eu;
+        assert_eq!(clock.now(), eu);
+        let tr = TimerangeBound::new("Hello world", cz_sk..za);
+        assert_eq!(tr.check_valid_with_clock(clock), Ok("Hello world"));
+        let tr = TimerangeBound::new("Hello world", cz_sk..eu - one_day);
+        assert_eq!(
+            tr.check_valid_with_clock(clock),
+            Err(TimeValidityError::Expired(one_day))
+        );
+
+        // The system clock agrees with check_valid_now().
+        assert!(SystemClock.now() >= za);
+        let tr = TimerangeBound::new("hello world", ..za);
+        assert!(tr.check_valid_with_clock(&SystemClock).is_err());
+    }
 }
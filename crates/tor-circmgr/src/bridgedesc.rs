@@ -0,0 +1,202 @@
+//! Code to fetch router descriptors from our bridges.
+//!
+//! Bridges usually aren't listed in the consensus, so before we can build
+//! a multi-hop circuit through one, we need to ask the bridge itself for
+//! its router descriptor.  We do this over a BEGIN_DIR stream on a one-hop
+//! circuit, made with CREATE_FAST, since until we have the descriptor we
+//! don't know the bridge's onion key.
+
+use crate::{Error, Result};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::time::SystemTime;
+use tor_checkable::{SelfSigned, Timebound};
+use tor_guardmgr::bridge::BridgeConfig;
+use tor_linkspec::OwnedCircTarget;
+use tor_netdoc::doc::routerdesc::RouterDesc;
+use tor_proto::circuit::ClientCirc;
+
+/// The request that asks a relay for its own router descriptor.
+const REQUEST: &[u8] = b"GET /tor/server/authority HTTP/1.0\r\n\r\n";
+
+/// The largest response that we'll accept from a bridge.
+///
+/// (Router descriptors are typically a few kilobytes long.)
+const MAX_RESPONSE_LEN: usize = 64 * 1024;
+
+/// Ask `bridge` for its router descriptor over `circ`, a one-hop circuit to
+/// that bridge, and return a circuit target for the bridge.
+///
+/// We reject the descriptor unless it is well-signed, valid at `now`, and
+/// has the identity given in the bridge line (if any).
+pub(crate) async fn fetch_bridge_desc(
+    circ: &ClientCirc,
+    bridge: &BridgeConfig,
+    now: SystemTime,
+) -> Result<OwnedCircTarget> {
+    let stream = circ.begin_dir_stream().await?;
+    fetch_over_stream(stream, bridge, now).await
+}
+
+/// Helper for `fetch_bridge_desc`: send our request on `stream`, and check
+/// the descriptor that comes back.
+async fn fetch_over_stream<S>(
+    mut stream: S,
+    bridge: &BridgeConfig,
+    now: SystemTime,
+) -> Result<OwnedCircTarget>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let bad = |problem: String| Error::BridgeDescriptor {
+        bridge: bridge.clone(),
+        problem,
+    };
+
+    stream
+        .write_all(REQUEST)
+        .await
+        .map_err(|e| bad(format!("Unable to send request: {}", e)))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| bad(format!("Unable to send request: {}", e)))?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_LEN as u64 + 1)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| bad(format!("Unable to read response: {}", e)))?;
+    if response.len() > MAX_RESPONSE_LEN {
+        return Err(bad("Response was too long".into()));
+    }
+
+    let body = response_body(&response).map_err(bad)?;
+    let desc = RouterDesc::parse(body)
+        .map_err(|e| bad(format!("Unable to parse descriptor: {}", e)))?
+        .check_signature()
+        .map_err(|e| bad(format!("Bad signature on descriptor: {}", e)))?
+        .check_valid_at(&now)
+        .map_err(|e| bad(format!("Descriptor is not currently valid: {}", e)))?;
+
+    let rsa_id = desc.rsa_identity();
+    if !bridge.allows_rsa_identity(&rsa_id) {
+        return Err(bad(format!(
+            "Descriptor had unexpected identity {}",
+            rsa_id
+        )));
+    }
+
+    Ok(OwnedCircTarget::new(
+        bridge.chan_target(*desc.ed_identity(), rsa_id),
+        *desc.ntor_onion_key(),
+        desc.protocols().clone(),
+    ))
+}
+
+/// Check that `response` is a successful HTTP response, and return its body.
+fn response_body(response: &[u8]) -> std::result::Result<&str, String> {
+    let response =
+        std::str::from_utf8(response).map_err(|_| "Response was not UTF-8".to_string())?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "Truncated HTTP response".to_string())?;
+    let status = head.lines().next().unwrap_or("");
+    let mut words = status.split(' ');
+    match (words.next(), words.next()) {
+        (Some(version), Some("200")) if version.starts_with("HTTP/1.") => Ok(body),
+        _ => Err(format!("Unexpected HTTP status {:?}", status)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::join;
+    use std::time::Duration;
+    use tor_linkspec::ChanTarget;
+    use tor_rtmock::io::stream_pair;
+
+    const TESTDATA: &str = include_str!("../testdata/routerdesc1.txt");
+
+    /// Serve `response` to a single request for a descriptor, and return
+    /// the outcome of fetching that descriptor for `bridge`.
+    fn fetch(response: String, bridge: &str, now: SystemTime) -> Result<OwnedCircTarget> {
+        let bridge: BridgeConfig = bridge.parse().unwrap();
+        let (client, mut server) = stream_pair();
+        let serve = async move {
+            let mut request = vec![0; REQUEST.len()];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..], REQUEST);
+            // The client may hang up early if the response is too long.
+            let _ = server.write_all(response.as_bytes()).await;
+            let _ = server.close().await;
+        };
+        let (outcome, ()) = futures::executor::block_on(async {
+            join!(fetch_over_stream(client, &bridge, now), serve)
+        });
+        outcome
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n{}",
+            body
+        )
+    }
+
+    #[test]
+    fn fetch_desc() {
+        let now = SystemTime::UNIX_EPOCH + Duration::new(1584559096 + 3600, 0);
+        let rsa = "EB6EFB27F29AC9511A4246D7ABE1AFABFB416FF1";
+
+        for line in &[
+            "192.0.2.7:443".to_string(),
+            format!("192.0.2.7:443 {}", rsa),
+        ] {
+            let target = fetch(ok(TESTDATA), line, now).unwrap();
+            assert_eq!(target.addrs(), &["192.0.2.7:443".parse().unwrap()]);
+            assert_eq!(
+                target.rsa_identity().to_string(),
+                format!("${}", rsa.to_lowercase())
+            );
+            assert_eq!(
+                target.ed_identity().to_string(),
+                "z3PGka1FKJSLKyhTCsu0lQsSr6Rq3HRQJ4vgWodVmR4"
+            );
+        }
+    }
+
+    #[test]
+    fn fetch_bad_desc() {
+        let now = SystemTime::UNIX_EPOCH + Duration::new(1584559096 + 3600, 0);
+        let problem = |response: String, bridge: &str, now| match fetch(response, bridge, now) {
+            Err(Error::BridgeDescriptor { problem, .. }) => problem,
+            other => panic!("unexpected outcome {:?}", other.map(|_| ())),
+        };
+
+        let wrong_id = format!("192.0.2.7:443 {}", "05".repeat(20));
+        assert!(
+            problem(ok(TESTDATA), &wrong_id, now).starts_with("Descriptor had unexpected identity")
+        );
+        assert!(
+            problem(ok(TESTDATA), "192.0.2.7:443", SystemTime::UNIX_EPOCH)
+                .starts_with("Descriptor is not currently valid")
+        );
+        assert!(problem(ok("hello world"), "192.0.2.7:443", now)
+            .starts_with("Unable to parse descriptor"));
+        assert_eq!(
+            problem(
+                "HTTP/1.0 404 Not found\r\n\r\n".into(),
+                "192.0.2.7:443",
+                now
+            ),
+            "Unexpected HTTP status \"HTTP/1.0 404 Not found\""
+        );
+        assert_eq!(
+            problem(ok(&"x".repeat(MAX_RESPONSE_LEN)), "192.0.2.7:443", now),
+            "Response was too long"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 //! Facilities to build circuits directly, instead of via a circuit manager.
 
+use crate::event::{CircEvent, CircEventSender, CircEvents};
+use crate::health::{CircHealth, HealthTracker};
 use crate::path::{OwnedPath, TorPath};
 use crate::timeouts::{self, Action};
 use crate::{Error, Result};
@@ -7,20 +9,27 @@ use async_trait::async_trait;
 use futures::channel::oneshot;
 use futures::task::SpawnExt;
 use futures::Future;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::sync::{
     atomic::{AtomicU32, Ordering},
-    Arc,
+    Arc, Mutex,
 };
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tor_chanmgr::ChanMgr;
+use tor_guardmgr::bridge::BridgeConfig;
 use tor_guardmgr::GuardStatus;
 use tor_linkspec::{ChanTarget, OwnedChanTarget, OwnedCircTarget};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_proto::channel::{Channel, HandshakeTimeouts};
 use tor_proto::circuit::{CircParameters, ClientCirc, PendingClientCirc};
 use tor_rtcompat::{Runtime, SleepProviderExt};
+use tracing::{debug, warn};
 
+mod guardlimit;
 mod guardstatus;
 
+use guardlimit::GuardBuildLimiter;
 pub(crate) use guardstatus::GuardStatusHandle;
 
 /// Represents an objects that can be constructed in a circuit-like way.
@@ -44,6 +53,18 @@ pub(crate) trait Buildable: Sized {
         params: &CircParameters,
     ) -> Result<Self>;
 
+    /// Launch a new one-hop circuit to a bridge, given only the bridge's
+    /// configuration.
+    ///
+    /// (As with `create_chantarget`, we can't extend the circuit to be
+    /// multihop later on.)
+    async fn create_bridge<RT: Runtime>(
+        chanmgr: &ChanMgr<RT>,
+        rt: &RT,
+        bridge: &BridgeConfig,
+        params: &CircParameters,
+    ) -> Result<Self>;
+
     /// Launch a new circuit through a given relay, given a circuit target
     /// `ct` specifying that relay.
     async fn create<RT: Runtime>(
@@ -80,6 +101,11 @@ async fn create_common<RT: Runtime, CT: ChanTarget>(
             peer: OwnedChanTarget::from_chan_target(target),
             cause,
         })?;
+    new_pending_circ(rt, &chan).await
+}
+
+/// Make a [`PendingClientCirc`] on `chan`, and start its reactor.
+async fn new_pending_circ<RT: Runtime>(rt: &RT, chan: &Channel) -> Result<PendingClientCirc> {
     let (pending_circ, reactor) = chan.new_circ().await?;
 
     rt.spawn(async {
@@ -101,6 +127,22 @@ impl Buildable for ClientCirc {
         let circ = create_common(chanmgr, rt, ct).await?;
         Ok(circ.create_firsthop_fast(params).await?)
     }
+    async fn create_bridge<RT: Runtime>(
+        chanmgr: &ChanMgr<RT>,
+        rt: &RT,
+        bridge: &BridgeConfig,
+        params: &CircParameters,
+    ) -> Result<Self> {
+        let chan = chanmgr
+            .launch_bridge_channel(bridge.addr(), bridge.rsa_identity())
+            .await
+            .map_err(|cause| Error::BridgeChannel {
+                bridge: bridge.clone(),
+                cause,
+            })?;
+        let circ = new_pending_circ(rt, &chan).await?;
+        Ok(circ.create_firsthop_fast(params).await?)
+    }
     async fn create<RT: Runtime>(
         chanmgr: &ChanMgr<RT>,
         rt: &RT,
@@ -135,6 +177,11 @@ struct Builder<R: Runtime, C: Buildable + Sync + Send + 'static> {
     chanmgr: Arc<ChanMgr<R>>,
     /// An estimator to determine the correct timeouts for circuit building.
     timeouts: timeouts::Estimator,
+    /// A limiter to keep us from building too many circuits through the
+    /// same guard at once.
+    guard_limiter: GuardBuildLimiter,
+    /// A record of how our recent multi-hop circuit builds have gone.
+    health: Mutex<HealthTracker>,
     /// We don't actually hold any clientcircs, so we need to put this
     /// type here so the compiler won't freak out.
     _phantom: std::marker::PhantomData<C>,
@@ -147,6 +194,8 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
             runtime,
             chanmgr,
             timeouts,
+            guard_limiter: GuardBuildLimiter::new(None),
+            health: Mutex::new(HealthTracker::default()),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -178,6 +227,16 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
                 n_hops_built.fetch_add(1, Ordering::SeqCst);
                 Ok(circ)
             }
+            OwnedPath::BridgeOneHop(bridge) => {
+                // Bridges take the place of guards; but we don't tell the
+                // guard manager about them.
+                guard_status.pending(GuardStatus::Failure);
+                let circ = C::create_bridge(&self.chanmgr, &self.runtime, &bridge, &params).await?;
+                self.timeouts
+                    .note_hop_completed(0, self.runtime.now() - start_time, true);
+                n_hops_built.fetch_add(1, Ordering::SeqCst);
+                Ok(circ)
+            }
             OwnedPath::Normal(p) => {
                 assert!(!p.is_empty());
                 let n_hops = p.len() as u8;
@@ -213,6 +272,23 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
         params: &CircParameters,
         guard_status: Arc<GuardStatusHandle>,
     ) -> Result<C> {
+        // If this circuit goes through a guard, wait until we're allowed to
+        // start another build through that guard.  We do this before
+        // starting the clock, since time spent waiting here tells us
+        // nothing about how long circuits take to build.
+        //
+        // (Paths to fallback directories are `ChannelOnly`, and one-hop
+        // paths to bridges are `BridgeOneHop`; neither is limited.)
+        let guard_slot = match &path {
+            OwnedPath::Normal(p) if !p.is_empty() => {
+                Some(self.guard_limiter.acquire(p[0].ed_identity()).await)
+            }
+            _ => None,
+        };
+
+        // One-hop circuits to fallback directories tell us nothing about how
+        // well we can build the circuits that user traffic needs.
+        let counts_for_health = matches!(path, OwnedPath::Normal(_));
         let action = Action::BuildCircuit { length: path.len() };
         let (timeout, abandon_timeout) = self.timeouts.timeouts(&action);
         let start_time = self.runtime.now();
@@ -232,8 +308,24 @@ impl<R: Runtime, C: Buildable + Sync + Send + 'static> Builder<R, C> {
             Arc::clone(&hops_built),
             guard_status,
         );
+        // Hold on to our guard slot until the build finishes, or until it
+        // is abandoned.
+        let circuit_future = async move {
+            let _guard_slot = guard_slot;
+            circuit_future.await
+        };
 
-        match double_timeout(&self.runtime, circuit_future, timeout, abandon_timeout).await {
+        let outcome = double_timeout(&self.runtime, circuit_future, timeout, abandon_timeout).await;
+        if counts_for_health {
+            let mut health = self.health.lock().expect("health lock poisoned");
+            let now = self.runtime.now();
+            match &outcome {
+                Ok(_) => health.note_success(now, now - start_time),
+                Err(_) => health.note_failure(now),
+            }
+        }
+
+        match outcome {
             Ok(circuit) => Ok(circuit),
             Err(Error::CircTimeout) => {
                 let n_built = hops_built.load(Ordering::SeqCst);
@@ -263,6 +355,13 @@ pub struct CircuitBuilder<R: Runtime> {
     /// Guard manager to tell us which guards nodes to use for the circuits
     /// we build.
     guardmgr: tor_guardmgr::GuardMgr<R>,
+    /// Circuit targets for the bridges whose descriptors we have fetched,
+    /// indexed by their configuration.
+    //
+    // TODO: We never refetch these descriptors, even once they expire.
+    bridge_descs: Mutex<HashMap<BridgeConfig, OwnedCircTarget>>,
+    /// Sender to tell subscribers when circuits are built and closed.
+    circ_events: CircEventSender,
 }
 
 impl<R: Runtime> CircuitBuilder<R> {
@@ -274,15 +373,22 @@ impl<R: Runtime> CircuitBuilder<R> {
         chanmgr: Arc<ChanMgr<R>>,
         path_config: crate::PathConfig,
         storage: crate::TimeoutStateHandle,
+        journal: crate::TimeoutJournalHandle,
         guardmgr: tor_guardmgr::GuardMgr<R>,
     ) -> Self {
-        let timeouts = timeouts::Estimator::from_storage(&storage);
+        let timeouts = timeouts::Estimator::from_storage(&storage, journal);
+        let builder = Builder::new(runtime, chanmgr, timeouts);
+        builder
+            .guard_limiter
+            .set_limit(path_config.max_concurrent_builds_per_guard());
 
         CircuitBuilder {
-            builder: Arc::new(Builder::new(runtime, chanmgr, timeouts)),
+            builder: Arc::new(builder),
             path_config: path_config.into(),
             storage,
             guardmgr,
+            bridge_descs: Mutex::new(HashMap::new()),
+            circ_events: CircEventSender::default(),
         }
     }
 
@@ -293,9 +399,18 @@ impl<R: Runtime> CircuitBuilder<R> {
 
     /// Replace this builder's [`PathConfig`](crate::PathConfig).
     pub(crate) fn set_path_config(&self, new_config: crate::PathConfig) {
+        self.builder
+            .guard_limiter
+            .set_limit(new_config.max_concurrent_builds_per_guard());
         self.path_config.replace(new_config);
     }
 
+    /// Change the timeouts that our channel manager uses for each phase of
+    /// the channel handshake.
+    pub(crate) fn set_handshake_timeouts(&self, timeouts: HandshakeTimeouts) {
+        self.builder.chanmgr.set_handshake_timeouts(timeouts);
+    }
+
     /// Flush state to the state manager if we own the lock.
     ///
     /// Return `Ok(true)` if we saved, and `Ok(false)` if we didn't hold the lock.
@@ -344,7 +459,28 @@ impl<R: Runtime> CircuitBuilder<R> {
         params: &CircParameters,
         guard_status: Arc<GuardStatusHandle>,
     ) -> Result<ClientCirc> {
-        self.builder.build_owned(path, params, guard_status).await
+        let circ = self.builder.build_owned(path, params, guard_status).await?;
+
+        let id = circ.unique_id();
+        self.circ_events.send(CircEvent::Built(id));
+        let closed = circ.wait_for_close();
+        let circ_events = self.circ_events.clone();
+        if let Err(e) = self.builder.runtime.spawn(async move {
+            closed.await;
+            circ_events.send(CircEvent::Closed(id));
+        }) {
+            // We can't tell anybody when this circuit closes, but the
+            // circuit itself is fine.
+            warn!("Unable to watch for close of {}: {}", id, e);
+        }
+
+        Ok(circ)
+    }
+
+    /// Return a new stream of events about the circuits this builder
+    /// constructs.
+    pub(crate) fn circ_events(&self) -> CircEvents {
+        self.circ_events.subscribe()
     }
 
     /// Try to construct a new circuit from a given path, using appropriate
@@ -358,11 +494,94 @@ impl<R: Runtime> CircuitBuilder<R> {
         self.build_owned(owned, params, Arc::new(None.into())).await
     }
 
+    /// Return circuit targets for those of our configured bridges whose
+    /// descriptors we know.
+    pub(crate) fn bridge_targets(&self) -> Vec<OwnedCircTarget> {
+        let descs = self.bridge_descs.lock().expect("bridge lock poisoned");
+        self.path_config()
+            .bridges()
+            .iter()
+            .filter_map(|b| descs.get(b).cloned())
+            .collect()
+    }
+
+    /// Try to fetch a descriptor from every configured bridge whose
+    /// descriptor we don't know yet.
+    ///
+    /// Failures are logged, but not returned: a bridge we can't reach just
+    /// won't be used for multi-hop circuits.
+    ///
+    /// Return true if we now know a descriptor for every configured bridge.
+    pub(crate) async fn learn_bridge_descs(&self, now: SystemTime) -> bool {
+        let missing: Vec<BridgeConfig> = {
+            let descs = self.bridge_descs.lock().expect("bridge lock poisoned");
+            self.path_config()
+                .bridges()
+                .iter()
+                .filter(|b| !descs.contains_key(b))
+                .cloned()
+                .collect()
+        };
+        let fetches = missing.into_iter().map(|bridge| async move {
+            let outcome = self.fetch_bridge_desc(&bridge, now).await;
+            (bridge, outcome)
+        });
+        let mut all_known = true;
+        for (bridge, outcome) in futures::future::join_all(fetches).await {
+            match outcome {
+                Ok(target) => {
+                    debug!("Learned descriptor for bridge {}", bridge);
+                    self.bridge_descs
+                        .lock()
+                        .expect("bridge lock poisoned")
+                        .insert(bridge, target);
+                }
+                Err(e) => {
+                    warn!("Unable to fetch descriptor for bridge {}: {}", bridge, e);
+                    all_known = false;
+                }
+            }
+        }
+        all_known
+    }
+
+    /// Build a one-hop circuit to `bridge`, and use it to fetch the
+    /// bridge's descriptor.
+    async fn fetch_bridge_desc(
+        &self,
+        bridge: &BridgeConfig,
+        now: SystemTime,
+    ) -> Result<OwnedCircTarget> {
+        let circ = self
+            .build_owned(
+                OwnedPath::BridgeOneHop(bridge.clone()),
+                &CircParameters::default(),
+                Arc::new(None.into()),
+            )
+            .await?;
+        crate::bridgedesc::fetch_bridge_desc(&circ, bridge, now).await
+    }
+
+    /// Return a report on how well our recent circuit builds have gone.
+    pub(crate) fn health(&self) -> CircHealth {
+        self.builder
+            .health
+            .lock()
+            .expect("health lock poisoned")
+            .health(self.builder.runtime.now())
+    }
+
     /// Return true if this builder is currently learning timeout info.
     pub(crate) fn learning_timeouts(&self) -> bool {
         self.builder.timeouts.learning_timeouts()
     }
 
+    /// Return the set of guards through which we are already building as
+    /// many circuits as our configuration allows.
+    pub(crate) fn busy_guards(&self) -> HashSet<Ed25519Identity> {
+        self.builder.guard_limiter.saturated()
+    }
+
     /// Return a reference to this builder's `GuardMgr`.
     pub(crate) fn guardmgr(&self) -> &tor_guardmgr::GuardMgr<R> {
         &self.guardmgr
@@ -422,8 +641,6 @@ mod test {
     use super::*;
     use crate::timeouts::TimeoutEstimator;
     use futures::channel::oneshot;
-    use std::sync::Mutex;
-    use tor_llcrypto::pk::ed25519::Ed25519Identity;
     use tor_rtcompat::{test_with_all_runtimes, SleepProvider};
     use tracing::trace;
 
@@ -601,6 +818,18 @@ mod test {
             };
             Ok(Mutex::new(c))
         }
+        async fn create_bridge<RT: Runtime>(
+            _: &ChanMgr<RT>,
+            _: &RT,
+            _: &BridgeConfig,
+            _: &CircParameters,
+        ) -> Result<Self> {
+            let c = FakeCirc {
+                hops: vec![],
+                onehop: true,
+            };
+            Ok(Mutex::new(c))
+        }
         async fn create<RT: Runtime>(
             _: &ChanMgr<RT>,
             rt: &RT,
@@ -679,7 +908,9 @@ mod test {
             let (rt, advance) = {
                 let mut this = self.lock().unwrap();
                 this.hist.push((true, hop, delay));
-                let _ = this.snd_success.take().unwrap().send(());
+                if let Some(snd) = this.snd_success.take() {
+                    let _ = snd.send(());
+                }
                 (this.runtime.clone(), this.on_success)
             };
             if !advance.is_zero() {
@@ -802,6 +1033,68 @@ mod test {
         });
     }
 
+    #[test]
+    fn build_limit_per_guard() {
+        test_with_all_runtimes!(|rt| async move {
+            let chanmgr = Arc::new(ChanMgr::new(rt.clone()));
+            let timeouts = Arc::new(Mutex::new(TimeoutRecorder::new(rt.clone())));
+            let builder: Builder<_, Mutex<FakeCirc>> = Builder::new(
+                rt.clone(),
+                chanmgr,
+                timeouts::Estimator::new(Arc::clone(&timeouts)),
+            );
+            builder.guard_limiter.set_limit(Some(8));
+            let builder = Arc::new(builder);
+            let params = CircParameters::default();
+
+            let guard = key_from_timeouts(Duration::from_millis(50), Duration::from_millis(0));
+            let other_guard = key_from_timeouts(Duration::from_millis(1), Duration::from_millis(0));
+            let relay = key_from_timeouts(Duration::from_millis(0), Duration::from_millis(0));
+
+            // Launch 20 builds through the same guard at once.
+            let start = rt.now();
+            let builds = (0..20).map(|_| {
+                let path = OwnedPath::Normal(vec![circ_t(guard), circ_t(relay), circ_t(relay)]);
+                builder.build_owned(path, &params, gs())
+            });
+            let builds = futures::future::join_all(builds);
+            let monitor = async {
+                rt.sleep(Duration::from_millis(10)).await;
+                let saturated = builder.guard_limiter.saturated();
+                let in_flight = builder.guard_limiter.n_in_flight(&guard);
+
+                // While the first guard is full, a build through another
+                // guard doesn't have to wait for it.
+                let path =
+                    OwnedPath::Normal(vec![circ_t(other_guard), circ_t(relay), circ_t(relay)]);
+                let spillover = builder.build_owned(path, &params, gs()).await;
+                let still_in_flight = builder.guard_limiter.n_in_flight(&guard);
+                (saturated, in_flight, spillover, still_in_flight)
+            };
+            let (outcomes, (saturated, in_flight, spillover, still_in_flight)) =
+                futures::join!(builds, monitor);
+
+            // The guard hit its limit, so the builds went through it in
+            // three rounds of (at most) 8, each taking 50 msec.  Once the
+            // queue drained, every build succeeded.
+            assert_eq!(saturated, std::iter::once(guard).collect());
+            assert_eq!(in_flight, 8);
+            let spillover = spillover.unwrap();
+            assert_eq!(spillover.lock().unwrap().hops, [other_guard, relay, relay]);
+            // The other build finished during the first round.
+            assert_eq!(still_in_flight, 8);
+            assert_eq!(builder.guard_limiter.n_in_flight(&other_guard), 0);
+            assert!(rt.now() - start >= Duration::from_millis(150));
+            assert_eq!(outcomes.len(), 20);
+            for outcome in outcomes {
+                let circ = outcome.unwrap();
+                assert_eq!(circ.lock().unwrap().hops, [guard, relay, relay]);
+            }
+            assert!(builder.guard_limiter.saturated().is_empty());
+            assert_eq!(builder.guard_limiter.n_in_flight(&guard), 0);
+        });
+    }
+
     #[test]
     fn build_huge_timeout() {
         test_with_all_runtimes!(|rt| async move {
@@ -0,0 +1,219 @@
+//! Code to limit the number of circuits that we build through a single guard
+//! at once.
+//!
+//! When we launch a burst of circuits, they will all tend to pick the same
+//! guard.  To avoid overloading that guard (and to avoid making all of our
+//! circuits wait on a single channel), we cap the number of builds that can be
+//! in flight through any one guard.  Builds over the cap wait here until a
+//! slot is released.
+
+use futures::channel::oneshot;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+/// Mutable state for a [`GuardBuildLimiter`].
+#[derive(Default)]
+struct Inner {
+    /// The number of circuit builds currently in flight through each guard.
+    in_flight: HashMap<Ed25519Identity, usize>,
+    /// For each guard, a queue of builds that are waiting for a slot.
+    waiters: HashMap<Ed25519Identity, VecDeque<oneshot::Sender<GuardBuildSlot>>>,
+}
+
+/// Tracks in-flight circuit builds per guard, and enforces a limit on them.
+pub(crate) struct GuardBuildLimiter {
+    /// The largest number of builds to allow through a single guard at once.
+    ///
+    /// Zero means "no limit".
+    limit: AtomicUsize,
+    /// Shared state, also referenced by every outstanding [`GuardBuildSlot`].
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// A permit to build a circuit through a given guard.
+///
+/// When this object is dropped, the slot is handed to the next build waiting
+/// on the same guard, if there is one.
+#[must_use = "Dropping a GuardBuildSlot releases it immediately"]
+pub(crate) struct GuardBuildSlot {
+    /// The guard that this slot is for.
+    id: Ed25519Identity,
+    /// The state that this slot is counted in.
+    inner: Arc<Mutex<Inner>>,
+    /// If false, this slot has already been released or transferred, and
+    /// must not be released again.
+    armed: bool,
+}
+
+impl GuardBuildLimiter {
+    /// Construct a new limiter that allows at most `limit` builds through
+    /// each guard, or any number of builds if `limit` is `None`.
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        GuardBuildLimiter {
+            limit: AtomicUsize::new(limit.unwrap_or(0)),
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    /// Change the limit used by this limiter.
+    ///
+    /// Builds that are already waiting keep waiting until a slot is released.
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Return the current limit, or `None` if there is no limit.
+    fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    /// Return the set of guards that are currently at or over the limit.
+    pub(crate) fn saturated(&self) -> HashSet<Ed25519Identity> {
+        let limit = match self.limit() {
+            Some(n) => n,
+            None => return HashSet::new(),
+        };
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner
+            .in_flight
+            .iter()
+            .filter(|(_, n)| **n >= limit)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// For testing: return the number of builds currently in flight through
+    /// `id`.
+    #[cfg(test)]
+    pub(crate) fn n_in_flight(&self, id: &Ed25519Identity) -> usize {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.in_flight.get(id).copied().unwrap_or(0)
+    }
+
+    /// Wait until we can start a new build through the guard `id`, and
+    /// return a slot that permits it.
+    pub(crate) async fn acquire(&self, id: &Ed25519Identity) -> GuardBuildSlot {
+        let rcv = {
+            let mut inner = self.inner.lock().expect("Poisoned lock");
+            let n = inner.in_flight.entry(*id).or_insert(0);
+            if self.limit().map(|limit| *n < limit).unwrap_or(true) {
+                *n += 1;
+                return GuardBuildSlot {
+                    id: *id,
+                    inner: Arc::clone(&self.inner),
+                    armed: true,
+                };
+            }
+            let (snd, rcv) = oneshot::channel();
+            inner.waiters.entry(*id).or_default().push_back(snd);
+            rcv
+        };
+
+        match rcv.await {
+            Ok(slot) => slot,
+            // This can only happen if every slot for this guard was dropped
+            // without being released, which shouldn't be possible.  Proceed
+            // without a slot rather than waiting forever.
+            Err(_) => GuardBuildSlot {
+                id: *id,
+                inner: Arc::clone(&self.inner),
+                armed: false,
+            },
+        }
+    }
+}
+
+impl Drop for GuardBuildSlot {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.armed = false;
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        // Hand our slot to the first waiter that is still interested.
+        if let Some(queue) = inner.waiters.get_mut(&self.id) {
+            while let Some(snd) = queue.pop_front() {
+                let slot = GuardBuildSlot {
+                    id: self.id,
+                    inner: Arc::clone(&self.inner),
+                    armed: true,
+                };
+                match snd.send(slot) {
+                    Ok(()) => return,
+                    Err(mut slot) => {
+                        // That waiter went away; don't release the slot twice.
+                        slot.armed = false;
+                    }
+                }
+            }
+            inner.waiters.remove(&self.id);
+        }
+        if let Some(n) = inner.in_flight.get_mut(&self.id) {
+            *n = n.saturating_sub(1);
+            if *n == 0 {
+                inner.in_flight.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn limit_and_transfer() {
+        futures::executor::block_on(async {
+            let lim = GuardBuildLimiter::new(Some(2));
+            let a: Ed25519Identity = [1; 32].into();
+            let b: Ed25519Identity = [2; 32].into();
+
+            let s1 = lim.acquire(&a).await;
+            assert!(lim.saturated().is_empty());
+            let s2 = lim.acquire(&a).await;
+            assert_eq!(lim.n_in_flight(&a), 2);
+            assert_eq!(lim.saturated(), std::iter::once(a).collect());
+
+            // A third build through `a` has to wait, but `b` is fine.
+            let mut waiting = Box::pin(lim.acquire(&a));
+            assert!((&mut waiting).now_or_never().is_none());
+            let s3 = lim.acquire(&b).await;
+
+            // A waiter that gives up doesn't keep its place in line.
+            let abandoned = Box::pin(lim.acquire(&a));
+            assert!(abandoned.now_or_never().is_none());
+
+            // Releasing a slot hands it to the waiter.
+            drop(s1);
+            let s4 = (&mut waiting).now_or_never().unwrap();
+            assert_eq!(lim.n_in_flight(&a), 2);
+
+            drop(s2);
+            assert_eq!(lim.n_in_flight(&a), 1);
+            drop(s4);
+            assert_eq!(lim.n_in_flight(&a), 0);
+            drop(s3);
+            assert_eq!(lim.n_in_flight(&b), 0);
+        });
+    }
+
+    #[test]
+    fn unlimited() {
+        futures::executor::block_on(async {
+            let lim = GuardBuildLimiter::new(None);
+            let a: Ed25519Identity = [1; 32].into();
+            let slots: Vec<_> = futures::future::join_all((0..20).map(|_| lim.acquire(&a))).await;
+            assert_eq!(lim.n_in_flight(&a), 20);
+            assert!(lim.saturated().is_empty());
+            drop(slots);
+            assert_eq!(lim.n_in_flight(&a), 0);
+        });
+    }
+}
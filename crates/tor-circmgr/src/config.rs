@@ -5,6 +5,8 @@
 //! Most types in this module are re-exported by `arti-client`.
 
 use tor_config::ConfigBuildError;
+use tor_guardmgr::bridge::BridgeConfig;
+use tor_proto::channel::HandshakeTimeouts;
 
 use derive_builder::Builder;
 use serde::Deserialize;
@@ -39,8 +41,47 @@ pub struct PathConfig {
     #[builder(default = "ipv6_prefix_default()")]
     #[serde(default = "ipv6_prefix_default")]
     ipv6_subnet_family_prefix: u8,
+
+    /// Set the largest number of circuits that we will try to build
+    /// through any single guard at once.
+    ///
+    /// When this many circuit builds are already in flight through our
+    /// preferred guard, new circuits spill over to the next primary guard if
+    /// there is one, and otherwise wait for a pending build to finish.
+    /// Circuits to fallback directories are not limited.  If this is zero,
+    /// there is no limit.
+    #[builder(default = "max_concurrent_builds_per_guard_default()")]
+    #[serde(default = "max_concurrent_builds_per_guard_default")]
+    max_concurrent_builds_per_guard: usize,
+
+    /// If nonzero, treat a relay's directory information as stale once it
+    /// is this much older than the consensus we're using.
+    ///
+    /// When picking middle and exit relays, we give relays with stale
+    /// information only a tenth of their usual weight, since their onion keys
+    /// are more likely to have been rotated away.  They still get picked
+    /// sometimes, and whenever no fresher relay is suitable.  This is
+    /// disabled (zero) by default, since it biases path selection away from
+    /// the usual bandwidth weights.
+    #[builder(default)]
+    #[serde(with = "humantime_serde", default)]
+    stale_relay_info_age: Duration,
+
+    /// A list of bridges to use as the first hop of every circuit.
+    ///
+    /// Each entry is a bridge line, of the form
+    /// `[transport] IP:port [fingerprint] [key=value ...]`.  If this list
+    /// is nonempty, we never build a circuit whose first hop isn't one of
+    /// these bridges.
+    #[builder(default)]
+    #[serde(default)]
+    bridges: Vec<BridgeConfig>,
 }
 
+/// The percentage of its usual weight that a relay with stale directory
+/// information keeps, when `stale_relay_info_age` is set.
+const STALE_RELAY_WEIGHT_PERCENT: u8 = 10;
+
 /// Default value for ipv4_subnet_family_prefix.
 fn ipv4_prefix_default() -> u8 {
     16
@@ -49,6 +90,10 @@ fn ipv4_prefix_default() -> u8 {
 fn ipv6_prefix_default() -> u8 {
     32
 }
+/// Default value for max_concurrent_builds_per_guard.
+fn max_concurrent_builds_per_guard_default() -> usize {
+    8
+}
 
 impl PathConfig {
     /// Return a new [`PathConfigBuilder`].
@@ -63,13 +108,54 @@ impl PathConfig {
         )
     }
 
+    /// Return the largest number of circuits that we should be building
+    /// through a single guard at once, or `None` if there is no limit.
+    pub fn max_concurrent_builds_per_guard(&self) -> Option<usize> {
+        match self.max_concurrent_builds_per_guard {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    /// Return the age after which we treat a relay's directory information
+    /// as stale, or `None` if we don't prefer fresher relays.
+    pub fn stale_relay_info_age(&self) -> Option<Duration> {
+        if self.stale_relay_info_age == Duration::default() {
+            None
+        } else {
+            Some(self.stale_relay_info_age)
+        }
+    }
+
+    /// Return the adjustments to make to relays' weights when we pick middle
+    /// and exit relays.
+    pub(crate) fn weight_adjustments(&self) -> tor_netdir::WeightAdjustments {
+        let mut adjust = tor_netdir::WeightAdjustments::new();
+        if let Some(age) = self.stale_relay_info_age() {
+            adjust.stale_relays(age, STALE_RELAY_WEIGHT_PERCENT);
+        }
+        adjust
+    }
+
+    /// Return the bridges that we must use as the first hop of every
+    /// circuit.  If this is empty, we aren't using bridges.
+    pub fn bridges(&self) -> &[BridgeConfig] {
+        &self.bridges[..]
+    }
+
     /// Return true if this configuration is at least as permissive as `other`.
     ///
     /// In other words, in other words, return true if every circuit permitted
     /// by `other` would also be permitted by this configuration.
     pub(crate) fn at_least_as_permissive_as(&self, other: &Self) -> bool {
+        // If we're using bridges, then circuits built under `other` are only
+        // okay if they went through bridges that we still have.
+        let bridges_ok = self.bridges.is_empty()
+            || (!other.bridges.is_empty()
+                && other.bridges.iter().all(|b| self.bridges.contains(b)));
         self.ipv4_subnet_family_prefix >= other.ipv4_subnet_family_prefix
             && self.ipv6_subnet_family_prefix >= other.ipv6_subnet_family_prefix
+            && bridges_ok
     }
 }
 
@@ -86,7 +172,10 @@ impl From<PathConfig> for PathConfigBuilder {
         let mut builder = PathConfigBuilder::default();
         builder
             .ipv4_subnet_family_prefix(cfg.ipv4_subnet_family_prefix)
-            .ipv6_subnet_family_prefix(cfg.ipv6_subnet_family_prefix);
+            .ipv6_subnet_family_prefix(cfg.ipv6_subnet_family_prefix)
+            .max_concurrent_builds_per_guard(cfg.max_concurrent_builds_per_guard)
+            .stale_relay_info_age(cfg.stale_relay_info_age)
+            .bridges(cfg.bridges);
         builder
     }
 }
@@ -177,6 +266,24 @@ pub struct CircuitTiming {
     #[builder(default = "default_request_loyalty()")]
     #[serde(with = "humantime_serde", default = "default_request_loyalty")]
     pub(crate) request_loyalty: Duration,
+
+    /// When opening a channel to a relay, give up if the relay sends us
+    /// nothing for this long while we wait for its VERSIONS cell.
+    #[builder(default = "default_chan_handshake_timeout()")]
+    #[serde(with = "humantime_serde", default = "default_chan_handshake_timeout")]
+    pub(crate) chan_versions_timeout: Duration,
+
+    /// When opening a channel to a relay, give up if the relay sends us
+    /// nothing for this long while we wait for its CERTS cell.
+    #[builder(default = "default_chan_handshake_timeout()")]
+    #[serde(with = "humantime_serde", default = "default_chan_handshake_timeout")]
+    pub(crate) chan_certs_timeout: Duration,
+
+    /// When opening a channel to a relay, give up if the relay sends us
+    /// nothing for this long while we wait for its NETINFO cell.
+    #[builder(default = "default_chan_handshake_timeout()")]
+    #[serde(with = "humantime_serde", default = "default_chan_handshake_timeout")]
+    pub(crate) chan_netinfo_timeout: Duration,
 }
 
 /// Return default threshold
@@ -219,6 +326,11 @@ fn default_request_loyalty() -> Duration {
     Duration::from_millis(50)
 }
 
+/// Return the default timeout for each phase of a channel handshake.
+fn default_chan_handshake_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
 // NOTE: it seems that `unwrap` may be safe because of builder defaults
 // check `derive_builder` documentation for details
 // https://docs.rs/derive_builder/0.10.2/derive_builder/#default-values
@@ -234,6 +346,15 @@ impl CircuitTiming {
     pub fn builder() -> CircuitTimingBuilder {
         CircuitTimingBuilder::default()
     }
+
+    /// Return the timeouts to use for each phase of a channel handshake.
+    pub(crate) fn handshake_timeouts(&self) -> HandshakeTimeouts {
+        HandshakeTimeouts::new(
+            self.chan_versions_timeout,
+            self.chan_certs_timeout,
+            self.chan_netinfo_timeout,
+        )
+    }
 }
 
 impl From<CircuitTiming> for CircuitTimingBuilder {
@@ -243,7 +364,10 @@ impl From<CircuitTiming> for CircuitTimingBuilder {
             .max_dirtiness(cfg.max_dirtiness)
             .request_timeout(cfg.request_timeout)
             .request_max_retries(cfg.request_max_retries)
-            .request_loyalty(cfg.request_loyalty);
+            .request_loyalty(cfg.request_loyalty)
+            .chan_versions_timeout(cfg.chan_versions_timeout)
+            .chan_certs_timeout(cfg.chan_certs_timeout)
+            .chan_netinfo_timeout(cfg.chan_netinfo_timeout);
         builder
     }
 }
@@ -335,5 +459,49 @@ mod test {
         assert!(!pc1.at_least_as_permissive_as(&pc2));
         assert!(!pc1.at_least_as_permissive_as(&pc3));
         assert!(!pc3.at_least_as_permissive_as(&pc2));
+
+        // The per-guard build limit doesn't change which circuits we
+        // can use.
+        let pc4 = PathConfig::builder()
+            .max_concurrent_builds_per_guard(2)
+            .build()
+            .unwrap();
+        assert_eq!(pc1.max_concurrent_builds_per_guard(), Some(8));
+        assert_eq!(pc4.max_concurrent_builds_per_guard(), Some(2));
+        assert!(pc4.at_least_as_permissive_as(&pc1));
+        assert!(pc1.at_least_as_permissive_as(&pc4));
+
+        let pc5 = PathConfig::builder()
+            .max_concurrent_builds_per_guard(0)
+            .build()
+            .unwrap();
+        assert_eq!(pc5.max_concurrent_builds_per_guard(), None);
+    }
+
+    #[test]
+    fn path_config_bridges() {
+        let b1: BridgeConfig = "192.0.2.1:443".parse().unwrap();
+        let b2: BridgeConfig = "192.0.2.2:443".parse().unwrap();
+        let no_bridges = PathConfig::default();
+        let one = PathConfig::builder()
+            .bridges(vec![b1.clone()])
+            .build()
+            .unwrap();
+        let two = PathConfig::builder().bridges(vec![b1, b2]).build().unwrap();
+        assert!(no_bridges.bridges().is_empty());
+        assert_eq!(two.bridges().len(), 2);
+
+        // Dropping bridges, or adding some, is always fine.
+        assert!(no_bridges.at_least_as_permissive_as(&two));
+        assert!(two.at_least_as_permissive_as(&one));
+        // But circuits that didn't use bridges, or used bridges we've
+        // removed, are no longer okay.
+        assert!(!one.at_least_as_permissive_as(&no_bridges));
+        assert!(!one.at_least_as_permissive_as(&two));
+
+        let pc: PathConfig =
+            toml::from_str(r#"bridges = ["Bridge 192.0.2.1:443", "192.0.2.2:443"]"#).unwrap();
+        assert_eq!(pc, two);
+        assert!(toml::from_str::<PathConfig>(r#"bridges = ["192.0.2.1"]"#).is_err());
     }
 }
@@ -7,6 +7,7 @@ use retry_error::RetryError;
 use thiserror::Error;
 
 use tor_error::{Bug, ErrorKind, HasKind};
+use tor_guardmgr::bridge::BridgeConfig;
 use tor_linkspec::OwnedChanTarget;
 
 /// An error returned while looking up or building a circuit
@@ -75,10 +76,36 @@ pub enum Error {
         cause: tor_chanmgr::Error,
     },
 
+    /// Problem with a channel to one of our bridges.
+    #[error("Problem with channel to bridge {bridge}")]
+    BridgeChannel {
+        /// Which bridge we were trying to connect to
+        bridge: BridgeConfig,
+
+        /// What went wrong
+        #[source]
+        cause: tor_chanmgr::Error,
+    },
+
+    /// One of our bridges didn't give us a usable descriptor.
+    #[error("Unable to get descriptor for bridge {bridge}: {problem}")]
+    BridgeDescriptor {
+        /// Which bridge we were asking for its descriptor
+        bridge: BridgeConfig,
+
+        /// What went wrong
+        problem: String,
+    },
+
     /// Protocol issue while building a circuit.
     #[error("Problem building a circuit: {0}")]
     Protocol(#[from] tor_proto::Error),
 
+    /// We tried to use a circuit for something it must never be used for,
+    /// such as carrying user streams on a one-hop directory circuit.
+    #[error("Circuit not allowed for this usage: {0}")]
+    UsageNotAllowed(String),
+
     /// We have an expired consensus
     #[error("Consensus is expired")]
     ExpiredConsensus,
@@ -130,6 +157,8 @@ impl HasKind for Error {
         use ErrorKind as EK;
         match self {
             E::Channel { cause, .. } => cause.kind(),
+            E::BridgeChannel { cause, .. } => cause.kind(),
+            E::BridgeDescriptor { .. } => EK::TorProtocolViolation,
             E::Bug(e) => e.kind(),
             E::NoPath(_) => EK::NoPath,
             E::NoExit(_) => EK::NoExit,
@@ -148,6 +177,7 @@ impl HasKind for Error {
             E::GuardMgr(e) => e.kind(),
             E::Guard(_) => EK::NoPath,
             E::ExpiredConsensus => EK::DirectoryExpired,
+            E::UsageNotAllowed(_) => EK::BadApiUsage,
             E::Spawn { cause, .. } => cause.kind(),
         }
     }
@@ -179,8 +209,11 @@ impl Error {
             E::Guard(_) => 40,
             E::RequestFailed(_) => 40,
             E::Channel { .. } => 40,
+            E::BridgeChannel { .. } => 40,
+            E::BridgeDescriptor { .. } => 40,
             E::Protocol(_) => 45,
             E::ExpiredConsensus => 50,
+            E::UsageNotAllowed(_) => 90,
             E::Spawn { .. } => 90,
             E::State(_) => 90,
             E::Bug(_) => 100,
@@ -0,0 +1,369 @@
+//! Code for exporting events from the circuit manager.
+
+use crate::HealthStatus;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use postage::watch;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tor_proto::circuit::UniqId;
+
+/// The status of our ability to build circuits.
+#[derive(Default, Debug, Clone)]
+pub struct CircStatus {
+    /// Have we ever successfully built (or been handed) a multi-hop circuit?
+    ///
+    /// One-hop directory circuits don't count here: they tell us nothing
+    /// about whether we can build the circuits that user traffic needs.
+    built_first_circuit: bool,
+    /// How well have our recent circuit builds gone?
+    ///
+    /// We don't report this until we've built our first circuit.
+    health: Option<HealthStatus>,
+}
+
+impl CircStatus {
+    /// Return true if this status is equal to `other`.
+    ///
+    /// (As with `ConnStatus` in `tor-chanmgr`, we don't want to expose a
+    /// PartialEq implementation for this struct.)
+    fn eq(&self, other: &CircStatus) -> bool {
+        self.built_first_circuit == other.built_first_circuit && self.health == other.health
+    }
+
+    /// Return true if this status indicates that we have been able to build
+    /// a circuit for user traffic.
+    pub fn usable(&self) -> bool {
+        self.built_first_circuit
+    }
+
+    /// Return how well our recent circuit builds have gone, if we've
+    /// finished bootstrapping.
+    ///
+    /// A client can use this to notice that, although it was once able to
+    /// build circuits, most of its attempts are now failing.
+    pub fn health(&self) -> Option<HealthStatus> {
+        self.health
+    }
+
+    /// Return a float representing "how bootstrapped" we are with respect to
+    /// building circuits, where 0 is "not at all" and 1 is "successful".
+    ///
+    /// Callers _should not_ depend on the specific meaning of any particular
+    /// fraction; we may change these fractions in the future.
+    pub fn frac(&self) -> f32 {
+        if self.built_first_circuit {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for CircStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.built_first_circuit {
+            match self.health {
+                Some(h) if h != HealthStatus::Healthy => {
+                    write!(f, "able to build circuits, but circuit health is {}", h)
+                }
+                _ => write!(f, "able to build circuits"),
+            }
+        } else {
+            write!(f, "building our first circuit")
+        }
+    }
+}
+
+/// A stream of [`CircStatus`] events describing changes in our ability to
+/// build circuits.
+///
+/// This stream is lossy; a reader might not see some events on the stream, if
+/// they are produced faster than the reader can consume.  In that case, the
+/// reader will see more recent updates, and miss older ones.  A stream
+/// returned by `CircMgr::bootstrap_events` always yields the most recent
+/// status first.
+#[derive(Clone)]
+pub struct CircStatusEvents {
+    /// The receiver that implements this stream.
+    ///
+    /// (We wrap it in a new type here so that we can replace the implementation
+    /// later on if we need to.)
+    inner: watch::Receiver<CircStatus>,
+}
+
+impl fmt::Debug for CircStatusEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircStatusEvents").finish_non_exhaustive()
+    }
+}
+
+impl Stream for CircStatusEvents {
+    type Item = CircStatus;
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Object that tracks a `CircMgr`'s status, and sends information about
+/// changes over an asynchronous channel.
+///
+/// Sending never blocks: if nobody is listening, the new status simply
+/// replaces the old one.
+pub(crate) struct CircMgrEventSender {
+    /// The last CircStatus that we sent over the channel.
+    last_status: CircStatus,
+    /// The channel that we use for sending CircStatus information.
+    sender: watch::Sender<CircStatus>,
+}
+
+impl CircMgrEventSender {
+    /// If `status` differs from what we last sent, tell any listeners.
+    fn push(&mut self, status: CircStatus) {
+        if !status.eq(&self.last_status) {
+            self.last_status = status.clone();
+            let mut b = self.sender.borrow_mut();
+            *b = status;
+        }
+    }
+
+    /// Note that we've successfully obtained a multi-hop circuit.
+    pub(crate) fn record_circ_built(&mut self) {
+        self.push(CircStatus {
+            built_first_circuit: true,
+            health: self.last_status.health.or(Some(HealthStatus::Healthy)),
+        });
+    }
+
+    /// Note that our circuit health status is now `health`.
+    ///
+    /// This has no effect until we've built our first circuit.
+    pub(crate) fn record_health(&mut self, health: HealthStatus) {
+        if self.last_status.built_first_circuit {
+            self.push(CircStatus {
+                built_first_circuit: true,
+                health: Some(health),
+            });
+        }
+    }
+}
+
+/// Create a new channel for sending circuit status events to other crates.
+pub(crate) fn channel() -> (CircMgrEventSender, CircStatusEvents) {
+    let (sender, receiver) = watch::channel();
+    let receiver = CircStatusEvents { inner: receiver };
+    let sender = CircMgrEventSender {
+        last_status: CircStatus::default(),
+        sender,
+    };
+    (sender, receiver)
+}
+
+/// An event describing a change in a single circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CircEvent {
+    /// We finished building the circuit with this identifier.
+    Built(UniqId),
+    /// A circuit that we built has closed.
+    Closed(UniqId),
+}
+
+/// How many [`CircEvent`]s can wait for each reader before we start
+/// dropping new ones.
+const CIRC_EVENT_BUFFER: usize = 64;
+
+/// A stream of [`CircEvent`]s.
+///
+/// Unlike [`CircStatusEvents`], this stream yields every event in order, so
+/// long as the reader keeps up.  If the reader falls more than a few dozen
+/// events behind, new events are dropped until it catches up.
+pub struct CircEvents {
+    /// The receiver that implements this stream.
+    pub(crate) inner: mpsc::Receiver<CircEvent>,
+}
+
+impl fmt::Debug for CircEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircEvents").finish_non_exhaustive()
+    }
+}
+
+impl Stream for CircEvents {
+    type Item = CircEvent;
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Object that sends events (by default, [`CircEvent`]s) to every receiver
+/// that has subscribed to it.
+///
+/// Sending never blocks: if a reader's buffer is full, that reader misses
+/// the event.
+#[derive(Clone)]
+pub(crate) struct CircEventSender<E = CircEvent> {
+    /// A sender for each subscribed receiver.
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<E>>>>,
+}
+
+impl<E> Default for CircEventSender<E> {
+    fn default() -> Self {
+        CircEventSender {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl CircEventSender<CircEvent> {
+    /// Return a new stream that will receive every event we send from now
+    /// on.
+    pub(crate) fn subscribe(&self) -> CircEvents {
+        CircEvents {
+            inner: self.subscribe_raw(),
+        }
+    }
+}
+
+impl<E: Clone> CircEventSender<E> {
+    /// Return a new receiver for every event we send from now on.
+    fn subscribe_raw(&self) -> mpsc::Receiver<E> {
+        let (sender, receiver) = mpsc::channel(CIRC_EVENT_BUFFER);
+        self.subscribers
+            .lock()
+            .expect("circ event lock poisoned")
+            .push(sender);
+        receiver
+    }
+
+    /// Send `event` to every subscriber that has room for it.
+    pub(crate) fn send(&self, event: E) {
+        let mut subscribers = self.subscribers.lock().expect("circ event lock poisoned");
+        for s in subscribers.iter_mut() {
+            // A full buffer just means that this reader misses the event.
+            let _ = s.try_send(event.clone());
+        }
+        // Forget about any streams that have been dropped.
+        subscribers.retain(|s| !s.is_closed());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn status_basics() {
+        let s1 = CircStatus::default();
+        assert_eq!(s1.to_string(), "building our first circuit");
+        assert!(!s1.usable());
+        assert!(s1.frac().abs() < f32::EPSILON);
+        assert!(s1.eq(&s1));
+
+        let s2 = CircStatus {
+            built_first_circuit: true,
+            health: Some(HealthStatus::Healthy),
+        };
+        assert_eq!(s2.to_string(), "able to build circuits");
+        assert!(s2.usable());
+        assert!((s2.frac() - 1.0).abs() < f32::EPSILON);
+        assert!(!s2.eq(&s1));
+
+        let s3 = CircStatus {
+            built_first_circuit: true,
+            health: Some(HealthStatus::Degraded),
+        };
+        assert_eq!(
+            s3.to_string(),
+            "able to build circuits, but circuit health is degraded"
+        );
+        assert!(s3.usable());
+        assert!(!s3.eq(&s2));
+    }
+
+    #[test]
+    fn health_after_bootstrap() {
+        let (mut sender, receiver) = channel();
+        let mut rcv = receiver.clone();
+        let s = futures::executor::block_on(rcv.next()).unwrap();
+        assert_eq!(s.health(), None);
+
+        // Health isn't reported before bootstrap.
+        sender.record_health(HealthStatus::Broken);
+        assert_eq!(sender.last_status.health(), None);
+
+        sender.record_circ_built();
+        let s = futures::executor::block_on(rcv.next()).unwrap();
+        assert_eq!(s.health(), Some(HealthStatus::Healthy));
+
+        sender.record_health(HealthStatus::Degraded);
+        let s = futures::executor::block_on(rcv.next()).unwrap();
+        assert_eq!(s.health(), Some(HealthStatus::Degraded));
+
+        // Building another circuit doesn't make us forget our health.
+        sender.record_circ_built();
+        assert_eq!(sender.last_status.health(), Some(HealthStatus::Degraded));
+    }
+
+    #[test]
+    fn sender_and_late_receivers() {
+        let (mut sender, receiver) = channel();
+
+        let mut early = receiver.clone();
+        let s = futures::executor::block_on(early.next()).unwrap();
+        assert!(!s.usable());
+
+        // Nobody has to be reading for us to send.
+        sender.record_circ_built();
+        sender.record_circ_built();
+
+        let s = futures::executor::block_on(early.next()).unwrap();
+        assert!(s.usable());
+
+        // A subscriber that shows up later still sees the latest status.
+        let mut late = receiver.clone();
+        let s = futures::executor::block_on(late.next()).unwrap();
+        assert!(s.usable());
+    }
+
+    #[test]
+    fn circ_events() {
+        use futures::executor::block_on;
+
+        // We can't make a UniqId here, so we test with plain numbers.
+        let sender: CircEventSender<u32> = CircEventSender::default();
+        // Nobody has to be listening for us to send.
+        sender.send(1);
+
+        let mut a = sender.subscribe_raw();
+        let b = sender.subscribe_raw();
+        sender.send(2);
+        sender.send(3);
+        assert_eq!(block_on(a.next()), Some(2));
+        assert_eq!(block_on(a.next()), Some(3));
+
+        // Dropped receivers get forgotten.
+        drop(b);
+        sender.send(4);
+        assert_eq!(sender.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(block_on(a.next()), Some(4));
+
+        // A reader that falls behind misses events, but sending doesn't block.
+        for n in 0..CIRC_EVENT_BUFFER * 2 {
+            sender.send(n as u32);
+        }
+        drop(sender);
+        let got: Vec<u32> = block_on(a.collect());
+        assert!(got.len() >= CIRC_EVENT_BUFFER);
+        assert!(got.len() < CIRC_EVENT_BUFFER * 2);
+        // The ones we got are the oldest ones, in order.
+        assert!(got.iter().enumerate().all(|(i, n)| i as u32 == *n));
+    }
+}
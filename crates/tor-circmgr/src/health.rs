@@ -0,0 +1,346 @@
+//! Code to keep track of how well we're doing at building circuits.
+//!
+//! We keep a rolling window of recent circuit build outcomes, and use it to
+//! compute a single health signal: what fraction of our recent builds
+//! succeeded, and how long the successful ones took.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How far back do we look when computing our health?
+const HEALTH_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How many outcomes do we need in our window before we'll change our
+/// status?
+///
+/// With fewer outcomes than this, a single failure could look like a
+/// disaster.
+const MIN_OUTCOMES: usize = 5;
+
+/// The largest number of outcomes that we'll remember at once.
+///
+/// (This keeps us from using unbounded memory if we build a lot of
+/// circuits quickly.)
+const MAX_OUTCOMES: usize = 1000;
+
+/// If our success rate is at least this high, we're healthy.
+const HEALTHY_THRESHOLD: f64 = 0.8;
+
+/// If our success rate is lower than this, we're broken.
+const BROKEN_THRESHOLD: f64 = 0.3;
+
+/// How far above a threshold our success rate needs to be before we call
+/// ourselves healthier than we are now.
+///
+/// Getting _less_ healthy doesn't require any margin: we'd rather report a
+/// problem promptly, and then be slow to say that it has gone away, than
+/// flap back and forth whenever the success rate hovers near a threshold.
+const HYSTERESIS: f64 = 0.1;
+
+/// A coarse summary of how well we're doing at building circuits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum HealthStatus {
+    /// Most of our recent circuit builds have failed.
+    Broken,
+    /// A noticeable fraction of our recent circuit builds have failed.
+    Degraded,
+    /// The great majority of our recent circuit builds have succeeded.
+    Healthy,
+}
+
+impl HealthStatus {
+    /// Return the status that corresponds to a success rate of `frac`,
+    /// ignoring hysteresis.
+    fn from_frac(frac: f64) -> Self {
+        if frac >= HEALTHY_THRESHOLD {
+            HealthStatus::Healthy
+        } else if frac >= BROKEN_THRESHOLD {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Broken
+        }
+    }
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Broken => write!(f, "broken"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Healthy => write!(f, "healthy"),
+        }
+    }
+}
+
+/// A report on how well we've been doing at building circuits recently.
+///
+/// Returned by [`CircMgr::health`](crate::CircMgr::health).
+#[derive(Debug, Clone)]
+pub struct CircHealth {
+    /// Our current status.
+    status: HealthStatus,
+    /// How many circuit builds have finished within our window?
+    n_outcomes: usize,
+    /// What fraction of those builds succeeded, if there were any?
+    success_frac: Option<f64>,
+    /// What was the median time taken by a successful build, if there were
+    /// any?
+    median_build_time: Option<Duration>,
+}
+
+impl CircHealth {
+    /// Return our current health status.
+    pub fn status(&self) -> HealthStatus {
+        self.status
+    }
+
+    /// Return the number of circuit builds that have finished recently.
+    pub fn n_outcomes(&self) -> usize {
+        self.n_outcomes
+    }
+
+    /// Return the fraction of recent circuit builds that succeeded, or None
+    /// if there have been no recent builds.
+    pub fn success_frac(&self) -> Option<f64> {
+        self.success_frac
+    }
+
+    /// Return the median time taken by a recent successful circuit build,
+    /// or None if there have been no recent successful builds.
+    pub fn median_build_time(&self) -> Option<Duration> {
+        self.median_build_time
+    }
+}
+
+/// The outcome of a single circuit build attempt.
+#[derive(Debug, Clone)]
+struct Outcome {
+    /// When did the attempt finish?
+    when: Instant,
+    /// How long did the attempt take, if it succeeded?
+    ///
+    /// (None if it failed.)
+    build_time: Option<Duration>,
+}
+
+/// A rolling aggregator of circuit build outcomes.
+#[derive(Debug, Clone)]
+pub(crate) struct HealthTracker {
+    /// Recent outcomes, in the order they finished.
+    outcomes: VecDeque<Outcome>,
+    /// Our status as of the last time we computed it.
+    status: HealthStatus,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        HealthTracker {
+            outcomes: VecDeque::new(),
+            status: HealthStatus::Healthy,
+        }
+    }
+}
+
+impl HealthTracker {
+    /// Record that a circuit build succeeded at `now`, after `build_time`.
+    pub(crate) fn note_success(&mut self, now: Instant, build_time: Duration) {
+        self.note_outcome(Outcome {
+            when: now,
+            build_time: Some(build_time),
+        });
+    }
+
+    /// Record that a circuit build failed at `now`.
+    pub(crate) fn note_failure(&mut self, now: Instant) {
+        self.note_outcome(Outcome {
+            when: now,
+            build_time: None,
+        });
+    }
+
+    /// Helper: remember `outcome`, and update our status.
+    fn note_outcome(&mut self, outcome: Outcome) {
+        let now = outcome.when;
+        if self.outcomes.len() >= MAX_OUTCOMES {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(outcome);
+        self.expire(now);
+        self.update_status();
+    }
+
+    /// Forget every outcome that is too old to be in our window as of `now`.
+    fn expire(&mut self, now: Instant) {
+        while let Some(oldest) = self.outcomes.front() {
+            if now.saturating_duration_since(oldest.when) > HEALTH_WINDOW {
+                self.outcomes.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Return the fraction of outcomes in our window that were successes, if
+    /// we have any outcomes.
+    fn success_frac(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        let n_success = self
+            .outcomes
+            .iter()
+            .filter(|o| o.build_time.is_some())
+            .count();
+        Some(n_success as f64 / self.outcomes.len() as f64)
+    }
+
+    /// Recompute our status from the outcomes in our window.
+    ///
+    /// We only become less healthy once our success rate falls below a
+    /// threshold, and only become more healthy once it rises a margin above
+    /// one.  If we don't have enough outcomes to judge, we keep our old
+    /// status.
+    fn update_status(&mut self) {
+        if self.outcomes.len() < MIN_OUTCOMES {
+            return;
+        }
+        let frac = match self.success_frac() {
+            Some(f) => f,
+            None => return,
+        };
+        let plain = HealthStatus::from_frac(frac);
+        let with_margin = HealthStatus::from_frac(frac - HYSTERESIS);
+        if plain < self.status {
+            self.status = plain;
+        } else if with_margin > self.status {
+            self.status = with_margin;
+        }
+    }
+
+    /// Return a report on our health as of `now`.
+    pub(crate) fn health(&mut self, now: Instant) -> CircHealth {
+        self.expire(now);
+        self.update_status();
+
+        let mut build_times: Vec<_> = self.outcomes.iter().filter_map(|o| o.build_time).collect();
+        build_times.sort_unstable();
+        let median_build_time = build_times.get(build_times.len() / 2).copied();
+
+        CircHealth {
+            status: self.status,
+            n_outcomes: self.outcomes.len(),
+            success_frac: self.success_frac(),
+            median_build_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    /// Feed `tracker` one outcome per second starting at `start`: a success
+    /// taking `build_time` for every `true` in `script`, a failure for every
+    /// `false`.  Return the time of the last outcome.
+    fn feed(
+        tracker: &mut HealthTracker,
+        start: Instant,
+        build_time: Duration,
+        script: &[bool],
+    ) -> Instant {
+        let mut now = start;
+        for ok in script {
+            now += Duration::from_secs(1);
+            if *ok {
+                tracker.note_success(now, build_time);
+            } else {
+                tracker.note_failure(now);
+            }
+        }
+        now
+    }
+
+    #[test]
+    fn rates_and_medians() {
+        let mut tracker = HealthTracker::default();
+        let start = Instant::now();
+
+        let h = tracker.health(start);
+        assert_eq!(h.status(), HealthStatus::Healthy);
+        assert_eq!(h.n_outcomes(), 0);
+        assert!(h.success_frac().is_none());
+        assert!(h.median_build_time().is_none());
+
+        let ms = Duration::from_millis;
+        tracker.note_success(start, ms(300));
+        tracker.note_success(start, ms(100));
+        tracker.note_failure(start);
+        tracker.note_success(start, ms(200));
+
+        let h = tracker.health(start);
+        assert_eq!(h.n_outcomes(), 4);
+        assert!((h.success_frac().unwrap() - 0.75).abs() < f64::EPSILON);
+        assert_eq!(h.median_build_time(), Some(ms(200)));
+
+        // Once those outcomes leave the window, we forget them.
+        let h = tracker.health(start + HEALTH_WINDOW + Duration::from_secs(1));
+        assert_eq!(h.n_outcomes(), 0);
+        assert!(h.success_frac().is_none());
+    }
+
+    #[test]
+    fn transitions() {
+        let mut tracker = HealthTracker::default();
+        let sec = Duration::from_secs(1);
+        let now = Instant::now();
+
+        // Too few outcomes to judge: no change.
+        let now = feed(&mut tracker, now, sec, &[false; 4]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Healthy);
+
+        // All failures: we're broken.
+        let now = feed(&mut tracker, now, sec, &[false; 6]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Broken);
+
+        // Let those age out, then fail 13 times and succeed 7 times (35%):
+        // that's above the broken threshold, but not by enough to recover.
+        let now = now + HEALTH_WINDOW + sec;
+        let mut script = vec![false; 13];
+        script.extend([true; 7].iter());
+        let now = feed(&mut tracker, now, sec, &script);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Broken);
+
+        // Three more successes: 10 out of 23 (43%) gets us to degraded.
+        let now = feed(&mut tracker, now, sec, &[true; 3]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Degraded);
+
+        // Enough successes for 80%: healthy by the plain threshold, but
+        // still not by enough to recover.
+        let now = feed(&mut tracker, now, sec, &[true; 42]);
+        let h = tracker.health(now);
+        assert!((h.success_frac().unwrap() - 52.0 / 65.0).abs() < f64::EPSILON);
+        assert_eq!(h.status(), HealthStatus::Degraded);
+
+        // Comfortably over 90%: now we're healthy.
+        let now = feed(&mut tracker, now, sec, &[true; 70]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Healthy);
+
+        // A failure that drops us just below 80% makes us degraded right
+        // away...
+        let now = now + HEALTH_WINDOW + sec;
+        let mut script = vec![true; 8];
+        script.extend([false; 2].iter());
+        let now = feed(&mut tracker, now, sec, &script);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Healthy);
+        let now = feed(&mut tracker, now, sec, &[false]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Degraded);
+
+        // ... and a success that brings us back to 75% isn't enough to
+        // flap back.
+        let now = feed(&mut tracker, now, sec, &[true]);
+        assert_eq!(tracker.health(now).status(), HealthStatus::Degraded);
+    }
+}
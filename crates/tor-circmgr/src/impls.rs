@@ -6,12 +6,16 @@ use crate::usage::{SupportedCircUsage, TargetCircUsage};
 use crate::{DirInfo, Error, Result};
 use async_trait::async_trait;
 use futures::future::OptionFuture;
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt::{self, Debug};
 use std::sync::Arc;
 use tor_error::internal;
+use tor_guardmgr::GuardUsable;
+use tor_linkspec::ChanTarget;
 use tor_proto::circuit::{CircParameters, ClientCirc};
 use tor_rtcompat::Runtime;
+use tracing::debug;
 
 impl mgr::AbstractCirc for tor_proto::circuit::ClientCirc {
     type Id = tor_proto::circuit::UniqId;
@@ -23,6 +27,21 @@ impl mgr::AbstractCirc for tor_proto::circuit::ClientCirc {
     }
 }
 
+/// How the per-guard limit on concurrent circuit builds affected the choice
+/// of guard for a planned circuit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum GuardLimitOutcome {
+    /// No guard was too busy to use; we picked our guard as usual.
+    Unconstrained,
+    /// Some guards were already building too many circuits, so we picked
+    /// the next primary guard instead.
+    SpilledOver,
+    /// Our guard is already building too many circuits, and there was no
+    /// other primary guard to use: this circuit will wait for one of the
+    /// other builds to finish.
+    Queued,
+}
+
 /// The information generated by circuit planning, and used to build a
 /// circuit.
 pub(crate) struct Plan {
@@ -40,6 +59,8 @@ pub(crate) struct Plan {
     /// whether we're allowed to use the circuit or whether we have to
     /// wait a while.
     guard_usable: Option<tor_guardmgr::GuardUsable>,
+    /// How the per-guard build limit affected our choice of guard.
+    guard_limit: GuardLimitOutcome,
 }
 
 impl Debug for Plan {
@@ -49,6 +70,7 @@ impl Debug for Plan {
             .field("path", &self.path)
             .field("params", &self.params)
             .field("guard_status", &self.guard_status)
+            .field("guard_limit", &self.guard_limit)
             .finish_non_exhaustive()
     }
 }
@@ -67,19 +89,76 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
         dir: DirInfo<'_>,
     ) -> Result<(Plan, SupportedCircUsage)> {
         let mut rng = rand::thread_rng();
-        let (path, final_spec, guard_status, guard_usable) = usage.build_path(
-            &mut rng,
-            dir,
-            Some(self.guardmgr()),
-            self.path_config().as_ref(),
-        )?;
+        let path_config = self.path_config();
+        let busy_guards = self.busy_guards();
+        let bridges = self.bridge_targets();
+
+        // If some of our guards are already building as many circuits as
+        // we allow, try to spill over onto another primary guard.  We don't
+        // accept a non-primary guard here: we wouldn't be allowed to use a
+        // circuit through it anyway, since our primary guards are up.
+        let mut spilled = None;
+        if !busy_guards.is_empty() {
+            match usage.build_path(
+                &mut rng,
+                dir,
+                Some(self.guardmgr()),
+                path_config.as_ref(),
+                &busy_guards,
+                &bridges,
+            ) {
+                Ok((path, spec, mon, usable)) => {
+                    if usable.as_ref().map(GuardUsable::is_primary).unwrap_or(true) {
+                        spilled = Some((path, spec, mon, usable));
+                    } else if let Some(mon) = mon {
+                        mon.attempt_abandoned();
+                    }
+                }
+                Err(e) => debug!("Unable to avoid busy guards: {}", e),
+            }
+        }
+
+        let (path, final_spec, guard_status, guard_usable, guard_limit) = match spilled {
+            Some((path, spec, mon, usable)) => {
+                (path, spec, mon, usable, GuardLimitOutcome::SpilledOver)
+            }
+            None => {
+                let (path, spec, mon, usable) = usage.build_path(
+                    &mut rng,
+                    dir,
+                    Some(self.guardmgr()),
+                    path_config.as_ref(),
+                    &HashSet::new(),
+                    &bridges,
+                )?;
+                (path, spec, mon, usable, GuardLimitOutcome::Unconstrained)
+            }
+        };
+        let path: OwnedPath = (&path).try_into()?;
+        let guard_limit = match &path {
+            OwnedPath::Normal(p)
+                if p.first()
+                    .map(|g| busy_guards.contains(g.ed_identity()))
+                    .unwrap_or(false) =>
+            {
+                GuardLimitOutcome::Queued
+            }
+            _ => guard_limit,
+        };
+        if guard_limit != GuardLimitOutcome::Unconstrained {
+            debug!(
+                "Guard build limit reached; planned circuit is {:?}",
+                guard_limit
+            );
+        }
 
         let plan = Plan {
             final_spec: final_spec.clone(),
-            path: (&path).try_into()?,
+            path,
             params: dir.circ_params(),
             guard_status,
             guard_usable,
+            guard_limit,
         };
 
         Ok((plan, final_spec))
@@ -94,6 +173,7 @@ impl<R: Runtime> crate::mgr::AbstractCircBuilder for crate::build::CircuitBuilde
             params,
             guard_status,
             guard_usable,
+            ..
         } = plan;
 
         let guard_usable: OptionFuture<_> = guard_usable.into();
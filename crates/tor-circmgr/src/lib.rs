@@ -50,19 +50,23 @@
 #![warn(clippy::unseparated_literal_suffix)]
 #![deny(clippy::unwrap_used)]
 
+use futures::task::SpawnExt;
 use tor_chanmgr::ChanMgr;
 use tor_netdir::{fallback::FallbackDir, NetDir};
 use tor_proto::circuit::{CircParameters, ClientCirc, UniqId};
 use tor_rtcompat::Runtime;
 
 use std::convert::TryInto;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
+mod bridgedesc;
 pub mod build;
 mod config;
 mod err;
+mod event;
+mod health;
 mod impls;
 mod mgr;
 pub mod path;
@@ -71,12 +75,15 @@ mod timeouts;
 mod usage;
 
 pub use err::Error;
+pub use event::{CircEvent, CircEvents, CircStatus, CircStatusEvents};
+pub use health::{CircHealth, HealthStatus};
 pub use usage::{IsolationToken, StreamIsolation, StreamIsolationBuilder, TargetPort, TargetPorts};
 
 pub use config::{
     CircMgrConfig, CircMgrConfigBuilder, CircuitTiming, CircuitTimingBuilder, PathConfig,
     PathConfigBuilder, PreemptiveCircuitConfig, PreemptiveCircuitConfigBuilder,
 };
+pub use tor_guardmgr::bridge::{BridgeConfig, BridgeParseError};
 
 use crate::preemptive::PreemptiveCircuitPredictor;
 use usage::TargetCircUsage;
@@ -87,9 +94,32 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Type alias for dynamic StorageHandle that can handle our timeout state.
 type TimeoutStateHandle = tor_persist::DynStorageHandle<timeouts::pareto::ParetoTimeoutState>;
 
+/// Type alias for dynamic JournalHandle that can record changes to our
+/// timeout state.
+type TimeoutJournalHandle =
+    tor_persist::DynJournalHandle<timeouts::estimator::TimeoutJournalRecord>;
+
 /// Key used to load timeout state information.
 const PARETO_TIMEOUT_DATA_KEY: &str = "circuit_timeouts";
 
+/// The smallest number of hops that a circuit carrying user streams may have.
+///
+/// Anything shorter would let the first hop see both who we are and where
+/// we're connecting.
+const MIN_EXIT_CIRC_HOPS: u8 = 2;
+
+/// How often to check whether we're missing any bridge descriptors, when
+/// we aren't retrying a failed fetch.
+const BRIDGE_DESC_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying a failed bridge descriptor fetch the
+/// first time.
+const BRIDGE_DESC_INITIAL_RETRY: Duration = Duration::from_secs(5);
+
+/// The longest that we'll wait before retrying a failed bridge descriptor
+/// fetch.
+const BRIDGE_DESC_MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+
 /// Represents what we know about the Tor network.
 ///
 /// This can either be a complete directory, or a list of fallbacks.
@@ -153,6 +183,10 @@ pub struct CircMgr<R: Runtime> {
     mgr: Arc<mgr::AbstractCircMgr<build::CircuitBuilder<R>, R>>,
     /// A preemptive circuit predictor, for, uh, building circuits preemptively.
     predictor: Arc<Mutex<PreemptiveCircuitPredictor>>,
+    /// Sender for [`CircStatus`] events.
+    status_sender: Arc<Mutex<event::CircMgrEventSender>>,
+    /// Stream of [`CircStatus`] events.
+    bootstrap_status: CircStatusEvents,
 }
 
 impl<R: Runtime> CircMgr<R> {
@@ -178,6 +212,11 @@ impl<R: Runtime> CircMgr<R> {
 
         let guardmgr = tor_guardmgr::GuardMgr::new(runtime.clone(), storage.clone())?;
 
+        chanmgr.set_handshake_timeouts(circuit_timing.handshake_timeouts());
+
+        let journal_handle = storage
+            .clone()
+            .create_journal_handle(PARETO_TIMEOUT_DATA_KEY);
         let storage_handle = storage.create_handle(PARETO_TIMEOUT_DATA_KEY);
 
         let builder = build::CircuitBuilder::new(
@@ -185,14 +224,25 @@ impl<R: Runtime> CircMgr<R> {
             chanmgr,
             path_rules,
             storage_handle,
+            journal_handle,
             guardmgr,
         );
         let mgr = mgr::AbstractCircMgr::new(builder, runtime.clone(), circuit_timing);
+        let (sender, receiver) = event::channel();
         let circmgr = Arc::new(CircMgr {
             mgr: Arc::new(mgr),
             predictor: preemptive,
+            status_sender: Arc::new(Mutex::new(sender)),
+            bootstrap_status: receiver,
         });
 
+        runtime
+            .spawn(continually_learn_bridge_descs(
+                runtime.clone(),
+                Arc::downgrade(&circmgr.mgr),
+            ))
+            .map_err(|e| Error::from_spawn("bridge descriptor task", e))?;
+
         Ok(circmgr)
     }
 
@@ -225,6 +275,9 @@ impl<R: Runtime> CircMgr<R> {
         self.mgr
             .peek_builder()
             .set_path_config(new_config.path_rules.clone());
+        self.mgr
+            .peek_builder()
+            .set_handshake_timeouts(new_config.circuit_timing.handshake_timeouts());
         self.mgr
             .set_circuit_timing(new_config.circuit_timing.clone());
         predictor.set_config(new_config.preemptive_circuits.clone());
@@ -306,27 +359,57 @@ impl<R: Runtime> CircMgr<R> {
     ///
     /// If the list of ports is empty, then the chosen circuit will
     /// still end at _some_ exit.
+    ///
+    /// If `require_stability` is true, every relay on the circuit will
+    /// have the Stable flag, making it suitable for long-lived streams.
     pub async fn get_or_launch_exit(
         &self,
         netdir: DirInfo<'_>, // TODO: This has to be a NetDir.
         ports: &[TargetPort],
         isolation: StreamIsolation,
+        require_stability: bool,
     ) -> Result<ClientCirc> {
         self.expire_circuits();
         let time = Instant::now();
         {
             let mut predictive = self.predictor.lock().expect("preemptive lock poisoned");
             if ports.is_empty() {
-                predictive.note_usage(None, time);
+                predictive.note_usage(None, require_stability, time);
             } else {
                 for port in ports.iter() {
-                    predictive.note_usage(Some(*port), time);
+                    predictive.note_usage(Some(*port), require_stability, time);
                 }
             }
         }
         let ports = ports.iter().map(Clone::clone).collect();
-        let usage = TargetCircUsage::Exit { ports, isolation };
-        self.mgr.get_or_launch(&usage, netdir).await
+        let usage = TargetCircUsage::Exit {
+            ports,
+            isolation,
+            require_stability,
+        };
+        let result = self
+            .mgr
+            .get_or_launch(&usage, netdir)
+            .await
+            .and_then(|circ| {
+                // The usage checks in the manager should make this
+                // impossible; check anyway, since getting it wrong would
+                // deanonymize the user.
+                debug_assert!(circ.n_hops() >= MIN_EXIT_CIRC_HOPS);
+                if circ.n_hops() < MIN_EXIT_CIRC_HOPS {
+                    return Err(Error::UsageNotAllowed(format!(
+                        "{}-hop circuit can't carry user streams",
+                        circ.n_hops()
+                    )));
+                }
+                Ok(circ)
+            });
+        if result.is_ok() {
+            self.note_circ_built();
+        }
+        // Tell anybody watching if our circuit health has changed.
+        let _ = self.health();
+        result
     }
 
     /// Launch circuits preemptively, using the preemptive circuit predictor's predictions.
@@ -356,10 +439,63 @@ impl<R: Runtime> CircMgr<R> {
         let results = futures::future::join_all(futures).await;
         for (i, result) in results.iter().enumerate() {
             match result {
-                Ok(_) => debug!("Circuit exists (or was created) for {:?}", circs[i]),
+                Ok(_) => {
+                    debug!("Circuit exists (or was created) for {:?}", circs[i]);
+                    self.note_circ_built();
+                }
                 Err(e) => warn!("Failed to build preemptive circuit {:?}: {}", circs[i], e),
             }
         }
+        // Tell anybody watching if our circuit health has changed.
+        let _ = self.health();
+    }
+
+    /// Return a stream of [`CircStatus`] events to tell us about changes
+    /// in our ability to build circuits.
+    ///
+    /// Note that this stream can be lossy: the caller will not necessarily
+    /// observe every event on the stream.
+    pub fn bootstrap_events(&self) -> CircStatusEvents {
+        self.bootstrap_status.clone()
+    }
+
+    /// Return a stream of events telling when circuits are built and when
+    /// they close.
+    ///
+    /// Only circuits built after this call are reported.  If the reader
+    /// falls far behind, some events are dropped.
+    pub fn circ_events(&self) -> CircEvents {
+        self.mgr.peek_builder().circ_events()
+    }
+
+    /// Return a report on how well our recent circuit builds have gone.
+    ///
+    /// This covers every multi-hop circuit that we've tried to build within
+    /// the last several minutes, whether for user traffic, for preemptive
+    /// use, or for measuring build times.  Once we've built our first
+    /// circuit, changes in the resulting [`HealthStatus`] are also reported
+    /// via [`CircMgr::bootstrap_events`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock on our health tracker or on our status sender has
+    /// been poisoned.
+    pub fn health(&self) -> CircHealth {
+        let health = self.mgr.peek_builder().health();
+        self.status_sender
+            .lock()
+            .expect("status sender lock poisoned")
+            .record_health(health.status());
+        health
+    }
+
+    /// Record that we've obtained a multi-hop circuit, and tell anybody
+    /// watching our bootstrap status if that's news.
+    fn note_circ_built(&self) {
+        self.status_sender
+            .lock()
+            .expect("status sender lock poisoned")
+            .record_circ_built();
     }
 
     /// If `circ_id` is the unique identifier for a circuit that we're
@@ -433,6 +569,40 @@ impl<R: Runtime> CircMgr<R> {
     }
 }
 
+/// Periodically fetch descriptors for any configured bridges whose
+/// descriptors we don't know yet.
+///
+/// We can only build multi-hop circuits through bridges whose descriptors we
+/// know.  When a fetch fails, we retry it with an exponential back-off;
+/// otherwise, we check every [`BRIDGE_DESC_CHECK_INTERVAL`] whether new
+/// bridges have been configured.
+///
+/// Exit when the circuit manager is dropped.
+async fn continually_learn_bridge_descs<R: Runtime>(
+    runtime: R,
+    mgr: Weak<mgr::AbstractCircMgr<build::CircuitBuilder<R>, R>>,
+) {
+    let mut retry_delay = BRIDGE_DESC_INITIAL_RETRY;
+    loop {
+        let delay = if let Some(mgr) = Weak::upgrade(&mgr) {
+            let builder = mgr.peek_builder();
+            if builder.path_config().bridges().is_empty()
+                || builder.learn_bridge_descs(runtime.wallclock()).await
+            {
+                retry_delay = BRIDGE_DESC_INITIAL_RETRY;
+                BRIDGE_DESC_CHECK_INTERVAL
+            } else {
+                let delay = retry_delay;
+                retry_delay = std::cmp::min(retry_delay * 2, BRIDGE_DESC_MAX_RETRY);
+                delay
+            }
+        } else {
+            return;
+        };
+        runtime.sleep(delay).await;
+    }
+}
+
 impl<R: Runtime> Drop for CircMgr<R> {
     fn drop(&mut self) {
         match self.store_persistent_state() {
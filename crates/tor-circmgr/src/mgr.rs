@@ -1995,6 +1995,7 @@ mod test {
             SupportedCircUsage::Exit {
                 policy: ep_none,
                 isolation: None,
+                all_relays_stable: false,
             },
             fake_circ.clone(),
             expiration.clone(),
@@ -2004,6 +2005,7 @@ mod test {
             SupportedCircUsage::Exit {
                 policy: ep_web,
                 isolation: None,
+                all_relays_stable: false,
             },
             fake_circ.clone(),
             expiration.clone(),
@@ -2013,6 +2015,7 @@ mod test {
             SupportedCircUsage::Exit {
                 policy: ep_full,
                 isolation: None,
+                all_relays_stable: false,
             },
             fake_circ,
             expiration,
@@ -2022,6 +2025,7 @@ mod test {
         let usage_web = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: StreamIsolation::no_isolation(),
+            require_stability: false,
         };
         let empty: Vec<&OpenEntry<SupportedCircUsage, FakeCirc>> = vec![];
 
@@ -2055,10 +2059,12 @@ mod test {
         let usage_preemptive_web = TargetCircUsage::Preemptive {
             port: Some(TargetPort::ipv4(80)),
             circs: 2,
+            require_stability: false,
         };
         let usage_preemptive_dns = TargetCircUsage::Preemptive {
             port: None,
             circs: 2,
+            require_stability: false,
         };
 
         // shouldn't return anything unless there are >=2 circuits
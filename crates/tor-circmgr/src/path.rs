@@ -6,14 +6,16 @@
 pub mod dirpath;
 pub mod exitpath;
 
+use rand::{seq::SliceRandom, Rng};
 use tor_error::bad_api_usage;
+use tor_guardmgr::bridge::BridgeConfig;
 use tor_linkspec::{OwnedChanTarget, OwnedCircTarget};
 use tor_netdir::{fallback::FallbackDir, Relay};
 
 use std::convert::TryFrom;
 
 use crate::usage::ExitPolicy;
-use crate::Result;
+use crate::{Error, Result};
 
 /// A list of Tor relays through the network.
 pub struct TorPath<'a> {
@@ -33,6 +35,15 @@ enum TorPathInner<'a> {
     FallbackOneHop(&'a FallbackDir),
     /// A multi-hop path, containing one or more relays.
     Path(Vec<Relay<'a>>),
+    /// A single-hop path for use with a bridge as a directory cache, when
+    /// all we know about the bridge is its bridge line.
+    BridgeOneHop(BridgeConfig),
+    /// A path whose first hop is a bridge whose descriptor we know,
+    /// followed by one or more relays.
+    ///
+    /// (The bridge is boxed, since it is much larger than the other
+    /// variants.)
+    Bridged(Box<OwnedCircTarget>, Vec<Relay<'a>>),
 }
 
 impl<'a> TorPath<'a> {
@@ -59,11 +70,31 @@ impl<'a> TorPath<'a> {
         }
     }
 
+    /// Create a new one-hop path for use with `bridge` as a directory
+    /// cache.
+    pub(crate) fn new_bridge_one_hop(bridge: BridgeConfig) -> Self {
+        Self {
+            inner: TorPathInner::BridgeOneHop(bridge),
+        }
+    }
+
+    /// Create a new path whose first hop is `bridge`, followed by `relays`.
+    pub(crate) fn new_bridged(
+        bridge: OwnedCircTarget,
+        relays: impl IntoIterator<Item = Relay<'a>>,
+    ) -> Self {
+        Self {
+            inner: TorPathInner::Bridged(Box::new(bridge), relays.into_iter().collect()),
+        }
+    }
+
     /// Return the final relay in this path, if this is a path for use
     /// with exit circuits.
     fn exit_relay(&self) -> Option<&Relay<'a>> {
         match &self.inner {
-            TorPathInner::Path(relays) if !relays.is_empty() => Some(&relays[relays.len() - 1]),
+            TorPathInner::Path(relays) | TorPathInner::Bridged(_, relays) if !relays.is_empty() => {
+                Some(&relays[relays.len() - 1])
+            }
             _ => None,
         }
     }
@@ -82,15 +113,35 @@ impl<'a> TorPath<'a> {
             OneHop(_) => 1,
             FallbackOneHop(_) => 1,
             Path(p) => p.len(),
+            BridgeOneHop(_) => 1,
+            Bridged(_, p) => p.len() + 1,
         }
     }
 }
 
+/// Pick one of `bridges` at random, to use as the first hop of a circuit.
+///
+/// We only consider bridges for which `usable` returns true.
+fn pick_bridge<'b, R, B, F>(rng: &mut R, bridges: &'b [B], usable: F) -> Result<&'b B>
+where
+    R: Rng,
+    F: Fn(&B) -> bool,
+{
+    let candidates: Vec<_> = bridges.iter().filter(|b| usable(b)).collect();
+    candidates
+        .choose(rng)
+        .copied()
+        .ok_or_else(|| Error::NoPath("No usable bridge found".into()))
+}
+
 /// A path composed entirely of owned components.
 #[derive(Clone, Debug)]
 pub(crate) enum OwnedPath {
     /// A path where we only know how to make circuits via CREATE_FAST.
     ChannelOnly(OwnedChanTarget),
+    /// A path to a bridge, where we only know the bridge's bridge line, and
+    /// so can only make circuits via CREATE_FAST.
+    BridgeOneHop(BridgeConfig),
     /// A path of one or more hops created via normal Tor handshakes.
     Normal(Vec<OwnedCircTarget>),
 }
@@ -109,6 +160,12 @@ impl<'a> TryFrom<&TorPath<'a>> for OwnedPath {
             Path(_) => {
                 return Err(bad_api_usage!("Path with no entries!").into());
             }
+            BridgeOneHop(b) => OwnedPath::BridgeOneHop(b.clone()),
+            Bridged(bridge, p) => OwnedPath::Normal(
+                std::iter::once(bridge.as_ref().clone())
+                    .chain(p.iter().map(OwnedCircTarget::from_circ_target))
+                    .collect(),
+            ),
         })
     }
 }
@@ -119,6 +176,7 @@ impl OwnedPath {
     pub(crate) fn len(&self) -> usize {
         match self {
             OwnedPath::ChannelOnly(_) => 1,
+            OwnedPath::BridgeOneHop(_) => 1,
             OwnedPath::Normal(p) => p.len(),
         }
     }
@@ -147,6 +205,17 @@ fn assert_same_path_when_owned(path: &TorPath<'_>) {
                 assert_eq!(n1.ed_identity(), n2.ed_identity());
             }
         }
+        (OwnedPath::BridgeOneHop(b1), TorPathInner::BridgeOneHop(b2)) => {
+            assert_eq!(b1, b2);
+        }
+        (OwnedPath::Normal(p1), TorPathInner::Bridged(b, p2)) => {
+            assert_eq!(p1.len(), p2.len() + 1);
+            assert_eq!(p1[0].ed_identity(), b.ed_identity());
+            assert_eq!(p1[0].addrs(), b.addrs());
+            for (n1, n2) in p1[1..].iter().zip(p2.iter()) {
+                assert_eq!(n1.ed_identity(), n2.ed_identity());
+            }
+        }
         (_, _) => {
             panic!("Mismatched path types.");
         }
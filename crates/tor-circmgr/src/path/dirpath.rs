@@ -1,7 +1,10 @@
 //! Code to construct paths to a directory for non-anonymous downloads
 use super::TorPath;
 use crate::{DirInfo, Error, Result};
+use std::collections::HashSet;
+use tor_guardmgr::bridge::BridgeConfig;
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
 use tor_netdir::{Relay, WeightRole};
 use tor_rtcompat::Runtime;
 
@@ -9,7 +12,12 @@ use rand::{seq::SliceRandom, Rng};
 
 /// A PathBuilder that can connect to a directory.
 #[non_exhaustive]
-pub struct DirPathBuilder {}
+pub struct DirPathBuilder {
+    /// Guards that we should not pick, because they are too busy.
+    avoid_guards: HashSet<Ed25519Identity>,
+    /// Bridges that we must use as our directory caches, if any.
+    bridges: Vec<BridgeConfig>,
+}
 
 impl Default for DirPathBuilder {
     fn default() -> Self {
@@ -20,7 +28,23 @@ impl Default for DirPathBuilder {
 impl DirPathBuilder {
     /// Create a new DirPathBuilder.
     pub fn new() -> Self {
-        DirPathBuilder {}
+        DirPathBuilder {
+            avoid_guards: HashSet::new(),
+            bridges: Vec::new(),
+        }
+    }
+
+    /// Tell this builder to avoid the guards in `ids` if it can, because
+    /// we are already building too many circuits through them.
+    pub(crate) fn avoid_guards(&mut self, ids: HashSet<Ed25519Identity>) -> &mut Self {
+        self.avoid_guards = ids;
+        self
+    }
+
+    /// Tell this builder that it must only use the bridges in `bridges`.
+    pub(crate) fn bridges(&mut self, bridges: Vec<BridgeConfig>) -> &mut Self {
+        self.bridges = bridges;
+        self
     }
 
     /// Try to create and return a path corresponding to the requirements of
@@ -31,6 +55,14 @@ impl DirPathBuilder {
         netdir: DirInfo<'a>,
         guards: Option<&GuardMgr<RT>>,
     ) -> Result<(TorPath<'a>, Option<GuardMonitor>, Option<GuardUsable>)> {
+        if !self.bridges.is_empty() {
+            // If we have bridges, we never talk to any other directory
+            // cache.  We don't need a directory to use them, either: we can
+            // always reach a bridge at the address in its bridge line.
+            let bridge = super::pick_bridge(rng, &self.bridges, |_| true)?;
+            return Ok((TorPath::new_bridge_one_hop(bridge.clone()), None, None));
+        }
+
         match (netdir, guards) {
             (DirInfo::Fallbacks(f), _) => {
                 let relay = f.choose(rng);
@@ -48,10 +80,14 @@ impl DirPathBuilder {
                 // TODO: We might want to use the guardmgr even if
                 // we don't have a netdir.  See arti#220.
                 guardmgr.update_network(netdir); // possibly unnecessary.
-                let guard_usage = tor_guardmgr::GuardUsageBuilder::default()
-                    .kind(tor_guardmgr::GuardUsageKind::OneHopDirectory)
-                    .build()
-                    .expect("Unable to build directory guard usage");
+                let mut b = tor_guardmgr::GuardUsageBuilder::default();
+                b.kind(tor_guardmgr::GuardUsageKind::OneHopDirectory);
+                if !self.avoid_guards.is_empty() {
+                    b.push_restriction(tor_guardmgr::GuardRestriction::AvoidAllIds(
+                        self.avoid_guards.clone(),
+                    ));
+                }
+                let guard_usage = b.build().expect("Unable to build directory guard usage");
                 let (guard, mon, usable) = guardmgr.select_guard(guard_usage, Some(netdir))?;
                 if let Some(r) = guard.get_relay(netdir) {
                     return Ok((TorPath::new_one_hop(r), Some(mon), Some(usable)));
@@ -146,6 +182,55 @@ mod test {
         assert!(matches!(err, Err(Error::NoPath(_))));
     }
 
+    #[test]
+    fn dirpath_bridges() {
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let mut rng = rand::thread_rng();
+        let guards: OptDummyGuardMgr<'_> = None;
+        let bridge: BridgeConfig = format!("198.51.100.1:443 {}", "05".repeat(20))
+            .parse()
+            .unwrap();
+
+        let fb = vec![FallbackDir::builder()
+            .rsa_identity([0x01; 20].into())
+            .ed_identity([0x01; 32].into())
+            .orport("127.0.0.1:9000".parse().unwrap())
+            .build()
+            .unwrap()];
+
+        // We use the bridge whether we have a directory or only fallbacks,
+        // and we never use a fallback or a directory cache instead.
+        for dirinfo in [(&netdir).into(), DirInfo::Fallbacks(&fb[..])] {
+            let (p, _, _) = DirPathBuilder::new()
+                .bridges(vec![bridge.clone()])
+                .pick_path(&mut rng, dirinfo, guards)
+                .unwrap();
+            assert_eq!(p.len(), 1);
+            assert_same_path_when_owned(&p);
+            if let crate::path::TorPathInner::BridgeOneHop(b) = p.inner {
+                assert_eq!(b, bridge);
+            } else {
+                panic!("Generated the wrong kind of path.");
+            }
+        }
+
+        // Bridges that name a pluggable transport are used the same way:
+        // reaching them through the transport is the channel manager's job.
+        let pt_bridge: BridgeConfig = "obfs4 198.51.100.2:443".parse().unwrap();
+        let (p, _, _) = DirPathBuilder::new()
+            .bridges(vec![pt_bridge.clone()])
+            .pick_path(&mut rng, DirInfo::Fallbacks(&fb[..]), guards)
+            .unwrap();
+        if let crate::path::TorPathInner::BridgeOneHop(b) = p.inner {
+            assert_eq!(b, pt_bridge);
+        } else {
+            panic!("Generated the wrong kind of path.");
+        }
+    }
+
     #[test]
     fn dirpath_with_guards() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
@@ -3,9 +3,12 @@
 use super::TorPath;
 use crate::{DirInfo, Error, PathConfig, Result, TargetPort};
 use rand::Rng;
+use std::collections::HashSet;
 use std::time::{Duration, SystemTime};
 use tor_error::{bad_api_usage, internal};
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
+use tor_linkspec::{ChanTarget, OwnedCircTarget};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
 use tor_netdir::{NetDir, Relay, SubnetConfig, WeightRole};
 use tor_rtcompat::Runtime;
 
@@ -30,6 +33,13 @@ enum ExitPathBuilderInner<'a> {
 pub struct ExitPathBuilder<'a> {
     /// The inner ExitPathBuilder state.
     inner: ExitPathBuilderInner<'a>,
+    /// Guards that we should not pick, because they are too busy.
+    avoid_guards: HashSet<Ed25519Identity>,
+    /// Targets for those of our configured bridges whose descriptors we
+    /// know.
+    bridges: Vec<OwnedCircTarget>,
+    /// If true, every relay in the path must have the Stable flag.
+    require_stability: bool,
 }
 
 impl<'a> ExitPathBuilder<'a> {
@@ -44,6 +54,9 @@ impl<'a> ExitPathBuilder<'a> {
         }
         Self {
             inner: ExitPathBuilderInner::WantsPorts(ports),
+            avoid_guards: HashSet::new(),
+            bridges: Vec::new(),
+            require_stability: false,
         }
     }
 
@@ -52,6 +65,9 @@ impl<'a> ExitPathBuilder<'a> {
     pub fn from_chosen_exit(exit_relay: Relay<'a>) -> Self {
         Self {
             inner: ExitPathBuilderInner::ChosenExit(exit_relay),
+            avoid_guards: HashSet::new(),
+            bridges: Vec::new(),
+            require_stability: false,
         }
     }
 
@@ -59,6 +75,9 @@ impl<'a> ExitPathBuilder<'a> {
     pub fn for_any_exit() -> Self {
         Self {
             inner: ExitPathBuilderInner::AnyExit { strict: true },
+            avoid_guards: HashSet::new(),
+            bridges: Vec::new(),
+            require_stability: false,
         }
     }
 
@@ -67,21 +86,60 @@ impl<'a> ExitPathBuilder<'a> {
     pub(crate) fn for_timeout_testing() -> Self {
         Self {
             inner: ExitPathBuilderInner::AnyExit { strict: false },
+            avoid_guards: HashSet::new(),
+            bridges: Vec::new(),
+            require_stability: false,
         }
     }
 
+    /// Tell this builder to avoid the guards in `ids` if it can, because
+    /// we are already building too many circuits through them.
+    pub(crate) fn avoid_guards(&mut self, ids: HashSet<Ed25519Identity>) -> &mut Self {
+        self.avoid_guards = ids;
+        self
+    }
+
+    /// Tell this builder which of our configured bridges we know how to
+    /// build circuits through.
+    ///
+    /// These are only used if the [`PathConfig`] has bridges.
+    pub(crate) fn bridges(&mut self, bridges: Vec<OwnedCircTarget>) -> &mut Self {
+        self.bridges = bridges;
+        self
+    }
+
+    /// Tell this builder whether every relay in the path must have the
+    /// Stable flag, because the circuit will carry long-lived streams.
+    ///
+    /// Whether or not this is set, every relay in the path must have the
+    /// Fast flag.
+    pub fn require_stability(&mut self, require_stability: bool) -> &mut Self {
+        self.require_stability = require_stability;
+        self
+    }
+
+    /// Return true if `relay` has the flags that this builder requires for
+    /// every hop of the path.
+    fn has_required_flags(&self, relay: &Relay<'_>) -> bool {
+        relay.is_flagged_fast() && (!self.require_stability || relay.is_flagged_stable())
+    }
+
     /// Find a suitable exit node from either the chosen exit or from the network directory.
     fn pick_exit<R: Rng>(
         &self,
         rng: &mut R,
         netdir: &'a NetDir,
-        guard: Option<&Relay<'a>>,
-        config: SubnetConfig,
+        entry: Option<Entry<'a, '_>>,
+        config: &PathConfig,
     ) -> Result<Relay<'a>> {
+        let subnet_config = config.subnet_config();
+        let adjust = config.weight_adjustments();
         match &self.inner {
             ExitPathBuilderInner::AnyExit { strict } => {
-                let exit = netdir.pick_relay(rng, WeightRole::Exit, |r| {
-                    r.policies_allow_some_port() && relays_can_share_circuit_opt(r, guard, config)
+                let exit = netdir.pick_relay_adjusted(rng, WeightRole::Exit, &adjust, |r| {
+                    r.policies_allow_some_port()
+                        && self.has_required_flags(r)
+                        && entry_can_share_circuit(r, entry, subnet_config)
                 });
                 match (exit, strict) {
                     (Some(exit), _) => return Ok(exit),
@@ -92,28 +150,64 @@ impl<'a> ExitPathBuilder<'a> {
                 // Non-strict case.  Arguably this doesn't belong in
                 // ExitPathBuilder.
                 netdir
-                    .pick_relay(rng, WeightRole::Exit, |r| {
-                        relays_can_share_circuit_opt(r, guard, config)
+                    .pick_relay_adjusted(rng, WeightRole::Exit, &adjust, |r| {
+                        self.has_required_flags(r)
+                            && entry_can_share_circuit(r, entry, subnet_config)
                     })
                     .ok_or_else(|| Error::NoExit("No relay found".into()))
             }
 
             ExitPathBuilderInner::WantsPorts(wantports) => Ok(netdir
-                .pick_relay(rng, WeightRole::Exit, |r| {
-                    relays_can_share_circuit_opt(r, guard, config)
+                .pick_relay_adjusted(rng, WeightRole::Exit, &adjust, |r| {
+                    self.has_required_flags(r)
+                        && entry_can_share_circuit(r, entry, subnet_config)
                         && wantports.iter().all(|p| p.is_supported_by(r))
                 })
                 .ok_or_else(|| Error::NoExit("No exit relay found".into()))?),
 
             ExitPathBuilderInner::ChosenExit(exit_relay) => {
                 // NOTE that this doesn't check
-                // relays_can_share_circuit_opt(exit_relay,guard).  we
+                // entry_can_share_circuit(exit_relay,entry).  we
                 // already did that, sort of, in pick_path.
                 Ok(exit_relay.clone())
             }
         }
     }
 
+    /// Try to create and return a path corresponding to the requirements of
+    /// this builder, using one of our known bridges as the first hop.
+    ///
+    /// As with guards, we pick the bridge before the exit.
+    fn pick_bridged_path<R: Rng>(
+        &self,
+        rng: &mut R,
+        netdir: &'a NetDir,
+        chosen_exit: Option<&Relay<'a>>,
+        config: &PathConfig,
+    ) -> Result<(TorPath<'a>, Option<GuardMonitor>, Option<GuardUsable>)> {
+        let subnet_config = config.subnet_config();
+        let bridge = super::pick_bridge(rng, &self.bridges, |b| match chosen_exit {
+            Some(e) => relay_can_share_circuit_with_bridge(e, b, subnet_config),
+            None => true,
+        })?;
+
+        let exit = self.pick_exit(rng, netdir, Some(Entry::Bridge(bridge)), config)?;
+
+        let middle = netdir
+            .pick_relay_adjusted(rng, WeightRole::Middle, &config.weight_adjustments(), |r| {
+                self.has_required_flags(r)
+                    && relays_can_share_circuit(r, &exit, subnet_config)
+                    && relay_can_share_circuit_with_bridge(r, bridge, subnet_config)
+            })
+            .ok_or_else(|| Error::NoPath("No suitable middle relay found".into()))?;
+
+        Ok((
+            TorPath::new_bridged(bridge.clone(), vec![middle, exit]),
+            None,
+            None,
+        ))
+    }
+
     /// Try to create and return a path corresponding to the requirements of
     /// this builder.
     pub fn pick_path<R: Rng, RT: Runtime>(
@@ -147,8 +241,17 @@ impl<'a> ExitPathBuilder<'a> {
         };
         let path_is_fully_random = chosen_exit.is_none();
 
+        if !config.bridges().is_empty() {
+            // When we have bridges, we use them instead of our guards.
+            return self.pick_bridged_path(rng, netdir, chosen_exit, config);
+        }
+
         // TODO-SPEC: Because of limitations in guard selection, we have to
         // pick the guard before the exit, which is not what our spec says.
+        //
+        // We can't ask the guard manager for a guard with particular
+        // flags; but the Guard flag is only given to relays that are
+        // already Fast and Stable.
         let (guard, mon, usable) = match guards {
             Some(guardmgr) => {
                 let mut b = tor_guardmgr::GuardUsageBuilder::default();
@@ -161,6 +264,11 @@ impl<'a> ExitPathBuilder<'a> {
                     family.extend(netdir.known_family_members(exit_relay).map(|r| *r.id()));
                     b.push_restriction(tor_guardmgr::GuardRestriction::AvoidAllIds(family));
                 }
+                if !self.avoid_guards.is_empty() {
+                    b.push_restriction(tor_guardmgr::GuardRestriction::AvoidAllIds(
+                        self.avoid_guards.clone(),
+                    ));
+                }
                 let guard_usage = b.build().expect("Failed while building guard usage!");
                 let (guard, mut mon, usable) = guardmgr.select_guard(guard_usage, Some(netdir))?;
                 let guard = guard.get_relay(netdir).ok_or_else(|| {
@@ -184,6 +292,7 @@ impl<'a> ExitPathBuilder<'a> {
                 let entry = netdir
                     .pick_relay(rng, WeightRole::Guard, |r| {
                         r.is_flagged_guard()
+                            && self.has_required_flags(r)
                             && relays_can_share_circuit_opt(r, chosen_exit, subnet_config)
                     })
                     .ok_or_else(|| Error::NoPath("No suitable  entry relay found".into()))?;
@@ -191,11 +300,12 @@ impl<'a> ExitPathBuilder<'a> {
             }
         };
 
-        let exit = self.pick_exit(rng, netdir, Some(&guard), subnet_config)?;
+        let exit = self.pick_exit(rng, netdir, Some(Entry::Relay(&guard)), config)?;
 
         let middle = netdir
-            .pick_relay(rng, WeightRole::Middle, |r| {
-                relays_can_share_circuit(r, &exit, subnet_config)
+            .pick_relay_adjusted(rng, WeightRole::Middle, &config.weight_adjustments(), |r| {
+                self.has_required_flags(r)
+                    && relays_can_share_circuit(r, &exit, subnet_config)
                     && relays_can_share_circuit(r, &guard, subnet_config)
             })
             .ok_or_else(|| Error::NoPath("No suitable middle relay found".into()))?;
@@ -213,6 +323,49 @@ fn relays_can_share_circuit(a: &Relay<'_>, b: &Relay<'_>, subnet_config: SubnetC
     !a.in_same_family(b) && !a.in_same_subnet(b, &subnet_config)
 }
 
+/// The first hop of a path that we're building.
+#[derive(Clone, Copy)]
+enum Entry<'a, 'b> {
+    /// A relay from the directory, used as a guard.
+    Relay(&'b Relay<'a>),
+    /// One of our bridges.
+    Bridge(&'b OwnedCircTarget),
+}
+
+/// Returns true if `relay` can appear in the same circuit as `entry` (if
+/// any).
+fn entry_can_share_circuit(
+    relay: &Relay<'_>,
+    entry: Option<Entry<'_, '_>>,
+    c: SubnetConfig,
+) -> bool {
+    match entry {
+        Some(Entry::Relay(guard)) => relays_can_share_circuit(relay, guard, c),
+        Some(Entry::Bridge(bridge)) => relay_can_share_circuit_with_bridge(relay, bridge, c),
+        None => true,
+    }
+}
+
+/// Returns true if `relay` can appear in the same circuit as `bridge`.
+///
+/// We can't check families here, since we don't know the bridge's family:
+/// we only make sure that they aren't the same relay, and that they aren't
+/// on the same network.
+fn relay_can_share_circuit_with_bridge(
+    relay: &Relay<'_>,
+    bridge: &OwnedCircTarget,
+    subnet_config: SubnetConfig,
+) -> bool {
+    relay.ed_identity() != bridge.ed_identity()
+        && relay.rsa_identity() != bridge.rsa_identity()
+        && !relay.addrs().iter().any(|a| {
+            bridge
+                .addrs()
+                .iter()
+                .any(|b| subnet_config.addrs_in_same_subnet(&a.ip(), &b.ip()))
+        })
+}
+
 /// Helper: wraps relays_can_share_circuit but takes an option.
 fn relays_can_share_circuit_opt(r1: &Relay<'_>, r2: Option<&Relay<'_>>, c: SubnetConfig) -> bool {
     match r2 {
@@ -230,7 +383,7 @@ mod test {
     use crate::test::OptDummyGuardMgr;
     use std::collections::HashSet;
     use std::convert::TryInto;
-    use tor_linkspec::ChanTarget;
+    use tor_guardmgr::bridge::BridgeConfig;
     use tor_netdir::testnet;
 
     fn assert_exit_path_ok(relays: &[Relay<'_>]) {
@@ -324,6 +477,99 @@ mod test {
         }
     }
 
+    /// Make a target for `bridge`, as if we had fetched its descriptor and
+    /// found that it had the identities `ed` and `rsa`.
+    fn bridge_target(bridge: &BridgeConfig, ed: u8, rsa: u8, like: &Relay<'_>) -> OwnedCircTarget {
+        use tor_linkspec::CircTarget;
+        OwnedCircTarget::new(
+            bridge.chan_target([ed; 32].into(), [rsa; 20].into()),
+            *like.ntor_onion_key(),
+            like.protovers().clone(),
+        )
+    }
+
+    #[test]
+    fn bridged_paths() {
+        let mut rng = rand::thread_rng();
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let dirinfo = (&netdir).into();
+        let guards: OptDummyGuardMgr<'_> = None;
+        let some_relay = netdir.by_id(&[0x20; 32].into()).unwrap();
+        let bridges: Vec<BridgeConfig> = vec![
+            format!("198.51.100.1:443 {}", "e1".repeat(20))
+                .parse()
+                .unwrap(),
+            "198.51.100.2:443".parse().unwrap(),
+        ];
+        let targets = vec![
+            bridge_target(&bridges[0], 0xe1, 0xe1, &some_relay),
+            bridge_target(&bridges[1], 0xe2, 0xe2, &some_relay),
+        ];
+        let config = PathConfig::builder()
+            .bridges(bridges.clone())
+            .build()
+            .unwrap();
+
+        for _ in 0..1000 {
+            let (path, mon, usable) = ExitPathBuilder::for_any_exit()
+                .bridges(targets.clone())
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            assert_same_path_when_owned(&path);
+            assert!(mon.is_none() && usable.is_none());
+            assert_eq!(path.len(), 3);
+            if let TorPathInner::Bridged(bridge, p) = path.inner {
+                assert!(targets.iter().any(|t| t.addrs() == bridge.addrs()));
+                assert_ne!(p[0].ed_identity(), p[1].ed_identity());
+                assert!(relays_can_share_circuit(
+                    &p[0],
+                    &p[1],
+                    config.subnet_config()
+                ));
+                for r in &p {
+                    assert!(relay_can_share_circuit_with_bridge(
+                        r,
+                        &bridge,
+                        config.subnet_config()
+                    ));
+                }
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+
+        // A bridge that shares a network with some of our relays is never
+        // used with them.
+        let near: BridgeConfig = "1.0.0.9:443".parse().unwrap();
+        let near_target = bridge_target(&near, 0xe3, 0xe3, &some_relay);
+        let config = PathConfig::builder().bridges(vec![near]).build().unwrap();
+        for _ in 0..100 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .bridges(vec![near_target.clone()])
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Bridged(_, p) = path.inner {
+                for r in &p {
+                    assert_ne!(
+                        r.addrs()[0].ip(),
+                        "1.0.0.3".parse::<std::net::IpAddr>().unwrap()
+                    );
+                }
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+
+        // If we don't know any of our bridges' descriptors, we don't build
+        // a path at all.
+        let config = PathConfig::builder().bridges(bridges).build().unwrap();
+        let outcome = ExitPathBuilder::for_any_exit().pick_path(&mut rng, dirinfo, guards, &config);
+        assert!(matches!(outcome, Err(Error::NoPath(_))));
+    }
+
     #[test]
     fn empty_path() {
         // This shouldn't actually be constructable IRL, but let's test to
@@ -371,6 +617,165 @@ mod test {
         assert!(outcome.is_ok());
     }
 
+    #[test]
+    fn ipv6_exits() {
+        let mut rng = rand::thread_rng();
+        let guards: OptDummyGuardMgr<'_> = None;
+        let config = PathConfig::default();
+        let ports = vec![TargetPort::ipv6(443)];
+
+        // No relay in the default test network allows IPv6 exits, so an
+        // IPv6-only request has to fail.
+        let netdir = testnet::construct_netdir()
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let outcome = ExitPathBuilder::from_target_ports(ports.clone()).pick_path(
+            &mut rng,
+            (&netdir).into(),
+            guards,
+            &config,
+        );
+        assert!(matches!(outcome, Err(Error::NoExit(_))));
+
+        // Now let some exits (relays 10-19 and 30-39 have the Exit flag)
+        // allow IPv6 connections to port 443.  We pick the even-numbered
+        // ones, so that they are spread across every subnet in the test
+        // network, and no choice of guard can rule them all out.
+        let netdir = testnet::construct_custom_netdir(|idx, bld| {
+            if idx % 2 == 0 && (idx / 10) % 2 == 1 {
+                bld.md.parse_ipv6_policy("accept 443").unwrap();
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        for _ in 0..100 {
+            let (path, _, _) = ExitPathBuilder::from_target_ports(ports.clone())
+                .pick_path(&mut rng, (&netdir).into(), guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert!(p[2].ipv6_policy().allows_port(443));
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+    }
+
+    #[test]
+    fn require_stability() {
+        use tor_netdoc::doc::netstatus::RelayFlags;
+        // Make half of the relays Stable, and one relay not Fast.
+        let netdir = testnet::construct_custom_netdir(|idx, bld| {
+            if idx % 2 == 0 {
+                bld.rs.add_flags(RelayFlags::STABLE);
+            }
+            if idx == 31 {
+                bld.rs
+                    .set_flags(RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::EXIT);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let dirinfo = (&netdir).into();
+        let guards: OptDummyGuardMgr<'_> = None;
+        let config = PathConfig::default();
+
+        for _ in 0..1000 {
+            let (path, _, _) = ExitPathBuilder::for_any_exit()
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert!(p.iter().all(|r| r.is_flagged_fast()));
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+
+            let (path, _, _) = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(22)])
+                .require_stability(true)
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert!(p
+                    .iter()
+                    .all(|r| r.is_flagged_fast() && r.is_flagged_stable()));
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+    }
+
+    #[test]
+    fn deprioritize_stale_relays() {
+        // Make the even-numbered relays look like they haven't published a
+        // descriptor in a month.
+        let week = Duration::from_secs(7 * 86400);
+        let netdir = testnet::construct_custom_netdir(|idx, bld| {
+            if idx % 2 == 0 {
+                bld.rs.published(SystemTime::now() - week * 4);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let is_stale = |r: &Relay<'_>| netdir.relay_info_age(r).unwrap() > week;
+        let mut rng = rand::thread_rng();
+        let dirinfo = (&netdir).into();
+        let guards: OptDummyGuardMgr<'_> = None;
+
+        // Return how many of the middles and exits in `n` paths are stale.
+        let mut count_stale = |config: &PathConfig, n| {
+            let mut n_stale = 0;
+            for _ in 0..n {
+                let (path, _, _) = ExitPathBuilder::for_any_exit()
+                    .pick_path(&mut rng, dirinfo, guards, config)
+                    .unwrap();
+                if let TorPathInner::Path(p) = path.inner {
+                    assert_exit_path_ok(&p[..]);
+                    n_stale += p[1..].iter().filter(|r| is_stale(r)).count();
+                } else {
+                    panic!("Generated the wrong kind of path");
+                }
+            }
+            n_stale
+        };
+
+        // By default, we don't care how old a relay's information is, so
+        // about half of our picks are stale.
+        let config = PathConfig::default();
+        assert!(config.stale_relay_info_age().is_none());
+        let n_default = count_stale(&config, 500);
+
+        // Once we deprioritize stale relays, they get a tenth of their
+        // usual weight: we pick them much less often, but not never.
+        let config = PathConfig::builder()
+            .stale_relay_info_age(week)
+            .build()
+            .unwrap();
+        let n_deprioritized = count_stale(&config, 500);
+        assert!(n_deprioritized > 0);
+        assert!(n_deprioritized * 3 < n_default);
+
+        // Only the even-numbered exits allow port 22, so we still use a
+        // stale exit when we need one.
+        for _ in 0..20 {
+            let (path, _, _) = ExitPathBuilder::from_target_ports(vec![TargetPort::ipv4(22)])
+                .pick_path(&mut rng, dirinfo, guards, &config)
+                .unwrap();
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert!(is_stale(&p[2]));
+            } else {
+                panic!("Generated the wrong kind of path");
+            }
+        }
+    }
+
     #[test]
     fn exitpath_with_guards() {
         use tor_guardmgr::GuardStatus;
@@ -473,4 +878,50 @@ mod test {
             assert!(usable.unwrap().await.unwrap());
         });
     }
+
+    #[test]
+    fn exitpath_avoid_busy_guards() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let netdir = testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let mut rng = rand::thread_rng();
+            let dirinfo = (&netdir).into();
+            let statemgr = tor_persist::TestingStateMgr::new();
+            let guards = tor_guardmgr::GuardMgr::new(rt.clone(), statemgr).unwrap();
+            let config = PathConfig::default();
+            guards.update_network(&netdir);
+            let port443 = TargetPort::ipv4(443);
+
+            // Find out which guard we'd use normally.
+            let (path, mon, usable) = ExitPathBuilder::from_target_ports(vec![port443])
+                .pick_path(&mut rng, dirinfo, Some(&guards), &config)
+                .unwrap();
+            let guard_a = match path.inner {
+                TorPathInner::Path(p) => *p[0].ed_identity(),
+                _ => panic!("Wrong kind of path"),
+            };
+            mon.unwrap().succeeded();
+            assert!(usable.unwrap().await.unwrap());
+
+            // If that guard is busy, we spill over to another primary guard.
+            let busy: HashSet<_> = std::iter::once(guard_a).collect();
+            let (path, mon, usable) = ExitPathBuilder::from_target_ports(vec![port443])
+                .avoid_guards(busy)
+                .pick_path(&mut rng, dirinfo, Some(&guards), &config)
+                .unwrap();
+            assert_eq!(path.len(), 3);
+            if let TorPathInner::Path(p) = path.inner {
+                assert_exit_path_ok(&p[..]);
+                assert_ne!(p[0].ed_identity(), &guard_a);
+            } else {
+                panic!("Wrong kind of path");
+            }
+            let usable = usable.unwrap();
+            assert!(usable.is_primary());
+            mon.unwrap().succeeded();
+            assert!(usable.await.unwrap());
+        });
+    }
 }
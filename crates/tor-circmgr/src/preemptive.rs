@@ -12,8 +12,11 @@ pub(crate) struct PreemptiveCircuitPredictor {
     /// A map of every exit port we've observed being used (or `None` if we observed an exit being
     /// used to resolve DNS names instead of building a stream), to the last time we encountered
     /// such usage.
+    ///
+    /// Each port is paired with whether the usage needed a circuit made of
+    /// Stable relays.
     // TODO(nickm): Let's have a mechanism for cleaning this out from time to time.
-    usages: HashMap<Option<TargetPort>, Instant>,
+    usages: HashMap<(Option<TargetPort>, bool), Instant>,
 
     /// Configuration for this predictor.
     config: tor_config::MutCfg<PreemptiveCircuitConfig>,
@@ -25,11 +28,11 @@ impl PreemptiveCircuitPredictor {
         let mut usages = HashMap::new();
         for port in &config.initial_predicted_ports {
             // TODO(nickm) should this be IPv6? Should we have a way to configure IPv6 initial ports?
-            usages.insert(Some(TargetPort::ipv4(*port)), Instant::now());
+            usages.insert((Some(TargetPort::ipv4(*port)), false), Instant::now());
         }
 
         // We want to build circuits for resolving DNS, too.
-        usages.insert(None, Instant::now());
+        usages.insert((None, false), Instant::now());
 
         Self {
             usages,
@@ -69,18 +72,32 @@ impl PreemptiveCircuitPredictor {
                         false
                     })
             })
-            .map(|(&port, _)| TargetCircUsage::Preemptive { port, circs })
+            .map(
+                |(&(port, require_stability), _)| TargetCircUsage::Preemptive {
+                    port,
+                    circs,
+                    require_stability,
+                },
+            )
             .collect()
     }
 
     /// Note the use of a new port at the provided `time`.
     ///
+    /// If `require_stability` is true, the port was used by a stream that
+    /// needed a circuit made of Stable relays.
+    ///
     /// # Limitations
     ///
     /// This function assumes that the `time` values it receives are
     /// monotonically increasing.
-    pub(crate) fn note_usage(&mut self, port: Option<TargetPort>, time: Instant) {
-        self.usages.insert(port, time);
+    pub(crate) fn note_usage(
+        &mut self,
+        port: Option<TargetPort>,
+        require_stability: bool,
+        time: Instant,
+    ) {
+        self.usages.insert((port, require_stability), time);
     }
 }
 
@@ -105,7 +122,8 @@ mod test {
             predictor.predict(),
             vec![TargetCircUsage::Preemptive {
                 port: None,
-                circs: 2
+                circs: 2,
+                require_stability: false,
             }]
         );
 
@@ -124,11 +142,13 @@ mod test {
             vec![
                 TargetCircUsage::Preemptive {
                     port: None,
-                    circs: 2
+                    circs: 2,
+                    require_stability: false,
                 },
                 TargetCircUsage::Preemptive {
                     port: Some(TargetPort::ipv4(80)),
-                    circs: 2
+                    circs: 2,
+                    require_stability: false,
                 },
             ]
         );
@@ -147,11 +167,12 @@ mod test {
             predictor.predict(),
             vec![TargetCircUsage::Preemptive {
                 port: None,
-                circs: 2
+                circs: 2,
+                require_stability: false,
             }]
         );
 
-        predictor.note_usage(Some(TargetPort::ipv4(1234)), Instant::now());
+        predictor.note_usage(Some(TargetPort::ipv4(1234)), false, Instant::now());
 
         let mut results = predictor.predict();
         results.sort();
@@ -160,11 +181,13 @@ mod test {
             vec![
                 TargetCircUsage::Preemptive {
                     port: None,
-                    circs: 2
+                    circs: 2,
+                    require_stability: false,
                 },
                 TargetCircUsage::Preemptive {
                     port: Some(TargetPort::ipv4(1234)),
-                    circs: 2
+                    circs: 2,
+                    require_stability: false,
                 }
             ]
         );
@@ -180,14 +203,45 @@ mod test {
         let mut predictor = PreemptiveCircuitPredictor::new(cfg);
         let more_than_an_hour_ago = Instant::now() - Duration::from_secs(60 * 60 + 1);
 
-        predictor.note_usage(Some(TargetPort::ipv4(2345)), more_than_an_hour_ago);
+        predictor.note_usage(Some(TargetPort::ipv4(2345)), false, more_than_an_hour_ago);
 
         assert_eq!(
             predictor.predict(),
             vec![TargetCircUsage::Preemptive {
                 port: None,
-                circs: 2
+                circs: 2,
+                require_stability: false,
             }]
         );
     }
+
+    #[test]
+    fn predicts_stable_ports() {
+        let cfg = PreemptiveCircuitConfig::builder()
+            .initial_predicted_ports(vec![])
+            .prediction_lifetime(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        let mut predictor = PreemptiveCircuitPredictor::new(cfg);
+
+        predictor.note_usage(Some(TargetPort::ipv4(22)), true, Instant::now());
+
+        let mut results = predictor.predict();
+        results.sort();
+        assert_eq!(
+            results,
+            vec![
+                TargetCircUsage::Preemptive {
+                    port: None,
+                    circs: 2,
+                    require_stability: false,
+                },
+                TargetCircUsage::Preemptive {
+                    port: Some(TargetPort::ipv4(22)),
+                    circs: 2,
+                    require_stability: true,
+                }
+            ]
+        );
+    }
 }
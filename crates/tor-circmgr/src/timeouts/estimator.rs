@@ -5,7 +5,8 @@ use crate::timeouts::{
     readonly::ReadonlyTimeoutEstimator,
     Action, TimeoutEstimator,
 };
-use crate::TimeoutStateHandle;
+use crate::{TimeoutJournalHandle, TimeoutStateHandle};
+use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 use std::time::Duration;
 use tor_netdir::params::NetParameters;
@@ -16,6 +17,45 @@ use tracing::{debug, warn};
 pub(crate) struct Estimator {
     /// The estimator we're currently using.
     inner: Mutex<Box<dyn TimeoutEstimator + Send + 'static>>,
+    /// A journal in which to record observations that we haven't saved yet,
+    /// if we have one.
+    journal: Option<TimeoutJournalHandle>,
+}
+
+/// An observation that affects our timeout state, recorded in a journal so
+/// that it survives a crash before we next save that state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum TimeoutJournalRecord {
+    /// A circuit hop completed.  See [`Estimator::note_hop_completed`].
+    HopCompleted {
+        /// The zero-indexed hop that completed.
+        hop: u8,
+        /// How long after launching the circuit the hop completed.
+        delay: Duration,
+        /// True if this was the last hop of the circuit.
+        is_last: bool,
+    },
+    /// A circuit timed out.  See [`Estimator::note_circ_timeout`].
+    CircTimeout {
+        /// The number of hops that completed.
+        hop: u8,
+        /// How long after launching the circuit we gave up on it.
+        delay: Duration,
+    },
+}
+
+impl TimeoutJournalRecord {
+    /// Tell `est` about the observation in this record.
+    fn apply_to(&self, est: &mut dyn TimeoutEstimator) {
+        match *self {
+            TimeoutJournalRecord::HopCompleted {
+                hop,
+                delay,
+                is_last,
+            } => est.note_hop_completed(hop, delay, is_last),
+            TimeoutJournalRecord::CircTimeout { hop, delay } => est.note_circ_timeout(hop, delay),
+        }
+    }
 }
 
 impl Estimator {
@@ -24,22 +64,28 @@ impl Estimator {
     pub(crate) fn new(est: impl TimeoutEstimator + Send + 'static) -> Self {
         Self {
             inner: Mutex::new(Box::new(est)),
+            journal: None,
         }
     }
 
-    /// Create this estimator based on the values stored in `storage`, and whether
-    /// this storage is read-only.
-    pub(crate) fn from_storage(storage: &TimeoutStateHandle) -> Self {
-        let (_, est) = estimator_from_storage(storage);
+    /// Create this estimator based on the values stored in `storage` (and
+    /// any observations recorded in `journal` since they were stored), and
+    /// whether this storage is read-only.
+    pub(crate) fn from_storage(
+        storage: &TimeoutStateHandle,
+        journal: TimeoutJournalHandle,
+    ) -> Self {
+        let (_, est) = estimator_from_storage(storage, Some(&journal));
         Self {
             inner: Mutex::new(est),
+            journal: Some(journal),
         }
     }
 
     /// Assuming that we can read and write to `storage`, replace our state with
     /// a new state that estimates timeouts.
     pub(crate) fn upgrade_to_owning_storage(&self, storage: &TimeoutStateHandle) {
-        let (readonly, est) = estimator_from_storage(storage);
+        let (readonly, est) = estimator_from_storage(storage, self.journal.as_ref());
         if readonly {
             warn!("Unable to upgrade to owned persistent storage.");
             return;
@@ -51,6 +97,7 @@ impl Estimator {
     /// based on the contents of `storage`.
     pub(crate) fn reload_readonly_from_storage(&self, storage: &TimeoutStateHandle) {
         if let Ok(Some(v)) = storage.load() {
+            let v = replay_journal(v, self.journal.as_ref());
             let est = ReadonlyTimeoutEstimator::from_state(&v);
             *self.inner.lock().expect("Timeout estimator lock poisoned") = Box::new(est);
         } else {
@@ -58,6 +105,19 @@ impl Estimator {
         }
     }
 
+    /// Append `rec` to our journal, if we have one and are allowed to write
+    /// to it.
+    fn journal(&self, rec: &TimeoutJournalRecord) {
+        if let Some(journal) = &self.journal {
+            if !journal.can_store() {
+                return;
+            }
+            if let Err(e) = journal.append(rec) {
+                warn!("Unable to record circuit timeout observation: {}", e);
+            }
+        }
+    }
+
     /// Record that a given circuit hop has completed.
     ///
     /// The `hop` number is a zero-indexed value for which hop just completed.
@@ -67,9 +127,15 @@ impl Estimator {
     ///
     /// If this is the last hop of the circuit, then `is_last` is true.
     pub(crate) fn note_hop_completed(&self, hop: u8, delay: Duration, is_last: bool) {
-        let mut inner = self.inner.lock().expect("Timeout estimator lock poisoned.");
-
-        inner.note_hop_completed(hop, delay, is_last);
+        {
+            let mut inner = self.inner.lock().expect("Timeout estimator lock poisoned.");
+            inner.note_hop_completed(hop, delay, is_last);
+        }
+        self.journal(&TimeoutJournalRecord::HopCompleted {
+            hop,
+            delay,
+            is_last,
+        });
     }
 
     /// Record that a circuit failed to complete because it took too long.
@@ -80,8 +146,11 @@ impl Estimator {
     /// The `delay` number is the amount of time after we first launched the
     /// circuit.
     pub(crate) fn note_circ_timeout(&self, hop: u8, delay: Duration) {
-        let mut inner = self.inner.lock().expect("Timeout estimator lock poisoned.");
-        inner.note_circ_timeout(hop, delay);
+        {
+            let mut inner = self.inner.lock().expect("Timeout estimator lock poisoned.");
+            inner.note_circ_timeout(hop, delay);
+        }
+        self.journal(&TimeoutJournalRecord::CircTimeout { hop, delay });
     }
 
     /// Return the current estimation for how long we should wait for a given
@@ -127,11 +196,12 @@ impl Estimator {
 }
 
 /// Try to construct a new boxed TimeoutEstimator based on the contents of
-/// storage, and whether it is read-only.
+/// storage and journal, and whether it is read-only.
 ///
 /// Returns true on a read-only state.
 fn estimator_from_storage(
     storage: &TimeoutStateHandle,
+    journal: Option<&TimeoutJournalHandle>,
 ) -> (bool, Box<dyn TimeoutEstimator + Send + 'static>) {
     let state = match storage.load() {
         Ok(Some(v)) => v,
@@ -141,6 +211,7 @@ fn estimator_from_storage(
             return (true, Box::new(ReadonlyTimeoutEstimator::new()));
         }
     };
+    let state = replay_journal(state, journal);
 
     if storage.can_store() {
         // We own the lock, so we're going to use a full estimator.
@@ -150,6 +221,30 @@ fn estimator_from_storage(
     }
 }
 
+/// Return a new timeout state based on `state`, with every observation in
+/// `journal` applied to it.
+fn replay_journal(
+    state: ParetoTimeoutState,
+    journal: Option<&TimeoutJournalHandle>,
+) -> ParetoTimeoutState {
+    let records = match journal.map(|j| j.load()).transpose() {
+        Ok(records) => records.unwrap_or_default(),
+        Err(e) => {
+            warn!("Unable to load circuit timeout journal: {}", e);
+            return state;
+        }
+    };
+    if records.is_empty() {
+        return state;
+    }
+    debug!("Replaying {} circuit timeout observations.", records.len());
+    let mut est = ParetoTimeoutEstimator::from_state(state.clone());
+    for rec in &records {
+        rec.apply_to(&mut est);
+    }
+    est.build_state().unwrap_or(state)
+}
+
 #[cfg(test)]
 mod test {
     #![allow(clippy::unwrap_used)]
@@ -164,8 +259,9 @@ mod test {
         let storage = tor_persist::TestingStateMgr::new();
         assert!(storage.try_lock().unwrap().held());
         let handle = storage.clone().create_handle("paretorama");
+        let journal = storage.clone().create_journal_handle("paretorama");
 
-        let est = Estimator::from_storage(&handle);
+        let est = Estimator::from_storage(&handle, journal);
         assert!(est.learning_timeouts());
         est.save_state(&handle).unwrap();
 
@@ -174,8 +270,9 @@ mod test {
         let storage2 = storage.new_manager();
         assert!(!storage2.try_lock().unwrap().held());
         let handle2 = storage2.clone().create_handle("paretorama");
+        let journal2 = storage2.clone().create_journal_handle("paretorama");
 
-        let est2 = Estimator::from_storage(&handle2);
+        let est2 = Estimator::from_storage(&handle2, journal2);
         assert!(!est2.learning_timeouts());
 
         est.update_params(&params);
@@ -235,4 +332,48 @@ mod test {
         let to_3 = est2.timeouts(&act);
         assert!(to_3.0 < to_2.0);
     }
+
+    #[test]
+    fn replay_estimator_journal() {
+        let params = NetParameters::default();
+        let act = Action::BuildCircuit { length: 3 };
+
+        let storage = tor_persist::TestingStateMgr::new();
+        assert!(storage.try_lock().unwrap().held());
+        let handle = storage.clone().create_handle("paretorama");
+        let journal = storage.clone().create_journal_handle("paretorama");
+        let est = Estimator::from_storage(&handle, journal);
+        est.update_params(&params);
+        est.save_state(&handle).unwrap();
+
+        // Get some observations, but "crash" before saving them.
+        for _ in 0..500 {
+            est.note_hop_completed(2, Duration::from_secs(7), true);
+            est.note_hop_completed(2, Duration::from_secs(2), true);
+        }
+        let to_1 = est.timeouts(&act);
+        drop(est);
+
+        // A new estimator built from the same storage gets nearly the same
+        // answer.  (Not exactly the same, since replaying goes through the
+        // state's histogram, which loses the order of the observations.)
+        let journal = storage.clone().create_journal_handle("paretorama");
+        let est2 = Estimator::from_storage(&handle, journal);
+        est2.update_params(&params);
+        assert!(!est2.learning_timeouts());
+        let to_2 = est2.timeouts(&act);
+        assert!(to_2.0 > to_1.0 - Duration::from_secs(1));
+        assert!(to_2.0 < to_1.0 + Duration::from_secs(1));
+
+        // Once the state is saved, the journal is gone, but the
+        // observations are still there.
+        est2.save_state(&handle).unwrap();
+        let records: Vec<TimeoutJournalRecord> = storage.journal_load("paretorama").unwrap();
+        assert!(records.is_empty());
+        let journal = storage.clone().create_journal_handle("paretorama");
+        let est3 = Estimator::from_storage(&handle, journal);
+        est3.update_params(&params);
+        let to_3 = est3.timeouts(&act);
+        assert!((to_2.0.as_secs_f64() - to_3.0.as_secs_f64()).abs() < 0.001);
+    }
 }
@@ -2,6 +2,7 @@
 
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fmt::{self, Display};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -10,12 +11,14 @@ use tracing::debug;
 
 use crate::path::{dirpath::DirPathBuilder, exitpath::ExitPathBuilder, TorPath};
 use tor_guardmgr::{GuardMgr, GuardMonitor, GuardUsable};
+use tor_linkspec::OwnedCircTarget;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
 use tor_netdir::Relay;
 use tor_netdoc::types::policy::PortPolicy;
 use tor_rtcompat::Runtime;
 
 use crate::mgr::{abstract_spec_find_supported, AbstractCirc, OpenEntry};
-use crate::Result;
+use crate::{Error, Result};
 
 /// An exit policy, as supported by the last hop of a circuit.
 #[derive(Clone, Debug, PartialEq)]
@@ -262,6 +265,9 @@ pub(crate) enum TargetCircUsage {
         ports: Vec<TargetPort>,
         /// Isolation group the circuit shall be part of
         isolation: StreamIsolation,
+        /// If true, every relay on the circuit must have the Stable flag,
+        /// since it will carry long-lived streams.
+        require_stability: bool,
     },
     /// For a circuit is only used for the purpose of building it.
     TimeoutTesting,
@@ -279,6 +285,8 @@ pub(crate) enum TargetCircUsage {
         port: Option<TargetPort>,
         /// The number of exit circuits needed for a port
         circs: usize,
+        /// If true, the circuits need to be usable for long-lived streams.
+        require_stability: bool,
     },
 }
 
@@ -297,20 +305,37 @@ pub(crate) enum SupportedCircUsage {
         /// Isolation group the circuit is part of. None when the circuit is not yet assigned to an
         /// isolation group.
         isolation: Option<StreamIsolation>,
+        /// True if every relay on the circuit has the Stable flag.
+        all_relays_stable: bool,
     },
     /// This circuit is not suitable for any usage.
     NoUsage,
 }
 
 impl TargetCircUsage {
+    /// Return true if circuits used for this purpose will carry user
+    /// streams (as opposed to directory streams or no streams at all).
+    pub(crate) fn carries_user_streams(&self) -> bool {
+        matches!(
+            self,
+            TargetCircUsage::Exit { .. } | TargetCircUsage::Preemptive { .. }
+        )
+    }
+
     /// Construct path for a given circuit purpose; return it and the
     /// usage that it _actually_ supports.
+    ///
+    /// If possible, don't use any guard in `avoid_guards`.  If `config`
+    /// has bridges, build multi-hop paths through one of `bridges`: the
+    /// configured bridges whose descriptors we know.
     pub(crate) fn build_path<'a, R: Rng, RT: Runtime>(
         &self,
         rng: &mut R,
         netdir: crate::DirInfo<'a>,
         guards: Option<&GuardMgr<RT>>,
         config: &crate::PathConfig,
+        avoid_guards: &HashSet<Ed25519Identity>,
+        bridges: &[OwnedCircTarget],
     ) -> Result<(
         TorPath<'a>,
         SupportedCircUsage,
@@ -319,12 +344,22 @@ impl TargetCircUsage {
     )> {
         match self {
             TargetCircUsage::Dir => {
-                let (path, mon, usable) = DirPathBuilder::new().pick_path(rng, netdir, guards)?;
+                let (path, mon, usable) = DirPathBuilder::new()
+                    .avoid_guards(avoid_guards.clone())
+                    .bridges(config.bridges().to_vec())
+                    .pick_path(rng, netdir, guards)?;
                 Ok((path, SupportedCircUsage::Dir, mon, usable))
             }
-            TargetCircUsage::Preemptive { port, .. } => {
+            TargetCircUsage::Preemptive {
+                port,
+                require_stability,
+                ..
+            } => {
                 // FIXME(eta): this is copypasta from `TargetCircUsage::Exit`.
                 let (path, mon, usable) = ExitPathBuilder::from_target_ports(port.iter().copied())
+                    .avoid_guards(avoid_guards.clone())
+                    .bridges(bridges.to_vec())
+                    .require_stability(*require_stability)
                     .pick_path(rng, netdir, guards, config)?;
                 let policy = path
                     .exit_policy()
@@ -334,6 +369,7 @@ impl TargetCircUsage {
                     SupportedCircUsage::Exit {
                         policy,
                         isolation: None,
+                        all_relays_stable: *require_stability,
                     },
                     mon,
                     usable,
@@ -342,8 +378,12 @@ impl TargetCircUsage {
             TargetCircUsage::Exit {
                 ports: p,
                 isolation,
+                require_stability,
             } => {
                 let (path, mon, usable) = ExitPathBuilder::from_target_ports(p.clone())
+                    .avoid_guards(avoid_guards.clone())
+                    .bridges(bridges.to_vec())
+                    .require_stability(*require_stability)
                     .pick_path(rng, netdir, guards, config)?;
                 let policy = path
                     .exit_policy()
@@ -353,6 +393,7 @@ impl TargetCircUsage {
                     SupportedCircUsage::Exit {
                         policy,
                         isolation: Some(*isolation),
+                        all_relays_stable: *require_stability,
                     },
                     mon,
                     usable,
@@ -360,12 +401,15 @@ impl TargetCircUsage {
             }
             TargetCircUsage::TimeoutTesting => {
                 let (path, mon, usable) = ExitPathBuilder::for_timeout_testing()
+                    .avoid_guards(avoid_guards.clone())
+                    .bridges(bridges.to_vec())
                     .pick_path(rng, netdir, guards, config)?;
                 let policy = path.exit_policy();
                 let usage = match policy {
                     Some(policy) if policy.allows_some_port() => SupportedCircUsage::Exit {
                         policy,
                         isolation: None,
+                        all_relays_stable: false,
                     },
                     _ => SupportedCircUsage::NoUsage,
                 };
@@ -376,6 +420,15 @@ impl TargetCircUsage {
     }
 }
 
+impl SupportedCircUsage {
+    /// Return true if a circuit with this usage may carry user streams.
+    ///
+    /// This is never true for one-hop directory circuits.
+    pub(crate) fn allows_user_streams(&self) -> bool {
+        matches!(self, SupportedCircUsage::Exit { .. })
+    }
+}
+
 impl crate::mgr::AbstractSpec for SupportedCircUsage {
     type Usage = TargetCircUsage;
 
@@ -387,21 +440,38 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
                 Exit {
                     policy: p1,
                     isolation: i1,
+                    all_relays_stable,
                 },
                 TargetCircUsage::Exit {
                     ports: p2,
                     isolation: i2,
+                    require_stability,
                 },
             ) => {
                 i1.map(|i1| i1.may_share_circuit(i2)).unwrap_or(true)
                     && p2.iter().all(|port| p1.allows_port(*port))
+                    && (*all_relays_stable || !require_stability)
             }
-            (Exit { policy, isolation }, TargetCircUsage::Preemptive { port, .. }) => {
+            (
+                Exit {
+                    policy,
+                    isolation,
+                    all_relays_stable,
+                },
+                TargetCircUsage::Preemptive {
+                    port,
+                    require_stability,
+                    ..
+                },
+            ) => {
                 if isolation.is_some() {
                     // If the circuit has a stream isolation token, we might not be able to use it
                     // for new streams that don't share it.
                     return false;
                 }
+                if *require_stability && !all_relays_stable {
+                    return false;
+                }
                 if let Some(p) = port {
                     policy.allows_port(*p)
                 } else {
@@ -416,6 +486,15 @@ impl crate::mgr::AbstractSpec for SupportedCircUsage {
     fn restrict_mut(&mut self, usage: &TargetCircUsage) -> Result<()> {
         use SupportedCircUsage::*;
 
+        if usage.carries_user_streams() && !self.allows_user_streams() {
+            // Checked first, so that no later change to the matching below
+            // can ever put user streams on (say) a directory circuit.
+            return Err(Error::UsageNotAllowed(format!(
+                "{:?} circuit can't carry user streams",
+                self
+            )));
+        }
+
         match (self, usage) {
             (Dir, TargetCircUsage::Dir) => Ok(()),
             // This usage is only used to create circuits preemptively, and doesn't actually
@@ -561,36 +640,44 @@ mod test {
         let supp_exit = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation),
+            all_relays_stable: false,
         };
         let supp_exit_iso2 = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation2),
+            all_relays_stable: false,
         };
         let supp_exit_no_iso = SupportedCircUsage::Exit {
             policy,
             isolation: None,
+            all_relays_stable: false,
         };
         let supp_none = SupportedCircUsage::NoUsage;
 
         let targ_80_v4 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation,
+            require_stability: false,
         };
         let targ_80_v4_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
+            require_stability: false,
         };
         let targ_80_23_v4 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv4(23)],
             isolation,
+            require_stability: false,
         };
         let targ_80_23_mixed = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80), TargetPort::ipv6(23)],
             isolation,
+            require_stability: false,
         };
         let targ_999_v6 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv6(999)],
             isolation,
+            require_stability: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
 
@@ -617,6 +704,57 @@ mod test {
         assert!(supp_none.supports(&targ_testing));
     }
 
+    #[test]
+    fn exit_stability() {
+        use crate::mgr::AbstractSpec;
+        let policy = ExitPolicy {
+            v4: Arc::new("accept 22,80".parse().unwrap()),
+            v6: Arc::new("reject 1-65535".parse().unwrap()),
+        };
+        let isolation = StreamIsolation::no_isolation();
+
+        let supp_unstable = SupportedCircUsage::Exit {
+            policy: policy.clone(),
+            isolation: None,
+            all_relays_stable: false,
+        };
+        let supp_stable = SupportedCircUsage::Exit {
+            policy,
+            isolation: None,
+            all_relays_stable: true,
+        };
+
+        let targ_22 = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(22)],
+            isolation,
+            require_stability: false,
+        };
+        let targ_22_stable = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(22)],
+            isolation,
+            require_stability: true,
+        };
+        let targ_pre = TargetCircUsage::Preemptive {
+            port: Some(TargetPort::ipv4(22)),
+            circs: 1,
+            require_stability: false,
+        };
+        let targ_pre_stable = TargetCircUsage::Preemptive {
+            port: Some(TargetPort::ipv4(22)),
+            circs: 1,
+            require_stability: true,
+        };
+
+        assert!(supp_unstable.supports(&targ_22));
+        assert!(!supp_unstable.supports(&targ_22_stable));
+        assert!(supp_unstable.supports(&targ_pre));
+        assert!(!supp_unstable.supports(&targ_pre_stable));
+        assert!(supp_stable.supports(&targ_22));
+        assert!(supp_stable.supports(&targ_22_stable));
+        assert!(supp_stable.supports(&targ_pre));
+        assert!(supp_stable.supports(&targ_pre_stable));
+    }
+
     #[test]
     fn restrict_mut() {
         use crate::mgr::AbstractSpec;
@@ -642,23 +780,28 @@ mod test {
         let supp_exit = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation),
+            all_relays_stable: false,
         };
         let supp_exit_iso2 = SupportedCircUsage::Exit {
             policy: policy.clone(),
             isolation: Some(isolation2),
+            all_relays_stable: false,
         };
         let supp_exit_no_iso = SupportedCircUsage::Exit {
             policy,
             isolation: None,
+            all_relays_stable: false,
         };
         let supp_none = SupportedCircUsage::NoUsage;
         let targ_exit = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation,
+            require_stability: false,
         };
         let targ_exit_iso2 = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(80)],
             isolation: isolation2,
+            require_stability: false,
         };
         let targ_testing = TargetCircUsage::TimeoutTesting;
 
@@ -715,6 +858,43 @@ mod test {
         assert!(supp_exit_no_iso_c.supports(&targ_exit_iso2));
     }
 
+    #[test]
+    fn no_user_streams_on_dir_circs() {
+        use crate::mgr::AbstractSpec;
+
+        let targ_exit = TargetCircUsage::Exit {
+            ports: vec![TargetPort::ipv4(80)],
+            isolation: StreamIsolation::no_isolation(),
+            require_stability: false,
+        };
+        let targ_exit_any = TargetCircUsage::Exit {
+            ports: vec![],
+            isolation: StreamIsolation::no_isolation(),
+            require_stability: false,
+        };
+        let targ_pre = TargetCircUsage::Preemptive {
+            port: None,
+            circs: 1,
+            require_stability: false,
+        };
+        assert!(!TargetCircUsage::Dir.carries_user_streams());
+        assert!(!TargetCircUsage::TimeoutTesting.carries_user_streams());
+
+        for supp in [SupportedCircUsage::Dir, SupportedCircUsage::NoUsage] {
+            assert!(!supp.allows_user_streams());
+            for targ in [&targ_exit, &targ_exit_any, &targ_pre] {
+                assert!(targ.carries_user_streams());
+                assert!(!supp.supports(targ));
+                let mut supp_c = supp.clone();
+                assert!(matches!(
+                    supp_c.restrict_mut(targ),
+                    Err(Error::UsageNotAllowed(_))
+                ));
+                assert_eq!(supp_c, supp);
+            }
+        }
+    }
+
     #[test]
     fn buildpath() {
         use crate::mgr::AbstractSpec;
@@ -733,7 +913,7 @@ mod test {
 
         // First, a one-hop directory circuit
         let (p_dir, u_dir, _, _) = TargetCircUsage::Dir
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &HashSet::new(), &[])
             .unwrap();
         assert!(matches!(u_dir, SupportedCircUsage::Dir));
         assert_eq!(p_dir.len(), 1);
@@ -748,9 +928,10 @@ mod test {
         let exit_usage = TargetCircUsage::Exit {
             ports: vec![TargetPort::ipv4(995)],
             isolation,
+            require_stability: false,
         };
         let (p_exit, u_exit, _, _) = exit_usage
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &HashSet::new(), &[])
             .unwrap();
         assert!(matches!(
             u_exit,
@@ -764,11 +945,11 @@ mod test {
 
         // Now try testing circuits.
         let (path, usage, _, _) = TargetCircUsage::TimeoutTesting
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &HashSet::new(), &[])
             .unwrap();
         let path = match OwnedPath::try_from(&path).unwrap() {
-            OwnedPath::ChannelOnly(_) => panic!("Impossible path type."),
             OwnedPath::Normal(p) => p,
+            _ => panic!("Impossible path type."),
         };
         assert_eq!(path.len(), 3);
 
@@ -783,7 +964,8 @@ mod test {
             usage,
             SupportedCircUsage::Exit {
                 policy,
-                isolation: None
+                isolation: None,
+                all_relays_stable: false,
             }
         );
     }
@@ -804,7 +986,7 @@ mod test {
         let guards: OptDummyGuardMgr<'_> = None;
 
         let (path, usage, _, _) = TargetCircUsage::TimeoutTesting
-            .build_path(&mut rng, di, guards, &config)
+            .build_path(&mut rng, di, guards, &config, &HashSet::new(), &[])
             .unwrap();
         assert_eq!(path.len(), 3);
         assert_eq!(usage, SupportedCircUsage::NoUsage);
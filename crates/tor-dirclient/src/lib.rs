@@ -72,7 +72,7 @@ use async_compression::futures::bufread::ZstdDecoder;
 use futures::io::{
     AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
 };
-use futures::FutureExt;
+use futures::{Future, FutureExt};
 use memchr::memchr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -108,27 +108,55 @@ where
     SP: SleepProvider,
 {
     let circuit = circ_mgr.get_or_launch_dir(dirinfo).await?;
-
-    // TODO(nickm) This should be an option, and is too long.
-    let begin_timeout = Duration::from_secs(5);
     let source = SourceInfo::new(circuit.unique_id());
 
-    // Launch the stream.
-    let mut stream = runtime
-        .timeout(begin_timeout, circuit.begin_dir_stream())
-        .await??; // TODO(nickm) handle fatalities here too
-
-    // TODO: Perhaps we want separate timeouts for each phase of this.
-    // For now, we just use higher-level timeouts in `dirmgr`.
-    let r = download(runtime, req, &mut stream, Some(source.clone())).await;
-
+    let r = begin_and_download(
+        runtime,
+        req,
+        circuit.begin_dir_stream(),
+        Some(source.clone()),
+    )
+    .await;
+
+    // If anything went wrong on this circuit, whether we couldn't open the
+    // stream or the cache at the end of it gave us a bad answer, stop using
+    // it, so that the retry logic in our caller gets a different cache next
+    // time.
     if should_retire_circ(&r) {
-        retire_circ(&circ_mgr, &source, "Partial response");
+        match &r {
+            Err(e) => retire_circ(&circ_mgr, &source, e),
+            Ok(_) => retire_circ(&circ_mgr, &source, "Partial response"),
+        }
     }
 
     r
 }
 
+/// Open a directory stream by waiting for `begin`, then use it to fetch the
+/// resource described by `req`.
+///
+/// Gives an error if the stream can't be opened in time.
+async fn begin_and_download<CR, S, SP, F>(
+    runtime: &SP,
+    req: &CR,
+    begin: F,
+    source: Option<SourceInfo>,
+) -> Result<DirResponse>
+where
+    CR: request::Requestable + ?Sized,
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    SP: SleepProvider,
+    F: Future<Output = tor_proto::Result<S>>,
+{
+    // TODO(nickm) This should be an option, and is too long.
+    let begin_timeout = Duration::from_secs(5);
+    let mut stream = runtime.timeout(begin_timeout, begin).await??;
+
+    // TODO: Perhaps we want separate timeouts for each phase of this.
+    // For now, we just use higher-level timeouts in `dirmgr`.
+    download(runtime, req, &mut stream, source).await
+}
+
 /// Return true if `result` holds an error indicating that we should retire the
 /// circuit used for the corresponding request.
 fn should_retire_circ(result: &Result<DirResponse>) -> bool {
@@ -663,6 +691,31 @@ mod test {
         })
     }
 
+    #[test]
+    fn retire_after_failed_begin() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async move {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let req: request::MicrodescRequest = vec![[9; 32]].into_iter().collect();
+
+            // If the stream can't be opened, we give up on the circuit.
+            let begin =
+                async { Err::<tor_rtmock::io::LocalStream, _>(tor_proto::Error::CircuitClosed) };
+            let r = begin_and_download(&rt, &req, begin, None).await;
+            assert!(matches!(r, Err(Error::Proto(_))));
+            assert!(should_retire_circ(&r));
+
+            // Same if it takes too long to open.
+            let begin =
+                futures::future::pending::<tor_proto::Result<tor_rtmock::io::LocalStream>>();
+            let (r, ()) = futures::join!(
+                begin_and_download(&rt, &req, begin, None),
+                rt.advance(Duration::from_secs(10)),
+            );
+            assert!(matches!(r, Err(Error::DirTimeout)));
+            assert!(should_retire_circ(&r));
+        });
+    }
+
     #[test]
     fn test_download() -> Result<()> {
         let req: request::MicrodescRequest = vec![[9; 32]].into_iter().collect();
@@ -47,8 +47,6 @@ pub struct ConsensusRequest {
     last_consensus_published: Option<SystemTime>,
     /// A set of SHA3-256 digests of the _signed portion_ of consensuses we have.
     /// Used to declare what diffs we would accept.
-    ///
-    /// (Currently we don't send this, since we can't handle diffs.)
     last_consensus_sha3_256: Vec<[u8; 32]>,
 }
 
@@ -75,6 +73,12 @@ impl ConsensusRequest {
         self.last_consensus_sha3_256.push(d);
     }
 
+    /// Remove every consensus digest from this request, so that it asks
+    /// for a full consensus rather than a diff.
+    pub fn clear_old_consensus_digests(&mut self) {
+        self.last_consensus_sha3_256.clear();
+    }
+
     /// Set the publication time we should say we have for our last
     /// consensus to `when`.
     pub fn set_last_consensus_date(&mut self, when: SystemTime) {
@@ -511,6 +515,14 @@ mod test {
         assert_eq!(req,
                    format!("GET /tor/status-vote/current/consensus-microdesc/03479e93ebf3ff2c58c1c9dbf2de9de9c2801b3e.z HTTP/1.0\r\naccept-encoding: {}\r\nif-modified-since: {}\r\nx-or-diff-from-consensus: 626c616820626c616820626c616820313220626c616820626c616820626c6168\r\n\r\n", encodings(), when));
 
+        // Without any old digests, we don't ask for a diff.
+        let mut req = ConsensusRequest::default();
+        req.push_old_consensus_digest(*d2);
+        req.clear_old_consensus_digests();
+        assert_eq!(req.old_consensus_digests().next(), None);
+        let req = crate::util::encode_request(&req.make_request()?);
+        assert!(!req.contains("x-or-diff-from-consensus"));
+
         Ok(())
     }
 
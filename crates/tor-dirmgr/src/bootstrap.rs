@@ -1,27 +1,33 @@
 //! Functions to download or load directory objects, using the
 //! state machines in the `states` module.
 
-use std::{
-    collections::HashMap,
-    sync::{Arc, Weak},
-    time::{Duration, SystemTime},
-};
-
-use crate::{
-    docid::{self, ClientRequest},
-    upgrade_weak_ref, DirMgr, DirState, DocId, DocumentText, Error, Readiness, Result,
-};
-
-use futures::channel::oneshot;
-use futures::FutureExt;
-use futures::StreamExt;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{docid, DirMgr, DirState, DocId, DocumentText, Result};
+
+use tor_rtcompat::Runtime;
+use tracing::trace;
+
+#[cfg(feature = "dirfetch")]
+use crate::{docid::ClientRequest, upgrade_weak_ref, Error, Readiness};
+#[cfg(feature = "dirfetch")]
+use futures::{channel::oneshot, FutureExt, StreamExt};
+#[cfg(feature = "dirfetch")]
+use std::collections::{HashSet, VecDeque};
+#[cfg(feature = "dirfetch")]
+use std::sync::Weak;
+#[cfg(feature = "dirfetch")]
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "dirfetch")]
 use tor_dirclient::DirResponse;
-use tor_rtcompat::{Runtime, SleepProviderExt};
-use tracing::{info, trace, warn};
+#[cfg(feature = "dirfetch")]
+use tor_rtcompat::SleepProviderExt;
+#[cfg(feature = "dirfetch")]
+use tracing::{debug, info, warn};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "dirfetch"))]
 use once_cell::sync::Lazy;
-#[cfg(test)]
+#[cfg(all(test, feature = "dirfetch"))]
 use std::sync::Mutex;
 
 /// Try to read a set of documents from `dirmgr` by ID.
@@ -41,10 +47,11 @@ fn load_all<R: Runtime>(
 ///
 /// Note that only one test uses this: otherwise there would be a race
 /// condition. :p
-#[cfg(test)]
+#[cfg(all(test, feature = "dirfetch"))]
 static CANNED_RESPONSE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
 
 /// Launch a single client request and get an associated response.
+#[cfg(feature = "dirfetch")]
 async fn fetch_single<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     request: ClientRequest,
@@ -74,6 +81,7 @@ async fn fetch_single<R: Runtime>(
 /// `missing`, and return each request along with the response it received.
 ///
 /// Don't launch more than `parallelism` requests at once.
+#[cfg(feature = "dirfetch")]
 async fn fetch_multiple<R: Runtime>(
     dirmgr: Arc<DirMgr<R>>,
     missing: Vec<DocId>,
@@ -104,6 +112,9 @@ async fn fetch_multiple<R: Runtime>(
                         "cache declined request; reported status {:?}",
                         response.status_code()
                     );
+                    if should_try_another_cache(response.status_code()) {
+                        retire_source(&dirmgr, &response);
+                    }
                 }
             }
             Err(e) => warn!("error while downloading: {:?}", e),
@@ -113,6 +124,33 @@ async fn fetch_multiple<R: Runtime>(
     Ok(useful_responses)
 }
 
+/// Return true if a directory cache's answer with HTTP status `status` means
+/// that we should ask a different cache next time.
+///
+/// A cache that says it doesn't have a document (404), or that is too busy
+/// to answer (503), may well say the same thing again, so we shouldn't keep
+/// asking it.  A "304 Not Modified" only tells us that we already have the
+/// latest document, so there's nothing wrong with the cache.
+#[cfg(feature = "dirfetch")]
+fn should_try_another_cache(status: u16) -> bool {
+    !matches!(status, 200 | 304)
+}
+
+/// Stop using the circuit on which we got `response`, so that our next
+/// request goes to a different directory cache.
+#[cfg(feature = "dirfetch")]
+fn retire_source<R: Runtime>(dirmgr: &DirMgr<R>, response: &DirResponse) {
+    if let (Some(source), Ok(circmgr)) = (response.source(), dirmgr.circmgr()) {
+        let id = source.unique_circ_id();
+        debug!(
+            "{}: Retiring circuit after cache declined request with status {}",
+            id,
+            response.status_code()
+        );
+        circmgr.retire_circ(id);
+    }
+}
+
 /// Try tp update `state` by loading cached information from `dirmgr`.
 /// Return true if anything changed.
 async fn load_once<R: Runtime>(
@@ -170,6 +208,23 @@ pub(crate) async fn load<R: Runtime>(
     Ok(state)
 }
 
+/// If `request` asked for a consensus diff, return a request for the same
+/// consensus in full.
+///
+/// We use this to fall back to a full download when a diff can't be
+/// applied.
+#[cfg(feature = "dirfetch")]
+fn full_consensus_request(request: &ClientRequest) -> Option<ClientRequest> {
+    match request {
+        ClientRequest::Consensus(r) if r.old_consensus_digests().next().is_some() => {
+            let mut r = r.clone();
+            r.clear_old_consensus_digests();
+            Some(ClientRequest::Consensus(r))
+        }
+        _ => None,
+    }
+}
+
 /// Helper: Make a set of download attempts for the current directory state,
 /// and on success feed their results into the state object.
 ///
@@ -177,6 +232,7 @@ pub(crate) async fn load<R: Runtime>(
 /// than `parallelism` requests at a time.
 ///
 /// Return true if the state reports that it changed.
+#[cfg(feature = "dirfetch")]
 async fn download_attempt<R: Runtime>(
     dirmgr: &Arc<DirMgr<R>>,
     state: &mut Box<dyn DirState>,
@@ -184,8 +240,19 @@ async fn download_attempt<R: Runtime>(
 ) -> Result<bool> {
     let mut changed = false;
     let missing = state.missing_docs();
-    let fetched = fetch_multiple(Arc::clone(dirmgr), missing, parallelism).await?;
-    for (client_req, dir_response) in fetched {
+    let mut fetched: VecDeque<_> = fetch_multiple(Arc::clone(dirmgr), missing, parallelism)
+        .await?
+        .into();
+    // For each answer to a request for descriptors: the circuit it came on,
+    // and the descriptors we asked for.
+    let mut answered = Vec::new();
+    while let Some((client_req, dir_response)) = fetched.pop_front() {
+        if let Some(source) = dir_response.source() {
+            let ids = client_req.descriptor_ids();
+            if !ids.is_empty() {
+                answered.push((*source.unique_circ_id(), ids));
+            }
+        }
         let text =
             String::from_utf8(dir_response.into_output()).map_err(Error::BadUtf8FromDirectory)?;
         match dirmgr.expand_response_text(&client_req, text) {
@@ -194,16 +261,49 @@ async fn download_attempt<R: Runtime>(
                 match outcome {
                     Ok(b) => changed |= b,
                     // TODO: in this case we might want to stop using this source.
-                    Err(e) => warn!("error while adding directory info: {}", e),
+                    Err(e) => {
+                        warn!("error while adding directory info: {}", e);
+                        dirmgr.download_errors.send(&e);
+                    }
                 }
             }
             Err(e) => {
                 // TODO: in this case we might want to stop using this source.
                 warn!("Error when expanding directory text: {}", e);
+                dirmgr.download_errors.send(&e);
+                // If we couldn't apply a consensus diff, nothing has been
+                // stored: ask for the whole consensus instead.
+                if let Some(full_req) = full_consensus_request(&client_req) {
+                    info!("Falling back to downloading a full consensus");
+                    match fetch_single(Arc::clone(dirmgr), full_req).await {
+                        Ok((req, resp)) if resp.status_code() == 200 => {
+                            fetched.push_back((req, resp));
+                        }
+                        Ok((_, resp)) => trace!(
+                            "cache declined request; reported status {:?}",
+                            resp.status_code()
+                        ),
+                        Err(e) => warn!("error while downloading: {:?}", e),
+                    }
+                }
             }
         }
     }
 
+    // Don't ask a cache for the same descriptor twice in a row: if it
+    // didn't give us everything we asked for, stop using its circuit, so
+    // that our next attempt goes to a different cache.
+    let still_missing = state.missing_docs();
+    if let Ok(circmgr) = dirmgr.circmgr() {
+        for id in sources_missing_docs(answered, &still_missing) {
+            debug!(
+                "{}: Retiring circuit after cache didn't give us every descriptor we asked for",
+                id
+            );
+            circmgr.retire_circ(&id);
+        }
+    }
+
     if changed {
         dirmgr.update_status(state.bootstrap_status());
     }
@@ -211,6 +311,21 @@ async fn download_attempt<R: Runtime>(
     Ok(changed)
 }
 
+/// Given a list of `(source, requested)` pairs, each describing the
+/// descriptors that we asked one source for, return the sources that didn't
+/// give us at least one of the documents in `missing`.
+#[cfg(feature = "dirfetch")]
+fn sources_missing_docs<S: Eq>(answered: Vec<(S, Vec<DocId>)>, missing: &[DocId]) -> Vec<S> {
+    let missing: HashSet<&DocId> = missing.iter().collect();
+    let mut result = Vec::new();
+    for (source, requested) in answered {
+        if requested.iter().any(|id| missing.contains(id)) && !result.contains(&source) {
+            result.push(source);
+        }
+    }
+    result
+}
+
 /// Download information into a DirState state machine until it is
 /// ["complete"](Readiness::Complete), or until we hit a
 /// non-recoverable error.
@@ -225,6 +340,7 @@ async fn download_attempt<R: Runtime>(
 /// Return Err only on a non-recoverable error.  On an error that
 /// merits another bootstrap attempt with the same state, return the
 /// state and an Error object in an option.
+#[cfg(feature = "dirfetch")]
 pub(crate) async fn download<R: Runtime>(
     dirmgr: Weak<DirMgr<R>>,
     mut state: Box<dyn DirState>,
@@ -332,6 +448,7 @@ pub(crate) async fn download<R: Runtime>(
 ///
 /// We use this to determine a reset time when no reset time is
 /// available, or when it is too far in the future.
+#[cfg(feature = "dirfetch")]
 fn no_more_than_a_week_from(now: SystemTime, v: Option<SystemTime>) -> SystemTime {
     let one_week_later = now + Duration::new(86400 * 7, 0);
     match v {
@@ -344,14 +461,76 @@ fn no_more_than_a_week_from(now: SystemTime, v: Option<SystemTime>) -> SystemTim
 mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
+    #[cfg(feature = "dirfetch")]
+    use crate::docid::ClientRequest;
     use crate::storage::DynStore;
     use crate::test::new_mgr;
+    #[cfg(feature = "dirfetch")]
     use crate::DownloadSchedule;
+    use crate::Readiness;
+    #[cfg(feature = "dirfetch")]
     use std::convert::TryInto;
     use std::sync::Mutex;
+    use std::time::SystemTime;
     use tor_netdoc::doc::microdesc::MdDigest;
 
     #[test]
+    #[cfg(feature = "dirfetch")]
+    fn try_another_cache() {
+        assert!(!should_try_another_cache(200));
+        assert!(!should_try_another_cache(304));
+        assert!(should_try_another_cache(404));
+        assert!(should_try_another_cache(503));
+    }
+
+    #[test]
+    #[cfg(feature = "dirfetch")]
+    fn full_consensus_fallback() {
+        use tor_dirclient::request::{ConsensusRequest, MicrodescRequest};
+        use tor_netdoc::doc::netstatus::ConsensusFlavor;
+
+        // Requests that didn't ask for a diff have no fallback.
+        let r = ClientRequest::Consensus(ConsensusRequest::new(ConsensusFlavor::Microdesc));
+        assert!(full_consensus_request(&r).is_none());
+        let r = ClientRequest::Microdescs(MicrodescRequest::new());
+        assert!(full_consensus_request(&r).is_none());
+
+        // A request for a diff falls back to the same request, without
+        // any digests.
+        let mut req = ConsensusRequest::new(ConsensusFlavor::Microdesc);
+        req.push_old_consensus_digest([0x99; 32]);
+        let when = SystemTime::now();
+        req.set_last_consensus_date(when);
+        match full_consensus_request(&ClientRequest::Consensus(req)) {
+            Some(ClientRequest::Consensus(r)) => {
+                assert_eq!(r.old_consensus_digests().count(), 0);
+                assert_eq!(r.last_consensus_date(), Some(when));
+            }
+            _ => panic!("Wrong kind of fallback request"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "dirfetch")]
+    fn avoid_sources_missing_docs() {
+        let md = |n| DocId::Microdesc([n; 32]);
+        let answered = vec![
+            ("cache-a", vec![md(1), md(2)]),
+            ("cache-b", vec![md(3), md(4)]),
+            ("cache-a", vec![md(5)]),
+            ("cache-c", vec![md(6)]),
+        ];
+        // Every source that held back a descriptor is avoided, once.
+        assert_eq!(
+            sources_missing_docs(answered.clone(), &[md(2), md(5), md(6), md(9)]),
+            vec!["cache-a", "cache-c"]
+        );
+        // If we got everything, we can keep using the same caches.
+        assert!(sources_missing_docs(answered, &[md(9)]).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "dirfetch")]
     fn week() {
         let now = SystemTime::now();
         let one_day = Duration::new(86400, 0);
@@ -456,6 +635,7 @@ mod test {
             }
             Ok(changed)
         }
+        #[cfg(feature = "dirfetch")]
         fn add_from_download(
             &mut self,
             text: &str,
@@ -475,6 +655,7 @@ mod test {
             }
             Ok(changed)
         }
+        #[cfg(feature = "dirfetch")]
         fn dl_config(&self) -> Result<DownloadSchedule> {
             Ok(DownloadSchedule::default())
         }
@@ -485,9 +666,11 @@ mod test {
                 Ok(self)
             }
         }
+        #[cfg(feature = "dirfetch")]
         fn reset_time(&self) -> Option<SystemTime> {
             None
         }
+        #[cfg(feature = "dirfetch")]
         fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
             Ok(Box::new(Self::new1()))
         }
@@ -515,22 +698,32 @@ mod test {
             assert!(result.is_ready(Readiness::Complete));
 
             // Try a bootstrap that could (but won't!) download.
-            let state = Box::new(DemoState::new1());
+            #[cfg(feature = "dirfetch")]
+            {
+                let state = Box::new(DemoState::new1());
 
-            let mut on_usable = None;
-            let result = super::download(Arc::downgrade(&mgr), state, &mut on_usable)
-                .await
-                .unwrap();
-            assert!(result.0.is_ready(Readiness::Complete));
+                let mut on_usable = None;
+                let result = super::download(Arc::downgrade(&mgr), state, &mut on_usable)
+                    .await
+                    .unwrap();
+                assert!(result.0.is_ready(Readiness::Complete));
+            }
         });
     }
 
     #[test]
+    #[cfg(feature = "dirfetch")]
     fn partly_in_cache() {
+        use tor_rtcompat::{BlockOn, SleepProvider};
+
         // Let's try bootstrapping with all of phase1 and part of
         // phase 2 in cache.
-        tor_rtcompat::test_with_one_runtime!(|rt| async {
-            let (_tempdir, mgr) = new_mgr(rt);
+        //
+        // We use a mock runtime, so that the retry delays below don't
+        // take any real time.
+        let rt = tor_rtmock::MockRuntime::default();
+        rt.block_on(async {
+            let (_tempdir, mgr) = new_mgr(rt.clone());
 
             {
                 let mut store = mgr.store_if_rw().unwrap().lock().unwrap();
@@ -540,6 +733,32 @@ mod test {
                         .unwrap();
                 }
             }
+            {
+                let mut resp = CANNED_RESPONSE.lock().unwrap();
+                // Just H4: that's enough to be usable, but not complete.
+                *resp = Some(
+                    "7768696c652069206c696b6520746f207761746368207468696e6773206f6e20".to_owned(),
+                );
+            }
+            let mgr = Arc::new(mgr);
+            let (snd, rcv) = oneshot::channel();
+            let mut on_usable = Some(snd);
+
+            // We keep retrying, with a delay between attempts, until we
+            // give up.
+            let start = rt.now();
+            let state = Box::new(DemoState::new1());
+            let (state, err) = super::download(Arc::downgrade(&mgr), state, &mut on_usable)
+                .await
+                .unwrap();
+            assert!(matches!(err, Some(Error::CantAdvanceState)));
+            assert!(state.is_ready(Readiness::Usable));
+            assert!(!state.is_ready(Readiness::Complete));
+            assert!(on_usable.is_none());
+            rcv.await.unwrap();
+            let n_attempts = DownloadSchedule::default().n_attempts();
+            assert!(rt.now() - start >= Duration::from_secs(1) * (n_attempts - 1));
+
             {
                 let mut resp = CANNED_RESPONSE.lock().unwrap();
                 // H4 and H5.
@@ -549,7 +768,6 @@ mod test {
                         .to_owned(),
                 );
             }
-            let mgr = Arc::new(mgr);
             let mut on_usable = None;
 
             let state = Box::new(DemoState::new1());
@@ -17,6 +17,7 @@ use tor_netdoc::doc::netstatus;
 
 use derive_builder::Builder;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::Deserialize;
 
@@ -82,6 +83,7 @@ impl NetworkConfig {
         &self.authorities[..]
     }
     /// Return the configured fallback directories
+    #[cfg(any(test, feature = "dirfetch"))]
     pub(crate) fn fallbacks(&self) -> &[FallbackDir] {
         &self.fallback_caches[..]
     }
@@ -130,6 +132,16 @@ pub struct DownloadScheduleConfig {
     #[serde(default = "default_microdesc_schedule")]
     #[builder(default = "default_microdesc_schedule()")]
     retry_microdescs: DownloadSchedule,
+
+    /// If true, split large microdescriptor and router descriptor downloads
+    /// into requests of nearly equal size.
+    ///
+    /// Otherwise, every request but the last asks for as many descriptors as
+    /// it can, so the size of the last one tells the directory cache exactly
+    /// how many descriptors we're missing.
+    #[serde(default = "default_balance_descriptor_batches")]
+    #[builder(default = "default_balance_descriptor_batches()")]
+    balance_descriptor_batches: bool,
 }
 
 /// Default value for retry_bootstrap in DownloadScheduleConfig.
@@ -142,6 +154,11 @@ fn default_microdesc_schedule() -> DownloadSchedule {
     DownloadSchedule::new(3, std::time::Duration::new(1, 0), 4)
 }
 
+/// Default value for balance_descriptor_batches in DownloadScheduleConfig.
+fn default_balance_descriptor_batches() -> bool {
+    true
+}
+
 impl Default for DownloadScheduleConfig {
     fn default() -> Self {
         Self::builder()
@@ -164,7 +181,8 @@ impl From<DownloadScheduleConfig> for DownloadScheduleConfigBuilder {
             .retry_bootstrap(cfg.retry_bootstrap)
             .retry_consensus(cfg.retry_consensus)
             .retry_certs(cfg.retry_certs)
-            .retry_microdescs(cfg.retry_microdescs);
+            .retry_microdescs(cfg.retry_microdescs)
+            .balance_descriptor_batches(cfg.balance_descriptor_batches);
         builder
     }
 }
@@ -217,6 +235,19 @@ pub struct DirMgrConfig {
     /// option will always be delayed.)
     #[builder(default)]
     override_net_params: netstatus::NetParams<i32>,
+
+    /// How far we allow our clock to disagree with the authorities' when
+    /// checking whether a consensus or authority certificate is currently
+    /// valid.
+    ///
+    /// Documents are accepted if they are within this much of their validity
+    /// period, in either direction.  By default this is zero, and documents
+    /// are checked strictly.
+    ///
+    /// This can be replaced on a running Arti client. Doing so will take
+    /// effect the next time we check a document.
+    #[builder(default)]
+    clock_skew_tolerance: Duration,
 }
 
 impl DirMgrConfigBuilder {
@@ -267,6 +298,7 @@ impl DirMgrConfig {
     }
 
     /// Return the configured set of fallback directories
+    #[cfg(any(test, feature = "dirfetch"))]
     pub(crate) fn fallbacks(&self) -> &[FallbackDir] {
         self.network_config.fallbacks()
     }
@@ -278,10 +310,17 @@ impl DirMgrConfig {
 
     /// Return the schedule configuration we should use to decide when to
     /// attempt and retry downloads.
+    #[cfg(feature = "dirfetch")]
     pub(crate) fn schedule(&self) -> &DownloadScheduleConfig {
         &self.schedule_config
     }
 
+    /// Return how far we allow our clock to be wrong when checking the
+    /// lifetimes of consensuses and certificates.
+    pub(crate) fn clock_skew_tolerance(&self) -> Duration {
+        self.clock_skew_tolerance
+    }
+
     /// Construct a new configuration object where all replaceable fields in
     /// `self` are replaced with those from  `new_config`.
     ///
@@ -295,10 +334,12 @@ impl DirMgrConfig {
             },
             schedule_config: new_config.schedule_config.clone(),
             override_net_params: new_config.override_net_params.clone(),
+            clock_skew_tolerance: new_config.clock_skew_tolerance,
         }
     }
 }
 
+#[cfg(any(test, feature = "dirfetch"))]
 impl DownloadScheduleConfig {
     /// Return configuration for retrying our entire bootstrap
     /// operation at startup.
@@ -320,6 +361,13 @@ impl DownloadScheduleConfig {
     pub(crate) fn retry_microdescs(&self) -> &DownloadSchedule {
         &self.retry_microdescs
     }
+
+    /// Return true if we should make our descriptor download requests
+    /// nearly equal in size.
+    #[cfg(feature = "dirfetch")]
+    pub(crate) fn balance_descriptor_batches(&self) -> bool {
+        self.balance_descriptor_batches
+    }
 }
 
 /// Helpers for initializing the fallback list.
@@ -458,6 +506,14 @@ mod test {
             .unwrap();
 
         assert_eq!(cfg.override_net_params().get("circwindow").unwrap(), &999);
+        assert_eq!(cfg.clock_skew_tolerance(), Duration::ZERO);
+
+        let cfg2 = bld
+            .clock_skew_tolerance(Duration::from_secs(300))
+            .build()
+            .unwrap();
+        let cfg = cfg.update_config(&cfg2);
+        assert_eq!(cfg.clock_skew_tolerance(), Duration::from_secs(300));
 
         Ok(())
     }
@@ -1,8 +1,11 @@
 //! Declare a general purpose "document ID type" for tracking which
 //! documents we want and which we have.
 
+#[cfg(feature = "dirfetch")]
+use rand::{seq::SliceRandom, Rng};
 use std::{borrow::Borrow, collections::HashMap};
 
+#[cfg(feature = "dirfetch")]
 use tor_dirclient::request;
 #[cfg(feature = "routerdesc")]
 use tor_netdoc::doc::routerdesc::RdDigest;
@@ -67,6 +70,7 @@ impl DocId {
 /// A request for a specific kind of directory resource that a DirMgr can
 /// request.
 #[derive(Clone, Debug)]
+#[cfg(feature = "dirfetch")]
 pub(crate) enum ClientRequest {
     /// Request for a consensus
     Consensus(request::ConsensusRequest),
@@ -79,6 +83,7 @@ pub(crate) enum ClientRequest {
     RouterDescs(request::RouterDescRequest),
 }
 
+#[cfg(feature = "dirfetch")]
 impl ClientRequest {
     /// Turn a ClientRequest into a Requestable.
     pub(crate) fn as_requestable(&self) -> &(dyn request::Requestable + Send + Sync) {
@@ -91,6 +96,20 @@ impl ClientRequest {
             RouterDescs(a) => a,
         }
     }
+
+    /// Return the IDs of the descriptors that this request asks for.
+    ///
+    /// (Consensus and authority certificate requests don't ask for any
+    /// descriptors, so for them this is empty.)
+    pub(crate) fn descriptor_ids(&self) -> Vec<DocId> {
+        use ClientRequest::*;
+        match self {
+            Consensus(_) | AuthCert(_) => Vec::new(),
+            Microdescs(r) => r.digests().map(|d| DocId::Microdesc(*d)).collect(),
+            #[cfg(feature = "routerdesc")]
+            RouterDescs(r) => r.digests().map(|d| DocId::RouterDesc(*d)).collect(),
+        }
+    }
 }
 
 /// Description of how to start out a given bootstrap attempt.
@@ -174,7 +193,15 @@ impl DocQuery {
 
     /// If this query contains too many documents to download with a single
     /// request, divide it up.
-    pub(crate) fn split_for_download(self) -> Vec<Self> {
+    ///
+    /// Relay descriptors are shuffled using `rng` before they are divided,
+    /// so that a directory cache can't learn much about which relays we'll
+    /// use next from the order of our requests.  If `balanced` is true, the
+    /// batches are also made as close to the same size as possible, so that
+    /// the size of the last one doesn't reveal exactly how many descriptors
+    /// we're missing.
+    #[cfg(feature = "dirfetch")]
+    pub(crate) fn split_for_download<R: Rng>(self, rng: &mut R, balanced: bool) -> Vec<Self> {
         use DocQuery::*;
         /// How many objects can be put in a single HTTP GET line?
         const N: usize = 500;
@@ -185,18 +212,64 @@ impl DocQuery {
                 v[..].chunks(N).map(|s| AuthCert(s.to_vec())).collect()
             }
             Microdesc(mut v) => {
-                v.sort_unstable();
-                v[..].chunks(N).map(|s| Microdesc(s.to_vec())).collect()
+                v.shuffle(rng);
+                descriptor_chunks(&v[..], N, balanced)
+                    .into_iter()
+                    .map(|s| Microdesc(s.to_vec()))
+                    .collect()
             }
             #[cfg(feature = "routerdesc")]
             RouterDesc(mut v) => {
-                v.sort_unstable();
-                v[..].chunks(N).map(|s| RouterDesc(s.to_vec())).collect()
+                v.shuffle(rng);
+                descriptor_chunks(&v[..], N, balanced)
+                    .into_iter()
+                    .map(|s| RouterDesc(s.to_vec()))
+                    .collect()
             }
         }
     }
 }
 
+/// Divide `v` into as few chunks of no more than `max` elements as
+/// possible.
+///
+/// If `balanced` is true, the chunks' sizes differ by no more than one
+/// element.  (Unlike `chunks`, this never leaves a small leftover chunk at
+/// the end whose size reveals exactly how many elements there were.)
+#[cfg(feature = "dirfetch")]
+fn descriptor_chunks<T>(v: &[T], max: usize, balanced: bool) -> Vec<&[T]> {
+    if balanced {
+        balanced_chunks(v, max).collect()
+    } else {
+        v.chunks(max).collect()
+    }
+}
+
+/// Divide `v` into as few chunks of no more than `max` elements as
+/// possible, with sizes that differ by no more than one element.
+///
+/// The larger chunks come first: for example, 10 elements with a `max` of
+/// 3 become chunks of 3, 3, 2, and 2 elements.
+#[cfg(feature = "dirfetch")]
+fn balanced_chunks<T>(v: &[T], max: usize) -> impl Iterator<Item = &[T]> {
+    let n_chunks = (v.len() + max - 1) / max;
+    let (small_size, n_large) = match n_chunks {
+        0 => (0, 0),
+        n => (v.len() / n, v.len() % n),
+    };
+    let mut rest = v;
+    (0..n_chunks).map(move |i| {
+        let size = if i < n_large {
+            small_size + 1
+        } else {
+            small_size
+        };
+        let (chunk, remainder) = rest.split_at(size);
+        rest = remainder;
+        chunk
+    })
+}
+
 impl From<DocId> for DocQuery {
     fn from(d: DocId) -> DocQuery {
         let mut result = DocQuery::empty_from_docid(&d);
@@ -291,6 +364,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "dirfetch")]
     fn split_into_chunks() {
         use std::collections::HashSet;
         //use itertools::Itertools;
@@ -301,7 +375,8 @@ mod test {
         let ids: HashSet<MdDigest> = (0..3400).into_iter().map(|_| rng.gen()).collect();
 
         // Test microdescs.
-        let split = DocQuery::Microdesc(ids.clone().into_iter().collect()).split_for_download();
+        let split = DocQuery::Microdesc(ids.clone().into_iter().collect())
+            .split_for_download(&mut rng, true);
         assert_eq!(split.len(), 7);
         let mut found_ids = HashSet::new();
         for q in split {
@@ -319,8 +394,8 @@ mod test {
         #[cfg(feature = "routerdesc")]
         {
             let ids: HashSet<RdDigest> = (0..1001).into_iter().map(|_| rng.gen()).collect();
-            let split =
-                DocQuery::RouterDesc(ids.clone().into_iter().collect()).split_for_download();
+            let split = DocQuery::RouterDesc(ids.clone().into_iter().collect())
+                .split_for_download(&mut rng, true);
             assert_eq!(split.len(), 3);
             let mut found_ids = HashSet::new();
             for q in split {
@@ -347,7 +422,8 @@ mod test {
                 }
             })
             .collect();
-        let split = DocQuery::AuthCert(ids.clone().into_iter().collect()).split_for_download();
+        let split = DocQuery::AuthCert(ids.clone().into_iter().collect())
+            .split_for_download(&mut rng, true);
         assert_eq!(split.len(), 5);
         let mut found_ids = HashSet::new();
         for q in split {
@@ -366,10 +442,85 @@ mod test {
             flavor: ConsensusFlavor::Microdesc,
             cache_usage: CacheUsage::CacheOkay,
         };
-        let split = query.clone().split_for_download();
+        let split = query.clone().split_for_download(&mut rng, true);
         assert_eq!(split, vec![query]);
     }
 
+    #[test]
+    #[cfg(feature = "dirfetch")]
+    fn split_randomized() {
+        use rand::SeedableRng;
+        let ids: Vec<MdDigest> = (0..1001_u16)
+            .map(|n| {
+                let mut d = [0_u8; 32];
+                d[..2].copy_from_slice(&n.to_be_bytes());
+                d
+            })
+            .collect();
+        let split_with_seed = |seed| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            DocQuery::Microdesc(ids.clone()).split_for_download(&mut rng, true)
+        };
+        let batch_sizes = |split: &[DocQuery]| -> Vec<usize> {
+            split
+                .iter()
+                .map(|q| match q {
+                    DocQuery::Microdesc(v) => v.len(),
+                    _ => panic!("Wrong type."),
+                })
+                .collect()
+        };
+
+        // Batches are evenly sized: no small leftover batch at the end.
+        let split = split_with_seed(7);
+        assert_eq!(batch_sizes(&split), vec![334, 334, 333]);
+
+        // The digests are shuffled, not requested in their original order.
+        let first_batch = match &split[0] {
+            DocQuery::Microdesc(v) => v.clone(),
+            _ => panic!("Wrong type."),
+        };
+        assert_ne!(&first_batch[..], &ids[..334]);
+        let mut sorted = first_batch.clone();
+        sorted.sort_unstable();
+        assert_ne!(first_batch, sorted);
+
+        // Same seed, same batches; different seed, different batches.
+        assert_eq!(split, split_with_seed(7));
+        assert_ne!(split, split_with_seed(8));
+
+        // Small queries aren't split at all.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let split = DocQuery::Microdesc(ids[..10].to_vec()).split_for_download(&mut rng, true);
+        assert_eq!(batch_sizes(&split), vec![10]);
+        let split = DocQuery::Microdesc(ids[..501].to_vec()).split_for_download(&mut rng, true);
+        assert_eq!(batch_sizes(&split), vec![251, 250]);
+        let split = DocQuery::Microdesc(vec![]).split_for_download(&mut rng, true);
+        assert!(batch_sizes(&split).is_empty());
+
+        // Without balancing, every batch but the last is full.
+        let split = DocQuery::Microdesc(ids.clone()).split_for_download(&mut rng, false);
+        assert_eq!(batch_sizes(&split), vec![500, 500, 1]);
+    }
+
+    #[test]
+    #[cfg(feature = "dirfetch")]
+    fn balanced_chunk_sizes() {
+        let sizes = |len: usize, max| -> Vec<usize> {
+            let v: Vec<usize> = (0..len).collect();
+            let chunks: Vec<_> = balanced_chunks(&v[..], max).collect();
+            // Nothing is lost or reordered.
+            assert_eq!(chunks.concat(), v);
+            chunks.iter().map(|c| c.len()).collect()
+        };
+        assert_eq!(sizes(10, 3), vec![3, 3, 2, 2]);
+        assert_eq!(sizes(9, 3), vec![3, 3, 3]);
+        assert_eq!(sizes(7, 3), vec![3, 2, 2]);
+        assert_eq!(sizes(3, 10), vec![3]);
+        assert_eq!(sizes(1, 1), vec![1]);
+        assert!(sizes(0, 3).is_empty());
+    }
+
     #[test]
     fn into_query() {
         let q: DocQuery = DocId::Microdesc([99; 32]).into();
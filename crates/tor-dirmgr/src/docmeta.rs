@@ -7,11 +7,13 @@
 
 use digest::Digest;
 use tor_llcrypto as ll;
-use tor_netdoc::doc::{
-    authcert::{AuthCert, AuthCertKeyIds},
-    netstatus::{Lifetime, MdConsensus, UnvalidatedMdConsensus},
-};
+#[cfg(feature = "dirfetch")]
+use tor_netdoc::doc::authcert::AuthCert;
+#[cfg(any(test, feature = "dirfetch"))]
+use tor_netdoc::doc::authcert::AuthCertKeyIds;
+use tor_netdoc::doc::netstatus::{Lifetime, MdConsensus, UnvalidatedMdConsensus};
 
+#[cfg(any(test, feature = "dirfetch"))]
 use std::time::SystemTime;
 
 /// Information about a consensus that we have in storage.
@@ -24,6 +26,7 @@ pub(crate) struct ConsensusMeta {
     lifetime: Lifetime,
     /// A sha3-256 digest of the signed portion of the consensus: used for
     /// fetching diffs.
+    #[cfg_attr(not(any(test, feature = "dirfetch")), allow(dead_code))]
     sha3_256_of_signed: [u8; 32],
     /// A sha3-256 digest of the entirety of the consensus: used for
     /// naming the file.
@@ -67,6 +70,7 @@ impl ConsensusMeta {
         &self.lifetime
     }
     /// Return the sha3-256 of the signed portion of this consensus.
+    #[cfg(any(test, feature = "dirfetch"))]
     pub(crate) fn sha3_256_of_signed(&self) -> &[u8; 32] {
         &self.sha3_256_of_signed
     }
@@ -92,6 +96,7 @@ fn sha3_dual(signed_part: impl AsRef<[u8]>, remainder: impl AsRef<[u8]>) -> ([u8
 /// This information is ordinarily derived from the authority cert, but it
 /// doesn't have to be.
 #[derive(Clone, Debug)]
+#[cfg(any(test, feature = "dirfetch"))]
 pub(crate) struct AuthCertMeta {
     /// Key IDs (identity and signing) for the certificate.
     ids: AuthCertKeyIds,
@@ -101,6 +106,7 @@ pub(crate) struct AuthCertMeta {
     expires: SystemTime,
 }
 
+#[cfg(any(test, feature = "dirfetch"))]
 impl AuthCertMeta {
     /// Construct a new AuthCertMeta from its components
     pub(crate) fn new(ids: AuthCertKeyIds, published: SystemTime, expires: SystemTime) -> Self {
@@ -112,6 +118,7 @@ impl AuthCertMeta {
     }
 
     /// Construct a new AuthCertMeta from a certificate.
+    #[cfg(feature = "dirfetch")]
     pub(crate) fn from_authcert(cert: &AuthCert) -> Self {
         AuthCertMeta::new(*cert.key_ids(), cert.published(), cert.expires())
     }
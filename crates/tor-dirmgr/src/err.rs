@@ -1,6 +1,7 @@
 //! Declare an error type for the tor-dirmgr crate.
 
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use crate::DocSource;
 use futures::task::SpawnError;
@@ -34,6 +35,24 @@ pub enum Error {
     /// bootstrapped directory, but we didn't have one.
     #[error("directory not present or not up-to-date")]
     DirectoryNotPresent,
+    /// We can't download directory information, and the snapshot of the
+    /// directory in our cache expired at the given time.
+    ///
+    /// Somebody else needs to refresh the cache before we can use it again.
+    #[error("directory snapshot in cache has expired")]
+    SnapshotExpired(SystemTime),
+    /// An offline `DirMgr` found no complete directory in its cache.
+    #[error("no usable directory in cache for offline use")]
+    OfflineDirectoryMissing,
+    /// An offline `DirMgr` found a directory in its cache, but it expired
+    /// longer ago than we were configured to tolerate.
+    #[error("cached directory expired at {valid_until:?}, more than {max_staleness:?} ago")]
+    OfflineDirectoryTooStale {
+        /// When the cached consensus stopped being valid.
+        valid_until: SystemTime,
+        /// How far past its expiration we were willing to use it.
+        max_staleness: Duration,
+    },
     /// A consensus document is signed by an unrecognized authority set.
     #[error("authorities on consensus do not match what we expect.")]
     UnrecognizedAuthorities,
@@ -48,6 +67,7 @@ pub enum Error {
     #[error("storage error: {0}")]
     StorageError(String),
     /// An error given by the consensus diff crate.
+    #[cfg(feature = "dirfetch")]
     #[error("consdiff error: {0}")]
     ConsensusDiffError(#[from] tor_consdiff::Error),
     /// Invalid UTF8 in directory response.
@@ -68,6 +88,20 @@ pub enum Error {
         #[source]
         cause: tor_netdoc::Error,
     },
+    /// A document wasn't valid at the current time, even allowing for our
+    /// configured clock skew tolerance.
+    ///
+    /// The cause says how far outside its validity period the document was,
+    /// and in which direction.  If this keeps happening with documents from
+    /// different sources, our clock is probably wrong.
+    #[error("untimely object from {source}: it {cause}")]
+    UntimelyObject {
+        /// Where the document came from.
+        source: DocSource,
+        /// How the document was untimely.
+        #[source]
+        cause: tor_checkable::TimeValidityError,
+    },
     /// An error given by dirclient
     #[error("dirclient error: {0}")]
     DirClientError(#[from] tor_dirclient::Error),
@@ -123,6 +157,22 @@ impl Error {
     pub(crate) fn from_netdoc(source: DocSource, cause: tor_netdoc::Error) -> Error {
         Error::NetDocError { source, cause }
     }
+
+    /// Return true if this error means that we tried to open a read-only
+    /// cache that doesn't exist.
+    ///
+    /// (If the cache directory itself is missing, we fail to open its lock
+    /// file before we ever get to the database.)
+    pub(crate) fn is_missing_cache(&self) -> bool {
+        match self {
+            Error::SqliteError(e) => matches!(
+                &**e,
+                rusqlite::Error::SqliteFailure(f, _) if f.code == rusqlite::ErrorCode::CannotOpen
+            ),
+            Error::IOError(e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
 }
 
 impl From<rusqlite::Error> for Error {
@@ -151,6 +201,9 @@ impl HasKind for Error {
             E::UnrecognizedSchema => EK::CacheCorrupted,
             E::BadNetworkConfig(_) => EK::InvalidConfig,
             E::DirectoryNotPresent => EK::DirectoryExpired,
+            E::SnapshotExpired(_) => EK::DirectoryExpired,
+            E::OfflineDirectoryMissing => EK::NoCachedDirectory,
+            E::OfflineDirectoryTooStale { .. } => EK::DirectoryExpired,
             E::BadUtf8FromDirectory(_) => EK::TorProtocolViolation,
             E::BadUtf8InCache(_) => EK::CacheCorrupted,
             E::BadHexInCache(_) => EK::CacheCorrupted,
@@ -158,11 +211,16 @@ impl HasKind for Error {
             E::ManagerDropped => EK::ArtiShuttingDown,
             E::CantAdvanceState => EK::TorAccessFailed,
             E::StorageError(_) => EK::CacheAccessFailed,
+            #[cfg(feature = "dirfetch")]
             E::ConsensusDiffError(_) => EK::TorProtocolViolation,
             E::NetDocError { source, .. } => match source {
                 DocSource::LocalCache => EK::CacheCorrupted,
                 DocSource::DirServer { .. } => EK::TorProtocolViolation,
             },
+            E::UntimelyObject { source, .. } => match source {
+                DocSource::LocalCache => EK::DirectoryExpired,
+                DocSource::DirServer { .. } => EK::TorDirectoryError,
+            },
             E::DirClientError(e) => e.kind(),
             E::SignatureError(_) => EK::TorProtocolViolation,
             E::IOError(_) => EK::CacheAccessFailed,
@@ -10,16 +10,18 @@ use std::{
     pin::Pin,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     task::Poll,
     time::SystemTime,
 };
 
-use futures::{stream::Stream, Future, StreamExt};
+use futures::{channel::mpsc, stream::Stream, Future, StreamExt};
 use time::OffsetDateTime;
 use tor_netdoc::doc::netstatus;
 
+use crate::Error;
+
 /// An event that a DirMgr can broadcast to indicate that a change in
 /// the status of its directory.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -522,6 +524,78 @@ impl std::fmt::Debug for DirBootstrapEvents {
     }
 }
 
+/// How many errors can wait for each [`DownloadErrors`] reader before we
+/// start dropping new ones.
+const DOWNLOAD_ERROR_BUFFER: usize = 32;
+
+/// A stream of the errors that a [`DirMgr`](crate::DirMgr) encounters while
+/// handling the directory documents that it downloads.
+///
+/// None of these errors are fatal: the `DirMgr` will retry, possibly from
+/// another source.  They're reported so that callers can notice patterns,
+/// like a run of documents that all seem to be from the wrong time.  If the
+/// reader falls behind, new errors are dropped until it catches up.
+pub struct DownloadErrors {
+    /// The receiver that implements this stream.
+    inner: mpsc::Receiver<Error>,
+}
+
+impl Stream for DownloadErrors {
+    type Item = Error;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl fmt::Debug for DownloadErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadErrors").finish_non_exhaustive()
+    }
+}
+
+/// Object that sends download errors to every [`DownloadErrors`] stream that
+/// has subscribed to it.
+///
+/// Sending never blocks: if a reader's buffer is full, that reader misses
+/// the error.
+#[derive(Default)]
+pub(crate) struct DownloadErrorSender {
+    /// A sender for each subscribed receiver.
+    subscribers: Mutex<Vec<mpsc::Sender<Error>>>,
+}
+
+impl DownloadErrorSender {
+    /// Return a new stream that will receive every error we send from now
+    /// on.
+    pub(crate) fn subscribe(&self) -> DownloadErrors {
+        let (sender, inner) = mpsc::channel(DOWNLOAD_ERROR_BUFFER);
+        self.subscribers
+            .lock()
+            .expect("download error lock poisoned")
+            .push(sender);
+        DownloadErrors { inner }
+    }
+
+    /// Send `err` to every subscriber that has room for it.
+    #[cfg(any(test, feature = "dirfetch"))]
+    pub(crate) fn send(&self, err: &Error) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .expect("download error lock poisoned");
+        for s in subscribers.iter_mut() {
+            // A full buffer just means that this reader misses the error.
+            let _ = s.try_send(err.clone());
+        }
+        // Forget about any streams that have been dropped.
+        subscribers.retain(|s| !s.is_closed());
+    }
+}
+
 #[allow(clippy::unwrap_used)]
 #[cfg(test)]
 mod test {
@@ -532,6 +606,35 @@ mod test {
     use futures::stream::StreamExt;
     use tor_rtcompat::test_with_all_runtimes;
 
+    #[test]
+    fn download_errors() {
+        use futures::executor::block_on;
+
+        let sender = DownloadErrorSender::default();
+        // Nobody has to be listening for us to send.
+        sender.send(&Error::ManagerDropped);
+
+        let mut a = sender.subscribe();
+        let b = sender.subscribe();
+        sender.send(&Error::CantAdvanceState);
+        assert!(matches!(block_on(a.next()), Some(Error::CantAdvanceState)));
+
+        // Dropped receivers get forgotten.
+        drop(b);
+        sender.send(&Error::ManagerDropped);
+        assert_eq!(sender.subscribers.lock().unwrap().len(), 1);
+        assert!(matches!(block_on(a.next()), Some(Error::ManagerDropped)));
+
+        // A reader that falls behind misses errors, but sending doesn't block.
+        for _ in 0..DOWNLOAD_ERROR_BUFFER * 2 {
+            sender.send(&Error::ManagerDropped);
+        }
+        drop(sender);
+        let got = block_on(a.collect::<Vec<_>>()).len();
+        assert!(got >= DOWNLOAD_ERROR_BUFFER);
+        assert!(got < DOWNLOAD_ERROR_BUFFER * 2);
+    }
+
     #[test]
     fn subscribe_and_publish() {
         test_with_all_runtimes!(|_rt| async {
@@ -19,6 +19,11 @@
 //! `mmap` (default) -- Use memory mapping to reduce the memory load for
 //! reading large directory objects from disk.
 //!
+//! `dirfetch` (default) -- Download directory information from the
+//! network.  Without this feature, a `DirMgr` can only bootstrap from a
+//! snapshot of the directory that is already in its cache, and stops handing
+//! out that directory once it expires.
+//!
 //! `static` -- Try to link with a static copy of sqlite3.
 //!
 //! `routerdesc` -- (Incomplete) support for downloading and storing
@@ -66,7 +71,9 @@ mod shared_ref;
 mod state;
 mod storage;
 
-use crate::docid::{CacheUsage, ClientRequest, DocQuery};
+#[cfg(feature = "dirfetch")]
+use crate::docid::ClientRequest;
+use crate::docid::{CacheUsage, DocQuery};
 use crate::shared_ref::SharedMutArc;
 use crate::storage::DynStore;
 use postage::watch;
@@ -76,8 +83,8 @@ use tor_netdir::NetDir;
 use tor_netdoc::doc::netstatus::ConsensusFlavor;
 
 use async_trait::async_trait;
-use futures::{channel::oneshot, stream::BoxStream, task::SpawnExt};
-use tor_rtcompat::{Runtime, SleepProviderExt};
+use futures::{stream::BoxStream, task::SpawnExt};
+use tor_rtcompat::Runtime;
 use tracing::{debug, info, trace, warn};
 
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -85,6 +92,11 @@ use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, sync::Weak};
 use std::{fmt::Debug, time::SystemTime};
 
+#[cfg(feature = "dirfetch")]
+use futures::channel::oneshot;
+#[cfg(feature = "dirfetch")]
+use tor_rtcompat::SleepProviderExt;
+
 pub use authority::{Authority, AuthorityBuilder};
 pub use config::{
     DirMgrConfig, DirMgrConfigBuilder, DownloadScheduleConfig, DownloadScheduleConfigBuilder,
@@ -92,13 +104,18 @@ pub use config::{
 };
 pub use docid::DocId;
 pub use err::Error;
-pub use event::{DirBootstrapEvents, DirBootstrapStatus, DirEvent, DirStatus};
+pub use event::{DirBootstrapEvents, DirBootstrapStatus, DirEvent, DirStatus, DownloadErrors};
 pub use storage::DocumentText;
 pub use tor_netdir::fallback::{FallbackDir, FallbackDirBuilder};
 
 /// A Result as returned by this crate.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// How often do we check our cache for a newer directory snapshot, when we
+/// can't download directory information ourselves?
+#[cfg(not(feature = "dirfetch"))]
+const SNAPSHOT_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(120);
+
 /// Trait for DirMgr implementations
 #[async_trait]
 pub trait DirProvider {
@@ -131,6 +148,18 @@ pub trait DirProvider {
     /// Note that this stream can be lossy: the caller will not necessarily
     /// observe every event on the stream
     fn bootstrap_events(&self) -> BoxStream<'static, DirBootstrapStatus>;
+
+    /// Return a stream of the non-fatal errors that we encounter while
+    /// handling downloaded directory documents.
+    ///
+    /// Only errors that happen after this call are reported.  This stream
+    /// can be lossy if the caller falls behind.
+    ///
+    /// The default implementation returns a stream that never yields
+    /// anything.
+    fn download_errors(&self) -> BoxStream<'static, Error> {
+        Box::pin(futures::stream::empty())
+    }
 }
 
 #[async_trait]
@@ -158,13 +187,18 @@ impl<R: Runtime> DirProvider for Arc<DirMgr<R>> {
     fn bootstrap_events(&self) -> BoxStream<'static, DirBootstrapStatus> {
         Box::pin(DirMgr::bootstrap_events(self))
     }
+
+    fn download_errors(&self) -> BoxStream<'static, Error> {
+        Box::pin(DirMgr::download_errors(self))
+    }
 }
 
 /// A directory manager to download, fetch, and cache a Tor directory.
 ///
 /// A DirMgr can operate in three modes:
 ///   * In **offline** mode, it only reads from the cache, and can
-///     only read once.
+///     only read once.  An offline `DirMgr` can be told to accept a
+///     directory that has recently expired: see [`DirMgr::load_offline`].
 ///   * In **read-only** mode, it reads from the cache, but checks
 ///     whether it can acquire an associated lock file.  If it can, then
 ///     it enters read-write mode.  If not, it checks the cache
@@ -202,6 +236,10 @@ pub struct DirMgr<R: Runtime> {
     /// to discard unread events.
     receive_status: DirBootstrapEvents,
 
+    /// A publisher handle that we notify about every non-fatal error we
+    /// encounter while handling downloaded documents.
+    download_errors: event::DownloadErrorSender,
+
     /// A circuit manager, if this DirMgr supports downloading.
     circmgr: Option<Arc<CircMgr<R>>>,
 
@@ -211,6 +249,13 @@ pub struct DirMgr<R: Runtime> {
     /// Whether or not we're operating in offline mode.
     offline: bool,
 
+    /// If we're in offline mode and were created with
+    /// [`DirMgr::create_offline`], how long past its expiration we will
+    /// accept a directory from the cache.
+    ///
+    /// (This is always `None` outside of offline mode.)
+    offline_tolerance: Option<std::time::Duration>,
+
     /// If we're not in offline mode, stores whether or not the `DirMgr` has attempted
     /// to bootstrap yet or not.
     ///
@@ -280,6 +325,54 @@ impl<R: Runtime> DirMgr<R> {
         dirmgr.opt_netdir().ok_or(Error::DirectoryNotPresent)
     }
 
+    /// Create a new `DirMgr` in offline mode that can be bootstrapped from
+    /// the cache alone, but don't load anything yet.
+    ///
+    /// Bootstrapping this `DirMgr` (with `bootstrap`) never touches the
+    /// network: it loads the latest consensus and microdescriptors from the
+    /// cache, and accepts them even if the consensus expired up to
+    /// `max_staleness` ago.  Nothing will ever replace that directory.
+    ///
+    /// Returns [`Error::OfflineDirectoryMissing`] if there is no cache to
+    /// load from.
+    pub fn create_offline(
+        config: DirMgrConfig,
+        runtime: R,
+        max_staleness: std::time::Duration,
+    ) -> Result<Arc<Self>> {
+        let mut dirmgr = DirMgr::from_config(config, runtime, None, true).map_err(|e| {
+            if e.is_missing_cache() {
+                // There's no cache at all, so there's certainly no directory.
+                Error::OfflineDirectoryMissing
+            } else {
+                e
+            }
+        })?;
+        dirmgr.offline_tolerance = Some(max_staleness);
+        Ok(Arc::new(dirmgr))
+    }
+
+    /// Load a directory from the cache alone, accepting it even if its
+    /// consensus expired up to `max_staleness` ago.
+    ///
+    /// Like `load_once`, but returns the offline `DirMgr` itself, so that
+    /// it can be used as a [`DirProvider`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OfflineDirectoryMissing`] if there is no complete
+    /// directory in the cache, and [`Error::OfflineDirectoryTooStale`] if
+    /// there is one but it expired more than `max_staleness` ago.
+    pub async fn load_offline(
+        runtime: R,
+        config: DirMgrConfig,
+        max_staleness: std::time::Duration,
+    ) -> Result<Arc<Self>> {
+        let dirmgr = Self::create_offline(config, runtime, max_staleness)?;
+        dirmgr.bootstrap().await?;
+        Ok(dirmgr)
+    }
+
     /// Return a current netdir, either loading it or bootstrapping it
     /// as needed.
     ///
@@ -325,18 +418,31 @@ impl<R: Runtime> DirMgr<R> {
     ///
     /// If bootstrapping has already successfully taken place, returns early with success.
     ///
+    /// Without the `dirfetch` feature, this function never touches the
+    /// network: it loads a directory snapshot from the cache, and launches a
+    /// background task that reloads the cache from time to time.
+    ///
+    /// For a `DirMgr` created with `create_offline`, this function only
+    /// loads the directory from the cache, and launches no background task.
+    ///
     /// # Errors
     ///
     /// Returns an error if bootstrapping fails. If the error is [`Error::CantAdvanceState`],
     /// it may be possible to successfully bootstrap later on by calling this function again.
     ///
-    /// # Panics
+    /// Without the `dirfetch` feature, returns [`Error::SnapshotExpired`] if
+    /// the snapshot in the cache has expired, and [`Error::DirectoryNotPresent`]
+    /// if there is no usable snapshot at all.
     ///
-    /// Panics if the `DirMgr` passed to this function was not created in online mode, such as
-    /// via `load_once`.
+    /// For a `DirMgr` created with `create_offline`, returns the errors
+    /// described in [`DirMgr::load_offline`].  For any other offline
+    /// `DirMgr`, returns [`Error::OfflineMode`].
     pub async fn bootstrap(self: &Arc<Self>) -> Result<()> {
         if self.offline {
-            return Err(Error::OfflineMode);
+            return match self.offline_tolerance {
+                Some(max_staleness) => self.bootstrap_offline(max_staleness).await,
+                None => Err(Error::OfflineMode),
+            };
         }
 
         // The semantics of this are "attempt to replace a 'false' value with 'true'.
@@ -362,6 +468,17 @@ impl<R: Runtime> DirMgr<R> {
             ordering: Ordering::SeqCst,
         };
 
+        self.finish_bootstrap(resetter).await
+    }
+
+    /// Helper for `bootstrap`: load what we can from the cache, then launch
+    /// a background task to download whatever we're missing and keep the
+    /// directory up to date.
+    ///
+    /// Waits until the directory is usable.  If that happens, disarms
+    /// `resetter`.
+    #[cfg(feature = "dirfetch")]
+    async fn finish_bootstrap(self: &Arc<Self>, resetter: BoolResetter<'_>) -> Result<()> {
         // Try to load from the cache.
         let have_directory = self.load_directory().await?;
 
@@ -413,6 +530,98 @@ impl<R: Runtime> DirMgr<R> {
         Ok(())
     }
 
+    /// Helper for `bootstrap`, when we have no way to download directory
+    /// information: load the directory snapshot from the cache, and launch a
+    /// background task to pick up newer snapshots as they appear there.
+    ///
+    /// Gives an error if the snapshot is missing, incomplete, or expired.  On
+    /// success, disarms `resetter`.
+    #[cfg(not(feature = "dirfetch"))]
+    async fn finish_bootstrap(self: &Arc<Self>, resetter: BoolResetter<'_>) -> Result<()> {
+        let valid_until = self
+            .store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .latest_consensus_meta(ConsensusFlavor::Microdesc)?
+            .map(|meta| meta.lifetime().valid_until())
+            .ok_or(Error::DirectoryNotPresent)?;
+        self.check_snapshot_timely(valid_until)?;
+
+        if !self.load_directory().await? {
+            warn!("The directory snapshot in our cache is not complete enough to use.");
+            return Err(Error::DirectoryNotPresent);
+        }
+        info!("Loaded a good directory snapshot from cache.");
+
+        let dirmgr_weak = Arc::downgrade(self);
+        self.runtime
+            .spawn(async move {
+                if let Err(e) = Self::reload_snapshot_forever(dirmgr_weak).await {
+                    match e {
+                        Error::ManagerDropped => {}
+                        _ => warn!(
+                            "Unrecovered error while reloading directory snapshot: {}",
+                            e
+                        ),
+                    }
+                }
+            })
+            .map_err(|e| Error::from_spawn("directory snapshot reloader task", e))?;
+
+        resetter.disarm();
+        Ok(())
+    }
+
+    /// Helper for `bootstrap` on a `DirMgr` created with `create_offline`:
+    /// load the directory from the cache, if it is complete and no more than
+    /// `max_staleness` past its expiration.
+    async fn bootstrap_offline(self: &Arc<Self>, max_staleness: std::time::Duration) -> Result<()> {
+        if self.netdir.get().is_some() {
+            // We only ever load once.
+            return Ok(());
+        }
+
+        let valid_until = self
+            .store
+            .lock()
+            .expect("Directory storage lock poisoned")
+            .latest_consensus_meta(ConsensusFlavor::Microdesc)?
+            .map(|meta| meta.lifetime().valid_until())
+            .ok_or(Error::OfflineDirectoryMissing)?;
+        let staleness = self.runtime.wallclock().duration_since(valid_until);
+        if matches!(staleness, Ok(d) if d > max_staleness) {
+            return Err(Error::OfflineDirectoryTooStale {
+                valid_until,
+                max_staleness,
+            });
+        }
+
+        if !self.load_directory().await? {
+            warn!("The directory in our cache is not complete enough to use offline.");
+            return Err(Error::OfflineDirectoryMissing);
+        }
+        info!("Loaded a directory from cache for offline use.");
+        Ok(())
+    }
+
+    /// Return an error if a directory snapshot that is valid until
+    /// `valid_until` has expired.
+    ///
+    /// Without the `dirfetch` feature, nothing will replace an expired
+    /// directory for us, so we refuse to use it.
+    ///
+    /// (An offline `DirMgr` accepts a snapshot as long as it is within its
+    /// configured tolerance.)
+    #[cfg(not(feature = "dirfetch"))]
+    fn check_snapshot_timely(&self, valid_until: SystemTime) -> Result<()> {
+        let tolerance = self.offline_tolerance.unwrap_or_default();
+        if valid_until + tolerance < self.runtime.wallclock() {
+            Err(Error::SnapshotExpired(valid_until))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns `true` if a bootstrap attempt is in progress, or successfully completed.
     pub fn bootstrap_started(&self) -> bool {
         self.bootstrap_started.load(Ordering::SeqCst)
@@ -439,6 +648,7 @@ impl<R: Runtime> DirMgr<R> {
     /// message using `on_complete`.
     ///
     /// If we eventually become the owner, return Ok().
+    #[cfg(feature = "dirfetch")]
     async fn reload_until_owner(
         weak: &Weak<Self>,
         on_complete: &mut Option<oneshot::Sender<()>>,
@@ -508,6 +718,7 @@ impl<R: Runtime> DirMgr<R> {
     ///
     /// If we have begin to have a bootstrapped directory, send a
     /// message using `on_complete`.
+    #[cfg(feature = "dirfetch")]
     async fn download_forever(
         weak: Weak<Self>,
         mut on_complete: Option<oneshot::Sender<()>>,
@@ -523,6 +734,12 @@ impl<R: Runtime> DirMgr<R> {
         };
 
         loop {
+            // We own the cache now, so it's up to us to keep it from
+            // growing without bound.
+            if let Err(e) = upgrade_weak_ref(&weak)?.expire_cache() {
+                warn!("Unable to expire old directory documents: {}", e);
+            }
+
             let mut usable = false;
 
             let retry_config = {
@@ -583,7 +800,34 @@ impl<R: Runtime> DirMgr<R> {
         }
     }
 
+    /// Periodically reload the directory snapshot from the cache, so that
+    /// we notice when somebody else replaces it with a newer one.
+    ///
+    /// Only returns on error.
+    #[cfg(not(feature = "dirfetch"))]
+    async fn reload_snapshot_forever(weak: Weak<Self>) -> Result<()> {
+        let runtime = upgrade_weak_ref(&weak)?.runtime.clone();
+        let mut warned_expired = false;
+
+        loop {
+            runtime.sleep(SNAPSHOT_RELOAD_INTERVAL).await;
+
+            let dirmgr = upgrade_weak_ref(&weak)?;
+            trace!("Checking the directory cache for a newer snapshot");
+            dirmgr.load_directory().await?;
+            match dirmgr.netdir() {
+                Err(Error::SnapshotExpired(_)) if !warned_expired => {
+                    warn!("Our directory snapshot has expired, and nothing has replaced it. We can't build circuits until the cache is refreshed.");
+                    warned_expired = true;
+                }
+                Ok(_) => warned_expired = false,
+                Err(_) => {}
+            }
+        }
+    }
+
     /// Get a reference to the circuit manager, if we have one.
+    #[cfg(feature = "dirfetch")]
     fn circmgr(&self) -> Result<Arc<CircMgr<R>>> {
         self.circmgr
             .as_ref()
@@ -641,6 +885,14 @@ impl<R: Runtime> DirMgr<R> {
         self.receive_status.clone()
     }
 
+    /// Return a stream of the non-fatal errors that we encounter while
+    /// handling downloaded directory documents.
+    ///
+    /// Only errors that happen after this call are reported.
+    pub fn download_errors(&self) -> DownloadErrors {
+        self.download_errors.subscribe()
+    }
+
     /// Replace the latest status with `new_status` and broadcast to anybody
     /// watching via a [`DirBootstrapEvents`] stream.
     fn update_status(&self, new_status: DirStatus) {
@@ -657,6 +909,7 @@ impl<R: Runtime> DirMgr<R> {
     /// Return true if we got the lock, or if we already had it.
     ///
     /// Return false if another process has the lock
+    #[cfg(feature = "dirfetch")]
     fn try_upgrade_to_readwrite(&self) -> Result<bool> {
         self.store
             .lock()
@@ -664,6 +917,29 @@ impl<R: Runtime> DirMgr<R> {
             .upgrade_to_readwrite()
     }
 
+    /// Delete expired documents from our cache.
+    ///
+    /// We keep superseded consensuses, old microdescriptors, and expired
+    /// certificates around for a while, but not forever.  We never delete
+    /// our latest usable consensus, or the documents it refers to.
+    ///
+    /// This happens automatically when we download a new consensus, and
+    /// periodically while we're keeping the directory up to date.  It does
+    /// nothing if another process is managing the cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock on our storage has been poisoned.
+    pub fn expire_cache(&self) -> Result<()> {
+        if let Some(store) = self.store_if_rw() {
+            store
+                .lock()
+                .expect("Directory storage lock poisoned")
+                .expire_all(&storage::EXPIRATION_DEFAULTS)?;
+        }
+        Ok(())
+    }
+
     /// Return a reference to the store, if it is currently read-write.
     fn store_if_rw(&self) -> Option<&Mutex<DynStore>> {
         let rw = !self
@@ -706,9 +982,11 @@ impl<R: Runtime> DirMgr<R> {
             events,
             send_status,
             receive_status,
+            download_errors: event::DownloadErrorSender::default(),
             circmgr,
             runtime,
             offline,
+            offline_tolerance: None,
             bootstrap_started: AtomicBool::new(false),
         })
     }
@@ -726,7 +1004,7 @@ impl<R: Runtime> DirMgr<R> {
 
     /// Return an Arc handle to our latest directory, if we have one.
     pub fn opt_netdir(&self) -> Option<Arc<NetDir>> {
-        self.netdir.get()
+        self.netdir().ok()
     }
 
     /// Return an Arc handle to our latest directory, returning an error if there is none.
@@ -734,9 +1012,15 @@ impl<R: Runtime> DirMgr<R> {
     /// # Errors
     ///
     /// Errors with [`Error::DirectoryNotPresent`] if the `DirMgr` hasn't been bootstrapped yet.
+    ///
+    /// Without the `dirfetch` feature, errors with [`Error::SnapshotExpired`]
+    /// if the directory snapshot we loaded has expired.
     // TODO: Add variants of this that make sure that it's up-to-date?
     pub fn netdir(&self) -> Result<Arc<NetDir>> {
-        self.opt_netdir().ok_or(Error::DirectoryNotPresent)
+        let netdir = self.netdir.get().ok_or(Error::DirectoryNotPresent)?;
+        #[cfg(not(feature = "dirfetch"))]
+        self.check_snapshot_timely(netdir.lifetime().valid_until())?;
+        Ok(netdir)
     }
 
     /// Return a new asynchronous stream that will receive notification
@@ -844,9 +1128,11 @@ impl<R: Runtime> DirMgr<R> {
     ///
     /// This conversion has to be a function of the dirmgr, since it may
     /// require knowledge about our current state.
+    #[cfg(feature = "dirfetch")]
     fn query_into_requests(&self, q: DocQuery) -> Result<Vec<ClientRequest>> {
         let mut res = Vec::new();
-        for q in q.split_for_download() {
+        let balanced = self.config.get().schedule().balance_descriptor_batches();
+        for q in q.split_for_download(&mut rand::thread_rng(), balanced) {
             match q {
                 DocQuery::LatestConsensus { flavor, .. } => {
                     res.push(self.make_consensus_request(flavor)?);
@@ -868,6 +1154,7 @@ impl<R: Runtime> DirMgr<R> {
 
     /// Construct an appropriate ClientRequest to download a consensus
     /// of the given flavor.
+    #[cfg(feature = "dirfetch")]
     fn make_consensus_request(&self, flavor: ConsensusFlavor) -> Result<ClientRequest> {
         #![allow(clippy::unnecessary_wraps)]
         let mut request = tor_dirclient::request::ConsensusRequest::new(flavor);
@@ -894,6 +1181,7 @@ impl<R: Runtime> DirMgr<R> {
     /// Currently, this handles expanding consensus diffs, and nothing
     /// else.  We do it at this stage of our downloading operation
     /// because it requires access to the store.
+    #[cfg(feature = "dirfetch")]
     fn expand_response_text(&self, req: &ClientRequest, text: String) -> Result<String> {
         if let ClientRequest::Consensus(req) = req {
             if tor_consdiff::looks_like_diff(&text) {
@@ -926,6 +1214,7 @@ impl<R: Runtime> DirMgr<R> {
 #[derive(Debug, Copy, Clone)]
 enum Readiness {
     /// There is no more information to download.
+    #[cfg(any(test, feature = "dirfetch"))]
     Complete,
     /// There is more information to download, but we don't need to
     Usable,
@@ -986,6 +1275,7 @@ trait DirState: Send {
     // TODO: It would be better to not have this function be async,
     // once the `must_not_suspend` lint is stable.
     // TODO: this should take a "DirSource" too.
+    #[cfg(feature = "dirfetch")]
     fn add_from_download(
         &mut self,
         text: &str,
@@ -996,13 +1286,16 @@ trait DirState: Send {
     fn bootstrap_status(&self) -> event::DirStatus;
 
     /// Return a configuration for attempting downloads.
+    #[cfg(feature = "dirfetch")]
     fn dl_config(&self) -> Result<DownloadSchedule>;
     /// If possible, advance to the next state.
     fn advance(self: Box<Self>) -> Result<Box<dyn DirState>>;
     /// Return a time (if any) when downloaders should stop attempting to
     /// advance this state, and should instead reset it and start over.
+    #[cfg(feature = "dirfetch")]
     fn reset_time(&self) -> Option<SystemTime>;
     /// Reset this state and start over.
+    #[cfg(feature = "dirfetch")]
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>>;
 }
 
@@ -1017,9 +1310,15 @@ mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
     use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+    use crate::Authority;
     use std::time::Duration;
     use tempfile::TempDir;
-    use tor_netdoc::doc::{authcert::AuthCertKeyIds, netstatus::Lifetime};
+    use tor_checkable::{SelfSigned, Timebound};
+    use tor_llcrypto::pk::rsa::RsaIdentity;
+    use tor_netdoc::doc::authcert::{AuthCert, AuthCertKeyIds};
+    use tor_netdoc::doc::microdesc::MicrodescReader;
+    use tor_netdoc::doc::netstatus::{Lifetime, MdConsensus};
+    use tor_netdoc::AllowAnnotations;
 
     pub(crate) fn new_mgr<R: Runtime>(runtime: R) -> (TempDir, DirMgr<R>) {
         let dir = TempDir::new().unwrap();
@@ -1032,11 +1331,93 @@ mod test {
         (dir, dirmgr)
     }
 
+    /// Return a `DirMgr` whose cache holds a complete directory snapshot
+    /// from our test data, along with the time at which that snapshot
+    /// becomes valid.
+    ///
+    /// The snapshot is signed by test authorities, so the `DirMgr` is
+    /// configured to trust those authorities.
+    pub(crate) fn new_mgr_with_snapshot<R: Runtime>(
+        runtime: R,
+    ) -> (TempDir, DirMgr<R>, SystemTime) {
+        const CONSENSUS: &str = include_str!("../testdata/snapshot-consensus.txt");
+        const CERTS: [&str; 2] = [
+            include_str!("../testdata/snapshot-cert-039E.txt"),
+            include_str!("../testdata/snapshot-cert-E301.txt"),
+        ];
+        const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
+
+        let dir = TempDir::new().unwrap();
+        let mut netcfg = NetworkConfig::builder();
+        netcfg.fallback_caches(vec![]);
+        netcfg.authorities(
+            [
+                "039E3F7C9D2CFA1049B72A187ABCF4D39BB82BA5",
+                "E301027D774319305113D369216B3464F8942FA7",
+            ]
+            .iter()
+            .map(|id| {
+                let id = hex::decode(id).unwrap();
+                Authority::builder()
+                    .name("test")
+                    .v3ident(RsaIdentity::from_bytes(&id).unwrap())
+                    .build()
+                    .unwrap()
+            })
+            .collect(),
+        );
+        let config = DirMgrConfig::builder()
+            .cache_path(dir.path())
+            .network_config(netcfg.build().unwrap())
+            .build()
+            .unwrap();
+        let dirmgr = DirMgr::from_config(config, runtime, None, false).unwrap();
+
+        let (signed, rest, consensus) = MdConsensus::parse(CONSENSUS).unwrap();
+        let consensus = consensus.dangerously_assume_timely();
+        let valid_after = consensus.peek_lifetime().valid_after();
+        {
+            let mut store = dirmgr.store.lock().unwrap();
+            let meta = ConsensusMeta::from_unvalidated(signed, rest, &consensus);
+            store
+                .store_consensus(&meta, ConsensusFlavor::Microdesc, false, CONSENSUS)
+                .unwrap();
+
+            let certs: Vec<_> = CERTS
+                .iter()
+                .map(|text| {
+                    let cert = AuthCert::parse(text)
+                        .unwrap()
+                        .check_signature()
+                        .unwrap()
+                        .dangerously_assume_timely();
+                    let meta = AuthCertMeta::new(*cert.key_ids(), cert.published(), cert.expires());
+                    (meta, *text)
+                })
+                .collect();
+            store.store_authcerts(&certs[..]).unwrap();
+
+            let mds: Vec<_> =
+                MicrodescReader::new(MICRODESCS, &AllowAnnotations::AnnotationsNotAllowed)
+                    .map(|res| {
+                        let anno = res.unwrap();
+                        let text = anno.within(MICRODESCS).unwrap();
+                        (text, *anno.into_microdesc().digest())
+                    })
+                    .collect();
+            let mds: Vec<_> = mds.iter().map(|(text, d)| (*text, d)).collect();
+            store.store_microdescs(&mds[..], valid_after).unwrap();
+        }
+
+        (dir, dirmgr, valid_after)
+    }
+
     #[test]
     fn failing_accessors() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
             let (_tempdir, mgr) = new_mgr(rt);
 
+            #[cfg(feature = "dirfetch")]
             assert!(mgr.circmgr().is_err());
             assert!(mgr.opt_netdir().is_none());
         });
@@ -1158,6 +1539,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "dirfetch")]
     fn make_consensus_request() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
             let (_tempdir, mgr) = new_mgr(rt);
@@ -1210,6 +1592,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "dirfetch")]
     fn make_other_requests() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
             use rand::Rng;
@@ -1253,6 +1636,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "dirfetch")]
     fn expand_response() {
         tor_rtcompat::test_with_one_runtime!(|rt| async {
             let (_tempdir, mgr) = new_mgr(rt);
@@ -1325,6 +1709,211 @@ replacement line
         });
     }
 
+    #[test]
+    #[cfg(not(feature = "dirfetch"))]
+    fn snapshot_missing_or_expired() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            // With nothing in the cache, there's nothing to bootstrap from.
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+            let mgr = Arc::new(mgr);
+            assert!(matches!(
+                mgr.bootstrap().await,
+                Err(Error::DirectoryNotPresent)
+            ));
+            assert!(!mgr.bootstrap_started());
+
+            // With a snapshot that has expired, we refuse to bootstrap.
+            let (_tempdir, mgr) = new_mgr(rt);
+            let now = SystemTime::now();
+            let valid_until = now - Duration::from_secs(86400);
+            {
+                let mut store = mgr.store.lock().unwrap();
+                let cmeta = ConsensusMeta::new(
+                    Lifetime::new(
+                        valid_until - Duration::from_secs(7200),
+                        valid_until - Duration::from_secs(3600),
+                        valid_until,
+                    )
+                    .unwrap(),
+                    [102; 32],
+                    [103; 32],
+                );
+                store
+                    .store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, "Fake consensus!")
+                    .unwrap();
+            }
+            let mgr = Arc::new(mgr);
+            match mgr.bootstrap().await {
+                Err(Error::SnapshotExpired(t)) => assert_eq!(t, valid_until),
+                other => panic!("Unexpected result {:?}", other),
+            }
+            assert!(!mgr.bootstrap_started());
+        });
+    }
+
+    #[test]
+    #[cfg(not(feature = "dirfetch"))]
+    fn snapshot_expires_in_place() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr) = new_mgr(rt.clone());
+
+            let netdir = tor_netdir::testnet::construct_netdir()
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+            let valid_until = netdir.lifetime().valid_until();
+            mgr.netdir.replace(netdir);
+            assert!(mgr.netdir().is_ok());
+            assert!(mgr.opt_netdir().is_some());
+
+            // Once the clock passes the end of the snapshot's lifetime, we
+            // stop handing it out.
+            rt.jump_to(valid_until + Duration::from_secs(1));
+            match mgr.netdir() {
+                Err(Error::SnapshotExpired(t)) => assert_eq!(t, valid_until),
+                other => panic!("Unexpected result {:?}", other),
+            }
+            assert!(mgr.opt_netdir().is_none());
+        });
+    }
+
+    #[test]
+    fn load_snapshot_from_cache() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr, valid_after) = new_mgr_with_snapshot(rt.clone());
+            let mgr = Arc::new(mgr);
+
+            rt.jump_to(valid_after + Duration::from_secs(10));
+            assert!(mgr.load_directory().await.unwrap());
+            let netdir = mgr.netdir().unwrap();
+            assert_eq!(netdir.lifetime().valid_after(), valid_after);
+        });
+    }
+
+    #[test]
+    #[cfg(not(feature = "dirfetch"))]
+    fn snapshot_bootstrap() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr, valid_after) = new_mgr_with_snapshot(rt.clone());
+            let mgr = Arc::new(mgr);
+
+            // While the snapshot is current, we bootstrap from it.
+            rt.jump_to(valid_after + Duration::from_secs(10));
+            mgr.bootstrap().await.unwrap();
+            assert!(mgr.bootstrap_started());
+            let netdir = mgr.netdir().unwrap();
+            assert_eq!(netdir.lifetime().valid_after(), valid_after);
+        });
+    }
+
+    #[test]
+    fn offline_missing_or_stale() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let (tempdir, mgr) = new_mgr(rt.clone());
+            let config = DirMgrConfig::builder()
+                .cache_path(tempdir.path())
+                .build()
+                .unwrap();
+            let hour = Duration::from_secs(3600);
+
+            // With no cache at all, we can't even create the DirMgr.
+            let empty_dir = TempDir::new().unwrap();
+            let empty_config = DirMgrConfig::builder()
+                .cache_path(empty_dir.path())
+                .build()
+                .unwrap();
+            assert!(matches!(
+                DirMgr::create_offline(empty_config, rt.clone(), hour),
+                Err(Error::OfflineDirectoryMissing)
+            ));
+            // Likewise if the cache directory isn't even there.
+            let missing_config = DirMgrConfig::builder()
+                .cache_path(empty_dir.path().join("missing"))
+                .build()
+                .unwrap();
+            assert!(matches!(
+                DirMgr::create_offline(missing_config, rt.clone(), hour),
+                Err(Error::OfflineDirectoryMissing)
+            ));
+
+            // With nothing in the cache, there's nothing to load.
+            let offline = DirMgr::create_offline(config.clone(), rt.clone(), hour).unwrap();
+            assert!(matches!(
+                offline.bootstrap().await,
+                Err(Error::OfflineDirectoryMissing)
+            ));
+            drop(offline);
+
+            // Put a consensus that expired a day ago into the cache.
+            let valid_until = SystemTime::now() - hour * 24;
+            {
+                let mut store = mgr.store.lock().unwrap();
+                let cmeta = ConsensusMeta::new(
+                    Lifetime::new(valid_until - hour * 2, valid_until - hour, valid_until).unwrap(),
+                    [102; 32],
+                    [103; 32],
+                );
+                store
+                    .store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, "Fake consensus!")
+                    .unwrap();
+            }
+
+            // An hour of tolerance isn't enough...
+            let offline = DirMgr::create_offline(config.clone(), rt.clone(), hour).unwrap();
+            match offline.bootstrap().await {
+                Err(Error::OfflineDirectoryTooStale {
+                    valid_until: t,
+                    max_staleness,
+                }) => {
+                    assert_eq!(t, valid_until);
+                    assert_eq!(max_staleness, hour);
+                }
+                other => panic!("Unexpected result {:?}", other),
+            }
+            assert!(offline.opt_netdir().is_none());
+
+            // ... but two days would be, if the consensus were real.
+            let offline = DirMgr::create_offline(config, rt, hour * 48).unwrap();
+            assert!(!matches!(
+                offline.bootstrap().await,
+                Err(Error::OfflineDirectoryTooStale { .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn offline_from_stale_snapshot() {
+        tor_rtcompat::test_with_one_runtime!(|rt| async {
+            let rt = tor_rtmock::MockSleepRuntime::new(rt);
+            let (_tempdir, mgr, valid_after) = new_mgr_with_snapshot(rt.clone());
+            let config = (*mgr.config.get()).clone();
+            drop(mgr);
+            let hour = Duration::from_secs(3600);
+
+            // About half an hour after the consensus expired, an hour of tolerance
+            // is enough to bootstrap from it.
+            rt.jump_to(valid_after + hour / 2);
+            let offline = DirMgr::create_offline(config.clone(), rt.clone(), hour).unwrap();
+            offline.bootstrap().await.unwrap();
+            let netdir = offline.netdir().unwrap();
+            assert_eq!(netdir.lifetime().valid_after(), valid_after);
+            drop(offline);
+
+            // The tolerance only applies after expiration: it doesn't let
+            // us use a consensus that isn't valid yet.
+            rt.jump_to(valid_after - hour / 2);
+            let offline = DirMgr::create_offline(config, rt.clone(), hour).unwrap();
+            assert!(matches!(
+                offline.bootstrap().await,
+                Err(Error::OfflineDirectoryMissing)
+            ));
+            assert!(offline.opt_netdir().is_none());
+        });
+    }
+
     #[test]
     #[allow(clippy::bool_assert_comparison)]
     fn bool_resetter_works() {
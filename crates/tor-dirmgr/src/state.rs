@@ -16,6 +16,7 @@ use std::fmt::Debug;
 use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, SystemTime};
 use time::OffsetDateTime;
+#[cfg(feature = "dirfetch")]
 use tor_error::internal;
 use tor_netdir::{MdReceiver, NetDir, PartialNetDir};
 use tor_netdoc::doc::netstatus::Lifetime;
@@ -24,28 +25,25 @@ use tracing::{info, warn};
 use crate::event::{DirStatus, DirStatusInner};
 
 use crate::storage::{DynStore, EXPIRATION_DEFAULTS};
+#[cfg(feature = "dirfetch")]
+use crate::{docmeta::AuthCertMeta, retry::DownloadSchedule, ClientRequest};
 use crate::{
-    docmeta::{AuthCertMeta, ConsensusMeta},
-    retry::DownloadSchedule,
-    shared_ref::SharedMutArc,
-    CacheUsage, ClientRequest, DirMgrConfig, DirState, DocId, DocumentText, Error, Readiness,
-    Result,
+    docmeta::ConsensusMeta, shared_ref::SharedMutArc, CacheUsage, DirMgrConfig, DirState, DocId,
+    DocumentText, Error, Readiness, Result,
 };
 use crate::{DirEvent, DocSource};
 use tor_checkable::{ExternallySigned, SelfSigned, Timebound};
 use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::doc::{
+    authcert::{AuthCert, AuthCertKeyIds},
+    netstatus::{ConsensusFlavor, UnvalidatedMdConsensus},
+};
 use tor_netdoc::doc::{
     microdesc::{MdDigest, Microdesc},
     netstatus::MdConsensus,
 };
-use tor_netdoc::{
-    doc::{
-        authcert::{AuthCert, AuthCertKeyIds},
-        microdesc::MicrodescReader,
-        netstatus::{ConsensusFlavor, UnvalidatedMdConsensus},
-    },
-    AllowAnnotations,
-};
+#[cfg(feature = "dirfetch")]
+use tor_netdoc::{doc::microdesc::MicrodescReader, AllowAnnotations};
 use tor_rtcompat::Runtime;
 
 /// An object where we can put a usable netdir.
@@ -80,10 +78,19 @@ pub(crate) trait WriteNetDir: 'static + Sync + Send {
 
     /// Called to find the current time.
     ///
-    /// This is just `SystemTime::now()` in production, but for
+    /// This is just the runtime's wall-clock time in production, but for
     /// testing it is helpful to be able to mock our our current view
     /// of the time.
     fn now(&self) -> SystemTime;
+
+    /// Return how long past their expiration we're willing to accept
+    /// consensuses and authority certificates from the cache.
+    ///
+    /// This is zero except for a `DirMgr` in offline mode, which has been
+    /// explicitly configured to tolerate a somewhat stale directory.
+    fn expiry_tolerance(&self) -> Duration {
+        Duration::ZERO
+    }
 }
 
 impl<R: Runtime> WriteNetDir for crate::DirMgr<R> {
@@ -106,7 +113,10 @@ impl<R: Runtime> WriteNetDir for crate::DirMgr<R> {
         }
     }
     fn now(&self) -> SystemTime {
-        SystemTime::now()
+        self.runtime.wallclock()
+    }
+    fn expiry_tolerance(&self) -> Duration {
+        self.offline_tolerance.unwrap_or(Duration::ZERO)
     }
 }
 
@@ -206,6 +216,7 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
             DirStatusInner::NoConsensus { after: self.after }.into()
         }
     }
+    #[cfg(feature = "dirfetch")]
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_consensus())
@@ -234,6 +245,7 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
         self.add_consensus_text(source, text.as_str().map_err(Error::BadUtf8InCache)?)
             .map(|meta| meta.is_some())
     }
+    #[cfg(feature = "dirfetch")]
     fn add_from_download(
         &mut self,
         text: &str,
@@ -257,9 +269,11 @@ impl<DM: WriteNetDir> DirState for GetConsensusState<DM> {
             None => self,
         })
     }
+    #[cfg(feature = "dirfetch")]
     fn reset_time(&self) -> Option<SystemTime> {
         None
     }
+    #[cfg(feature = "dirfetch")]
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
         Ok(self)
     }
@@ -279,11 +293,16 @@ impl<DM: WriteNetDir> GetConsensusState<DM> {
             let (signedval, remainder, parsed) =
                 MdConsensus::parse(text).map_err(|e| Error::from_netdoc(source.clone(), e))?;
             let now = current_time(&self.writedir)?;
-            if let Ok(timely) = parsed.check_valid_at(&now) {
-                let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
-                (meta, timely)
-            } else {
-                return Ok(None);
+            let (pre, post) = time_tolerances(&self.writedir)?;
+            match parsed.extend_tolerances(pre, post).check_valid_at(&now) {
+                Ok(timely) => {
+                    let meta = ConsensusMeta::from_unvalidated(signedval, remainder, &timely);
+                    (meta, timely)
+                }
+                // An old consensus in the cache is no surprise; one that
+                // we just downloaded may mean that our clock is wrong.
+                Err(_) if matches!(source, DocSource::LocalCache) => return Ok(None),
+                Err(cause) => return Err(Error::UntimelyObject { source, cause }),
             }
         };
 
@@ -384,6 +403,7 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
         }
         .into()
     }
+    #[cfg(feature = "dirfetch")]
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_certs())
@@ -406,7 +426,8 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
                     .map_err(|e| Error::from_netdoc(DocSource::LocalCache, e))?
                     .check_signature()?;
                 let now = current_time(&self.writedir)?;
-                if let Ok(cert) = parsed.check_valid_at(&now) {
+                let (pre, post) = time_tolerances(&self.writedir)?;
+                if let Ok(cert) = parsed.extend_tolerances(pre, post).check_valid_at(&now) {
                     self.missing_certs.remove(cert.key_ids());
                     self.certs.push(cert);
                     changed = true;
@@ -417,6 +438,7 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
         }
         Ok(changed)
     }
+    #[cfg(feature = "dirfetch")]
     fn add_from_download(
         &mut self,
         text: &str,
@@ -436,8 +458,11 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
                     .expect("Certificate was not in input as expected");
                 if let Ok(wellsigned) = parsed.check_signature() {
                     let now = current_time(&self.writedir)?;
-                    if let Ok(timely) = wellsigned.check_valid_at(&now) {
-                        newcerts.push((timely, s));
+                    let (pre, post) = time_tolerances(&self.writedir)?;
+                    match wellsigned.extend_tolerances(pre, post).check_valid_at(&now) {
+                        Ok(timely) => newcerts.push((timely, s)),
+                        // TODO: note the source.
+                        Err(e) => warn!("Untimely certificate received and discarded: it {}", e),
                     }
                 } else {
                     // TODO: note the source.
@@ -502,9 +527,11 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
             Ok(self)
         }
     }
+    #[cfg(feature = "dirfetch")]
     fn reset_time(&self) -> Option<SystemTime> {
         Some(self.consensus_meta.lifetime().valid_until())
     }
+    #[cfg(feature = "dirfetch")]
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
         Ok(Box::new(GetConsensusState::new(
             self.writedir,
@@ -517,6 +544,7 @@ impl<DM: WriteNetDir> DirState for GetCertsState<DM> {
 #[derive(Debug, Clone)]
 struct GetMicrodescsState<DM: WriteNetDir> {
     /// How should we get the consensus from the cache, if at all?
+    #[cfg_attr(not(feature = "dirfetch"), allow(dead_code))]
     cache_usage: CacheUsage,
     /// The digests of the microdescriptors we are missing.
     missing: HashSet<MdDigest>,
@@ -715,6 +743,7 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
     }
     fn is_ready(&self, ready: Readiness) -> bool {
         match ready {
+            #[cfg(any(test, feature = "dirfetch"))]
             Readiness::Complete => self.missing.is_empty(),
             Readiness::Usable => self.partial.is_none(),
         }
@@ -731,6 +760,7 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         }
         .into()
     }
+    #[cfg(feature = "dirfetch")]
     fn dl_config(&self) -> Result<DownloadSchedule> {
         if let Some(wd) = Weak::upgrade(&self.writedir) {
             Ok(*wd.config().schedule().retry_microdescs())
@@ -769,6 +799,7 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
         Ok(changed)
     }
 
+    #[cfg(feature = "dirfetch")]
     fn add_from_download(
         &mut self,
         text: &str,
@@ -826,9 +857,11 @@ impl<DM: WriteNetDir> DirState for GetMicrodescsState<DM> {
     fn advance(self: Box<Self>) -> Result<Box<dyn DirState>> {
         Ok(self)
     }
+    #[cfg(feature = "dirfetch")]
     fn reset_time(&self) -> Option<SystemTime> {
         Some(self.reset_time)
     }
+    #[cfg(feature = "dirfetch")]
     fn reset(self: Box<Self>) -> Result<Box<dyn DirState>> {
         let cache_usage = if self.cache_usage == CacheUsage::CacheOnly {
             // Cache only means we can't ever download.
@@ -897,7 +930,23 @@ fn current_time<DM: WriteNetDir>(writedir: &Weak<DM>) -> Result<SystemTime> {
     }
 }
 
-#[cfg(test)]
+/// Helper: return how long before and after its validity period we're
+/// willing to accept a consensus or certificate, as a `(pre, post)` pair.
+///
+/// Both include our configured clock skew tolerance; `post` also includes
+/// our `expiry_tolerance`.
+fn time_tolerances<DM: WriteNetDir>(writedir: &Weak<DM>) -> Result<(Duration, Duration)> {
+    if let Some(writedir) = Weak::upgrade(writedir) {
+        let skew = writedir.config().clock_skew_tolerance();
+        Ok((skew, writedir.expiry_tolerance().saturating_add(skew)))
+    } else {
+        Err(Error::ManagerDropped)
+    }
+}
+
+// These tests drive each state through its downloads, so they need the
+// `dirfetch` feature.
+#[cfg(all(test, feature = "dirfetch"))]
 mod test {
     #![allow(clippy::unwrap_used)]
     #![allow(clippy::cognitive_complexity)]
@@ -973,6 +1022,18 @@ mod test {
                 descriptors_changed: false.into(),
             }
         }
+
+        /// Replace our configuration with one that tolerates up to `d` of
+        /// clock skew.
+        fn with_clock_skew_tolerance(mut self, d: Duration) -> Self {
+            let new_cfg = DirMgrConfig::builder()
+                .cache_path("/we_will_never_use_this/")
+                .clock_skew_tolerance(d)
+                .build()
+                .unwrap();
+            self.cfg = Arc::new(self.cfg.update_config(&new_cfg));
+            self
+        }
     }
 
     impl WriteNetDir for DirRcv {
@@ -1157,6 +1218,54 @@ mod test {
         assert!(state.can_advance());
     }
 
+    #[test]
+    fn get_consensus_clock_skew() {
+        let (_tempdir, store) = temp_store();
+        let req = tor_dirclient::request::ConsensusRequest::new(ConsensusFlavor::Microdesc);
+        let req = crate::docid::ClientRequest::Consensus(req);
+        // CONSENSUS stopped being valid 25 seconds before this.
+        let late = test_time() + Duration::from_secs(60);
+
+        // With no tolerance, we reject the downloaded consensus, and say why.
+        let rcv = Arc::new(DirRcv::new(late, Some(test_authorities())));
+        let mut state =
+            GetConsensusState::new(Arc::downgrade(&rcv), CacheUsage::CacheOkay).unwrap();
+        let outcome = state.add_from_download(CONSENSUS, &req, Some(&store));
+        match outcome {
+            Err(Error::UntimelyObject {
+                source: DocSource::DirServer {},
+                cause: tor_checkable::TimeValidityError::Expired(d),
+            }) => assert_eq!(d, Duration::from_secs(25)),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert!(!state.can_advance());
+
+        // An untimely consensus in the cache is just ignored.
+        let docid = state.missing_docs()[0];
+        let text: crate::storage::InputString = CONSENSUS.to_owned().into();
+        let map = vec![(docid, text.into())].into_iter().collect();
+        assert!(!state.add_from_cache(map, None).unwrap());
+
+        // Too little tolerance doesn't help...
+        let rcv = DirRcv::new(late, Some(test_authorities()))
+            .with_clock_skew_tolerance(Duration::from_secs(20));
+        let rcv = Arc::new(rcv);
+        let mut state =
+            GetConsensusState::new(Arc::downgrade(&rcv), CacheUsage::CacheOkay).unwrap();
+        let outcome = state.add_from_download(CONSENSUS, &req, Some(&store));
+        assert!(matches!(outcome, Err(Error::UntimelyObject { .. })));
+
+        // ... but enough tolerance does.
+        let rcv = DirRcv::new(late, Some(test_authorities()))
+            .with_clock_skew_tolerance(Duration::from_secs(30));
+        let rcv = Arc::new(rcv);
+        let mut state =
+            GetConsensusState::new(Arc::downgrade(&rcv), CacheUsage::CacheOkay).unwrap();
+        let outcome = state.add_from_download(CONSENSUS, &req, Some(&store));
+        assert!(outcome.unwrap());
+        assert!(state.can_advance());
+    }
+
     #[test]
     fn get_certs_state() {
         /// Construct a GetCertsState with our test data
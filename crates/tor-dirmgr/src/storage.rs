@@ -13,10 +13,15 @@ use tor_netdoc::doc::netstatus::ConsensusFlavor;
 #[cfg(feature = "routerdesc")]
 use tor_netdoc::doc::routerdesc::RdDigest;
 
-use crate::docmeta::{AuthCertMeta, ConsensusMeta};
-use crate::{Error, Result};
+#[cfg(any(test, feature = "dirfetch"))]
+use crate::docmeta::AuthCertMeta;
+use crate::docmeta::ConsensusMeta;
+#[cfg(any(test, feature = "dirfetch"))]
+use crate::Error;
+use crate::Result;
 use std::cell::RefCell;
 use std::collections::HashMap;
+#[cfg(any(test, feature = "dirfetch"))]
 use std::time::SystemTime;
 use std::{path::Path, str::Utf8Error};
 use time::Duration;
@@ -90,6 +95,7 @@ pub(crate) enum InputString {
 
 impl InputString {
     /// Return a view of this InputString as a &str, if it is valid UTF-8.
+    #[cfg(any(test, feature = "dirfetch"))]
     pub(crate) fn as_str(&self) -> Result<&str> {
         self.as_str_impl()
             .map_err(|_| Error::CacheCorruption("Invalid UTF-8"))
@@ -197,7 +203,9 @@ pub(crate) const EXPIRATION_DEFAULTS: ExpirationConfig = {
         router_descs: Duration::days(3 * 30),
         // TODO: Choose a more realistic time.
         microdescs: Duration::days(3 * 30),
-        authcerts: Duration::ZERO,
+        // Keep expired certificates for a little while, in case our
+        // clock is slightly wrong.
+        authcerts: Duration::days(1),
         consensuses: Duration::days(2),
     }
 };
@@ -212,12 +220,15 @@ pub(crate) trait Store {
     /// Try to upgrade from a read-only connection to a read-write connection.
     ///
     /// Return true on success; false if another process had the lock.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn upgrade_to_readwrite(&mut self) -> Result<bool>;
 
     /// Delete all completely-expired objects from the database.
     ///
     /// This is pretty conservative, and only removes things that are
-    /// definitely past their good-by date.
+    /// definitely past their good-by date.  It never removes the latest
+    /// usable consensus, or anything that consensus might still need.
+    /// Afterwards, it reclaims the space that the removed objects used.
     fn expire_all(&mut self, expiration: &ExpirationConfig) -> Result<()>;
 
     /// Load the latest consensus from disk.
@@ -235,14 +246,17 @@ pub(crate) trait Store {
     /// including its valid-after time and digest.
     fn latest_consensus_meta(&self, flavor: ConsensusFlavor) -> Result<Option<ConsensusMeta>>;
     /// Try to read the consensus corresponding to the provided metadata object.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<InputString>;
     /// Try to read the consensus whose SHA3-256 digests is the provided
     /// value, and its metadata.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn consensus_by_sha3_digest_of_signed_part(
         &self,
         d: &[u8; 32],
     ) -> Result<Option<(InputString, ConsensusMeta)>>;
     /// Write a consensus to disk.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_consensus(
         &mut self,
         cmeta: &ConsensusMeta,
@@ -258,15 +272,18 @@ pub(crate) trait Store {
     /// Read all of the specified authority certs from the cache.
     fn authcerts(&self, certs: &[AuthCertKeyIds]) -> Result<HashMap<AuthCertKeyIds, String>>;
     /// Save a list of authority certificates to the cache.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()>;
 
     /// Read all the microdescriptors listed in `input` from the cache.
     fn microdescs(&self, digests: &[MdDigest]) -> Result<HashMap<MdDigest, String>>;
     /// Store every microdescriptor in `input` into the cache, and say that
     /// it was last listed at `when`.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()>;
     /// Update the `last-listed` time of every microdescriptor in
     /// `input` to `when` or later.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()>;
 
     /// Read all the microdescriptors listed in `input` from the cache.
@@ -277,6 +294,7 @@ pub(crate) trait Store {
     /// Store every router descriptors in `input` into the cache.
     #[cfg(feature = "routerdesc")]
     #[allow(unused)]
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()>;
 }
 
@@ -4,7 +4,9 @@
 //! which we store as "blob" files in a separate directory.
 
 use super::ExpirationConfig;
-use crate::docmeta::{AuthCertMeta, ConsensusMeta};
+#[cfg(any(test, feature = "dirfetch"))]
+use crate::docmeta::AuthCertMeta;
+use crate::docmeta::ConsensusMeta;
 use crate::storage::{InputString, Store};
 use crate::{Error, Result};
 
@@ -14,14 +16,17 @@ use tor_netdoc::doc::netstatus::{ConsensusFlavor, Lifetime};
 #[cfg(feature = "routerdesc")]
 use tor_netdoc::doc::routerdesc::RdDigest;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::path::{self, Path, PathBuf};
+#[cfg(any(test, feature = "dirfetch"))]
 use std::time::SystemTime;
 
-use rusqlite::{params, OpenFlags, OptionalExtension, Transaction};
+#[cfg(any(test, feature = "dirfetch"))]
+use rusqlite::Transaction;
+use rusqlite::{params, OpenFlags, OptionalExtension};
 use time::OffsetDateTime;
-use tracing::trace;
+use tracing::{debug, trace};
 
 #[cfg(target_family = "unix")]
 use std::os::unix::fs::DirBuilderExt;
@@ -192,6 +197,7 @@ impl SqliteStore {
     ///
     /// Return a SavedBlobHandle that describes where the blob is, and which
     /// can be used either to commit the blob or delete it.
+    #[cfg(any(test, feature = "dirfetch"))]
     fn save_blob_internal(
         &mut self,
         contents: &[u8],
@@ -242,6 +248,35 @@ impl SqliteStore {
         Ok(fname)
     }
 
+    /// Delete every consensus blob in our blob directory that the ExtDocs
+    /// table doesn't know about.
+    ///
+    /// These can get left behind if we stop after an expiry pass commits, but
+    /// before it has unlinked the files that it expired.
+    fn remove_unreferenced_blobs(&self) -> Result<()> {
+        let known: HashSet<String> = {
+            let mut stmt = self.conn.prepare(FIND_ALL_EXTDOC_FILENAMES)?;
+            let names = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .filter_map(std::result::Result::ok)
+                .collect();
+            names
+        };
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            // Consensuses are the only blobs we store; leave anything else
+            // alone.
+            let name = match entry.file_name().into_string() {
+                Ok(name) if name.starts_with("con:") => name,
+                _ => continue,
+            };
+            if !known.contains(&name) {
+                let _ignore = std::fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
     /// Return the valid-after time for the latest non non-pending consensus,
     #[cfg(test)]
     // We should revise the tests to use latest_consensus_meta instead.
@@ -259,6 +294,7 @@ impl Store for SqliteStore {
             None => false,
         }
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn upgrade_to_readwrite(&mut self) -> Result<bool> {
         if self.is_readonly() && self.sql_path.is_some() {
             let lf = self
@@ -294,10 +330,20 @@ impl Store for SqliteStore {
             names
         };
 
+        // Never expire anything that our latest usable consensus might
+        // need, however old it is: it may be the only directory we have.
+        let protected_since: Option<OffsetDateTime> =
+            tx.query_row(FIND_LATEST_USABLE_VALID_AFTER, [], |row| row.get(0))?;
         let now = OffsetDateTime::now_utc();
+        let cutoff = |keep_for: time::Duration| match protected_since {
+            Some(t) => std::cmp::min(now - keep_for, t),
+            None => now - keep_for,
+        };
+
         tx.execute(DROP_OLD_EXTDOCS, [])?;
-        tx.execute(DROP_OLD_MICRODESCS, [now - expiration.microdescs])?;
-        tx.execute(DROP_OLD_AUTHCERTS, [now - expiration.authcerts])?;
+        tx.execute(DROP_CONSENSUSES_WITHOUT_EXTDOCS, [])?;
+        tx.execute(DROP_OLD_MICRODESCS, [cutoff(expiration.microdescs)])?;
+        tx.execute(DROP_OLD_AUTHCERTS, [cutoff(expiration.authcerts)])?;
         tx.execute(DROP_OLD_CONSENSUSES, [now - expiration.consensuses])?;
         tx.execute(DROP_OLD_ROUTERDESCS, [now - expiration.router_descs])?;
         tx.commit()?;
@@ -307,6 +353,14 @@ impl Store for SqliteStore {
                 let _ignore = std::fs::remove_file(fname);
             }
         }
+        self.remove_unreferenced_blobs()?;
+
+        // Give the space we just freed back to the filesystem.  This needs
+        // the database to itself for a moment, so if another reader is busy
+        // with it, we just try again next time.
+        if let Err(e) = self.conn.execute_batch(VACUUM) {
+            debug!("Couldn't vacuum the directory cache: {}", e);
+        }
         Ok(())
     }
 
@@ -346,6 +400,7 @@ impl Store for SqliteStore {
             Ok(None)
         }
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn consensus_by_meta(&self, cmeta: &ConsensusMeta) -> Result<InputString> {
         if let Some((text, _)) =
             self.consensus_by_sha3_digest_of_signed_part(cmeta.sha3_256_of_signed())?
@@ -357,6 +412,7 @@ impl Store for SqliteStore {
             ))
         }
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn consensus_by_sha3_digest_of_signed_part(
         &self,
         d: &[u8; 32],
@@ -375,6 +431,7 @@ impl Store for SqliteStore {
             Ok(None)
         }
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_consensus(
         &mut self,
         cmeta: &ConsensusMeta,
@@ -463,6 +520,7 @@ impl Store for SqliteStore {
 
         Ok(result)
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_authcerts(&mut self, certs: &[(AuthCertMeta, &str)]) -> Result<()> {
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(INSERT_AUTHCERT)?;
@@ -497,6 +555,7 @@ impl Store for SqliteStore {
 
         Ok(result)
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_microdescs(&mut self, digests: &[(&str, &MdDigest)], when: SystemTime) -> Result<()> {
         let when: OffsetDateTime = when.into();
 
@@ -511,6 +570,7 @@ impl Store for SqliteStore {
         tx.commit()?;
         Ok(())
     }
+    #[cfg(any(test, feature = "dirfetch"))]
     fn update_microdescs_listed(&mut self, digests: &[MdDigest], when: SystemTime) -> Result<()> {
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(UPDATE_MD_LISTED)?;
@@ -546,6 +606,7 @@ impl Store for SqliteStore {
         Ok(result)
     }
     #[cfg(feature = "routerdesc")]
+    #[cfg(any(test, feature = "dirfetch"))]
     fn store_routerdescs(&mut self, digests: &[(&str, SystemTime, &RdDigest)]) -> Result<()> {
         let tx = self.conn.transaction()?;
         let mut stmt = tx.prepare(INSERT_RD)?;
@@ -563,6 +624,7 @@ impl Store for SqliteStore {
 
 /// Handle to a blob that we have saved to disk but not yet committed to
 /// the database.
+#[cfg(any(test, feature = "dirfetch"))]
 struct SavedBlobHandle<'a> {
     /// Transaction we're using to add the blob to the ExtDocs table.
     tx: Transaction<'a>,
@@ -580,11 +642,13 @@ struct SavedBlobHandle<'a> {
 ///
 /// When this handle is dropped, the file gets deleted, unless you have
 /// first called [`Unlinker::forget`].
+#[cfg(any(test, feature = "dirfetch"))]
 struct Unlinker {
     /// The location of the file to remove, or None if we shouldn't
     /// remove it.
     p: Option<PathBuf>,
 }
+#[cfg(any(test, feature = "dirfetch"))]
 impl Unlinker {
     /// Make a new Unlinker for a given filename.
     fn new<P: AsRef<Path>>(p: P) -> Self {
@@ -598,6 +662,7 @@ impl Unlinker {
         self.p = None;
     }
 }
+#[cfg(any(test, feature = "dirfetch"))]
 impl Drop for Unlinker {
     fn drop(&mut self) {
         if let Some(p) = self.p.take() {
@@ -750,6 +815,7 @@ const FIND_LATEST_CONSENSUS_META: &str = "
 ";
 
 /// Look up a consensus by its digest-of-signed-part string.
+#[cfg(any(test, feature = "dirfetch"))]
 const FIND_CONSENSUS_AND_META_BY_DIGEST_OF_SIGNED: &str = "
   SELECT valid_after, fresh_until, valid_until, sha3_of_signed_part, Consensuses.digest, filename
   FROM Consensuses
@@ -792,18 +858,47 @@ const FIND_RD: &str = "
   WHERE sha1_digest = ?
 ";
 
-/// Query: find every ExtDocs member that has expired.
+/// Query: find the filenames of all the blobs that we know about.
+const FIND_ALL_EXTDOC_FILENAMES: &str = "
+  SELECT filename FROM ExtDocs;
+";
+
+/// Query: rebuild the database file, to reclaim the space that expired
+/// documents used.
+const VACUUM: &str = "VACUUM;";
+
+/// Query: find every ExtDocs member that has expired, other than the latest
+/// usable consensus of each flavor.
 const FIND_EXPIRED_EXTDOCS: &str = "
-  SELECT filename FROM Extdocs where expires < datetime('now');
+  SELECT filename FROM Extdocs where expires < datetime('now')
+  AND digest NOT IN (
+    SELECT digest FROM Consensuses AS c
+    WHERE pending = 0 AND valid_until = (
+      SELECT max(valid_until) FROM Consensuses AS c2
+      WHERE c2.pending = 0 AND c2.flavor = c.flavor));
+";
+
+/// Query: find the valid-after time of the oldest among the latest usable
+/// consensuses of each flavor, or NULL if there are none.
+///
+/// Documents listed by those consensuses may have been last listed (or
+/// expired) as early as this time, but we still need them.
+const FIND_LATEST_USABLE_VALID_AFTER: &str = "
+  SELECT min(valid_after) FROM Consensuses AS c
+  WHERE pending = 0 AND valid_until = (
+    SELECT max(valid_until) FROM Consensuses AS c2
+    WHERE c2.pending = 0 AND c2.flavor = c.flavor);
 ";
 
 /// Query: Add a new entry to ExtDocs.
+#[cfg(any(test, feature = "dirfetch"))]
 const INSERT_EXTDOC: &str = "
   INSERT OR REPLACE INTO ExtDocs ( digest, created, expires, type, filename )
   VALUES ( ?, datetime('now'), ?, ?, ? );
 ";
 
 /// Query: Add a new consensus.
+#[cfg(any(test, feature = "dirfetch"))]
 const INSERT_CONSENSUS: &str = "
   INSERT OR REPLACE INTO Consensuses
     ( valid_after, fresh_until, valid_until, flavor, pending, sha3_of_signed_part, digest )
@@ -811,6 +906,7 @@ const INSERT_CONSENSUS: &str = "
 ";
 
 /// Query: Add a new AuthCert
+#[cfg(any(test, feature = "dirfetch"))]
 const INSERT_AUTHCERT: &str = "
   INSERT OR REPLACE INTO Authcerts
     ( id_digest, sk_digest, published, expires, contents)
@@ -818,6 +914,7 @@ const INSERT_AUTHCERT: &str = "
 ";
 
 /// Query: Add a new microdescriptor
+#[cfg(any(test, feature = "dirfetch"))]
 const INSERT_MD: &str = "
   INSERT OR REPLACE INTO Microdescs ( sha256_digest, last_listed, contents )
   VALUES ( ?, ?, ? );
@@ -826,22 +923,39 @@ const INSERT_MD: &str = "
 /// Query: Add a new router descriptor
 #[allow(unused)]
 #[cfg(feature = "routerdesc")]
+#[cfg(any(test, feature = "dirfetch"))]
 const INSERT_RD: &str = "
   INSERT OR REPLACE INTO RouterDescs ( sha1_digest, published, contents )
   VALUES ( ?, ?, ? );
 ";
 
 /// Query: Change the time when a given microdescriptor was last listed.
+#[cfg(any(test, feature = "dirfetch"))]
 const UPDATE_MD_LISTED: &str = "
   UPDATE Microdescs
   SET last_listed = max(last_listed, ?)
   WHERE sha256_digest = ?;
 ";
 
-/// Query: Discard every expired extdoc.
+/// Query: Discard every expired extdoc, other than the latest usable
+/// consensus of each flavor.
 ///
 /// External documents aren't exposed through [`Store`].
-const DROP_OLD_EXTDOCS: &str = "DELETE FROM ExtDocs WHERE expires < datetime('now');";
+const DROP_OLD_EXTDOCS: &str = "
+  DELETE FROM ExtDocs WHERE expires < datetime('now')
+  AND digest NOT IN (
+    SELECT digest FROM Consensuses AS c
+    WHERE pending = 0 AND valid_until = (
+      SELECT max(valid_until) FROM Consensuses AS c2
+      WHERE c2.pending = 0 AND c2.flavor = c.flavor));
+";
+
+/// Query: Discard every consensus whose text we no longer have.
+///
+/// (We don't turn on sqlite's foreign key support, so deleting an ExtDocs
+/// entry doesn't cascade.)
+const DROP_CONSENSUSES_WITHOUT_EXTDOCS: &str =
+    "DELETE FROM Consensuses WHERE digest NOT IN (SELECT digest FROM ExtDocs);";
 
 /// Query: Discard every router descriptor that hasn't been listed for 3
 /// months.
@@ -853,8 +967,15 @@ const DROP_OLD_MICRODESCS: &str = "DELETE FROM Microdescs WHERE last_listed < ?;
 /// Query: Discard every expired authority certificate.
 const DROP_OLD_AUTHCERTS: &str = "DELETE FROM Authcerts WHERE expires < ?;";
 /// Query: Discard every consensus that's been expired for at least
-/// two days.
-const DROP_OLD_CONSENSUSES: &str = "DELETE FROM Consensuses WHERE valid_until < ?;";
+/// two days, other than the latest usable consensus of each flavor.
+const DROP_OLD_CONSENSUSES: &str = "
+  DELETE FROM Consensuses WHERE valid_until < ?
+  AND digest NOT IN (
+    SELECT digest FROM Consensuses AS c
+    WHERE pending = 0 AND valid_until = (
+      SELECT max(valid_until) FROM Consensuses AS c2
+      WHERE c2.pending = 0 AND c2.flavor = c.flavor));
+";
 
 #[cfg(test)]
 mod test {
@@ -1107,6 +1228,106 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn expire_keeps_latest_usable() -> Result<()> {
+        use tor_netdoc::doc::netstatus;
+
+        let (_tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let cmeta_at = |valid_after: OffsetDateTime, d: u8| {
+            ConsensusMeta::new(
+                netstatus::Lifetime::new(
+                    valid_after.into(),
+                    (valid_after + 1.hours()).into(),
+                    (valid_after + 3.hours()).into(),
+                )
+                .unwrap(),
+                [d; 32],
+                [d; 32],
+            )
+        };
+
+        // Two consensuses, both long expired.  The newer one is still the
+        // latest one we have.
+        let older = cmeta_at(now - 200.days(), 0x11);
+        let newer = cmeta_at(now - 100.days(), 0x22);
+        store.store_consensus(&older, ConsensusFlavor::Microdesc, false, "Older consensus")?;
+        store.store_consensus(&newer, ConsensusFlavor::Microdesc, false, "Newer consensus")?;
+
+        let md = [7_u8; 32];
+        store.store_microdescs(&[("Fake micro", &md)], (now - 99.days()).into())?;
+        let keyids = AuthCertKeyIds {
+            id_fingerprint: [3; 20].into(),
+            sk_fingerprint: [4; 20].into(),
+        };
+        let cert_meta =
+            AuthCertMeta::new(keyids, (now - 300.days()).into(), (now - 99.days()).into());
+        store.store_authcerts(&[(cert_meta, "Old cert")])?;
+
+        // Expiring keeps the latest consensus, and what it needs.
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert!(store.consensus_by_meta(&older).is_err());
+        assert_eq!(
+            store.consensus_by_meta(&newer)?.as_str()?,
+            "Newer consensus"
+        );
+        assert_eq!(store.microdescs(&[md])?.len(), 1);
+        assert_eq!(store.authcerts(&[keyids])?.len(), 1);
+
+        // Once there's a newer usable consensus, the old one and its
+        // documents can go.
+        let current = cmeta_at(now, 0x33);
+        store.store_consensus(
+            &current,
+            ConsensusFlavor::Microdesc,
+            false,
+            "Current consensus",
+        )?;
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert!(store.consensus_by_meta(&newer).is_err());
+        assert_eq!(
+            store.consensus_by_meta(&current)?.as_str()?,
+            "Current consensus"
+        );
+        assert!(store.microdescs(&[md])?.is_empty());
+        assert!(store.authcerts(&[keyids])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn expire_removes_unreferenced_blobs() -> Result<()> {
+        use tor_netdoc::doc::netstatus;
+
+        let (tmp_dir, mut store) = new_empty()?;
+        let now = OffsetDateTime::now_utc();
+        let cmeta = ConsensusMeta::new(
+            netstatus::Lifetime::new(
+                now.into(),
+                (now + 1.hours()).into(),
+                (now + 2.hours()).into(),
+            )
+            .unwrap(),
+            [0xAB; 32],
+            [0xBC; 32],
+        );
+        store.store_consensus(&cmeta, ConsensusFlavor::Microdesc, false, "A consensus")?;
+
+        // A consensus blob that the database has forgotten about goes away;
+        // everything else stays.
+        let orphan = tmp_dir.path().join("con:microdesc_sha3-256-0123");
+        std::fs::write(&orphan, "Forgotten consensus")?;
+        let unrelated = tmp_dir.path().join("notes.txt");
+        std::fs::write(&unrelated, "Not a blob")?;
+        store.expire_all(&EXPIRATION_DEFAULTS)?;
+        assert!(!orphan.exists());
+        assert!(unrelated.exists());
+        assert!(tmp_dir.path().join("db.sql").exists());
+        assert_eq!(store.consensus_by_meta(&cmeta)?.as_str()?, "A consensus");
+
+        Ok(())
+    }
+
     #[test]
     fn microdescs() -> Result<()> {
         let (_tmp_dir, mut store) = new_empty()?;
@@ -145,6 +145,15 @@ pub enum ErrorKind {
     #[display(fmt = "network directory is expired.")]
     DirectoryExpired,
 
+    /// We were told not to fetch a network directory, but there is no usable
+    /// one in our cache.
+    ///
+    /// This can only happen when the client has been configured to run
+    /// without ever downloading directory information.  Some other Tor client
+    /// needs to fill the cache before this one can be used.
+    #[display(fmt = "no usable network directory in cache")]
+    NoCachedDirectory,
+
     /// IO error accessing local persistent state
     ///
     /// For example, the disk might be full, or there may be a permissions problem.
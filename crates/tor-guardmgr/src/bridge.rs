@@ -0,0 +1,313 @@
+//! Configuration and parsing for bridge relays.
+//!
+//! A bridge is a relay that isn't (necessarily) listed in the public
+//! directory, which a client uses as the first hop of every circuit in
+//! place of its ordinary guards.  Users configure bridges with "bridge
+//! lines", in the same format that Tor uses:
+//!
+//! ```text
+//! Bridge [transport] IP:port [fingerprint] [key=value ...]
+//! ```
+//!
+//! (The leading `Bridge` keyword is optional.)
+//!
+//! A bridge line doesn't tell us the bridge's Ed25519 identity or its
+//! onion keys: we have to learn those from the bridge's own descriptor,
+//! which we fetch from the bridge itself.  Until we have it, all we can
+//! do with a bridge is make a one-hop circuit to it with `CREATE_FAST`.
+//!
+//! # Limitations
+//!
+//! We don't remember which bridge we used, or the descriptors we fetched,
+//! across restarts.  Bridges that name a pluggable transport are reached
+//! through the channel manager's transports, which must include one for
+//! that transport.
+
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tor_linkspec::OwnedChanTarget;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+
+/// A single configured bridge, as parsed from a bridge line.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BridgeConfig {
+    /// The pluggable transport to use when connecting to this bridge, if any.
+    transport: Option<String>,
+    /// The address at which to connect to this bridge.
+    addr: SocketAddr,
+    /// The RSA identity that this bridge must have, if we know it.
+    rsa_id: Option<RsaIdentity>,
+    /// Parameters to pass to the pluggable transport, in order.
+    params: Vec<(String, String)>,
+}
+
+/// An error that occurred while parsing a bridge line.
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BridgeParseError {
+    /// The bridge line had no address in it.
+    #[error("Bridge line was empty")]
+    Empty,
+    /// We couldn't parse the address of the bridge.
+    #[error("Invalid address {0:?} in bridge line")]
+    BadAddress(String),
+    /// We couldn't parse the name of the pluggable transport.
+    #[error("Invalid transport name {0:?} in bridge line")]
+    BadTransport(String),
+    /// We couldn't parse an entry after the address as a fingerprint or
+    /// a transport parameter.
+    #[error("Invalid fingerprint or parameter {0:?} in bridge line")]
+    BadParameter(String),
+    /// The bridge line had transport parameters, but no transport.
+    #[error("Bridge line has parameters, but no pluggable transport")]
+    ParametersWithoutTransport,
+}
+
+impl BridgeConfig {
+    /// Return the name of the pluggable transport for this bridge, if any.
+    pub fn transport(&self) -> Option<&str> {
+        self.transport.as_deref()
+    }
+
+    /// Return the address at which we connect to this bridge.
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Return the RSA identity that this bridge must have, if one was given.
+    pub fn rsa_identity(&self) -> Option<&RsaIdentity> {
+        self.rsa_id.as_ref()
+    }
+
+    /// Return an iterator over the pluggable transport parameters for this
+    /// bridge.
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Return true if `rsa_id` could be this bridge's RSA identity.
+    ///
+    /// If the bridge line has no fingerprint, any identity will do.
+    pub fn allows_rsa_identity(&self, rsa_id: &RsaIdentity) -> bool {
+        match &self.rsa_id {
+            Some(id) => id == rsa_id,
+            None => true,
+        }
+    }
+
+    /// Return a channel target for connecting to this bridge, once we
+    /// have learned its identities (usually from its descriptor).
+    ///
+    /// The target uses the bridge's configured address, whatever addresses
+    /// the bridge claims for itself.  The caller should make sure that the
+    /// bridge [allows](BridgeConfig::allows_rsa_identity) `rsa_id`.
+    pub fn chan_target(&self, ed_id: Ed25519Identity, rsa_id: RsaIdentity) -> OwnedChanTarget {
+        OwnedChanTarget::new(vec![self.addr], ed_id, rsa_id)
+    }
+}
+
+/// Return true if `s` is a well-formed pluggable transport name.
+fn is_transport_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Try to parse `s` as a bridge fingerprint: forty hex digits, optionally
+/// preceded by a dollar sign.
+///
+/// (Since `s` is a single word of the bridge line, it can't be one of the
+/// fingerprints with spaces that [`RsaIdentity`] also accepts.)
+fn parse_fingerprint(s: &str) -> Option<RsaIdentity> {
+    s.parse().ok()
+}
+
+impl FromStr for BridgeConfig {
+    type Err = BridgeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().peekable();
+        if matches!(words.peek(), Some(w) if w.eq_ignore_ascii_case("bridge")) {
+            words.next();
+        }
+
+        let first = words.next().ok_or(BridgeParseError::Empty)?;
+        let (transport, addr) = match first.parse::<SocketAddr>() {
+            Ok(addr) => (None, addr),
+            Err(_) if is_transport_name(first) => {
+                let addr = words.next().ok_or(BridgeParseError::Empty)?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| BridgeParseError::BadAddress(addr.to_string()))?;
+                (Some(first.to_string()), addr)
+            }
+            Err(_) if first.contains(':') => {
+                return Err(BridgeParseError::BadAddress(first.to_string()))
+            }
+            Err(_) => return Err(BridgeParseError::BadTransport(first.to_string())),
+        };
+
+        let rsa_id = match words.peek().and_then(|w| parse_fingerprint(w)) {
+            Some(id) => {
+                words.next();
+                Some(id)
+            }
+            None => None,
+        };
+
+        let params = words
+            .map(|w| match w.split_once('=') {
+                Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+                _ => Err(BridgeParseError::BadParameter(w.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if transport.is_none() && !params.is_empty() {
+            return Err(BridgeParseError::ParametersWithoutTransport);
+        }
+
+        Ok(BridgeConfig {
+            transport,
+            addr,
+            rsa_id,
+            params,
+        })
+    }
+}
+
+impl TryFrom<String> for BridgeConfig {
+    type Error = BridgeParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A BridgeConfig is displayed as a bridge line, without the leading
+/// `Bridge` keyword.
+impl Display for BridgeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(transport) = &self.transport {
+            write!(f, "{} ", transport)?;
+        }
+        write!(f, "{}", self.addr)?;
+        if let Some(id) = &self.rsa_id {
+            write!(f, " {}", hex::encode_upper(id.as_bytes()))?;
+        }
+        for (k, v) in &self.params {
+            write!(f, " {}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use tor_linkspec::ChanTarget;
+
+    #[test]
+    fn parse_bridge_lines() {
+        let b: BridgeConfig = "Bridge 192.0.2.1:443 4352e58420e68f5e40bf7c74faddccd9d1349413"
+            .parse()
+            .unwrap();
+        assert_eq!(b.transport(), None);
+        assert_eq!(b.addr(), &"192.0.2.1:443".parse().unwrap());
+        let id: RsaIdentity = [
+            0x43, 0x52, 0xe5, 0x84, 0x20, 0xe6, 0x8f, 0x5e, 0x40, 0xbf, 0x7c, 0x74, 0xfa, 0xdd,
+            0xcc, 0xd9, 0xd1, 0x34, 0x94, 0x13,
+        ]
+        .into();
+        assert_eq!(b.rsa_identity(), Some(&id));
+        assert_eq!(b.params().count(), 0);
+        assert_eq!(
+            b.to_string(),
+            "192.0.2.1:443 4352E58420E68F5E40BF7C74FADDCCD9D1349413"
+        );
+
+        let b: BridgeConfig = "obfs4 [2001:db8::1]:9001 $4352E58420E68F5E40BF7C74FADDCCD9D1349413 cert=ABCD iat-mode=0"
+            .parse()
+            .unwrap();
+        assert_eq!(b.transport(), Some("obfs4"));
+        assert_eq!(b.addr(), &"[2001:db8::1]:9001".parse().unwrap());
+        assert_eq!(b.rsa_identity(), Some(&id));
+        let params: Vec<_> = b.params().collect();
+        assert_eq!(params, vec![("cert", "ABCD"), ("iat-mode", "0")]);
+        assert_eq!(
+            b.to_string(),
+            "obfs4 [2001:db8::1]:9001 4352E58420E68F5E40BF7C74FADDCCD9D1349413 cert=ABCD iat-mode=0"
+        );
+
+        // The fingerprint is optional, and so is the keyword.
+        let b: BridgeConfig = "snowflake 192.0.2.3:1 url=https://example.com/"
+            .parse()
+            .unwrap();
+        assert_eq!(b.rsa_identity(), None);
+        assert_eq!(b.params().count(), 1);
+        let b: BridgeConfig = "  192.0.2.4:80  ".parse().unwrap();
+        assert_eq!(b.to_string(), "192.0.2.4:80");
+
+        // Round trip.
+        let b2: BridgeConfig = b.to_string().parse().unwrap();
+        assert_eq!(b, b2);
+    }
+
+    #[test]
+    fn parse_bridge_failures() {
+        use BridgeParseError as E;
+        fn err(s: &str) -> BridgeParseError {
+            s.parse::<BridgeConfig>().unwrap_err()
+        }
+        assert!(matches!(err(""), E::Empty));
+        assert!(matches!(err("Bridge"), E::Empty));
+        assert!(matches!(err("obfs4"), E::Empty));
+        assert!(matches!(err("192.0.2.1"), E::BadTransport(_)));
+        assert!(matches!(err("192.0.2.1:99999"), E::BadAddress(_)));
+        assert!(matches!(err("obfs4 example.com:443"), E::BadAddress(_)));
+        assert!(matches!(err("9bad 192.0.2.1:80"), E::BadTransport(_)));
+        assert!(matches!(err("192.0.2.1:80 4352e584"), E::BadParameter(_)));
+        assert!(matches!(err("obfs4 192.0.2.1:80 =x"), E::BadParameter(_)));
+        assert!(matches!(
+            err("192.0.2.1:80 cert=ABCD"),
+            E::ParametersWithoutTransport
+        ));
+    }
+
+    #[test]
+    fn bridge_identities() {
+        let ed: Ed25519Identity = [0x21; 32].into();
+        let rsa: RsaIdentity = [0x21; 20].into();
+        let other_rsa: RsaIdentity = [0xfe; 20].into();
+
+        // A bridge with a fingerprint only allows that identity, and we
+        // connect to it at the configured address.
+        let line = format!("203.0.113.7:9999 {}", hex::encode([0x21; 20]));
+        let bridge: BridgeConfig = line.parse().unwrap();
+        assert!(bridge.allows_rsa_identity(&rsa));
+        assert!(!bridge.allows_rsa_identity(&other_rsa));
+        let target = bridge.chan_target(ed, rsa);
+        assert_eq!(target.addrs(), &["203.0.113.7:9999".parse().unwrap()]);
+        assert_eq!(target.ed_identity(), &ed);
+        assert_eq!(target.rsa_identity(), &rsa);
+
+        // A bridge without a fingerprint allows any identity.
+        let bridge: BridgeConfig = "203.0.113.7:9999".parse().unwrap();
+        assert!(bridge.allows_rsa_identity(&rsa));
+        assert!(bridge.allows_rsa_identity(&other_rsa));
+
+        // Transport bridges are handled like any other bridge.
+        let line = format!("obfs4 192.0.2.1:443 {} cert=x", hex::encode([0x21; 20]));
+        let bridge: BridgeConfig = line.parse().unwrap();
+        assert_eq!(bridge.transport(), Some("obfs4"));
+        assert!(bridge.allows_rsa_identity(&rsa));
+        assert!(!bridge.allows_rsa_identity(&other_rsa));
+    }
+}
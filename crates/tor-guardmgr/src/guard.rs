@@ -238,6 +238,16 @@ impl Guard {
         self.unlisted_since.is_none() && self.disabled.is_none()
     }
 
+    /// Return true if we have ever used this guard successfully.
+    pub(crate) fn confirmed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+
+    /// Return true if this guard has been permanently disabled.
+    pub(crate) fn disabled(&self) -> bool {
+        self.disabled.is_some()
+    }
+
     /// Copy all _non-persistent_ status from `other` to self.
     ///
     /// Requires that the two `Guard`s have the same ID.
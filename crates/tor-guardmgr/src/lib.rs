@@ -76,7 +76,9 @@
 //! # Limitations
 //!
 //! * Only one guard selection is currently supported: we don't allow a
-//!   "filtered" or a "bridges" selection.
+//!   "filtered" or a "bridges" selection.  (When bridges are configured,
+//!   the circuit manager uses them directly, without a `GuardMgr`: see
+//!   the [`bridge`] module.)
 //!
 //! * Our circuit blocking algorithm is simplified from the one that Tor uses.
 //!   See comments in `GuardSet::circ_usability_status` for more information.
@@ -143,9 +145,10 @@ use tracing::{debug, info, trace, warn};
 use tor_error::{ErrorKind, HasKind};
 use tor_llcrypto::pk;
 use tor_netdir::{params::NetParameters, NetDir, Relay};
-use tor_persist::{DynStorageHandle, StateMgr};
+use tor_persist::{DynJournalHandle, DynStorageHandle, StateMgr};
 use tor_rtcompat::Runtime;
 
+pub mod bridge;
 mod daemon;
 mod filter;
 mod guard;
@@ -229,6 +232,9 @@ struct GuardMgrInner {
 
     /// Location in which to store persistent state.
     storage: DynStorageHandle<GuardSets>,
+
+    /// Journal of changes to our persistent state since we last stored it.
+    journal: DynJournalHandle<GuardJournalRecord>,
 }
 
 /// Persistent state for a guard manager, as serialized to disk.
@@ -247,6 +253,21 @@ struct GuardSets {
     remaining: HashMap<String, tor_persist::JsonValue>,
 }
 
+/// A change to our persistent guard state, recorded in a journal so that it
+/// survives a crash before we next store our state.
+///
+/// Each record holds a copy of the guard as it was just after the change.
+/// We only journal changes that we'd regret losing: most failure
+/// information isn't persistent in the first place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GuardJournalRecord {
+    /// We used a guard successfully for the first time.
+    Confirmed(guard::Guard),
+    /// We permanently disabled a guard after too many of its circuits
+    /// failed.
+    Disabled(guard::Guard),
+}
+
 /// The key (filename) we use for storing our persistent guard state in the
 /// `StateMgr`.
 ///
@@ -264,12 +285,15 @@ impl<R: Runtime> GuardMgr<R> {
         S: StateMgr + Send + Sync + 'static,
     {
         let (ctrl, rcv) = mpsc::unbounded();
-        let storage: DynStorageHandle<GuardSets> = state_mgr.create_handle(STORAGE_KEY);
+        let storage: DynStorageHandle<GuardSets> = state_mgr.clone().create_handle(STORAGE_KEY);
+        let journal: DynJournalHandle<GuardJournalRecord> =
+            state_mgr.create_journal_handle(STORAGE_KEY);
         // TODO(nickm): We should do something about the old state in
         // `default_guards`.  Probably it would be best to delete it.  We could
         // try to migrate it instead, but that's beyond the stability guarantee
         // that we're getting at this stage of our (pre-0.1) development.
-        let state = storage.load()?.unwrap_or_default();
+        let mut state: GuardSets = storage.load()?.unwrap_or_default();
+        state.replay_journal(journal.load()?);
         let inner = Arc::new(Mutex::new(GuardMgrInner {
             guards: state,
             last_primary_retry_time: runtime.now(),
@@ -278,6 +302,7 @@ impl<R: Runtime> GuardMgr<R> {
             pending: HashMap::new(),
             waiting: Vec::new(),
             storage,
+            journal,
         }));
         {
             let weak_inner = Arc::downgrade(&inner);
@@ -311,7 +336,8 @@ impl<R: Runtime> GuardMgr<R> {
     /// files.  If we have the lock, we only want to save.
     pub fn reload_persistent_state(&self) -> Result<(), GuardMgrError> {
         let mut inner = self.inner.lock().expect("Poisoned lock");
-        if let Some(new_guards) = inner.storage.load()? {
+        if let Some(mut new_guards) = inner.storage.load()? {
+            new_guards.replay_journal(inner.journal.load()?);
             let now = self.runtime.wallclock();
             inner.replace_guards_with(new_guards, now);
         }
@@ -324,7 +350,8 @@ impl<R: Runtime> GuardMgr<R> {
     pub fn upgrade_to_owned_persistent_state(&self) -> Result<(), GuardMgrError> {
         let mut inner = self.inner.lock().expect("Poisoned lock");
         debug_assert!(inner.storage.can_store());
-        let new_guards = inner.storage.load()?.unwrap_or_default();
+        let mut new_guards: GuardSets = inner.storage.load()?.unwrap_or_default();
+        new_guards.replay_journal(inner.journal.load()?);
         let now = self.runtime.wallclock();
         inner.replace_guards_with(new_guards, now);
         Ok(())
@@ -529,6 +556,18 @@ impl GuardSets {
     fn copy_status_from(&mut self, other: &GuardSets) {
         self.default.copy_status_from(&other.default);
     }
+
+    /// Apply a list of journaled changes, in order, to this state.
+    fn replay_journal(&mut self, records: Vec<GuardJournalRecord>) {
+        for rec in records {
+            let guard = match rec {
+                GuardJournalRecord::Confirmed(g) | GuardJournalRecord::Disabled(g) => g,
+            };
+            // There's only one guard set right now, so that's where every
+            // journaled guard belongs.
+            self.default.replay_journaled_guard(guard);
+        }
+    }
 }
 
 impl GuardMgrInner {
@@ -590,6 +629,31 @@ impl GuardMgrInner {
         self.update(now, None);
     }
 
+    /// Return the value of `property` for the active guard with `guard_id`,
+    /// or None if there is no such guard.
+    fn guard_has(&self, guard_id: &GuardId, property: fn(&guard::Guard) -> bool) -> Option<bool> {
+        self.guards.active_guards().get(guard_id).map(property)
+    }
+
+    /// Record a change to the guard with `guard_id` in our journal, if we're
+    /// allowed to write our persistent state.
+    ///
+    /// `make_record` builds the record from a copy of the guard.
+    fn journal_guard(
+        &self,
+        guard_id: &GuardId,
+        make_record: fn(guard::Guard) -> GuardJournalRecord,
+    ) {
+        if !self.journal.can_store() {
+            return;
+        }
+        if let Some(guard) = self.guards.active_guards().get(guard_id) {
+            if let Err(e) = self.journal.append(&make_record(guard.clone())) {
+                warn!("Unable to record guard state change: {}", e);
+            }
+        }
+    }
+
     /// Mark all of our primary guards as retriable, if we haven't done
     /// so since long enough before `now`.
     ///
@@ -637,11 +701,15 @@ impl GuardMgrInner {
                     }
 
                     // The guard succeeded.  Tell the GuardSet.
+                    let was_confirmed = self.guard_has(guard_id, guard::Guard::confirmed);
                     self.guards.active_guards_mut().record_success(
                         guard_id,
                         &self.params,
                         runtime.wallclock(),
                     );
+                    if was_confirmed == Some(false) {
+                        self.journal_guard(guard_id, GuardJournalRecord::Confirmed);
+                    }
                     // Either tell the request whether the guard is
                     // usable, or schedule it as a "waiting" request.
                     if let Some(usable) = self.guard_usability_status(&pending, runtime.now()) {
@@ -668,9 +736,15 @@ impl GuardMgrInner {
                     pending.reply(false);
                 }
                 GuardStatus::Indeterminate => {
+                    let was_disabled = self.guard_has(guard_id, guard::Guard::disabled);
                     self.guards
                         .active_guards_mut()
                         .record_indeterminate_result(guard_id);
+                    if was_disabled == Some(false)
+                        && self.guard_has(guard_id, guard::Guard::disabled) == Some(true)
+                    {
+                        self.journal_guard(guard_id, GuardJournalRecord::Disabled);
+                    }
                     pending.reply(false);
                 }
             };
@@ -918,8 +992,13 @@ impl GuardId {
 
     /// Return the relay in `netdir` that corresponds to this ID, if there
     /// is one.
+    ///
+    /// (If this guard's identities now belong to different relays, then
+    /// there is no such relay.)
     pub fn get_relay<'a>(&self, netdir: &'a NetDir) -> Option<Relay<'a>> {
-        netdir.by_id_pair(&self.ed25519, &self.rsa)
+        netdir
+            .by_id_pair(&self.ed25519, &self.rsa)
+            .unwrap_or_default()
     }
 }
 
@@ -1131,6 +1210,38 @@ mod test {
         });
     }
 
+    #[test]
+    fn journal_replay() {
+        test_with_all_runtimes!(|rt| async move {
+            let (guardmgr, statemgr, netdir) = init(rt.clone());
+            let usage = GuardUsage::default();
+
+            guardmgr.update_network(&netdir);
+
+            let (guard, mon, usable) = guardmgr.select_guard(usage, Some(&netdir)).unwrap();
+            mon.succeeded();
+            assert!(usable.await.unwrap());
+            guardmgr.flush_msg_queue().await;
+            let journaled: Vec<GuardJournalRecord> = statemgr.journal_load(STORAGE_KEY).unwrap();
+            assert_eq!(journaled.len(), 1);
+
+            // "Crash" without storing our state, and reload it.
+            drop(guardmgr);
+            assert!(statemgr.load::<GuardSets>(STORAGE_KEY).unwrap().is_none());
+            let guardmgr2 = GuardMgr::new(rt.clone(), statemgr.clone()).unwrap();
+            {
+                let inner = guardmgr2.inner.lock().unwrap();
+                let g = inner.guards.active_guards().get(guard.id()).unwrap();
+                assert!(g.confirmed());
+            }
+
+            // Storing our state discards the journal.
+            guardmgr2.store_persistent_state().unwrap();
+            let journaled: Vec<GuardJournalRecord> = statemgr.journal_load(STORAGE_KEY).unwrap();
+            assert!(journaled.is_empty());
+        });
+    }
+
     #[test]
     fn simple_waiting() {
         // TODO(nickm): This test fails in rare cases; I suspect a
@@ -76,6 +76,16 @@ impl GuardUsable {
         let (snd, rcv) = oneshot::channel();
         (GuardUsable { u: Some(rcv) }, snd)
     }
+
+    /// Return true if this guard was selected as a primary guard, and is
+    /// therefore usable immediately.
+    ///
+    /// Callers that pick a guard under extra restrictions can use this to
+    /// tell whether they have ended up with a speculative guard whose
+    /// usability is still undecided.
+    pub fn is_primary(&self) -> bool {
+        self.u.is_none()
+    }
 }
 
 /// A message that we can get back from the circuit manager who asked
@@ -379,6 +379,32 @@ impl GuardSet {
         self.primary_guards_invalidated = true;
     }
 
+    /// Replace our persistent information about a guard with `guard`, which
+    /// we read from a journal of changes made after this `GuardSet` was last
+    /// saved.
+    ///
+    /// Adds the guard to the sample (and to the confirmed list, if it is
+    /// confirmed) if it isn't there already.
+    pub(crate) fn replay_journaled_guard(&mut self, mut guard: Guard) {
+        let id = guard.guard_id().clone();
+        let confirmed = guard.confirmed();
+        match self.guards.get_mut(&id) {
+            Some(old) => {
+                guard.copy_status_from(old);
+                *old = guard;
+            }
+            None => {
+                self.guards.insert(id.clone(), guard);
+                self.sample.push(id.clone());
+            }
+        }
+        if confirmed && !self.confirmed.contains(&id) {
+            self.confirmed.push(id);
+        }
+        self.primary_guards_invalidated = true;
+        self.assert_consistency();
+    }
+
     /// Return the number of our primary guards are missing their
     /// microdescriptors in `dir`.
     pub(crate) fn missing_primary_microdescriptors(&mut self, dir: &NetDir) -> usize {
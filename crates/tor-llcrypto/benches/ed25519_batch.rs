@@ -0,0 +1,62 @@
+//! Compare batched and one-at-a-time verification of ed25519 signatures.
+//!
+//! A fresh consensus makes us check on the order of a thousand ed25519
+//! signatures at once, so that's the size we measure here.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand_core::RngCore;
+use signature::Signer;
+use tor_llcrypto::pk::ed25519::{
+    validate_batch, validate_batch_or_find_invalid, Keypair, ValidatableEd25519Signature,
+};
+use tor_llcrypto::pk::ValidatableSignature;
+use tor_llcrypto::util::rand_compat::RngCompatExt;
+
+/// How many signatures to check in each iteration.
+const N_SIGS: usize = 1000;
+
+/// Return `n` valid signatures, each made by a different key on a different
+/// message.
+fn make_sigs(n: usize) -> Vec<ValidatableEd25519Signature> {
+    let mut rng = rand::thread_rng().rng_compat();
+    (0..n)
+        .map(|_| {
+            let kp = Keypair::generate(&mut rng);
+            let mut bytes = [0_u8; 128];
+            rng.fill_bytes(&mut bytes[..]);
+            let sig = kp.sign(&bytes[..]);
+            ValidatableEd25519Signature::new(kp.public, sig, &bytes[..])
+        })
+        .collect()
+}
+
+fn ed25519_batch(c: &mut Criterion) {
+    let sigs = make_sigs(N_SIGS);
+    let sigrefs: Vec<_> = sigs.iter().collect();
+
+    let mut group = c.benchmark_group("ed25519_1000_sigs");
+    group.bench_function("serial", |b| {
+        b.iter(|| assert!(sigrefs.iter().all(|s| s.is_valid())));
+    });
+    group.bench_function("batch", |b| {
+        b.iter(|| assert!(validate_batch(&sigrefs[..])));
+    });
+    // When one signature is bad, we pay for the failed batch and then for
+    // checking every signature on its own.
+    let kp = Keypair::generate(&mut rand::thread_rng().rng_compat());
+    let bad = ValidatableEd25519Signature::new(kp.public, kp.sign(b"Apples"), b"Oranges");
+    let mut badrefs = sigrefs.clone();
+    badrefs[N_SIGS / 2] = &bad;
+    group.bench_function("batch_one_invalid", |b| {
+        b.iter(|| {
+            assert_eq!(
+                validate_batch_or_find_invalid(&badrefs[..]),
+                Err(vec![N_SIGS / 2])
+            );
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ed25519_batch);
+criterion_main!(benches);
@@ -7,6 +7,31 @@ pub mod ed25519;
 pub mod keymanip;
 pub mod rsa;
 
+use thiserror::Error;
+
+/// An error that occurred while parsing a relay identity from a string.
+///
+/// Returned by the `FromStr` implementations for
+/// [`RsaIdentity`](rsa::RsaIdentity) and
+/// [`Ed25519Identity`](ed25519::Ed25519Identity).
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IdParseError {
+    /// The string had a character that isn't allowed in this kind of
+    /// identity, or a space in the wrong place.
+    #[error("invalid character in identity")]
+    BadCharacter,
+    /// The string had the wrong number of characters for this kind of
+    /// identity.
+    #[error("wrong length for identity: expected {expected} characters, found {found}")]
+    BadLength {
+        /// The number of characters we wanted.
+        expected: usize,
+        /// The number of characters we got.
+        found: usize,
+    },
+}
+
 /// Re-exporting Curve25519 implementations.
 ///
 /// *TODO*: Eventually we should probably recommend using is code via some
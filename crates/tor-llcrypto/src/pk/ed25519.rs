@@ -125,6 +125,45 @@ impl Display for Ed25519Identity {
     }
 }
 
+/// The number of characters in the unpadded base64 encoding of an
+/// [`Ed25519Identity`].
+const ED25519_ID_BASE64_LEN: usize = 43;
+
+impl std::str::FromStr for Ed25519Identity {
+    type Err = super::IdParseError;
+
+    /// Parse an Ed25519 identity from base64, with or without padding.
+    ///
+    /// ```
+    /// use tor_llcrypto::pk::ed25519::Ed25519Identity;
+    ///
+    /// let id: Ed25519Identity = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo".parse().unwrap();
+    /// assert_eq!(id.to_string(), "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo");
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use super::IdParseError as E;
+        let s = s.strip_suffix('=').unwrap_or(s);
+        if !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+        {
+            return Err(E::BadCharacter);
+        }
+        if s.len() != ED25519_ID_BASE64_LEN {
+            return Err(E::BadLength {
+                expected: ED25519_ID_BASE64_LEN,
+                found: s.len(),
+            });
+        }
+        let bytes =
+            base64::decode_config(s, base64::STANDARD_NO_PAD).map_err(|_| E::BadCharacter)?;
+        Ed25519Identity::from_bytes(&bytes).ok_or(E::BadLength {
+            expected: ED25519_ID_BASE64_LEN,
+            found: s.len(),
+        })
+    }
+}
+
 impl Debug for Ed25519Identity {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Ed25519Identity {{ {} }}", self)
@@ -161,10 +200,7 @@ impl<'de> serde::Deserialize<'de> for Ed25519Identity {
                 where
                     E: serde::de::Error,
                 {
-                    let bytes =
-                        base64::decode_config(s, base64::STANDARD_NO_PAD).map_err(E::custom)?;
-                    Ed25519Identity::from_bytes(&bytes)
-                        .ok_or_else(|| E::custom("wrong length for Ed25519 public key"))
+                    s.parse().map_err(E::custom)
                 }
             }
 
@@ -190,6 +226,40 @@ impl<'de> serde::Deserialize<'de> for Ed25519Identity {
     }
 }
 
+/// Return true if `pk` is encoded the only way that its point can be.
+///
+/// Some points have more than one 32-byte encoding, and
+/// [`PublicKey::from_bytes`] accepts all of them.  Where a key is used to
+/// identify something, we usually want to insist on the canonical one, so
+/// that there's only one way to write each identity.
+pub fn is_canonical(pk: &PublicKey) -> bool {
+    canonical_point(pk).is_some()
+}
+
+/// Return true if `pk` is canonically encoded (see [`is_canonical`]), and
+/// its point lies in the prime-order subgroup.
+///
+/// A key that fails this check has a small-order component.  Honestly
+/// generated keys never do, and such keys can let one signature verify
+/// under several different keys, so they shouldn't be used to bind
+/// identities together.
+pub fn is_strictly_valid(pk: &PublicKey) -> bool {
+    canonical_point(pk)
+        .map(|point| point.is_torsion_free() && !point.is_small_order())
+        .unwrap_or(false)
+}
+
+/// Helper: decompress the point that `pk` encodes, and return it if `pk` is
+/// the canonical encoding of that point.
+fn canonical_point(pk: &PublicKey) -> Option<curve25519_dalek::edwards::EdwardsPoint> {
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+
+    let bytes = pk.to_bytes();
+    CompressedEdwardsY(bytes)
+        .decompress()
+        .filter(|point| point.compress().to_bytes() == bytes)
+}
+
 /// An ed25519 signature, plus the document that it signs and its
 /// public key.
 pub struct ValidatableEd25519Signature {
@@ -269,3 +339,35 @@ pub fn validate_batch(sigs: &[&ValidatableEd25519Signature]) -> bool {
         ed25519_dalek::verify_batch(&ed_msgs[..], &ed_sigs[..], &ed_pks[..]).is_ok()
     }
 }
+
+/// Perform a batch verification operation on the provided signatures, and
+/// if it fails, find out which signatures were at fault.
+///
+/// Return `Ok(())` if every signature is valid.  Otherwise, return the
+/// indices in `sigs` of every signature that is invalid, in order.
+///
+/// When the batch is valid, this is as fast as [`validate_batch`].  When it
+/// isn't, we check every signature individually, which costs as much as not
+/// batching at all.  Because of the caveats on [`validate_batch`], it's
+/// possible (though it should never happen with honestly generated
+/// signatures) for the batch to fail while every signature passes on its
+/// own: in that case, we believe the individual checks and return `Ok(())`.
+pub fn validate_batch_or_find_invalid(
+    sigs: &[&ValidatableEd25519Signature],
+) -> Result<(), Vec<usize>> {
+    use crate::pk::ValidatableSignature;
+    if validate_batch(sigs) {
+        return Ok(());
+    }
+    let invalid: Vec<usize> = sigs
+        .iter()
+        .enumerate()
+        .filter(|(_, sig)| !sig.is_valid())
+        .map(|(idx, _)| idx)
+        .collect();
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
@@ -14,7 +14,11 @@
 //! Ed25519 key used as the .onion address.  This algorithm allows
 //! directories to validate the signatures on onion service
 //! descriptors, without knowing which services they represent.  We
-//! implement this blinding operation via [`blind_pubkey`].
+//! implement this blinding operation via [`blind_pubkey`], and derive
+//! the blinding parameter for a given time period with
+//! [`blinding_factor`].  The subcredential that clients and services
+//! derive from the two keys comes from [`hs_credential`] and
+//! [`hs_subcredential`].
 //!
 //! ## TODO
 //!
@@ -145,6 +149,13 @@ pub fn blind_pubkey(pk: &PublicKey, mut param: [u8; 32]) -> Result<PublicKey, Bl
     use curve25519_dalek::edwards::CompressedEdwardsY;
     use curve25519_dalek::scalar::Scalar;
 
+    // Keys that are derived from an onion address have to be encoded
+    // canonically, or else two different addresses could name the same
+    // service.
+    if !pk::ed25519::is_canonical(pk) {
+        return Err(BlindingError::BadPubkey);
+    }
+
     // Clamp the blinding parameter
     param[0] &= 248;
     param[31] &= 63;
@@ -164,6 +175,104 @@ pub fn blind_pubkey(pk: &PublicKey, mut param: [u8; 32]) -> Result<PublicKey, Bl
     Ok(PublicKey::from_bytes(&blinded_pubkey_point.0)?)
 }
 
+/// The prefix for the blinding parameter, from `rend-spec-v3.txt`
+/// section A.2.  (The trailing NUL is part of the string.)
+#[cfg(feature = "hsv3-client")]
+const BLIND_STRING: &[u8] = b"Derive temporary signing key\0";
+
+/// The string that `rend-spec-v3.txt` uses to represent the ed25519 base
+/// point `B` when computing a blinding parameter.
+#[cfg(feature = "hsv3-client")]
+const ED25519_BASEPOINT_STRING: &[u8] = b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)";
+
+/// The prefix for the nonce `N` that makes a blinding parameter specific
+/// to a time period.
+#[cfg(feature = "hsv3-client")]
+const KEYBLIND_NONCE_PREFIX: &[u8] = b"key-blind";
+
+/// Compute the blinding parameter `h` for the onion service identity key
+/// `pk`, during the time period numbered `period_num`, whose length is
+/// `period_length` minutes.
+///
+/// This is the `h` from `rend-spec-v3.txt` section A.2, without the
+/// optional secret `s`: pass it to [`blind_pubkey`] (which does the
+/// clamping) to get the blinded key for that period.
+///
+/// # Errors
+///
+/// Fails with [`BlindingError::BadPubkey`] if `pk` isn't a canonically
+/// encoded Ed25519 point.
+///
+/// # Availability
+///
+/// This function is only available when the `hsv3-client` feature is enabled.
+#[cfg(feature = "hsv3-client")]
+pub fn blinding_factor(
+    pk: &PublicKey,
+    period_num: u64,
+    period_length: u64,
+) -> Result<[u8; 32], BlindingError> {
+    use crate::d::Sha3_256;
+    use digest::Digest;
+
+    if !pk::ed25519::is_canonical(pk) {
+        return Err(BlindingError::BadPubkey);
+    }
+
+    let h = Sha3_256::new()
+        .chain_update(BLIND_STRING)
+        .chain_update(pk.as_bytes())
+        .chain_update(ED25519_BASEPOINT_STRING)
+        .chain_update(KEYBLIND_NONCE_PREFIX)
+        .chain_update(period_num.to_be_bytes())
+        .chain_update(period_length.to_be_bytes())
+        .finalize();
+    Ok(h.into())
+}
+
+/// Compute the onion service credential for the identity key `pk`.
+///
+/// This is `N_hs_cred` from `rend-spec-v3.txt` section 2.1.  It isn't
+/// secret from anybody who knows the onion address, but is never sent
+/// on the network.
+///
+/// # Availability
+///
+/// This function is only available when the `hsv3-client` feature is enabled.
+#[cfg(feature = "hsv3-client")]
+pub fn hs_credential(pk: &PublicKey) -> [u8; 32] {
+    use crate::d::Sha3_256;
+    use digest::Digest;
+
+    Sha3_256::new()
+        .chain_update(b"credential")
+        .chain_update(pk.as_bytes())
+        .finalize()
+        .into()
+}
+
+/// Compute the onion service subcredential for the credential `credential`
+/// (from [`hs_credential`]) and the blinded key `blinded_pk` for some time
+/// period.
+///
+/// This is `N_hs_subcred` from `rend-spec-v3.txt` section 2.1.
+///
+/// # Availability
+///
+/// This function is only available when the `hsv3-client` feature is enabled.
+#[cfg(feature = "hsv3-client")]
+pub fn hs_subcredential(credential: &[u8; 32], blinded_pk: &PublicKey) -> [u8; 32] {
+    use crate::d::Sha3_256;
+    use digest::Digest;
+
+    Sha3_256::new()
+        .chain_update(b"subcredential")
+        .chain_update(credential)
+        .chain_update(blinded_pk.as_bytes())
+        .finalize()
+        .into()
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::unwrap_used)]
@@ -252,4 +361,68 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    #[cfg(feature = "hsv3-client")]
+    fn blinding_for_period() {
+        use crate::d::Sha3_256;
+        use digest::Digest;
+        use hex_literal::hex;
+
+        // This test vector is from test_blinding_basics in little-t-tor's
+        // test_hs_common.c: time period 1234, with the default period
+        // length of 1440 minutes.
+        let pk = PublicKey::from_bytes(&hex!(
+            "833990B085C1A688C1D4C8B1F6B56AFAF5A2ECA674449E1D704F83765CCB7BC6"
+        ))
+        .unwrap();
+        let h = blinding_factor(&pk, 1234, 1440).unwrap();
+        let blinded_pk = blind_pubkey(&pk, h).unwrap();
+        assert_eq!(
+            blinded_pk.to_bytes(),
+            hex!("3A50BF210E8F9EE955AE0014F7A6917FB65EBF098A86305ABB508D1A7291B6D5")
+        );
+
+        // Different periods give different keys.
+        let h2 = blinding_factor(&pk, 1235, 1440).unwrap();
+        assert_ne!(h, h2);
+        assert_ne!(blind_pubkey(&pk, h2).unwrap(), blinded_pk);
+
+        // The subcredential is the hash chain from rend-spec-v3 section 2.1.
+        let cred = hs_credential(&pk);
+        let expected_cred: [u8; 32] = Sha3_256::new()
+            .chain_update(b"credential")
+            .chain_update(pk.to_bytes())
+            .finalize()
+            .into();
+        assert_eq!(cred, expected_cred);
+        let subcred = hs_subcredential(&cred, &blinded_pk);
+        let expected_subcred: [u8; 32] = Sha3_256::new()
+            .chain_update(b"subcredential")
+            .chain_update(expected_cred)
+            .chain_update(blinded_pk.to_bytes())
+            .finalize()
+            .into();
+        assert_eq!(subcred, expected_subcred);
+    }
+
+    #[test]
+    #[cfg(feature = "hsv3-client")]
+    fn blinding_rejects_noncanonical() {
+        use hex_literal::hex;
+
+        // The identity point, encoded canonically (y = 1) and with the
+        // x sign bit set, which isn't canonical since x = 0.
+        let canonical = hex!("0100000000000000000000000000000000000000000000000000000000000000");
+        let noncanonical = hex!("0100000000000000000000000000000000000000000000000000000000000080");
+        let pk = PublicKey::from_bytes(&canonical).unwrap();
+        assert!(crate::pk::ed25519::is_canonical(&pk));
+        let pk = PublicKey::from_bytes(&noncanonical).unwrap();
+        assert!(!crate::pk::ed25519::is_canonical(&pk));
+        assert_eq!(
+            blinding_factor(&pk, 1234, 1440),
+            Err(BlindingError::BadPubkey)
+        );
+        assert_eq!(blind_pubkey(&pk, [0x11; 32]), Err(BlindingError::BadPubkey));
+    }
 }
@@ -5,13 +5,14 @@
 //! similar places.
 //!
 //! Currently, that means validating PKCSv1 signatures, and encoding
-//! and decoding RSA public keys from DER.
+//! and decoding RSA public keys from DER.  We can also make PKCSv1
+//! signatures, though in Tor that's only needed for relays and
+//! authorities (and for tests).
 //!
 //! # Limitations:
 //!
-//! Currently missing are support for signing and RSA-OEAP.  In Tor,
-//! RSA signing is only needed for relays and authorities, and
-//! RSA-OAEP padding is only needed for the (obsolete) TAP protocol.
+//! Currently missing is support for RSA-OEAP.  In Tor, RSA-OAEP padding
+//! is only needed for the (obsolete) TAP protocol.
 //!
 //! This module should expose RustCrypto trait-based wrappers,
 //! but the [`rsa`] crate didn't support them as of initial writing.
@@ -58,6 +59,16 @@ impl fmt::Display for RsaIdentity {
         write!(f, "${}", hex::encode(&self.id[..]))
     }
 }
+impl fmt::LowerHex for RsaIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.id[..]))
+    }
+}
+impl fmt::UpperHex for RsaIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode_upper(&self.id[..]))
+    }
+}
 impl fmt::Debug for RsaIdentity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "RsaIdentity {{ ${} }}", hex::encode(&self.id[..]))
@@ -94,9 +105,7 @@ impl<'de> serde::Deserialize<'de> for RsaIdentity {
                 where
                     E: serde::de::Error,
                 {
-                    let bytes = hex::decode(s).map_err(E::custom)?;
-                    RsaIdentity::from_bytes(&bytes)
-                        .ok_or_else(|| E::custom("wrong length for RSA identity"))
+                    s.parse().map_err(E::custom)
                 }
             }
 
@@ -153,6 +162,48 @@ impl RsaIdentity {
     }
 }
 
+impl std::str::FromStr for RsaIdentity {
+    type Err = super::IdParseError;
+
+    /// Parse an RSA identity from its hexadecimal fingerprint.
+    ///
+    /// The fingerprint may start with a `$`, and may be split into groups
+    /// of four digits by single spaces, as Tor Metrics shows it.
+    ///
+    /// ```
+    /// use tor_llcrypto::pk::rsa::RsaIdentity;
+    ///
+    /// let a: RsaIdentity = "$9695DFC35FFEB861329B9F1AB04C46397020CE31".parse().unwrap();
+    /// let b: RsaIdentity = "9695 DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31"
+    ///     .parse()
+    ///     .unwrap();
+    /// assert_eq!(a, b);
+    /// assert_eq!(a.to_string(), "$9695dfc35ffeb861329b9f1ab04c46397020ce31");
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use super::IdParseError as E;
+        let s = s.strip_prefix('$').unwrap_or(s);
+        let digits: String = s.split(' ').collect();
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(E::BadCharacter);
+        }
+        if digits.len() != RSA_ID_LEN * 2 {
+            return Err(E::BadLength {
+                expected: RSA_ID_LEN * 2,
+                found: digits.len(),
+            });
+        }
+        if s.contains(' ') && !s.split(' ').all(|group| group.len() == 4) {
+            return Err(E::BadCharacter);
+        }
+        let bytes = hex::decode(&digits).map_err(|_| E::BadCharacter)?;
+        RsaIdentity::from_bytes(&bytes).ok_or(E::BadLength {
+            expected: RSA_ID_LEN * 2,
+            found: digits.len(),
+        })
+    }
+}
+
 impl From<[u8; 20]> for RsaIdentity {
     fn from(id: [u8; 20]) -> RsaIdentity {
         RsaIdentity { id }
@@ -182,7 +233,20 @@ impl PrivateKey {
     pub fn from_der(der: &[u8]) -> Option<Self> {
         Some(PrivateKey(rsa::RsaPrivateKey::from_pkcs1_der(der).ok()?))
     }
-    // ....
+    /// Sign a hash (as used in Tor), using `rng` for blinding.  The hash
+    /// to sign should be in 'hashed'.
+    ///
+    /// This is the counterpart of [`PublicKey::verify`]: Tor uses
+    /// RSA-PKCSv1 signatures, with hash algorithm OIDs omitted.
+    pub fn sign<R>(&self, rng: &mut R, hashed: &[u8]) -> Result<Vec<u8>, signature::Error>
+    where
+        R: rand_core::RngCore + rand_core::CryptoRng,
+    {
+        let padding = rsa::PaddingScheme::new_pkcs1v15_sign(None);
+        self.0
+            .sign_blinded(rng, padding, hashed)
+            .map_err(|_| signature::Error::new())
+    }
 }
 impl PublicKey {
     /// Return true iff the exponent for this key is the same
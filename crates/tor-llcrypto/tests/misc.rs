@@ -37,6 +37,38 @@ fn test_ed25519_identity() {
     assert_eq!(ex3.as_bytes(), &example_key[..]);
 }
 
+#[test]
+fn strict_ed25519_keys() {
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+    use ll::pk::ed25519::{is_canonical, is_strictly_valid, PublicKey};
+
+    let example_key = hex!("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+    let pk = PublicKey::from_bytes(&example_key).unwrap();
+    assert!(is_canonical(&pk));
+    assert!(is_strictly_valid(&pk));
+
+    // The identity point is canonical, but has small order.
+    let identity = hex!("0100000000000000000000000000000000000000000000000000000000000000");
+    let pk = PublicKey::from_bytes(&identity).unwrap();
+    assert!(is_canonical(&pk));
+    assert!(!is_strictly_valid(&pk));
+
+    // So is it with the sign bit set, but that isn't canonical.
+    let neg_identity = hex!("0100000000000000000000000000000000000000000000000000000000000080");
+    let pk = PublicKey::from_bytes(&neg_identity).unwrap();
+    assert!(!is_canonical(&pk));
+    assert!(!is_strictly_valid(&pk));
+
+    // A good key plus a point of order 2 is canonical, but not in the
+    // prime-order subgroup.
+    let order_2 = hex!("ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f");
+    let p = CompressedEdwardsY(example_key).decompress().unwrap();
+    let t = CompressedEdwardsY(order_2).decompress().unwrap();
+    let pk = PublicKey::from_bytes((p + t).compress().as_bytes()).unwrap();
+    assert!(is_canonical(&pk));
+    assert!(!is_strictly_valid(&pk));
+}
+
 #[test]
 fn batch_verify() {
     use ll::pk::ed25519::*;
@@ -75,6 +107,23 @@ fn batch_verify() {
     ));
     let sigrefs: Vec<_> = sigs.iter().collect();
     assert!(!validate_batch(&sigrefs[..]));
+    assert_eq!(validate_batch_or_find_invalid(&sigrefs[..]), Err(vec![3]));
+    assert_eq!(validate_batch_or_find_invalid(&sigrefs[1..2]), Ok(()));
+    assert_eq!(validate_batch_or_find_invalid(&[]), Ok(()));
+
+    // Put the junk one in the middle, and add another one.
+    sigs.swap(1, 3);
+    sigs.push(ValidatableEd25519Signature::new(
+        kp.public,
+        sig,
+        &b"Pears"[..],
+    ));
+    let sigrefs: Vec<_> = sigs.iter().collect();
+    assert_eq!(
+        validate_batch_or_find_invalid(&sigrefs[..]),
+        Err(vec![1, 4])
+    );
+    assert_eq!(validate_batch_or_find_invalid(&sigrefs[1..2]), Err(vec![0]));
 }
 
 #[test]
@@ -90,6 +139,86 @@ fn serde_rsaid() {
     assert_tokens(&rsa_id.compact(), &[Token::Bytes(b"example key id here!")]);
 }
 
+#[test]
+fn parse_rsaid() {
+    use ll::pk::rsa::RsaIdentity;
+    use ll::pk::IdParseError;
+
+    let id = RsaIdentity::from(hex!("9695DFC35FFEB861329B9F1AB04C46397020CE31"));
+    for s in &[
+        "9695DFC35FFEB861329B9F1AB04C46397020CE31",
+        "$9695dfc35ffeb861329b9f1ab04c46397020ce31",
+        "9695 DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31",
+        "$9695 DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31",
+    ] {
+        assert_eq!(s.parse::<RsaIdentity>(), Ok(id));
+    }
+    assert_eq!(id.to_string(), "$9695dfc35ffeb861329b9f1ab04c46397020ce31");
+    assert_eq!(
+        format!("{:x}", id),
+        "9695dfc35ffeb861329b9f1ab04c46397020ce31"
+    );
+    assert_eq!(
+        format!("{:X}", id),
+        "9695DFC35FFEB861329B9F1AB04C46397020CE31"
+    );
+    assert_eq!(id.to_string().parse::<RsaIdentity>(), Ok(id));
+
+    assert_eq!(
+        "9695DFC35FFEB861329B9F1AB04C46397020CE3".parse::<RsaIdentity>(),
+        Err(IdParseError::BadLength {
+            expected: 40,
+            found: 39
+        })
+    );
+    assert_eq!(
+        "9695DFC35FFEB861329B9F1AB04C46397020CE3X".parse::<RsaIdentity>(),
+        Err(IdParseError::BadCharacter)
+    );
+    assert_eq!(
+        "$$9695DFC35FFEB861329B9F1AB04C46397020CE31".parse::<RsaIdentity>(),
+        Err(IdParseError::BadCharacter)
+    );
+    assert_eq!(
+        "9695D FC35FFEB861329B9F1AB04C46397020CE31".parse::<RsaIdentity>(),
+        Err(IdParseError::BadCharacter)
+    );
+    assert_eq!(
+        "9695  DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31".parse::<RsaIdentity>(),
+        Err(IdParseError::BadCharacter)
+    );
+}
+
+#[test]
+fn parse_edid() {
+    use ll::pk::ed25519::Ed25519Identity;
+    use ll::pk::IdParseError;
+
+    let id = Ed25519Identity::new(hex!(
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+    ));
+    let s = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo";
+    assert_eq!(s.parse::<Ed25519Identity>(), Ok(id));
+    assert_eq!(format!("{}=", s).parse::<Ed25519Identity>(), Ok(id));
+    assert_eq!(id.to_string().parse::<Ed25519Identity>(), Ok(id));
+
+    assert_eq!(
+        "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHUR".parse::<Ed25519Identity>(),
+        Err(IdParseError::BadLength {
+            expected: 43,
+            found: 42
+        })
+    );
+    assert_eq!(
+        "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHUR!".parse::<Ed25519Identity>(),
+        Err(IdParseError::BadCharacter)
+    );
+    assert_eq!(
+        "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo".parse::<Ed25519Identity>(),
+        Err(IdParseError::BadCharacter)
+    );
+}
+
 #[test]
 fn serde_edid() {
     use serde_test::{assert_tokens, Configure, Token};
@@ -569,6 +569,10 @@ rI2rzhqqBIhzFFaYuxyRAhkSBxCKTdl6X0k74ahT3MM=
     assert!(public.verify(&digest, &to_der(sig)).is_ok());
     assert!(public.verify(&wrong_digest, &to_der(sig)).is_err());
 
+    // PKCSv1 signatures are deterministic, so we get the same one back.
+    let mut rng = rand::thread_rng();
+    assert_eq!(secret.sign(&mut rng, &digest).unwrap(), to_der(sig));
+
     let val = rsa::ValidatableRsaSignature::new(&public, &to_der(sig), &digest);
 
     assert!(val.is_valid());
@@ -9,4 +9,8 @@ pub enum Error {
     /// We don't have enough directory info to build circuits
     #[error("not enough directory information to build circuits")]
     NotEnoughInfo,
+    /// We were asked for a relay with a pair of identities, but those
+    /// identities belong to different relays.
+    #[error("identities belong to different relays")]
+    IdentityMismatch,
 }
@@ -0,0 +1,126 @@
+//! Interned relay families, for fast "same family" checks.
+//!
+//! Every time we build a path, we check pairs of relays to see whether they
+//! belong to the same family.  Rather than comparing RSA identities each
+//! time, we intern every identity listed in the consensus as a small
+//! integer when we add a microdescriptor, and keep each relay's family as a
+//! sorted set of those integers.
+
+use std::collections::HashMap;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::types::family::RelayFamily;
+
+/// An interned relay identity.
+///
+/// This is the index of the relay's routerstatus within the consensus; it's
+/// only meaningful within a single [`NetDir`](crate::NetDir).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub(crate) struct FamilyId(u32);
+
+impl FamilyId {
+    /// Return the index of the routerstatus that this id refers to.
+    pub(crate) fn rs_idx(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A sorted, deduplicated set of [`FamilyId`]s.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FamilyIdSet(Box<[FamilyId]>);
+
+impl FamilyIdSet {
+    /// Return true if `id` is a member of this set.
+    pub(crate) fn contains(&self, id: FamilyId) -> bool {
+        self.0.binary_search(&id).is_ok()
+    }
+
+    /// Return an iterator over the members of this set.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = FamilyId> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+/// Family information for a single relay whose microdescriptor we have.
+#[derive(Clone, Debug)]
+pub(crate) struct FamilyInfo {
+    /// The interned identity of this relay.
+    id: FamilyId,
+    /// The interned identities of the relays that this relay claims as
+    /// family members.
+    ///
+    /// Members that aren't listed in the consensus are omitted, since we
+    /// can never build a path through them anyway.
+    members: FamilyIdSet,
+}
+
+impl FamilyInfo {
+    /// Construct the family information for a relay with the interned
+    /// identity `id`, which declared `family`.
+    ///
+    /// Uses `index` to intern the family members.
+    pub(crate) fn new(
+        id: usize,
+        family: &RelayFamily,
+        index: &HashMap<RsaIdentity, usize>,
+    ) -> Self {
+        // RelayFamily is already sorted by identity, but our interned ids
+        // are in a different order.
+        let mut members: Vec<FamilyId> = family
+            .members()
+            .filter_map(|rsa_id| index.get(rsa_id))
+            .map(|idx| FamilyId(*idx as u32))
+            .collect();
+        members.sort_unstable();
+        members.dedup();
+        FamilyInfo {
+            id: FamilyId(id as u32),
+            members: FamilyIdSet(members.into_boxed_slice()),
+        }
+    }
+
+    /// Return the set of relays that this relay claims as family members.
+    pub(crate) fn members(&self) -> &FamilyIdSet {
+        &self.members
+    }
+
+    /// Return true if this relay and `other` each list the other as a
+    /// family member.
+    pub(crate) fn mutual(&self, other: &FamilyInfo) -> bool {
+        self.members.contains(other.id) && other.members.contains(self.id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn intern() {
+        let index: HashMap<RsaIdentity, usize> = (0_u8..10)
+            .map(|i| ([i * 3; 20].into(), usize::from(9 - i)))
+            .collect();
+
+        // Relay 0x03 (index 8) lists 0x06 (index 7), 0x09 (index 6), and an
+        // unlisted relay.
+        let fam1: RelayFamily = "$0909090909090909090909090909090909090909 \
+                                 $0606060606060606060606060606060606060606 \
+                                 $0707070707070707070707070707070707070707 \
+                                 $0606060606060606060606060606060606060606"
+            .parse()
+            .unwrap();
+        let info1 = FamilyInfo::new(8, &fam1, &index);
+        assert_eq!(info1.id.rs_idx(), 8);
+        let members: Vec<_> = info1.members().iter().map(FamilyId::rs_idx).collect();
+        assert_eq!(members, vec![6, 7]);
+
+        // Relay 0x06 lists 0x03 back; relay 0x09 doesn't.
+        let fam2: RelayFamily = "0303030303030303030303030303030303030303".parse().unwrap();
+        let info2 = FamilyInfo::new(7, &fam2, &index);
+        let info3 = FamilyInfo::new(6, &RelayFamily::new(), &index);
+        assert!(info1.mutual(&info2));
+        assert!(info2.mutual(&info1));
+        assert!(!info1.mutual(&info3));
+        assert!(!info3.mutual(&info1));
+    }
+}
@@ -57,6 +57,7 @@
 
 mod err;
 pub mod fallback;
+mod family;
 pub mod params;
 #[cfg(test)]
 mod testing;
@@ -65,6 +66,7 @@ mod weight;
 #[cfg(any(test, feature = "testing"))]
 pub mod testnet;
 
+use family::FamilyInfo;
 use tor_linkspec::ChanTarget;
 use tor_llcrypto as ll;
 use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
@@ -74,12 +76,13 @@ use tor_netdoc::types::policy::PortPolicy;
 
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tracing::warn;
 
 pub use err::Error;
-pub use weight::WeightRole;
+pub use weight::{WeightAdjustments, WeightExplanation, WeightRole};
 /// A Result using the Error type from the tor-netdir crate
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -125,7 +128,7 @@ impl SubnetConfig {
     }
 
     /// Are two addresses in the same subnet according to this configuration
-    fn addrs_in_same_subnet(&self, a: &IpAddr, b: &IpAddr) -> bool {
+    pub fn addrs_in_same_subnet(&self, a: &IpAddr, b: &IpAddr) -> bool {
         match (a, b) {
             (IpAddr::V4(a), IpAddr::V4(b)) => {
                 let bits = self.subnets_family_v4;
@@ -171,6 +174,9 @@ enum MdEntry {
     Present {
         /// The microdescriptor itself.
         md: Arc<Microdesc>,
+        /// The interned family information for the relay that uses this
+        /// microdescriptor.
+        family: FamilyInfo,
     },
 }
 
@@ -297,6 +303,16 @@ pub struct NetDir {
     /// This is constructed at the same time as the NetDir object, so it
     /// can be immutable.
     rs_idx_by_rsa: Arc<HashMap<RsaIdentity, usize>>,
+    /// Map from OR port address to the indices of every routerstatus
+    /// within `self.consensus.relays()` that lists that address.
+    ///
+    /// Like `rs_idx_by_rsa`, this is constructed along with the NetDir.
+    rs_idx_by_addr: Arc<HashMap<SocketAddr, Vec<usize>>>,
+    /// Map from lowercased nickname to the indices of every routerstatus
+    /// within `self.consensus.relays()` that has that nickname.
+    ///
+    /// Like `rs_idx_by_rsa`, this is constructed along with the NetDir.
+    rs_idx_by_nickname: Arc<HashMap<String, Vec<usize>>>,
 
     /// Weight values to apply to a given relay when deciding how frequently
     /// to choose it for a given role.
@@ -320,6 +336,8 @@ pub struct Relay<'a> {
     rs: &'a netstatus::MdConsensusRouterStatus,
     /// A microdescriptor for this relay.
     md: &'a Microdesc,
+    /// Interned family information for this relay.
+    family: &'a FamilyInfo,
 }
 
 /// A relay that we haven't checked for validity or usability in
@@ -330,6 +348,9 @@ pub struct UncheckedRelay<'a> {
     rs: &'a netstatus::MdConsensusRouterStatus,
     /// A microdescriptor for this relay, if there is one.
     md: Option<&'a Microdesc>,
+    /// Interned family information for this relay, if we have a
+    /// microdescriptor for it.
+    family: Option<&'a FamilyInfo>,
 }
 
 /// A partial or full network directory that we can download
@@ -390,11 +411,25 @@ impl PartialNetDir {
             .map(|(rs_idx, rs)| (*rs.rsa_identity(), rs_idx))
             .collect();
 
+        let mut rs_idx_by_addr: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        let mut rs_idx_by_nickname: HashMap<String, Vec<usize>> = HashMap::new();
+        for (rs_idx, rs) in consensus.relays().iter().enumerate() {
+            for addr in rs.orport_addrs() {
+                rs_idx_by_addr.entry(*addr).or_default().push(rs_idx);
+            }
+            rs_idx_by_nickname
+                .entry(rs.nickname().to_ascii_lowercase())
+                .or_default()
+                .push(rs_idx);
+        }
+
         let netdir = NetDir {
             consensus: Arc::new(consensus),
             params,
             mds,
             rs_idx_by_rsa: Arc::new(rs_idx_by_rsa),
+            rs_idx_by_addr: Arc::new(rs_idx_by_addr),
+            rs_idx_by_nickname: Arc::new(rs_idx_by_nickname),
             rs_idx_by_ed: HashMap::new(),
             weights,
         };
@@ -475,8 +510,14 @@ impl NetDir {
                 // we'll let the most recent one win.
                 self.rs_idx_by_ed.insert(*md.ed25519_id(), rs_idx);
 
+                // Intern this relay's family, so that we don't have to
+                // compare identities every time we build a path.
+                let rsa_id = self.consensus.relays()[rs_idx].rsa_identity();
+                let fam_idx = self.rs_idx_by_rsa.get(rsa_id).copied().unwrap_or(rs_idx);
+                let family = FamilyInfo::new(fam_idx, md.family(), &self.rs_idx_by_rsa);
+
                 // Happy path: we did indeed want this one.
-                self.mds.insert(MdEntry::Present { md });
+                self.mds.insert(MdEntry::Present { md, family });
 
                 return true;
             } else {
@@ -495,11 +536,11 @@ impl NetDir {
         &'a self,
         rs: &'a netstatus::MdConsensusRouterStatus,
     ) -> UncheckedRelay<'a> {
-        let md = match self.mds.get(rs.md_digest()) {
-            Some(MdEntry::Present { md }) => Some(Arc::as_ref(md)),
-            _ => None,
+        let (md, family) = match self.mds.get(rs.md_digest()) {
+            Some(MdEntry::Present { md, family }) => (Some(Arc::as_ref(md)), Some(family)),
+            _ => (None, None),
         };
-        UncheckedRelay { rs, md }
+        UncheckedRelay { rs, md, family }
     }
 
     /// Replace the overridden parameters in this netdir with `new_replacement`.
@@ -554,20 +595,70 @@ impl NetDir {
     ///
     /// (Does not return unusable relays.)
     ///
+    /// Return `Ok(None)` if we have no usable relay with either key.  Return
+    /// [`Error::IdentityMismatch`] if we have a usable relay with one of the
+    /// keys, but it doesn't have the other: callers should usually treat
+    /// that as a protocol violation.
+    ///
     /// Note that if a microdescriptor is subsequently added for a relay
     /// with this ID, the ID may become usable.
-    pub fn by_id_pair(&self, ed_id: &Ed25519Identity, rsa_id: &RsaIdentity) -> Option<Relay<'_>> {
-        self.by_id(ed_id).filter(|r| r.rs.rsa_identity() == rsa_id)
+    pub fn by_id_pair(
+        &self,
+        ed_id: &Ed25519Identity,
+        rsa_id: &RsaIdentity,
+    ) -> Result<Option<Relay<'_>>> {
+        match (self.by_id(ed_id), self.by_rsa_id(rsa_id)) {
+            (None, None) => Ok(None),
+            (Some(r1), Some(r2)) if r1.same_relay(&r2) => Ok(Some(r1)),
+            (_, _) => Err(Error::IdentityMismatch),
+        }
     }
 
     /// Return the usable relay matching a given [`ChanTarget`]'s
     /// identities, if any.
     ///
     /// (Does not return unusable relays.)
-    pub fn by_chantarget(&self, chan_target: &impl tor_linkspec::ChanTarget) -> Option<Relay<'_>> {
+    ///
+    /// As with [`NetDir::by_id_pair`], return an error if the target's
+    /// identities belong to different relays.
+    pub fn by_chantarget(
+        &self,
+        chan_target: &impl tor_linkspec::ChanTarget,
+    ) -> Result<Option<Relay<'_>>> {
         self.by_id_pair(chan_target.ed_identity(), chan_target.rsa_identity())
     }
 
+    /// Return every usable relay that lists `addr` as one of its OR port
+    /// addresses.
+    ///
+    /// (Does not return unusable relays.)
+    pub fn by_addr(&self, addr: &SocketAddr) -> impl Iterator<Item = Relay<'_>> {
+        self.relays_by_idx(self.rs_idx_by_addr.get(addr))
+    }
+
+    /// Return every usable relay whose nickname is `nickname`.
+    ///
+    /// Nicknames are compared case-insensitively.  They are not unique, and
+    /// they are not authenticated, so don't use them to identify relays in
+    /// any security-sensitive way.
+    ///
+    /// (Does not return unusable relays.)
+    pub fn by_nickname(&self, nickname: &str) -> impl Iterator<Item = Relay<'_>> {
+        self.relays_by_idx(self.rs_idx_by_nickname.get(&nickname.to_ascii_lowercase()))
+    }
+
+    /// Helper: return the usable relays whose routerstatus indices are listed
+    /// in `idxs`.
+    fn relays_by_idx<'a>(
+        &'a self,
+        idxs: Option<&'a Vec<usize>>,
+    ) -> impl Iterator<Item = Relay<'a>> + 'a {
+        idxs.into_iter().flatten().filter_map(move |rs_idx| {
+            let rs = self.consensus.relays().get(*rs_idx)?;
+            self.relay_from_rs(rs).into_relay()
+        })
+    }
+
     /// Return a boolean if this consensus definitely has (or does not
     /// have) a relay matching both the given Ed25519 and RSA
     /// identity.
@@ -712,6 +803,27 @@ impl NetDir {
 
         available >= min_frac_paths
     }
+    /// Return how old the directory information for `relay` was when this
+    /// directory became valid.
+    ///
+    /// This is the time between when `relay` published the descriptor that
+    /// its consensus entry is based on, and this directory's valid-after
+    /// time.  (If the relay's published time is later than valid-after, we
+    /// return zero.)  Return None if we don't know when the relay published
+    /// its descriptor.
+    ///
+    /// Relays rotate their onion keys periodically, so a relay with very
+    /// old directory information is more likely to reject our circuit
+    /// extension attempts.
+    pub fn relay_info_age(&self, relay: &Relay<'_>) -> Option<Duration> {
+        let published = relay.published()?;
+        Some(
+            self.lifetime()
+                .valid_after()
+                .duration_since(published)
+                .unwrap_or_default(),
+        )
+    }
     /// Choose a relay at random.
     ///
     /// Each relay is chosen with probability proportional to its weight
@@ -726,6 +838,22 @@ impl NetDir {
         role: WeightRole,
         usable: P,
     ) -> Option<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        self.pick_relay_adjusted(rng, role, &WeightAdjustments::default(), usable)
+    }
+
+    /// Choose a relay at random, as [`NetDir::pick_relay`] does, after
+    /// applying `adjust` to every relay's weight.
+    pub fn pick_relay_adjusted<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        role: WeightRole,
+        adjust: &WeightAdjustments,
+        usable: P,
+    ) -> Option<Relay<'a>>
     where
         R: rand::Rng,
         P: Fn(&Relay<'a>) -> bool,
@@ -754,7 +882,7 @@ impl NetDir {
         // can exceed u64::MAX.  We make sure that can't happen when we
         // set up `self.weights`.
         relays[..]
-            .choose_weighted(rng, |r| self.weights.weight_rs_for_role(r.rs, role))
+            .choose_weighted(rng, |r| self.selection_weight(r, role, adjust))
             .ok()
             .cloned()
     }
@@ -779,6 +907,23 @@ impl NetDir {
         role: WeightRole,
         usable: P,
     ) -> Vec<Relay<'a>>
+    where
+        R: rand::Rng,
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        self.pick_n_relays_adjusted(rng, n, role, &WeightAdjustments::default(), usable)
+    }
+
+    /// Helper: choose `n` relays at random, as [`NetDir::pick_n_relays`]
+    /// does, after applying `adjust` to every relay's weight.
+    fn pick_n_relays_adjusted<'a, R, P>(
+        &'a self,
+        rng: &mut R,
+        n: usize,
+        role: WeightRole,
+        adjust: &WeightAdjustments,
+        usable: P,
+    ) -> Vec<Relay<'a>>
     where
         R: rand::Rng,
         P: Fn(&Relay<'a>) -> bool,
@@ -786,9 +931,9 @@ impl NetDir {
         use rand::seq::SliceRandom;
         let relays: Vec<_> = self.relays().filter(usable).collect();
         // NOTE: See discussion in pick_relay().
-        let mut relays = match relays[..].choose_multiple_weighted(rng, n, |r| {
-            self.weights.weight_rs_for_role(r.rs, role) as f64
-        }) {
+        let mut relays = match relays[..]
+            .choose_multiple_weighted(rng, n, |r| self.selection_weight(r, role, adjust) as f64)
+        {
             Err(_) => Vec::new(),
             Ok(iter) => iter.map(Relay::clone).collect(),
         };
@@ -796,12 +941,75 @@ impl NetDir {
         relays
     }
 
+    /// Choose `n` relays at random, as [`NetDir::pick_n_relays`] does, using
+    /// a random number generator seeded with `seed`, and after applying
+    /// `adjust` to every relay's weight.
+    ///
+    /// Given the same directory, `seed`, and arguments, this function always
+    /// returns the same relays in the same order.  That makes it useful for
+    /// simulations and experiments that need to be reproducible.  It is
+    /// _not_ suitable for choosing relays for real circuits, since anybody
+    /// who knows the seed can predict the result.
+    pub fn pick_n_relays_seeded<'a, P>(
+        &'a self,
+        seed: u64,
+        n: usize,
+        role: WeightRole,
+        adjust: &WeightAdjustments,
+        usable: P,
+    ) -> Vec<Relay<'a>>
+    where
+        P: Fn(&Relay<'a>) -> bool,
+    {
+        use rand::SeedableRng;
+        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(seed);
+        self.pick_n_relays_adjusted(&mut rng, n, role, adjust, usable)
+    }
+
+    /// Return the percentage of its weight that `adjust` leaves `relay`.
+    ///
+    /// This is where we decide which of the [`WeightAdjustments`] apply to
+    /// a given relay.
+    fn weight_percent(&self, relay: &Relay<'_>, adjust: &WeightAdjustments) -> u8 {
+        adjust.percent_for(self.relay_info_age(relay))
+    }
+
+    /// Return the weight that our random selection functions use for
+    /// `relay` in `role`, after applying `adjust`.
+    fn selection_weight(
+        &self,
+        relay: &Relay<'_>,
+        role: WeightRole,
+        adjust: &WeightAdjustments,
+    ) -> u64 {
+        let percent = self.weight_percent(relay, adjust);
+        self.weights
+            .adjusted_weight_rs_for_role(relay.rs, role, percent)
+    }
+
     /// Compute the weight with which `relay` will be selected for a given
     /// `role`.
     pub fn relay_weight<'a>(&'a self, relay: &Relay<'a>, role: WeightRole) -> RelayWeight {
         RelayWeight(self.weights.weight_rs_for_role(relay.rs, role))
     }
 
+    /// Explain how we compute the weight with which `relay` will be selected
+    /// for a given `role`, after applying `adjust`.
+    ///
+    /// The explained weight is always the one that
+    /// [`NetDir::pick_relay_adjusted`] uses for `relay` with the same
+    /// adjustments.  With no adjustments, it's the same as the one that
+    /// [`NetDir::relay_weight`] returns.
+    pub fn weight_explanation<'a>(
+        &'a self,
+        relay: &Relay<'a>,
+        role: WeightRole,
+        adjust: &WeightAdjustments,
+    ) -> WeightExplanation {
+        let percent = self.weight_percent(relay, adjust);
+        self.weights.explain_rs_for_role(relay.rs, role, percent)
+    }
+
     /// Compute the total weight with which any relay matching `usable`
     /// will be selected for a given `role`.
     ///
@@ -854,10 +1062,11 @@ impl NetDir {
         &'a self,
         relay: &'a Relay<'a>,
     ) -> impl Iterator<Item = Relay<'a>> {
-        let relay_rsa_id = relay.rsa_id();
-        relay.md.family().members().filter_map(move |other_rsa_id| {
-            self.by_rsa_id(other_rsa_id)
-                .filter(|other_relay| other_relay.md.family().contains(relay_rsa_id))
+        relay.family.members().iter().filter_map(move |id| {
+            let rs = self.consensus.relays().get(id.rs_idx())?;
+            self.relay_from_rs(rs)
+                .into_relay()
+                .filter(|other_relay| other_relay.family.mutual(relay.family))
         })
     }
 }
@@ -892,6 +1101,7 @@ impl<'a> UncheckedRelay<'a> {
             Some(Relay {
                 rs: self.rs,
                 md: self.md?,
+                family: self.family?,
             })
         } else {
             None
@@ -939,6 +1149,21 @@ impl<'a> Relay<'a> {
     pub fn is_flagged_guard(&self) -> bool {
         self.rs.is_flagged_guard()
     }
+    /// Return the time when this relay published the descriptor that its
+    /// consensus entry is based on, if we know it.
+    pub fn published(&self) -> Option<SystemTime> {
+        self.rs.published()
+    }
+    /// Return true if this relay is marked as suitable for long-lived
+    /// circuits.
+    pub fn is_flagged_stable(&self) -> bool {
+        self.rs.is_flagged_stable()
+    }
+    /// Return true if this relay is marked as suitable for high-bandwidth
+    /// circuits.
+    pub fn is_flagged_fast(&self) -> bool {
+        self.rs.is_flagged_fast()
+    }
     /// Return true if both relays are in the same subnet, as configured by
     /// `subnet_config`.
     ///
@@ -961,7 +1186,7 @@ impl<'a> Relay<'a> {
         if self.same_relay(other) {
             return true;
         }
-        self.md.family().contains(other.rsa_id()) && other.md.family().contains(self.rsa_id())
+        self.family.mutual(other.family)
     }
 
     /// Return true if there are any ports for which this Relay can be
@@ -1481,15 +1706,25 @@ mod test {
         assert_eq!(r.rs.rsa_identity().as_bytes(), &[13; 20]);
         assert!(netdir.rsa_id_is_listed(&[13; 20].into()));
 
-        let r = netdir.by_id_pair(&[13; 32].into(), &[13; 20].into());
+        let r = netdir
+            .by_id_pair(&[13; 32].into(), &[13; 20].into())
+            .unwrap();
         assert!(r.is_none());
         let r = netdir
             .by_id_pair(&[14; 32].into(), &[14; 20].into())
+            .unwrap()
             .unwrap();
         assert_eq!(r.rsa_identity(), &[14; 20].into());
         assert_eq!(r.ed_identity(), &[14; 32].into());
+        let r = netdir.by_id_pair(&[99; 32].into(), &[99; 20].into());
+        assert!(matches!(r, Ok(None)));
+        // These identities belong to different relays, or to none at all.
         let r = netdir.by_id_pair(&[14; 32].into(), &[99; 20].into());
-        assert!(r.is_none());
+        assert!(matches!(r, Err(Error::IdentityMismatch)));
+        let r = netdir.by_id_pair(&[99; 32].into(), &[14; 20].into());
+        assert!(matches!(r, Err(Error::IdentityMismatch)));
+        let r = netdir.by_id_pair(&[14; 32].into(), &[15; 20].into());
+        assert!(matches!(r, Err(Error::IdentityMismatch)));
 
         assert_eq!(
             netdir.id_pair_listed(&[13; 32].into(), &[13; 20].into()),
@@ -1505,6 +1740,62 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_published() {
+        let day = Duration::from_secs(86400);
+        let netdir = construct_custom_netdir(|idx, nb| {
+            if idx == 3 {
+                nb.rs.published(SystemTime::now() - day * 30);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let valid_after = netdir.lifetime().valid_after();
+
+        // By default, every relay in the test network published right when
+        // the consensus became valid.
+        let r4 = netdir.by_id(&[4; 32].into()).unwrap();
+        assert_eq!(r4.published(), Some(valid_after));
+        assert_eq!(netdir.relay_info_age(&r4), Some(Duration::default()));
+
+        let r3 = netdir.by_id(&[3; 32].into()).unwrap();
+        assert!(r3.published().unwrap() < valid_after);
+        let age = netdir.relay_info_age(&r3).unwrap();
+        assert!(age > day * 29 && age < day * 31);
+    }
+
+    #[test]
+    fn test_by_addr_and_nickname() {
+        let netdir = construct_custom_netdir(|idx, nb| {
+            nb.omit_md = idx == 13;
+            nb.rs.nickname(format!("Relay{}", idx % 20));
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        // Every fifth relay shares an address; relay 13 is unusable.
+        let addr: SocketAddr = "3.0.0.3:9001".parse().unwrap();
+        let mut ids: Vec<_> = netdir
+            .by_addr(&addr)
+            .map(|r| r.id().as_bytes()[0])
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![3, 8, 18, 23, 28, 33, 38]);
+        let addr: SocketAddr = "3.0.0.3:9002".parse().unwrap();
+        assert_eq!(netdir.by_addr(&addr).count(), 0);
+
+        let mut ids: Vec<_> = netdir
+            .by_nickname("relay5")
+            .map(|r| r.id().as_bytes()[0])
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![5, 25]);
+        assert_eq!(netdir.by_nickname("RELAY13").count(), 1);
+        assert_eq!(netdir.by_nickname("Nobody").count(), 0);
+    }
+
     #[test]
     fn weight_type() {
         let r0 = RelayWeight(0);
@@ -1545,6 +1836,101 @@ mod test {
             .is_none());
     }
 
+    #[test]
+    fn weight_explanation() {
+        // Make every third relay's information a month old.
+        let day = Duration::from_secs(86400);
+        let netdir = construct_custom_netdir(|idx, nb| {
+            if idx % 3 == 0 {
+                nb.rs.published(SystemTime::now() - day * 30);
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let mut stale = WeightAdjustments::new();
+        stale.stale_relays(day * 7, 10);
+
+        let mut n_stale = 0;
+        for adjust in &[WeightAdjustments::new(), stale] {
+            for relay in netdir.relays() {
+                for role in [
+                    WeightRole::Guard,
+                    WeightRole::Middle,
+                    WeightRole::Exit,
+                    WeightRole::BeginDir,
+                    WeightRole::Unweighted,
+                ] {
+                    let e = netdir.weight_explanation(&relay, role, adjust);
+                    // The factors multiply to the weight that the sampler
+                    // uses.
+                    let product = (u128::from(e.bandwidth())
+                        * u128::from(e.role_factor())
+                        * u128::from(e.stale_percent())
+                        / 100)
+                        >> e.shift();
+                    assert_eq!(RelayWeight(product as u64), e.weight());
+                    assert_eq!(
+                        e.weight(),
+                        RelayWeight(netdir.selection_weight(&relay, role, adjust))
+                    );
+
+                    if e.stale_percent() == 100 {
+                        assert_eq!(e.weight(), netdir.relay_weight(&relay, role));
+                    } else {
+                        assert_eq!(e.stale_percent(), 10);
+                        assert!(netdir.relay_info_age(&relay).unwrap() > day * 7);
+                        n_stale += 1;
+                    }
+                }
+            }
+        }
+        // Only the month-old relays are stale, and only when we ask.
+        let n_old = netdir
+            .relays()
+            .filter(|r| netdir.relay_info_age(r).unwrap() > day)
+            .count();
+        assert!(n_old > 0);
+        assert_eq!(n_stale, n_old * 5);
+
+        // All our Wxy are 1, so the weight is just the bandwidth.
+        let relay = netdir.by_id(&[35; 32].into()).unwrap();
+        let e = netdir.weight_explanation(&relay, WeightRole::Guard, &WeightAdjustments::new());
+        assert_eq!(e.bandwidth(), 6_000);
+        assert_eq!(e.role_factor(), 1);
+        assert_eq!(e.stale_percent(), 100);
+        assert_eq!(e.shift(), 0);
+    }
+
+    #[test]
+    fn pick_seeded() {
+        let dir = construct_netdir().unwrap().unwrap_if_sufficient().unwrap();
+        let usable = |r: &Relay<'_>| r.supports_exit_port_ipv4(80);
+        let none = WeightAdjustments::new();
+        let ids = |relays: Vec<Relay<'_>>| -> Vec<RsaIdentity> {
+            relays.iter().map(|r| *r.rsa_identity()).collect()
+        };
+
+        let a = ids(dir.pick_n_relays_seeded(1234, 4, WeightRole::Middle, &none, usable));
+        let b = ids(dir.pick_n_relays_seeded(1234, 4, WeightRole::Middle, &none, usable));
+        assert_eq!(a.len(), 4);
+        assert_eq!(a, b);
+        // No relay gets picked twice.
+        assert!(a.iter().collect::<HashSet<_>>().len() == 4);
+
+        // Some other seed will give us a different answer.
+        assert!((0..16).any(|seed| {
+            ids(dir.pick_n_relays_seeded(seed, 4, WeightRole::Middle, &none, usable)) != a
+        }));
+
+        // We only ever return usable relays.
+        for seed in 0..16 {
+            for r in dir.pick_n_relays_seeded(seed, 40, WeightRole::Middle, &none, usable) {
+                assert!(usable(&r));
+            }
+        }
+    }
+
     #[test]
     fn family_list() {
         let netdir = construct_custom_netdir(|idx, n| {
@@ -1579,4 +1965,72 @@ mod test {
         // Note that 13 doesn't get put in, even though it's listed, since it doesn't claim
         //  membership with 10.
     }
+
+    #[test]
+    fn family_canonical() {
+        // Family lines as they show up in practice: mixed-case hex, with
+        // and without dollar signs, nicknames, and duplicates.
+        let netdir = construct_custom_netdir(|idx, n| {
+            if idx == 0x0a {
+                n.md.family(
+                    "$0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B=Relay11 \
+                     0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c Relay13 \
+                     $0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b \
+                     $0D0d0D0d0D0d0D0d0D0d0D0d0D0d0D0d0D0d0D0d~Relay13 \
+                     $FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"
+                        .parse()
+                        .unwrap(),
+                );
+            } else if idx == 0x0c {
+                n.md.family("Relay10 relay11".parse().unwrap());
+            } else if idx == 0x0d {
+                n.md.family(
+                    "$0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a \
+                     $0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C0C"
+                        .parse()
+                        .unwrap(),
+                );
+            }
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+
+        let r10 = netdir.by_id(&[10; 32].into()).unwrap();
+        let r11 = netdir.by_id(&[11; 32].into()).unwrap();
+        let r12 = netdir.by_id(&[12; 32].into()).unwrap();
+        let r13 = netdir.by_id(&[13; 32].into()).unwrap();
+
+        // The family line was canonicalized: sorted, without duplicates,
+        // and without the nickname.
+        let fam: Vec<_> = r10.md.family().members().cloned().collect();
+        assert_eq!(
+            fam,
+            vec![
+                [0x0b; 20].into(),
+                [0x0c; 20].into(),
+                [0x0d; 20].into(),
+                [0xff; 20].into()
+            ]
+        );
+        assert_eq!(r10.md.family().n_dropped(), 1);
+        assert!(r12.md.family().is_empty());
+        assert_eq!(r12.md.family().n_dropped(), 2);
+
+        assert!(r10.in_same_family(&r10));
+        assert!(r10.in_same_family(&r11));
+        assert!(r11.in_same_family(&r10));
+        assert!(r10.in_same_family(&r13));
+        assert!(r13.in_same_family(&r10));
+        // 12 only lists nicknames, so it isn't in anybody's family.
+        assert!(!r10.in_same_family(&r12));
+        assert!(!r13.in_same_family(&r12));
+        assert!(!r12.in_same_family(&r13));
+        assert!(!r11.in_same_family(&r13));
+
+        let family: HashSet<_> = netdir.known_family_members(&r10).map(|r| *r.id()).collect();
+        assert_eq!(family.len(), 2);
+        assert!(family.contains(&Ed25519Identity::from([11; 32])));
+        assert!(family.contains(&Ed25519Identity::from([13; 32])));
+    }
 }
@@ -81,8 +81,8 @@ pub fn construct_network() -> BuildResult<(MdConsensus, Vec<Microdesc>)> {
 /// Each pair of relays is in a family with one another: 0x00..00 with
 /// 0x01..01, and so on.
 ///
-/// All relays are marked as usable.  The first ten are marked with no
-/// additional flags.  The next ten are marked with the exit flag.
+/// All relays are marked as usable, and as Fast.  The first ten are marked
+/// with no additional flags.  The next ten are marked with the exit flag.
 /// The next ten are marked with the guard flag.  The last ten are
 /// marked with the exit _and_ guard flags.
 ///
@@ -100,7 +100,9 @@ pub fn construct_network() -> BuildResult<(MdConsensus, Vec<Microdesc>)> {
 /// flag-based bandwidth weighting.
 ///
 /// The consensus is declared as using method 34, and as being valid for
-/// one day (in realtime) after the current `SystemTime`.
+/// one day (in realtime) after the current `SystemTime`.  Every relay is
+/// listed as having published its descriptor at the moment the consensus
+/// becomes valid.
 ///
 /// # Customization
 ///
@@ -129,7 +131,7 @@ pub fn construct_custom_network<F>(mut func: F) -> BuildResult<(MdConsensus, Vec
 where
     F: FnMut(usize, &mut NodeBuilders),
 {
-    let f = RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::V2DIR;
+    let f = RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::V2DIR | RelayFlags::FAST;
     // define 4 groups of flags
     let flags = [
         f,
@@ -183,6 +185,7 @@ where
         rs_builder
             .identity([idx; 20].into())
             .add_or_port(SocketAddr::from(([idx % 5, 0, 0, 3], 9001)))
+            .published(now)
             .protos(protocols)
             .set_flags(flags)
             .weight(weight);
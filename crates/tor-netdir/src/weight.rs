@@ -11,9 +11,12 @@
 //! - The flags that a relay has in the consensus, and their scarcity.  If a
 //!   relay provides particularly scarce functionality, we might choose not to
 //!   use it for other roles, or to use it less commonly for them.
+//! - Any [`WeightAdjustments`] that the caller asks for, such as making
+//!   relays with stale directory information less likely to be picked.
 
 use crate::params::NetParameters;
 use bitflags::bitflags;
+use std::time::Duration;
 use tor_netdoc::doc::netstatus::{self, MdConsensus, MdConsensusRouterStatus, NetParams};
 
 /// Helper: Calculate the function we should use to find initial relay
@@ -99,6 +102,116 @@ pub enum WeightRole {
     Unweighted,
 }
 
+/// Adjustments to make to relays' weights when picking them at random, on
+/// top of the weights that the consensus gives them.
+///
+/// Each adjustment keeps only a percentage of the weight of the relays it
+/// applies to.  The default makes no adjustments, so that every relay keeps
+/// the weight that [`NetDir::relay_weight`](crate::NetDir::relay_weight)
+/// reports.
+///
+/// An adjustment never takes a nonzero weight all the way down to zero:
+/// it makes relays less likely to be picked, but doesn't exclude them.
+#[derive(Clone, Debug, Default)]
+pub struct WeightAdjustments {
+    /// If present, relays whose directory information is older than this
+    /// age keep only this percentage of their weight.
+    stale: Option<(Duration, u8)>,
+}
+
+impl WeightAdjustments {
+    /// Return a new set of adjustments that leaves every weight alone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep only `percent` percent of the weight of every relay whose
+    /// directory information is more than `max_age` old.
+    ///
+    /// See [`NetDir::relay_info_age`](crate::NetDir::relay_info_age).  A
+    /// relay whose published time we don't know counts as stale.  Values
+    /// of `percent` over 100 are treated as 100.
+    pub fn stale_relays(&mut self, max_age: Duration, percent: u8) -> &mut Self {
+        self.stale = Some((max_age, percent.min(100)));
+        self
+    }
+
+    /// Return the percentage of its weight that a relay keeps, if its
+    /// directory information is `info_age` old.
+    pub(crate) fn percent_for(&self, info_age: Option<Duration>) -> u8 {
+        match (self.stale, info_age) {
+            (Some((max_age, _)), Some(age)) if age <= max_age => 100,
+            (Some((_, percent)), _) => percent,
+            (None, _) => 100,
+        }
+    }
+}
+
+/// A breakdown of how we found the weight of a single relay for a single
+/// [`WeightRole`].
+///
+/// The weight is `(bandwidth * role_factor * stale_percent / 100) >> shift`,
+/// rounded down, except that a nonzero weight is never adjusted down to
+/// zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WeightExplanation {
+    /// The relay's bandwidth, as we count it.
+    bandwidth: u32,
+    /// The consensus bandwidth-weight factor for this kind of relay in this
+    /// role.
+    role_factor: u32,
+    /// The percentage of its weight that the relay keeps, given how old its
+    /// directory information is.
+    stale_percent: u8,
+    /// The number of bits by which we shift every weight right.
+    shift: u8,
+    /// The resulting weight.
+    weight: u64,
+}
+
+impl WeightExplanation {
+    /// Return the bandwidth that we count for this relay.
+    ///
+    /// This is usually the relay's measured bandwidth from the consensus.
+    /// If the consensus has no measured bandwidths, it's the relay's
+    /// self-reported bandwidth; if the consensus has no nonzero bandwidths
+    /// at all, every relay counts as having bandwidth 1.  If the consensus
+    /// has measured bandwidths but not for this relay, it's zero.
+    pub fn bandwidth(&self) -> u32 {
+        self.bandwidth
+    }
+
+    /// Return the bandwidth-weight factor that we apply to this relay's
+    /// bandwidth.
+    ///
+    /// This is one of the `Wxx` values from the consensus (such as `Wgg`
+    /// or `Wme`), chosen by the relay's flags and the role we want it for.
+    pub fn role_factor(&self) -> u32 {
+        self.role_factor
+    }
+
+    /// Return the percentage of its weight that this relay keeps because
+    /// of how old its directory information is.
+    ///
+    /// This is 100 unless the [`WeightAdjustments`] we were given treat
+    /// this relay as stale.
+    pub fn stale_percent(&self) -> u8 {
+        self.stale_percent
+    }
+
+    /// Return the number of bits by which we shift every relay's weight to
+    /// the right, so that the total of all weights fits in a `u64`.
+    pub fn shift(&self) -> u8 {
+        self.shift
+    }
+
+    /// Return the weight that we give this relay.
+    pub fn weight(&self) -> crate::RelayWeight {
+        self.weight.into()
+    }
+}
+
 /// Description for how to weight a single kind of relay for each WeightRole.
 #[derive(Clone, Debug, Copy)]
 struct RelayWeight {
@@ -221,16 +334,60 @@ impl WeightSet {
     /// actually matches the given role.  For example, if `role` is Guard
     /// we don't check whether or not `rs` actually has the Guard flag.
     pub(crate) fn weight_rs_for_role(&self, rs: &MdConsensusRouterStatus, role: WeightRole) -> u64 {
-        self.weight_bw_for_role(WeightKind::for_rs(rs), rs.weight(), role)
+        self.adjusted_weight_rs_for_role(rs, role, 100)
+    }
+
+    /// As [`WeightSet::weight_rs_for_role`], but keep only `percent`
+    /// percent of the weight.
+    ///
+    /// This is where every [`WeightAdjustments`] gets applied.
+    pub(crate) fn adjusted_weight_rs_for_role(
+        &self,
+        rs: &MdConsensusRouterStatus,
+        role: WeightRole,
+        percent: u8,
+    ) -> u64 {
+        self.adjusted_weight_bw_for_role(WeightKind::for_rs(rs), rs.weight(), role, percent)
+    }
+
+    /// Explain how [`WeightSet::adjusted_weight_rs_for_role`] finds the
+    /// weight for a given routerstatus, role, and percentage.
+    pub(crate) fn explain_rs_for_role(
+        &self,
+        rs: &MdConsensusRouterStatus,
+        role: WeightRole,
+        percent: u8,
+    ) -> WeightExplanation {
+        let kind = WeightKind::for_rs(rs);
+        WeightExplanation {
+            bandwidth: self.bandwidth_fn.apply(rs.weight()),
+            role_factor: self.w[kind.idx()].for_role(role),
+            stale_percent: percent,
+            shift: self.shift,
+            weight: self.adjusted_weight_bw_for_role(kind, rs.weight(), role, percent),
+        }
     }
 
     /// Find the 64-bit weight to report for a relay of `kind` whose weight in
     /// the consensus is `relay_weight` when using it for `role`.
+    #[cfg(test)]
     fn weight_bw_for_role(
         &self,
         kind: WeightKind,
         relay_weight: &netstatus::RelayWeight,
         role: WeightRole,
+    ) -> u64 {
+        self.adjusted_weight_bw_for_role(kind, relay_weight, role, 100)
+    }
+
+    /// As [`WeightSet::weight_bw_for_role`], but keep only `percent` percent
+    /// of the weight.
+    fn adjusted_weight_bw_for_role(
+        &self,
+        kind: WeightKind,
+        relay_weight: &netstatus::RelayWeight,
+        role: WeightRole,
+        percent: u8,
     ) -> u64 {
         let ws = &self.w[kind.idx()];
 
@@ -240,7 +397,15 @@ impl WeightSet {
         // safe, since the inputs are both u32, and so cannot overflow
         // a u64.
         let router_weight = u64::from(router_bw) * u64::from(ws.for_role(role));
-        router_weight >> self.shift
+        if percent >= 100 || router_weight >> self.shift == 0 {
+            return router_weight >> self.shift;
+        }
+        // We scale down before we shift, for the same reason.  The product
+        // can't overflow a u128, and the result is no bigger than the
+        // unadjusted weight, so it fits in a u64.
+        let adjusted = (u128::from(router_weight) * u128::from(percent) / 100) >> self.shift;
+        // Don't let an adjustment exclude the relay entirely.
+        (adjusted as u64).max(1)
     }
 
     /// Compute the correct WeightSet for a provided MdConsensus.
@@ -583,6 +748,42 @@ mod test {
         assert_eq!(ws.weight_rs_for_role(&rs, WeightRole::Unweighted), 7777);
     }
 
+    #[test]
+    fn t_weightset_adjusted() {
+        let params = TESTVEC_PARAMS.parse().unwrap();
+        let ws = WeightSet::from_parts(BandwidthFn::MeasuredOnly, 1_000_000_000, 10000, &params);
+        let kind = WeightKind::GUARD | WeightKind::DIR;
+        let w = |bw, percent| ws.adjusted_weight_bw_for_role(kind, &bw, WeightRole::Guard, percent);
+
+        assert_eq!(w(RW::Measured(7777), 100), 7777 * 5904);
+        assert_eq!(w(RW::Measured(7777), 50), 7777 * 5904 / 2);
+        assert_eq!(w(RW::Measured(7777), 10), 7777 * 5904 / 10);
+        // An adjustment never takes a weight down to zero, or up from it.
+        assert_eq!(w(RW::Measured(7777), 0), 1);
+        assert_eq!(w(RW::Measured(0), 50), 0);
+        assert_eq!(w(RW::Unmeasured(7777), 50), 0);
+    }
+
+    #[test]
+    fn t_weight_adjustments() {
+        let day = Duration::from_secs(86400);
+        let none = WeightAdjustments::new();
+        assert_eq!(none.percent_for(None), 100);
+        assert_eq!(none.percent_for(Some(day * 100)), 100);
+
+        let mut adjust = WeightAdjustments::new();
+        adjust.stale_relays(day * 7, 10);
+        assert_eq!(adjust.percent_for(Some(day)), 100);
+        assert_eq!(adjust.percent_for(Some(day * 7)), 100);
+        assert_eq!(adjust.percent_for(Some(day * 8)), 10);
+        // If we don't know how old a relay's information is, it's stale.
+        assert_eq!(adjust.percent_for(None), 10);
+
+        // We never increase a relay's weight.
+        adjust.stale_relays(day, 250);
+        assert_eq!(adjust.percent_for(Some(day * 2)), 100);
+    }
+
     /// Return a routerstatus builder set up to deliver a routerstatus
     /// with most features disabled.
     fn rs_builder() -> RouterStatusBuilder<[u8; 32]> {
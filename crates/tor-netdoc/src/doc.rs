@@ -21,6 +21,11 @@
 //! All of these formats are described in
 //! [dir-spec.txt](https://spec.torproject.org/dir-spec).
 //!
+//! Onion services publish their own kind of document: an encrypted
+//! descriptor (`hsdesc::EncryptedHsDesc`) that tells clients how to reach
+//! them.  That format is described in
+//! [rend-spec-v3.txt](https://spec.torproject.org/rend-spec-v3).
+//!
 //! # Limitations
 //!
 //! Tor recognizes other kinds of documents that this crate doesn't
@@ -30,6 +35,8 @@
 //! use in order to calculate the consensus.
 
 pub mod authcert;
+#[cfg(feature = "hsdesc")]
+pub mod hsdesc;
 pub mod microdesc;
 pub mod netstatus;
 
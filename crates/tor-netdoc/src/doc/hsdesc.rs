@@ -0,0 +1,629 @@
+//! Implementation for version 3 onion service descriptors.
+//!
+//! An onion service descriptor tells a client how to reach an onion
+//! service: which relays act as its introduction points, and which keys to
+//! use with them.  Onion services upload their descriptors to a rotating
+//! set of HSDirs, where clients fetch them.
+//!
+//! A descriptor has three layers.  The outer layer is signed with a
+//! short-term "descriptor signing key", which is in turn certified by the
+//! service's "blinded" identity key for the current time period.  It holds
+//! an encrypted "superencrypted" layer, which lists the clients (if any)
+//! that are authorized to read the descriptor; and that layer holds an
+//! "encrypted" layer, which finally describes the introduction points.
+//!
+//! To decrypt the inner layers, a client needs the service's
+//! [`Subcredential`] for the time period (which it can only derive if it
+//! knows the service's onion address), and, if the service uses client
+//! authorization, the client's x25519 authorization key.
+//!
+//! For full information about this format, see section 2.4 of
+//! [rend-spec-v3.txt](https://spec.torproject.org/rend-spec-v3).
+//!
+//! # Limitations
+//!
+//! We don't check that the blinded key in a descriptor was actually
+//! derived from the onion service's identity key: callers need to compare
+//! [`EncryptedHsDesc::blinded_id`] against the key they expect.
+//!
+//! We don't yet parse the legacy (RSA) introduction point keys.
+//!
+//! # Availability
+//!
+//! This module is only available when this crate is built with the
+//! `hsdesc` feature enabled.
+
+mod desc_enc;
+mod inner;
+mod middle;
+
+use crate::parse::keyword::Keyword;
+use crate::parse::parser::SectionRules;
+use crate::parse::tokenize::NetDocReader;
+use crate::types::misc::{UnvalidatedEdCert, B64};
+use crate::{ParseErrorKind as EK, Result};
+
+use desc_enc::{HsDescEncryption, ENCRYPTED_CONST, SUPERENCRYPTED_CONST};
+
+use once_cell::sync::Lazy;
+use std::time::{Duration, SystemTime};
+use tor_cert::CertType;
+use tor_checkable::{signed, timed, Timebound};
+use tor_linkspec::LinkSpec;
+use tor_llcrypto::d::Sha3_256;
+use tor_llcrypto::pk::{self, curve25519, ed25519};
+
+use digest::Digest;
+#[allow(unused_imports)] // This 'use' is needed with ed25519 < 1.3.0
+use signature::Signature;
+
+/// The shortest descriptor lifetime that we accept, in minutes.
+const MIN_LIFETIME_MINUTES: u16 = 30;
+/// The longest descriptor lifetime that we accept, in minutes.
+const MAX_LIFETIME_MINUTES: u16 = 720;
+
+/// The prefix that onion services use when signing descriptors.
+const HSDESC_SIG_PREFIX: &[u8] = b"Tor onion service descriptor sig v3";
+
+/// A "subcredential" for an onion service during a single time period.
+///
+/// The subcredential is derived from the service's public identity key and
+/// its blinded key for the time period.  It is used to decrypt the
+/// service's descriptors, and only clients that know the service's
+/// identity key (that is, its onion address) can compute it.
+#[derive(Clone, Debug)]
+pub struct Subcredential([u8; 32]);
+
+impl Subcredential {
+    /// Compute the subcredential for the onion service with identity key
+    /// `identity`, during the time period for which its blinded key is
+    /// `blinded_id`.
+    pub fn new(identity: &ed25519::Ed25519Identity, blinded_id: &ed25519::Ed25519Identity) -> Self {
+        let mut credential = Sha3_256::new();
+        credential.update(b"credential");
+        credential.update(identity.as_bytes());
+        let credential = credential.finalize();
+
+        let mut subcredential = Sha3_256::new();
+        subcredential.update(b"subcredential");
+        subcredential.update(credential);
+        subcredential.update(blinded_id.as_bytes());
+        Subcredential(subcredential.finalize().into())
+    }
+}
+
+impl From<[u8; 32]> for Subcredential {
+    fn from(s: [u8; 32]) -> Self {
+        Subcredential(s)
+    }
+}
+
+impl AsRef<[u8]> for Subcredential {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+decl_keyword! {
+    HsOuterKwd {
+        "hs-descriptor" => HS_DESCRIPTOR,
+        "descriptor-lifetime" => DESCRIPTOR_LIFETIME,
+        "descriptor-signing-key-cert" => DESCRIPTOR_SIGNING_KEY_CERT,
+        "revision-counter" => REVISION_COUNTER,
+        "superencrypted" => SUPERENCRYPTED,
+        "signature" => SIGNATURE,
+    }
+}
+
+/// Rules about how keywords appear in the outer layer of an onion service
+/// descriptor.
+static HS_OUTER_RULES: Lazy<SectionRules<HsOuterKwd>> = Lazy::new(|| {
+    use HsOuterKwd::*;
+
+    let mut rules = SectionRules::new();
+    rules.add(HS_DESCRIPTOR.rule().required().args(1..));
+    rules.add(DESCRIPTOR_LIFETIME.rule().required().args(1..));
+    rules.add(
+        DESCRIPTOR_SIGNING_KEY_CERT
+            .rule()
+            .required()
+            .no_args()
+            .obj_required(),
+    );
+    rules.add(REVISION_COUNTER.rule().required().args(1..));
+    rules.add(SUPERENCRYPTED.rule().required().no_args().obj_required());
+    rules.add(SIGNATURE.rule().required().args(1..));
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules
+});
+
+/// The outer layer of an onion service descriptor.
+///
+/// This is the part of a descriptor that an HSDir can check without knowing
+/// which service it belongs to.  Use [`EncryptedHsDesc::decrypt`] to get
+/// at the [`HsDesc`] inside.
+#[derive(Clone, Debug)]
+pub struct EncryptedHsDesc {
+    /// How long this descriptor should be cached for.
+    lifetime: Duration,
+    /// The short-term key that signed this descriptor.
+    desc_signing_key: ed25519::PublicKey,
+    /// The blinded identity key that certified `desc_signing_key`.
+    blinded_id: ed25519::Ed25519Identity,
+    /// When the certificate for `desc_signing_key` expires.
+    signing_key_expiry: SystemTime,
+    /// A counter that increases every time the service publishes a new
+    /// descriptor for this blinded key.
+    revision_counter: u64,
+    /// The encrypted body of the descriptor.
+    superencrypted: Vec<u8>,
+}
+
+/// An onion service descriptor whose outer signature and validity time we
+/// haven't checked.
+pub struct UncheckedEncryptedHsDesc {
+    /// The actual unchecked descriptor.
+    desc: signed::SignatureGated<timed::TimerangeBound<EncryptedHsDesc>>,
+}
+
+impl tor_checkable::SelfSigned<timed::TimerangeBound<EncryptedHsDesc>>
+    for UncheckedEncryptedHsDesc
+{
+    type Error = signature::Error;
+
+    fn dangerously_assume_wellsigned(self) -> timed::TimerangeBound<EncryptedHsDesc> {
+        self.desc.dangerously_assume_wellsigned()
+    }
+    fn is_well_signed(&self) -> std::result::Result<(), Self::Error> {
+        self.desc.is_well_signed()
+    }
+}
+
+impl EncryptedHsDesc {
+    /// Parse the outer layer of an onion service descriptor from a string.
+    pub fn parse(s: &str) -> Result<UncheckedEncryptedHsDesc> {
+        let mut reader = NetDocReader::new(s);
+        let result = EncryptedHsDesc::take_from_reader(&mut reader).map_err(|e| e.within(s));
+        reader.should_be_exhausted()?;
+        result
+    }
+
+    /// Return how long this descriptor may be cached.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Return this descriptor's revision counter.
+    pub fn revision_counter(&self) -> u64 {
+        self.revision_counter
+    }
+
+    /// Return the blinded identity key that (indirectly) signed this
+    /// descriptor.
+    pub fn blinded_id(&self) -> &ed25519::Ed25519Identity {
+        &self.blinded_id
+    }
+
+    /// Return the short-term key that signed this descriptor.
+    pub fn desc_signing_key(&self) -> &ed25519::PublicKey {
+        &self.desc_signing_key
+    }
+
+    /// Decrypt the inner layers of this descriptor, using the onion
+    /// service's `subcredential` for this time period.
+    ///
+    /// If the service requires client authorization, `client_key` must be
+    /// a secret key for one of its authorized clients.
+    pub fn decrypt(
+        &self,
+        subcredential: &Subcredential,
+        client_key: Option<&curve25519::StaticSecret>,
+    ) -> Result<UncheckedHsDesc> {
+        let middle_text = HsDescEncryption {
+            blinded_id: &self.blinded_id,
+            desc_cookie: None,
+            subcredential,
+            revision: self.revision_counter,
+            string_const: SUPERENCRYPTED_CONST,
+        }
+        .decrypt(&self.superencrypted)?;
+        let middle_text = std::str::from_utf8(&middle_text[..])
+            .map_err(|_| EK::BadObjectVal.with_msg("superencrypted layer was not valid UTF-8"))?;
+        let middle_layer = middle::HsDescMiddle::parse(middle_text)?;
+
+        let desc_cookie =
+            client_key.and_then(|key| middle_layer.find_desc_cookie(subcredential, key));
+        let inner_text = HsDescEncryption {
+            blinded_id: &self.blinded_id,
+            desc_cookie: desc_cookie.as_ref(),
+            subcredential,
+            revision: self.revision_counter,
+            string_const: ENCRYPTED_CONST,
+        }
+        .decrypt(middle_layer.encrypted())
+        .map_err(|e| match desc_cookie {
+            None => EK::DecryptionFailed
+                .with_msg("couldn't decrypt inner layer: client authorization may be required"),
+            Some(_) => e,
+        })?;
+        let inner_text = std::str::from_utf8(&inner_text[..])
+            .map_err(|_| EK::BadObjectVal.with_msg("encrypted layer was not valid UTF-8"))?;
+
+        inner::parse_inner(inner_text, self)
+    }
+
+    /// Parse the outer layer of an onion service descriptor from a reader.
+    fn take_from_reader(
+        reader: &mut NetDocReader<'_, HsOuterKwd>,
+    ) -> Result<UncheckedEncryptedHsDesc> {
+        use HsOuterKwd::*;
+
+        let s = reader.str();
+        let body = HS_OUTER_RULES.parse(&mut reader.iter())?;
+
+        // Unwrap should be safe because `.parse()` would have already
+        // returned an Error if there were no items.
+        #[allow(clippy::unwrap_used)]
+        let first_item = body.first_item().unwrap();
+        if first_item.kwd() != HS_DESCRIPTOR {
+            return Err(EK::WrongStartingToken
+                .with_msg(first_item.kwd_str().to_string())
+                .at_pos(first_item.pos()));
+        }
+        #[allow(clippy::unwrap_used)]
+        let last_item = body.last_item().unwrap();
+        if last_item.kwd() != SIGNATURE {
+            return Err(EK::WrongEndingToken
+                .with_msg(last_item.kwd_str().to_string())
+                .at_pos(last_item.pos()));
+        }
+
+        let version = first_item.parse_arg::<u32>(0)?;
+        if version != 3 {
+            return Err(EK::BadDocumentVersion.with_msg(format!("unexpected version {}", version)));
+        }
+
+        let lifetime = {
+            let tok = body.required(DESCRIPTOR_LIFETIME)?;
+            let minutes: u16 = tok.parse_arg(0)?;
+            if !(MIN_LIFETIME_MINUTES..=MAX_LIFETIME_MINUTES).contains(&minutes) {
+                return Err(EK::BadArgument
+                    .at_pos(tok.arg_pos(0))
+                    .with_msg("descriptor lifetime out of range"));
+            }
+            Duration::from_secs(u64::from(minutes) * 60)
+        };
+
+        // The descriptor signing key, certified by the blinded key.
+        let (cert, desc_signing_key) = {
+            let cert_tok = body.required(DESCRIPTOR_SIGNING_KEY_CERT)?;
+            let cert: tor_cert::UncheckedCert = cert_tok
+                .parse_obj::<UnvalidatedEdCert>("ED25519 CERT")?
+                .check_cert_type(CertType::HS_BLINDED_ID_V_SIGNING)?
+                .into_unchecked()
+                .check_key(&None)?;
+            let sk = cert.peek_subject_key().as_ed25519().ok_or_else(|| {
+                EK::BadObjectVal
+                    .at_pos(cert_tok.pos())
+                    .with_msg("no ed25519 signing key")
+            })?;
+            let sk = *sk;
+            (cert, sk)
+        };
+        let blinded_id: ed25519::Ed25519Identity = cert.peek_signing_key().into();
+
+        let revision_counter = body.required(REVISION_COUNTER)?.parse_arg::<u64>(0)?;
+        let superencrypted = body.required(SUPERENCRYPTED)?.obj("MESSAGE")?;
+
+        // The signature covers everything up to the "signature" keyword.
+        let signature = {
+            // Unwraps are safe because these items came from `s`.
+            #[allow(clippy::unwrap_used)]
+            let start_offset = first_item.offset_in(s).unwrap();
+            #[allow(clippy::unwrap_used)]
+            let end_offset = last_item.offset_in(s).unwrap();
+            let mut signed = HSDESC_SIG_PREFIX.to_vec();
+            signed.extend_from_slice(&s.as_bytes()[start_offset..end_offset]);
+
+            let sig: B64 = last_item.parse_arg(0)?;
+            let sig = ed25519::Signature::from_bytes(sig.as_bytes())
+                .map_err(|_| EK::BadSignature.at_pos(last_item.pos()))?;
+            ed25519::ValidatableEd25519Signature::new(desc_signing_key, sig, &signed[..])
+        };
+
+        let (cert, cert_sig) = cert.dangerously_split()?;
+        let signing_key_expiry = cert.dangerously_assume_timely().expiry();
+
+        let desc = EncryptedHsDesc {
+            lifetime,
+            desc_signing_key,
+            blinded_id,
+            signing_key_expiry,
+            revision_counter,
+            superencrypted,
+        };
+        let signatures: Vec<Box<dyn pk::ValidatableSignature>> =
+            vec![Box::new(cert_sig), Box::new(signature)];
+        let timed = timed::TimerangeBound::new(desc, ..signing_key_expiry);
+        Ok(UncheckedEncryptedHsDesc {
+            desc: signed::SignatureGated::new(timed, signatures),
+        })
+    }
+}
+
+/// The decrypted contents of an onion service descriptor.
+#[derive(Clone, Debug)]
+pub struct HsDesc {
+    /// The blinded identity key for the service.
+    blinded_id: ed25519::Ed25519Identity,
+    /// The revision counter from the outer layer.
+    revision_counter: u64,
+    /// The CREATE2 handshake types that the service supports.
+    create2_formats: Vec<u16>,
+    /// True if the service requires clients to authenticate to its
+    /// introduction points.
+    intro_auth_required: bool,
+    /// True if the service is a "single onion service", which doesn't
+    /// try to hide its location.
+    is_single_onion_service: bool,
+    /// The service's introduction points.
+    intro_points: Vec<IntroPointDesc>,
+}
+
+/// Information about one introduction point, as listed in an onion service
+/// descriptor.
+#[derive(Clone, Debug)]
+pub struct IntroPointDesc {
+    /// How to reach the introduction point.
+    link_specifiers: Vec<LinkSpec>,
+    /// The introduction point's ntor onion key, for extending circuits to it.
+    ipt_ntor_key: curve25519::PublicKey,
+    /// The key that the service uses to authenticate itself to this
+    /// introduction point.
+    ipt_sid_key: ed25519::PublicKey,
+    /// The key that clients use to encrypt INTRODUCE2 messages to the
+    /// service via this introduction point.
+    svc_ntor_key: curve25519::PublicKey,
+}
+
+/// A decrypted onion service descriptor whose inner certificates and
+/// validity time we haven't checked.
+pub struct UncheckedHsDesc {
+    /// The actual unchecked descriptor.
+    desc: signed::SignatureGated<timed::TimerangeBound<HsDesc>>,
+}
+
+impl tor_checkable::SelfSigned<timed::TimerangeBound<HsDesc>> for UncheckedHsDesc {
+    type Error = signature::Error;
+
+    fn dangerously_assume_wellsigned(self) -> timed::TimerangeBound<HsDesc> {
+        self.desc.dangerously_assume_wellsigned()
+    }
+    fn is_well_signed(&self) -> std::result::Result<(), Self::Error> {
+        self.desc.is_well_signed()
+    }
+}
+
+impl HsDesc {
+    /// Return the blinded identity key for the service.
+    pub fn blinded_id(&self) -> &ed25519::Ed25519Identity {
+        &self.blinded_id
+    }
+
+    /// Return the revision counter for this descriptor.
+    pub fn revision_counter(&self) -> u64 {
+        self.revision_counter
+    }
+
+    /// Return the CREATE2 handshake types that the service supports.
+    pub fn create2_formats(&self) -> &[u16] {
+        &self.create2_formats
+    }
+
+    /// Return true if the service requires clients to authenticate to its
+    /// introduction points.
+    pub fn intro_auth_required(&self) -> bool {
+        self.intro_auth_required
+    }
+
+    /// Return true if the service is a single onion service.
+    pub fn is_single_onion_service(&self) -> bool {
+        self.is_single_onion_service
+    }
+
+    /// Return the introduction points for the service.
+    pub fn intro_points(&self) -> &[IntroPointDesc] {
+        &self.intro_points
+    }
+}
+
+impl IntroPointDesc {
+    /// Return the link specifiers for reaching this introduction point.
+    pub fn link_specifiers(&self) -> &[LinkSpec] {
+        &self.link_specifiers
+    }
+
+    /// Return the ntor onion key for this introduction point.
+    pub fn ipt_ntor_key(&self) -> &curve25519::PublicKey {
+        &self.ipt_ntor_key
+    }
+
+    /// Return the key that the service uses to authenticate to this
+    /// introduction point.
+    pub fn ipt_sid_key(&self) -> &ed25519::PublicKey {
+        &self.ipt_sid_key
+    }
+
+    /// Return the key that clients use to encrypt their introductions to
+    /// the service via this introduction point.
+    pub fn svc_ntor_key(&self) -> &curve25519::PublicKey {
+        &self.svc_ntor_key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use hex_literal::hex;
+    use std::net::IpAddr;
+    use tor_checkable::SelfSigned;
+
+    // These descriptors were made with an independent implementation of
+    // rend-spec-v3, using fixed keys.  The first has no client
+    // authorization; the second has client authorization for CLIENT_SK.
+    const TESTDATA: &str = include_str!("../../testdata/hsdesc1.txt");
+    const TESTDATA_AUTH: &str = include_str!("../../testdata/hsdesc2.txt");
+
+    const ID: [u8; 32] = hex!("8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c");
+    const BLINDED_ID: [u8; 32] =
+        hex!("8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394");
+    const SUBCREDENTIAL: [u8; 32] =
+        hex!("3f2132137755f8243e7fe64fcc66aed97a88c338ddb0082873cf4087c12d2bb2");
+    const DESC_SK: [u8; 32] =
+        hex!("ed4928c628d1c2c6eae90338905995612959273a5c63f93636c14614ac8737d1");
+    const CLIENT_SK: [u8; 32] = [0x70; 32];
+
+    /// A time when the test descriptors' certificates are valid.
+    fn valid_time() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn parse_outer(s: &str) -> EncryptedHsDesc {
+        EncryptedHsDesc::parse(s)
+            .unwrap()
+            .check_signature()
+            .unwrap()
+            .check_valid_at(&valid_time())
+            .unwrap()
+    }
+
+    fn check_inner(desc: UncheckedHsDesc) -> HsDesc {
+        desc.check_signature()
+            .unwrap()
+            .check_valid_at(&valid_time())
+            .unwrap()
+    }
+
+    #[test]
+    fn subcredential() {
+        let sc = Subcredential::new(&ID.into(), &BLINDED_ID.into());
+        assert_eq!(sc.as_ref(), &SUBCREDENTIAL[..]);
+    }
+
+    #[test]
+    fn parse_outer_layer() {
+        let desc = parse_outer(TESTDATA);
+        assert_eq!(desc.lifetime(), Duration::from_secs(180 * 60));
+        assert_eq!(desc.revision_counter(), 7);
+        assert_eq!(
+            desc.blinded_id(),
+            &ed25519::Ed25519Identity::from(BLINDED_ID)
+        );
+        assert_eq!(desc.desc_signing_key().as_bytes(), &DESC_SK);
+
+        // The certificate expires in 2030.
+        let late = SystemTime::UNIX_EPOCH + Duration::from_secs(1_900_000_000);
+        let unchecked = EncryptedHsDesc::parse(TESTDATA).unwrap();
+        assert!(unchecked
+            .check_signature()
+            .unwrap()
+            .check_valid_at(&late)
+            .is_err());
+    }
+
+    #[test]
+    fn decrypt_no_auth() {
+        let desc = parse_outer(TESTDATA);
+        let sc = Subcredential::from(SUBCREDENTIAL);
+        let inner = check_inner(desc.decrypt(&sc, None).unwrap());
+
+        assert_eq!(
+            inner.blinded_id(),
+            &ed25519::Ed25519Identity::from(BLINDED_ID)
+        );
+        assert_eq!(inner.revision_counter(), 7);
+        assert_eq!(inner.create2_formats(), &[2]);
+        assert!(!inner.intro_auth_required());
+        assert!(!inner.is_single_onion_service());
+        assert_eq!(inner.intro_points().len(), 3);
+
+        let ipt = &inner.intro_points()[1];
+        assert_eq!(
+            ipt.link_specifiers(),
+            &[
+                LinkSpec::OrPort(IpAddr::from([192, 0, 2, 11]), 9002),
+                LinkSpec::RsaId([0x12; 20].into()),
+                LinkSpec::Ed25519Id([0x52; 32].into()),
+            ]
+        );
+        assert_eq!(
+            ipt.ipt_sid_key().as_bytes(),
+            &hex!("884b8857f4eaa1613c61504db34d4beaf346517a0e31de3cddd4d9b4201d9d0b")
+        );
+        assert_eq!(
+            ipt.ipt_ntor_key().as_bytes(),
+            &hex!("04f5f29162c31a8defa18e6e742224ee806fc1718a278be859ba5620402b8f3a")
+        );
+        assert_eq!(
+            ipt.svc_ntor_key().as_bytes(),
+            &hex!("7a1a4e709bf085ac494aba0469b9b1eda0ab1f78b16aabb79ffeda90623e8522")
+        );
+
+        // A client key doesn't hurt, even if the service doesn't use
+        // client authorization.
+        let client_sk = curve25519::StaticSecret::from(CLIENT_SK);
+        assert!(desc.decrypt(&sc, Some(&client_sk)).is_ok());
+
+        // The wrong subcredential can't decrypt the descriptor.
+        let wrong_sc = Subcredential::from([0x99; 32]);
+        assert!(desc.decrypt(&wrong_sc, None).is_err());
+    }
+
+    #[test]
+    fn decrypt_client_auth() {
+        let desc = parse_outer(TESTDATA_AUTH);
+        assert_eq!(desc.revision_counter(), 42);
+        let sc = Subcredential::from(SUBCREDENTIAL);
+
+        // Without the right client key, we can't read the inner layer.
+        let err = desc.decrypt(&sc, None).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("client authorization may be required"));
+        let wrong_sk = curve25519::StaticSecret::from([0x71; 32]);
+        assert!(desc.decrypt(&sc, Some(&wrong_sk)).is_err());
+
+        let client_sk = curve25519::StaticSecret::from(CLIENT_SK);
+        let inner = check_inner(desc.decrypt(&sc, Some(&client_sk)).unwrap());
+        assert!(inner.intro_auth_required());
+        assert!(inner.is_single_onion_service());
+        assert_eq!(inner.intro_points().len(), 2);
+        assert_eq!(
+            inner.intro_points()[0].link_specifiers()[0],
+            LinkSpec::OrPort(IpAddr::from([192, 0, 2, 10]), 9001)
+        );
+    }
+
+    #[test]
+    fn parse_bad() {
+        // A modified descriptor no longer has a valid signature.
+        let modified = TESTDATA.replace("revision-counter 7", "revision-counter 8");
+        let unchecked = EncryptedHsDesc::parse(&modified).unwrap();
+        assert!(unchecked.is_well_signed().is_err());
+
+        let bad_version = TESTDATA.replace("hs-descriptor 3", "hs-descriptor 2");
+        assert!(EncryptedHsDesc::parse(&bad_version).is_err());
+
+        let bad_lifetime = TESTDATA.replace("descriptor-lifetime 180", "descriptor-lifetime 5");
+        assert!(EncryptedHsDesc::parse(&bad_lifetime).is_err());
+
+        // Truncating the encrypted blob breaks its MAC.
+        let desc = parse_outer(TESTDATA);
+        let mut truncated = desc.clone();
+        truncated.superencrypted.truncate(1000);
+        assert!(truncated
+            .decrypt(&Subcredential::from(SUBCREDENTIAL), None)
+            .is_err());
+    }
+}
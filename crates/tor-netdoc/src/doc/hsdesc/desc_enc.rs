@@ -0,0 +1,108 @@
+//! Encryption layers for onion service descriptors.
+//!
+//! Both encrypted layers of an onion service descriptor use the same
+//! construction, described in section 2.5.3 of rend-spec-v3: a
+//! SHAKE-256-derived AES-256-CTR key and IV, with a SHA3-256 MAC over the
+//! ciphertext.
+
+use super::Subcredential;
+use crate::{ParseErrorKind as EK, Result};
+
+use cipher::generic_array::GenericArray;
+use cipher::{NewCipher, StreamCipher};
+use digest::{Digest, ExtendableOutput, Update, XofReader};
+use subtle::ConstantTimeEq;
+use tor_llcrypto::cipher::aes::Aes256Ctr;
+use tor_llcrypto::d::{Sha3_256, Shake256};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+/// Length of the salt at the start of an encrypted layer.
+const SALT_LEN: usize = 16;
+/// Length of the MAC at the end of an encrypted layer.
+const MAC_LEN: usize = 32;
+/// Length of the AES-256 key.
+const S_KEY_LEN: usize = 32;
+/// Length of the AES-CTR IV.
+const S_IV_LEN: usize = 16;
+/// Length of the key we use for the MAC.
+const MAC_KEY_LEN: usize = 32;
+
+/// The string constant for the outer ("superencrypted") layer.
+pub(super) const SUPERENCRYPTED_CONST: &[u8] = b"hsdir-superencrypted-data";
+/// The string constant for the inner ("encrypted") layer.
+pub(super) const ENCRYPTED_CONST: &[u8] = b"hsdir-encrypted-data";
+
+/// Everything we need to decrypt one layer of an onion service descriptor.
+pub(super) struct HsDescEncryption<'a> {
+    /// The blinded public key of the onion service for this period.
+    pub(super) blinded_id: &'a Ed25519Identity,
+    /// The descriptor cookie, if this is the inner layer and client
+    /// authorization is in use.
+    pub(super) desc_cookie: Option<&'a [u8; 32]>,
+    /// The subcredential for the onion service for this period.
+    pub(super) subcredential: &'a Subcredential,
+    /// The revision counter of the descriptor.
+    pub(super) revision: u64,
+    /// The string constant that identifies which layer this is.
+    pub(super) string_const: &'a [u8],
+}
+
+impl<'a> HsDescEncryption<'a> {
+    /// Decrypt `data`, checking its MAC.
+    ///
+    /// Returns the plaintext, with any trailing NUL padding removed.
+    pub(super) fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < SALT_LEN + MAC_LEN {
+            return Err(EK::DecryptionFailed.with_msg("encrypted layer too short"));
+        }
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (ciphertext, mac) = rest.split_at(rest.len() - MAC_LEN);
+
+        let (key, iv, mac_key) = self.get_keys(salt);
+        let expected_mac = {
+            let mut d = Sha3_256::new();
+            Digest::update(&mut d, (MAC_KEY_LEN as u64).to_be_bytes());
+            Digest::update(&mut d, &mac_key[..]);
+            Digest::update(&mut d, (SALT_LEN as u64).to_be_bytes());
+            Digest::update(&mut d, salt);
+            Digest::update(&mut d, ciphertext);
+            d.finalize()
+        };
+        if !bool::from(expected_mac[..].ct_eq(mac)) {
+            return Err(EK::DecryptionFailed.with_msg("incorrect MAC"));
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        let mut cipher = Aes256Ctr::new(
+            GenericArray::from_slice(&key),
+            GenericArray::from_slice(&iv),
+        );
+        cipher.apply_keystream(&mut plaintext[..]);
+        while plaintext.last() == Some(&0) {
+            plaintext.pop();
+        }
+        Ok(plaintext)
+    }
+
+    /// Derive the key, IV, and MAC key for this layer, given its salt.
+    fn get_keys(&self, salt: &[u8]) -> ([u8; S_KEY_LEN], [u8; S_IV_LEN], [u8; MAC_KEY_LEN]) {
+        let mut kdf = Shake256::default();
+        kdf.update(self.blinded_id.as_bytes());
+        if let Some(cookie) = self.desc_cookie {
+            kdf.update(&cookie[..]);
+        }
+        kdf.update(self.subcredential.as_ref());
+        kdf.update(&self.revision.to_be_bytes());
+        kdf.update(salt);
+        kdf.update(self.string_const);
+        let mut r = kdf.finalize_xof();
+
+        let mut key = [0_u8; S_KEY_LEN];
+        let mut iv = [0_u8; S_IV_LEN];
+        let mut mac_key = [0_u8; MAC_KEY_LEN];
+        r.read(&mut key[..]);
+        r.read(&mut iv[..]);
+        r.read(&mut mac_key[..]);
+        (key, iv, mac_key)
+    }
+}
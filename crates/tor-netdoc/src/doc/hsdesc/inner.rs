@@ -0,0 +1,227 @@
+//! The "encrypted" (inner) layer of an onion service descriptor.
+//!
+//! This layer says which handshakes the service supports, and lists its
+//! introduction points.
+
+use super::{EncryptedHsDesc, HsDesc, IntroPointDesc, UncheckedHsDesc};
+use crate::parse::keyword::Keyword;
+use crate::parse::parser::{Section, SectionRules};
+use crate::parse::tokenize::{Item, ItemResult, NetDocReader};
+use crate::types::misc::{Curve25519Public, UnvalidatedEdCert, B64};
+use crate::{ParseErrorKind as EK, Result};
+
+use once_cell::sync::Lazy;
+use std::time::SystemTime;
+use tor_bytes::Reader;
+use tor_cert::CertType;
+use tor_checkable::{signed, timed, Timebound};
+use tor_linkspec::LinkSpec;
+use tor_llcrypto::pk::{self, curve25519};
+
+decl_keyword! {
+    HsInnerKwd {
+        "create2-formats" => CREATE2_FORMATS,
+        "intro-auth-required" => INTRO_AUTH_REQUIRED,
+        "single-onion-service" => SINGLE_ONION_SERVICE,
+        "introduction-point" => INTRODUCTION_POINT,
+        "onion-key" => ONION_KEY,
+        "auth-key" => AUTH_KEY,
+        "enc-key" => ENC_KEY,
+        "enc-key-cert" => ENC_KEY_CERT,
+        "legacy-key" => LEGACY_KEY,
+        "legacy-key-cert" => LEGACY_KEY_CERT,
+    }
+}
+
+/// Rules about how keywords appear in the header of the inner layer of an
+/// onion service descriptor.
+static HS_INNER_HEADER_RULES: Lazy<SectionRules<HsInnerKwd>> = Lazy::new(|| {
+    use HsInnerKwd::*;
+
+    let mut rules = SectionRules::new();
+    rules.add(CREATE2_FORMATS.rule().required().args(1..));
+    rules.add(INTRO_AUTH_REQUIRED.rule().args(1..));
+    rules.add(SINGLE_ONION_SERVICE.rule());
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules
+});
+
+/// Rules about how keywords appear in each introduction point section of
+/// an onion service descriptor.
+static HS_INNER_INTRO_RULES: Lazy<SectionRules<HsInnerKwd>> = Lazy::new(|| {
+    use HsInnerKwd::*;
+
+    let mut rules = SectionRules::new();
+    rules.add(INTRODUCTION_POINT.rule().required().args(1..));
+    rules.add(ONION_KEY.rule().required().may_repeat().args(2..));
+    rules.add(AUTH_KEY.rule().required().no_args().obj_required());
+    rules.add(ENC_KEY.rule().required().may_repeat().args(2..));
+    rules.add(ENC_KEY_CERT.rule().required().no_args().obj_required());
+    rules.add(LEGACY_KEY.rule().no_args().obj_required());
+    rules.add(LEGACY_KEY_CERT.rule().no_args().obj_required());
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules
+});
+
+/// Parse the decrypted inner layer `s` of the descriptor whose outer layer
+/// is `outer`.
+pub(super) fn parse_inner(s: &str, outer: &EncryptedHsDesc) -> Result<UncheckedHsDesc> {
+    let mut reader = NetDocReader::new(s);
+    let result = take_inner(&mut reader, outer).map_err(|e| e.within(s))?;
+    reader.should_be_exhausted().map_err(|e| e.within(s))?;
+    Ok(result)
+}
+
+/// Extract the inner layer of a descriptor from a reader.
+fn take_inner(
+    reader: &mut NetDocReader<'_, HsInnerKwd>,
+    outer: &EncryptedHsDesc,
+) -> Result<UncheckedHsDesc> {
+    use HsInnerKwd::*;
+
+    let header = {
+        let mut items = reader.pause_at(|item| item.is_ok_with_kwd(INTRODUCTION_POINT));
+        HS_INNER_HEADER_RULES.parse(&mut items)?
+    };
+
+    let create2_formats = header
+        .required(CREATE2_FORMATS)?
+        .args()
+        .map(|a| a.parse::<u16>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let intro_auth_required = match header.get(INTRO_AUTH_REQUIRED) {
+        None => false,
+        Some(tok) => {
+            if !tok.args().any(|a| a == "ed25519") {
+                return Err(EK::BadArgument
+                    .at_pos(tok.pos())
+                    .with_msg("no supported intro-auth-required type"));
+            }
+            true
+        }
+    };
+    let is_single_onion_service = header.get(SINGLE_ONION_SERVICE).is_some();
+
+    // The signatures on all the certificates, and when the first of them
+    // expires.
+    let mut signatures: Vec<Box<dyn pk::ValidatableSignature>> = Vec::new();
+    let mut expiry = outer.signing_key_expiry;
+
+    let mut intro_points = Vec::new();
+    while !reader.is_exhausted() {
+        let mut first = true;
+        let mut items = reader.pause_at(|item| {
+            let at_next = item.is_ok_with_kwd(INTRODUCTION_POINT) && !first;
+            first = false;
+            at_next
+        });
+        let section = HS_INNER_INTRO_RULES.parse(&mut items)?;
+        let ipt = take_intro_point(&section, outer, &mut signatures, &mut expiry)?;
+        intro_points.push(ipt);
+    }
+
+    let desc = HsDesc {
+        blinded_id: outer.blinded_id,
+        revision_counter: outer.revision_counter,
+        create2_formats,
+        intro_auth_required,
+        is_single_onion_service,
+        intro_points,
+    };
+    let timed = timed::TimerangeBound::new(desc, ..expiry);
+    Ok(UncheckedHsDesc {
+        desc: signed::SignatureGated::new(timed, signatures),
+    })
+}
+
+/// Build an [`IntroPointDesc`] from a parsed introduction point section.
+///
+/// Adds the signatures of its certificates to `signatures`, and lowers
+/// `expiry` if any of them expires before it.
+fn take_intro_point(
+    section: &Section<'_, HsInnerKwd>,
+    outer: &EncryptedHsDesc,
+    signatures: &mut Vec<Box<dyn pk::ValidatableSignature>>,
+    expiry: &mut SystemTime,
+) -> Result<IntroPointDesc> {
+    use HsInnerKwd::*;
+
+    let signing_key = Some(outer.desc_signing_key);
+
+    let intro_tok = section.required(INTRODUCTION_POINT)?;
+    let link_specifiers = {
+        let ls: B64 = intro_tok.parse_arg(0)?;
+        let mut r = Reader::from_slice(ls.as_bytes());
+        let n = r.take_u8()?;
+        let link_specifiers = (0..n)
+            .map(|_| r.extract::<LinkSpec>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        r.should_be_exhausted()?;
+        link_specifiers
+    };
+
+    let ipt_ntor_key = ntor_key(section.slice(ONION_KEY))?.ok_or_else(|| {
+        EK::MissingToken
+            .at_pos(intro_tok.pos())
+            .with_msg("no ntor onion-key")
+    })?;
+
+    let auth_tok = section.required(AUTH_KEY)?;
+    let auth_cert = auth_tok
+        .parse_obj::<UnvalidatedEdCert>("ED25519 CERT")?
+        .check_cert_type(CertType::HS_IP_V_SIGNING)?
+        .into_unchecked()
+        .check_key(&signing_key)?;
+    let ipt_sid_key = *auth_cert.peek_subject_key().as_ed25519().ok_or_else(|| {
+        EK::BadObjectVal
+            .at_pos(auth_tok.pos())
+            .with_msg("no ed25519 key in auth-key certificate")
+    })?;
+
+    let svc_ntor_key = ntor_key(section.slice(ENC_KEY))?.ok_or_else(|| {
+        EK::MissingToken
+            .at_pos(intro_tok.pos())
+            .with_msg("no ntor enc-key")
+    })?;
+
+    // Like C tor, we don't check the subject of this certificate: it
+    // contains the enc-key converted to ed25519, with an unknown sign bit.
+    let enc_cert = section
+        .required(ENC_KEY_CERT)?
+        .parse_obj::<UnvalidatedEdCert>("ED25519 CERT")?
+        .check_cert_type(CertType::HS_IP_CC_SIGNING)?
+        .into_unchecked()
+        .check_key(&signing_key)?;
+
+    let mut add_cert = |cert: tor_cert::UncheckedCert| -> Result<()> {
+        let (cert, sig) = cert.dangerously_split()?;
+        let cert_expiry = cert.dangerously_assume_timely().expiry();
+        if cert_expiry < *expiry {
+            *expiry = cert_expiry;
+        }
+        signatures.push(Box::new(sig));
+        Ok(())
+    };
+    add_cert(auth_cert)?;
+    add_cert(enc_cert)?;
+
+    Ok(IntroPointDesc {
+        link_specifiers,
+        ipt_ntor_key,
+        ipt_sid_key,
+        svc_ntor_key,
+    })
+}
+
+/// Return the first `ntor` key from a list of `onion-key` or `enc-key`
+/// items, if there is one.
+fn ntor_key(items: &[Item<'_, HsInnerKwd>]) -> Result<Option<curve25519::PublicKey>> {
+    for item in items {
+        if item.arg(0) == Some("ntor") {
+            let key: Curve25519Public = item.parse_arg(1)?;
+            return Ok(Some(key.into()));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,172 @@
+//! The "superencrypted" (middle) layer of an onion service descriptor.
+//!
+//! This layer lists the clients who are authorized to read the inner
+//! layer, each with an encrypted copy of the "descriptor cookie" that is
+//! needed to decrypt it.  When a service doesn't use client authorization,
+//! the entries here are all random.
+
+use super::Subcredential;
+use crate::parse::keyword::Keyword;
+use crate::parse::parser::SectionRules;
+use crate::parse::tokenize::{Item, NetDocReader};
+use crate::types::misc::{Curve25519Public, B64};
+use crate::{ParseErrorKind as EK, Result};
+
+use cipher::generic_array::GenericArray;
+use cipher::{NewCipher, StreamCipher};
+use digest::{ExtendableOutput, Update, XofReader};
+use once_cell::sync::Lazy;
+use std::convert::TryInto;
+use subtle::ConstantTimeEq;
+use tor_llcrypto::cipher::aes::Aes256Ctr;
+use tor_llcrypto::d::Shake256;
+use tor_llcrypto::pk::curve25519;
+
+/// Length of the client identifier in an `auth-client` entry.
+const CLIENT_ID_LEN: usize = 8;
+/// Length of the IV in an `auth-client` entry.
+const COOKIE_IV_LEN: usize = 16;
+/// Length of the (encrypted) descriptor cookie in an `auth-client` entry.
+const COOKIE_LEN: usize = 32;
+
+decl_keyword! {
+    HsMiddleKwd {
+        "desc-auth-type" => DESC_AUTH_TYPE,
+        "desc-auth-ephemeral-key" => DESC_AUTH_EPHEMERAL_KEY,
+        "auth-client" => AUTH_CLIENT,
+        "encrypted" => ENCRYPTED,
+    }
+}
+
+/// Rules about how keywords appear in the middle layer of an onion service
+/// descriptor.
+static HS_MIDDLE_RULES: Lazy<SectionRules<HsMiddleKwd>> = Lazy::new(|| {
+    use HsMiddleKwd::*;
+
+    let mut rules = SectionRules::new();
+    rules.add(DESC_AUTH_TYPE.rule().required().args(1..));
+    rules.add(DESC_AUTH_EPHEMERAL_KEY.rule().required().args(1..));
+    rules.add(AUTH_CLIENT.rule().required().may_repeat().args(3..));
+    rules.add(ENCRYPTED.rule().required().no_args().obj_required());
+    rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules
+});
+
+/// One `auth-client` entry: an encrypted descriptor cookie for a single
+/// (possibly fake) client.
+struct AuthClient {
+    /// An identifier that the client can derive, so it can find its entry.
+    client_id: [u8; CLIENT_ID_LEN],
+    /// The IV used to encrypt the cookie.
+    iv: [u8; COOKIE_IV_LEN],
+    /// The encrypted descriptor cookie.
+    encrypted_cookie: [u8; COOKIE_LEN],
+}
+
+/// The decrypted middle layer of an onion service descriptor.
+pub(super) struct HsDescMiddle {
+    /// The service's ephemeral key for client authorization.
+    ephemeral_key: curve25519::PublicKey,
+    /// The list of `auth-client` entries.
+    auth_clients: Vec<AuthClient>,
+    /// The encrypted inner layer.
+    encrypted: Vec<u8>,
+}
+
+impl HsDescMiddle {
+    /// Parse the middle layer of a descriptor from a decrypted string.
+    pub(super) fn parse(s: &str) -> Result<Self> {
+        let mut reader = NetDocReader::new(s);
+        let result = Self::take_from_reader(&mut reader).map_err(|e| e.within(s))?;
+        reader.should_be_exhausted().map_err(|e| e.within(s))?;
+        Ok(result)
+    }
+
+    /// Return the encrypted inner layer.
+    pub(super) fn encrypted(&self) -> &[u8] {
+        &self.encrypted[..]
+    }
+
+    /// Try to find the descriptor cookie for the client whose
+    /// authorization key is `client_key`.
+    ///
+    /// Returns None if there is no entry for this client.
+    pub(super) fn find_desc_cookie(
+        &self,
+        subcredential: &Subcredential,
+        client_key: &curve25519::StaticSecret,
+    ) -> Option<[u8; COOKIE_LEN]> {
+        let secret_seed = client_key.diffie_hellman(&self.ephemeral_key);
+        let mut kdf = Shake256::default();
+        kdf.update(subcredential.as_ref());
+        kdf.update(secret_seed.as_bytes());
+        let mut r = kdf.finalize_xof();
+        let mut client_id = [0_u8; CLIENT_ID_LEN];
+        let mut cookie_key = [0_u8; 32];
+        r.read(&mut client_id[..]);
+        r.read(&mut cookie_key[..]);
+
+        let entry = self
+            .auth_clients
+            .iter()
+            .find(|ent| bool::from(ent.client_id.ct_eq(&client_id)))?;
+        let mut cookie = entry.encrypted_cookie;
+        let mut cipher = Aes256Ctr::new(
+            GenericArray::from_slice(&cookie_key),
+            GenericArray::from_slice(&entry.iv),
+        );
+        cipher.apply_keystream(&mut cookie[..]);
+        Some(cookie)
+    }
+
+    /// Extract the middle layer from a reader.
+    fn take_from_reader(reader: &mut NetDocReader<'_, HsMiddleKwd>) -> Result<Self> {
+        use HsMiddleKwd::*;
+
+        let body = HS_MIDDLE_RULES.parse(&mut reader.iter())?;
+
+        {
+            let tok = body.required(DESC_AUTH_TYPE)?;
+            if tok.arg(0) != Some("x25519") {
+                return Err(EK::BadArgument
+                    .at_pos(tok.arg_pos(0))
+                    .with_msg("unsupported client authorization type"));
+            }
+        }
+
+        let ephemeral_key: Curve25519Public =
+            body.required(DESC_AUTH_EPHEMERAL_KEY)?.parse_arg(0)?;
+
+        let auth_clients = body
+            .slice(AUTH_CLIENT)
+            .iter()
+            .map(|tok| {
+                /// Parse a fixed-length base64 argument from `tok`.
+                fn fixed_arg<const N: usize>(
+                    tok: &Item<'_, HsMiddleKwd>,
+                    idx: usize,
+                ) -> Result<[u8; N]> {
+                    let b: B64 = tok.parse_arg(idx)?;
+                    b.as_bytes().try_into().map_err(|_| {
+                        EK::BadArgument
+                            .at_pos(tok.arg_pos(idx))
+                            .with_msg("wrong length in auth-client")
+                    })
+                }
+                Ok(AuthClient {
+                    client_id: fixed_arg(tok, 0)?,
+                    iv: fixed_arg(tok, 1)?,
+                    encrypted_cookie: fixed_arg(tok, 2)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let encrypted = body.required(ENCRYPTED)?.obj("MESSAGE")?;
+
+        Ok(HsDescMiddle {
+            ephemeral_key: ephemeral_key.into(),
+            auth_clients,
+            encrypted,
+        })
+    }
+}
@@ -458,6 +458,49 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn parse_family_and_unknown() -> Result<()> {
+        // A family line of the kind that relays really publish, mixing
+        // nicknames with fingerprints in several forms, plus a line we
+        // don't recognize.
+        let family = "family Unnamed $0BC8BA32CC3CB0F598E0C92778F7C0946DFBCE91 \
+                      c19b33758b3a5144894233ec4c95d7985b9fd101 \
+                      $195712e96fd1c1b18d14d09e9e4e7a6416e23b2c=relay1 \
+                      $A60697FF383EEEE2E88505DD4E305C07BF326B11~relay2 myRelay3\n\
+                      future-keyword with some arguments\n";
+        let text: String = TESTDATA
+            .lines()
+            .map(|line| {
+                if line.starts_with("family ") {
+                    family.to_string()
+                } else {
+                    format!("{}\n", line)
+                }
+            })
+            .collect();
+        let md = Microdesc::parse(&text)?;
+
+        let members: Vec<_> = md.family().members().cloned().collect();
+        assert_eq!(
+            members,
+            vec![
+                hex!("0BC8BA32CC3CB0F598E0C92778F7C0946DFBCE91").into(),
+                hex!("195712E96FD1C1B18D14D09E9E4E7A6416E23B2C").into(),
+                hex!("A60697FF383EEEE2E88505DD4E305C07BF326B11").into(),
+                hex!("C19B33758B3A5144894233EC4C95D7985B9FD101").into(),
+            ]
+        );
+        let nicknames: Vec<_> = md.family().nicknames().collect();
+        assert_eq!(nicknames, vec!["Unnamed", "myRelay3"]);
+        assert_eq!(md.family().n_dropped(), 2);
+
+        assert!(md.ipv6_policy().allows_port(443));
+        let orig = Microdesc::parse(TESTDATA)?;
+        assert_eq!(md.ed25519_id(), orig.ed25519_id());
+        assert_ne!(md.digest(), orig.digest());
+        Ok(())
+    }
+
     #[test]
     fn parse_multi() -> Result<()> {
         use std::time::{Duration, SystemTime};
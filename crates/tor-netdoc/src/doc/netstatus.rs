@@ -29,9 +29,8 @@
 //! all historical consensus-methods.
 //!
 //! NOTE: This module _does_ parse some fields that are not in current
-//! use, like relay nicknames, and the "published" times on
-//! microdescriptors. We should probably decide whether we actually
-//! want to do this.
+//! use, like relay nicknames. We should probably decide whether we
+//! actually want to do this.
 //!
 //! TODO: This module doesn't implement vote parsing at all yet.
 //!
@@ -1650,6 +1649,10 @@ mod test {
             r0.rsa_identity().as_bytes(),
             &hex!("0a3057af2910415794d8ea430309d9ac5f5d524b")
         );
+        assert_eq!(
+            r0.published(),
+            Some(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1596804041))
+        );
         assert!(!r0.weight().is_measured());
         assert!(!r0.weight().is_nonzero());
         let pv = &r0.protovers();
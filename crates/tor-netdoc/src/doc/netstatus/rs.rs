@@ -34,6 +34,9 @@ struct GenericRouterStatus<D> {
     identity: RsaIdentity,
     /// A list of address:port values where this relay can be reached.
     addrs: Vec<net::SocketAddr>,
+    /// Time at which the authorities saw this relay publish the descriptor
+    /// that this routerstatus is based on, if we know it.
+    published: Option<time::SystemTime>,
     /// Declared OR port for this relay.
     #[allow(dead_code)] // This value is never used; we look at addrs instead.
     or_port: u16,
@@ -80,6 +83,17 @@ macro_rules! implement_accessors {
             pub fn flags(&self) -> &RelayFlags {
                 &self.rs.flags
             }
+            /// Return the time when this relay published the descriptor that
+            /// this routerstatus is based on.
+            ///
+            /// A relay rotates its onion key from time to time, so a relay
+            /// whose descriptor is very old is more likely to have directory
+            /// information we can't build circuits with.
+            ///
+            /// Return None if we don't know when the relay published it.
+            pub fn published(&self) -> Option<std::time::SystemTime> {
+                self.rs.published
+            }
             /// Return the version of this routerstatus.
             pub fn version(&self) -> &Option<String> {
                 &self.rs.version
@@ -105,6 +119,14 @@ macro_rules! implement_accessors {
             pub fn is_flagged_guard(&self) -> bool {
                 self.rs.flags.contains(RelayFlags::GUARD)
             }
+            /// Return true if this routerstatus is listed with the Stable flag.
+            pub fn is_flagged_stable(&self) -> bool {
+                self.rs.flags.contains(RelayFlags::STABLE)
+            }
+            /// Return true if this routerstatus is listed with the Fast flag.
+            pub fn is_flagged_fast(&self) -> bool {
+                self.rs.flags.contains(RelayFlags::FAST)
+            }
         }
     };
 }
@@ -142,9 +164,7 @@ where
                 .with_msg("Wrong identity length")
         })?;
         let skip = if microdesc_format { 0 } else { 1 };
-        // We check that the published time is well-formed, but we never use it
-        // for anything in a consensus document.
-        let _ignore_published: time::SystemTime = {
+        let published: time::SystemTime = {
             // TODO: It's annoying to have to do this allocation, since we
             // already have a slice that contains both of these arguments.
             // Instead, we could get a slice of arguments: we'd have to add
@@ -205,6 +225,7 @@ where
             nickname,
             identity,
             addrs,
+            published: Some(published),
             or_port,
             doc_digest,
             flags,
@@ -8,6 +8,7 @@ use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_protover::Protocols;
 
 use std::net::SocketAddr;
+use std::time::SystemTime;
 
 #[cfg(feature = "ns_consensus")]
 use super::NsConsensusRouterStatus;
@@ -24,6 +25,8 @@ pub struct RouterStatusBuilder<D> {
     identity: Option<RsaIdentity>,
     /// See [`GenericRouterStatus::addrs`].
     addrs: Vec<SocketAddr>,
+    /// See [`GenericRouterStatus::published`].
+    published: Option<SystemTime>,
     /// See [`GenericRouterStatus::doc_digest`].
     doc_digest: Option<D>,
     /// See [`GenericRouterStatus::flags`].
@@ -43,6 +46,7 @@ impl<D: Clone> RouterStatusBuilder<D> {
             nickname: None,
             identity: None,
             addrs: Vec::new(),
+            published: None,
             doc_digest: None,
             flags: RelayFlags::empty(),
             version: None,
@@ -75,6 +79,14 @@ impl<D: Clone> RouterStatusBuilder<D> {
         self.addrs.push(addr);
         self
     }
+    /// Set the time at which the relay published the descriptor that this
+    /// routerstatus is based on.
+    ///
+    /// This value defaults to the start of the Unix epoch.
+    pub fn published(&mut self, published: SystemTime) -> &mut Self {
+        self.published = Some(published);
+        self
+    }
     /// Set the document digest for this routerstatus.
     ///
     /// This value is required.
@@ -141,6 +153,7 @@ impl<D: Clone> RouterStatusBuilder<D> {
             nickname,
             identity,
             addrs: self.addrs.clone(),
+            published: self.published,
             or_port,
             doc_digest,
             version: self.version.clone(),
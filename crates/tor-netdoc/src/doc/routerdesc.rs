@@ -24,10 +24,6 @@
 //! descriptions, parsed keys, and things like that.  We will probably want to
 //! de-duplicate those.
 //!
-//! TODO: There should be accessor functions for some or all of the
-//! fields in RouterDesc.  I'm deferring those until I know what they
-//! should be.
-//!
 //! # Availability
 //!
 //! Most of this module is only available when this crate is built with the
@@ -57,7 +53,6 @@ use signature::Signature;
 pub type RdDigest = [u8; 20];
 
 /// A router descriptor, with possible annotations.
-#[allow(dead_code)]
 pub struct AnnotatedRouterDesc {
     /// Annotation for this router descriptor; possibly empty.
     ann: RouterAnnotation,
@@ -66,7 +61,6 @@ pub struct AnnotatedRouterDesc {
 }
 
 /// Annotations about a router descriptor, as stored on disc.
-#[allow(dead_code)] // don't warn about fields not getting read.
 #[derive(Default)]
 pub struct RouterAnnotation {
     /// Description of where we got this router descriptor
@@ -77,6 +71,45 @@ pub struct RouterAnnotation {
     purpose: Option<String>,
 }
 
+impl AnnotatedRouterDesc {
+    /// Return the annotations for this router descriptor.
+    pub fn ann(&self) -> &RouterAnnotation {
+        &self.ann
+    }
+
+    /// Return a reference to the (unchecked) router descriptor within this
+    /// annotated router descriptor.
+    pub fn router(&self) -> &UncheckedRouterDesc {
+        &self.router
+    }
+
+    /// Consume this annotated router descriptor and discard its
+    /// annotations.
+    pub fn into_router(self) -> UncheckedRouterDesc {
+        self.router
+    }
+}
+
+impl RouterAnnotation {
+    /// Return a description of where we got this router descriptor, if
+    /// we recorded one.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// Return the time when this router descriptor was first downloaded,
+    /// if we recorded it.
+    pub fn downloaded(&self) -> Option<time::SystemTime> {
+        self.downloaded
+    }
+
+    /// Return a description of what we're willing to use this router
+    /// descriptor for, if we recorded one.
+    pub fn purpose(&self) -> Option<&str> {
+        self.purpose.as_deref()
+    }
+}
+
 /// Information about a relay, parsed from a router descriptor.
 ///
 /// This type does not hold all the information in the router descriptor
@@ -90,7 +123,6 @@ pub struct RouterAnnotation {
 ///
 /// Before using this type to connect to a relay, you MUST check that
 /// it is valid, using is_expired_at().
-#[allow(dead_code)] // don't warn about fields not getting read.
 pub struct RouterDesc {
     /// Human-readable nickname for this relay.
     ///
@@ -112,6 +144,12 @@ pub struct RouterDesc {
     uptime: Option<u64>,
     /// Time when this router descriptor was published.
     published: time::SystemTime,
+    /// Average, burst, and observed bandwidth for this relay, in bytes
+    /// per second.
+    bandwidth: (u64, u64, u64),
+    /// Ed25519 identity for this relay, taken from its identity
+    /// certificate.
+    ed_identity: ll::pk::ed25519::Ed25519Identity,
     /// Ed25519 identity certificate (identity key authenticating a
     /// signing key)
     identity_cert: tor_cert::Ed25519Cert,
@@ -268,9 +306,9 @@ static ROUTER_BODY_RULES: Lazy<SectionRules<RouterKwd>> = Lazy::new(|| {
     rules.add(TUNNELLED_DIR_SERVER.rule());
     rules.add(PROTO.rule().required().args(1..));
     rules.add(UNRECOGNIZED.rule().may_repeat().obj_optional());
+    rules.add(BANDWIDTH.rule().required().args(3..));
     // TODO: these aren't parsed yet.  Only authorities use them.
     {
-        rules.add(BANDWIDTH.rule().required().args(3..));
         rules.add(BRIDGE_DISTRIBUTION_REQUEST.rule().args(1..));
         rules.add(HIBERNATING.rule().args(1..));
         rules.add(CONTACT.rule());
@@ -330,6 +368,128 @@ const ROUTER_EXPIRY_SECONDS: u64 = 5 * 86400;
 const ROUTER_PRE_VALIDITY_SECONDS: u64 = 86400;
 
 impl RouterDesc {
+    /// Return the nickname for this relay.
+    ///
+    /// This is not secure, and not guaranteed to be unique.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    /// Return the IPv4 address for this relay, if it has one.
+    pub fn ipv4_addr(&self) -> Option<net::Ipv4Addr> {
+        self.ipv4addr
+    }
+
+    /// Return the IPv4 ORPort for this relay.
+    pub fn or_port(&self) -> u16 {
+        self.orport
+    }
+
+    /// Return the IPv6 address and ORPort for this relay, if it has one.
+    pub fn ipv6_addr(&self) -> Option<(net::Ipv6Addr, u16)> {
+        self.ipv6addr
+    }
+
+    /// Return the directory port for this relay, or 0 if it has none.
+    pub fn dir_port(&self) -> u16 {
+        self.dirport
+    }
+
+    /// Return this relay's declared uptime, in seconds.
+    pub fn uptime(&self) -> Option<u64> {
+        self.uptime
+    }
+
+    /// Return the time when this router descriptor was published.
+    pub fn published(&self) -> time::SystemTime {
+        self.published
+    }
+
+    /// Return this relay's average bandwidth, in bytes per second.
+    pub fn bandwidth_avg(&self) -> u64 {
+        self.bandwidth.0
+    }
+
+    /// Return this relay's burst bandwidth, in bytes per second.
+    pub fn bandwidth_burst(&self) -> u64 {
+        self.bandwidth.1
+    }
+
+    /// Return this relay's observed bandwidth, in bytes per second.
+    pub fn bandwidth_observed(&self) -> u64 {
+        self.bandwidth.2
+    }
+
+    /// Return the Ed25519 identity for this relay.
+    pub fn ed_identity(&self) -> &ll::pk::ed25519::Ed25519Identity {
+        &self.ed_identity
+    }
+
+    /// Return the certificate binding this relay's Ed25519 identity to its
+    /// signing key.
+    pub fn identity_cert(&self) -> &tor_cert::Ed25519Cert {
+        &self.identity_cert
+    }
+
+    /// Return the legacy RSA identity key for this relay.
+    pub fn rsa_identity_key(&self) -> &ll::pk::rsa::PublicKey {
+        &self.rsa_identity
+    }
+
+    /// Return the legacy RSA identity for this relay.
+    pub fn rsa_identity(&self) -> RsaIdentity {
+        self.rsa_identity.to_rsa_identity()
+    }
+
+    /// Return the key for extending a circuit to this relay using the ntor
+    /// protocol.
+    pub fn ntor_onion_key(&self) -> &ll::pk::curve25519::PublicKey {
+        &self.ntor_onion_key
+    }
+
+    /// Return the key for extending a circuit to this relay using the
+    /// (deprecated) TAP protocol.
+    pub fn tap_onion_key(&self) -> &ll::pk::rsa::PublicKey {
+        &self.tap_onion_key
+    }
+
+    /// Return the subprotocol versions supported by this relay.
+    pub fn protocols(&self) -> &tor_protover::Protocols {
+        &self.proto
+    }
+
+    /// Return true if this relay says it's a directory cache.
+    pub fn is_dir_cache(&self) -> bool {
+        self.is_dircache
+    }
+
+    /// Return true if this relay says that it caches extrainfo documents.
+    pub fn is_extrainfo_cache(&self) -> bool {
+        self.is_extrainfo_cache
+    }
+
+    /// Return the family that this relay declares, if any.
+    pub fn family(&self) -> Option<&RelayFamily> {
+        self.family.as_ref()
+    }
+
+    /// Return the software that this relay says it's running, if it says.
+    pub fn platform(&self) -> Option<&RelayPlatform> {
+        self.platform.as_ref()
+    }
+
+    /// Return this relay's policy for which IPv4 addresses and ports it is
+    /// willing to connect to.
+    pub fn ipv4_policy(&self) -> &AddrPolicy {
+        &self.ipv4_policy
+    }
+
+    /// Return a summary of which ports this relay is willing to connect to
+    /// on IPv6.
+    pub fn ipv6_policy(&self) -> &Arc<PortPolicy> {
+        &self.ipv6_policy
+    }
+
     /// Helper: tokenize `s`, and divide it into three validated sections.
     fn parse_sections<'a>(
         reader: &mut NetDocReader<'a, RouterKwd>,
@@ -492,6 +652,12 @@ impl RouterDesc {
         // uptime
         let uptime = body.maybe(UPTIME).parse_arg(0)?;
 
+        // bandwidth avg burst observed
+        let bandwidth = {
+            let bw = body.required(BANDWIDTH)?;
+            (bw.parse_arg(0)?, bw.parse_arg(1)?, bw.parse_arg(2)?)
+        };
+
         // published time.
         let published = body
             .required(PUBLISHED)?
@@ -614,11 +780,11 @@ impl RouterDesc {
                 .args_as_str()
                 .parse()
                 .map_err(|e| EK::BadPolicy.at_pos(p.pos()).with_source(e))?,
-            // Unwrap is safe here because str is not empty
-            #[allow(clippy::unwrap_used)]
-            None => "reject 1-65535".parse::<PortPolicy>().unwrap(),
+            None => PortPolicy::new_reject_all(),
         };
 
+        let ed_identity: ll::pk::ed25519::Ed25519Identity = identity_cert.peek_signing_key().into();
+
         // Now we're going to collect signatures and expiration times.
         let (identity_cert, identity_sig) = identity_cert.dangerously_split()?;
         let (crosscert_cert, cc_sig) = crosscert_cert.dangerously_split()?;
@@ -651,6 +817,8 @@ impl RouterDesc {
             dirport,
             uptime,
             published,
+            bandwidth,
+            ed_identity,
             identity_cert,
             rsa_identity,
             ntor_onion_key,
@@ -806,6 +974,62 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn accessors() -> Result<()> {
+        use std::time::{Duration, SystemTime};
+        use tor_checkable::{SelfSigned, Timebound};
+        let rd = RouterDesc::parse(TESTDATA)?
+            .check_signature()?
+            .dangerously_assume_timely();
+
+        assert_eq!(rd.nickname(), "idun2");
+        assert_eq!(rd.ipv4_addr(), Some("51.68.172.83".parse().unwrap()));
+        assert_eq!(rd.or_port(), 9001);
+        assert_eq!(rd.ipv6_addr(), None);
+        assert_eq!(rd.dir_port(), 0);
+        assert!(!rd.is_dir_cache());
+        assert!(!rd.is_extrainfo_cache());
+        assert_eq!(rd.uptime(), Some(1828391));
+        assert_eq!(
+            rd.published(),
+            SystemTime::UNIX_EPOCH + Duration::new(1584559096, 0)
+        );
+        assert_eq!(rd.bandwidth_avg(), 10485760);
+        assert_eq!(rd.bandwidth_burst(), 10485760);
+        assert_eq!(rd.bandwidth_observed(), 9974201);
+        assert_eq!(
+            rd.rsa_identity().as_bytes(),
+            &hex_literal::hex!("EB6EFB27F29AC9511A4246D7ABE1AFABFB416FF1")
+        );
+        let ed_identity: ll::pk::ed25519::Ed25519Identity =
+            "z3PGka1FKJSLKyhTCsu0lQsSr6Rq3HRQJ4vgWodVmR4"
+                .parse::<Ed25519Public>()?
+                .into();
+        assert_eq!(rd.ed_identity(), &ed_identity);
+        assert_eq!(
+            rd.identity_cert().cert_type(),
+            tor_cert::CertType::IDENTITY_V_SIGNING
+        );
+        assert_eq!(
+            rd.platform(),
+            Some(&RelayPlatform::Tor(
+                "0.4.2.6".parse().unwrap(),
+                "Linux".to_string()
+            ))
+        );
+        assert!(rd
+            .protocols()
+            .supports_known_subver(tor_protover::ProtoKind::Link, 5));
+        assert!(rd.family().is_none());
+        assert!(!rd.ipv6_policy().allows_some_port());
+        assert_eq!(
+            rd.ipv4_policy().allows(&"127.0.0.1".parse().unwrap(), 80),
+            Some(RuleKind::Reject)
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_bad() {
         use crate::types::policy::PolicyError;
@@ -871,10 +1095,8 @@ mod test {
         let v: Vec<_> = rd.collect();
         assert!(v[0].is_err());
         assert!(v[1].is_ok());
-        assert_eq!(
-            v[1].as_ref().unwrap().ann.source,
-            Some("\"127.0.0.1\"".to_string())
-        );
+        assert_eq!(v[1].as_ref().unwrap().ann().source(), Some("\"127.0.0.1\""));
+        assert_eq!(v[1].as_ref().unwrap().ann().purpose(), None);
         assert!(v[2].is_err());
     }
 
@@ -270,6 +270,9 @@ pub enum ParseErrorKind {
     /// A consensus lifetime was ill-formed.
     #[display(fmt = "Invalid consensus lifetime")]
     InvalidLifetime,
+    /// An encrypted part of a document couldn't be decrypted.
+    #[display(fmt = "couldn't decrypt onion service descriptor")]
+    DecryptionFailed,
 }
 
 /// The underlying source for an [`Error`](crate::Error).
@@ -291,6 +294,9 @@ pub(crate) enum ParseErrorSource {
     /// An error when validating a signature.
     #[error("Invalid signature")]
     Signature(#[source] Arc<signature::Error>),
+    /// An error when checking an Ed25519 certificate.
+    #[error("Invalid certificate")]
+    Cert(#[from] tor_cert::CertError),
     /// Invalid protocol versions.
     #[error("Protocol versions")]
     Protovers(#[from] tor_protover::ParseError),
@@ -461,6 +467,24 @@ declare_into! { std::num::ParseIntError => BadArgument }
 declare_into! { std::net::AddrParseError => BadArgument }
 declare_into! { PolicyError => BadPolicy }
 
+impl From<tor_cert::CertError> for Error {
+    fn from(err: tor_cert::CertError) -> Self {
+        use tor_cert::CertError as CE;
+        let kind = match err {
+            CE::BytesErr(_) => ParseErrorKind::Undecodable,
+            CE::BadSignature => ParseErrorKind::BadSignature,
+            _ => ParseErrorKind::BadObjectVal,
+        };
+
+        Error {
+            kind,
+            msg: None,
+            pos: None,
+            source: Some(err.into()),
+        }
+    }
+}
+
 impl From<tor_error::Bug> for Error {
     fn from(err: tor_error::Bug) -> Self {
         use tor_error::HasKind;
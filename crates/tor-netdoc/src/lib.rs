@@ -35,6 +35,9 @@
 //! `ns-consensus`: enable support for the "ns consensus" document type, which
 //! some relays cache and serve.
 //!
+//! `hsdesc`: enable support for parsing and decrypting v3 onion service
+//! descriptors.
+//!
 //! # Caveat haxxor: limitations and infelicities
 //!
 //! TODO: This crate requires that all of its inputs be valid UTF-8:
@@ -5,6 +5,7 @@
 
 use crate::types::misc::LongIdent;
 use crate::{Error, Result};
+use std::iter::FromIterator;
 use tor_llcrypto::pk::rsa::RsaIdentity;
 
 /// Information about a relay family.
@@ -15,28 +16,92 @@ use tor_llcrypto::pk::rsa::RsaIdentity;
 /// belong to the same family if and only if each one lists the other
 /// as belonging to its family.
 ///
-/// NOTE: when parsing, this type always discards incorrectly-formatted
-/// entries, including entries that are only nicknames.
+/// When parsing, this type canonicalizes the family: entries are
+/// converted to RSA identities, sorted, and deduplicated.  Entries that
+/// can't be converted to an RSA identity are never used for matching, but
+/// we remember how many of them there were.  Of those, the ones that are
+/// well-formed nicknames are kept (in their original order) for
+/// informational purposes.
 ///
 /// TODO: This type probably belongs in a different crate.
 #[derive(Clone, Debug)]
-pub struct RelayFamily(Vec<RsaIdentity>);
+pub struct RelayFamily {
+    /// The members of this family, sorted and without duplicates.
+    members: Vec<RsaIdentity>,
+    /// The nicknames listed in this family, in the order they were listed.
+    nicknames: Vec<String>,
+    /// The number of entries that we discarded because we couldn't turn
+    /// them into RSA identities.
+    n_dropped: usize,
+}
 
 impl RelayFamily {
     /// Return a new empty RelayFamily.
     pub fn new() -> Self {
-        RelayFamily(Vec::new())
+        RelayFamily {
+            members: Vec::new(),
+            nicknames: Vec::new(),
+            n_dropped: 0,
+        }
     }
 
     /// Does this family include the given relay?
     pub fn contains(&self, rsa_id: &RsaIdentity) -> bool {
-        self.0.contains(rsa_id)
+        self.members.binary_search(rsa_id).is_ok()
     }
 
     /// Return an iterator over the RSA identity keys listed in this
-    /// family.
+    /// family, in sorted order.
     pub fn members(&self) -> impl Iterator<Item = &RsaIdentity> {
-        self.0.iter()
+        self.members.iter()
+    }
+
+    /// Return the number of distinct RSA identity keys listed in this
+    /// family.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Return true if this family lists no RSA identity keys.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Return an iterator over the nicknames listed in this family.
+    ///
+    /// Nicknames aren't unique, so they can't be used to tell whether a
+    /// relay belongs to a family: this is for display purposes only.
+    pub fn nicknames(&self) -> impl Iterator<Item = &str> {
+        self.nicknames.iter().map(String::as_str)
+    }
+
+    /// Return the number of entries that were discarded when parsing this
+    /// family, because they were nicknames or otherwise could not be turned
+    /// into RSA identities.
+    pub fn n_dropped(&self) -> usize {
+        self.n_dropped
+    }
+}
+
+/// Return true if `s` is a well-formed relay nickname.
+fn is_nickname(s: &str) -> bool {
+    (1..=19).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// A RelayFamily is displayed in canonical form: its identities as
+/// uppercase hex with a leading dollar sign, followed by its nicknames.
+impl std::fmt::Display for RelayFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut sep = "";
+        for id in &self.members {
+            write!(f, "{}${}", sep, hex::encode_upper(id.as_bytes()))?;
+            sep = " ";
+        }
+        for nickname in &self.nicknames {
+            write!(f, "{}{}", sep, nickname)?;
+            sep = " ";
+        }
+        Ok(())
     }
 }
 
@@ -46,15 +111,42 @@ impl Default for RelayFamily {
     }
 }
 
+impl FromIterator<RsaIdentity> for RelayFamily {
+    fn from_iter<I: IntoIterator<Item = RsaIdentity>>(iter: I) -> Self {
+        let mut members: Vec<RsaIdentity> = iter.into_iter().collect();
+        members.sort_unstable();
+        members.dedup();
+        members.shrink_to_fit();
+        RelayFamily {
+            members,
+            nicknames: Vec::new(),
+            n_dropped: 0,
+        }
+    }
+}
+
 impl std::str::FromStr for RelayFamily {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
-        let v: Result<Vec<RsaIdentity>> = s
+        let mut n_dropped = 0;
+        let mut nicknames = Vec::new();
+        let mut family: RelayFamily = s
             .split(crate::parse::tokenize::is_sp)
-            .map(|e| e.parse::<LongIdent>().map(|v| v.into()))
-            .filter(Result::is_ok)
+            .filter(|e| !e.is_empty())
+            .filter_map(|e| match e.parse::<LongIdent>() {
+                Ok(id) => Some(id.into()),
+                Err(_) => {
+                    n_dropped += 1;
+                    if is_nickname(e) {
+                        nicknames.push(e.to_string());
+                    }
+                    None
+                }
+            })
             .collect();
-        Ok(RelayFamily(v?))
+        family.nicknames = nicknames;
+        family.n_dropped = n_dropped;
+        Ok(family)
     }
 }
 
@@ -68,7 +160,7 @@ mod test {
         let f = "nickname1 nickname2 $ffffffffffffffffffffffffffffffffffffffff=foo eeeeeeeeeeeeeeeeeeeEEEeeeeeeeeeeeeeeeeee ddddddddddddddddddddddddddddddddd  $cccccccccccccccccccccccccccccccccccccccc~blarg ".parse::<RelayFamily>()?;
         let v = vec![
             RsaIdentity::from_bytes(
-                &hex::decode("ffffffffffffffffffffffffffffffffffffffff").unwrap()[..],
+                &hex::decode("cccccccccccccccccccccccccccccccccccccccc").unwrap()[..],
             )
             .unwrap(),
             RsaIdentity::from_bytes(
@@ -76,11 +168,54 @@ mod test {
             )
             .unwrap(),
             RsaIdentity::from_bytes(
-                &hex::decode("cccccccccccccccccccccccccccccccccccccccc").unwrap()[..],
+                &hex::decode("ffffffffffffffffffffffffffffffffffffffff").unwrap()[..],
             )
             .unwrap(),
         ];
-        assert_eq!(f.0, v);
+        assert_eq!(f.members, v);
+        // Two nicknames, and one identity that was too short.
+        assert_eq!(f.n_dropped(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn family_canonical() -> Result<()> {
+        // A messy family line, of the kind we see in practice: mixed-case
+        // hex, with and without dollar signs and nicknames, and with
+        // duplicates.
+        let f = "$0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A Reliable0 \
+                 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b \
+                 $0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a=Reliable0 \
+                 $0B0b0B0b0B0b0B0b0B0b0B0b0B0b0B0b0B0b0B0b~Reliable1 \
+                 $01010101010101010101010101010101010101 \
+                 reliable2 $zz0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a"
+            .parse::<RelayFamily>()?;
+        let members: Vec<_> = f.members().cloned().collect();
+        assert_eq!(members, vec![[0x0a; 20].into(), [0x0b; 20].into()]);
+        assert_eq!(f.len(), 2);
+        assert!(!f.is_empty());
+        assert!(f.contains(&[0x0a; 20].into()));
+        assert!(f.contains(&[0x0b; 20].into()));
+        assert!(!f.contains(&[0x01; 20].into()));
+        assert_eq!(f.n_dropped(), 4);
+        let nicknames: Vec<_> = f.nicknames().collect();
+        assert_eq!(nicknames, vec!["Reliable0", "reliable2"]);
+        assert_eq!(
+            f.to_string(),
+            "$0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A0A \
+             $0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B Reliable0 reliable2"
+        );
+
+        let f = "".parse::<RelayFamily>()?;
+        assert!(f.is_empty());
+        assert_eq!(f.n_dropped(), 0);
+        assert_eq!(f.to_string(), "");
+
+        let f: RelayFamily = vec![[3; 20].into(), [1; 20].into(), [3; 20].into()]
+            .into_iter()
+            .collect();
+        let members: Vec<_> = f.members().cloned().collect();
+        assert_eq!(members, vec![[1; 20].into(), [3; 20].into()]);
         Ok(())
     }
 
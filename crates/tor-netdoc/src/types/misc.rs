@@ -9,7 +9,7 @@ pub(crate) use b16impl::*;
 pub(crate) use b64impl::*;
 pub(crate) use curve25519impl::*;
 pub(crate) use ed25519impl::*;
-#[cfg(feature = "routerdesc")]
+#[cfg(any(feature = "routerdesc", feature = "hsdesc"))]
 pub(crate) use edcert::*;
 pub(crate) use fingerprint::*;
 pub(crate) use rsa::*;
@@ -266,7 +266,7 @@ mod rsa {
 }
 
 /// Types for decoding Ed25519 certificates
-#[cfg(feature = "routerdesc")]
+#[cfg(any(feature = "routerdesc", feature = "hsdesc"))]
 mod edcert {
     use crate::{ParseErrorKind as EK, Pos, Result};
     use tor_cert::{CertType, Ed25519Cert, KeyUnknownCert};
@@ -110,6 +110,42 @@ impl PortPolicy {
     pub fn intern(self) -> Arc<Self> {
         POLICY_CACHE.intern(self)
     }
+    /// Return a new PortPolicy that allows only the ports allowed by both
+    /// `self` and `other`.
+    ///
+    /// # Example
+    /// ```
+    /// use tor_netdoc::types::policy::PortPolicy;
+    ///
+    /// let web: PortPolicy = "accept 80,443,8000-8999".parse().unwrap();
+    /// let low: PortPolicy = "accept 1-1023".parse().unwrap();
+    /// let both = web.intersection(&low);
+    /// assert!(both.allows_port(443));
+    /// assert!(! both.allows_port(8080));
+    /// ```
+    pub fn intersection(&self, other: &PortPolicy) -> PortPolicy {
+        let mut allowed = Vec::new();
+        let mut a = self.allowed.iter().peekable();
+        let mut b = other.allowed.iter().peekable();
+        while let (Some(ra), Some(rb)) = (a.peek(), b.peek()) {
+            let lo = std::cmp::max(ra.lo, rb.lo);
+            let hi = std::cmp::min(ra.hi, rb.hi);
+            if lo <= hi {
+                allowed.push(PortRange::new_unchecked(lo, hi));
+            }
+            // Advance whichever range ends first; it can't overlap anything
+            // else in the other list.
+            if ra.hi < rb.hi {
+                a.next();
+            } else {
+                b.next();
+            }
+        }
+        // Since both inputs were sorted, disjoint, and compact, the output
+        // is too: two adjacent outputs would have required adjacent ranges
+        // in one of the inputs.
+        PortPolicy { allowed }
+    }
     /// Return true if this policy allows any ports at all.
     ///
     /// # Example
@@ -203,6 +239,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_intersection() {
+        fn check(a: &str, b: &str, outp: &str) {
+            let a = a.parse::<PortPolicy>().unwrap();
+            let b = b.parse::<PortPolicy>().unwrap();
+            assert_eq!(format!("{}", a.intersection(&b)), outp);
+            assert_eq!(format!("{}", b.intersection(&a)), outp);
+        }
+
+        check("accept 1-10,30-50,600", "accept 5-35", "accept 5-10,30-35");
+        check("accept 1-65535", "accept 22,80,443", "accept 22,80,443");
+        check("reject 1-65535", "accept 22,80,443", "reject 1-65535");
+        check("accept 1-10", "accept 11-20", "reject 1-65535");
+        check(
+            "reject 25,119",
+            "accept 20-30,100-200",
+            "accept 20-24,26-30,100-118,120-200",
+        );
+    }
+
     #[test]
     fn test_bad() {
         for s in &[
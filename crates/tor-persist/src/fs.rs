@@ -1,8 +1,12 @@
 //! Filesystem + JSON implementation of StateMgr.
 
+use crate::journal;
 use crate::{load_error, store_error};
 use crate::{Error, LockStatus, Result, StateMgr};
 use serde::{de::DeserializeOwned, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -34,6 +38,16 @@ use std::os::unix::fs::DirBuilderExt;
 /// fs-safe on all systems.
 ///
 /// NEVER use user-controlled or remote-controlled data for your keys.
+///
+/// # Journaling
+///
+/// A manager constructed with [`FsStateMgr::from_path_with_journal()`]
+/// keeps an append-only journal for each key next to its state file.  Other
+/// managers ignore journal records entirely.
+///
+/// Journal records are written to the file as soon as they are appended,
+/// but we only ask the operating system to flush them to disk once every
+/// few records, or when the corresponding state is stored.
 #[derive(Clone, Debug)]
 pub struct FsStateMgr {
     /// Inner reference-counted object.
@@ -47,15 +61,43 @@ struct FsStateMgrInner {
     statepath: PathBuf,
     /// Lockfile to achieve exclusive access to state files.
     lockfile: Mutex<fslock::LockFile>,
+    /// If journaling is enabled, a map from key to the journal files that
+    /// we've opened for appending.
+    journals: Option<Mutex<HashMap<String, JournalFile>>>,
 }
 
+/// A journal file that we're appending records to.
+#[derive(Debug)]
+struct JournalFile {
+    /// The open file, positioned just after its last intact record.
+    file: File,
+    /// The number of records we've written since we last synced the file.
+    n_unsynced: usize,
+}
+
+/// How many journal records do we write before we sync a journal file
+/// to disk?
+const JOURNAL_SYNC_INTERVAL: usize = 8;
+
 impl FsStateMgr {
     /// Construct a new `FsStateMgr` to store data in `path`.
     ///
     /// This function will try to create `path` if it does not already
     /// exist.
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
+        Self::new(path.as_ref(), false)
+    }
+    /// Construct a new `FsStateMgr` to store data in `path`, and keep
+    /// journals of changes alongside the stored data.
+    ///
+    /// This function will try to create `path` if it does not already
+    /// exist.
+    pub fn from_path_with_journal<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(path.as_ref(), true)
+    }
+    /// Helper: construct a new `FsStateMgr` to store data in `path`, with
+    /// journaling enabled if `journal` is true.
+    fn new(path: &Path, journal: bool) -> Result<Self> {
         let statepath = path.join("state");
         let lockpath = path.join("state.lock");
 
@@ -72,6 +114,7 @@ impl FsStateMgr {
             inner: Arc::new(FsStateMgrInner {
                 statepath,
                 lockfile,
+                journals: journal.then(|| Mutex::new(HashMap::new())),
             }),
         })
     }
@@ -83,6 +126,60 @@ impl FsStateMgr {
             .statepath
             .join(sanitize_filename::sanitize(key) + ".json")
     }
+    /// Return a filename to use for the journal associated with `key`.
+    fn journal_filename(&self, key: &str) -> PathBuf {
+        self.inner
+            .statepath
+            .join(sanitize_filename::sanitize(key) + ".journal")
+    }
+    /// Open the journal for `key` so that we can append to it.
+    ///
+    /// If the journal ends with a truncated or corrupt record, we discard
+    /// it here: otherwise, any records that we append would be lost
+    /// behind it.
+    fn open_journal(&self, key: &str) -> Result<JournalFile> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.journal_filename(key))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (_, valid_len) = journal::split_records(&contents);
+        if valid_len != contents.len() {
+            file.set_len(valid_len as u64)?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+        Ok(JournalFile {
+            file,
+            n_unsynced: 0,
+        })
+    }
+    /// Discard the journal associated with `key`, if there is one.
+    ///
+    /// We remove the journal file even if this manager doesn't keep journals
+    /// itself: one left behind by an earlier journaling manager would
+    /// otherwise get replayed on top of a newer snapshot.
+    fn clear_journal(&self, key: &str) -> Result<()> {
+        if let Some(journals) = &self.inner.journals {
+            let mut journals = journals.lock().expect("Poisoned lock on journals");
+            journals.remove(key);
+        }
+        if let Err(e) = std::fs::remove_file(self.journal_filename(key)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+    /// Return true if this storage manager keeps journals of changes.
+    ///
+    /// (This is true if it was constructed with
+    /// [`FsStateMgr::from_path_with_journal`].)
+    pub fn has_journal(&self) -> bool {
+        self.inner.journals.is_some()
+    }
     /// Return the top-level directory for this storage manager.
     ///
     /// (This is the same directory passed to [`FsStateMgr::from_path`].)
@@ -161,11 +258,67 @@ impl StateMgr for FsStateMgr {
         let output = serde_json::to_string_pretty(val).map_err(store_error)?;
 
         let fname_tmp = fname.with_extension("tmp");
-        std::fs::write(&fname_tmp, &output)?;
+        {
+            let mut f = File::create(&fname_tmp)?;
+            f.write_all(output.as_bytes())?;
+            if self.inner.journals.is_some() || self.journal_filename(key).exists() {
+                // We're about to throw away the journal, so we need to be
+                // sure that this state actually reaches the disk first.
+                f.sync_all()?;
+            }
+        }
         std::fs::rename(fname_tmp, fname)?;
 
+        self.clear_journal(key)?;
+
         Ok(())
     }
+
+    fn journal_append<S>(&self, key: &str, rec: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let journals = match &self.inner.journals {
+            Some(j) => j,
+            None => return Ok(()),
+        };
+        if !self.can_store() {
+            return Err(Error::NoLock);
+        }
+
+        let record = journal::encode_record(rec)?;
+
+        let mut journals = journals.lock().expect("Poisoned lock on journals");
+        let jf = match journals.entry(key.to_string()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(self.open_journal(key)?),
+        };
+        jf.file.write_all(&record)?;
+        jf.n_unsynced += 1;
+        if jf.n_unsynced >= JOURNAL_SYNC_INTERVAL {
+            jf.file.sync_data()?;
+            jf.n_unsynced = 0;
+        }
+
+        Ok(())
+    }
+
+    fn journal_load<D>(&self, key: &str) -> Result<Vec<D>>
+    where
+        D: DeserializeOwned,
+    {
+        if self.inner.journals.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let contents = match std::fs::read(self.journal_filename(key)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(journal::decode_records(&contents))
+    }
 }
 
 #[cfg(test)]
@@ -212,4 +365,146 @@ mod test {
 
         Ok(())
     }
+
+    #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    enum Change {
+        Set(String, u32),
+        Remove(String),
+    }
+
+    /// Replay `changes` on top of `snapshot`.
+    fn replay(mut snapshot: HashMap<String, u32>, changes: Vec<Change>) -> HashMap<String, u32> {
+        for c in changes {
+            match c {
+                Change::Set(k, v) => {
+                    snapshot.insert(k, v);
+                }
+                Change::Remove(k) => {
+                    snapshot.remove(&k);
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// Load the "counts" state from `store`, with its journal replayed.
+    fn load_counts(store: &FsStateMgr) -> Result<HashMap<String, u32>> {
+        let snapshot = store.load("counts")?.unwrap_or_default();
+        Ok(replay(snapshot, store.journal_load("counts")?))
+    }
+
+    #[test]
+    fn journal_replay() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+
+        let snapshot: HashMap<_, _> = vec![("a".to_string(), 1), ("b".to_string(), 2)]
+            .into_iter()
+            .collect();
+        store.store("counts", &snapshot)?;
+        for change in &[
+            Change::Set("a".into(), 10),
+            Change::Remove("b".into()),
+            Change::Set("c".into(), 3),
+        ] {
+            store.journal_append("counts", change)?;
+        }
+
+        // "Crash" without storing a new snapshot, and reload.
+        drop(store);
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        let counts = load_counts(&store)?;
+        let expected: HashMap<_, _> = vec![("a".to_string(), 10), ("c".to_string(), 3)]
+            .into_iter()
+            .collect();
+        assert_eq!(counts, expected);
+
+        // We can't add to the journal without the lock.
+        assert!(matches!(
+            store.journal_append("counts", &Change::Remove("a".into())),
+            Err(Error::NoLock)
+        ));
+
+        // Storing a snapshot discards the journal.
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        store.store("counts", &counts)?;
+        assert!(store.journal_load::<Change>("counts")?.is_empty());
+        assert_eq!(load_counts(&store)?, expected);
+
+        // A manager without journaling ignores records entirely.
+        drop(store);
+        let store = FsStateMgr::from_path(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        store.journal_append("counts", &Change::Remove("a".into()))?;
+        assert!(store.journal_load::<Change>("counts")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_without_journal_discards_old_journal() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+
+        let snapshot: HashMap<_, _> = vec![("a".to_string(), 1)].into_iter().collect();
+        store.store("counts", &snapshot)?;
+        store.journal_append("counts", &Change::Set("a".into(), 10))?;
+        store.journal_append("counts", &Change::Set("b".into(), 20))?;
+        drop(store);
+
+        // A manager without journaling stores a newer snapshot...
+        let store = FsStateMgr::from_path(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        let newer: HashMap<_, _> = vec![("c".to_string(), 3)].into_iter().collect();
+        store.store("counts", &newer)?;
+        drop(store);
+
+        // ... so the old journal must not be replayed on top of it.
+        let fname = dir.path().join("state").join("counts.journal");
+        assert!(!fname.exists());
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        assert_eq!(load_counts(&store)?, newer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_corrupt() -> Result<()> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+
+        let snapshot: HashMap<String, u32> = HashMap::new();
+        store.store("counts", &snapshot)?;
+        for i in 0..4 {
+            store.journal_append("counts", &Change::Set(format!("k{}", i), i))?;
+        }
+        drop(store);
+
+        // Corrupt the last byte of the third record.
+        let fname = dir.path().join("state").join("counts.journal");
+        let mut contents = std::fs::read(&fname).unwrap();
+        let rec_len = contents.len() / 4;
+        contents[rec_len * 3 - 1] ^= 0x01;
+        std::fs::write(&fname, &contents).unwrap();
+
+        // Replay stops cleanly at the corrupt record.
+        let store = FsStateMgr::from_path_with_journal(dir.path())?;
+        let changes: Vec<Change> = store.journal_load("counts")?;
+        assert_eq!(
+            changes,
+            vec![Change::Set("k0".into(), 0), Change::Set("k1".into(), 1)]
+        );
+
+        // New records replace the corrupt tail, so they can be replayed.
+        assert_eq!(store.try_lock()?, LockStatus::NewlyAcquired);
+        store.journal_append("counts", &Change::Remove("k0".into()))?;
+        let counts = load_counts(&store)?;
+        let expected: HashMap<_, _> = vec![("k1".to_string(), 1)].into_iter().collect();
+        assert_eq!(counts, expected);
+
+        Ok(())
+    }
 }
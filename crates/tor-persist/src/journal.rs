@@ -0,0 +1,242 @@
+//! Append-only journals of state changes.
+//!
+//! A [`StateMgr`] only saves an object when its owner asks it to, and owners
+//! typically do that periodically.  If we crash between two saves, every
+//! change since the last save is lost.  To avoid that, an owner can append
+//! small records describing each important change to a journal kept alongside
+//! the saved object, and replay those records on top of the saved object when
+//! loading it.  Whenever the object is successfully saved again, the journal
+//! for its key is discarded.
+//!
+//! # Format
+//!
+//! A journal is a sequence of records.  Each record is encoded as:
+//!
+//! ```text
+//!    length   [4 bytes, big-endian]
+//!    crc32    [4 bytes, big-endian; CRC-32 of the body]
+//!    body     [length bytes; a JSON-encoded object]
+//! ```
+//!
+//! When reading a journal, we stop at the first record that is truncated or
+//! whose checksum doesn't match: everything before it is still trustworthy,
+//! but nothing after it can be.
+
+use crate::{store_error, Result, StateMgr};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Length of the header on each journal record.
+const HEADER_LEN: usize = 8;
+
+/// Largest body we're willing to accept for a single journal record.
+///
+/// Records are supposed to be small; anything bigger than this is
+/// almost certainly a corrupted length field.
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// A handle to a journal of records of a single type, kept at a single
+/// location.
+///
+/// To get an object of this type, call [`StateMgr::create_journal_handle`].
+///
+/// Like [`StorageHandle`](crate::StorageHandle), this trait is object-safe.
+pub trait JournalHandle<R: Serialize + DeserializeOwned> {
+    /// Append a record to the journal.
+    fn append(&self, rec: &R) -> Result<()>;
+
+    /// Load every intact record from the journal, in the order they were
+    /// appended.
+    fn load(&self) -> Result<Vec<R>>;
+
+    /// Return true if we have the lock; see [`StateMgr::can_store`].
+    fn can_store(&self) -> bool;
+}
+
+/// Type wrapper for a reference-counted `dyn` [`JournalHandle`].
+pub type DynJournalHandle<R> = Arc<dyn JournalHandle<R> + Send + Sync + 'static>;
+
+/// Concrete implementation of [`JournalHandle`].
+#[derive(Debug)]
+pub(crate) struct JournalHandleImpl<M, R> {
+    /// An underlying [`StateMgr`] to use.
+    mgr: M,
+    /// The key of the object that this journal belongs to.
+    key: String,
+    /// A zero-sized type to please the type checker.
+    ///
+    /// See `StorageHandleImpl` for why this is a function type.
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<M, R> JournalHandle<R> for JournalHandleImpl<M, R>
+where
+    M: StateMgr,
+    R: Serialize + DeserializeOwned + 'static,
+{
+    fn append(&self, rec: &R) -> Result<()> {
+        self.mgr.journal_append(&self.key, rec)
+    }
+    fn load(&self) -> Result<Vec<R>> {
+        self.mgr.journal_load(&self.key)
+    }
+    fn can_store(&self) -> bool {
+        self.mgr.can_store()
+    }
+}
+
+impl<M, R> JournalHandleImpl<M, R>
+where
+    M: Send + Sync + 'static,
+    R: Serialize + DeserializeOwned + 'static,
+{
+    /// Construct a new JournalHandleImpl.
+    pub(crate) fn new(mgr: M, key: String) -> JournalHandleImpl<M, R> {
+        JournalHandleImpl {
+            mgr,
+            key,
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// Encode `rec` as a journal record, including its header.
+pub(crate) fn encode_record<S: Serialize>(rec: &S) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(rec).map_err(store_error)?;
+    if body.len() > MAX_RECORD_LEN {
+        // This isn't a JSON problem exactly, but it's the closest thing
+        // we have: the value can't be represented in our format.
+        return Err(store_error(serde::ser::Error::custom(
+            "journal record too long",
+        )));
+    }
+    let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Split `journal` into the bodies of its intact records.
+///
+/// Returns the bodies, and the number of bytes at the start of `journal`
+/// that they occupy.  Any bytes after that point are truncated or corrupt.
+pub(crate) fn split_records(journal: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut bodies = Vec::new();
+    let mut pos = 0;
+    while journal.len() - pos >= HEADER_LEN {
+        let header = &journal[pos..pos + HEADER_LEN];
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if len > MAX_RECORD_LEN || journal.len() - pos - HEADER_LEN < len {
+            break;
+        }
+        let body = &journal[pos + HEADER_LEN..pos + HEADER_LEN + len];
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        bodies.push(body);
+        pos += HEADER_LEN + len;
+    }
+    (bodies, pos)
+}
+
+/// Decode every intact record in `journal`.
+///
+/// Stops at the first truncated or corrupt record.  Records that are
+/// intact but can't be decoded as a `D` (for example, because they were
+/// written by a newer version of Arti) are skipped.
+pub(crate) fn decode_records<D: DeserializeOwned>(journal: &[u8]) -> Vec<D> {
+    split_records(journal)
+        .0
+        .into_iter()
+        .filter_map(|body| serde_json::from_slice(body).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+    enum Rec {
+        Hello(u32),
+        Goodbye { name: String },
+    }
+
+    fn journal(recs: &[Rec]) -> Vec<u8> {
+        recs.iter()
+            .flat_map(|r| encode_record(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let recs = vec![
+            Rec::Hello(7),
+            Rec::Goodbye {
+                name: "world".into(),
+            },
+            Rec::Hello(99),
+        ];
+        let j = journal(&recs);
+        let (bodies, len) = split_records(&j);
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(len, j.len());
+        assert_eq!(decode_records::<Rec>(&j), recs);
+        assert_eq!(decode_records::<Rec>(&[]), vec![]);
+    }
+
+    #[test]
+    fn truncated() {
+        let recs = vec![Rec::Hello(1), Rec::Hello(2)];
+        let j = journal(&recs);
+        let first_len = encode_record(&recs[0]).unwrap().len();
+        for cut in first_len..j.len() {
+            let (bodies, len) = split_records(&j[..cut]);
+            assert_eq!(bodies.len(), 1);
+            assert_eq!(len, first_len);
+        }
+        assert!(decode_records::<Rec>(&j[..first_len - 1]).is_empty());
+    }
+
+    #[test]
+    fn corrupt() {
+        let recs = vec![Rec::Hello(1), Rec::Hello(2), Rec::Hello(3)];
+        let mut j = journal(&recs);
+        let rec_len = encode_record(&recs[0]).unwrap().len();
+
+        // Flip a bit in the body of the second record: we stop before it,
+        // even though the third record is fine.
+        j[rec_len + HEADER_LEN + 2] ^= 0x10;
+        let (bodies, len) = split_records(&j);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(len, rec_len);
+        assert_eq!(decode_records::<Rec>(&j), vec![Rec::Hello(1)]);
+
+        // A ridiculous length is treated as corruption too.
+        let mut j = journal(&recs);
+        j[0] = 0xff;
+        assert!(decode_records::<Rec>(&j).is_empty());
+    }
+
+    #[test]
+    fn skip_unrecognized() {
+        #[derive(Serialize)]
+        enum NewRec {
+            Hello(u32),
+            Unknown,
+        }
+        let j: Vec<u8> = [NewRec::Hello(5), NewRec::Unknown, NewRec::Hello(6)]
+            .iter()
+            .flat_map(|r| encode_record(r).unwrap())
+            .collect();
+        assert_eq!(
+            decode_records::<Rec>(&j),
+            vec![Rec::Hello(5), Rec::Hello(6)]
+        );
+    }
+}
@@ -41,6 +41,7 @@
 #[cfg(not(target_arch = "wasm32"))]
 mod fs;
 mod handle;
+mod journal;
 #[cfg(feature = "testing")]
 mod testing;
 
@@ -53,6 +54,7 @@ type Result<T> = std::result::Result<T, crate::Error>;
 #[cfg(not(target_arch = "wasm32"))]
 pub use fs::FsStateMgr;
 pub use handle::{DynStorageHandle, StorageHandle};
+pub use journal::{DynJournalHandle, JournalHandle};
 pub use serde_json::Value as JsonValue;
 #[cfg(feature = "testing")]
 pub use testing::TestingStateMgr;
@@ -78,7 +80,8 @@ pub trait StateMgr: Clone {
         D: DeserializeOwned;
     /// Try to save `val` with key `key` in the store.
     ///
-    /// Replaces any previous value associated with `key`.
+    /// Replaces any previous value associated with `key`, and discards
+    /// the journal (if any) associated with `key`.
     fn store<S>(&self, key: &str, val: &S) -> Result<()>
     where
         S: Serialize;
@@ -110,6 +113,42 @@ pub trait StateMgr: Clone {
     {
         Arc::new(handle::StorageHandleImpl::new(self, key.into()))
     }
+
+    /// Append `rec` to the journal of changes associated with `key`.
+    ///
+    /// The journal is discarded the next time a value is successfully
+    /// stored with `key`.  See [`JournalHandle`] for more information.
+    ///
+    /// The default implementation doesn't support journaling, and
+    /// discards all records.
+    fn journal_append<S>(&self, key: &str, rec: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let _ = (key, rec);
+        Ok(())
+    }
+
+    /// Load every intact record from the journal associated with `key`.
+    ///
+    /// Return an empty list if there is no such journal.
+    fn journal_load<D>(&self, key: &str) -> Result<Vec<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let _ = key;
+        Ok(Vec::new())
+    }
+
+    /// Make a new [`JournalHandle`] to append records of a particular
+    /// type to the journal for a particular key.
+    fn create_journal_handle<R>(self, key: impl Into<String>) -> DynJournalHandle<R>
+    where
+        Self: Send + Sync + Sized + 'static,
+        R: Serialize + DeserializeOwned + 'static,
+    {
+        Arc::new(journal::JournalHandleImpl::new(self, key.into()))
+    }
 }
 
 /// A possible outcome from calling [`StateMgr::try_lock()`]
@@ -1,5 +1,6 @@
 //! Testing-only StateMgr that stores values in a hash table.
 
+use crate::journal;
 use crate::{load_error, store_error};
 use crate::{Error, LockStatus, Result, StateMgr};
 use serde::{de::DeserializeOwned, Serialize};
@@ -50,6 +51,11 @@ struct TestingStateMgrStorage {
     /// have to use `Any`) and to try to detect any
     /// serialization-related bugs.
     entries: HashMap<String, String>,
+    /// Map from key to encoded journal.
+    ///
+    /// We use the same encoding as a real journal file, so that tests can
+    /// corrupt it if they want.
+    journals: HashMap<String, Vec<u8>>,
 }
 
 impl Default for TestingStateMgr {
@@ -64,6 +70,7 @@ impl TestingStateMgr {
         let storage = TestingStateMgrStorage {
             lock_available: true,
             entries: HashMap::new(),
+            journals: HashMap::new(),
         };
         let inner = TestingStateMgrInner {
             lock_held: false,
@@ -115,9 +122,43 @@ impl StateMgr for TestingStateMgr {
         let val = serde_json::to_string_pretty(val).map_err(store_error)?;
 
         storage.entries.insert(key.to_string(), val);
+        storage.journals.remove(key);
+        Ok(())
+    }
+
+    fn journal_append<S>(&self, key: &str, rec: &S) -> Result<()>
+    where
+        S: Serialize,
+    {
+        let inner = self.inner.lock().expect("Lock poisoned.");
+        if !inner.lock_held {
+            return Err(Error::NoLock);
+        }
+        let mut storage = inner.storage.lock().expect("Lock poisoned.");
+
+        let record = journal::encode_record(rec)?;
+
+        storage
+            .journals
+            .entry(key.to_string())
+            .or_default()
+            .extend_from_slice(&record);
         Ok(())
     }
 
+    fn journal_load<D>(&self, key: &str) -> Result<Vec<D>>
+    where
+        D: DeserializeOwned,
+    {
+        let inner = self.inner.lock().expect("Lock poisoned.");
+        let storage = inner.storage.lock().expect("Lock poisoned.");
+        Ok(storage
+            .journals
+            .get(key)
+            .map(|j| journal::decode_records(j))
+            .unwrap_or_default())
+    }
+
     fn can_store(&self) -> bool {
         let inner = self.inner.lock().expect("Lock poisoned.");
 
@@ -264,6 +305,31 @@ mod test {
         assert_eq!(h3.load().unwrap(), Some(s2));
     }
 
+    #[test]
+    fn journal_handles() {
+        use crate::{DynJournalHandle, DynStorageHandle};
+        let mgr = TestingStateMgr::new();
+
+        let h: DynStorageHandle<Ex1> = mgr.clone().create_handle("foo");
+        let j: DynJournalHandle<u32> = mgr.clone().create_journal_handle("foo");
+
+        assert!(matches!(j.append(&3), Err(Error::NoLock)));
+        assert!(mgr.try_lock().unwrap().held());
+        assert!(j.can_store());
+        j.append(&3).unwrap();
+        j.append(&4).unwrap();
+        assert_eq!(j.load().unwrap(), vec![3, 4]);
+
+        // A second manager sharing the storage sees the same journal.
+        let mgr2 = mgr.new_manager();
+        assert_eq!(mgr2.journal_load::<u32>("foo").unwrap(), vec![3, 4]);
+        assert!(mgr2.journal_load::<u32>("bar").unwrap().is_empty());
+
+        // Storing the value clears the journal.
+        h.store(&Ex1 { v1: 7, v2: 7 }).unwrap();
+        assert!(j.load().unwrap().is_empty());
+    }
+
     #[test]
     fn futureproof() {
         use crate::Futureproof;
@@ -48,7 +48,9 @@
 //!
 //! This is client-only, and only supports link protocol version 4.
 //!
-//! TODO: There is no channel padding.
+//! TODO: There is no channel padding.  When we add it, we should send
+//! the same PADDING_NEGOTIATE cells (if any) that a C tor client sends by
+//! default, so that our channels can't be told apart from its channels.
 //!
 //! TODO: There is no flow control, rate limiting, queueing, or
 //! fairness.
@@ -80,7 +82,7 @@ use futures::channel::{mpsc, oneshot};
 use futures::io::{AsyncRead, AsyncWrite};
 
 use futures::{Sink, SinkExt};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
@@ -90,7 +92,10 @@ use tracing::trace;
 use crate::channel::unique_id::CircUniqIdContext;
 #[cfg(test)]
 pub(crate) use codec::CodecError;
-pub use handshake::{OutboundClientHandshake, UnverifiedChannel, VerifiedChannel};
+pub use handshake::{
+    HandshakePhase, HandshakeTimeouts, OutboundClientHandshake, UnverifiedChannel, VerifiedChannel,
+};
+pub use reactor::RejectedCellCounts;
 
 /// Type alias: A Sink and Stream that transforms a TLS connection into
 /// a cell-based communication mechanism.
@@ -137,6 +142,11 @@ pub(crate) struct ChannelDetails {
     /// If calling `time_since_update` returns None,
     /// this channel is still in use by at least one circuit.
     unused_since: OptTimestamp,
+    /// Number of CREATE-type cells that we've rejected on this channel.
+    n_create_cells_rejected: AtomicU64,
+    /// Number of cells that we've rejected on this channel because their
+    /// circuit IDs were not in the range that we allocate from.
+    n_wrong_circid_range_rejected: AtomicU64,
 }
 
 impl Sink<ChanCell> for Channel {
@@ -263,6 +273,8 @@ impl Channel {
             rsa_id,
             closed,
             unused_since,
+            n_create_cells_rejected: AtomicU64::new(0),
+            n_wrong_circid_range_rejected: AtomicU64::new(0),
         };
         let details = Arc::new(details);
 
@@ -328,6 +340,18 @@ impl Channel {
         self.details.closed.load(Ordering::SeqCst)
     }
 
+    /// Return how many cells of each kind this channel's reactor has
+    /// rejected so far.
+    pub fn rejected_cell_counts(&self) -> RejectedCellCounts {
+        RejectedCellCounts {
+            create_cells: self.details.n_create_cells_rejected.load(Ordering::Relaxed),
+            wrong_circid_range: self
+                .details
+                .n_wrong_circid_range_rejected
+                .load(Ordering::Relaxed),
+        }
+    }
+
     /// If the channel is not in use, return the amount of time
     /// it has had with no circuits.
     ///
@@ -464,6 +488,8 @@ pub(crate) mod test {
             rsa_id: [10_u8; 20].into(),
             closed: AtomicBool::new(false),
             unused_since,
+            n_create_cells_rejected: AtomicU64::new(0),
+            n_wrong_circid_range_rejected: AtomicU64::new(0),
         })
     }
 
@@ -32,22 +32,34 @@ pub(super) enum CircIdRange {
     // protocol version 4.
 }
 
+impl CircIdRange {
+    /// Return true if `id` is one that we could allocate from this range.
+    pub(super) fn contains(self, id: CircId) -> bool {
+        let v: u32 = id.into();
+        let high_bit = v & 0x8000_0000 != 0;
+        match self {
+            CircIdRange::Low => !high_bit && v != 0,
+            CircIdRange::High => high_bit,
+        }
+    }
+}
+
 impl rand::distributions::Distribution<CircId> for CircIdRange {
     /// Return a random circuit ID in the appropriate range.
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> CircId {
-        // Make sure v is nonzero.
-        let v = loop {
-            match rng.gen() {
-                0_u32 => (), // zero is not a valid circuit ID
-                x => break x,
+        loop {
+            let v: u32 = rng.gen();
+            // Force the high bit of v to the appropriate value.
+            let v = match self {
+                CircIdRange::Low => v & 0x7fff_ffff,
+                CircIdRange::High => v | 0x8000_0000,
+            };
+            // Make sure v is nonzero.  (We have to check this _after_
+            // masking, or a Low range could give us zero.)
+            if v != 0 {
+                break v.into();
             }
-        };
-        // Force the high bit of v to the appropriate value.
-        match self {
-            CircIdRange::Low => v & 0x7fff_ffff,
-            CircIdRange::High => v | 0x8000_0000,
         }
-        .into()
     }
 }
 
@@ -149,8 +161,10 @@ impl CircMap {
         /// How many times do we probe for a random circuit ID before
         /// we assume that the range is fully populated?
         ///
-        /// TODO: C tor does 64, but that is probably overkill with 4-byte circuit IDs.
-        const N_ATTEMPTS: usize = 16;
+        /// This is what C tor does.  It's probably overkill with 4-byte
+        /// circuit IDs, but we don't want to behave observably differently
+        /// when a channel is crowded.
+        const N_ATTEMPTS: usize = 64;
         let iter = self.range.sample_iter(rng).take(N_ATTEMPTS);
         let circ_ent = CircEnt::Opening(createdsink, sink);
         for id in iter {
@@ -164,6 +178,15 @@ impl CircMap {
         Err(Error::IdRangeFull)
     }
 
+    /// Return true if `id` is in the range of circuit IDs that this map
+    /// allocates from.
+    ///
+    /// Circuits on this channel with any other ID could only have been
+    /// created by the other side.
+    pub(super) fn allocates(&self, id: CircId) -> bool {
+        self.range.contains(id)
+    }
+
     /// Testing only: install an entry in this circuit map without regard
     /// for consistency.
     #[cfg(test)]
@@ -314,4 +337,85 @@ mod test {
         let adv = map_high.advance_from_opening(77.into());
         assert!(adv.is_err());
     }
+
+    #[test]
+    fn circmap_allocates() {
+        let mut map_low = CircMap::new(CircIdRange::Low);
+        let mut map_high = CircMap::new(CircIdRange::High);
+        let mut rng = rand::thread_rng();
+
+        // Zero is never a valid circuit ID.
+        assert!(!map_low.allocates(CircId::from(0)));
+        assert!(!map_high.allocates(CircId::from(0)));
+
+        // Each map allocates only from its own half of the range.
+        for _ in 0..128 {
+            let (csnd, _) = oneshot::channel();
+            let (snd, _) = mpsc::channel(8);
+            let id_low = map_low.add_ent(&mut rng, csnd, snd).unwrap();
+            let (csnd, _) = oneshot::channel();
+            let (snd, _) = mpsc::channel(8);
+            let id_high = map_high.add_ent(&mut rng, csnd, snd).unwrap();
+
+            assert!(map_low.allocates(id_low));
+            assert!(!map_low.allocates(id_high));
+            assert!(map_high.allocates(id_high));
+            assert!(!map_high.allocates(id_low));
+        }
+    }
+
+    #[test]
+    fn circmap_ids_not_sequential() {
+        let mut map = CircMap::new(CircIdRange::High);
+        let mut rng = rand::thread_rng();
+        let ids: Vec<u32> = (0..256)
+            .map(|_| {
+                let (csnd, _) = oneshot::channel();
+                let (snd, _) = mpsc::channel(8);
+                map.add_ent(&mut rng, csnd, snd).unwrap().into()
+            })
+            .collect();
+
+        // With 31 random bits per ID, we should essentially never see two
+        // neighboring IDs allocated one after another.
+        assert!(ids.windows(2).all(|w| w[0].wrapping_add(1) != w[1]));
+        // And the IDs should be spread all over the range.
+        assert!(ids.iter().any(|id| *id < 0xc000_0000));
+        assert!(ids.iter().any(|id| *id >= 0xc000_0000));
+    }
+
+    #[test]
+    fn circmap_collisions() {
+        use rand::rngs::mock::StepRng;
+        let mut map = CircMap::new(CircIdRange::High);
+        let add = |map: &mut CircMap, rng: &mut StepRng| {
+            let (csnd, _) = oneshot::channel();
+            let (snd, _) = mpsc::channel(8);
+            map.add_ent(rng, csnd, snd)
+        };
+
+        // An RNG that only ever gives us 5.
+        let mut stuck = StepRng::new(5, 0);
+        let id = add(&mut map, &mut stuck).unwrap();
+        assert_eq!(id, CircId::from(0x8000_0005));
+        // Every later attempt collides, so eventually we give up.
+        assert!(matches!(add(&mut map, &mut stuck), Err(Error::IdRangeFull)));
+
+        // If we collide a few times, we keep trying until we find a free ID.
+        let mut counting = StepRng::new(3, 1);
+        let id = add(&mut map, &mut counting).unwrap();
+        assert_eq!(id, CircId::from(0x8000_0003));
+        let id = add(&mut map, &mut counting).unwrap();
+        assert_eq!(id, CircId::from(0x8000_0004));
+        // 5 is taken, so we skip to 6.
+        let id = add(&mut map, &mut counting).unwrap();
+        assert_eq!(id, CircId::from(0x8000_0006));
+
+        // Zero is never a circuit ID, even when the RNG gives us a value
+        // that masks to zero.
+        let mut low_map = CircMap::new(CircIdRange::Low);
+        let mut rng = StepRng::new(0x8000_0000, 1);
+        let id = add(&mut low_map, &mut rng).unwrap();
+        assert_eq!(id, CircId::from(1));
+    }
 }
@@ -2,6 +2,7 @@
 
 use arrayref::array_ref;
 use asynchronous_codec as futures_codec;
+use futures::future::{self, Either, Future};
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
@@ -14,6 +15,8 @@ use tor_cell::chancell::{msg, ChanCmd};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
 
 use tor_bytes::Reader;
 use tor_linkspec::ChanTarget;
@@ -31,6 +34,77 @@ use tracing::{debug, trace};
 // We only support version 4 for now, since we don't do padding right.
 static LINK_PROTOCOLS: &[u16] = &[4];
 
+/// A phase of the client side of the channel handshake.
+///
+/// We give each phase its own deadline, so that a relay can't hold one of
+/// our handshakes open indefinitely by sending its cells very slowly.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HandshakePhase {
+    /// Waiting for the relay's VERSIONS cell.
+    Versions,
+    /// Waiting for the relay's CERTS cell.
+    Certs,
+    /// Waiting for the relay's NETINFO cell.
+    Netinfo,
+}
+
+impl std::fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            HandshakePhase::Versions => "VERSIONS",
+            HandshakePhase::Certs => "CERTS",
+            HandshakePhase::Netinfo => "NETINFO",
+        };
+        write!(f, "{} cell", s)
+    }
+}
+
+/// How long to wait without progress in each phase of a channel handshake.
+///
+/// Each phase's timer starts when that phase starts, and starts over
+/// whenever we receive any data from the relay.  So a relay that is slow
+/// but still sending can finish the handshake, but one that stops sending
+/// can't hold it open.
+#[derive(Clone, Debug)]
+pub struct HandshakeTimeouts {
+    /// How long to wait for data while reading the VERSIONS cell.
+    versions: Duration,
+    /// How long to wait for data while reading the CERTS cell.
+    certs: Duration,
+    /// How long to wait for data while reading the NETINFO cell.
+    netinfo: Duration,
+}
+
+impl HandshakeTimeouts {
+    /// Construct a new HandshakeTimeouts with a given timeout for each
+    /// phase.
+    pub fn new(versions: Duration, certs: Duration, netinfo: Duration) -> Self {
+        HandshakeTimeouts {
+            versions,
+            certs,
+            netinfo,
+        }
+    }
+
+    /// Return the timeout for a given phase.
+    pub fn timeout_for(&self, phase: HandshakePhase) -> Duration {
+        match phase {
+            HandshakePhase::Versions => self.versions,
+            HandshakePhase::Certs => self.certs,
+            HandshakePhase::Netinfo => self.netinfo,
+        }
+    }
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        // Each phase should take no more than a round trip.
+        let three_seconds = Duration::from_secs(3);
+        HandshakeTimeouts::new(three_seconds, three_seconds, three_seconds)
+    }
+}
+
 /// A raw client channel on which nothing has been done.
 pub struct OutboundClientHandshake<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> {
     /// Underlying TLS stream.
@@ -108,7 +182,47 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
 
     /// Negotiate a link protocol version with the relay, and read
     /// the relay's handshake information.
-    pub async fn connect(mut self) -> Result<UnverifiedChannel<T>> {
+    ///
+    /// This function doesn't time out on its own: see
+    /// [`connect_with_timeouts`](Self::connect_with_timeouts) for a version
+    /// that does.
+    pub async fn connect(self) -> Result<UnverifiedChannel<T>> {
+        self.connect_impl(HandshakeTimeouts::default(), |_| {
+            futures::future::pending::<()>()
+        })
+        .await
+    }
+
+    /// As [`connect`](Self::connect), but give up with
+    /// [`Error::HandshakePhaseTimeout`] if, during any phase of the
+    /// handshake, the relay sends us nothing for longer than that phase's
+    /// timeout in `timeouts`.
+    ///
+    /// `sleep_fn` should return a future that becomes ready after a given
+    /// duration: typically, this is `SleepProvider::sleep` for the current
+    /// runtime.
+    pub async fn connect_with_timeouts<F, S>(
+        self,
+        timeouts: HandshakeTimeouts,
+        sleep_fn: F,
+    ) -> Result<UnverifiedChannel<T>>
+    where
+        F: FnMut(Duration) -> S + Send,
+        S: Future<Output = ()> + Send,
+    {
+        self.connect_impl(timeouts, sleep_fn).await
+    }
+
+    /// Implementation for `connect` and `connect_with_timeouts`.
+    async fn connect_impl<F, S>(
+        mut self,
+        timeouts: HandshakeTimeouts,
+        mut sleep_fn: F,
+    ) -> Result<UnverifiedChannel<T>>
+    where
+        F: FnMut(Duration) -> S + Send,
+        S: Future<Output = ()> + Send,
+    {
         /// Helper: wrap an IoError as a HandshakeIoErr.
         fn io_err_to_handshake(err: std::io::Error) -> Error {
             Error::HandshakeIoErr(Arc::new(err))
@@ -132,6 +246,8 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
         // Get versions cell.
         trace!("{}: waiting for versions", self.unique_id);
         let their_versions: msg::Versions = {
+            let phase = HandshakePhase::Versions;
+            let timeout = timeouts.timeout_for(phase);
             // TODO: this could be turned into another function, I suppose.
             let mut hdr = [0_u8; 5];
             let not_relay = || {
@@ -139,7 +255,9 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
                     "Doesn't seem to be a tor relay".into(),
                 ))
             };
-            match self.tls.read_exact(&mut hdr).await {
+            match read_exact_until_stalled(&mut self.tls, &mut hdr, phase, timeout, &mut sleep_fn)
+                .await?
+            {
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return not_relay(),
                 otherwise => otherwise,
             }
@@ -149,9 +267,8 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
             }
             let msglen = u16::from_be_bytes(*array_ref![hdr, 3, 2]);
             let mut msg = vec![0; msglen as usize];
-            self.tls
-                .read_exact(&mut msg)
-                .await
+            read_exact_until_stalled(&mut self.tls, &mut msg, phase, timeout, &mut sleep_fn)
+                .await?
                 .map_err(io_err_to_handshake)?;
             let mut reader = Reader::from_slice(&msg);
             reader.extract()?
@@ -175,10 +292,42 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
         let mut netinfo: Option<msg::Netinfo> = None;
         let mut seen_authchallenge = false;
 
+        // Each phase has its own timeout.  The timer starts over whenever we
+        // receive a cell or part of one, so that only a relay that stops
+        // sending can make us give up.
+        let mut phase = HandshakePhase::Certs;
+
         // Loop: reject duplicate and unexpected cells
         trace!("{}: waiting for rest of handshake.", self.unique_id);
-        while let Some(m) = tls.next().await {
+        loop {
             use msg::ChanMsg::*;
+            if phase == HandshakePhase::Certs && certs.is_some() {
+                phase = HandshakePhase::Netinfo;
+            }
+            let timeout = timeouts.timeout_for(phase);
+            let mut deadline = Box::pin(sleep_fn(timeout));
+            let mut n_buffered = tls.read_buffer().len();
+            let next = future::poll_fn(|cx| {
+                if let Poll::Ready(m) = tls.poll_next_unpin(cx) {
+                    return Poll::Ready(Either::Left(m));
+                }
+                // The codec keeps a partial cell in its read buffer: if
+                // that grew, we're still making progress.
+                let now_buffered = tls.read_buffer().len();
+                if now_buffered != n_buffered {
+                    n_buffered = now_buffered;
+                    deadline = Box::pin(sleep_fn(timeout));
+                }
+                deadline.as_mut().poll(cx).map(Either::Right)
+            });
+            let m = match next.await {
+                Either::Left(Some(m)) => m,
+                Either::Left(None) => break,
+                Either::Right(()) => {
+                    debug!("{}: timed out waiting for {}", self.unique_id, phase);
+                    return Err(Error::HandshakePhaseTimeout(phase));
+                }
+            };
             let (_, m) = m.map_err(codec_err_to_handshake)?.into_circid_and_msg();
             trace!("{}: received a {} cell.", self.unique_id, m.cmd());
             match m {
@@ -241,6 +390,42 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> OutboundClientHandshake
     }
 }
 
+/// Read enough bytes from `r` to fill `buf`, giving up with
+/// [`Error::HandshakePhaseTimeout`] if we ever wait longer than `timeout`
+/// without receiving anything.
+///
+/// If we didn't time out, return the outcome of the reads themselves: an
+/// `UnexpectedEof` error if the stream closed early, or any other IO error.
+async fn read_exact_until_stalled<R, F, S>(
+    r: &mut R,
+    buf: &mut [u8],
+    phase: HandshakePhase,
+    timeout: Duration,
+    sleep_fn: &mut F,
+) -> Result<std::io::Result<()>>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(Duration) -> S,
+    S: Future<Output = ()>,
+{
+    let mut n_read = 0;
+    while n_read < buf.len() {
+        // Every time we receive something, the timer starts over.
+        let deadline = sleep_fn(timeout);
+        futures::pin_mut!(deadline);
+        match future::select(r.read(&mut buf[n_read..]), deadline).await {
+            Either::Left((Ok(0), _)) => {
+                return Ok(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            Either::Left((Ok(n), _)) => n_read += n,
+            Either::Left((Err(e), _)) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Either::Left((Err(e), _)) => return Ok(Err(e)),
+            Either::Right(((), _)) => return Err(Error::HandshakePhaseTimeout(phase)),
+        }
+    }
+    Ok(Ok(()))
+}
+
 impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
     /// Validate the certificates and keys in the relay's handshake.
     ///
@@ -262,14 +447,41 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         now: Option<std::time::SystemTime>,
     ) -> Result<VerifiedChannel<T>> {
         let peer_cert_sha256 = ll::d::Sha256::digest(peer_cert);
-        self.check_internal(peer, &peer_cert_sha256[..], now)
+        self.check_internal(
+            Some(peer.ed_identity()),
+            Some(peer.rsa_identity()),
+            &peer_cert_sha256[..],
+            now,
+        )
+    }
+
+    /// Validate the certificates and keys in a bridge's handshake.
+    ///
+    /// This is the same as `check`, except that we don't know the
+    /// bridge's Ed25519 identity in advance.  If `rsa_id` is provided,
+    /// the bridge must have that RSA identity; otherwise, we accept any
+    /// relay that can prove its identities at all.
+    ///
+    /// Afterwards, the channel's `peer_ed25519_id()` and `peer_rsa_id()`
+    /// tell us who the bridge turned out to be.
+    pub fn check_bridge(
+        self,
+        rsa_id: Option<&RsaIdentity>,
+        peer_cert: &[u8],
+        now: Option<std::time::SystemTime>,
+    ) -> Result<VerifiedChannel<T>> {
+        let peer_cert_sha256 = ll::d::Sha256::digest(peer_cert);
+        self.check_internal(None, rsa_id, &peer_cert_sha256[..], now)
     }
 
     /// Same as `check`, but takes the SHA256 hash of the peer certificate,
-    /// since that is all we use.
-    fn check_internal<U: ChanTarget + ?Sized>(
+    /// since that is all we use, and takes the identities that we expect
+    /// separately.  An identity of `None` means that we accept any
+    /// identity of that type.
+    fn check_internal(
         self,
-        peer: &U,
+        peer_ed: Option<&Ed25519Identity>,
+        peer_rsa: Option<&RsaIdentity>,
         peer_cert_sha256: &[u8],
         now: Option<std::time::SystemTime>,
     ) -> Result<VerifiedChannel<T>> {
@@ -278,15 +490,15 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         // We need to check the following lines of authentication:
         //
         // First, to bind the ed identity to the channel.
-        //    peer.ed_identity() matches the key in...
+        //    peer_ed (if given) matches the key in...
         //    IDENTITY_V_SIGNING cert, which signs...
         //    SIGNING_V_TLS_CERT cert, which signs peer_cert.
         //
         // Second, to bind the rsa identity to the ed identity:
-        //    peer.rsa_identity() matches the key in...
+        //    peer_rsa (if given) matches the key in...
         //    the x.509 RSA identity certificate (type 2), which signs...
         //    the RSA->Ed25519 crosscert (type 7), which signs...
-        //    peer.ed_identity().
+        //    the Ed25519 identity.
 
         let c = &self.certs_cell;
         /// Helper: get a cert from a Certs cell, and convert errors appropriately.
@@ -304,12 +516,21 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         let id_sk = get_cert(c, CertType::IDENTITY_V_SIGNING)?;
         let sk_tls = get_cert(c, CertType::SIGNING_V_TLS_CERT)?;
 
+        /// Helper: convert a certificate error into an Error that says
+        /// which certificate had the problem.
+        fn cert_err(tp: CertType) -> impl FnOnce(tor_cert::CertError) -> Error {
+            move |e| Error::HandshakeCertErr(tp, e)
+        }
+
         let mut sigs = Vec::new();
 
         // Part 1: validate ed25519 stuff.
 
         // Check the identity->signing cert
-        let (id_sk, id_sk_sig) = id_sk.check_key(&None)?.dangerously_split()?;
+        let (id_sk, id_sk_sig) = id_sk
+            .check_key(&None)
+            .and_then(|c| c.dangerously_split())
+            .map_err(cert_err(CertType::IDENTITY_V_SIGNING))?;
         sigs.push(&id_sk_sig);
         let id_sk = id_sk
             .check_valid_at_opt(now)
@@ -321,15 +542,17 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         })?;
 
         // Take the signing key from the identity->signing cert
-        let signing_key = id_sk.subject_key().as_ed25519().ok_or_else(|| {
-            Error::HandshakeProto("Bad key type in identity->signing cert".into())
-        })?;
+        let signing_key = id_sk
+            .subject_key()
+            .require_ed25519()
+            .map_err(cert_err(CertType::IDENTITY_V_SIGNING))?;
 
         // Now look at the signing->TLS cert and check it against the
         // peer certificate.
         let (sk_tls, sk_tls_sig) = sk_tls
-            .check_key(&Some(*signing_key))? // TODO(nickm): this is a bad interface
-            .dangerously_split()?;
+            .check_key(&Some(*signing_key)) // TODO(nickm): this is a bad interface
+            .and_then(|c| c.dangerously_split())
+            .map_err(cert_err(CertType::SIGNING_V_TLS_CERT))?;
         sigs.push(&sk_tls_sig);
         let sk_tls = sk_tls
             .check_valid_at_opt(now)
@@ -346,9 +569,18 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         // In theory we could build a list of _all_ the certificates here
         // and call pk::validate_all_sigs() instead, but that doesn't gain
         // any performance.
-        if !ll::pk::ed25519::validate_batch(&sigs[..]) {
-            return Err(Error::HandshakeProto(
-                "Invalid ed25519 signature in handshake".into(),
+        //
+        // If the batch fails, report the first certificate at fault.  (We
+        // pushed the identity->signing signature first, then the
+        // signing->TLS signature.)
+        if let Err(bad) = ll::pk::ed25519::validate_batch_or_find_invalid(&sigs[..]) {
+            let tp = match bad.first() {
+                Some(0) => CertType::IDENTITY_V_SIGNING,
+                _ => CertType::SIGNING_V_TLS_CERT,
+            };
+            return Err(Error::HandshakeCertErr(
+                tp,
+                tor_cert::CertError::BadSignature,
             ));
         }
 
@@ -376,7 +608,7 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
             .ok_or_else(|| Error::HandshakeProto("No RSA->Ed crosscert".into()))?;
         let rsa_cert = tor_cert::rsa::RsaCrosscert::decode(rsa_cert)?
             .check_signature(&pkrsa)
-            .map_err(|_| Error::HandshakeProto("Bad RSA->Ed crosscert signature".into()))?
+            .map_err(cert_err(CertType::RSA_ID_V_IDENTITY))?
             .check_valid_at_opt(now)
             .map_err(|_| Error::HandshakeProto("RSA->Ed crosscert expired or invalid".into()))?;
 
@@ -403,13 +635,13 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> UnverifiedChannel<T> {
         // We do this _last_, since "this is the wrong peer" is
         // usually a different situation than "this peer couldn't even
         // identify itself right."
-        if *peer.ed_identity() != ed25519_id {
+        if matches!(peer_ed, Some(id) if *id != ed25519_id) {
             return Err(Error::HandshakeProto(
                 "Peer ed25519 id not as expected".into(),
             ));
         }
 
-        if *peer.rsa_identity() != rsa_id {
+        if matches!(peer_rsa, Some(id) if *id != rsa_id) {
             return Err(Error::HandshakeProto("Peer RSA id not as expected".into()));
         }
 
@@ -440,6 +672,10 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> VerifiedChannel<T> {
         // time to be no earlier than _that_ timestamp.
         crate::note_incoming_traffic();
         trace!("{}: Sending netinfo cell.", self.unique_id);
+        // TODO: We send our first cell on the channel as soon as this
+        // NETINFO cell is out.  Check whether C tor's clients wait in a way
+        // that an observer could use to tell us apart, and match them if
+        // they do.
         let netinfo = msg::Netinfo::for_client(self.target_addr.as_ref().map(SocketAddr::ip));
         self.tls
             .send(netinfo.into())
@@ -632,6 +868,82 @@ pub(super) mod test {
         });
     }
 
+    #[test]
+    fn connect_phase_timeouts() {
+        use tor_rtcompat::{BlockOn, SleepProvider};
+        use tor_rtmock::{io::stream_pair, MockRuntime};
+
+        let secs = Duration::from_secs;
+        let timeouts = HandshakeTimeouts::new(secs(5), secs(20), secs(30));
+
+        let mut netinfo = Vec::new();
+        add_netinfo(&mut netinfo);
+        let mut before_netinfo = VERSIONS.to_vec();
+        before_netinfo.extend_from_slice(NOCERTS);
+        let vpadding_forever = VPADDING.repeat(100);
+
+        // Each case is: what the relay sends at once, what it then
+        // trickles out at one byte per second before it stalls, and the
+        // phase and time at which we should give up.  Every byte we get
+        // starts the timer over, so we give up one timeout after the last
+        // byte arrives.
+        let n_versions = VERSIONS.len() as u64;
+        let cases: [(&[u8], &[u8], HandshakePhase, u64); 5] = [
+            (&[], &[], HandshakePhase::Versions, 5),
+            (&[], &VERSIONS[..3], HandshakePhase::Versions, 2 + 5),
+            // The VERSIONS cell takes longer than its timeout to arrive,
+            // but it keeps coming, so we only give up after it's done.
+            (&[], VERSIONS, HandshakePhase::Certs, n_versions - 1 + 20),
+            (
+                VERSIONS,
+                &vpadding_forever[..40],
+                HandshakePhase::Certs,
+                39 + 20,
+            ),
+            (
+                &before_netinfo,
+                &netinfo[..10],
+                HandshakePhase::Netinfo,
+                9 + 30,
+            ),
+        ];
+
+        for (prefix, trickle, phase, when) in cases.iter() {
+            // The mock runtime only lets time pass when neither side can
+            // make progress, so this runs instantly and the same way
+            // every time.
+            let rt = MockRuntime::default();
+            rt.block_on(async {
+                let (local, mut remote) = stream_pair();
+                remote.write_all(prefix).await.unwrap();
+
+                let start = rt.now();
+                let handshake = OutboundClientHandshake::new(local, None);
+                let handshake_fut = async {
+                    let result = handshake
+                        .connect_with_timeouts(timeouts.clone(), |d| rt.sleep(d))
+                        .await;
+                    (result, rt.now())
+                };
+                let relay_fut = async {
+                    for idx in 0..90 {
+                        if let Some(byte) = trickle.get(idx) {
+                            // This fails once the handshake gives up.
+                            let _ = remote.write_all(&[*byte]).await;
+                        }
+                        rt.sleep(secs(1)).await;
+                    }
+                    remote
+                };
+                let ((result, finished), _remote) = futures::join!(handshake_fut, relay_fut);
+
+                let err = result.err().unwrap();
+                assert!(matches!(err, Error::HandshakePhaseTimeout(p) if p == *phase));
+                assert_eq!(finished - start, secs(*when));
+            });
+        }
+    }
+
     fn make_unverified(certs: msg::Certs) -> UnverifiedChannel<MsgBuf> {
         let localhost = std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
         let netinfo_cell = msg::Netinfo::for_client(Some(localhost));
@@ -645,22 +957,6 @@ pub(super) mod test {
         }
     }
 
-    struct DummyChanTarget {
-        ed: Ed25519Identity,
-        rsa: RsaIdentity,
-    }
-    impl ChanTarget for DummyChanTarget {
-        fn addrs(&self) -> &[SocketAddr] {
-            &[]
-        }
-        fn ed_identity(&self) -> &Ed25519Identity {
-            &self.ed
-        }
-        fn rsa_identity(&self) -> &RsaIdentity {
-            &self.rsa
-        }
-    }
-
     // Timestamp when the example certificates were all valid.
     fn cert_timestamp() -> SystemTime {
         SystemTime::UNIX_EPOCH + Duration::new(1601143280, 0)
@@ -676,8 +972,7 @@ pub(super) mod test {
         let unver = make_unverified(certs);
         let ed = Ed25519Identity::from_bytes(peer_ed).unwrap();
         let rsa = RsaIdentity::from_bytes(peer_rsa).unwrap();
-        let chan = DummyChanTarget { ed, rsa };
-        unver.check_internal(&chan, peer_cert_sha256, when)
+        unver.check_internal(Some(&ed), Some(&rsa), peer_cert_sha256, when)
     }
 
     // no certs at all!
@@ -807,6 +1102,57 @@ pub(super) mod test {
         );
     }
 
+    #[test]
+    fn certs_bridge() {
+        let mut certs = msg::Certs::new_empty();
+        certs.push_cert_body(2.into(), certs::CERT_T2);
+        certs.push_cert_body(5.into(), certs::CERT_T5);
+        certs.push_cert_body(7.into(), certs::CERT_T7);
+        certs.push_cert_body(4.into(), certs::CERT_T4);
+        let rsa = RsaIdentity::from_bytes(certs::PEER_RSA).unwrap();
+
+        // Without an expected identity, we learn who the peer is.
+        let ver = make_unverified(certs.clone())
+            .check_internal(None, None, certs::PEER_CERT_DIGEST, Some(cert_timestamp()))
+            .unwrap();
+        assert_eq!(ver.ed25519_id.as_bytes(), certs::PEER_ED);
+        assert_eq!(ver.rsa_id, rsa);
+
+        // With an RSA identity, it has to match.
+        let ver = make_unverified(certs.clone())
+            .check_internal(
+                None,
+                Some(&rsa),
+                certs::PEER_CERT_DIGEST,
+                Some(cert_timestamp()),
+            )
+            .unwrap();
+        assert_eq!(ver.ed25519_id.as_bytes(), certs::PEER_ED);
+        let err = make_unverified(certs.clone())
+            .check_internal(
+                None,
+                Some(&[0x99; 20].into()),
+                certs::PEER_CERT_DIGEST,
+                Some(cert_timestamp()),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "handshake protocol violation: Peer RSA id not as expected"
+        );
+
+        // The certificates still have to authenticate the TLS connection.
+        let err = make_unverified(certs)
+            .check_internal(None, None, &[0; 32], Some(cert_timestamp()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "handshake protocol violation: Peer cert did not authenticate TLS cert"
+        );
+    }
+
     #[test]
     fn certs_badsig() {
         fn munge(inp: &[u8]) -> Vec<u8> {
@@ -831,7 +1177,7 @@ pub(super) mod test {
 
         assert_eq!(
             format!("{}", res),
-            "handshake protocol violation: Invalid ed25519 signature in handshake"
+            "invalid SIGNING_V_TLS_CERT certificate in handshake: Invalid signature on certificate"
         );
 
         let mut certs = msg::Certs::new_empty();
@@ -851,7 +1197,15 @@ pub(super) mod test {
 
         assert_eq!(
             format!("{}", res),
-            "handshake protocol violation: Bad RSA->Ed crosscert signature"
+            "invalid RSA_ID_V_IDENTITY certificate in handshake: Invalid signature on certificate"
+        );
+        assert!(matches!(
+            res,
+            Error::HandshakeCertErr(_, tor_cert::CertError::BadSignature)
+        ));
+        assert_eq!(
+            tor_error::HasKind::kind(&res),
+            tor_error::ErrorKind::TorAccessFailed
         );
     }
 
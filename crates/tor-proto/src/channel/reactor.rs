@@ -11,7 +11,7 @@ use crate::circuit::halfcirc::HalfCirc;
 use crate::util::err::ReactorError;
 use crate::{Error, Result};
 use tor_cell::chancell::msg::{Destroy, DestroyReason};
-use tor_cell::chancell::{msg::ChanMsg, ChanCell, CircId};
+use tor_cell::chancell::{msg::ChanMsg, ChanCell, ChanCmd, CircId};
 
 use futures::channel::{mpsc, oneshot};
 
@@ -29,7 +29,7 @@ use std::task::Poll;
 
 use crate::channel::{codec::CodecError, unique_id, ChannelDetails};
 use crate::circuit::celltypes::{ClientCircChanMsg, CreateResponse};
-use tracing::{debug, trace};
+use tracing::{debug, info, trace};
 
 /// A boxed trait object that can provide `ChanCell`s.
 pub(super) type BoxedChannelStream =
@@ -49,6 +49,19 @@ fn codec_err_to_chan(err: CodecError) -> Error {
     }
 }
 
+/// Counts of cells that a channel's reactor has rejected as protocol
+/// violations.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct RejectedCellCounts {
+    /// CREATE, CREATE_FAST, and CREATE2 cells received on a channel that we
+    /// initiated.
+    pub create_cells: u64,
+    /// RELAY cells for circuit IDs in the half of the ID space that we never
+    /// allocate from.
+    pub wrong_circid_range: u64,
+}
+
 /// A message telling the channel reactor to do something.
 #[derive(Debug)]
 pub(super) enum CtrlMsg {
@@ -257,10 +270,21 @@ impl Reactor {
         }
 
         match msg {
+            // Only the responder on a channel may be asked to create
+            // circuits, and we're always the initiator.
+            Create(_) | CreateFast(_) | Create2(_) => {
+                self.details
+                    .n_create_cells_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                self.log_rejected_cell(msg.cmd(), circid);
+                Err(Error::CreateOnClientChannel(msg.cmd()))
+            }
+
             // These aren't allowed on clients.
-            Create(_) | CreateFast(_) | Create2(_) | RelayEarly(_) | PaddingNegotiate(_) => Err(
-                Error::ChanProto(format!("{} cell on client channel", msg.cmd())),
-            ),
+            RelayEarly(_) | PaddingNegotiate(_) => Err(Error::ChanProto(format!(
+                "{} cell on client channel",
+                msg.cmd()
+            ))),
 
             // In theory this is allowed in clients, but we should never get
             // one, since we don't use TAP.
@@ -276,6 +300,17 @@ impl Reactor {
                 msg.cmd()
             ))),
 
+            // We only ever allocate circuit IDs from our own half of the ID
+            // space, so a relay cell for any other ID can't be for one of
+            // our circuits.
+            Relay(_) if !self.circs.allocates(circid) => {
+                self.details
+                    .n_wrong_circid_range_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                self.log_rejected_cell(msg.cmd(), circid);
+                Err(Error::WrongCircIdRange(circid))
+            }
+
             // These are allowed, and need to be handled.
             Relay(_) => self.deliver_relay(circid, msg).await,
 
@@ -295,6 +330,15 @@ impl Reactor {
         }
     }
 
+    /// Log that our peer sent us a `cmd` cell for `circid`, which no
+    /// well-behaved relay would do, and that we're closing the channel.
+    fn log_rejected_cell(&self, cmd: ChanCmd, circid: CircId) {
+        info!(
+            "{}: Relay {} ({}) sent a {} cell for circuit {}; closing channel.",
+            &self, self.details.ed25519_id, self.details.rsa_id, cmd, circid
+        );
+    }
+
     /// Give the RELAY cell `msg` to the appropriate circuit.
     async fn deliver_relay(&mut self, circid: CircId, msg: ChanMsg) -> Result<()> {
         let mut ent = self
@@ -420,6 +464,11 @@ pub(crate) mod test {
 
     type CodecResult = std::result::Result<ChanCell, CodecError>;
 
+    /// Return a circuit ID in the range that a client channel allocates.
+    fn client_circid(n: u32) -> CircId {
+        CircId::from(0x8000_0000 | n)
+    }
+
     pub(crate) fn new_reactor() -> (
         crate::channel::Channel,
         Reactor,
@@ -597,6 +646,7 @@ pub(crate) mod test {
                 .unwrap();
 
             let e = reactor.run_once().await.unwrap_err().unwrap_err();
+            assert!(matches!(e, Error::CreateOnClientChannel(ChanCmd::CREATE2)));
             assert_eq!(
                 format!("{}", e),
                 "channel protocol violation: CREATE2 cell on client channel"
@@ -609,9 +659,9 @@ pub(crate) mod test {
             );
 
             // Can't get a relay cell on a circuit we've never heard of.
-            let relay_cell = msg::Relay::new(b"abc").into();
+            let relay_cell: ChanMsg = msg::Relay::new(b"abc").into();
             input
-                .send(Ok(ChanCell::new(4.into(), relay_cell)))
+                .send(Ok(ChanCell::new(client_circid(4), relay_cell.clone())))
                 .await
                 .unwrap();
             let e = reactor.run_once().await.unwrap_err().unwrap_err();
@@ -620,6 +670,18 @@ pub(crate) mod test {
                 "channel protocol violation: Relay cell on nonexistent circuit"
             );
 
+            // Nor on a circuit that only the other side could have created.
+            input
+                .send(Ok(ChanCell::new(4.into(), relay_cell)))
+                .await
+                .unwrap();
+            let e = reactor.run_once().await.unwrap_err().unwrap_err();
+            assert!(matches!(e, Error::WrongCircIdRange(id) if id == CircId::from(4)));
+            assert_eq!(
+                format!("{}", e),
+                "channel protocol violation: cell for circuit 4, which is not in our range of circuit IDs"
+            );
+
             // Can't get handshaking cells while channel is open.
             let versions_cell = msg::Versions::new([3]).unwrap().into();
             input
@@ -646,6 +708,44 @@ pub(crate) mod test {
         });
     }
 
+    // Make sure that rejected cells get counted.
+    #[test]
+    fn rejected_cells_counted() {
+        tor_rtcompat::test_with_all_runtimes!(|_rt| async move {
+            use tor_cell::chancell::msg;
+            let (chan, mut reactor, _output, mut input) = new_reactor();
+            assert_eq!(chan.rejected_cell_counts(), RejectedCellCounts::default());
+
+            let create_cell = msg::CreateFast::new(*b"12345678901234567890").into();
+            input
+                .send(Ok(ChanCell::new(client_circid(9), create_cell)))
+                .await
+                .unwrap();
+            let e = reactor.run_once().await.unwrap_err().unwrap_err();
+            assert!(matches!(
+                e,
+                Error::CreateOnClientChannel(ChanCmd::CREATE_FAST)
+            ));
+            let counts = chan.rejected_cell_counts();
+            assert_eq!(counts.create_cells, 1);
+            assert_eq!(counts.wrong_circid_range, 0);
+
+            let (chan2, mut reactor, _output, mut input) = new_reactor();
+            let relay_cell = msg::Relay::new(b"abc").into();
+            input
+                .send(Ok(ChanCell::new(0x7fff_ffff.into(), relay_cell)))
+                .await
+                .unwrap();
+            let e = reactor.run_once().await.unwrap_err().unwrap_err();
+            assert!(matches!(e, Error::WrongCircIdRange(_)));
+            let counts2 = chan2.rejected_cell_counts();
+            assert_eq!(counts2.create_cells, 0);
+            assert_eq!(counts2.wrong_circid_range, 1);
+            // The first channel's counts are its own.
+            assert_eq!(chan.rejected_cell_counts(), counts);
+        });
+    }
+
     #[test]
     fn deliver_relay() {
         tor_rtcompat::test_with_all_runtimes!(|_rt| async move {
@@ -660,14 +760,16 @@ pub(crate) mod test {
                 let (snd2, rcv2) = mpsc::channel(64);
                 reactor
                     .circs
-                    .put_unchecked(7.into(), CircEnt::Opening(snd1, snd2));
+                    .put_unchecked(client_circid(7), CircEnt::Opening(snd1, snd2));
 
                 let (snd3, rcv3) = mpsc::channel(64);
-                reactor.circs.put_unchecked(13.into(), CircEnt::Open(snd3));
+                reactor
+                    .circs
+                    .put_unchecked(client_circid(13), CircEnt::Open(snd3));
 
                 reactor
                     .circs
-                    .put_unchecked(23.into(), CircEnt::DestroySent(HalfCirc::new(25)));
+                    .put_unchecked(client_circid(23), CircEnt::DestroySent(HalfCirc::new(25)));
                 (rcv2, rcv3)
             };
 
@@ -675,7 +777,7 @@ pub(crate) mod test {
             // should get it.
             let relaycell: ChanMsg = msg::Relay::new(b"do you suppose").into();
             input
-                .send(Ok(ChanCell::new(13.into(), relaycell.clone())))
+                .send(Ok(ChanCell::new(client_circid(13), relaycell.clone())))
                 .await
                 .unwrap();
             reactor.run_once().await.unwrap();
@@ -684,7 +786,7 @@ pub(crate) mod test {
 
             // If a relay cell is sent on an opening channel, that's an error.
             input
-                .send(Ok(ChanCell::new(7.into(), relaycell.clone())))
+                .send(Ok(ChanCell::new(client_circid(7), relaycell.clone())))
                 .await
                 .unwrap();
             let e = reactor.run_once().await.unwrap_err().unwrap_err();
@@ -695,7 +797,7 @@ pub(crate) mod test {
 
             // If a relay cell is sent on a non-existent channel, that's an error.
             input
-                .send(Ok(ChanCell::new(101.into(), relaycell.clone())))
+                .send(Ok(ChanCell::new(client_circid(101), relaycell.clone())))
                 .await
                 .unwrap();
             let e = reactor.run_once().await.unwrap_err().unwrap_err();
@@ -710,7 +812,7 @@ pub(crate) mod test {
             // We can do this 25 more times according to our setup:
             for _ in 0..25 {
                 input
-                    .send(Ok(ChanCell::new(23.into(), relaycell.clone())))
+                    .send(Ok(ChanCell::new(client_circid(23), relaycell.clone())))
                     .await
                     .unwrap();
                 reactor.run_once().await.unwrap(); // should be fine.
@@ -718,7 +820,7 @@ pub(crate) mod test {
 
             // This one will fail.
             input
-                .send(Ok(ChanCell::new(23.into(), relaycell.clone())))
+                .send(Ok(ChanCell::new(client_circid(23), relaycell.clone())))
                 .await
                 .unwrap();
             let e = reactor.run_once().await.unwrap_err().unwrap_err();
@@ -49,8 +49,9 @@ mod unique_id;
 use crate::channel::Channel;
 use crate::circuit::celltypes::*;
 use crate::circuit::reactor::{
-    CircuitHandshake, CtrlMsg, Reactor, RECV_WINDOW_INIT, STREAM_READER_BUFFER,
+    CircuitHandshake, CtrlMsg, Reactor, STREAM_READER_BUFFER, STREAM_WINDOW_INIT,
 };
+pub use crate::circuit::streammap::{ClosedReason, StreamStats};
 pub use crate::circuit::unique_id::UniqId;
 use crate::crypto::cell::{HopNum, InboundClientCrypt, OutboundClientCrypt};
 use crate::stream::{DataStream, ResolveStream, StreamParameters, StreamReader};
@@ -64,8 +65,9 @@ use tor_error::{bad_api_usage, internal};
 use tor_linkspec::{CircTarget, LinkSpec};
 
 use futures::channel::{mpsc, oneshot};
+use futures::future::{FutureExt, Shared};
 
-use crate::circuit::sendme::StreamRecvWindow;
+use crate::circuit::sendme::{StreamRecvWindow, StreamSendWindow};
 use futures::SinkExt;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU8, Ordering};
@@ -80,6 +82,11 @@ use self::reactor::RequireSendmeAuth;
 /// The size of the buffer for communication between `ClientCirc` and its reactor.
 pub const CIRCUIT_BUFFER_SIZE: usize = 128;
 
+/// The scheduling priority for streams that don't ask for another one.
+///
+/// (This is the same as the default in [`StreamParameters`].)
+const DEFAULT_STREAM_PRIORITY: u8 = 0;
+
 #[derive(Clone, Debug)]
 /// A circuit that we have constructed over the Tor network.
 ///
@@ -104,10 +111,19 @@ pub struct ClientCirc {
     ///
     /// This value is incremented after the circuit successfully completes extending to a new hop.
     hops: Arc<AtomicU8>,
+    /// Number of RELAY_EARLY cells that we may still send on this circuit.
+    ///
+    /// This value is set from the [`CircParameters`] when we create the first
+    /// hop, and decremented by the reactor whenever it sends a RELAY_EARLY cell.
+    relay_early_left: Arc<AtomicU8>,
     /// A unique identifier for this circuit.
     unique_id: UniqId,
     /// Channel to send control messages to the reactor.
     control: mpsc::UnboundedSender<CtrlMsg>,
+    /// A future that resolves when the reactor exits.
+    ///
+    /// (The reactor never sends on this channel; it just drops its end.)
+    reactor_closed_rx: Shared<oneshot::Receiver<std::convert::Infallible>>,
     /// For testing purposes: the CircId, for use in peek_circid().
     #[cfg(test)]
     circid: CircId,
@@ -125,21 +141,34 @@ pub struct PendingClientCirc {
     circ: ClientCirc,
 }
 
+/// The number of RELAY_EARLY cells that Tor relays allow on a single circuit.
+///
+/// Every EXTEND2 cell has to be sent in a RELAY_EARLY cell, so this limits the
+/// number of times we can extend a circuit.
+pub(crate) const DEFAULT_RELAY_EARLY_BUDGET: u8 = 8;
+
 /// Description of the network's current rules for building circuits.
 #[derive(Clone, Debug)]
 pub struct CircParameters {
-    /// Initial value to use for our outbound circuit-level windows.
-    initial_send_window: u16,
+    /// Flow-control window parameters for this circuit and its streams.
+    congestion: CongestionParams,
     /// Whether we should include ed25519 identities when we send
     /// EXTEND2 cells.
     extend_by_ed25519_id: bool,
+    /// If present, the first stream ID that a hop built with these
+    /// parameters should try to allocate.
+    initial_stream_id: Option<u16>,
+    /// How many RELAY_EARLY cells we may send on a circuit.
+    relay_early_budget: u8,
 }
 
 impl Default for CircParameters {
     fn default() -> CircParameters {
         CircParameters {
-            initial_send_window: 1000,
+            congestion: CongestionParams::default(),
             extend_by_ed25519_id: true,
+            initial_stream_id: None,
+            relay_early_budget: DEFAULT_RELAY_EARLY_BUDGET,
         }
     }
 }
@@ -150,19 +179,12 @@ impl CircParameters {
     ///
     /// You should probably not call this.
     pub fn set_initial_send_window(&mut self, v: u16) -> Result<()> {
-        if v <= 1000 {
-            self.initial_send_window = v;
-            Ok(())
-        } else {
-            Err(Error::from(bad_api_usage!(
-                "Tried to set an initial send window over 1000"
-            )))
-        }
+        self.congestion.set_circ_window(v)
     }
 
     /// Return the initial send window as set in this parameter set.
     pub fn initial_send_window(&self) -> u16 {
-        self.initial_send_window
+        self.congestion.circ_window()
     }
 
     /// Override the default decision about whether to use ed25519
@@ -178,6 +200,159 @@ impl CircParameters {
     pub fn extend_by_ed25519_id(&self) -> bool {
         self.extend_by_ed25519_id
     }
+
+    /// Make hops built with these parameters start allocating stream IDs
+    /// at `v`, rather than at a random ID.
+    ///
+    /// This is for resuming a circuit's streams on a new circuit: passing
+    /// the value from [`ClientCirc::stream_id_allocator_state`] keeps us from
+    /// reusing stream IDs that the peer might still remember.  Gives an
+    /// error if `v` is 0, since that isn't a valid stream ID.
+    #[allow(dead_code)] // Stream resumption code will need this.
+    pub(crate) fn set_initial_stream_id(&mut self, v: u16) -> Result<()> {
+        if v == 0 {
+            return Err(bad_api_usage!("Tried to start allocating stream IDs at 0").into());
+        }
+        self.initial_stream_id = Some(v);
+        Ok(())
+    }
+
+    /// Return the first stream ID that hops built with these parameters
+    /// should allocate, if one was set.
+    pub(crate) fn initial_stream_id(&self) -> Option<u16> {
+        self.initial_stream_id
+    }
+
+    /// Override the number of RELAY_EARLY cells that we may send on a
+    /// circuit built with these parameters.
+    ///
+    /// Every extension uses one RELAY_EARLY cell.  The default is 8, which
+    /// is what Tor relays allow; you should only change it for test
+    /// networks.
+    pub fn set_relay_early_budget(&mut self, v: u8) {
+        self.relay_early_budget = v;
+    }
+
+    /// Return the number of RELAY_EARLY cells that we may send on a circuit
+    /// built with these parameters.
+    pub fn relay_early_budget(&self) -> u8 {
+        self.relay_early_budget
+    }
+
+    /// Override the flow-control window parameters for these parameters.
+    ///
+    /// You should probably not call this.
+    pub fn set_congestion_params(&mut self, v: CongestionParams) {
+        self.congestion = v;
+    }
+
+    /// Return the flow-control window parameters in this parameter set.
+    pub fn congestion_params(&self) -> &CongestionParams {
+        &self.congestion
+    }
+}
+
+/// Flow-control window sizes for a circuit and the streams on it.
+///
+/// The defaults are the values that Tor has always used.  They're
+/// gathered here so that experiments (and, eventually, consensus
+/// parameters) can change them without editing every call site.
+#[derive(Clone, Debug)]
+pub struct CongestionParams {
+    /// Initial value for stream-level receive windows.
+    ///
+    /// Our stream-level send windows always start at
+    /// [`STREAM_WINDOW_INIT`], since that is all that the protocol lets
+    /// us send before the other side acknowledges some cells.
+    stream_window: u16,
+    /// Initial value for our outbound circuit-level window.
+    circ_window: u16,
+    /// Number of cells acknowledged by each stream-level SENDME.
+    stream_increment: u16,
+}
+
+impl Default for CongestionParams {
+    fn default() -> Self {
+        CongestionParams {
+            stream_window: STREAM_WINDOW_INIT,
+            circ_window: 1000,
+            stream_increment: 50,
+        }
+    }
+}
+
+impl CongestionParams {
+    /// Override the initial receive window for streams.
+    /// Gives an error on any value below 500 (the protocol default), or
+    /// below the stream SENDME increment.
+    ///
+    /// You should probably not call this.
+    pub fn set_stream_window(&mut self, v: u16) -> Result<()> {
+        if v < STREAM_WINDOW_INIT || v < self.stream_increment {
+            return Err(Error::from(bad_api_usage!(
+                "Tried to set a stream window of {}",
+                v
+            )));
+        }
+        self.stream_window = v;
+        Ok(())
+    }
+
+    /// Return the initial receive window for streams.
+    pub fn stream_window(&self) -> u16 {
+        self.stream_window
+    }
+
+    /// Override the initial outbound window for circuits.
+    /// Gives an error on any value above 1000.
+    ///
+    /// You should probably not call this.
+    pub fn set_circ_window(&mut self, v: u16) -> Result<()> {
+        if v <= 1000 {
+            self.circ_window = v;
+            Ok(())
+        } else {
+            Err(Error::from(bad_api_usage!(
+                "Tried to set an initial send window over 1000"
+            )))
+        }
+    }
+
+    /// Return the initial outbound window for circuits.
+    pub fn circ_window(&self) -> u16 {
+        self.circ_window
+    }
+
+    /// Override the number of cells acknowledged by each stream-level
+    /// SENDME.  Gives an error on zero, or on any value above the initial
+    /// stream window.
+    ///
+    /// You should probably not call this.
+    pub fn set_stream_increment(&mut self, v: u16) -> Result<()> {
+        if v == 0 || v > self.stream_window {
+            return Err(Error::from(bad_api_usage!(
+                "Tried to set a stream SENDME increment of {}",
+                v
+            )));
+        }
+        self.stream_increment = v;
+        Ok(())
+    }
+
+    /// Return the number of cells acknowledged by each stream-level SENDME.
+    pub fn stream_increment(&self) -> u16 {
+        self.stream_increment
+    }
+
+    /// Construct a new send window for a stream using these parameters.
+    pub(crate) fn new_stream_send_window(&self) -> StreamSendWindow {
+        StreamSendWindow::new_with_increment(STREAM_WINDOW_INIT, self.stream_increment)
+    }
+
+    /// Construct a new receive window for a stream using these parameters.
+    pub(crate) fn new_stream_recv_window(&self) -> StreamRecvWindow {
+        StreamRecvWindow::new_with_increment(self.stream_window, self.stream_increment)
+    }
 }
 
 /// A stream on a particular circuit.
@@ -193,6 +368,21 @@ pub(crate) struct StreamTarget {
     circ: ClientCirc,
 }
 
+/// A reference to a stream on a particular circuit, used to ask the
+/// circuit's reactor about the stream.
+///
+/// Unlike a [`StreamTarget`], this can't send anything on the stream, and
+/// holding it doesn't keep the stream open.
+#[derive(Clone, Debug)]
+pub(crate) struct StreamHandle {
+    /// Which hop of the circuit this stream is with.
+    hop_num: HopNum,
+    /// Reactor ID for this stream.
+    stream_id: StreamId,
+    /// Reference to the circuit that this stream is on.
+    circ: ClientCirc,
+}
+
 impl ClientCirc {
     /// Extend the circuit via the ntor handshake to a new target last
     /// hop.
@@ -228,6 +418,70 @@ impl ClientCirc {
         Ok(())
     }
 
+    /// Return the byte counters for the stream `stream_id` on the hop
+    /// `hop_num`, if it is open, half-closed, or ended by the other side.
+    pub(crate) async fn stream_stats(
+        &self,
+        hop_num: HopNum,
+        stream_id: StreamId,
+    ) -> Result<Option<StreamStats>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::QueryStreamStats {
+                hop_num,
+                stream_id,
+                done: tx,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return the reason why the stream `stream_id` on the hop `hop_num`
+    /// stopped being open, if it has been closed recently.
+    pub(crate) async fn stream_closed_reason(
+        &self,
+        hop_num: HopNum,
+        stream_id: StreamId,
+    ) -> Result<Option<ClosedReason>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::QueryClosedReason {
+                hop_num,
+                stream_id,
+                done: tx,
+            })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return the next stream ID that the last hop of this circuit will
+    /// try to allocate.
+    ///
+    /// This value can be passed to
+    /// [`CircParameters::set_initial_stream_id`] when building a
+    /// replacement circuit.
+    #[allow(dead_code)] // Stream resumption code will need this.
+    pub(crate) async fn stream_id_allocator_state(&self) -> Result<u16> {
+        let num_hops = self.hops.load(Ordering::SeqCst);
+        if num_hops == 0 {
+            return Err(Error::from(internal!(
+                "Can't get the allocator state of the 0th hop"
+            )));
+        }
+        let hop_num: HopNum = (num_hops - 1).into();
+        let (tx, rx) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::QueryAllocatorState { hop_num, done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Helper, used to begin a stream.
     ///
     /// This function allocates a stream ID, and sends the message
@@ -235,7 +489,11 @@ impl ClientCirc {
     ///
     /// The caller will typically want to see the first cell in response,
     /// to see whether it is e.g. an END or a CONNECTED.
-    async fn begin_stream_impl(&self, begin_msg: RelayMsg) -> Result<(StreamReader, StreamTarget)> {
+    async fn begin_stream_impl(
+        &self,
+        begin_msg: RelayMsg,
+        priority: u8,
+    ) -> Result<(StreamReader, StreamTarget)> {
         // TODO: Possibly this should take a hop, rather than just
         // assuming it's the last hop.
 
@@ -245,6 +503,13 @@ impl ClientCirc {
                 "Can't begin a stream at the 0th hop"
             )));
         }
+        if num_hops == 1 && !matches!(begin_msg, RelayMsg::BeginDir) {
+            // A one-hop circuit goes straight to a relay that knows who we
+            // are: never use it for anything but directory traffic.
+            return Err(Error::from(bad_api_usage!(
+                "Tried to open a non-directory stream on a one-hop circuit"
+            )));
+        }
         let hop_num: HopNum = (num_hops - 1).into();
         let (sender, receiver) = mpsc::channel(STREAM_READER_BUFFER);
         let (tx, rx) = oneshot::channel();
@@ -256,11 +521,12 @@ impl ClientCirc {
                 message: begin_msg,
                 sender,
                 rx: msg_rx,
+                priority,
                 done: tx,
             })
             .map_err(|_| Error::CircuitClosed)?;
 
-        let stream_id = rx.await.map_err(|_| Error::CircuitClosed)??;
+        let (stream_id, recv_window) = rx.await.map_err(|_| Error::CircuitClosed)??;
 
         let target = StreamTarget {
             circ: self.clone(),
@@ -272,7 +538,7 @@ impl ClientCirc {
         let reader = StreamReader {
             target: target.clone(),
             receiver,
-            recv_window: StreamRecvWindow::new(RECV_WINDOW_INIT),
+            recv_window,
             ended: false,
         };
 
@@ -281,8 +547,13 @@ impl ClientCirc {
 
     /// Start a DataStream (anonymized connection) to the given
     /// address and port, using a BEGIN cell.
-    async fn begin_data_stream(&self, msg: RelayMsg, optimistic: bool) -> Result<DataStream> {
-        let (reader, target) = self.begin_stream_impl(msg).await?;
+    async fn begin_data_stream(
+        &self,
+        msg: RelayMsg,
+        optimistic: bool,
+        priority: u8,
+    ) -> Result<DataStream> {
+        let (reader, target) = self.begin_stream_impl(msg, priority).await?;
         let mut stream = DataStream::new(reader, target);
         if !optimistic {
             stream.wait_for_connection().await?;
@@ -304,8 +575,10 @@ impl ClientCirc {
         let parameters = parameters.unwrap_or_default();
         let begin_flags = parameters.begin_flags();
         let optimistic = parameters.is_optimistic();
+        let priority = parameters.get_priority();
         let beginmsg = Begin::new(target, port, begin_flags)?;
-        self.begin_data_stream(beginmsg.into(), optimistic).await
+        self.begin_data_stream(beginmsg.into(), optimistic, priority)
+            .await
     }
 
     /// Start a new stream to the last relay in the circuit, using
@@ -315,7 +588,8 @@ impl ClientCirc {
         // Since they are local to a relay that we've already authenticated
         // with and built a circuit to, there should be no additional checks
         // we need to perform to see whether the BEGINDIR will succeed.
-        self.begin_data_stream(RelayMsg::BeginDir, true).await
+        self.begin_data_stream(RelayMsg::BeginDir, true, DEFAULT_STREAM_PRIORITY)
+            .await
     }
 
     /// Perform a DNS lookup, using a RESOLVE cell with the last relay
@@ -324,6 +598,14 @@ impl ClientCirc {
     /// Note that this function does not check for timeouts; that's
     /// the caller's responsibility.
     pub async fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>> {
+        let answers = self.resolve_with_ttl(hostname).await?;
+        Ok(answers.into_iter().map(|(ip, _)| ip).collect())
+    }
+
+    /// Perform a DNS lookup, as with [`resolve`](ClientCirc::resolve), but
+    /// also return the TTL (in seconds) that the exit reported for each
+    /// address.
+    pub async fn resolve_with_ttl(&self, hostname: &str) -> Result<Vec<(IpAddr, u32)>> {
         let resolve_msg = Resolve::new(hostname);
 
         let resolved_msg = self.try_resolve(resolve_msg).await?;
@@ -331,8 +613,8 @@ impl ClientCirc {
         resolved_msg
             .into_answers()
             .into_iter()
-            .filter_map(|(val, _)| match resolvedval_to_result(val) {
-                Ok(ResolvedVal::Ip(ip)) => Some(Ok(ip)),
+            .filter_map(|(val, ttl)| match resolvedval_to_result(val) {
+                Ok(ResolvedVal::Ip(ip)) => Some(Ok((ip, ttl))),
                 Ok(_) => None,
                 Err(e) => Some(Err(e)),
             })
@@ -345,6 +627,14 @@ impl ClientCirc {
     /// Note that this function does not check for timeouts; that's
     /// the caller's responsibility.
     pub async fn resolve_ptr(&self, addr: IpAddr) -> Result<Vec<String>> {
+        let answers = self.resolve_ptr_with_ttl(addr).await?;
+        Ok(answers.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Perform a reverse DNS lookup, as with
+    /// [`resolve_ptr`](ClientCirc::resolve_ptr), but also return the TTL (in
+    /// seconds) that the exit reported for each hostname.
+    pub async fn resolve_ptr_with_ttl(&self, addr: IpAddr) -> Result<Vec<(String, u32)>> {
         let resolve_ptr_msg = Resolve::new_reverse(&addr);
 
         let resolved_msg = self.try_resolve(resolve_ptr_msg).await?;
@@ -352,9 +642,10 @@ impl ClientCirc {
         resolved_msg
             .into_answers()
             .into_iter()
-            .filter_map(|(val, _)| match resolvedval_to_result(val) {
+            .filter_map(|(val, ttl)| match resolvedval_to_result(val) {
                 Ok(ResolvedVal::Hostname(v)) => Some(
                     String::from_utf8(v)
+                        .map(|name| (name, ttl))
                         .map_err(|_| Error::StreamProto("Resolved Hostname was not utf-8".into())),
                 ),
                 Ok(_) => None,
@@ -366,7 +657,9 @@ impl ClientCirc {
     /// Helper: Send the resolve message, and read resolved message from
     /// resolve stream.
     async fn try_resolve(&self, msg: Resolve) -> Result<Resolved> {
-        let (reader, _) = self.begin_stream_impl(msg.into()).await?;
+        let (reader, _) = self
+            .begin_stream_impl(msg.into(), DEFAULT_STREAM_PRIORITY)
+            .await?;
         let mut resolve_stream = ResolveStream::new(reader);
         resolve_stream.read_msg().await
     }
@@ -385,6 +678,37 @@ impl ClientCirc {
         let _ = self.control.unbounded_send(CtrlMsg::Shutdown);
     }
 
+    /// Stop opening new streams on this circuit, so that it can close once
+    /// its existing streams are done.
+    ///
+    /// After this returns, every attempt to open a stream on this circuit
+    /// fails with [`Error::Draining`]; streams that are already open keep
+    /// working.  There is no way to undo this.
+    pub async fn begin_drain(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::BeginDrain { done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return true if [`begin_drain`](ClientCirc::begin_drain) has been
+    /// called on this circuit, and every stream on it has since closed.
+    ///
+    /// Once this is true, the circuit can be torn down without cutting
+    /// off anybody's stream.
+    pub async fn is_drained(&self) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+
+        self.control
+            .unbounded_send(CtrlMsg::QueryDrained { done: tx })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Called when a circuit-level protocol error has occurred and the
     /// circuit needs to shut down.
     ///
@@ -401,15 +725,36 @@ impl ClientCirc {
         self.control.is_closed()
     }
 
+    /// Return a future that will resolve once this circuit has closed.
+    ///
+    /// Note that this method does not itself cause the circuit to shut down.
+    pub fn wait_for_close(&self) -> impl futures::Future<Output = ()> + Send + Sync + 'static {
+        self.reactor_closed_rx.clone().map(|_| ())
+    }
+
     /// Return a process-unique identifier for this circuit.
     pub fn unique_id(&self) -> UniqId {
         self.unique_id
     }
 
-    #[cfg(test)]
+    /// Return the number of hops in this circuit.
+    ///
+    /// Circuits with only one hop can only be used for directory streams.
     pub fn n_hops(&self) -> u8 {
         self.hops.load(Ordering::SeqCst)
     }
+
+    /// Return the number of RELAY_EARLY cells that we may still send on this
+    /// circuit.
+    ///
+    /// Each extension uses one of these, so this is also the number of
+    /// further extension attempts this circuit allows: once it reaches zero,
+    /// [`extend_ntor`](ClientCirc::extend_ntor) fails with
+    /// [`Error::NoEarlyCellsLeft`], and a builder has to start over with a
+    /// new circuit.
+    pub fn relay_early_left(&self) -> u8 {
+        self.relay_early_left.load(Ordering::SeqCst)
+    }
 }
 
 impl PendingClientCirc {
@@ -427,7 +772,9 @@ impl PendingClientCirc {
     ) -> (PendingClientCirc, reactor::Reactor) {
         let crypto_out = OutboundClientCrypt::new();
         let (control_tx, control_rx) = mpsc::unbounded();
+        let (reactor_closed_tx, reactor_closed_rx) = oneshot::channel();
         let num_hops = Arc::new(AtomicU8::new(0));
+        let relay_early_left = Arc::new(AtomicU8::new(DEFAULT_RELAY_EARLY_BUDGET));
 
         let reactor = Reactor {
             control: control_rx,
@@ -440,13 +787,18 @@ impl PendingClientCirc {
             channel_id: id,
             crypto_out,
             meta_handler: None,
+            draining: false,
             num_hops: Arc::clone(&num_hops),
+            relay_early_left: Arc::clone(&relay_early_left),
+            reactor_closed_tx,
         };
 
         let circuit = ClientCirc {
             hops: num_hops,
+            relay_early_left,
             unique_id,
             control: control_tx,
+            reactor_closed_rx: reactor_closed_rx.shared(),
             #[cfg(test)]
             circid: id,
         };
@@ -608,13 +960,38 @@ impl StreamTarget {
             .map_err(|_| Error::CircuitClosed)?;
         Ok(())
     }
+
+    /// Return a handle that refers to this stream.
+    pub(crate) fn handle(&self) -> StreamHandle {
+        StreamHandle {
+            hop_num: self.hop_num,
+            stream_id: self.stream_id,
+            circ: self.circ.clone(),
+        }
+    }
+}
+
+impl StreamHandle {
+    /// Return the byte counters for this stream, if it is open,
+    /// half-closed, or ended by the other side.
+    pub(crate) async fn stats(&self) -> Result<Option<StreamStats>> {
+        self.circ.stream_stats(self.hop_num, self.stream_id).await
+    }
+
+    /// Return the reason why this stream stopped being open, if it has
+    /// been closed recently.
+    pub(crate) async fn closed_reason(&self) -> Result<Option<ClosedReason>> {
+        self.circ
+            .stream_closed_reason(self.hop_num, self.stream_id)
+            .await
+    }
 }
 
 /// Convert a [`ResolvedVal`] into a Result, based on whether or not
 /// it represents an error.
 fn resolvedval_to_result(val: ResolvedVal) -> Result<ResolvedVal> {
     match val {
-        ResolvedVal::TransientError => Err(Error::ResolveError(
+        ResolvedVal::TransientError => Err(Error::ResolveTransient(
             "Received retriable transient error".into(),
         )),
         ResolvedVal::NontransientError => {
@@ -630,6 +1007,7 @@ mod test {
 
     use super::*;
     use crate::channel::{test::new_reactor, CodecError};
+    use crate::circuit::reactor::MetaCellDisposition;
     use crate::crypto::cell::RelayCellBody;
     use chanmsg::{ChanMsg, Created2, CreatedFast};
     use futures::channel::mpsc::{Receiver, Sender};
@@ -641,7 +1019,7 @@ mod test {
     use rand::thread_rng;
     use std::time::Duration;
     use tor_cell::chancell::{msg as chanmsg, ChanCell};
-    use tor_cell::relaycell::{msg as relaymsg, RelayCell, StreamId};
+    use tor_cell::relaycell::{msg as relaymsg, RelayCell, RelayCmd, StreamId};
     use tor_llcrypto::pk;
     use tor_rtcompat::{Runtime, SleepProvider};
     use tracing::trace;
@@ -848,6 +1226,17 @@ mod test {
         rt: &R,
         chan: Channel,
         next_msg_from: HopNum,
+    ) -> (ClientCirc, mpsc::Sender<ClientCircChanMsg>) {
+        newcirc_with_hops(rt, chan, 3, next_msg_from).await
+    }
+
+    // Helper: set up an n_hops-hop circuit with no encryption, where the
+    // next inbound message seems to come from hop next_msg_from
+    async fn newcirc_with_hops<R: Runtime>(
+        rt: &R,
+        chan: Channel,
+        n_hops: u8,
+        next_msg_from: HopNum,
     ) -> (ClientCirc, mpsc::Sender<ClientCircChanMsg>) {
         let circid = 128.into();
         let (_created_send, created_recv) = oneshot::channel();
@@ -867,13 +1256,13 @@ mod test {
             recvcreated: _,
         } = pending;
 
-        for idx in 0_u8..3 {
+        for idx in 0_u8..n_hops {
             let params = CircParameters::default();
             let (tx, rx) = oneshot::channel();
             circ.control
                 .unbounded_send(CtrlMsg::AddFakeHop {
                     supports_flowctrl_1: true,
-                    fwd_lasthop: idx == 2,
+                    fwd_lasthop: idx == n_hops - 1,
                     rev_lasthop: idx == next_msg_from.into(),
                     params,
                     done: tx,
@@ -1112,11 +1501,261 @@ mod test {
         });
     }
 
+    #[test]
+    fn wait_for_close() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (circ, _sink2) = newcirc(&rt, chan).await;
+            let closed = circ.wait_for_close();
+            assert!(!circ.is_closing());
+            circ.terminate();
+            closed.await;
+            assert!(circ.is_closing());
+            // Once the circuit is closed, new waiters finish right away.
+            circ.wait_for_close().await;
+        });
+    }
+
+    #[test]
+    fn relay_early_budget() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            let params = CircParameters::default();
+            assert_eq!(params.relay_early_budget(), 8);
+            assert_eq!(circ.relay_early_left(), 8);
+
+            let extend_fut = async move {
+                let target = example_target();
+                // Every attempt fails, but uses up one RELAY_EARLY cell.
+                for n_left in (0..8).rev() {
+                    let outcome = circ.extend_ntor(&target, &params).await;
+                    assert!(matches!(outcome, Err(Error::BadCircHandshake)));
+                    assert_eq!(circ.relay_early_left(), n_left);
+                    assert_eq!(circ.n_hops(), 3);
+                }
+                // Now we can't even try.
+                let outcome = circ.extend_ntor(&target, &params).await;
+                assert!(matches!(outcome, Err(Error::NoEarlyCellsLeft)));
+                assert_eq!(circ.relay_early_left(), 0);
+                assert_eq!(circ.n_hops(), 3);
+                circ
+            };
+            let reply_fut = async move {
+                for _ in 0..8 {
+                    let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                    assert!(matches!(chmsg, ChanMsg::RelayEarly(_)));
+                    let extended2 = relaymsg::Extended2::new(vec![99; 256]).into();
+                    sink.send(rmsg_to_ccmsg(0, extended2)).await.unwrap();
+                }
+                (rx, sink)
+            };
+
+            let (_circ, (_rx, _sink)) = futures::join!(extend_fut, reply_fut);
+        });
+    }
+
+    /// A toy meta-cell handler that treats DROP cells from a given hop as
+    /// acknowledgments.
+    struct DropAckHandler {
+        /// The hop we expect the DROP cells from.
+        hop: HopNum,
+        /// How many more DROP cells we need before we're done.
+        acks_needed: usize,
+    }
+
+    impl reactor::MetaCellHandler for DropAckHandler {
+        fn expected_hop(&self) -> HopNum {
+            self.hop
+        }
+        fn expected_commands(&self) -> &[RelayCmd] {
+            &[RelayCmd::DROP]
+        }
+        fn handle(&mut self, msg: RelayMsg, _reactor: &mut Reactor) -> MetaCellDisposition {
+            assert!(matches!(msg, RelayMsg::Drop));
+            self.acks_needed -= 1;
+            if self.acks_needed == 0 {
+                MetaCellDisposition::Done(Ok(()))
+            } else {
+                MetaCellDisposition::Continue
+            }
+        }
+    }
+
+    // Helper: install a DropAckHandler on circ that wants `acks_needed`
+    // DROP cells, and return a receiver for its result.
+    //
+    // Doesn't return until the reactor has installed the handler, so that
+    // cells sent afterwards can't overtake it.
+    async fn install_drop_ack_handler(
+        circ: &ClientCirc,
+        acks_needed: usize,
+    ) -> oneshot::Receiver<Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        let handler = DropAckHandler {
+            hop: 2.into(),
+            acks_needed,
+        };
+        circ.control
+            .unbounded_send(CtrlMsg::InstallMetaHandler {
+                handler: reactor::BoxedMetaCellHandler(Box::new(handler)),
+                done: tx,
+            })
+            .unwrap();
+        // The reactor handles control messages in order, so once it answers
+        // this query, the handler is in place.
+        let (query_tx, query_rx) = oneshot::channel();
+        circ.control
+            .unbounded_send(CtrlMsg::QuerySendWindow {
+                hop: 2.into(),
+                done: query_tx,
+            })
+            .unwrap();
+        query_rx.await.unwrap().unwrap();
+        rx
+    }
+
+    #[test]
+    fn meta_handler_dispatch() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // The handler gets the DROP it was waiting for.
+            let done = install_drop_ack_handler(&circ, 1).await;
+            sink.send(rmsg_to_ccmsg(0, RelayMsg::Drop)).await.unwrap();
+            done.await.unwrap().unwrap();
+
+            // Once it's gone, we can install another.
+            let done = install_drop_ack_handler(&circ, 1).await;
+            sink.send(rmsg_to_ccmsg(0, RelayMsg::Drop)).await.unwrap();
+            done.await.unwrap().unwrap();
+            assert!(!circ.is_closing());
+
+            // A handler that wants several messages stays installed until it
+            // has them all.
+            let done = install_drop_ack_handler(&circ, 3).await;
+            for _ in 0..3 {
+                sink.send(rmsg_to_ccmsg(0, RelayMsg::Drop)).await.unwrap();
+            }
+            done.await.unwrap().unwrap();
+            assert!(!circ.is_closing());
+
+            // With no handler installed, a DROP is unexpected again.
+            sink.send(rmsg_to_ccmsg(0, RelayMsg::Drop)).await.unwrap();
+            let mut tries = 0;
+            while !circ.is_closing() {
+                // TODO: Don't sleep in tests.
+                rt.sleep(Duration::from_millis(100)).await;
+                tries += 1;
+                if tries > 10 {
+                    panic!("reactor continued running after unexpected DROP");
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn stray_end_is_fatal() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // An END for a stream that we never opened closes the circuit.
+            let end = relaymsg::End::new_misc().into();
+            sink.send(rmsg_to_ccmsg(99, end)).await.unwrap();
+            let mut tries = 0;
+            while !circ.is_closing() {
+                // TODO: Don't sleep in tests.
+                rt.sleep(Duration::from_millis(100)).await;
+                tries += 1;
+                if tries > 10 {
+                    panic!("reactor continued running after a stray END");
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn meta_handler_violation() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // A meta cell that the handler doesn't expect is reported to
+            // the handler's owner, and closes the circuit.
+            let done = install_drop_ack_handler(&circ, 1).await;
+            let extended2 = relaymsg::Extended2::new(vec![]).into();
+            sink.send(rmsg_to_ccmsg(0, extended2)).await.unwrap();
+            match done.await.unwrap() {
+                Err(Error::CircProto(s)) => assert_eq!(s, "wanted DROP; got EXTENDED2"),
+                other => panic!("got other result: {:?}", other),
+            }
+            let mut tries = 0;
+            while !circ.is_closing() {
+                // TODO: Don't sleep in tests.
+                rt.sleep(Duration::from_millis(100)).await;
+                tries += 1;
+                if tries > 10 {
+                    panic!("reactor continued running after unexpected EXTENDED2");
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn initial_stream_id() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let mut params = CircParameters::default();
+            assert!(params.set_initial_stream_id(0).is_err());
+            params.set_initial_stream_id(u16::MAX).unwrap();
+
+            // Make a one-hop circuit whose hop starts at the ID we chose.
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (_created_send, created_recv) = oneshot::channel();
+            let (_circmsg_send, circmsg_recv) = mpsc::channel(64);
+            let (pending, reactor) = PendingClientCirc::new(
+                128.into(),
+                chan,
+                created_recv,
+                circmsg_recv,
+                UniqId::new(23, 17),
+            );
+            rt.spawn(async {
+                let _ignore = reactor.run().await;
+            })
+            .unwrap();
+            let circ = pending.circ;
+            let (tx, done) = oneshot::channel();
+            circ.control
+                .unbounded_send(CtrlMsg::AddFakeHop {
+                    supports_flowctrl_1: true,
+                    fwd_lasthop: true,
+                    rev_lasthop: true,
+                    params,
+                    done: tx,
+                })
+                .unwrap();
+            done.await.unwrap().unwrap();
+            assert_eq!(circ.stream_id_allocator_state().await.unwrap(), u16::MAX);
+
+            let _stream = circ.begin_dir_stream().await.unwrap();
+            let rmsg = match rx.next().await.unwrap().into_circid_and_msg().1 {
+                ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            assert_eq!(rmsg.into_streamid_and_msg().0, u16::MAX.into());
+            // The cursor wrapped around, skipping 0.
+            assert_eq!(circ.stream_id_allocator_state().await.unwrap(), 1);
+        });
+    }
+
     #[test]
     fn begindir() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
             let (chan, mut rx, _sink) = working_fake_channel(&rt);
             let (circ, mut sink) = newcirc(&rt, chan).await;
+            let (stats_checked_tx, stats_checked_rx) = oneshot::channel();
 
             let begin_and_send_fut = async move {
                 // Here we'll say we've got a circuit, and we want to
@@ -1127,8 +1766,19 @@ mod test {
                 let mut buf = [0_u8; 1024];
                 let n = stream.read(&mut buf).await.unwrap();
                 assert_eq!(&buf[..n], b"HTTP/1.0 404 Not found\r\n");
+                // Both the request and the reply have been counted.
+                let stats = stream.stream_stats().await.unwrap().unwrap();
+                assert_eq!(stats.bytes_sent(), 16);
+                assert_eq!(stats.bytes_recv(), 24);
+                assert_eq!(stream.closed_reason().await.unwrap(), None);
+                stats_checked_tx.send(()).unwrap();
                 let n = stream.read(&mut buf).await.unwrap();
                 assert_eq!(n, 0);
+                // The relay ended the stream before we did.
+                assert_eq!(
+                    stream.closed_reason().await.unwrap(),
+                    Some(ClosedReason::PeerEnd)
+                );
                 stream
             };
             let reply_fut = async move {
@@ -1167,6 +1817,7 @@ mod test {
                     .unwrap()
                     .into();
                 sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                stats_checked_rx.await.unwrap();
 
                 // Send an END cell to say that the conversation is over.
                 let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
@@ -1179,6 +1830,228 @@ mod test {
         });
     }
 
+    #[test]
+    fn one_hop_only_begindir() {
+        use tor_error::{ErrorKind, HasKind};
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, _sink2) = newcirc_with_hops(&rt, chan, 1, 0.into()).await;
+            assert_eq!(circ.n_hops(), 1);
+
+            // Every way of opening an exit stream gets refused...
+            let e = circ.begin_stream("www.example.com", 443, None).await;
+            assert_eq!(e.err().unwrap().kind(), ErrorKind::BadApiUsage);
+            let e = circ.resolve("www.example.com").await;
+            assert_eq!(e.err().unwrap().kind(), ErrorKind::BadApiUsage);
+            let e = circ.resolve_ptr("127.0.0.1".parse().unwrap()).await;
+            assert_eq!(e.err().unwrap().kind(), ErrorKind::BadApiUsage);
+            // ... without anything getting sent.
+            assert!(rx.try_next().is_err());
+
+            // But directory streams are fine.
+            let _stream = circ.begin_dir_stream().await.unwrap();
+            let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+            let rmsg = match chmsg {
+                ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                _ => panic!(),
+            };
+            let (_streamid, rmsg) = rmsg.into_streamid_and_msg();
+            assert!(matches!(rmsg, RelayMsg::BeginDir));
+        });
+    }
+
+    #[test]
+    fn buffered_read() {
+        use futures::io::AsyncBufReadExt;
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            let read_fut = async move {
+                let stream = circ.begin_dir_stream().await.unwrap();
+                let (mut reader, _writer) = stream.split();
+
+                // We see each cell's payload as it arrived, with no copying.
+                assert_eq!(reader.fill_buf().await.unwrap(), b"HTTP/1.0 ");
+                reader.consume_unpin(5);
+                assert_eq!(reader.fill_buf().await.unwrap(), b"1.0 ");
+                assert_eq!(reader.poll_read_buffered(), 4);
+                reader.consume_unpin(4);
+                assert_eq!(reader.fill_buf().await.unwrap(), b"404 Not found\r\n");
+                // Consuming more than there is just empties the buffer.
+                reader.consume_unpin(1000);
+                assert_eq!(reader.poll_read_buffered(), 0);
+                // Then we see the end of the stream.
+                assert!(reader.fill_buf().await.unwrap().is_empty());
+                reader
+            };
+            let reply_fut = async move {
+                let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                    _ => panic!(),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, RelayMsg::BeginDir));
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+                for data in [&b"HTTP/1.0 "[..], &b"404 Not found\r\n"[..]] {
+                    let data = relaymsg::Data::new(data).unwrap().into();
+                    sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                }
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+                (rx, sink)
+            };
+
+            let (_reader, (_rx, _sink)) = futures::join!(read_fut, reply_fut);
+        });
+    }
+
+    #[test]
+    fn read_timeout_keeps_late_data() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            let (send_data_tx, send_data_rx) = oneshot::channel::<()>();
+            let (send_more_tx, send_more_rx) = oneshot::channel::<()>();
+
+            let rt2 = rt.clone();
+            let read_fut = async move {
+                let mut stream = circ.begin_dir_stream().await.unwrap();
+                let mut buf = [0_u8; 1024];
+
+                // Nothing has been sent yet, so this times out.
+                let e = stream
+                    .read_with_deadline(&mut buf, rt2.sleep(Duration::from_millis(100)))
+                    .await
+                    .unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+                assert_eq!(stream.poll_read_buffered(), 0);
+
+                // Once the data arrives, it's all still there.
+                send_data_tx.send(()).unwrap();
+                let n = stream.read(&mut buf[..5]).await.unwrap();
+                assert_eq!(&buf[..n], b"HTTP/");
+                assert_eq!(stream.poll_read_buffered(), 19);
+                let n = stream.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"1.0 404 Not found\r\n");
+                assert_eq!(stream.poll_read_buffered(), 0);
+
+                // Same thing, with a default read timeout.
+                let rt3 = rt2.clone();
+                stream.set_read_timeout(Duration::from_millis(100), move |d| rt3.sleep(d));
+                let e = stream.read(&mut buf).await.unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+                send_more_tx.send(()).unwrap();
+                let n = stream.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"Go away\r\n");
+                stream
+            };
+            let reply_fut = async move {
+                let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+                    _ => panic!(),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, RelayMsg::BeginDir));
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // Wait until the reader has given up once before answering.
+                send_data_rx.await.unwrap();
+                let data = relaymsg::Data::new(b"HTTP/1.0 404 Not found\r\n")
+                    .unwrap()
+                    .into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+
+                send_more_rx.await.unwrap();
+                let data = relaymsg::Data::new(b"Go away\r\n").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+
+                (rx, sink) // gotta keep these alive, or the reactor will exit.
+            };
+
+            let (_stream, (_rx, _sink)) = futures::join!(read_fut, reply_fut);
+        });
+    }
+
+    // Helper: read the next cell from `rx`, and decode it as a relay cell.
+    async fn next_relay_msg(rx: &mut Receiver<ChanCell>) -> (StreamId, RelayMsg) {
+        let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+        match chmsg {
+            ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body())
+                .unwrap()
+                .into_streamid_and_msg(),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn split_stream_halves() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+            let (data_sent_tx, data_sent_rx) = oneshot::channel::<()>();
+
+            let client_fut = async move {
+                // Dropping the read half doesn't stop us from writing.
+                let (reader, mut writer1) = circ.begin_dir_stream().await.unwrap().split();
+                drop(reader);
+                writer1.write_all(b"still here").await.unwrap();
+                writer1.flush().await.unwrap();
+
+                // Closing the write half doesn't stop us from reading.
+                let (mut reader, mut writer2) = circ.begin_dir_stream().await.unwrap().split();
+                writer2.close().await.unwrap();
+                assert!(writer2.write_all(b"too late").await.is_err());
+                data_sent_rx.await.unwrap();
+                let mut buf = [0_u8; 64];
+                let n = reader.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"buffered");
+
+                // Now that both halves are gone, the stream gets closed.
+                drop(reader);
+                drop(writer2);
+                writer1
+            };
+            let reply_fut = async move {
+                let (id1, rmsg) = next_relay_msg(&mut rx).await;
+                assert!(matches!(rmsg, RelayMsg::BeginDir));
+                // Data, not an END, on the first stream.  The reactor may
+                // send the second BEGIN_DIR before or after it.
+                let mut saw_data = false;
+                let mut id2 = None;
+                while !saw_data || id2.is_none() {
+                    match next_relay_msg(&mut rx).await {
+                        (id, RelayMsg::Data(d)) => {
+                            assert_eq!(id, id1);
+                            assert_eq!(d.as_ref(), &b"still here"[..]);
+                            saw_data = true;
+                        }
+                        (id, RelayMsg::BeginDir) => id2 = Some(id),
+                        _ => panic!(),
+                    }
+                }
+                let id2 = id2.unwrap();
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(id2, connected)).await.unwrap();
+                let data = relaymsg::Data::new(b"buffered").unwrap().into();
+                sink.send(rmsg_to_ccmsg(id2, data)).await.unwrap();
+                data_sent_tx.send(()).unwrap();
+
+                let (id, rmsg) = next_relay_msg(&mut rx).await;
+                assert_eq!(id, id2);
+                assert!(matches!(rmsg, RelayMsg::End(_)));
+
+                (rx, sink) // gotta keep these alive, or the reactor will exit.
+            };
+
+            let (_writer1, (_rx, _sink)) = futures::join!(client_fut, reply_fut);
+        });
+    }
+
     // Set up a circuit and stream that expects some incoming SENDMEs.
     async fn setup_incoming_sendme_case<R: Runtime>(
         rt: &R,
@@ -1256,6 +2129,57 @@ mod test {
         (circ, stream, sink, streamid, cells_received, rx, sink2)
     }
 
+    #[test]
+    fn drain() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (circ, mut sink) = newcirc(&rt, chan).await;
+
+            // Open a stream before we start draining.
+            let mut stream = circ.begin_dir_stream().await.unwrap();
+            let (streamid, rmsg) = next_relay_msg(&mut rx).await;
+            assert!(matches!(rmsg, RelayMsg::BeginDir));
+
+            circ.begin_drain().await.unwrap();
+            assert!(!circ.is_drained().await.unwrap());
+
+            // New streams get refused, without anything getting sent.
+            let e = circ.begin_dir_stream().await;
+            assert!(matches!(e, Err(Error::Draining)));
+            let e = circ.begin_stream("www.example.com", 443, None).await;
+            assert!(matches!(e, Err(Error::Draining)));
+            assert!(rx.try_next().is_err());
+
+            // But the old stream keeps working in both directions.
+            let connected = relaymsg::Connected::new_empty().into();
+            sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+            let data = relaymsg::Data::new(b"HTTP/1.0 200 OK\r\n").unwrap().into();
+            sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            let mut buf = [0_u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"HTTP/1.0 200 OK\r\n");
+            stream.write_all(b"HTTP/1.0 GET /\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+            match next_relay_msg(&mut rx).await {
+                (id, RelayMsg::Data(d)) => {
+                    assert_eq!(id, streamid);
+                    assert_eq!(d.as_ref(), &b"HTTP/1.0 GET /\r\n"[..]);
+                }
+                other => panic!("got other message: {:?}", other),
+            }
+            assert!(!circ.is_drained().await.unwrap());
+
+            // Once we're done with the stream, the circuit is drained.
+            drop(stream);
+            match next_relay_msg(&mut rx).await {
+                (id, RelayMsg::End(_)) => assert_eq!(id, streamid),
+                other => panic!("got other message: {:?}", other),
+            }
+            assert!(circ.is_drained().await.unwrap());
+            assert!(!circ.is_closing());
+        });
+    }
+
     #[test]
     fn accept_valid_sendme() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
@@ -1379,4 +2303,34 @@ mod test {
         assert!(p.set_initial_send_window(9000).is_err());
         assert_eq!(p.initial_send_window(), 500);
     }
+
+    #[test]
+    fn congestion_params() {
+        let mut c = CongestionParams::default();
+        assert_eq!(c.stream_window(), 500);
+        assert_eq!(c.circ_window(), 1000);
+        assert_eq!(c.stream_increment(), 50);
+
+        assert!(c.set_stream_window(800).is_ok());
+        assert!(c.set_stream_increment(20).is_ok());
+        assert_eq!(c.stream_window(), 800);
+        assert_eq!(c.stream_increment(), 20);
+        // We can receive more than the protocol default, but we never
+        // send more than it.
+        assert_eq!(c.new_stream_send_window().window(), 500);
+
+        assert!(c.set_stream_window(0).is_err());
+        assert!(c.set_stream_window(499).is_err());
+        assert!(c.set_stream_increment(0).is_err());
+        assert!(c.set_stream_increment(801).is_err());
+        assert!(c.set_circ_window(1001).is_err());
+        assert_eq!(c.stream_window(), 800);
+        assert_eq!(c.stream_increment(), 20);
+
+        let mut p = CircParameters::default();
+        assert!(c.set_circ_window(300).is_ok());
+        p.set_congestion_params(c);
+        assert_eq!(p.initial_send_window(), 300);
+        assert_eq!(p.congestion_params().stream_window(), 800);
+    }
 }
@@ -4,6 +4,7 @@
 //! we might still receive some cells.
 
 use crate::circuit::sendme::{StreamRecvWindow, StreamSendWindow};
+use crate::circuit::streammap::StreamStats;
 use crate::{Error, Result};
 use tor_cell::relaycell::msg::RelayMsg;
 use tor_error::internal;
@@ -16,6 +17,7 @@ use tor_error::internal;
 /// We need to track these streams instead of forgetting about them entirely,
 /// since otherwise we'd be vulnerable to a class of "DropMark" attacks;
 /// see <https://gitlab.torproject.org/tpo/core/tor/-/issues/25573>.
+#[derive(Debug)]
 pub(super) struct HalfStream {
     /// Send window for this stream. Used to detect whether we get too many
     /// SENDME cells.
@@ -23,8 +25,12 @@ pub(super) struct HalfStream {
     /// Receive window for this stream. Used to detect whether we get too
     /// many data cells.
     recvw: StreamRecvWindow,
-    /// If true, accept a connected cell on this stream.
+    /// If true, we never got a CONNECTED (or RESOLVED) cell on this stream
+    /// before we closed it, so we'll accept one now.  Until we do, DATA
+    /// cells aren't allowed.
     connected_ok: bool,
+    /// Byte counters carried over from when this stream was open.
+    stats: StreamStats,
 }
 
 impl HalfStream {
@@ -33,14 +39,47 @@ impl HalfStream {
         sendw: StreamSendWindow,
         recvw: StreamRecvWindow,
         connected_ok: bool,
+        stats: StreamStats,
     ) -> Self {
         HalfStream {
             sendw,
             recvw,
             connected_ok,
+            stats,
         }
     }
 
+    /// Return the number of cells we may still send on this stream.
+    ///
+    /// We won't send any more cells on a half-closed stream, but the
+    /// other side may still acknowledge the ones we sent earlier.
+    pub(super) fn send_window(&self) -> u16 {
+        self.sendw.window()
+    }
+
+    /// Return the number of DATA cells the other side may still send us on
+    /// this stream before it has to wait for a SENDME.
+    pub(super) fn recv_window(&self) -> u16 {
+        self.recvw.window()
+    }
+
+    /// Return true if we never got a CONNECTED (or RESOLVED) cell on this
+    /// stream, and so would still accept one.
+    pub(super) fn connected_ok(&self) -> bool {
+        self.connected_ok
+    }
+
+    /// Return the byte counters for this stream.
+    pub(super) fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Note that we sent a SENDME on this stream, so the other side is
+    /// allowed to send us more data.
+    pub(super) fn note_sendme_sent(&mut self) {
+        self.recvw.put();
+    }
+
     /// Process an incoming message and adjust this HalfStream accordingly.
     /// Give an error if the protocol has been violated.
     ///
@@ -53,18 +92,26 @@ impl HalfStream {
                 self.sendw.put(Some(()))?;
                 Ok(())
             }
-            RelayMsg::Data(_) => {
+            RelayMsg::Data(d) => {
+                if self.connected_ok {
+                    // The other side never told us the stream was open.
+                    return Err(Error::CircProto(
+                        "DATA cell on a closed stream that was never connected!".into(),
+                    ));
+                }
                 self.recvw.take()?;
+                self.stats.note_data_received(d.as_ref().len());
                 Ok(())
             }
-            RelayMsg::Connected(_) => {
+            RelayMsg::Connected(_) | RelayMsg::Resolved(_) => {
                 if self.connected_ok {
                     self.connected_ok = false;
                     Ok(())
                 } else {
-                    Err(Error::CircProto(
-                        "Bad CONNECTED cell on a closed stream!".into(),
-                    ))
+                    Err(Error::CircProto(format!(
+                        "Bad {} cell on a closed stream!",
+                        msg.cmd()
+                    )))
                 }
             }
             RelayMsg::End(_) => Err(Error::from(internal!(
@@ -90,7 +137,12 @@ mod test {
         let mut sendw = StreamSendWindow::new(101);
         sendw.take(&())?; // Make sure that it will accept one sendme.
 
-        let mut hs = HalfStream::new(sendw, StreamRecvWindow::new(20), true);
+        let mut hs = HalfStream::new(
+            sendw,
+            StreamRecvWindow::new(20),
+            true,
+            StreamStats::default(),
+        );
 
         // one sendme is fine
         let m = msg::Sendme::new_empty().into();
@@ -105,12 +157,23 @@ mod test {
     }
 
     fn hs_new() -> HalfStream {
-        HalfStream::new(StreamSendWindow::new(20), StreamRecvWindow::new(20), true)
+        HalfStream::new(
+            StreamSendWindow::new(20),
+            StreamRecvWindow::new(20),
+            true,
+            StreamStats::default(),
+        )
     }
 
     #[test]
     fn halfstream_data() {
-        let mut hs = hs_new();
+        // This stream got a CONNECTED cell before we closed it.
+        let mut hs = HalfStream::new(
+            StreamSendWindow::new(20),
+            StreamRecvWindow::new(20),
+            false,
+            StreamStats::default(),
+        );
 
         // 20 data cells are okay.
         let m = msg::Data::new(&b"this offer is unrepeatable"[..])
@@ -119,6 +182,8 @@ mod test {
         for _ in 0_u8..20 {
             assert!(hs.handle_msg(&m).is_ok());
         }
+        assert_eq!(hs.stats().bytes_recv, 20 * 26);
+        assert_eq!(hs.stats().bytes_sent, 0);
 
         // But one more is a protocol violation.
         let e = hs.handle_msg(&m).err().unwrap();
@@ -139,7 +204,12 @@ mod test {
 
         // If we try that again with connected_ok == false, we won't
         // accept any.
-        let mut hs = HalfStream::new(StreamSendWindow::new(20), StreamRecvWindow::new(20), false);
+        let mut hs = HalfStream::new(
+            StreamSendWindow::new(20),
+            StreamRecvWindow::new(20),
+            false,
+            StreamStats::default(),
+        );
         let e = hs.handle_msg(&m).err().unwrap();
         assert_eq!(
             format!("{}", e),
@@ -147,6 +217,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn halfstream_data_before_connected() {
+        // We never got a CONNECTED on this stream, so DATA isn't allowed.
+        let mut hs = hs_new();
+        let data = msg::Data::new(&b"too soon"[..]).unwrap().into();
+        let e = hs.handle_msg(&data).err().unwrap();
+        assert_eq!(
+            format!("{}", e),
+            "circuit protocol violation: DATA cell on a closed stream that was never connected!"
+        );
+
+        // Once the CONNECTED arrives, DATA is fine.
+        let mut hs = hs_new();
+        let connected = msg::Connected::new_empty().into();
+        assert!(hs.handle_msg(&connected).is_ok());
+        assert!(hs.handle_msg(&data).is_ok());
+        assert_eq!(hs.stats().bytes_recv, 8);
+
+        // A RESOLVED counts as well, but only once.
+        let mut hs = hs_new();
+        let resolved = msg::Resolved::new_empty().into();
+        assert!(hs.handle_msg(&resolved).is_ok());
+        assert!(hs.handle_msg(&resolved).is_err());
+        assert!(hs.handle_msg(&connected).is_err());
+    }
+
     #[test]
     fn halfstream_other() {
         let mut hs = hs_new();
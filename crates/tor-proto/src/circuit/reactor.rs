@@ -1,9 +1,10 @@
 //! Code to handle incoming cells on a circuit.
-use super::streammap::{ShouldSendEnd, StreamEnt};
+use super::streammap::{AddStreamParams, ClosedReason, ShouldSendEnd, StreamKind, StreamStats};
 use crate::circuit::celltypes::{ClientCircChanMsg, CreateResponse};
 use crate::circuit::unique_id::UniqId;
 use crate::circuit::{
-    sendme, streammap, CircParameters, Create2Wrap, CreateFastWrap, CreateHandshakeWrap,
+    sendme, streammap, CircParameters, CongestionParams, Create2Wrap, CreateFastWrap,
+    CreateHandshakeWrap,
 };
 use crate::crypto::cell::{
     ClientLayer, CryptInit, HopNum, InboundClientCrypt, InboundClientLayer, OutboundClientCrypt,
@@ -31,25 +32,26 @@ use std::task::{Context, Poll};
 use crate::channel::Channel;
 #[cfg(test)]
 use crate::circuit::sendme::CircTag;
-use crate::circuit::sendme::StreamSendWindow;
+use crate::circuit::sendme::StreamRecvWindow;
 use crate::crypto::handshake::ntor::{NtorClient, NtorPublicKey};
 use crate::crypto::handshake::{ClientHandshake, KeyGenerator};
 use tor_cell::chancell;
 use tor_cell::chancell::{ChanCell, CircId};
 use tor_linkspec::LinkSpec;
 use tor_llcrypto::pk;
-use tracing::{debug, trace, warn};
+use tracing::{debug, trace, trace_span, warn};
 
-/// Initial value for outbound flow-control window on streams.
-pub(super) const SEND_WINDOW_INIT: u16 = 500;
-/// Initial value for inbound flow-control window on streams.
-pub(super) const RECV_WINDOW_INIT: u16 = 500;
+/// Default initial value for flow-control windows on streams.
+///
+/// This is also the smallest stream window that
+/// [`CongestionParams`](super::CongestionParams) will accept.
+pub(super) const STREAM_WINDOW_INIT: u16 = 500;
 /// Size of the buffer used between the reactor and a `StreamReader`.
 ///
 /// FIXME(eta): We pick 2× the receive window, which is very conservative (we arguably shouldn't
 ///             get sent more than the receive window anyway!). We might do due to things that
 ///             don't count towards the window though.
-pub(super) const STREAM_READER_BUFFER: usize = (2 * RECV_WINDOW_INIT) as usize;
+pub(super) const STREAM_READER_BUFFER: usize = (2 * STREAM_WINDOW_INIT) as usize;
 
 /// The type of a oneshot channel used to inform reactor users of the result of an operation.
 pub(super) type ReactorResultChannel<T> = oneshot::Sender<Result<T>>;
@@ -117,8 +119,39 @@ pub(super) enum CtrlMsg {
         sender: mpsc::Sender<RelayMsg>,
         /// A channel to receive messages to send on this stream from.
         rx: mpsc::Receiver<RelayMsg>,
-        /// Oneshot channel to notify on completion, with the allocated stream ID.
-        done: ReactorResultChannel<StreamId>,
+        /// The scheduling priority for the stream.
+        priority: u8,
+        /// Oneshot channel to notify on completion, with the allocated stream ID
+        /// and a receive window for the stream's reader.
+        done: ReactorResultChannel<(StreamId, StreamRecvWindow)>,
+    },
+    /// Report the byte counters for a stream.
+    QueryStreamStats {
+        /// The hop number the stream is on.
+        hop_num: HopNum,
+        /// The stream ID to report on.
+        stream_id: StreamId,
+        /// Oneshot channel on which to report the counters, if the stream
+        /// is open or half-closed.
+        done: ReactorResultChannel<Option<StreamStats>>,
+    },
+    /// Report why a stream on a given hop stopped being open.
+    QueryClosedReason {
+        /// The hop number the stream is on.
+        hop_num: HopNum,
+        /// The stream ID to report on.
+        stream_id: StreamId,
+        /// Oneshot channel on which to report the reason, if the stream
+        /// was closed recently enough for us to remember.
+        done: ReactorResultChannel<Option<ClosedReason>>,
+    },
+    /// Report the state of a hop's stream ID allocator.
+    QueryAllocatorState {
+        /// The hop number to report on.
+        hop_num: HopNum,
+        /// Oneshot channel on which to report the next stream ID that the
+        /// hop will try to allocate.
+        done: ReactorResultChannel<u16>,
     },
     /// Send a SENDME cell (used to ask for more data to be sent) on the given stream.
     SendSendme {
@@ -127,6 +160,17 @@ pub(super) enum CtrlMsg {
         /// The hop number the stream is on.
         hop_num: HopNum,
     },
+    /// Stop accepting new streams on every hop of this circuit, including
+    /// any hops added later.
+    BeginDrain {
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<()>,
+    },
+    /// Find out whether this circuit has finished draining.
+    QueryDrained {
+        /// Oneshot channel on which to report whether every hop is drained.
+        done: ReactorResultChannel<bool>,
+    },
     /// Shut down the reactor.
     Shutdown,
     /// (tests only) Add a hop to the list of hops on this circuit, with dummy cryptography.
@@ -151,6 +195,12 @@ pub(super) enum CtrlMsg {
         early: bool,
         cell: RelayCell,
     },
+    /// (tests only) Install a meta-cell handler with set_meta_handler().
+    #[cfg(test)]
+    InstallMetaHandler {
+        handler: BoxedMetaCellHandler,
+        done: ReactorResultChannel<()>,
+    },
 }
 /// Represents the reactor's view of a single hop.
 pub(super) struct CircHop {
@@ -159,14 +209,17 @@ pub(super) struct CircHop {
     /// We store this with the reactor instead of the circuit, since the
     /// reactor needs it for every incoming cell on a stream, whereas
     /// the circuit only needs it when allocating new streams.
+    ///
+    /// This map also holds the hop's circuit-level send window, so that it
+    /// can check it along with the stream windows.
     map: streammap::StreamMap,
+    /// Flow-control parameters for new streams on this hop.
+    congestion: CongestionParams,
     /// Window used to say how many cells we can receive.
     recvwindow: sendme::CircRecvWindow,
     /// If true, this hop is using an older link protocol and we
     /// shouldn't expect good authenticated SENDMEs from it.
     auth_sendme_required: RequireSendmeAuth,
-    /// Window used to say how many cells we can send.
-    sendwindow: sendme::CircSendWindow,
     /// Buffer for messages we can't send to this hop yet due to congestion control.
     ///
     /// Contains the cell to send, and a boolean equivalent to the `early` parameter
@@ -228,14 +281,25 @@ enum CellStatus {
 
 impl CircHop {
     /// Create a new hop.
-    pub(super) fn new(auth_sendme_required: RequireSendmeAuth, initial_window: u16) -> Self {
-        CircHop {
-            map: streammap::StreamMap::new(),
+    ///
+    /// Gives an error if `params` has an invalid initial stream ID.
+    pub(super) fn new(
+        auth_sendme_required: RequireSendmeAuth,
+        params: &CircParameters,
+    ) -> Result<Self> {
+        let congestion = params.congestion_params().clone();
+        let initial_window = congestion.circ_window();
+        let map = match params.initial_stream_id() {
+            Some(id) => streammap::StreamMap::new_with_next_id(initial_window, id)?,
+            None => streammap::StreamMap::new(initial_window),
+        };
+        Ok(CircHop {
+            map,
+            congestion,
             recvwindow: sendme::CircRecvWindow::new(1000),
             auth_sendme_required,
-            sendwindow: sendme::CircSendWindow::new(initial_window),
             outbound: VecDeque::new(),
-        }
+        })
     }
 }
 
@@ -249,14 +313,58 @@ impl CircHop {
 ///
 /// To get around this problem, the reactor can send some cells, and then make one of these
 /// `MetaCellHandler` objects, which will be run when the reply arrives.
+///
+/// Only one handler can be installed on a circuit at a time.  While it is installed, it
+/// receives every meta cell from its expected hop whose command it lists in
+/// [`expected_commands`](MetaCellHandler::expected_commands); any other meta cell is still a
+/// protocol violation, and closes the circuit.  New kinds of interaction (like the ones used
+/// by onion services) should be added by implementing this trait, rather than by adding new
+/// special cases to the reactor's dispatch code.
 pub(super) trait MetaCellHandler: Send {
     /// The hop we're expecting the message to come from. This is compared against the hop
     /// from which we actually receive messages, and an error is thrown if the two don't match.
     fn expected_hop(&self) -> HopNum;
-    /// Called when the message we were waiting for arrives.
+    /// The relay commands that this handler is willing to receive.
     ///
+    /// Must not be empty.
+    fn expected_commands(&self) -> &[RelayCmd];
+    /// Called when a message that we were waiting for arrives.
+    ///
+    /// The message's command is always one of those listed by `expected_commands()`.
     /// Gets a copy of the `Reactor` in order to do anything it likes there.
-    fn finish(&mut self, msg: RelayMsg, reactor: &mut Reactor) -> Result<()>;
+    fn handle(&mut self, msg: RelayMsg, reactor: &mut Reactor) -> MetaCellDisposition;
+}
+
+/// What the reactor should do after a [`MetaCellHandler`] has handled a message.
+#[derive(Debug)]
+pub(super) enum MetaCellDisposition {
+    /// The interaction is over: uninstall the handler, and report this result
+    /// to whoever was waiting for it.
+    ///
+    /// The circuit stays open, even if the result is an error.
+    Done(Result<()>),
+    /// The message was acceptable, but the interaction isn't over yet: keep
+    /// the handler installed to receive further messages.
+    // No handler outside the tests needs this yet.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Continue,
+    /// The message violated the protocol: report this error to whoever was
+    /// waiting, and close the circuit.
+    Violation(Error),
+}
+
+/// A boxed [`MetaCellHandler`], wrapped so that it can be sent in a [`CtrlMsg`].
+#[cfg(test)]
+pub(super) struct BoxedMetaCellHandler(pub(super) Box<dyn MetaCellHandler>);
+
+#[cfg(test)]
+impl std::fmt::Debug for BoxedMetaCellHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetaCellHandler")
+            .field("expected_hop", &self.0.expected_hop())
+            .field("expected_commands", &self.0.expected_commands())
+            .finish()
+    }
 }
 
 /// An object that can extend a circuit by one hop, using the `MetaCellHandler` trait.
@@ -314,6 +422,12 @@ where
         let mut rng = rand::thread_rng();
         let unique_id = reactor.unique_id;
 
+        // An EXTEND2 cell that isn't sent as RELAY_EARLY will just get
+        // rejected, so don't bother.
+        if reactor.relay_early_left.load(Ordering::SeqCst) == 0 {
+            return Err(Error::NoEarlyCellsLeft);
+        }
+
         use tor_cell::relaycell::msg::{Body, Extend2};
         // Perform the first part of the cryptographic handshake
         let (state, msg) = H::client1(&mut rng, key)?;
@@ -348,29 +462,10 @@ where
             phantom: Default::default(),
         })
     }
-}
 
-impl<H, L, FWD, REV> MetaCellHandler for CircuitExtender<H, L, FWD, REV>
-where
-    H: ClientHandshake,
-    H::StateType: Send,
-    H::KeyGen: KeyGenerator,
-    L: CryptInit + ClientLayer<FWD, REV> + Send,
-    FWD: OutboundClientLayer + 'static + Send,
-    REV: InboundClientLayer + 'static + Send,
-{
-    fn expected_hop(&self) -> HopNum {
-        self.expected_hop
-    }
+    /// Complete the extension, given the EXTENDED2 message that we were
+    /// waiting for.
     fn finish(&mut self, msg: RelayMsg, reactor: &mut Reactor) -> Result<()> {
-        // Did we get the right response?
-        if msg.cmd() != RelayCmd::EXTENDED2 {
-            return Err(Error::CircProto(format!(
-                "wanted EXTENDED2; got {}",
-                msg.cmd(),
-            )));
-        }
-
         // ???? Do we need to shutdown the circuit for the remaining error
         // ???? cases in this function?
 
@@ -409,8 +504,27 @@ where
             Box::new(layer_fwd),
             Box::new(layer_back),
             &self.params,
-        );
-        Ok(())
+        )
+    }
+}
+
+impl<H, L, FWD, REV> MetaCellHandler for CircuitExtender<H, L, FWD, REV>
+where
+    H: ClientHandshake,
+    H::StateType: Send,
+    H::KeyGen: KeyGenerator,
+    L: CryptInit + ClientLayer<FWD, REV> + Send,
+    FWD: OutboundClientLayer + 'static + Send,
+    REV: InboundClientLayer + 'static + Send,
+{
+    fn expected_hop(&self) -> HopNum {
+        self.expected_hop
+    }
+    fn expected_commands(&self) -> &[RelayCmd] {
+        &[RelayCmd::EXTENDED2]
+    }
+    fn handle(&mut self, msg: RelayMsg, reactor: &mut Reactor) -> MetaCellDisposition {
+        MetaCellDisposition::Done(self.finish(msg, reactor))
     }
 }
 
@@ -447,12 +561,22 @@ pub struct Reactor {
     pub(super) hops: Vec<CircHop>,
     /// Shared atomic for the number of hops this circuit has.
     pub(super) num_hops: Arc<AtomicU8>,
+    /// Shared atomic for the number of RELAY_EARLY cells we may still send.
+    pub(super) relay_early_left: Arc<AtomicU8>,
     /// An identifier for logging about this reactor's circuit.
     pub(super) unique_id: UniqId,
     /// This circuit's identifier on the upstream channel.
     pub(super) channel_id: CircId,
     /// A handler for a meta cell, together with a result channel to notify on completion.
     pub(super) meta_handler: Option<(Box<dyn MetaCellHandler>, ReactorResultChannel<()>)>,
+    /// True if we've been told to stop accepting new streams.
+    pub(super) draining: bool,
+    /// A sender that we drop when this reactor exits, to tell anybody
+    /// waiting in [`ClientCirc::wait_for_close`](super::ClientCirc::wait_for_close).
+    ///
+    /// (Since it carries an `Infallible`, we never actually send on it.)
+    #[allow(dead_code)] // Only dropped, never used.
+    pub(super) reactor_closed_tx: oneshot::Sender<std::convert::Infallible>,
 }
 
 impl Reactor {
@@ -557,7 +681,7 @@ impl Reactor {
                         let hop_num = HopNum::from(i as u8);
                         // If we can, drain our queue of things we tried to send earlier, but
                         // couldn't due to congestion control.
-                        if self.hops[i].sendwindow.window() > 0 {
+                        if self.hops[i].map.circuit_window_remaining() > 0 {
                             'hop: while let Some((early, cell)) = self.hops[i].outbound.pop_front()
                             {
                                 trace!(
@@ -570,39 +694,61 @@ impl Reactor {
                                 if !self.channel.poll_ready(cx)? {
                                     break 'outer;
                                 }
-                                if self.hops[i].sendwindow.window() == 0 {
+                                if self.hops[i].map.circuit_window_remaining() == 0 {
                                     break 'hop;
                                 }
                             }
                         }
                         let hop = &mut self.hops[i];
-                        // Look at all of the streams on this hop.
-                        for (id, stream) in hop.map.inner().iter_mut() {
-                            if let StreamEnt::Open {
-                                rx, send_window, ..
-                            } = stream
-                            {
-                                // Do the stream and hop send windows allow us to obtain and
-                                // send something?
-                                //
-                                // FIXME(eta): not everything counts toward congestion control!
-                                if send_window.window() > 0 && hop.sendwindow.window() > 0 {
-                                    match Pin::new(rx).poll_next(cx) {
-                                        Poll::Ready(Some(m)) => {
-                                            stream_relaycells
-                                                .push((hop_num, RelayCell::new(*id, m)));
-                                        }
-                                        Poll::Ready(None) => {
-                                            // Stream receiver was dropped; close the stream.
-                                            // We can't close it here though due to borrowck; that
-                                            // will happen later.
-                                            streams_to_close.push((hop_num, *id));
-                                        }
-                                        Poll::Pending => {}
-                                    }
+                        // Look at the streams on this hop that are allowed to send, in
+                        // the order the stream map schedules them, so that each stream
+                        // gets a share of the hop's send window that follows its
+                        // priority, and no stream can starve the others.
+                        //
+                        // We take at most as many cells as there are streams on each
+                        // pass, so that we don't pull more out of the streams than the
+                        // channel is likely to accept.  The stream map remembers how far
+                        // through its round it got, so the shares still work out over
+                        // several passes.
+                        //
+                        // How many more cells does the hop send window allow us to obtain
+                        // and send?
+                        //
+                        // FIXME(eta): not everything counts toward congestion control!
+                        let mut budget = hop.map.circuit_window_remaining();
+                        for _ in 0..hop.map.inner().len() {
+                            if budget == 0 {
+                                break;
+                            }
+                            let id = match hop.map.next_ready_stream() {
+                                Some(id) => id,
+                                None => break,
+                            };
+                            match hop.map.poll_next_msg(id, cx) {
+                                Some(Poll::Ready(Some(m))) => {
+                                    stream_relaycells.push((hop_num, RelayCell::new(id, m)));
+                                    budget -= 1;
                                 }
+                                Some(Poll::Ready(None)) => {
+                                    // Stream receiver was dropped; close the stream.
+                                    // We can't close it here though due to borrowck; that
+                                    // will happen later.  Until then, the stream map will
+                                    // keep offering it to us, so stop here: we'll come
+                                    // back for the others once it's closed.
+                                    streams_to_close.push((hop_num, id));
+                                    break;
+                                }
+                                // The stream map only offers us streams that have
+                                // something queued, so this shouldn't happen; but if it
+                                // does, don't spin.
+                                Some(Poll::Pending) | None => break,
                             }
                         }
+                        // The stream map skipped the streams that had nothing to
+                        // send, so make sure we hear about it when they do.
+                        if budget > 0 && hop.map.poll_idle_streams(cx) {
+                            did_things = true;
+                        }
                     }
 
                     break;
@@ -632,6 +778,19 @@ impl Reactor {
             }
         });
         let create_message = fut.await?;
+
+        // Find out which streams finished closing while we were working.
+        for (i, hop) in self.hops.iter_mut().enumerate() {
+            for id in hop.map.take_closed() {
+                trace!(
+                    "{}: Stream {} on hop {} is fully closed",
+                    self.unique_id,
+                    id,
+                    i
+                );
+            }
+        }
+
         if let Some(CtrlMsg::Create {
             recv_created,
             handshake,
@@ -696,6 +855,9 @@ impl Reactor {
         // function consumes the PendingClientCirc and only returns
         // a ClientCirc on success.
 
+        self.relay_early_left
+            .store(params.relay_early_budget(), Ordering::SeqCst);
+
         let (state, msg) = {
             // done like this because holding the RNG across an await boundary makes the future
             // non-Send
@@ -727,8 +889,7 @@ impl Reactor {
             Box::new(layer_fwd),
             Box::new(layer_back),
             params,
-        );
-        Ok(())
+        )
     }
 
     /// Use the (questionable!) CREATE_FAST handshake to connect to the
@@ -803,15 +964,16 @@ impl Reactor {
         fwd: Box<dyn OutboundClientLayer + 'static + Send>,
         rev: Box<dyn InboundClientLayer + 'static + Send>,
         params: &CircParameters,
-    ) {
-        let hop = crate::circuit::reactor::CircHop::new(
-            require_sendme_auth,
-            params.initial_send_window(),
-        );
+    ) -> Result<()> {
+        let mut hop = crate::circuit::reactor::CircHop::new(require_sendme_auth, params)?;
+        if self.draining {
+            hop.map.begin_drain();
+        }
         self.hops.push(hop);
         self.crypto_in.add_layer(rev);
         self.crypto_out.add_layer(fwd);
         self.num_hops.fetch_add(1, Ordering::SeqCst);
+        Ok(())
     }
 
     /// Handle a RELAY cell on this circuit with stream ID 0.
@@ -837,30 +999,51 @@ impl Reactor {
 
         // For all other command types, we'll only get them in response
         // to another command, which should have registered a responder.
-        //
-        // TODO: that means that service-introduction circuits will need
-        // a different implementation, but that should be okay. We'll work
-        // something out.
         if let Some((mut handler, done)) = self.meta_handler.take() {
-            if handler.expected_hop() == hopnum {
-                // Somebody was waiting for a message -- maybe this message
-                let ret = handler.finish(msg, self);
-                trace!(
-                    "{}: meta handler completed with result: {:?}",
-                    self.unique_id,
-                    ret
-                );
-                let _ = done.send(ret); // don't care if sender goes away
-                Ok(CellStatus::Continue)
-            } else {
+            if handler.expected_hop() != hopnum {
                 // Somebody wanted a message from a different hop!  Put this
                 // one back.
                 self.meta_handler = Some((handler, done));
-                Err(Error::CircProto(format!(
+                return Err(Error::CircProto(format!(
                     "Unexpected {} cell from hop {} on client circuit",
                     msg.cmd(),
                     hopnum,
+                )));
+            }
+            let disposition = if handler.expected_commands().contains(&msg.cmd()) {
+                // Somebody was waiting for a message -- maybe this message
+                handler.handle(msg, self)
+            } else {
+                let wanted = handler
+                    .expected_commands()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+                MetaCellDisposition::Violation(Error::CircProto(format!(
+                    "wanted {}; got {}",
+                    wanted,
+                    msg.cmd()
                 )))
+            };
+            trace!(
+                "{}: meta handler returned {:?}",
+                self.unique_id,
+                disposition
+            );
+            match disposition {
+                MetaCellDisposition::Done(ret) => {
+                    let _ = done.send(ret); // don't care if sender goes away
+                    Ok(CellStatus::Continue)
+                }
+                MetaCellDisposition::Continue => {
+                    self.meta_handler = Some((handler, done));
+                    Ok(CellStatus::Continue)
+                }
+                MetaCellDisposition::Violation(e) => {
+                    let _ = done.send(Err(e.clone())); // don't care if sender goes away
+                    Err(e)
+                }
             }
         } else {
             // No need to call shutdown here, since this error will
@@ -896,7 +1079,7 @@ impl Reactor {
                 }
             }
         };
-        hop.sendwindow.put(auth)?;
+        hop.map.note_circuit_sendme(auth)?;
         Ok(CellStatus::Continue)
     }
 
@@ -959,6 +1142,26 @@ impl Reactor {
         Ok(())
     }
 
+    /// Use up one of this circuit's RELAY_EARLY cells, if there are any left.
+    ///
+    /// Return false if the budget is already exhausted: the caller should
+    /// send a plain RELAY cell instead.
+    fn take_relay_early(&self) -> bool {
+        let taken = self
+            .relay_early_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if !taken {
+            trace!(
+                "{}: No RELAY_EARLY cells left; sending RELAY instead",
+                self.unique_id
+            );
+        }
+        taken
+    }
+
     /// Encode the relay cell `cell`, encrypt it, and send it to the 'hop'th hop.
     ///
     /// Does not check whether the cell is well-formed or reasonable.
@@ -971,6 +1174,10 @@ impl Reactor {
     ) -> Result<()> {
         let c_t_w = sendme::cell_counts_towards_windows(&cell);
         let stream_id = cell.stream_id();
+        let data_len = match cell.msg() {
+            RelayMsg::Data(d) => d.as_ref().len(),
+            _ => 0,
+        };
         // Check whether the hop send window is empty, if this cell counts towards windows.
         // NOTE(eta): It is imperative this happens *before* calling encrypt() below, otherwise
         //            we'll have cells rejected due to a protocol violation! (Cells have to be
@@ -978,7 +1185,7 @@ impl Reactor {
         if c_t_w {
             let hop_num = Into::<usize>::into(hop);
             let hop = &mut self.hops[hop_num];
-            if hop.sendwindow.window() == 0 {
+            if hop.map.circuit_window_remaining() == 0 {
                 // Send window is empty! Push this cell onto the hop's outbound queue, and it'll
                 // get sent later.
                 trace!(
@@ -991,6 +1198,10 @@ impl Reactor {
                 return Ok(());
             }
         }
+        // Decide whether this is a RELAY_EARLY cell now, since we can't
+        // look at our budget while the encryption tag borrows our crypto
+        // state.
+        let early = early && self.take_relay_early();
         let mut body: RelayCellBody = cell.encode(&mut rand::thread_rng())?.into();
         let tag = self.crypto_out.encrypt(&mut body, hop)?;
         // NOTE(eta): Now that we've encrypted the cell, we *must* either send it or abort
@@ -1006,24 +1217,17 @@ impl Reactor {
         if c_t_w {
             let hop_num = Into::<usize>::into(hop);
             let hop = &mut self.hops[hop_num];
-            // checked by earlier conditional, so this shouldn't fail
-            hop.sendwindow.take(tag)?;
-            if !stream_id.is_zero() {
-                // We need to decrement the stream-level sendme window.
-                // Stream data cells should only be dequeued and fed into this function if
-                // the window is above zero, so we don't need to worry about enqueuing things.
-                if let Some(window) = hop.map.get_mut(stream_id).and_then(StreamEnt::send_window) {
-                    window.take(&())?;
-                } else {
-                    warn!(
-                        "{}: sending a relay cell for non-existent or non-open stream with ID {}!",
-                        self.unique_id, stream_id
-                    );
-                    return Err(Error::CircProto(format!(
-                        "tried to send a relay cell on non-open stream {}",
-                        stream_id
-                    )));
-                }
+            // The circuit window was checked by the earlier conditional.
+            // Stream data cells should only be dequeued and fed into this
+            // function if the stream window is above zero, so we don't need
+            // to worry about enqueuing things.  Either way, this checks
+            // both windows before taking from either.
+            if let Err(e) = hop.map.take_send_windows(stream_id, tag, data_len) {
+                warn!(
+                    "{}: couldn't send a relay cell on stream {}: {}",
+                    self.unique_id, stream_id, e
+                );
+                return Err(e);
             }
         }
         self.send_msg_direct(cx, msg)
@@ -1083,15 +1287,60 @@ impl Reactor {
                 message,
                 sender,
                 rx,
+                priority,
+                done,
+            } => {
+                let ret = self.begin_stream(cx, hop_num, message, sender, rx, priority);
+                let _ = done.send(ret); // don't care if sender goes away
+            }
+            CtrlMsg::QueryStreamStats {
+                hop_num,
+                stream_id,
+                done,
+            } => {
+                let stats = self
+                    .hop_mut(hop_num)
+                    .and_then(|hop| hop.map.stream_stats(stream_id));
+                let _ = done.send(Ok(stats)); // don't care if sender goes away
+            }
+            CtrlMsg::QueryClosedReason {
+                hop_num,
+                stream_id,
                 done,
             } => {
-                let ret = self.begin_stream(cx, hop_num, message, sender, rx);
+                let reason = self
+                    .hop_mut(hop_num)
+                    .and_then(|hop| hop.map.closed_reason(stream_id));
+                let _ = done.send(Ok(reason)); // don't care if sender goes away
+            }
+            CtrlMsg::QueryAllocatorState { hop_num, done } => {
+                let ret = match self.hop_mut(hop_num) {
+                    Some(hop) => Ok(hop.map.allocator_state()),
+                    None => Err(Error::from(internal!(
+                        "Asked for allocator state of nonexistent hop {}",
+                        hop_num
+                    ))),
+                };
                 let _ = done.send(ret); // don't care if sender goes away
             }
             CtrlMsg::SendSendme { stream_id, hop_num } => {
                 let sendme = Sendme::new_empty();
                 let cell = RelayCell::new(stream_id, sendme.into());
                 self.send_relay_cell(cx, hop_num, false, cell)?;
+                if let Some(hop) = self.hop_mut(hop_num) {
+                    hop.map.note_sendme_sent(stream_id);
+                }
+            }
+            CtrlMsg::BeginDrain { done } => {
+                self.draining = true;
+                for hop in self.hops.iter_mut() {
+                    hop.map.begin_drain();
+                }
+                let _ = done.send(Ok(())); // don't care if sender goes away
+            }
+            CtrlMsg::QueryDrained { done } => {
+                let drained = self.draining && self.hops.iter().all(|hop| hop.map.is_drained());
+                let _ = done.send(Ok(drained)); // don't care if sender goes away
             }
             #[cfg(test)]
             CtrlMsg::AddFakeHop {
@@ -1112,13 +1361,17 @@ impl Reactor {
 
                 let fwd = Box::new(DummyCrypto::new(fwd_lasthop));
                 let rev = Box::new(DummyCrypto::new(rev_lasthop));
-                self.add_hop(require_sendme_auth, fwd, rev, &params);
-                let _ = done.send(Ok(()));
+                if self.hops.is_empty() {
+                    self.relay_early_left
+                        .store(params.relay_early_budget(), Ordering::SeqCst);
+                }
+                let ret = self.add_hop(require_sendme_auth, fwd, rev, &params);
+                let _ = done.send(ret);
             }
             #[cfg(test)]
             CtrlMsg::QuerySendWindow { hop, done } => {
                 let _ = done.send(if let Some(hop) = self.hop_mut(hop) {
-                    Ok(hop.sendwindow.window_and_expected_tags())
+                    Ok(hop.map.circuit_window_and_expected_tags())
                 } else {
                     Err(Error::from(internal!(
                         "received QuerySendWindow for unknown hop {:?}",
@@ -1130,6 +1383,10 @@ impl Reactor {
             CtrlMsg::SendRelayCell { hop, early, cell } => {
                 self.send_relay_cell(cx, hop, early, cell)?;
             }
+            #[cfg(test)]
+            CtrlMsg::InstallMetaHandler { handler, done } => {
+                self.set_meta_handler(handler.0, done)?;
+            }
         }
         Ok(())
     }
@@ -1143,15 +1400,45 @@ impl Reactor {
         message: RelayMsg,
         sender: mpsc::Sender<RelayMsg>,
         rx: mpsc::Receiver<RelayMsg>,
-    ) -> Result<StreamId> {
+        priority: u8,
+    ) -> Result<(StreamId, StreamRecvWindow)> {
         let hop = self
             .hop_mut(hopnum)
             .ok_or_else(|| Error::from(internal!("No such hop {:?}", hopnum)))?;
-        let send_window = StreamSendWindow::new(SEND_WINDOW_INIT);
-        let r = hop.map.add_ent(sender, rx, send_window)?;
+        let kind = match message {
+            RelayMsg::Resolve(_) => StreamKind::Resolve,
+            _ => StreamKind::Data,
+        };
+        let params = AddStreamParams::new(sender, rx, &hop.congestion)
+            .priority(priority)
+            .kind(kind)
+            .capacity(STREAM_READER_BUFFER);
+        let r = match hop.map.add_stream(params) {
+            Ok(r) => r,
+            Err(Error::IdRangeFull) => {
+                // Say how the IDs are being used, since a hop that is full
+                // of half-closed streams points to a different problem than
+                // one that is full of open ones.
+                let (n_used, n_open) = hop.map.n_ids_in_use();
+                warn!(
+                    "{}: No stream IDs left on hop {}: {} streams in use, of which {} are open.",
+                    self.unique_id, hopnum, n_used, n_open
+                );
+                return Err(Error::IdRangeFull);
+            }
+            Err(e) => return Err(e),
+        };
+        let recv_window = hop.congestion.new_stream_recv_window();
         let cell = RelayCell::new(r, message);
-        self.send_relay_cell(cx, hopnum, false, cell)?;
-        Ok(r)
+        if let Err(e) = self.send_relay_cell(cx, hopnum, false, cell) {
+            // The other side never heard of this stream, so there's no need
+            // to send it an END: we can just forget about it.
+            if let Some(hop) = self.hop_mut(hopnum) {
+                hop.map.drop_stream(r);
+            }
+            return Err(e);
+        }
+        Ok((r, recv_window))
     }
 
     /// Close the stream associated with `id` because the stream was
@@ -1159,6 +1446,10 @@ impl Reactor {
     ///
     /// If we have not already received an END cell on this stream, send one.
     fn close_stream(&mut self, cx: &mut Context<'_>, hopnum: HopNum, id: StreamId) -> Result<()> {
+        // Tag everything we log about this stream with its hop and ID.
+        let span = trace_span!("stream", hop = ?hopnum, stream_id = ?id);
+        let _enter = span.enter();
+
         // Mark the stream as closing.
         let hop = self.hop_mut(hopnum).ok_or_else(|| {
             Error::from(internal!(
@@ -1167,13 +1458,32 @@ impl Reactor {
             ))
         })?;
 
-        let should_send_end = hop.map.terminate(id)?;
-        trace!(
-            "{}: Ending stream {}; should_send_end={:?}",
-            self.unique_id,
-            id,
-            should_send_end
-        );
+        let (should_send_end, halfstream, dropped) = hop.map.terminate_take(id)?;
+        let windows = halfstream.map(|hs| (hs.send_window(), hs.recv_window(), hs.connected_ok()));
+        match windows {
+            Some((sendw, recvw, connected_ok)) => trace!(
+                "{}: Ending stream {}; should_send_end={:?}; half-stream send window={} recv window={} connected_ok={}",
+                self.unique_id,
+                id,
+                should_send_end,
+                sendw,
+                recvw,
+                connected_ok
+            ),
+            None => trace!(
+                "{}: Ending stream {}; should_send_end={:?}",
+                self.unique_id,
+                id,
+                should_send_end
+            ),
+        }
+        if dropped > 0 {
+            // The stream was abandoned while data was still arriving.
+            debug!(
+                "{}: Stream {} closed after dropping {} cells",
+                self.unique_id, id, dropped
+            );
+        }
         // TODO: I am about 80% sure that we only send an END cell if
         // we didn't already get an END cell.  But I should double-check!
         if should_send_end == ShouldSendEnd::Send {
@@ -1277,77 +1587,26 @@ impl Reactor {
             return self.handle_meta_cell(hopnum, msg);
         }
 
+        // Tag everything we log about this stream with its hop and ID.
+        let span = trace_span!("stream", hop = ?hopnum, stream_id = ?streamid);
+        let _enter = span.enter();
+
+        let unique_id = self.unique_id;
         let hop = self
             .hop_mut(hopnum)
             .ok_or_else(|| Error::CircProto("Cell from nonexistent hop!".into()))?;
-        match hop.map.get_mut(streamid) {
-            Some(StreamEnt::Open {
-                sink,
-                send_window,
-                dropped,
-                ref mut received_connected,
-                ..
-            }) => {
-                // The stream for this message exists, and is open.
-
-                if let RelayMsg::Sendme(_) = msg {
-                    // We need to handle sendmes here, not in the stream's
-                    // recv() method, or else we'd never notice them if the
-                    // stream isn't reading.
-                    send_window.put(Some(()))?;
-                    return Ok(CellStatus::Continue);
-                }
-
-                if matches!(msg, RelayMsg::Connected(_)) {
-                    // Remember that we've received a Connected cell, and can't get another,
-                    // even if we become a HalfStream.  (This rule is enforced separately at
-                    // DataStreamReader.)
-                    *received_connected = true;
-                }
-
-                // Remember whether this was an end cell: if so we should
-                // close the stream.
-                let is_end_cell = matches!(msg, RelayMsg::End(_));
-
-                // TODO: Add a wrapper type here to reject cells that should
-                // never go to a client, like BEGIN.
-                if let Err(e) = sink.try_send(msg) {
-                    if e.is_full() {
-                        // If we get here, we either have a logic bug (!), or an attacker
-                        // is sending us more cells than we asked for via congestion control.
-                        return Err(Error::CircProto(format!(
-                            "Stream sink would block; received too many cells on stream ID {}",
-                            streamid,
-                        )));
-                    }
-                    if e.is_disconnected() && c_t_w {
-                        // the other side of the stream has gone away; remember
-                        // that we received a cell that we couldn't queue for it.
-                        //
-                        // Later this value will be recorded in a half-stream.
-                        *dropped += 1;
-                    }
-                }
-                if is_end_cell {
-                    hop.map.end_received(streamid)?;
-                }
-            }
-            Some(StreamEnt::EndSent(halfstream)) => {
-                // We sent an end but maybe the other side hasn't heard.
-
-                if matches!(msg, RelayMsg::End(_)) {
-                    hop.map.end_received(streamid)?;
-                } else {
-                    halfstream.handle_msg(&msg)?;
-                }
-            }
-            _ => {
-                // No stream wants this message.
-                return Err(Error::CircProto(
-                    "Cell received on nonexistent stream!?".into(),
-                ));
-            }
+        // The stream map decides whether this message makes sense on the
+        // stream, and delivers it if so.
+        let result = hop.map.deliver(streamid, msg);
+        if result.is_err() && hop.map.protocol_errors() > 0 {
+            debug!(
+                "{}: Hop {} sent {} END cells for closed or nonexistent streams",
+                unique_id,
+                hopnum,
+                hop.map.protocol_errors()
+            );
         }
+        result?;
         Ok(CellStatus::Continue)
     }
 
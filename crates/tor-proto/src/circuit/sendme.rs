@@ -71,6 +71,8 @@ where
 {
     /// Current value for this window
     window: u16,
+    /// Number of cells acknowledged by each SENDME on this window.
+    increment: u16,
     /// Tag values that incoming "SENDME" messages need to match in order
     /// for us to send more data.
     tags: VecDeque<T>,
@@ -78,6 +80,22 @@ where
     _dummy: std::marker::PhantomData<P>,
 }
 
+impl<P, T> std::fmt::Debug for SendWindow<P, T>
+where
+    P: WindowParams,
+    T: PartialEq + Eq + Clone,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only say how many tags we're waiting for; the tags themselves
+        // aren't useful in a log.
+        f.debug_struct("SendWindow")
+            .field("window", &self.window)
+            .field("increment", &self.increment)
+            .field("n_tags", &self.tags.len())
+            .finish()
+    }
+}
+
 /// Helper: parametrizes a window to determine its maximum and its increment.
 pub(crate) trait WindowParams {
     /// Largest allowable value for this window.
@@ -118,10 +136,24 @@ where
 {
     /// Construct a new SendWindow.
     pub(crate) fn new(window: u16) -> SendWindow<P, T> {
-        let increment = P::increment();
-        let capacity = (window + increment - 1) / increment;
+        Self::new_with_increment(window, P::increment())
+    }
+
+    /// Construct a new SendWindow, where each SENDME acknowledges
+    /// `increment` cells instead of the default for `P`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `increment` is zero.
+    pub(crate) fn new_with_increment(window: u16, increment: u16) -> SendWindow<P, T> {
+        assert!(
+            increment > 0,
+            "Tried to make a SendWindow with no increment"
+        );
+        let capacity = window / increment + 1;
         SendWindow {
             window,
+            increment,
             tags: VecDeque::with_capacity(capacity as usize),
             _dummy: std::marker::PhantomData,
         }
@@ -141,7 +173,7 @@ where
     {
         if let Some(val) = self.window.checked_sub(1) {
             self.window = val;
-            if self.window % P::increment() == 0 {
+            if self.window % self.increment == 0 {
                 // We record this tag.
                 // TODO: I'm not saying that this cell in particular
                 // matches the spec, but Tor seems to like it.
@@ -187,7 +219,7 @@ where
 
         let v = self
             .window
-            .checked_add(P::increment())
+            .checked_add(self.increment)
             .ok_or_else(|| Error::from(internal!("Overflow on SENDME window")))?;
         self.window = v;
         Ok(v)
@@ -213,6 +245,8 @@ pub(crate) struct RecvWindow<P: WindowParams> {
     /// Number of cells that we'd be willing to receive on this window
     /// before sending a SENDME.
     window: u16,
+    /// Number of cells acknowledged by each SENDME we send.
+    increment: u16,
     /// Marker type to tell the compiler that the P type is used.
     _dummy: std::marker::PhantomData<P>,
 }
@@ -220,8 +254,23 @@ pub(crate) struct RecvWindow<P: WindowParams> {
 impl<P: WindowParams> RecvWindow<P> {
     /// Create a new RecvWindow.
     pub(crate) fn new(window: u16) -> RecvWindow<P> {
+        Self::new_with_increment(window, P::increment())
+    }
+
+    /// Create a new RecvWindow, where we send a SENDME after every
+    /// `increment` cells instead of the default for `P`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `increment` is zero.
+    pub(crate) fn new_with_increment(window: u16, increment: u16) -> RecvWindow<P> {
+        assert!(
+            increment > 0,
+            "Tried to make a RecvWindow with no increment"
+        );
         RecvWindow {
             window,
+            increment,
             _dummy: std::marker::PhantomData,
         }
     }
@@ -237,7 +286,7 @@ impl<P: WindowParams> RecvWindow<P> {
             self.window = x;
             // TODO: same note as in SendWindow.take(). I don't know if
             // this truly matches the spec, but tor accepts it.
-            Ok(x % P::increment() == 0)
+            Ok(x % self.increment == 0)
         } else {
             Err(Error::CircProto(
                 "Received a data cell in violation of a window".into(),
@@ -245,24 +294,16 @@ impl<P: WindowParams> RecvWindow<P> {
         }
     }
 
-    /// Reduce this window by `n`; give an error if this is not possible.
-    pub(crate) fn decrement_n(&mut self, n: u16) -> crate::Result<()> {
-        let v = self.window.checked_sub(n);
-        if let Some(x) = v {
-            self.window = x;
-            Ok(())
-        } else {
-            Err(crate::Error::CircProto(
-                "Received too many cells on a stream".into(),
-            ))
-        }
+    /// Return the current receive window value.
+    pub(crate) fn window(&self) -> u16 {
+        self.window
     }
 
     /// Called when we've just sent a SENDME.
     pub(crate) fn put(&mut self) {
         self.window = self
             .window
-            .checked_add(P::increment())
+            .checked_add(self.increment)
             .expect("Overflow detected while attempting to increment window");
     }
 }
@@ -296,6 +337,33 @@ mod test {
         assert!(cell_counts_towards_windows(&RelayCell::new(128.into(), m)));
     }
 
+    #[test]
+    fn recvwindow_custom_increment() {
+        let mut w: RecvWindow<StreamParams> = RecvWindow::new_with_increment(100, 25);
+
+        for _ in 0..24 {
+            assert!(!w.take().unwrap());
+        }
+        assert!(w.take().unwrap());
+        assert_eq!(w.window, 75);
+        w.put();
+        assert_eq!(w.window, 100);
+    }
+
+    #[test]
+    fn sendwindow_custom_increment() -> Result<()> {
+        let mut w: SendWindow<StreamParams, NoTag> = SendWindow::new_with_increment(100, 10);
+        for _ in 0_usize..25 {
+            w.take(&())?;
+        }
+        assert_eq!(w.window, 75);
+        assert_eq!(w.tags.len(), 2);
+        assert_eq!(w.put(Some(()))?, 85);
+        assert_eq!(w.put(Some(()))?, 95);
+        assert!(w.put(Some(())).is_err());
+        Ok(())
+    }
+
     #[test]
     fn recvwindow() {
         let mut w: RecvWindow<StreamParams> = RecvWindow::new(500);
@@ -306,19 +374,41 @@ mod test {
         assert!(w.take().unwrap());
         assert_eq!(w.window, 450);
 
-        assert!(w.decrement_n(123).is_ok());
-        assert_eq!(w.window, 327);
-
         w.put();
-        assert_eq!(w.window, 377);
+        assert_eq!(w.window, 500);
 
-        // failing decrement.
-        assert!(w.decrement_n(400).is_err());
+        for _ in 0..500 {
+            assert!(w.take().is_ok());
+        }
+        assert_eq!(w.window, 0);
         // failing take.
-        assert!(w.decrement_n(377).is_ok());
         assert!(w.take().is_err());
     }
 
+    #[test]
+    fn stream_window_accessors() -> Result<()> {
+        let mut sendw = StreamSendWindow::new(500);
+        let mut recvw = StreamRecvWindow::new(500);
+        assert_eq!(sendw.window(), 500);
+        assert_eq!(recvw.window(), 500);
+
+        for _ in 0_usize..50 {
+            sendw.take(&())?;
+            recvw.take()?;
+        }
+        // Looking at a window doesn't change it.
+        assert_eq!(sendw.window(), 450);
+        assert_eq!(sendw.window(), 450);
+        assert_eq!(recvw.window(), 450);
+        assert_eq!(recvw.window(), 450);
+
+        sendw.put(Some(()))?;
+        recvw.put();
+        assert_eq!(sendw.window(), 500);
+        assert_eq!(recvw.window(), 500);
+        Ok(())
+    }
+
     fn new_sendwindow() -> SendWindow<CircParams, &'static str> {
         SendWindow::new(1000)
     }
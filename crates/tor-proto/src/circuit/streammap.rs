@@ -2,6 +2,7 @@
 
 use crate::circuit::halfstream::HalfStream;
 use crate::circuit::sendme;
+use crate::circuit::CongestionParams;
 use crate::{Error, Result};
 /// Mapping from stream ID to streams.
 // NOTE: This is a work in progress and I bet I'll refactor it a lot;
@@ -9,36 +10,101 @@ use crate::{Error, Result};
 use tor_cell::relaycell::{msg::RelayMsg, StreamId};
 
 use futures::channel::mpsc;
+use futures::Stream;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use tor_error::internal;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tor_error::{bad_api_usage, internal};
 
 use rand::Rng;
 
-use crate::circuit::reactor::RECV_WINDOW_INIT;
-use crate::circuit::sendme::StreamRecvWindow;
 use tracing::info;
 
+/// Something that we can deliver the relay messages for an open stream into.
+///
+/// In production, this is the sending half of the channel that the stream's
+/// reader listens on; tests can substitute a sink that records what it is
+/// given, or that fails on demand.
+pub(super) trait StreamSink {
+    /// Try to deliver `msg` to the stream, without blocking.
+    fn try_send(&mut self, msg: RelayMsg) -> std::result::Result<(), StreamSinkError>;
+}
+
+/// An error from [`StreamSink::try_send`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum StreamSinkError {
+    /// The sink has no room for another message.
+    ///
+    /// Since the stream's receive window should keep this from happening,
+    /// it means that the other side sent more than it was allowed to.
+    Full,
+    /// The stream's reader has gone away, so nobody will see the message.
+    Closed,
+}
+
+impl StreamSink for mpsc::Sender<RelayMsg> {
+    fn try_send(&mut self, msg: RelayMsg) -> std::result::Result<(), StreamSinkError> {
+        mpsc::Sender::try_send(self, msg).map_err(|e| {
+            if e.is_full() {
+                StreamSinkError::Full
+            } else {
+                StreamSinkError::Closed
+            }
+        })
+    }
+}
+
 /// The entry for a stream.
-pub(super) enum StreamEnt {
+///
+/// `S` is the type of sink that we deliver the stream's incoming messages
+/// into; see [`StreamSink`].
+pub(super) enum StreamEnt<S = mpsc::Sender<RelayMsg>> {
     /// An open stream.
     Open {
         /// Sink to send relay cells tagged for this stream into.
-        sink: mpsc::Sender<RelayMsg>,
+        sink: S,
         /// Stream for cells that should be sent down this stream.
         rx: mpsc::Receiver<RelayMsg>,
+        /// A message that [`peek_pending`] took from `rx` to find out
+        /// whether it was empty.
+        ///
+        /// This goes out before anything still in `rx`.
+        peeked: Option<RelayMsg>,
         /// Send window, for congestion control purposes.
         send_window: sendme::StreamSendWindow,
-        /// Number of cells dropped due to the stream disappearing before we can
-        /// transform this into an `EndSent`.
-        dropped: u16,
-        /// True iff we've received a CONNECTED cell on this stream.
+        /// Receive window, used to detect whether the other side sends us
+        /// too many cells, and to build a half-stream once we send an END.
+        ///
+        /// This is decremented as cells arrive (see
+        /// [`StreamMap::note_data_received`]), whether or not the stream's
+        /// reader is still around to receive them, and incremented when the
+        /// reader asks us to send a SENDME.
+        recv_window: sendme::StreamRecvWindow,
+        /// True iff we've received a CONNECTED cell on this stream (or a
+        /// RESOLVED cell, if it is a resolve stream).
         /// (This is redundant with `DataStreamReader::connected`.)
         received_connected: bool,
+        /// What kind of stream this is, and so which messages it accepts.
+        kind: StreamKind,
+        /// Running totals of the data sent and received on this stream.
+        stats: StreamStats,
+        /// Number of incoming cells that we've had to throw away because
+        /// the stream's reader had gone away.
+        ///
+        /// (These cells still count against `recv_window`; we only keep
+        /// this number for diagnostics.)
+        dropped: u16,
+        /// How eagerly [`StreamMap::next_ready_stream`] should pick this
+        /// stream over others: higher values go first, and get more turns.
+        priority: u8,
     },
     /// A stream for which we have received an END cell, but not yet
     /// had the stream object get dropped.
-    EndReceived,
+    EndReceived {
+        /// The byte counters that the stream had when its END arrived.
+        stats: StreamStats,
+    },
     /// A stream for which we have sent an END cell but not yet received an END
     /// cell.
     ///
@@ -47,15 +113,109 @@ pub(super) enum StreamEnt {
     EndSent(HalfStream),
 }
 
-impl StreamEnt {
-    /// Retrieve the send window for this stream, if it is open.
-    pub(super) fn send_window(&mut self) -> Option<&mut sendme::StreamSendWindow> {
+impl<S> std::fmt::Debug for StreamEnt<S> {
+    /// Describe this entry, without trying to print its channels.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StreamEnt::Open {
-                ref mut send_window,
+                send_window,
+                recv_window,
+                received_connected,
+                kind,
+                stats,
+                dropped,
+                priority,
+                peeked,
                 ..
-            } => Some(send_window),
-            _ => None,
+            } => f
+                .debug_struct("Open")
+                .field("send_window", &send_window.window())
+                .field("recv_window", &recv_window.window())
+                .field("received_connected", received_connected)
+                .field("kind", kind)
+                .field("stats", stats)
+                .field("dropped", dropped)
+                .field("priority", priority)
+                .field("has_peeked", &peeked.is_some())
+                .finish_non_exhaustive(),
+            StreamEnt::EndReceived { stats } => {
+                f.debug_struct("EndReceived").field("stats", stats).finish()
+            }
+            StreamEnt::EndSent(halfstream) => f.debug_tuple("EndSent").field(halfstream).finish(),
+        }
+    }
+}
+
+/// Running totals of the data sent and received on a single stream.
+///
+/// Unlike the flow-control windows, these counters only ever increase:
+/// they're meant for accounting and metrics, not for congestion control.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct StreamStats {
+    /// Total number of bytes of DATA cell payload sent on this stream.
+    pub(super) bytes_sent: u64,
+    /// Total number of bytes of DATA cell payload received on this stream.
+    pub(super) bytes_recv: u64,
+}
+
+impl StreamStats {
+    /// Return the total number of bytes of DATA cell payload sent on this
+    /// stream.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Return the total number of bytes of DATA cell payload received on
+    /// this stream.
+    pub fn bytes_recv(&self) -> u64 {
+        self.bytes_recv
+    }
+
+    /// Record that we sent a DATA cell with `len` bytes of payload.
+    pub(super) fn note_data_sent(&mut self, len: usize) {
+        self.bytes_sent = self.bytes_sent.saturating_add(len as u64);
+    }
+
+    /// Record that we received a DATA cell with `len` bytes of payload.
+    pub(super) fn note_data_received(&mut self, len: usize) {
+        self.bytes_recv = self.bytes_recv.saturating_add(len as u64);
+    }
+}
+
+/// Return value to indicate that a stream's receive window has reached a
+/// point where the other side will be waiting for a SENDME.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) struct SendmeNeeded;
+
+/// The kind of a stream, which decides which messages it may receive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum StreamKind {
+    /// A stream opened with BEGIN or BEGIN_DIR, which receives a
+    /// CONNECTED and then DATA.
+    Data,
+    /// A stream opened with RESOLVE, which receives a single RESOLVED.
+    Resolve,
+}
+
+impl StreamKind {
+    /// Return true if a stream of this kind may receive `msg`, given
+    /// whether it has already received its CONNECTED or RESOLVED.
+    ///
+    /// END and SENDME are fine on any stream, and we leave messages we
+    /// don't know about for the stream's reader to reject.
+    fn accepts(self, msg: &RelayMsg, received_connected: bool) -> bool {
+        use StreamKind::*;
+        match (msg, self) {
+            (RelayMsg::End(_), _) | (RelayMsg::Sendme(_), _) => true,
+            (RelayMsg::Connected(_), Data) => !received_connected,
+            (RelayMsg::Data(_), Data) => received_connected,
+            (RelayMsg::Resolved(_), Resolve) => !received_connected,
+            (RelayMsg::Connected(_), Resolve)
+            | (RelayMsg::Data(_), Resolve)
+            | (RelayMsg::Resolved(_), Data) => false,
+            // A client never gets asked to open a stream.
+            (RelayMsg::Begin(_), _) | (RelayMsg::BeginDir, _) | (RelayMsg::Resolve(_), _) => false,
+            (_, _) => true,
         }
     }
 }
@@ -70,20 +230,194 @@ pub(super) enum ShouldSendEnd {
     DontSend,
 }
 
+/// Everything [`StreamMap::add_stream`] needs to know about a new stream.
+///
+/// Make one with [`AddStreamParams::new`], and then override whichever
+/// defaults you need to.
+pub(super) struct AddStreamParams<S = mpsc::Sender<RelayMsg>> {
+    /// Sink to send relay cells tagged for this stream into.
+    sink: S,
+    /// Stream for cells that should be sent down this stream.
+    rx: mpsc::Receiver<RelayMsg>,
+    /// The stream's initial send window.
+    send_window: sendme::StreamSendWindow,
+    /// The stream's initial receive window.
+    recv_window: sendme::StreamRecvWindow,
+    /// The stream's scheduling priority.  Defaults to 0.
+    priority: u8,
+    /// The kind of the stream.  Defaults to [`StreamKind::Data`].
+    kind: StreamKind,
+    /// How many messages `sink` can hold, if we know.
+    capacity: Option<usize>,
+    /// True if the stream is already connected, so that it won't accept a
+    /// CONNECTED (or RESOLVED) cell.  Defaults to false.
+    connected: bool,
+}
+
+impl<S> AddStreamParams<S> {
+    /// Describe a new stream that delivers messages into `sink` and takes
+    /// messages to send from `rx`, with flow-control windows taken from
+    /// `params`.
+    pub(super) fn new(sink: S, rx: mpsc::Receiver<RelayMsg>, params: &CongestionParams) -> Self {
+        AddStreamParams {
+            sink,
+            rx,
+            send_window: params.new_stream_send_window(),
+            recv_window: params.new_stream_recv_window(),
+            priority: 0,
+            kind: StreamKind::Data,
+            capacity: None,
+            connected: false,
+        }
+    }
+
+    /// Give the stream scheduling priority `priority`: see
+    /// [`StreamMap::next_ready_stream`].
+    pub(super) fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set what kind of stream this is.
+    pub(super) fn kind(mut self, kind: StreamKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Record that the stream's sink can hold at most `capacity` messages.
+    ///
+    /// [`StreamMap::add_stream`] then refuses a receive window larger than
+    /// that, since it would let the other side overfill the sink without
+    /// breaking any rules.
+    pub(super) fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Treat the stream as already connected.
+    #[allow(dead_code)] // Stream resumption code will need this.
+    pub(super) fn connected(mut self) -> Self {
+        self.connected = true;
+        self
+    }
+}
+
+/// Why a stream stopped being open.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ClosedReason {
+    /// The other side sent an END cell before we did.
+    PeerEnd,
+    /// We sent an END cell before the other side did.
+    LocalEnd,
+    /// We dropped the stream without sending an END cell.
+    Dropped,
+}
+
+/// The largest number of streams that can exist on a single hop at once.
+///
+/// (Every nonzero 16-bit StreamId can be in use.)
+const MAX_STREAMS: usize = u16::MAX as usize;
+
+/// The number of removed streams whose [`ClosedReason`] a [`StreamMap`]
+/// remembers.
+const RECENTLY_CLOSED_LEN: usize = 64;
+
+/// The most turns that a single stream can get in one scheduling round of
+/// [`StreamMap::next_ready_stream`].
+///
+/// A ready stream always gets at least one turn per round, so this bounds
+/// how much more of the circuit a high-priority stream can take than a
+/// low-priority one.
+const MAX_TURNS_PER_ROUND: u16 = 16;
+
+/// Return how many turns a stream with priority `priority` gets in each
+/// scheduling round: one more than its priority, up to
+/// [`MAX_TURNS_PER_ROUND`].
+fn turns_per_round(priority: u8) -> u16 {
+    (u16::from(priority) + 1).min(MAX_TURNS_PER_ROUND)
+}
+
 /// A map from stream IDs to stream entries. Each circuit has one for each
 /// hop.
-pub(super) struct StreamMap {
+///
+/// `S` is the type of sink that open streams use; see [`StreamSink`].
+pub(super) struct StreamMap<S = mpsc::Sender<RelayMsg>> {
     /// Map from StreamId to StreamEnt.  If there is no entry for a
     /// StreamId, that stream doesn't exist.
-    m: HashMap<StreamId, StreamEnt>,
+    m: HashMap<StreamId, StreamEnt<S>>,
     /// The next StreamId that we should use for a newly allocated
     /// circuit.  (0 is not a valid streamID).
     next_stream_id: u16,
+    /// The StreamId most recently returned by
+    /// [`StreamMap::next_ready_stream`], or 0 if there hasn't been one.
+    ///
+    /// Among streams of equal priority, the next round will start just
+    /// after this ID, so that every ready stream gets its turn.
+    ready_cursor: u16,
+    /// The streams that still have turns left in the current scheduling
+    /// round, in the order that [`StreamMap::next_ready_stream`] will visit
+    /// them, along with how many turns each one has left.
+    ///
+    /// Once this is empty, the round is over, and we start a new one.
+    round: VecDeque<(StreamId, u16)>,
+    /// How many times has the other side sent us an END cell for a stream
+    /// that doesn't exist, or that it had already ended?
+    ///
+    /// A well-behaved peer never does this.
+    protocol_errors: u32,
+    /// The streams that have been removed from this map since the last call
+    /// to [`StreamMap::take_closed`].
+    closed: Vec<StreamId>,
+    /// The streams that have been removed from this map most recently, and
+    /// why they closed, oldest first.
+    ///
+    /// This holds at most [`RECENTLY_CLOSED_LEN`] entries.
+    recently_closed: VecDeque<(StreamId, ClosedReason)>,
+    /// The circuit-level window that limits how many cells we can send to
+    /// this hop, across all of its streams.
+    ///
+    /// A stream can only send a cell when both this window and its own
+    /// send window permit it.
+    circ_window: sendme::CircSendWindow,
+    /// If true, this map has stopped accepting new streams, and is waiting
+    /// for its existing ones to close.
+    ///
+    /// See [`StreamMap::begin_drain`].
+    draining: bool,
 }
 
-impl StreamMap {
-    /// Make a new empty StreamMap.
-    pub(super) fn new() -> Self {
+/// Return true if an open stream, whose outgoing queue is `rx`, has a
+/// message queued for us to send, without waiting for one.
+///
+/// To find out, we may take a message from `rx` and hold on to it in
+/// `peeked`; [`StreamMap::poll_next_msg`] returns that message before
+/// anything else, so nothing is lost or reordered.  Also returns true once
+/// the stream's writer has gone away, since the caller still has to poll the
+/// stream to find that out.
+///
+/// Unlike polling, this doesn't arrange for anybody to be woken up when a
+/// message arrives: see [`StreamMap::poll_idle_streams`].
+fn peek_pending(rx: &mut mpsc::Receiver<RelayMsg>, peeked: &mut Option<RelayMsg>) -> bool {
+    if peeked.is_some() {
+        return true;
+    }
+    match rx.try_next() {
+        Ok(Some(msg)) => {
+            *peeked = Some(msg);
+            true
+        }
+        // The writer has gone away.
+        Ok(None) => true,
+        // Nothing is queued yet.
+        Err(_) => false,
+    }
+}
+
+impl<S: StreamSink> StreamMap<S> {
+    /// Make a new empty StreamMap, whose circuit-level send window starts
+    /// at `circ_window`.
+    pub(super) fn new(circ_window: u16) -> Self {
         let mut rng = rand::thread_rng();
         let next_stream_id: u16 = loop {
             let v: u16 = rng.gen();
@@ -91,30 +425,182 @@ impl StreamMap {
                 break v;
             }
         };
+        Self::with_next_id(circ_window, next_stream_id)
+    }
+
+    /// Make a new empty StreamMap, whose circuit-level send window starts
+    /// at `circ_window`, and which will start allocating stream IDs at
+    /// `next_stream_id`.
+    ///
+    /// This is for restoring the state saved with
+    /// [`StreamMap::allocator_state`], so that we don't reuse IDs that a
+    /// peer might still remember.  Returns an error if `next_stream_id` is 0,
+    /// since that isn't a valid StreamId.
+    pub(super) fn new_with_next_id(circ_window: u16, next_stream_id: u16) -> Result<Self> {
+        if next_stream_id == 0 {
+            return Err(bad_api_usage!("Tried to start allocating stream IDs at 0").into());
+        }
+        Ok(Self::with_next_id(circ_window, next_stream_id))
+    }
+
+    /// Helper: make a new empty StreamMap with a circuit-level send window
+    /// of `circ_window`, whose next stream ID is `next_stream_id`.
+    ///
+    /// The caller must make sure that `next_stream_id` is not 0.
+    fn with_next_id(circ_window: u16, next_stream_id: u16) -> Self {
         StreamMap {
             m: HashMap::new(),
             next_stream_id,
+            ready_cursor: 0,
+            round: VecDeque::new(),
+            protocol_errors: 0,
+            closed: Vec::new(),
+            recently_closed: VecDeque::new(),
+            circ_window: sendme::CircSendWindow::new(circ_window),
+            draining: false,
         }
     }
 
+    /// Return the number of END cells that the other side has sent us for
+    /// nonexistent or already-ended streams.
+    ///
+    /// This is for diagnostics only: each such END is already a protocol
+    /// violation, reported by [`StreamMap::end_received`].
+    pub(super) fn protocol_errors(&self) -> u32 {
+        self.protocol_errors
+    }
+
+    /// Return the state of this map's stream ID allocator: that is, the next
+    /// StreamId it will try to allocate.
+    ///
+    /// The result is never 0, and can be passed to
+    /// [`StreamMap::new_with_next_id`].
+    pub(super) fn allocator_state(&self) -> u16 {
+        // When the cursor wraps around to 0, add_stream skips ahead to 1.
+        std::cmp::max(self.next_stream_id, 1)
+    }
+
+    /// Return the IDs of every stream that has been removed from this map
+    /// since the last time this function was called, in the order they were
+    /// removed.
+    ///
+    /// A stream is removed once both sides have finished with it: that is,
+    /// once we've both sent and received an END, or once we've dropped a
+    /// stream that had already received an END.  Callers should drain this
+    /// regularly, so that they can clean up anything they were keeping for
+    /// those streams.
+    pub(super) fn take_closed(&mut self) -> Vec<StreamId> {
+        std::mem::take(&mut self.closed)
+    }
+
+    /// Return the reason that the stream with `id` stopped being open.
+    ///
+    /// Returns None if the stream is still open, or if it was removed from
+    /// the map so long ago that we have forgotten about it.
+    pub(super) fn closed_reason(&self, id: StreamId) -> Option<ClosedReason> {
+        match self.m.get(&id) {
+            Some(StreamEnt::Open { .. }) => None,
+            Some(StreamEnt::EndReceived { .. }) => Some(ClosedReason::PeerEnd),
+            Some(StreamEnt::EndSent(_)) => Some(ClosedReason::LocalEnd),
+            None => self
+                .recently_closed
+                .iter()
+                .rev()
+                .find(|(closed_id, _)| *closed_id == id)
+                .map(|(_, reason)| *reason),
+        }
+    }
+
+    /// Record that the stream with `id` has been removed from this map,
+    /// because of `reason`.
+    fn note_removed(&mut self, id: StreamId, reason: ClosedReason) {
+        self.closed.push(id);
+        if self.recently_closed.len() >= RECENTLY_CLOSED_LEN {
+            self.recently_closed.pop_front();
+        }
+        self.recently_closed.push_back((id, reason));
+    }
+
+    /// Stop accepting new streams on this map, but let the existing ones
+    /// keep working until they close.
+    ///
+    /// Once this is called, [`StreamMap::add_stream`] fails with
+    /// [`Error::Draining`].  There is no way to undo it.
+    pub(super) fn begin_drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// Return true if [`StreamMap::begin_drain`] has been called, and every
+    /// stream in this map has since finished.
+    ///
+    /// A stream counts as finished once we have sent an END on it, even if
+    /// the other side hasn't acknowledged that yet: nothing more will be
+    /// sent on it, so the circuit can be torn down safely.
+    pub(super) fn is_drained(&self) -> bool {
+        self.draining
+            && self
+                .m
+                .values()
+                .all(|ent| matches!(ent, StreamEnt::EndSent(_)))
+    }
+
     /// Get the `HashMap` inside this stream map.
-    pub(super) fn inner(&mut self) -> &mut HashMap<StreamId, StreamEnt> {
+    pub(super) fn inner(&mut self) -> &mut HashMap<StreamId, StreamEnt<S>> {
         &mut self.m
     }
 
-    /// Add an entry to this map; return the newly allocated StreamId.
-    pub(super) fn add_ent(
-        &mut self,
-        sink: mpsc::Sender<RelayMsg>,
-        rx: mpsc::Receiver<RelayMsg>,
-        send_window: sendme::StreamSendWindow,
-    ) -> Result<StreamId> {
+    /// Return true if there is a free StreamId in this map.
+    ///
+    /// Streams that are half-closed still count against the limit, since
+    /// their IDs are still in use.
+    pub(super) fn has_room(&self) -> bool {
+        self.m.len() < MAX_STREAMS
+    }
+
+    /// Add an entry to this map, as described by `params`; return the newly
+    /// allocated StreamId.
+    ///
+    /// Fails with [`Error::Draining`] if [`StreamMap::begin_drain`] has been
+    /// called.
+    pub(super) fn add_stream(&mut self, params: AddStreamParams<S>) -> Result<StreamId> {
+        if self.draining {
+            return Err(Error::Draining);
+        }
+        // Don't bother looking for an ID if we know there aren't any.
+        if !self.has_room() {
+            return Err(Error::IdRangeFull);
+        }
+        let AddStreamParams {
+            sink,
+            rx,
+            send_window,
+            recv_window,
+            priority,
+            kind,
+            capacity,
+            connected,
+        } = params;
+        if let Some(capacity) = capacity {
+            if usize::from(recv_window.window()) > capacity {
+                return Err(bad_api_usage!(
+                    "Receive window of {} is larger than stream capacity of {}",
+                    recv_window.window(),
+                    capacity
+                )
+                .into());
+            }
+        }
         let stream_ent = StreamEnt::Open {
             sink,
             rx,
+            peeked: None,
             send_window,
+            recv_window,
+            received_connected: connected,
+            kind,
+            stats: StreamStats::default(),
             dropped: 0,
-            received_connected: false,
+            priority,
         };
         // This "65536" seems too aggressive, but it's what tor does.
         //
@@ -126,9 +612,8 @@ impl StreamMap {
             if id.is_zero() {
                 continue;
             }
-            let ent = self.m.entry(id);
-            if let Entry::Vacant(_) = ent {
-                ent.or_insert(stream_ent);
+            if !self.contains(id) {
+                self.m.insert(id, stream_ent);
                 return Ok(id);
             }
         }
@@ -136,75 +621,573 @@ impl StreamMap {
         Err(Error::IdRangeFull)
     }
 
+    /// Add an entry to this map, with scheduling priority `priority` and
+    /// flow-control windows taken from `params`; return the newly allocated
+    /// StreamId.
+    ///
+    /// This is a shorthand for [`StreamMap::add_stream`], for streams that
+    /// need no other parameters.
+    #[allow(dead_code)] // The reactor uses add_stream directly.
+    pub(super) fn add_ent(
+        &mut self,
+        sink: S,
+        rx: mpsc::Receiver<RelayMsg>,
+        priority: u8,
+        params: &CongestionParams,
+    ) -> Result<StreamId> {
+        self.add_stream(AddStreamParams::new(sink, rx, params).priority(priority))
+    }
+
+    /// Return the number of stream IDs in use, and how many of those belong
+    /// to open streams.
+    pub(super) fn n_ids_in_use(&self) -> (usize, usize) {
+        let n_open = self
+            .m
+            .values()
+            .filter(|ent| matches!(ent, StreamEnt::Open { .. }))
+            .count();
+        (self.m.len(), n_open)
+    }
+
+    /// Return true if there is an entry for `id` in this map.
+    pub(super) fn contains(&self, id: StreamId) -> bool {
+        self.m.contains_key(&id)
+    }
+
     /// Return the entry for `id` in this map, if any.
-    pub(super) fn get_mut(&mut self, id: StreamId) -> Option<&mut StreamEnt> {
+    pub(super) fn get(&self, id: StreamId) -> Option<&StreamEnt<S>> {
+        self.m.get(&id)
+    }
+
+    /// For testing: return the entry for `id` in this map, if any.
+    #[cfg(test)]
+    pub(super) fn get_mut(&mut self, id: StreamId) -> Option<&mut StreamEnt<S>> {
         self.m.get_mut(&id)
     }
 
+    /// Return the number of cells that the circuit-level send window still
+    /// lets us send to this hop.
+    pub(super) fn circuit_window_remaining(&self) -> u16 {
+        self.circ_window.window()
+    }
+
+    /// Handle a circuit-level SENDME from this hop, with the authentication
+    /// tag `tag` (if any).
+    ///
+    /// On success, return the new size of the circuit-level send window.
+    pub(super) fn note_circuit_sendme(&mut self, tag: Option<[u8; 20]>) -> Result<u16> {
+        self.circ_window.put(tag)
+    }
+
+    /// Note that we're sending a cell that counts towards flow-control
+    /// windows, with `data_len` bytes of data, on the stream with `id` (or
+    /// on the circuit itself, if `id` is zero).
+    ///
+    /// `tag` is the tag that the cryptography layer generated for the cell;
+    /// we may need it to check a later SENDME.
+    ///
+    /// Takes one cell from the circuit-level send window, and from the
+    /// stream's send window.  Returns an error (and changes nothing) if
+    /// either window is empty, or if the stream isn't open.
+    pub(super) fn take_send_windows(
+        &mut self,
+        id: StreamId,
+        tag: &[u8; 20],
+        data_len: usize,
+    ) -> Result<()> {
+        if self.circ_window.window() == 0 {
+            return Err(Error::from(internal!(
+                "tried to send a cell with an empty circuit send window"
+            )));
+        }
+        if !id.is_zero() {
+            match self.m.get_mut(&id) {
+                Some(StreamEnt::Open {
+                    send_window, stats, ..
+                }) => {
+                    send_window.take(&())?;
+                    stats.note_data_sent(data_len);
+                }
+                _ => {
+                    return Err(Error::CircProto(format!(
+                        "tried to send a relay cell on non-open stream {}",
+                        id
+                    )))
+                }
+            }
+        }
+        self.circ_window.take(tag)?;
+        Ok(())
+    }
+
+    /// For testing: get a copy of the circuit-level send window, and the
+    /// tags that we expect on incoming circuit-level SENDMEs.
+    #[cfg(test)]
+    pub(super) fn circuit_window_and_expected_tags(&self) -> (u16, Vec<sendme::CircTag>) {
+        self.circ_window.window_and_expected_tags()
+    }
+
+    /// Return the ID of the next open stream whose send window would let
+    /// it send a cell, and advance our cursor past it.
+    ///
+    /// If the circuit-level send window is empty, no stream can send, and
+    /// we return None.
+    ///
+    /// Streams are scheduled with deficit round-robin.  Each round, every
+    /// ready stream gets [`turns_per_round`] turns, depending on its
+    /// priority, and we return it once for each of them in a row.  Since
+    /// every cell costs the same, that gives each busy stream a share of
+    /// the circuit in proportion to its turns: a stream with priority 3
+    /// sends four cells for every one that a priority-0 stream sends.
+    /// Every ready stream gets at least one turn per round, so no stream
+    /// with a nonempty send window can be starved by the others.
+    ///
+    /// Within a round, streams with a higher priority go first; streams of
+    /// equal priority are visited in a round-robin order of their IDs,
+    /// starting just after the one we returned last time.  Returns None if
+    /// no open stream can send.
+    ///
+    /// A stream with nothing queued when its turn comes (see
+    /// [`peek_pending`]) loses the rest of its turns for this round, as in
+    /// any deficit round-robin scheduler, so the caller should use
+    /// [`StreamMap::poll_idle_streams`] to find out when such streams have
+    /// something to send.
+    pub(super) fn next_ready_stream(&mut self) -> Option<StreamId> {
+        if self.circ_window.window() == 0 {
+            return None;
+        }
+        let mut started_round = false;
+        loop {
+            let id = match self.round.front() {
+                Some((id, _)) => *id,
+                // Nothing in the round we just started can send.
+                None if started_round => return None,
+                None => {
+                    // Every stream has had its turns: start a new round.
+                    self.start_round();
+                    started_round = true;
+                    continue;
+                }
+            };
+            if !self.can_send_now(id) {
+                self.round.pop_front();
+                continue;
+            }
+            match self.round.front_mut() {
+                Some((_, turns)) if *turns > 1 => *turns -= 1,
+                _ => {
+                    self.round.pop_front();
+                }
+            }
+            self.ready_cursor = id.into();
+            return Some(id);
+        }
+    }
+
+    /// Helper for `next_ready_stream`: fill `self.round` with every open
+    /// stream whose send window would let it send a cell, and its number of
+    /// turns, in the order that they should get their turns.
+    ///
+    /// We do this once per round, rather than looking at every stream each
+    /// time we pick one.
+    fn start_round(&mut self) {
+        let start = self.ready_cursor.wrapping_add(1);
+        let mut round: Vec<_> = self
+            .m
+            .iter()
+            .filter_map(|(id, ent)| match ent {
+                StreamEnt::Open {
+                    send_window,
+                    priority,
+                    ..
+                } if send_window.window() > 0 => Some((*id, *priority)),
+                _ => None,
+            })
+            .collect();
+        // Highest priority first; then the first ID after the cursor.
+        // (The cursor's own ID sorts last, since it has already had a
+        // turn.)
+        round.sort_unstable_by_key(|(id, priority)| {
+            (
+                std::cmp::Reverse(*priority),
+                u16::from(*id).wrapping_sub(start),
+            )
+        });
+        self.round.clear();
+        self.round.extend(
+            round
+                .into_iter()
+                .map(|(id, priority)| (id, turns_per_round(priority))),
+        );
+    }
+
+    /// Helper for `next_ready_stream`: return true if `id` is an open stream
+    /// whose send window would let it send a cell, and which has something
+    /// queued.
+    fn can_send_now(&mut self, id: StreamId) -> bool {
+        let has_window = matches!(
+            self.m.get(&id),
+            Some(StreamEnt::Open { send_window, .. }) if send_window.window() > 0
+        );
+        has_window && self.stream_has_pending(id) == Some(true)
+    }
+
+    /// Return true if the open stream with `id` has a message queued for us
+    /// to send.  Returns None if there is no open stream with `id`.
+    ///
+    /// This doesn't drop or reorder any messages: see [`peek_pending`].
+    pub(super) fn stream_has_pending(&mut self, id: StreamId) -> Option<bool> {
+        match self.m.get_mut(&id)? {
+            StreamEnt::Open { rx, peeked, .. } => Some(peek_pending(rx, peeked)),
+            StreamEnt::EndReceived { .. } | StreamEnt::EndSent(_) => None,
+        }
+    }
+
+    /// Poll every open stream that could send but has nothing queued, so
+    /// that `cx` is woken up once one of them gets a message.
+    ///
+    /// Returns true if any of them turned out to have a message (or to have
+    /// lost its writer) after all; in that case, [`StreamMap::next_ready_stream`]
+    /// will return it, and the caller should try again instead of waiting.
+    pub(super) fn poll_idle_streams(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut found = false;
+        for ent in self.m.values_mut() {
+            if let StreamEnt::Open {
+                rx,
+                peeked,
+                send_window,
+                ..
+            } = ent
+            {
+                if peeked.is_some() || send_window.window() == 0 {
+                    continue;
+                }
+                match Pin::new(rx).poll_next(cx) {
+                    Poll::Ready(Some(msg)) => {
+                        *peeked = Some(msg);
+                        found = true;
+                    }
+                    Poll::Ready(None) => found = true,
+                    Poll::Pending => {}
+                }
+            }
+        }
+        found
+    }
+
+    /// Poll the open stream with `id` for the next message that it wants
+    /// us to send.
+    ///
+    /// Returns `Ready(None)` once the stream's writer has gone away, and
+    /// None if there is no open stream with `id`.
+    pub(super) fn poll_next_msg(
+        &mut self,
+        id: StreamId,
+        cx: &mut Context<'_>,
+    ) -> Option<Poll<Option<RelayMsg>>> {
+        match self.m.get_mut(&id)? {
+            StreamEnt::Open { rx, peeked, .. } => Some(match peeked.take() {
+                Some(msg) => Poll::Ready(Some(msg)),
+                None => Pin::new(rx).poll_next(cx),
+            }),
+            StreamEnt::EndReceived { .. } | StreamEnt::EndSent(_) => None,
+        }
+    }
+
+    /// Return the byte counters for the stream with `id`, if it is open,
+    /// half-closed, or ended by the other side.
+    pub(super) fn stream_stats(&self, id: StreamId) -> Option<StreamStats> {
+        match self.get(id)? {
+            StreamEnt::Open { stats, .. } => Some(*stats),
+            StreamEnt::EndSent(halfstream) => Some(halfstream.stats()),
+            StreamEnt::EndReceived { stats } => Some(*stats),
+        }
+    }
+
+    /// Note that we received a cell that counts towards flow-control
+    /// windows on the open stream with `id`, and decrement its receive
+    /// window accordingly.
+    ///
+    /// Returns `Some(SendmeNeeded)` if the window has just reached a point
+    /// where the other side will be waiting for a SENDME.  Returns an error
+    /// if the other side has violated the window, or if there is no open
+    /// stream with `id`.
+    pub(super) fn note_data_received(&mut self, id: StreamId) -> Result<Option<SendmeNeeded>> {
+        match self.m.get_mut(&id) {
+            Some(StreamEnt::Open { recv_window, .. }) => Ok(if recv_window.take()? {
+                Some(SendmeNeeded)
+            } else {
+                None
+            }),
+            _ => Err(Error::from(internal!(
+                "Tried to note data received on a stream that isn't open"
+            ))),
+        }
+    }
+
+    /// Note that we sent a SENDME on the stream with `id`, and increment
+    /// its receive window accordingly.
+    ///
+    /// Does nothing if there is no such stream, or if we have already
+    /// received an END on it.
+    pub(super) fn note_sendme_sent(&mut self, id: StreamId) {
+        match self.m.get_mut(&id) {
+            Some(StreamEnt::Open { recv_window, .. }) => recv_window.put(),
+            Some(StreamEnt::EndSent(halfstream)) => halfstream.note_sendme_sent(),
+            Some(StreamEnt::EndReceived { .. }) | None => {}
+        }
+    }
+
     /// Note that we received an END cell on the stream with `id`.
     ///
-    /// Returns true if there was really a stream there.
+    /// Gives an error if there was no such stream, or if the other side had
+    /// already ended it.  Either way, we also count the END in
+    /// `protocol_errors`.
     pub(super) fn end_received(&mut self, id: StreamId) -> Result<()> {
         // Check the hashmap for the right stream. Bail if not found.
         // Also keep the hashmap handle so that we can do more efficient inserts/removals
         let mut stream_entry = match self.m.entry(id) {
             Entry::Vacant(_) => {
+                self.protocol_errors = self.protocol_errors.saturating_add(1);
                 return Err(Error::CircProto(
                     "Received END cell on nonexistent stream".into(),
-                ))
+                ));
             }
             Entry::Occupied(o) => o,
         };
 
         // Progress the stream's state machine accordingly
         match stream_entry.get() {
-            StreamEnt::EndReceived => Err(Error::CircProto(
-                "Received two END cells on same stream".into(),
-            )),
+            StreamEnt::EndReceived { .. } => {
+                self.protocol_errors = self.protocol_errors.saturating_add(1);
+                Err(Error::CircProto(
+                    "Received two END cells on same stream".into(),
+                ))
+            }
             StreamEnt::EndSent(_) => {
-                info!("Actually got an end cell on a half-closed stream!");
+                info!(
+                    stream_id = ?id,
+                    "Actually got an end cell on a half-closed stream!"
+                );
                 // We got an END, and we already sent an END. Great!
                 // we can forget about this stream.
                 stream_entry.remove_entry();
+                self.note_removed(id, ClosedReason::LocalEnd);
                 Ok(())
             }
-            StreamEnt::Open { .. } => {
-                stream_entry.insert(StreamEnt::EndReceived);
+            StreamEnt::Open { stats, .. } => {
+                let stats = *stats;
+                stream_entry.insert(StreamEnt::EndReceived { stats });
                 Ok(())
             }
         }
     }
 
+    /// Handle `msg`, which arrived from the other side on the stream with
+    /// `id`.
+    ///
+    /// If the stream is open, check that it can accept `msg` at this point
+    /// (for example, that it isn't a second CONNECTED, or DATA on a resolve
+    /// stream), count it against the stream's windows, and pass it on to the
+    /// stream's sink; SENDME messages are credited to the send window
+    /// instead.  A cell that arrives after the stream's reader has gone away
+    /// is dropped, but still counted.  An END marks the stream as ended.
+    ///
+    /// If the stream isn't open, this is the same as
+    /// [`StreamMap::handle_msg_on_closed_stream`].
+    ///
+    /// Returns [`Error::CircProto`] if the other side sent something it
+    /// shouldn't have.
+    pub(super) fn deliver(&mut self, id: StreamId, msg: RelayMsg) -> Result<()> {
+        match self.m.get(&id) {
+            Some(StreamEnt::Open {
+                kind,
+                received_connected,
+                ..
+            }) => {
+                if !kind.accepts(&msg, *received_connected) {
+                    return Err(Error::CircProto(format!(
+                        "Unexpected {} cell on {:?} stream {}",
+                        msg.cmd(),
+                        kind,
+                        id
+                    )));
+                }
+            }
+            _ => return self.handle_msg_on_closed_stream(id, &msg),
+        }
+
+        if sendme::msg_counts_towards_windows(&msg) {
+            // We don't send a SENDME when the window says we need one: the
+            // stream's reader does that once it has actually consumed the
+            // data, and we credit the window when it asks us to.
+            let _sendme_needed = self.note_data_received(id)?;
+        }
+
+        let is_end_cell = matches!(msg, RelayMsg::End(_));
+        match self.m.get_mut(&id) {
+            Some(StreamEnt::Open {
+                sink,
+                send_window,
+                received_connected,
+                stats,
+                dropped,
+                ..
+            }) => {
+                if let RelayMsg::Sendme(_) = msg {
+                    // We need to handle sendmes here, not in the stream's
+                    // recv() method, or else we'd never notice them if the
+                    // stream isn't reading.
+                    send_window.put(Some(()))?;
+                    return Ok(());
+                }
+                if matches!(msg, RelayMsg::Connected(_) | RelayMsg::Resolved(_)) {
+                    // Remember that we've received a Connected (or
+                    // Resolved) cell, and can't get another, even if we
+                    // become a HalfStream.
+                    *received_connected = true;
+                }
+                if let RelayMsg::Data(d) = &msg {
+                    stats.note_data_received(d.as_ref().len());
+                }
+                match sink.try_send(msg) {
+                    Err(StreamSinkError::Full) => {
+                        // If we get here, we either have a logic bug (!), or an attacker
+                        // is sending us more cells than we asked for via congestion control.
+                        return Err(Error::CircProto(format!(
+                            "Stream sink would block; received too many cells on stream ID {}",
+                            id,
+                        )));
+                    }
+                    Err(StreamSinkError::Closed) => {
+                        // The other side of the stream has gone away.
+                        // We've already taken this cell from the stream's receive
+                        // window, which will later be recorded in a half-stream.
+                        *dropped = dropped.saturating_add(1);
+                    }
+                    Ok(()) => {}
+                }
+            }
+            _ => {
+                return Err(Error::from(internal!(
+                    "Stream {} stopped being open while we delivered to it",
+                    id
+                )))
+            }
+        }
+        if is_end_cell {
+            self.end_received(id)?;
+        }
+        Ok(())
+    }
+
+    /// Handle `msg`, which arrived on the stream with `id` after that stream
+    /// stopped being open.
+    ///
+    /// If we have sent an END on the stream (it is `EndSent`), the other
+    /// side may not have seen it yet, and may still send us cells that were
+    /// already in flight.  We credit any SENDME to the half-stream's send
+    /// window, and count any DATA against its receive window before
+    /// dropping it.  We only return an error if the other side violates one
+    /// of those windows, or sends a cell that makes no sense on a stream.
+    /// An END finishes the stream.
+    ///
+    /// If we have received an END on the stream (it is `EndReceived`), the
+    /// other side has told us that it's done with the stream, so any
+    /// further cell on it, even a SENDME, is a protocol violation.
+    pub(super) fn handle_msg_on_closed_stream(
+        &mut self,
+        id: StreamId,
+        msg: &RelayMsg,
+    ) -> Result<()> {
+        let is_end = matches!(msg, RelayMsg::End(_));
+        match self.m.get_mut(&id) {
+            Some(StreamEnt::EndSent(halfstream)) => {
+                if is_end {
+                    self.end_received(id)
+                } else {
+                    halfstream.handle_msg(msg)
+                }
+            }
+            Some(StreamEnt::EndReceived { .. }) => {
+                if is_end {
+                    // This gives the right error.
+                    self.end_received(id)
+                } else {
+                    Err(Error::CircProto(format!(
+                        "Received {} cell on a stream after its END",
+                        msg.cmd()
+                    )))
+                }
+            }
+            Some(StreamEnt::Open { .. }) => Err(Error::from(internal!(
+                "Tried to handle a message on an open stream as if it were closed"
+            ))),
+            None if is_end => self.end_received(id),
+            None => Err(Error::CircProto(
+                "Cell received on nonexistent stream!?".into(),
+            )),
+        }
+    }
+
     /// Handle a termination of the stream with `id` from this side of
     /// the circuit. Return true if the stream was open and an END
     /// ought to be sent.
+    ///
+    /// The reactor uses [`StreamMap::terminate_take`] instead, so that it can
+    /// log the new half-stream.
+    #[cfg(test)]
     pub(super) fn terminate(&mut self, id: StreamId) -> Result<ShouldSendEnd> {
+        self.terminate_take(id)
+            .map(|(should_send_end, _, _)| should_send_end)
+    }
+
+    /// Handle a termination of the stream with `id` from this side of the
+    /// circuit, as with [`StreamMap::terminate`].
+    ///
+    /// If the stream was open, we've just replaced it with a [`HalfStream`]
+    /// that tracks the cells the other side may still send; return a
+    /// reference to that half-stream along with [`ShouldSendEnd::Send`], so
+    /// that the caller can inspect it without looking it up again, and the
+    /// number of incoming cells we dropped because the stream's reader had
+    /// gone away.  Otherwise, return [`ShouldSendEnd::DontSend`], no
+    /// half-stream, and a count of zero.
+    pub(super) fn terminate_take(
+        &mut self,
+        id: StreamId,
+    ) -> Result<(ShouldSendEnd, Option<&HalfStream>, u16)> {
         // Progress the stream's state machine accordingly
         match self
             .m
             .remove(&id)
             .ok_or_else(|| Error::from(internal!("Somehow we terminated a nonexistent stream?")))?
         {
-            StreamEnt::EndReceived => Ok(ShouldSendEnd::DontSend),
+            StreamEnt::EndReceived { .. } => {
+                self.note_removed(id, ClosedReason::PeerEnd);
+                Ok((ShouldSendEnd::DontSend, None, 0))
+            }
             StreamEnt::Open {
                 send_window,
-                dropped,
+                recv_window,
                 received_connected,
+                stats,
+                dropped,
                 // notably absent: the channels for sink and stream, which will get dropped and
                 // closed (meaning reads/writes from/to this stream will now fail)
                 ..
             } => {
-                // FIXME(eta): we don't copy the receive window, instead just creating a new one,
-                //             so a malicious peer can send us slightly more data than they should
-                //             be able to; see arti#230.
-                let mut recv_window = StreamRecvWindow::new(RECV_WINDOW_INIT);
-                recv_window.decrement_n(dropped)?;
                 // TODO: would be nice to avoid new_ref.
                 // If we haven't gotten a CONNECTED already, we accept one on the half-stream.
                 let connected_ok = !received_connected;
-                let halfstream = HalfStream::new(send_window, recv_window, connected_ok);
-                self.m.insert(id, StreamEnt::EndSent(halfstream));
-                Ok(ShouldSendEnd::Send)
+                let halfstream = HalfStream::new(send_window, recv_window, connected_ok, stats);
+                match self.m.entry(id).or_insert(StreamEnt::EndSent(halfstream)) {
+                    StreamEnt::EndSent(halfstream) => {
+                        Ok((ShouldSendEnd::Send, Some(halfstream), dropped))
+                    }
+                    _ => Err(Error::from(internal!(
+                        "Stream reappeared while we were terminating it?"
+                    ))),
+                }
             }
             StreamEnt::EndSent(_) => {
                 panic!("Hang on! We're sending an END on a stream where we already sent an END‽");
@@ -212,6 +1195,27 @@ impl StreamMap {
         }
     }
 
+    /// Forget about the stream with `id` entirely, without sending an END.
+    ///
+    /// Unlike [`StreamMap::terminate`], this removes the stream whatever
+    /// state it is in, and doesn't leave a half-stream behind to absorb cells
+    /// that the other side has already sent: use it only when the other side
+    /// never heard of the stream (for instance, because we couldn't send its
+    /// BEGIN cell).  If the stream was open, any cells still queued for it
+    /// are discarded, and its reader and writer will see it as closed.
+    ///
+    /// Return true if there was a stream with `id`.
+    pub(super) fn drop_stream(&mut self, id: StreamId) -> bool {
+        let reason = match self.m.remove(&id) {
+            Some(StreamEnt::Open { .. }) => ClosedReason::Dropped,
+            Some(StreamEnt::EndReceived { .. }) => ClosedReason::PeerEnd,
+            Some(StreamEnt::EndSent(_)) => ClosedReason::LocalEnd,
+            None => return false,
+        };
+        self.note_removed(id, reason);
+        true
+    }
+
     // TODO: Eventually if we want relay support, we'll need to support
     // stream IDs chosen by somebody else. But for now, we don't need those.
 }
@@ -220,11 +1224,39 @@ impl StreamMap {
 mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
-    use crate::circuit::sendme::StreamSendWindow;
+
+    /// Add a new open stream to `map`, with default parameters, and return
+    /// its ID.
+    fn add_test_stream(map: &mut StreamMap) -> StreamId {
+        add_test_stream_with_priority(map, 0)
+    }
+
+    /// As [`add_test_stream`], but give the stream a scheduling priority.
+    fn add_test_stream_with_priority(map: &mut StreamMap, priority: u8) -> StreamId {
+        map.add_stream(test_stream_params().priority(priority))
+            .unwrap()
+    }
+
+    /// Return parameters for a stream whose channels nobody is using.
+    fn test_stream_params() -> AddStreamParams<mpsc::Sender<RelayMsg>> {
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        AddStreamParams::new(sink, rx, &CongestionParams::default())
+    }
+
+    /// Record that the open stream `id` has received a CONNECTED cell.
+    fn mark_connected(map: &mut StreamMap, id: StreamId) {
+        match map.get_mut(id) {
+            Some(StreamEnt::Open {
+                received_connected, ..
+            }) => *received_connected = true,
+            _ => panic!("stream wasn't open"),
+        }
+    }
 
     #[test]
     fn streammap_basics() -> Result<()> {
-        let mut map = StreamMap::new();
+        let mut map = StreamMap::new(1000);
         let mut next_id = map.next_stream_id;
         let mut ids = Vec::new();
 
@@ -232,7 +1264,7 @@ mod test {
         for _ in 0..128 {
             let (sink, _) = mpsc::channel(128);
             let (_, rx) = mpsc::channel(2);
-            let id = map.add_ent(sink, rx, StreamSendWindow::new(500))?;
+            let id = map.add_ent(sink, rx, 0, &CongestionParams::default())?;
             let expect_id: StreamId = next_id.into();
             assert_eq!(expect_id, id);
             next_id = next_id.wrapping_add(1);
@@ -250,7 +1282,10 @@ mod test {
         // Test end_received
         assert!(map.end_received(nonesuch_id).is_err());
         assert!(map.end_received(ids[1]).is_ok());
-        assert!(matches!(map.get_mut(ids[1]), Some(StreamEnt::EndReceived)));
+        assert!(matches!(
+            map.get_mut(ids[1]),
+            Some(StreamEnt::EndReceived { .. })
+        ));
         assert!(map.end_received(ids[1]).is_err());
 
         // Test terminate
@@ -266,4 +1301,936 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn streammap_protocol_errors() {
+        let mut map = StreamMap::new(1000);
+        let id = add_test_stream(&mut map);
+        let nonesuch_id = map.next_stream_id.into();
+
+        // An END for a stream that isn't there, or that was already ended,
+        // is an error, and also gets counted.
+        assert_eq!(map.protocol_errors(), 0);
+        assert!(map.end_received(nonesuch_id).is_err());
+        assert_eq!(map.protocol_errors(), 1);
+        assert!(map.end_received(id).is_ok());
+        assert!(matches!(map.get(id), Some(StreamEnt::EndReceived { .. })));
+        assert_eq!(map.protocol_errors(), 1);
+        assert!(map.end_received(id).is_err());
+        assert_eq!(map.protocol_errors(), 2);
+    }
+
+    #[test]
+    fn streammap_get_and_contains() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_ent(sink, rx, 0, &CongestionParams::default())?;
+        let nonesuch_id = map.next_stream_id.into();
+
+        assert!(matches!(map.get(id), Some(StreamEnt::Open { .. })));
+        assert!(map.get(nonesuch_id).is_none());
+        assert!(map.contains(id));
+        assert!(!map.contains(nonesuch_id));
+
+        // A stream that's gone is neither.
+        assert_eq!(map.terminate(id)?, ShouldSendEnd::Send);
+        map.end_received(id)?;
+        assert!(map.get(id).is_none());
+        assert!(!map.contains(id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_stream_stats() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (sink, _) = mpsc::channel(128);
+            let (_, rx) = mpsc::channel(2);
+            ids.push(map.add_ent(sink, rx, 0, &CongestionParams::default())?);
+        }
+        let nonesuch_id = map.next_stream_id.into();
+
+        assert_eq!(map.stream_stats(ids[0]), Some(StreamStats::default()));
+        assert_eq!(map.stream_stats(nonesuch_id), None);
+        if let Some(StreamEnt::Open { stats, .. }) = map.get_mut(ids[0]) {
+            stats.note_data_sent(498);
+            stats.note_data_received(100);
+            stats.note_data_received(23);
+        }
+
+        // The byte counters survive the half-close.
+        assert_eq!(map.terminate(ids[0])?, ShouldSendEnd::Send);
+        let stats = map.stream_stats(ids[0]).unwrap();
+        assert_eq!(stats.bytes_sent(), 498);
+        assert_eq!(stats.bytes_recv(), 123);
+
+        // They survive an END from the other side, too.
+        if let Some(StreamEnt::Open { stats, .. }) = map.get_mut(ids[1]) {
+            stats.note_data_received(77);
+        }
+        map.end_received(ids[1])?;
+        let stats = map.stream_stats(ids[1]).unwrap();
+        assert_eq!(stats.bytes_sent(), 0);
+        assert_eq!(stats.bytes_recv(), 77);
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_recv_window() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_ent(sink, rx, 0, &CongestionParams::default())?;
+
+        // With the default window of 500 and increment of 50, every 50th
+        // cell wants a SENDME.
+        let mut n_sendmes = 0;
+        for n in 1..=500 {
+            if map.note_data_received(id)?.is_some() {
+                assert_eq!(n % 50, 0);
+                n_sendmes += 1;
+            }
+        }
+        assert_eq!(n_sendmes, 10);
+        // The window is now empty.
+        assert!(map.note_data_received(id).is_err());
+
+        // Sending a SENDME makes room for more cells.
+        map.note_sendme_sent(id);
+        for _ in 0..49 {
+            assert_eq!(map.note_data_received(id)?, None);
+        }
+        assert_eq!(map.note_data_received(id)?, Some(SendmeNeeded));
+        assert!(map.note_data_received(id).is_err());
+
+        // Cells received on the open stream carry over to the half-stream.
+        mark_connected(&mut map, id);
+        assert_eq!(map.terminate(id)?, ShouldSendEnd::Send);
+        assert!(map.note_data_received(id).is_err());
+        let data = RelayMsg::Data(tor_cell::relaycell::msg::Data::new(b"x")?);
+        match map.get_mut(id) {
+            Some(StreamEnt::EndSent(halfstream)) => {
+                assert!(halfstream.handle_msg(&data).is_err());
+            }
+            _ => panic!("stream wasn't half-closed"),
+        }
+        map.note_sendme_sent(id);
+        match map.get_mut(id) {
+            Some(StreamEnt::EndSent(halfstream)) => {
+                assert!(halfstream.handle_msg(&data).is_ok());
+            }
+            _ => panic!("stream wasn't half-closed"),
+        }
+
+        // There's no window to decrement on a nonexistent stream.
+        assert!(map.note_data_received(12_u16.into()).is_err());
+        map.note_sendme_sent(12_u16.into());
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_terminate_take() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let ids: Vec<_> = (0..3).map(|_| add_test_stream(&mut map)).collect();
+
+        // A connected stream that has received a few cells, some of them
+        // after its reader went away.
+        mark_connected(&mut map, ids[0]);
+        for _ in 0..3 {
+            map.note_data_received(ids[0])?;
+        }
+        if let Some(StreamEnt::Open { dropped, .. }) = map.get_mut(ids[0]) {
+            *dropped = 2;
+        }
+        let (should_send_end, hs, dropped) = map.terminate_take(ids[0])?;
+        assert_eq!(should_send_end, ShouldSendEnd::Send);
+        assert_eq!(dropped, 2);
+        let hs = hs.unwrap();
+        assert_eq!(hs.send_window(), 500);
+        assert_eq!(hs.recv_window(), 497);
+        assert!(!hs.connected_ok());
+        // The half-stream we got back is the one in the map.
+        assert!(matches!(map.get(ids[0]), Some(StreamEnt::EndSent(_))));
+
+        // A stream that never got connected will still accept a CONNECTED.
+        let (should_send_end, hs, dropped) = map.terminate_take(ids[1])?;
+        assert_eq!(should_send_end, ShouldSendEnd::Send);
+        assert_eq!(dropped, 0);
+        assert!(hs.unwrap().connected_ok());
+
+        // If the other side already ended the stream, there's no half-stream.
+        map.end_received(ids[2])?;
+        let (should_send_end, hs, dropped) = map.terminate_take(ids[2])?;
+        assert_eq!(should_send_end, ShouldSendEnd::DontSend);
+        assert_eq!(dropped, 0);
+        assert!(hs.is_none());
+        assert!(!map.contains(ids[2]));
+
+        assert!(map.terminate_take(ids[2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_drop_stream() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let mut ids = Vec::new();
+        let mut sinks = Vec::new();
+        for _ in 0..3 {
+            let (sink, stream) = mpsc::channel(128);
+            let (_, rx) = mpsc::channel(2);
+            ids.push(map.add_stream(AddStreamParams::new(
+                sink,
+                rx,
+                &CongestionParams::default(),
+            ))?);
+            sinks.push(stream);
+        }
+        map.end_received(ids[1])?;
+        assert_eq!(map.terminate(ids[2])?, ShouldSendEnd::Send);
+
+        // We can drop a stream in any state, and nothing is left behind.
+        for id in &ids {
+            assert!(map.drop_stream(*id));
+            assert!(!map.contains(*id));
+            assert!(!map.drop_stream(*id));
+        }
+        assert_eq!(map.take_closed(), ids);
+
+        // The reader of the open stream sees it closed.
+        assert!(matches!(sinks[0].try_next(), Ok(None)));
+
+        // An END for a dropped stream is a stray.
+        let n_errors = map.protocol_errors();
+        assert!(map.end_received(ids[0]).is_err());
+        assert!(!map.contains(ids[0]));
+        assert_eq!(map.protocol_errors(), n_errors + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_take_closed() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let ids: Vec<_> = (0..3).map(|_| add_test_stream(&mut map)).collect();
+        assert!(map.take_closed().is_empty());
+
+        // A stream that has only been half-closed isn't reported.
+        map.end_received(ids[1])?;
+        assert_eq!(map.terminate(ids[2])?, ShouldSendEnd::Send);
+        assert!(map.take_closed().is_empty());
+
+        // Exactly the streams that went away are reported as closed, once.
+        assert_eq!(map.terminate(ids[1])?, ShouldSendEnd::DontSend);
+        map.end_received(ids[2])?;
+        assert_eq!(map.take_closed(), vec![ids[1], ids[2]]);
+        assert!(map.take_closed().is_empty());
+        assert!(map.contains(ids[0]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_allocator_state() -> Result<()> {
+        assert!(StreamMap::<mpsc::Sender<RelayMsg>>::new_with_next_id(1000, 0).is_err());
+
+        let mut map = StreamMap::new_with_next_id(1000, u16::MAX)?;
+        assert_eq!(map.allocator_state(), u16::MAX);
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_ent(sink, rx, 0, &CongestionParams::default())?;
+        assert_eq!(id, u16::MAX.into());
+        // The cursor has wrapped around, but we never report 0.
+        assert_eq!(map.allocator_state(), 1);
+
+        // A restored map picks up where the old one left off.
+        let mut restored = StreamMap::new_with_next_id(1000, map.allocator_state())?;
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let id = restored.add_ent(sink, rx, 0, &CongestionParams::default())?;
+        assert_eq!(id, 1_u16.into());
+        assert_eq!(restored.allocator_state(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_closed_streams() -> Result<()> {
+        use tor_cell::relaycell::msg;
+        let mut map = StreamMap::new(1000);
+        let sendme: RelayMsg = msg::Sendme::new_empty().into();
+        let data: RelayMsg = msg::Data::new(&b"still in flight"[..]).unwrap().into();
+        let end: RelayMsg = msg::End::new_misc().into();
+
+        // Calling this on an open stream is a bug.
+        let open = add_test_stream(&mut map);
+        assert!(map.handle_msg_on_closed_stream(open, &data).is_err());
+
+        // EndSent: in-flight cells are fine, up to the limits of the windows.
+        let sent = add_test_stream(&mut map);
+        for _ in 0..5 {
+            assert!(map.note_data_received(sent)?.is_none());
+        }
+        mark_connected(&mut map, sent);
+        assert_eq!(map.terminate(sent)?, ShouldSendEnd::Send);
+        // We didn't send any data, so the other side can't have a SENDME
+        // for us.
+        let e = map.handle_msg_on_closed_stream(sent, &sendme).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "circuit protocol violation: Received a SENDME when none was expected"
+        );
+        // The receive window carries over from the open stream.
+        let remaining = CongestionParams::default().stream_window() - 5;
+        for _ in 0..remaining {
+            map.handle_msg_on_closed_stream(sent, &data)?;
+        }
+        assert!(map.handle_msg_on_closed_stream(sent, &data).is_err());
+        if let Some(StreamEnt::EndSent(hs)) = map.get(sent) {
+            assert_eq!(hs.stats().bytes_recv, u64::from(remaining) * 15);
+        } else {
+            panic!("Stream went away!");
+        }
+        // An END finishes the stream.
+        map.handle_msg_on_closed_stream(sent, &end)?;
+        assert!(map.get(sent).is_none());
+        assert!(map.handle_msg_on_closed_stream(sent, &data).is_err());
+
+        // EndReceived: the other side said it was done, so even a SENDME is
+        // a violation.
+        let received = add_test_stream(&mut map);
+        map.end_received(received)?;
+        let e = map
+            .handle_msg_on_closed_stream(received, &sendme)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "circuit protocol violation: Received SENDME cell on a stream after its END"
+        );
+        assert!(map.handle_msg_on_closed_stream(received, &data).is_err());
+        let e = map.handle_msg_on_closed_stream(received, &end).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "circuit protocol violation: Received two END cells on same stream"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_debug() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let ids: Vec<StreamId> = (0..3)
+            .map(|_| add_test_stream_with_priority(&mut map, 3))
+            .collect();
+        map.end_received(ids[1])?;
+        map.terminate(ids[2])?;
+
+        let open = format!("{:?}", map.get(ids[0]).unwrap());
+        assert!(open.starts_with("Open {"));
+        assert!(open.contains("send_window: 500"));
+        assert!(open.contains("recv_window: 500"));
+        assert!(open.contains("received_connected: false"));
+        assert!(open.contains("priority: 3"));
+        // We never print the channels.
+        assert!(!open.contains("Sender"));
+        assert!(!open.contains("Receiver"));
+
+        assert!(format!("{:?}", map.get(ids[1]).unwrap()).starts_with("EndReceived {"));
+        let half_closed = format!("{:?}", map.get(ids[2]).unwrap());
+        assert!(half_closed.starts_with("EndSent(HalfStream {"));
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_closed_reason() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+
+        // The other side ends a stream, and then we let it go.
+        let peer = add_test_stream(&mut map);
+        assert_eq!(map.closed_reason(peer), None);
+        map.end_received(peer)?;
+        assert_eq!(map.closed_reason(peer), Some(ClosedReason::PeerEnd));
+        assert_eq!(map.terminate(peer)?, ShouldSendEnd::DontSend);
+        assert!(!map.contains(peer));
+        assert_eq!(map.closed_reason(peer), Some(ClosedReason::PeerEnd));
+
+        // We end a stream, and then the other side acknowledges it.
+        let local = add_test_stream(&mut map);
+        assert_eq!(map.terminate(local)?, ShouldSendEnd::Send);
+        assert_eq!(map.closed_reason(local), Some(ClosedReason::LocalEnd));
+        map.end_received(local)?;
+        assert!(!map.contains(local));
+        assert_eq!(map.closed_reason(local), Some(ClosedReason::LocalEnd));
+
+        // We drop a stream without ending it.
+        let dropped = add_test_stream(&mut map);
+        assert!(map.drop_stream(dropped));
+        assert_eq!(map.closed_reason(dropped), Some(ClosedReason::Dropped));
+
+        // We never heard of this one.
+        assert_eq!(map.closed_reason(12_u16.into()), None);
+
+        // We only remember a bounded number of reasons.
+        for _ in 0..RECENTLY_CLOSED_LEN {
+            let id = add_test_stream(&mut map);
+            map.drop_stream(id);
+        }
+        assert_eq!(map.recently_closed.len(), RECENTLY_CLOSED_LEN);
+        assert_eq!(map.closed_reason(peer), None);
+        assert_eq!(map.closed_reason(local), None);
+        assert_eq!(map.closed_reason(dropped), None);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_drain() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let a = add_test_stream(&mut map);
+        let b = add_test_stream(&mut map);
+        let c = add_test_stream(&mut map);
+        assert!(!map.is_drained());
+
+        map.begin_drain();
+        assert!(matches!(
+            map.add_stream(test_stream_params()),
+            Err(Error::Draining)
+        ));
+        assert!(!map.is_drained());
+
+        // Existing streams keep working.
+        map.end_received(a)?;
+        assert_eq!(map.terminate(a)?, ShouldSendEnd::DontSend);
+        assert_eq!(map.terminate(b)?, ShouldSendEnd::Send);
+        assert!(!map.is_drained());
+
+        // A stream that we've ended doesn't hold up the drain, even if the
+        // other side hasn't answered yet.
+        assert_eq!(map.terminate(c)?, ShouldSendEnd::Send);
+        assert!(map.is_drained());
+        map.end_received(b)?;
+        map.end_received(c)?;
+        assert!(map.inner().is_empty());
+        assert!(map.is_drained());
+        assert!(matches!(
+            map.add_stream(test_stream_params()),
+            Err(Error::Draining)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_next_ready() -> Result<()> {
+        let mut map = StreamMap::new_with_next_id(1000, u16::MAX - 1)?;
+        assert_eq!(map.next_ready_stream(), None);
+
+        let ids: Vec<_> = (0..4).map(|_| add_test_stream(&mut map)).collect();
+        // The IDs wrap around: 65534, 65535, 1, 2.
+        assert_eq!(u16::from(ids[2]), 1);
+
+        // Every stream gets a turn, in order, even across the wraparound.
+        let order: Vec<_> = (0..8).map(|_| map.next_ready_stream().unwrap()).collect();
+        assert_eq!(&order[..4], &order[4..]);
+        let first = order.iter().position(|id| *id == ids[0]).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(order[(first + i) % 4], *id);
+        }
+
+        // Streams that aren't open, or that can't send, get skipped.
+        map.end_received(ids[1])?;
+        if let Some(StreamEnt::Open { send_window, .. }) = map.get_mut(ids[3]) {
+            while send_window.take(&()).is_ok() {}
+        }
+        let cur = map.next_ready_stream().unwrap();
+        let next = map.next_ready_stream().unwrap();
+        assert_ne!(cur, next);
+        for id in [cur, next] {
+            assert!(id == ids[0] || id == ids[2]);
+        }
+
+        // Once nothing can send, there's nothing ready.
+        for id in [ids[0], ids[2]] {
+            if let Some(StreamEnt::Open { send_window, .. }) = map.get_mut(id) {
+                while send_window.take(&()).is_ok() {}
+            }
+        }
+        assert_eq!(map.next_ready_stream(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_next_ready_priority() -> Result<()> {
+        let mut map = StreamMap::new_with_next_id(1000, 1)?;
+        let bulk1 = add_test_stream(&mut map);
+        let interactive = add_test_stream_with_priority(&mut map, 10);
+        let bulk2 = add_test_stream(&mut map);
+
+        // Each round, the interactive stream goes first and gets eleven
+        // turns, but the bulk streams still get their turns.
+        for _ in 0..3 {
+            for _ in 0..11 {
+                assert_eq!(map.next_ready_stream(), Some(interactive));
+            }
+            let a = map.next_ready_stream().unwrap();
+            let b = map.next_ready_stream().unwrap();
+            assert_ne!(a, b);
+            for id in [a, b] {
+                assert!(id == bulk1 || id == bulk2);
+            }
+        }
+
+        // A stream that can't send doesn't get a turn, whatever its
+        // priority.
+        if let Some(StreamEnt::Open { send_window, .. }) = map.get_mut(interactive) {
+            while send_window.take(&()).is_ok() {}
+        }
+        let a = map.next_ready_stream().unwrap();
+        let b = map.next_ready_stream().unwrap();
+        assert_ne!(a, interactive);
+        assert_ne!(b, interactive);
+        assert_ne!(a, b);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_next_ready_shares() {
+        use futures::task::noop_waker_ref;
+        use tor_cell::relaycell::msg;
+        let mut map = StreamMap::new(1000);
+        let data: RelayMsg = msg::Data::new(b"hi").unwrap().into();
+        let mut txs = Vec::new();
+        let mut add = |map: &mut StreamMap, priority| {
+            let (sink, _) = mpsc::channel(128);
+            let (mut tx, rx) = mpsc::channel(200);
+            for _ in 0..200 {
+                tx.try_send(data.clone()).unwrap();
+            }
+            txs.push(tx);
+            let params = AddStreamParams::new(sink, rx, &CongestionParams::default());
+            map.add_stream(params.priority(priority)).unwrap()
+        };
+        let high = add(&mut map, 3);
+        let low = add(&mut map, 0);
+        let top = add(&mut map, 255);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Every stream always has something queued, so they all contend
+        // for every cell that we send.
+        let mut sent = HashMap::new();
+        for _ in 0..(16 + 4 + 1) * 8 {
+            let id = map.next_ready_stream().unwrap();
+            assert!(matches!(
+                map.poll_next_msg(id, &mut cx),
+                Some(Poll::Ready(Some(_)))
+            ));
+            *sent.entry(id).or_insert(0) += 1;
+        }
+
+        // Each stream's share follows its priority, but even the lowest
+        // priority stream gets its turns, and the highest can't take more
+        // than MAX_TURNS_PER_ROUND times as many.
+        assert_eq!(sent[&top], 16 * 8);
+        assert_eq!(sent[&high], 4 * 8);
+        assert_eq!(sent[&low], 8);
+    }
+
+    #[test]
+    fn streammap_has_pending() -> Result<()> {
+        use futures::task::noop_waker_ref;
+        use tor_cell::relaycell::msg;
+        let mut map = StreamMap::new(1000);
+        let (sink, _) = mpsc::channel(128);
+        let (mut tx, rx) = mpsc::channel(4);
+        let id = map.add_stream(AddStreamParams::new(sink, rx, &CongestionParams::default()))?;
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut next = |map: &mut StreamMap| match map.poll_next_msg(id, &mut cx) {
+            Some(Poll::Ready(Some(m))) => Some(m),
+            Some(Poll::Pending) => None,
+            other => panic!("unexpected poll result {:?}", other),
+        };
+
+        // Nothing queued yet.
+        assert_eq!(map.stream_has_pending(id), Some(false));
+        assert!(next(&mut map).is_none());
+
+        // Looking (even repeatedly) doesn't lose or reorder anything.
+        let msgs: Vec<RelayMsg> = (0..3_u8)
+            .map(|n| msg::Data::new(&[n]).unwrap().into())
+            .collect();
+        for m in &msgs {
+            tx.try_send(m.clone()).unwrap();
+        }
+        assert_eq!(map.stream_has_pending(id), Some(true));
+        assert_eq!(map.stream_has_pending(id), Some(true));
+        for (i, m) in msgs.iter().enumerate() {
+            let got = next(&mut map).unwrap();
+            assert_eq!(format!("{:?}", got), format!("{:?}", m));
+            assert_eq!(map.stream_has_pending(id), Some(i + 1 < msgs.len()));
+        }
+
+        // Once the writer is gone, we still deliver what it sent.
+        tx.try_send(msgs[0].clone()).unwrap();
+        drop(tx);
+        assert_eq!(map.stream_has_pending(id), Some(true));
+        assert!(next(&mut map).is_some());
+        // ...and then the end of the stream is what's left to handle.
+        assert_eq!(map.stream_has_pending(id), Some(true));
+        assert!(matches!(
+            map.poll_next_msg(id, &mut cx),
+            Some(Poll::Ready(None))
+        ));
+
+        // Only open streams have a queue.
+        map.end_received(id)?;
+        assert_eq!(map.stream_has_pending(id), None);
+        assert!(map.poll_next_msg(id, &mut cx).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_next_ready_skips_idle() {
+        use futures::task::noop_waker_ref;
+        use tor_cell::relaycell::msg;
+        let mut map = StreamMap::new(1000);
+        let add = |map: &mut StreamMap| {
+            let (sink, _) = mpsc::channel(128);
+            let (tx, rx) = mpsc::channel(4);
+            let params = AddStreamParams::new(sink, rx, &CongestionParams::default());
+            (map.add_stream(params).unwrap(), tx)
+        };
+        let (busy, mut busy_tx) = add(&mut map);
+        let (idle, mut idle_tx) = add(&mut map);
+        let data: RelayMsg = msg::Data::new(b"hi").unwrap().into();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Nothing is queued anywhere, so nothing is ready.
+        assert_eq!(map.next_ready_stream(), None);
+        assert!(!map.poll_idle_streams(&mut cx));
+
+        // Only the stream with something queued gets a turn.
+        busy_tx.try_send(data.clone()).unwrap();
+        assert_eq!(map.next_ready_stream(), Some(busy));
+        assert_eq!(map.next_ready_stream(), Some(busy));
+        assert!(matches!(
+            map.poll_next_msg(busy, &mut cx),
+            Some(Poll::Ready(Some(_)))
+        ));
+        assert_eq!(map.next_ready_stream(), None);
+
+        // Polling the idle streams finds messages that showed up since, and
+        // holds on to them for next_ready_stream.
+        idle_tx.try_send(data).unwrap();
+        assert!(map.poll_idle_streams(&mut cx));
+        assert!(!map.poll_idle_streams(&mut cx));
+        assert_eq!(map.next_ready_stream(), Some(idle));
+
+        // A stream whose writer went away is ready too, so that the caller
+        // can close it.
+        drop(busy_tx);
+        assert_eq!(map.next_ready_stream(), Some(busy));
+    }
+
+    #[test]
+    fn streammap_add_stream_params() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let params = CongestionParams::default();
+
+        // With no overrides, we get the defaults.
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let plain = map.add_stream(AddStreamParams::new(sink, rx, &params))?;
+        match map.get(plain) {
+            Some(StreamEnt::Open {
+                send_window,
+                recv_window,
+                received_connected,
+                priority,
+                ..
+            }) => {
+                assert_eq!(send_window.window(), params.stream_window());
+                assert_eq!(recv_window.window(), params.stream_window());
+                assert!(!received_connected);
+                assert_eq!(*priority, 0);
+            }
+            _ => panic!("stream wasn't open"),
+        }
+
+        // An override takes effect.
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let tweaked = map.add_stream(AddStreamParams::new(sink, rx, &params).priority(7))?;
+        match map.get(tweaked) {
+            Some(StreamEnt::Open { priority, .. }) => assert_eq!(*priority, 7),
+            _ => panic!("stream wasn't open"),
+        }
+
+        // An already-connected stream won't take another CONNECTED.
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let resumed = map.add_stream(AddStreamParams::new(sink, rx, &params).connected())?;
+        assert!(matches!(
+            map.get(resumed),
+            Some(StreamEnt::Open {
+                received_connected: true,
+                ..
+            })
+        ));
+        let connected = tor_cell::relaycell::msg::Connected::new_empty().into();
+        assert!(map.deliver(resumed, connected).is_err());
+
+        // A sink with less room than the receive window is refused.
+        let window = usize::from(params.stream_window());
+        for (capacity, ok) in [(window - 1, false), (window, true)] {
+            let (sink, _) = mpsc::channel(128);
+            let (_, rx) = mpsc::channel(2);
+            let p = AddStreamParams::new(sink, rx, &params).capacity(capacity);
+            assert_eq!(map.add_stream(p).is_ok(), ok);
+        }
+
+        // add_ent is the same as add_stream with a priority.
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_ent(sink, rx, 3, &params)?;
+        match map.get(id) {
+            Some(StreamEnt::Open { priority, kind, .. }) => {
+                assert_eq!(*priority, 3);
+                assert_eq!(*kind, StreamKind::Data);
+            }
+            _ => panic!("stream wasn't open"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_circuit_window() -> Result<()> {
+        let mut map = StreamMap::new(100);
+        assert_eq!(map.circuit_window_remaining(), 100);
+        let id = add_test_stream(&mut map);
+        let stream_window = |map: &mut StreamMap| match map.get_mut(id) {
+            Some(StreamEnt::Open { send_window, .. }) => send_window.window(),
+            _ => panic!("stream not open"),
+        };
+
+        // Stream cells come out of both windows; cells on the circuit
+        // itself only come out of the circuit window.
+        let tag = [7; 20];
+        for _ in 0..99 {
+            map.take_send_windows(id, &tag, 498)?;
+        }
+        map.take_send_windows(0_u16.into(), &tag, 0)?;
+        assert_eq!(map.circuit_window_remaining(), 0);
+        assert_eq!(stream_window(&mut map), 401);
+
+        // The stream's own window would let it send, but the circuit's
+        // won't.
+        assert_eq!(map.next_ready_stream(), None);
+        assert!(map.take_send_windows(id, &tag, 498).is_err());
+        assert_eq!(stream_window(&mut map), 401);
+
+        // A SENDME with the right tag opens the circuit window again.
+        assert_eq!(map.circuit_window_and_expected_tags().1.len(), 1);
+        assert!(map.note_circuit_sendme(Some([8; 20])).is_err());
+        assert_eq!(map.note_circuit_sendme(Some(tag))?, 100);
+        assert_eq!(map.next_ready_stream(), Some(id));
+
+        // Trying to send on a stream that isn't open takes nothing from
+        // the circuit window.
+        map.end_received(id)?;
+        assert!(map.take_send_windows(id, &tag, 498).is_err());
+        assert_eq!(map.circuit_window_remaining(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_full() {
+        let mut map = StreamMap::new(1000);
+        assert!(map.has_room());
+
+        // Fill every ID with a half-closed stream.
+        for id in 1..=u16::MAX {
+            map.inner().insert(
+                id.into(),
+                StreamEnt::EndReceived {
+                    stats: StreamStats::default(),
+                },
+            );
+        }
+        assert!(!map.has_room());
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        assert!(matches!(
+            map.add_ent(sink, rx, 0, &CongestionParams::default()),
+            Err(Error::IdRangeFull)
+        ));
+
+        // Free up one ID: add_ent finds it.
+        let free_id: StreamId = 1234_u16.into();
+        map.inner().remove(&free_id);
+        assert!(map.has_room());
+        let (sink, _) = mpsc::channel(128);
+        let (_, rx) = mpsc::channel(2);
+        assert_eq!(
+            map.add_ent(sink, rx, 0, &CongestionParams::default())
+                .unwrap(),
+            free_id
+        );
+        assert!(!map.has_room());
+    }
+
+    /// A sink that records the messages it's given, until it's told that
+    /// it's full.
+    #[derive(Default)]
+    struct RecordingSink {
+        /// The messages we've accepted.
+        msgs: std::sync::Arc<std::sync::Mutex<Vec<RelayMsg>>>,
+        /// If true, we refuse every message.
+        full: bool,
+    }
+
+    impl StreamSink for RecordingSink {
+        fn try_send(&mut self, msg: RelayMsg) -> std::result::Result<(), StreamSinkError> {
+            if self.full {
+                return Err(StreamSinkError::Full);
+            }
+            self.msgs.lock().unwrap().push(msg);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streammap_custom_sink() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let sink = RecordingSink::default();
+        let msgs = std::sync::Arc::clone(&sink.msgs);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_stream(AddStreamParams::new(sink, rx, &CongestionParams::default()))?;
+
+        let data = || RelayMsg::Data(tor_cell::relaycell::msg::Data::new(b"hi").unwrap());
+        map.deliver(
+            id,
+            RelayMsg::Connected(tor_cell::relaycell::msg::Connected::new_empty()),
+        )?;
+        map.deliver(id, data())?;
+        {
+            let msgs = msgs.lock().unwrap();
+            assert_eq!(msgs.len(), 2);
+            assert!(matches!(msgs[0], RelayMsg::Connected(_)));
+            assert!(matches!(&msgs[1], RelayMsg::Data(d) if d.as_ref() == b"hi"));
+        }
+
+        // A sink that can't take any more is a protocol violation by the
+        // other side, since it sent more than the window allowed.
+        match map.get_mut(id) {
+            Some(StreamEnt::Open { sink, .. }) => sink.full = true,
+            _ => panic!("stream wasn't open"),
+        }
+        assert!(matches!(map.deliver(id, data()), Err(Error::CircProto(_))));
+        assert_eq!(msgs.lock().unwrap().len(), 2);
+
+        // Terminating the stream drops its sink.
+        assert_eq!(map.terminate(id)?, ShouldSendEnd::Send);
+        assert_eq!(std::sync::Arc::strong_count(&msgs), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_deliver() -> Result<()> {
+        use tor_cell::relaycell::msg::{Begin, Connected, Data, End, Resolved};
+        let mut map = StreamMap::new(1000);
+        let sink = RecordingSink::default();
+        let msgs = std::sync::Arc::clone(&sink.msgs);
+        let (_, rx) = mpsc::channel(2);
+        let data_id =
+            map.add_stream(AddStreamParams::new(sink, rx, &CongestionParams::default()))?;
+        let sink = RecordingSink::default();
+        let resolve_msgs = std::sync::Arc::clone(&sink.msgs);
+        let (_, rx) = mpsc::channel(2);
+        let params =
+            AddStreamParams::new(sink, rx, &CongestionParams::default()).kind(StreamKind::Resolve);
+        let resolve_id = map.add_stream(params)?;
+
+        let data = || RelayMsg::Data(Data::new(b"hello").unwrap());
+        let connected = || RelayMsg::Connected(Connected::new_empty());
+        let resolved = || RelayMsg::Resolved(Resolved::new_err(true, 60));
+        let is_proto_err = |r: Result<()>| matches!(r, Err(Error::CircProto(_)));
+
+        // A data stream needs a CONNECTED before any DATA, and only one.
+        assert!(is_proto_err(map.deliver(data_id, data())));
+        map.deliver(data_id, connected())?;
+        assert!(is_proto_err(map.deliver(data_id, connected())));
+        map.deliver(data_id, data())?;
+        assert!(is_proto_err(map.deliver(data_id, resolved())));
+        let begin = Begin::new("www.example.com", 80, 0)?;
+        assert!(is_proto_err(map.deliver(data_id, begin.into())));
+        assert_eq!(msgs.lock().unwrap().len(), 2);
+        assert_eq!(map.stream_stats(data_id).unwrap().bytes_recv, 5);
+        match map.get(data_id) {
+            Some(StreamEnt::Open { recv_window, .. }) => assert_eq!(recv_window.window(), 499),
+            _ => panic!("stream wasn't open"),
+        }
+
+        // A resolve stream only gets a single RESOLVED.
+        assert!(is_proto_err(map.deliver(resolve_id, connected())));
+        assert!(is_proto_err(map.deliver(resolve_id, data())));
+        map.deliver(resolve_id, resolved())?;
+        assert!(is_proto_err(map.deliver(resolve_id, resolved())));
+        assert_eq!(resolve_msgs.lock().unwrap().len(), 1);
+
+        // An END gets delivered, and then nothing else is allowed.
+        map.deliver(data_id, End::new_misc().into())?;
+        assert_eq!(msgs.lock().unwrap().len(), 3);
+        assert!(matches!(
+            map.get(data_id),
+            Some(StreamEnt::EndReceived { .. })
+        ));
+        assert!(is_proto_err(map.deliver(data_id, data())));
+
+        // Nor can anything arrive on a stream that doesn't exist.
+        let nonesuch: StreamId = 0x7777.into();
+        assert!(is_proto_err(map.deliver(nonesuch, data())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn streammap_deliver_dropped() -> Result<()> {
+        let mut map = StreamMap::new(1000);
+        let (sink, sink_rx) = mpsc::channel(8);
+        let (_, rx) = mpsc::channel(2);
+        let id = map.add_stream(AddStreamParams::new(sink, rx, &CongestionParams::default()))?;
+        let msg = tor_cell::relaycell::msg::Data::new(b"nobody is listening")?;
+
+        mark_connected(&mut map, id);
+        drop(sink_rx);
+        map.deliver(id, msg.clone().into())?;
+        map.deliver(id, msg.into())?;
+        let (_, hs, dropped) = map.terminate_take(id)?;
+        // The dropped cells still came out of the window.
+        assert_eq!(hs.unwrap().recv_window(), 498);
+        assert_eq!(dropped, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn mpsc_sink() {
+        let (mut sink, rx) = mpsc::channel(0);
+        let msg = RelayMsg::Drop;
+        // A channel of size 0 still has one slot for each sender.
+        assert_eq!(StreamSink::try_send(&mut sink, msg.clone()), Ok(()));
+        assert_eq!(
+            StreamSink::try_send(&mut sink, msg.clone()),
+            Err(StreamSinkError::Full)
+        );
+        drop(rx);
+
+        let (mut sink, rx) = mpsc::channel(4);
+        drop(rx);
+        assert_eq!(
+            StreamSink::try_send(&mut sink, msg),
+            Err(StreamSinkError::Closed)
+        );
+    }
 }
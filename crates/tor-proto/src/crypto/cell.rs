@@ -395,8 +395,8 @@ pub(crate) mod tor1 {
 mod test {
     #![allow(clippy::unwrap_used)]
     use super::*;
-    use crate::SecretBytes;
     use rand::RngCore;
+    use tor_bytes::SecretBuf;
 
     fn add_layers(
         cc_out: &mut OutboundClientCrypt,
@@ -412,9 +412,9 @@ mod test {
     fn roundtrip() {
         // Take canned keys and make sure we can do crypto correctly.
         use crate::crypto::handshake::ShakeKeyGenerator as KGen;
-        fn s(seed: &[u8]) -> SecretBytes {
-            let mut s: SecretBytes = SecretBytes::new(Vec::new());
-            s.extend(seed);
+        fn s(seed: &[u8]) -> SecretBuf {
+            let mut s: SecretBuf = SecretBuf::new();
+            s.extend_from_slice(seed);
             s
         }
 
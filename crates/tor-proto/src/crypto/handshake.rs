@@ -18,8 +18,11 @@ pub(crate) mod ntor;
 pub(crate) mod ntor_v3;
 
 use crate::{Result, SecretBytes};
+use tor_bytes::SecretBuf;
 //use zeroize::Zeroizing;
 use rand_core::{CryptoRng, RngCore};
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::util::rand_compat::RngCompatExt;
 
 /// A ClientHandshake is used to generate a client onionskin and
 /// handle a relay onionskin.
@@ -65,6 +68,60 @@ pub(crate) trait ServerHandshake {
     ) -> RelayHandshakeResult<(Self::KeyGen, Vec<u8>)>;
 }
 
+/// A source for the temporary curve25519 keys that a handshake generates.
+///
+/// Outside of tests, these keys always come from a strong random number
+/// generator, via [`RngKeyGen`].  Tests can use a `FixedKeyGen` instead,
+/// so that they can check the real handshake code against published test
+/// vectors.
+///
+/// The keys are returned as `StaticSecret`s, since most handshakes need to
+/// use their temporary key more than once.
+pub(crate) trait EphemeralKeyGen {
+    /// Return a new secret key for use in a single handshake.
+    fn static_secret(&mut self) -> curve25519::StaticSecret;
+}
+
+/// An [`EphemeralKeyGen`] that generates keys using a random number
+/// generator.
+pub(crate) struct RngKeyGen<'a, R>(pub(crate) &'a mut R);
+
+impl<R: RngCore + CryptoRng> EphemeralKeyGen for RngKeyGen<'_, R> {
+    fn static_secret(&mut self) -> curve25519::StaticSecret {
+        curve25519::StaticSecret::new((&mut *self.0).rng_compat())
+    }
+}
+
+/// An [`EphemeralKeyGen`] that returns a fixed list of keys, in order.
+///
+/// Only for testing: using this in production would be catastrophic.
+#[cfg(test)]
+pub(crate) struct FixedKeyGen(std::collections::VecDeque<[u8; 32]>);
+
+#[cfg(test)]
+impl FixedKeyGen {
+    /// Make a new FixedKeyGen that will return `keys`, in order.
+    pub(crate) fn new(keys: &[[u8; 32]]) -> Self {
+        FixedKeyGen(keys.iter().copied().collect())
+    }
+
+    /// Return the next key in our list.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we have run out of keys.
+    fn next_key(&mut self) -> [u8; 32] {
+        self.0.pop_front().expect("Ran out of fixed keys")
+    }
+}
+
+#[cfg(test)]
+impl EphemeralKeyGen for FixedKeyGen {
+    fn static_secret(&mut self) -> curve25519::StaticSecret {
+        self.next_key().into()
+    }
+}
+
 /// A KeyGenerator is returned by a handshake, and used to generate
 /// session keys for the protocol.
 ///
@@ -81,12 +138,12 @@ pub(crate) trait KeyGenerator {
 /// This is deprecated and shouldn't be used for new keys.
 pub(crate) struct TapKeyGenerator {
     /// Seed for the TAP KDF.
-    seed: SecretBytes,
+    seed: SecretBuf,
 }
 
 impl TapKeyGenerator {
     /// Create a key generator based on a provided seed
-    pub(crate) fn new(seed: SecretBytes) -> Self {
+    pub(crate) fn new(seed: SecretBuf) -> Self {
         TapKeyGenerator { seed }
     }
 }
@@ -101,13 +158,13 @@ impl KeyGenerator for TapKeyGenerator {
 /// Generates keys based on SHAKE-256.
 pub(crate) struct ShakeKeyGenerator {
     /// Seed for the key generator
-    seed: SecretBytes,
+    seed: SecretBuf,
 }
 
 impl ShakeKeyGenerator {
     /// Create a key generator based on a provided seed
     #[allow(dead_code)] // We'll construct these for v3 onion services
-    pub(crate) fn new(seed: SecretBytes) -> Self {
+    pub(crate) fn new(seed: SecretBuf) -> Self {
         ShakeKeyGenerator { seed }
     }
 }
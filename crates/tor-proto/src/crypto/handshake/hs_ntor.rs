@@ -27,7 +27,7 @@
 use crate::crypto::handshake::KeyGenerator;
 use crate::crypto::ll::kdf::{Kdf, ShakeKdf};
 use crate::{Error, Result, SecretBytes};
-use tor_bytes::{Reader, Writer};
+use tor_bytes::{Reader, SecretBuf, Writer};
 use tor_llcrypto::d::Sha3_256;
 use tor_llcrypto::pk::{curve25519, ed25519};
 use tor_llcrypto::util::rand_compat::RngCompatExt;
@@ -40,7 +40,6 @@ use rand_core::{CryptoRng, RngCore};
 use std::convert::TryInto;
 use tor_error::into_internal;
 use tor_llcrypto::cipher::aes::Aes256Ctr;
-use zeroize::Zeroizing;
 
 /// The ENC_KEY from the HS Ntor protocol
 type EncKey = [u8; 32];
@@ -57,12 +56,12 @@ pub type Subcredential = [u8; 32];
 /// expansion protocol specified in section "Key expansion" of rend-spec-v3.txt .
 pub struct HsNtorHkdfKeyGenerator {
     /// Secret data derived from the handshake, used as input to HKDF
-    seed: SecretBytes,
+    seed: SecretBuf,
 }
 
 impl HsNtorHkdfKeyGenerator {
     /// Create a new key generator to expand a given seed
-    pub fn new(seed: SecretBytes) -> Self {
+    pub fn new(seed: SecretBuf) -> Self {
         HsNtorHkdfKeyGenerator { seed }
     }
 }
@@ -402,7 +401,7 @@ fn get_introduce1_key_material(
 
     // Construct hs_keys = KDF(intro_secret_hs_input | t_hsenc | info, S_KEY_LEN+MAC_LEN)
     // Start by getting 'intro_secret_hs_input'
-    let mut secret_input = Zeroizing::new(Vec::new());
+    let mut secret_input = SecretBuf::new();
     secret_input.write(bx); // EXP(B,x)
     secret_input.write(auth_key); // AUTH_KEY
     secret_input.write(X); // X
@@ -459,7 +458,7 @@ fn get_rendezvous1_key_material(
     let hs_ntor_key_constant = &b"tor-hs-ntor-curve25519-sha3-256-1:hs_key_extract"[..];
 
     // Start with rend_secret_hs_input
-    let mut secret_input = Zeroizing::new(Vec::new());
+    let mut secret_input = SecretBuf::new();
     secret_input.write(xy); // EXP(X,y)
     secret_input.write(xb); // EXP(X,b)
     secret_input.write(auth_key); // AUTH_KEY
@@ -473,7 +472,7 @@ fn get_rendezvous1_key_material(
     let verify = hs_ntor_mac(&secret_input, hs_ntor_verify_constant)?;
 
     // Start building 'auth_input'
-    let mut auth_input = Zeroizing::new(Vec::new());
+    let mut auth_input = SecretBuf::new();
     auth_input.write(&verify);
     auth_input.write(auth_key); // AUTH_KEY
     auth_input.write(B); // B
@@ -486,10 +485,10 @@ fn get_rendezvous1_key_material(
     let auth_input_mac = hs_ntor_mac(&auth_input, hs_ntor_mac_constant)?;
 
     // Now finish up with the KDF construction
-    let mut kdf_seed = Zeroizing::new(Vec::new());
+    let mut kdf_seed = SecretBuf::new();
     kdf_seed.write(&ntor_key_seed);
     kdf_seed.write(hs_ntor_expand_constant);
-    let keygen = HsNtorHkdfKeyGenerator::new(Zeroizing::new(kdf_seed.to_vec()));
+    let keygen = HsNtorHkdfKeyGenerator::new(kdf_seed);
 
     Ok((keygen, auth_input_mac))
 }
@@ -1,17 +1,15 @@
 //! Implements the ntor handshake, as used in modern Tor.
 
-use super::{KeyGenerator, RelayHandshakeError, RelayHandshakeResult};
+use super::{EphemeralKeyGen, KeyGenerator, RelayHandshakeError, RelayHandshakeResult, RngKeyGen};
 use crate::util::ct;
 use crate::{Error, Result, SecretBytes};
-use tor_bytes::{Reader, Writer};
+use tor_bytes::{Reader, SecretBuf, Writer};
 use tor_llcrypto::d;
 use tor_llcrypto::pk::curve25519::*;
 use tor_llcrypto::pk::rsa::RsaIdentity;
-use tor_llcrypto::util::rand_compat::RngCompatExt;
 
 use digest::Mac;
 use rand_core::{CryptoRng, RngCore};
-use zeroize::Zeroizing;
 
 /// Client side of the Ntor handshake.
 pub(crate) struct NtorClient;
@@ -25,7 +23,7 @@ impl super::ClientHandshake for NtorClient {
         rng: &mut R,
         key: &Self::KeyType,
     ) -> Result<(Self::StateType, Vec<u8>)> {
-        Ok(client_handshake_ntor_v1(rng, key))
+        Ok(client_handshake_ntor_v1(&mut RngKeyGen(rng), key))
     }
 
     fn client2<T: AsRef<[u8]>>(state: Self::StateType, msg: T) -> Result<Self::KeyGen> {
@@ -45,7 +43,7 @@ impl super::ServerHandshake for NtorServer {
         key: &[Self::KeyType],
         msg: T,
     ) -> RelayHandshakeResult<(Self::KeyGen, Vec<u8>)> {
-        server_handshake_ntor_v1(rng, msg, key)
+        server_handshake_ntor_v1(&mut RngKeyGen(rng), msg, key)
     }
 }
 
@@ -104,12 +102,12 @@ pub(crate) struct NtorHandshakeState {
 pub(crate) struct NtorHkdfKeyGenerator {
     /// Secret key information derived from the handshake, used as input
     /// to HKDF
-    seed: SecretBytes,
+    seed: SecretBuf,
 }
 
 impl NtorHkdfKeyGenerator {
     /// Create a new key generator to expand a given seed
-    pub(crate) fn new(seed: SecretBytes) -> Self {
+    pub(crate) fn new(seed: SecretBuf) -> Self {
         NtorHkdfKeyGenerator { seed }
     }
 }
@@ -126,26 +124,19 @@ impl KeyGenerator for NtorHkdfKeyGenerator {
 /// Alias for an HMAC output, used to validate correctness of a handshake.
 type Authcode = digest::CtOutput<hmac::Hmac<d::Sha256>>;
 
-/// Perform a client handshake, generating an onionskin and a state object
-fn client_handshake_ntor_v1<R>(
-    rng: &mut R,
+/// Perform a client handshake, generating an onionskin and a state object.
+///
+/// Our temporary key comes from `keygen`.
+fn client_handshake_ntor_v1<K>(
+    keygen: &mut K,
     relay_public: &NtorPublicKey,
 ) -> (NtorHandshakeState, Vec<u8>)
 where
-    R: RngCore + CryptoRng,
+    K: EphemeralKeyGen,
 {
-    let my_sk = StaticSecret::new(rng.rng_compat());
+    let my_sk = keygen.static_secret();
     let my_public = PublicKey::from(&my_sk);
 
-    client_handshake_ntor_v1_no_keygen(my_public, my_sk, relay_public)
-}
-
-/// Helper: client handshake _without_ generating  new keys.
-fn client_handshake_ntor_v1_no_keygen(
-    my_public: PublicKey,
-    my_sk: StaticSecret,
-    relay_public: &NtorPublicKey,
-) -> (NtorHandshakeState, Vec<u8>) {
     let mut v: Vec<u8> = Vec::new();
 
     v.write(&relay_public.id);
@@ -205,7 +196,7 @@ fn ntor_derive(
     let ntor1_verify = &b"ntor-curve25519-sha256-1:verify"[..];
     let server_string = &b"Server"[..];
 
-    let mut secret_input = Zeroizing::new(Vec::new());
+    let mut secret_input = SecretBuf::new();
     secret_input.write(xy); // EXP(X,y)
     secret_input.write(xb); // EXP(X,b)
     secret_input.write(&server_pk.id); // ID
@@ -222,7 +213,7 @@ fn ntor_derive(
         m.update(&secret_input[..]);
         m.finalize()
     };
-    let mut auth_input: SecretBytes = Zeroizing::new(Vec::new());
+    let mut auth_input = SecretBuf::new();
     auth_input.write_and_consume(verify); // verify
     auth_input.write(&server_pk.id); // ID
     auth_input.write(&server_pk.pk); // B
@@ -244,36 +235,24 @@ fn ntor_derive(
 
 /// Perform a server-side ntor handshake.
 ///
-/// On success returns a key generator and a server onionskin.
-fn server_handshake_ntor_v1<R, T>(
-    rng: &mut R,
+/// Our temporary key comes from `keygen`.  On success returns a key
+/// generator and a server onionskin.
+fn server_handshake_ntor_v1<K, T>(
+    keygen: &mut K,
     msg: T,
     keys: &[NtorSecretKey],
 ) -> RelayHandshakeResult<(NtorHkdfKeyGenerator, Vec<u8>)>
 where
-    R: RngCore + CryptoRng,
+    K: EphemeralKeyGen,
     T: AsRef<[u8]>,
 {
     // TODO(nickm): we generate this key whether or not we are
     // actually going to find our nodeid or keyid. Perhaps we should
     // delay that till later?  It shouldn't matter for most cases,
     // though.
-    let ephem = EphemeralSecret::new(rng.rng_compat());
+    let ephem = keygen.static_secret();
     let ephem_pub = PublicKey::from(&ephem);
 
-    server_handshake_ntor_v1_no_keygen(ephem_pub, ephem, msg, keys)
-}
-
-/// Helper: perform a server handshake without generating any new keys.
-fn server_handshake_ntor_v1_no_keygen<T>(
-    ephem_pub: PublicKey,
-    ephem: EphemeralSecret,
-    msg: T,
-    keys: &[NtorSecretKey],
-) -> RelayHandshakeResult<(NtorHkdfKeyGenerator, Vec<u8>)>
-where
-    T: AsRef<[u8]>,
-{
     let mut cur = Reader::from_slice(msg.as_ref());
 
     let my_id: RsaIdentity = cur.extract()?;
@@ -313,7 +292,8 @@ where
 mod tests {
     #![allow(clippy::unwrap_used)]
     use super::*;
-    use crate::crypto::testing::FakePRNG;
+    use crate::crypto::handshake::FixedKeyGen;
+    use tor_llcrypto::util::rand_compat::RngCompatExt;
 
     #[test]
     fn simple() -> Result<()> {
@@ -346,12 +326,6 @@ mod tests {
         Ok(())
     }
 
-    fn make_fake_ephem_key(bytes: &[u8]) -> EphemeralSecret {
-        assert_eq!(bytes.len(), 32);
-        let mut rng = FakePRNG::new(bytes).rng_compat();
-        EphemeralSecret::new(&mut rng)
-    }
-
     #[test]
     fn testvec() -> Result<()> {
         use hex_literal::hex;
@@ -359,9 +333,7 @@ mod tests {
         let b_sk = hex!("4820544f4c4420594f5520444f474954204b454550532048415050454e494e47");
         let b_pk = hex!("ccbc8541904d18af08753eae967874749e6149f873de937f57f8fd903a21c471");
         let x_sk = hex!("706f6461792069207075742e2e2e2e2e2e2e2e4a454c4c59206f6e2074686973");
-        let x_pk = hex!("e65dfdbef8b2635837fe2cebc086a8096eae3213e6830dc407516083d412b078");
         let y_sk = hex!("70686520737175697272656c2e2e2e2e2e2e2e2e686173206869732067616d65");
-        let y_pk = hex!("390480a14362761d6aec1fea840f6e9e928fb2adb7b25c670be1045e35133a37");
         let id = hex!("69546f6c64596f7541626f75745374616972732e");
         let client_handshake = hex!("69546f6c64596f7541626f75745374616972732eccbc8541904d18af08753eae967874749e6149f873de937f57f8fd903a21c471e65dfdbef8b2635837fe2cebc086a8096eae3213e6830dc407516083d412b078");
         let server_handshake = hex!("390480a14362761d6aec1fea840f6e9e928fb2adb7b25c670be1045e35133a371cbdf68b89923e1f85e8e18ee6e805ea333fe4849c790ffd2670bd80fec95cc8");
@@ -376,14 +348,14 @@ mod tests {
             sk: b_sk.into(),
         };
 
+        // The handshake messages include our public keys X and Y, so
+        // checking them also checks that we derived those keys correctly.
         let (state, create_msg) =
-            client_handshake_ntor_v1_no_keygen(x_pk.into(), x_sk.into(), &relay_pk);
+            client_handshake_ntor_v1(&mut FixedKeyGen::new(&[x_sk]), &relay_pk);
         assert_eq!(&create_msg[..], &client_handshake[..]);
 
-        let ephem = make_fake_ephem_key(&y_sk[..]);
-        let ephem_pub = y_pk.into();
         let (s_keygen, created_msg) =
-            server_handshake_ntor_v1_no_keygen(ephem_pub, ephem, &create_msg[..], &[relay_sk])
+            server_handshake_ntor_v1(&mut FixedKeyGen::new(&[y_sk]), &create_msg[..], &[relay_sk])
                 .unwrap();
         assert_eq!(&created_msg[..], &server_handshake[..]);
 
@@ -13,18 +13,16 @@
 // This module is still unused: so allow some dead code for now.
 #![allow(dead_code)]
 
-use super::{RelayHandshakeError, RelayHandshakeResult};
+use super::{EphemeralKeyGen, RelayHandshakeError, RelayHandshakeResult};
 use crate::util::ct;
 use crate::{Error, Result};
-use tor_bytes::{Reader, Writeable, Writer};
+use tor_bytes::{Reader, SecretBuf, Writeable, Writer};
 use tor_llcrypto::d::{Sha3_256, Shake256};
 use tor_llcrypto::pk::{curve25519, ed25519::Ed25519Identity};
-use tor_llcrypto::util::rand_compat::RngCompatExt;
 
 use cipher::{NewCipher, StreamCipher};
 
 use generic_array::GenericArray;
-use rand_core::{CryptoRng, RngCore};
 use subtle::{Choice, ConstantTimeEq};
 use tor_llcrypto::cipher::aes::Aes256Ctr;
 use zeroize::Zeroizing;
@@ -271,27 +269,16 @@ pub(crate) struct NtorV3KeyGenerator {
 
 /// Client-side Ntor version 3 handshake, part one.
 ///
-/// Given a secure `rng`, a relay's public key, a secret message to send,
-/// and a shared verification string, generate a new handshake state
-/// and a message to send to the relay.
-fn client_handshake_ntor_v3<R: RngCore + CryptoRng>(
-    rng: &mut R,
+/// Given a source of temporary keys in `keygen`, a relay's public key, a
+/// secret message to send, and a shared verification string, generate a
+/// new handshake state and a message to send to the relay.
+fn client_handshake_ntor_v3<K: EphemeralKeyGen>(
+    keygen: &mut K,
     relay_public: &NtorV3PublicKey,
     client_msg: &[u8],
     verification: &[u8],
 ) -> (NtorV3HandshakeState, Vec<u8>) {
-    let my_sk = curve25519::StaticSecret::new(rng.rng_compat());
-    client_handshake_ntor_v3_no_keygen(relay_public, client_msg, verification, my_sk)
-}
-
-/// As `client_handshake_ntor_v3`, but don't generate an ephemeral DH
-/// key: instead take that key an arguments `my_sk`.
-fn client_handshake_ntor_v3_no_keygen(
-    relay_public: &NtorV3PublicKey,
-    client_msg: &[u8],
-    verification: &[u8],
-    my_sk: curve25519::StaticSecret,
-) -> (NtorV3HandshakeState, Vec<u8>) {
+    let my_sk = keygen.static_secret();
     let my_public = curve25519::PublicKey::from(&my_sk);
     let bx = my_sk.diffie_hellman(&relay_public.pk);
 
@@ -339,7 +326,7 @@ pub(crate) trait MsgReply {
 
 /// Complete an ntor v3 handshake as a server.
 ///
-/// Use the provided `rng` to generate keys; use the provided
+/// Use the provided `keygen` to generate keys; use the provided
 /// `reply_fn` to handle incoming client secret message and decide how
 /// to reply.  The client's handshake is in `message`.  Our private
 /// key(s) are in `keys`.  The `verification` string must match the
@@ -347,25 +334,14 @@ pub(crate) trait MsgReply {
 ///
 /// On success, return the server handshake message to send, and an XofReader
 /// to use in generating circuit keys.
-fn server_handshake_ntor_v3<RNG: CryptoRng + RngCore, REPLY: MsgReply>(
-    rng: &mut RNG,
-    reply_fn: &mut REPLY,
-    message: &[u8],
-    keys: &[NtorV3SecretKey],
-    verification: &[u8],
-) -> RelayHandshakeResult<(Vec<u8>, impl digest::XofReader)> {
-    let secret_key_y = curve25519::StaticSecret::new(rng.rng_compat());
-    server_handshake_ntor_v3_no_keygen(reply_fn, &secret_key_y, message, keys, verification)
-}
-
-/// As `server_handshake_ntor_v3`, but take a secret key instead of an RNG.
-fn server_handshake_ntor_v3_no_keygen<REPLY: MsgReply>(
+fn server_handshake_ntor_v3<K: EphemeralKeyGen, REPLY: MsgReply>(
+    keygen: &mut K,
     reply_fn: &mut REPLY,
-    secret_key_y: &curve25519::StaticSecret,
     message: &[u8],
     keys: &[NtorV3SecretKey],
     verification: &[u8],
 ) -> RelayHandshakeResult<(Vec<u8>, impl digest::XofReader)> {
+    let secret_key_y = keygen.static_secret();
     // Decode the message.
     let mut r = Reader::from_slice(message);
     let id: Ed25519Identity = r.extract()?;
@@ -394,7 +370,7 @@ fn server_handshake_ntor_v3_no_keygen<REPLY: MsgReply>(
         mac.write(client_msg);
         mac.take().finalize().into()
     };
-    let y_pk: curve25519::PublicKey = (secret_key_y).into();
+    let y_pk: curve25519::PublicKey = (&secret_key_y).into();
     let xy = secret_key_y.diffie_hellman(&client_pk);
 
     let mut okay = computed_mac.ct_eq(&msg_mac)
@@ -416,7 +392,7 @@ fn server_handshake_ntor_v3_no_keygen<REPLY: MsgReply>(
     // that we're going to reply.
 
     let secret_input = {
-        let mut si = Zeroizing::new(Vec::new());
+        let mut si = SecretBuf::new();
         si.write(&xy);
         si.write(&xb);
         si.write(&keypair.pk.id);
@@ -491,7 +467,7 @@ fn client_handshake_ntor_v3_part2(
 
     let yx = state.my_sk.diffie_hellman(&y_pk);
     let secret_input = {
-        let mut si = Zeroizing::new(Vec::new());
+        let mut si = SecretBuf::new();
         si.write(&yx);
         si.write(&state.shared_secret);
         si.write(&state.relay_public.id);
@@ -560,12 +536,15 @@ impl super::ClientHandshake for NtorV3Client {
 #[allow(clippy::unwrap_used)]
 mod test {
     use super::*;
+    use crate::crypto::handshake::{FixedKeyGen, RngKeyGen};
     use hex_literal::hex;
+    use tor_llcrypto::util::rand_compat::RngCompatExt;
 
     #[test]
     fn test_ntor3_roundtrip() {
         let b = curve25519::StaticSecret::new(rand::thread_rng().rng_compat());
         let mut rng = rand::thread_rng();
+        let mut keygen = RngKeyGen(&mut rng);
         let id = b"not identifier---but correct len";
 
         let B: curve25519::PublicKey = (&b).into();
@@ -583,7 +562,7 @@ mod test {
         let relay_message = &b"Greetings, client. I am a robot. Beep boop."[..];
 
         let (c_state, c_handshake) =
-            client_handshake_ntor_v3(&mut rng, &relay_public, client_message, verification);
+            client_handshake_ntor_v3(&mut keygen, &relay_public, client_message, verification);
 
         struct Rep(Vec<u8>, Vec<u8>);
         impl MsgReply for Rep {
@@ -595,7 +574,7 @@ mod test {
         let mut rep = Rep(Vec::new(), relay_message.to_vec());
 
         let (s_handshake, mut s_keygen) = server_handshake_ntor_v3(
-            &mut rng,
+            &mut keygen,
             &mut rep,
             &c_handshake,
             &[relay_private],
@@ -625,9 +604,6 @@ mod test {
         let b: curve25519::StaticSecret = b.into();
         let B: curve25519::PublicKey = (&b).into();
         let id: Ed25519Identity = id.into();
-        let x: curve25519::StaticSecret = x.into();
-        //let X = (&x).into();
-        let y: curve25519::StaticSecret = y.into();
 
         let client_message = hex!("68656c6c6f20776f726c64");
         let verification = hex!("78797a7a79");
@@ -639,8 +615,12 @@ mod test {
             pk: relay_public.clone(),
         };
 
-        let (state, client_handshake) =
-            client_handshake_ntor_v3_no_keygen(&relay_public, &client_message, &verification, x);
+        let (state, client_handshake) = client_handshake_ntor_v3(
+            &mut FixedKeyGen::new(&[x]),
+            &relay_public,
+            &client_message,
+            &verification,
+        );
 
         assert_eq!(client_handshake[..], hex!("9fad2af287ef942632833d21f946c6260c33fae6172b60006e86e4a6911753a2f8307a2bc1870b00b828bb74dbb8fd88e632a6375ab3bcd1ae706aaa8b6cdd1d252fe9ae91264c91d4ecb8501f79d0387e34ad8ca0f7c995184f7d11d5da4f463bebd9151fd3b47c180abc9e044d53565f04d82bbb3bebed3d06cea65db8be9c72b68cd461942088502f67")[..]);
 
@@ -654,9 +634,9 @@ mod test {
         }
         let mut rep = Replier(client_message.to_vec(), server_message.to_vec(), false);
 
-        let (server_handshake, mut server_keygen) = server_handshake_ntor_v3_no_keygen(
+        let (server_handshake, mut server_keygen) = server_handshake_ntor_v3(
+            &mut FixedKeyGen::new(&[y]),
             &mut rep,
-            &y,
             &client_handshake,
             &[relay_private],
             &verification,
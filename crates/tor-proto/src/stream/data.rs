@@ -4,7 +4,8 @@
 use crate::{Error, Result};
 use tor_cell::relaycell::msg::EndReason;
 
-use futures::io::{AsyncRead, AsyncWrite};
+use futures::future::Either;
+use futures::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
 use futures::task::{Context, Poll};
 use futures::Future;
 
@@ -16,10 +17,11 @@ use tokio_crate::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite
 use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
 
 use std::fmt::{self, Debug};
-use std::io::Result as IoResult;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::pin::Pin;
+use std::time::Duration;
 
-use crate::circuit::StreamTarget;
+use crate::circuit::{ClosedReason, StreamHandle, StreamStats, StreamTarget};
 use crate::stream::StreamReader;
 use tor_cell::relaycell::msg::{Data, RelayMsg};
 use tor_error::internal;
@@ -82,6 +84,14 @@ use tor_error::internal;
 /// This type is internally composed of a [`DataReader`] and a [`DataWriter`]; the
 /// `DataStream::split` method can be used to split it into those two parts, for more
 /// convenient usage with e.g. stream combinators.
+///
+/// # Timeouts
+///
+/// It's safe to stop waiting for a read (for example, by dropping the
+/// future returned by [`AsyncReadExt::read`](futures::io::AsyncReadExt::read)):
+/// a cell that was already on its way is kept, and its bytes are returned
+/// by the next read.  [`DataStream::read_with_deadline`] and
+/// [`DataStream::set_read_timeout`] rely on this.
 // # Semver note
 //
 // Note that this type is re-exported as a part of the public API of
@@ -93,6 +103,8 @@ pub struct DataStream {
     w: DataWriter,
     /// Underlying reader for this stream
     r: DataReader,
+    /// A reference to this stream, for asking the circuit about it.
+    handle: StreamHandle,
 }
 
 /// The write half of a [`DataStream`], implementing [`futures::io::AsyncWrite`].
@@ -144,6 +156,56 @@ pub struct DataReader {
     /// poll_read().  It might be possible to do better here, and we
     /// should refactor if so.
     state: Option<DataReaderState>,
+
+    /// If present, the longest time that a read may wait for data.
+    timeout: Option<ReadTimeout>,
+}
+
+/// A future that completes after some amount of time has elapsed.
+type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A default timeout for reads on a [`DataReader`].
+struct ReadTimeout {
+    /// How long a read may wait before it fails.
+    duration: Duration,
+    /// A function to make a future that completes after a given duration.
+    sleep: Box<dyn Fn(Duration) -> SleepFuture + Send>,
+    /// The timer for the read that is currently waiting, if any.
+    timer: Option<SleepFuture>,
+}
+
+impl Debug for ReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadTimeout")
+            .field("duration", &self.duration)
+            .field("waiting", &self.timer.is_some())
+            .finish()
+    }
+}
+
+impl ReadTimeout {
+    /// Return true if the read that is currently waiting has waited too
+    /// long, starting a timer for it if there isn't one already.
+    ///
+    /// If this returns false, `cx` will be woken when the timer expires.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        let (sleep, duration) = (&self.sleep, self.duration);
+        let timer = self.timer.get_or_insert_with(|| sleep(duration));
+        if timer.as_mut().poll(cx).is_ready() {
+            self.timer = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Return the error we give when a read has taken too long.
+fn read_timed_out() -> IoError {
+    IoError::new(
+        IoErrorKind::TimedOut,
+        "Timed out waiting for data on stream",
+    )
 }
 
 impl DataStream {
@@ -152,6 +214,7 @@ impl DataStream {
     /// For non-optimistic stream, function `wait_for_connection`
     /// must be called after to make sure CONNECTED is received.
     pub(crate) fn new(reader: StreamReader, target: StreamTarget) -> Self {
+        let handle = target.handle();
         let r = DataReader {
             state: Some(DataReaderState::Ready(DataReaderImpl {
                 s: reader,
@@ -159,6 +222,7 @@ impl DataStream {
                 offset: 0,
                 connected: false,
             })),
+            timeout: None,
         };
         let w = DataWriter {
             state: Some(DataWriterState::Ready(DataWriterImpl {
@@ -167,14 +231,73 @@ impl DataStream {
                 n_pending: 0,
             })),
         };
-        DataStream { w, r }
+        DataStream { w, r, handle }
     }
 
     /// Divide this DataStream into its constituent parts.
+    ///
+    /// The two halves are owned independently, so they can be used from
+    /// different tasks without any locking.  Dropping the reader doesn't
+    /// close the stream: the writer keeps working.  Likewise, closing the
+    /// writer flushes it and stops any further writes, but buffered and
+    /// incoming data can still be read.  We only close the stream (and tell
+    /// the exit with an END cell) once both halves have been dropped.
     pub fn split(self) -> (DataReader, DataWriter) {
         (self.r, self.w)
     }
 
+    /// Return the number of bytes of data that we've sent and received on
+    /// this stream so far.
+    ///
+    /// Returns `None` once the other side has closed the stream.
+    pub async fn stream_stats(&self) -> Result<Option<StreamStats>> {
+        self.handle.stats().await
+    }
+
+    /// Return the reason why this stream stopped being open.
+    ///
+    /// Returns `None` while the stream is still open, or once the circuit
+    /// has closed too many other streams to remember this one.
+    pub async fn closed_reason(&self) -> Result<Option<ClosedReason>> {
+        self.handle.closed_reason().await
+    }
+
+    /// Read some bytes from this stream into `buf`, giving up if
+    /// `deadline` completes first.
+    ///
+    /// See [`DataReader::read_with_deadline`].
+    pub async fn read_with_deadline<D>(&mut self, buf: &mut [u8], deadline: D) -> IoResult<usize>
+    where
+        D: Future<Output = ()>,
+    {
+        self.r.read_with_deadline(buf, deadline).await
+    }
+
+    /// Make every read on this stream fail if it waits longer than
+    /// `timeout` for data.
+    ///
+    /// See [`DataReader::set_read_timeout`].
+    pub fn set_read_timeout<F, S>(&mut self, timeout: Duration, sleep: F)
+    where
+        F: Fn(Duration) -> S + Send + 'static,
+        S: Future<Output = ()> + Send + 'static,
+    {
+        self.r.set_read_timeout(timeout, sleep);
+    }
+
+    /// Remove any read timeout set with [`DataStream::set_read_timeout`].
+    pub fn clear_read_timeout(&mut self) {
+        self.r.clear_read_timeout();
+    }
+
+    /// Return the number of bytes that can be read from this stream
+    /// right away, without waiting for the network.
+    ///
+    /// See [`DataReader::poll_read_buffered`].
+    pub fn poll_read_buffered(&self) -> usize {
+        self.r.poll_read_buffered()
+    }
+
     /// Wait until a CONNECTED cell is received, or some other cell
     /// is received to indicate an error.
     ///
@@ -319,7 +442,11 @@ impl DataWriter {
             DataWriterState::Ready(imp) => {
                 if imp.n_pending == 0 {
                     // Nothing to flush!
-                    self.state = Some(DataWriterState::Ready(imp));
+                    self.state = Some(if should_close {
+                        DataWriterState::Closed
+                    } else {
+                        DataWriterState::Ready(imp)
+                    });
                     return Poll::Ready(Ok(()));
                 }
 
@@ -468,6 +595,9 @@ impl DataWriterImpl {
 enum DataReaderState {
     /// In this state we have received an end cell or an error.
     Closed,
+    /// In this state the other side has closed the stream cleanly, and
+    /// every further read reports end-of-file.
+    Finished,
     /// In this state the reader is not currently fetching a cell; it
     /// either has data or not.
     Ready(DataReaderImpl),
@@ -480,6 +610,7 @@ impl std::fmt::Debug for DataReaderState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Closed => write!(f, "Closed"),
+            Self::Finished => write!(f, "Finished"),
             Self::Ready(_) => write!(f, "Ready"),
             Self::ReadingCell(_) => write!(f, "ReadingCell"),
         }
@@ -504,25 +635,95 @@ struct DataReaderImpl {
     connected: bool,
 }
 
-impl AsyncRead for DataReader {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut [u8],
-    ) -> Poll<IoResult<usize>> {
+impl DataReader {
+    /// Read some bytes into `buf`, giving up if `deadline` completes first.
+    ///
+    /// On timeout, this returns an error of kind
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut), and leaves the reader
+    /// usable: any data that arrives later is returned by the next read.
+    ///
+    /// The `deadline` can be any future; typically it will come from a
+    /// runtime's `sleep` or `sleep_until_wallclock` function.  If data is
+    /// available when the deadline completes, the data is returned.
+    pub async fn read_with_deadline<D>(&mut self, buf: &mut [u8], deadline: D) -> IoResult<usize>
+    where
+        D: Future<Output = ()>,
+    {
+        futures::pin_mut!(deadline);
+        match futures::future::select(self.read(buf), deadline).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((), _)) => Err(read_timed_out()),
+        }
+    }
+
+    /// Make every read on this reader fail if it waits longer than
+    /// `timeout` for data.
+    ///
+    /// The timer starts when a read first has to wait for data, and is
+    /// reset whenever a read completes.  A read that times out fails with
+    /// an error of kind [`TimedOut`](std::io::ErrorKind::TimedOut), and
+    /// leaves the reader usable, as with [`DataReader::read_with_deadline`].
+    ///
+    /// Since this crate doesn't know about any particular runtime, the
+    /// caller must provide `sleep`, a function that returns a future
+    /// that completes after a given duration.
+    pub fn set_read_timeout<F, S>(&mut self, timeout: Duration, sleep: F)
+    where
+        F: Fn(Duration) -> S + Send + 'static,
+        S: Future<Output = ()> + Send + 'static,
+    {
+        self.timeout = Some(ReadTimeout {
+            duration: timeout,
+            sleep: Box::new(move |d| Box::pin(sleep(d))),
+            timer: None,
+        });
+    }
+
+    /// Remove any read timeout set with [`DataReader::set_read_timeout`].
+    pub fn clear_read_timeout(&mut self) {
+        self.timeout = None;
+    }
+
+    /// Return the number of bytes that can be read from this reader
+    /// right away, without waiting for the network.
+    ///
+    /// This never blocks, and never reads from the network: it only
+    /// reports data that has already arrived and not yet been read.
+    pub fn poll_read_buffered(&self) -> usize {
+        match &self.state {
+            Some(DataReaderState::Ready(imp)) => imp.pending.len() - imp.offset,
+            _ => 0,
+        }
+    }
+
+    /// Try to read some bytes into `buf`, ignoring any read timeout.
+    fn poll_read_inner(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        match self.poll_fill_inner(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(match &mut self.state {
+                Some(DataReaderState::Ready(imp)) => imp.extract_bytes(buf),
+                _ => 0,
+            })),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Wait until there is buffered data to read, ignoring any read timeout.
+    ///
+    /// On success, either the buffered data is in our `Ready` state, or the
+    /// stream has ended cleanly and we are now `Finished`.
+    fn poll_fill_inner(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
         // We're pulling the state object out of the reader.  We MUST
         // put it back before this function returns.
         let mut state = self.state.take().expect("Missing state in DataReader");
 
         loop {
             let mut future = match state {
-                DataReaderState::Ready(mut imp) => {
+                DataReaderState::Ready(imp) => {
                     // There may be data to read already.
-                    let n_copied = imp.extract_bytes(buf);
-                    if n_copied != 0 {
-                        // We read data into the buffer.  Tell the caller.
+                    if !imp.buf_is_empty() {
                         self.state = Some(DataReaderState::Ready(imp));
-                        return Poll::Ready(Ok(n_copied));
+                        return Poll::Ready(Ok(()));
                     }
 
                     // No data available!  We have to launch a read.
@@ -533,6 +734,13 @@ impl AsyncRead for DataReader {
                     self.state = Some(DataReaderState::Closed);
                     return Poll::Ready(Err(Error::NotConnected.into()));
                 }
+                DataReaderState::Finished => {
+                    // Callers such as `AsyncBufReadExt::fill_buf` may ask
+                    // again after we've told them about the end of the
+                    // stream, so we have to keep telling them.
+                    self.state = Some(DataReaderState::Finished);
+                    return Poll::Ready(Ok(()));
+                }
             };
 
             // We have a future that represents an in-progress read.
@@ -541,10 +749,11 @@ impl AsyncRead for DataReader {
                 Poll::Ready((_imp, Err(e))) => {
                     // There aren't any survivable errors in the current
                     // design.
-                    self.state = Some(DataReaderState::Closed);
                     let result = if matches!(e, Error::EndReceived(EndReason::DONE)) {
-                        Ok(0)
+                        self.state = Some(DataReaderState::Finished);
+                        Ok(())
                     } else {
+                        self.state = Some(DataReaderState::Closed);
                         Err(e.into())
                     };
                     return Poll::Ready(result);
@@ -562,6 +771,62 @@ impl AsyncRead for DataReader {
             }
         }
     }
+
+    /// Apply our read timeout, if any, to `result`, the outcome of an
+    /// attempt to read.
+    fn apply_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        result: Poll<IoResult<T>>,
+    ) -> Poll<IoResult<T>> {
+        if let Some(timeout) = self.timeout.as_mut() {
+            match result {
+                Poll::Pending => {
+                    if timeout.poll_expired(cx) {
+                        // The state is untouched: any cell we were waiting
+                        // for will be read next time.
+                        return Poll::Ready(Err(read_timed_out()));
+                    }
+                }
+                Poll::Ready(_) => timeout.timer = None,
+            }
+        }
+        result
+    }
+}
+
+impl AsyncRead for DataReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let result = self.poll_read_inner(cx, buf);
+        self.apply_timeout(cx, result)
+    }
+}
+
+/// Reading a `DataReader` as an [`AsyncBufRead`] gives the caller each
+/// cell's payload directly, without copying it into another buffer first.
+impl AsyncBufRead for DataReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<&[u8]>> {
+        let this = self.get_mut();
+        let result = this.poll_fill_inner(cx);
+        match this.apply_timeout(cx, result) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(match &this.state {
+                Some(DataReaderState::Ready(imp)) => &imp.pending[imp.offset..],
+                _ => &[],
+            })),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        if let Some(DataReaderState::Ready(imp)) = &mut self.state {
+            imp.offset = std::cmp::min(imp.offset + amt, imp.pending.len());
+        }
+    }
 }
 
 #[cfg(feature = "tokio")]
@@ -9,6 +9,9 @@ pub struct StreamParameters {
     ip_version: IpVersionPreference,
     /// True if we are requesting an optimistic stream.
     optimistic: bool,
+    /// How eagerly the circuit should send this stream's data, compared
+    /// to other streams on the same circuit.
+    priority: u8,
 }
 
 impl StreamParameters {
@@ -46,11 +49,33 @@ impl StreamParameters {
         self
     }
 
+    /// Configure this stream's scheduling priority.
+    ///
+    /// When several streams on the same circuit have data to send, the
+    /// circuit sends data from streams with a higher priority first, and
+    /// more of it: a stream with priority `p` sends up to `p + 1` cells for
+    /// every cell from a priority-0 stream, up to a limit of 16.  Every
+    /// stream still gets a turn, so a busy high-priority stream can slow
+    /// down lower-priority ones, but not starve them.  This is useful for
+    /// (say) keeping an interactive SSH session responsive while a bulk
+    /// download shares its circuit.
+    ///
+    /// The default priority is 0, the lowest.
+    pub fn priority(&mut self, priority: u8) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
     /// Crate-internal: Return true if the stream is optimistic.
     pub(crate) fn is_optimistic(&self) -> bool {
         self.optimistic
     }
 
+    /// Crate-internal: Return this stream's scheduling priority.
+    pub(crate) fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
     /// Crate-internal: Get a set of [`BeginFlags`] for this stream.
     pub(crate) fn begin_flags(&self) -> BeginFlags {
         self.ip_version.into()
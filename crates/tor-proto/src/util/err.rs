@@ -1,6 +1,7 @@
 //! Define an error type for the tor-proto crate.
 use std::sync::Arc;
 use thiserror::Error;
+use tor_cell::chancell::{ChanCmd, CircId};
 use tor_cell::relaycell::msg::EndReason;
 use tor_error::{ErrorKind, HasKind};
 
@@ -42,10 +43,27 @@ pub enum Error {
     /// Handshake protocol violation.
     #[error("handshake protocol violation: {0}")]
     HandshakeProto(String),
+    /// A certificate that the peer sent during the channel handshake
+    /// couldn't be verified.
+    #[error("invalid {0} certificate in handshake: {1}")]
+    HandshakeCertErr(tor_cert::CertType, #[source] tor_cert::CertError),
+    /// The relay stopped sending during a phase of the channel handshake.
+    #[error("channel handshake timed out waiting for {0}")]
+    HandshakePhaseTimeout(crate::channel::HandshakePhase),
     /// Protocol violation at the channel level, other than at the handshake
     /// stage.
     #[error("channel protocol violation: {0}")]
     ChanProto(String),
+    /// We received a CREATE-type cell on a channel that we initiated.
+    ///
+    /// Only the other side of a channel may ask to create circuits on it,
+    /// and we never accept channels, so this is always a protocol violation.
+    #[error("channel protocol violation: {0} cell on client channel")]
+    CreateOnClientChannel(ChanCmd),
+    /// We received a cell for a circuit ID in the half of the ID space that
+    /// we never allocate from.
+    #[error("channel protocol violation: cell for circuit {0}, which is not in our range of circuit IDs")]
+    WrongCircIdRange(CircId),
     /// Protocol violation at the circuit level
     #[error("circuit protocol violation: {0}")]
     CircProto(String),
@@ -58,6 +76,10 @@ pub enum Error {
     /// Can't allocate any more circuit or stream IDs on a channel.
     #[error("too many entries in map: can't allocate ID")]
     IdRangeFull,
+    /// Tried to open a stream on a circuit hop that has stopped accepting
+    /// new streams, so that it can close once its existing ones are done.
+    #[error("not accepting new streams: waiting for existing ones to close")]
+    Draining,
     /// Couldn't extend a circuit because the extending relay or the
     /// target relay refused our request.
     #[error("circuit extension handshake error: {0}")]
@@ -84,6 +106,16 @@ pub enum Error {
     /// Remote DNS lookup failed.
     #[error("remote resolve failed: {0}")]
     ResolveError(String),
+    /// We can't extend this circuit any further: we have already sent as
+    /// many RELAY_EARLY cells on it as its budget allows.
+    ///
+    /// See [`ClientCirc::relay_early_left`](crate::circuit::ClientCirc::relay_early_left).
+    #[error("no RELAY_EARLY cells left on this circuit")]
+    NoEarlyCellsLeft,
+    /// Remote DNS lookup failed, but the exit told us that the failure
+    /// might be temporary.
+    #[error("remote resolve failed transiently: {0}")]
+    ResolveTransient(String),
 }
 
 impl From<tor_cell::Error> for Error {
@@ -113,14 +145,25 @@ impl From<Error> for std::io::Error {
 
             ChannelClosed | CircuitClosed => ErrorKind::ConnectionReset,
 
-            BytesErr(_) | BadCellAuth | BadCircHandshake | HandshakeProto(_) | ChanProto(_)
-            | CircProto(_) | CellErr(_) | ChanMismatch(_) | StreamProto(_) => {
-                ErrorKind::InvalidData
-            }
+            BytesErr(_)
+            | BadCellAuth
+            | BadCircHandshake
+            | HandshakeProto(_)
+            | HandshakeCertErr(..)
+            | ChanProto(_)
+            | CreateOnClientChannel(_)
+            | WrongCircIdRange(_)
+            | CircProto(_)
+            | CellErr(_)
+            | ChanMismatch(_)
+            | StreamProto(_) => ErrorKind::InvalidData,
 
             Bug(ref e) if e.kind() == tor_error::ErrorKind::BadApiUsage => ErrorKind::InvalidData,
 
-            IdRangeFull | CircRefused(_) | ResolveError(_) | Bug(_) => ErrorKind::Other,
+            HandshakePhaseTimeout(_) => ErrorKind::TimedOut,
+
+            IdRangeFull | Draining | NoEarlyCellsLeft | CircRefused(_) | ResolveError(_)
+            | ResolveTransient(_) | Bug(_) => ErrorKind::Other,
         };
         std::io::Error::new(kind, err)
     }
@@ -142,10 +185,23 @@ impl HasKind for Error {
             E::BadCellAuth => EK::TorProtocolViolation,
             E::BadCircHandshake => EK::TorProtocolViolation,
             E::HandshakeProto(_) => EK::TorAccessFailed,
+            // A certificate that doesn't verify, or that certifies the wrong
+            // kind of key, is reported like our other handshake failures.  One
+            // that we can't decode, or whose signing key we can't find, is a
+            // protocol violation, like any other undecodable object.
+            E::HandshakeCertErr(
+                _,
+                tor_cert::CertError::BadSignature | tor_cert::CertError::WrongSubjectKeyType(_),
+            ) => EK::TorAccessFailed,
+            E::HandshakeCertErr(..) => EK::TorProtocolViolation,
+            E::HandshakePhaseTimeout(_) => EK::TorNetworkTimeout,
             E::ChanProto(_) => EK::TorProtocolViolation,
+            E::CreateOnClientChannel(_) | E::WrongCircIdRange(_) => EK::TorProtocolViolation,
             E::CircProto(_) => EK::TorProtocolViolation,
             E::ChannelClosed | E::CircuitClosed => EK::CircuitCollapse,
             E::IdRangeFull => EK::BadApiUsage,
+            E::Draining => EK::TransientFailure,
+            E::NoEarlyCellsLeft => EK::BadApiUsage,
             E::CircRefused(_) => EK::CircuitRefused,
             E::BadStreamAddress => EK::BadApiUsage,
             E::EndReceived(reason) => reason.kind(),
@@ -153,6 +209,7 @@ impl HasKind for Error {
             E::StreamProto(_) => EK::TorProtocolViolation,
             E::ChanMismatch(_) => EK::RelayIdMismatch,
             E::ResolveError(_) => EK::RemoteHostNotFound,
+            E::ResolveTransient(_) => EK::TransientFailure,
             E::Bug(e) => e.kind(),
         }
     }
@@ -0,0 +1,104 @@
+//! Test helpers to pick apart a TLS ClientHello.
+//!
+//! We use these to check that the handshakes our TLS providers send don't
+//! reveal anything that Tor clients aren't supposed to reveal.
+
+/// The extension type for Server Name Indication.
+pub(crate) const EXT_SERVER_NAME: u16 = 0;
+/// The extension type for Application-Layer Protocol Negotiation.
+pub(crate) const EXT_ALPN: u16 = 16;
+
+/// The parts of a ClientHello that our tests look at.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientHello {
+    /// The offered ciphersuites, in order.
+    pub(crate) suites: Vec<u16>,
+    /// The type of each extension, in order.
+    pub(crate) extensions: Vec<u16>,
+}
+
+/// A minimal cursor over a byte slice.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    /// Remove and return the next `n` bytes, if there are that many.
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (first, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(first)
+    }
+    /// Remove and return a single byte.
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    /// Remove and return a big-endian u16.
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+    /// Remove and return a big-endian u24.
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (usize::from(b[0]) << 16) | (usize::from(b[1]) << 8) | usize::from(b[2]))
+    }
+    /// Remove and return a byte string with a one-byte length prefix.
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n.into())
+    }
+    /// Remove and return a byte string with a two-byte length prefix.
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n.into())
+    }
+}
+
+impl ClientHello {
+    /// Parse a ClientHello from `tls`, which should start with the first TLS
+    /// record that a client sent.
+    ///
+    /// Return None if that record isn't a complete ClientHello.
+    pub(crate) fn from_record(tls: &[u8]) -> Option<Self> {
+        let mut rec = Cursor(tls);
+        if rec.u8()? != 22 {
+            // Not a handshake record.
+            return None;
+        }
+        let _version = rec.u16()?;
+        let mut hs = Cursor(rec.vec16()?);
+        if hs.u8()? != 1 {
+            // Not a ClientHello.
+            return None;
+        }
+        let len = hs.u24()?;
+        let mut body = Cursor(hs.take(len)?);
+
+        let _version = body.u16()?;
+        let _random = body.take(32)?;
+        let _session_id = body.vec8()?;
+        let suites = body
+            .vec16()?
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or(0)]))
+            .collect();
+        let _compression = body.vec8()?;
+
+        let mut extensions = Vec::new();
+        if !body.0.is_empty() {
+            let mut exts = Cursor(body.vec16()?);
+            while !exts.0.is_empty() {
+                extensions.push(exts.u16()?);
+                let _data = exts.vec16()?;
+            }
+        }
+
+        Some(ClientHello { suites, extensions })
+    }
+
+    /// Return true if this ClientHello has an extension of type `ext`.
+    pub(crate) fn has_extension(&self, ext: u16) -> bool {
+        self.extensions.contains(&ext)
+    }
+}
@@ -12,8 +12,9 @@ use std::io::Result as IoResult;
 ///
 /// The `SpawnR` component should implements [`Spawn`] and [`BlockOn`];
 /// the `SleepR` component should implement [`SleepProvider`]; the `TcpR`
-/// component should implement [`TcpProvider`]; and the `TlsR` component should
-/// implement [`TlsProvider`].
+/// component should implement [`TcpProvider`] (and, optionally,
+/// [`UdpProvider`] and `UnixProvider`); and the `TlsR` component should implement
+/// [`TlsProvider`].
 ///
 /// You can use this structure to create new runtimes in two ways: either by
 /// overriding a single part of an existing runtime, or by building an entirely
@@ -118,6 +119,43 @@ where
     }
 }
 
+#[async_trait]
+impl<SpawnR, SleepR, TcpR, TlsR> UdpProvider for CompoundRuntime<SpawnR, SleepR, TcpR, TlsR>
+where
+    TcpR: UdpProvider,
+    SpawnR: Send + Sync + 'static,
+    SleepR: Send + Sync + 'static,
+    TcpR: Send + Sync + 'static,
+    TlsR: Send + Sync + 'static,
+{
+    type UdpSocket = TcpR::UdpSocket;
+
+    #[inline]
+    async fn bind(&self, addr: &SocketAddr) -> IoResult<Self::UdpSocket> {
+        self.inner.tcp.bind(addr).await
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl<SpawnR, SleepR, TcpR, TlsR> UnixProvider for CompoundRuntime<SpawnR, SleepR, TcpR, TlsR>
+where
+    TcpR: UnixProvider,
+    SpawnR: Send + Sync + 'static,
+    SleepR: Send + Sync + 'static,
+    TcpR: Send + Sync + 'static,
+    TlsR: Send + Sync + 'static,
+{
+    type UnixStream = TcpR::UnixStream;
+
+    type UnixListener = TcpR::UnixListener;
+
+    #[inline]
+    async fn listen_unix(&self, path: &std::path::Path) -> IoResult<Self::UnixListener> {
+        self.inner.tcp.listen_unix(path).await
+    }
+}
+
 impl<SpawnR, SleepR, TcpR, TlsR, S> TlsProvider<S> for CompoundRuntime<SpawnR, SleepR, TcpR, TlsR>
 where
     TcpR: TcpProvider,
@@ -130,6 +168,11 @@ where
     fn tls_connector(&self) -> Self::Connector {
         self.inner.tls.tls_connector()
     }
+
+    #[inline]
+    fn fingerprint_warning(&self) -> Option<&'static str> {
+        self.inner.tls.fingerprint_warning()
+    }
 }
 
 impl<SpawnR, SleepR, TcpR, TlsR> std::fmt::Debug for CompoundRuntime<SpawnR, SleepR, TcpR, TlsR> {
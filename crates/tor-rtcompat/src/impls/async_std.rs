@@ -9,7 +9,7 @@
 mod net {
     use crate::traits;
 
-    use async_std_crate::net::{TcpListener, TcpStream};
+    use async_std_crate::net::{TcpListener, TcpStream, UdpSocket};
     use async_trait::async_trait;
     use futures::future::Future;
     use futures::stream::Stream;
@@ -109,6 +109,67 @@ mod net {
             TcpListener::bind(*addr).await
         }
     }
+
+    #[async_trait]
+    impl traits::UdpSocket for UdpSocket {
+        async fn recv(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+            UdpSocket::recv_from(self, buf).await
+        }
+        async fn send(&self, buf: &[u8], target: &SocketAddr) -> IoResult<usize> {
+            UdpSocket::send_to(self, buf, target).await
+        }
+        fn local_addr(&self) -> IoResult<SocketAddr> {
+            UdpSocket::local_addr(self)
+        }
+    }
+
+    #[async_trait]
+    impl traits::UdpProvider for async_executors::AsyncStd {
+        type UdpSocket = UdpSocket;
+        async fn bind(&self, addr: &SocketAddr) -> IoResult<Self::UdpSocket> {
+            UdpSocket::bind(*addr).await
+        }
+    }
+
+    /// Types used for Unix domain sockets (async_std implementation)
+    #[cfg(unix)]
+    mod unix {
+        use crate::traits;
+
+        use async_std_crate::os::unix::net::{UnixListener, UnixStream};
+        use async_trait::async_trait;
+        use futures::stream::{BoxStream, StreamExt};
+        use std::io::Result as IoResult;
+        use std::path::Path;
+
+        #[async_trait]
+        impl traits::UnixListener for UnixListener {
+            type UnixStream = UnixStream;
+            type Incoming = BoxStream<'static, IoResult<UnixStream>>;
+            async fn accept(&self) -> IoResult<Self::UnixStream> {
+                let (stream, _) = UnixListener::accept(self).await?;
+                Ok(stream)
+            }
+            fn incoming(self) -> Self::Incoming {
+                // async_std's own Incoming borrows the listener, so we
+                // make an owning stream instead.
+                futures::stream::unfold(self, |lis| async move {
+                    let next = UnixListener::accept(&lis).await.map(|(s, _)| s);
+                    Some((next, lis))
+                })
+                .boxed()
+            }
+        }
+
+        #[async_trait]
+        impl traits::UnixProvider for async_executors::AsyncStd {
+            type UnixStream = UnixStream;
+            type UnixListener = UnixListener;
+            async fn listen_unix(&self, path: &Path) -> IoResult<Self::UnixListener> {
+                UnixListener::bind(path).await
+            }
+        }
+    }
 }
 
 // ==============================
@@ -77,6 +77,9 @@ where
         builder
             .danger_accept_invalid_certs(true)
             .danger_accept_invalid_hostnames(true);
+        // Tor clients don't send SNI or ALPN.  (We never call
+        // `request_alpns`, so there's nothing to turn off for ALPN.)
+        builder.use_sni(false);
 
         let connector = builder.try_into().expect("Couldn't build a TLS connector!");
 
@@ -85,6 +88,25 @@ where
             _phantom: std::marker::PhantomData,
         }
     }
+
+    fn fingerprint_warning(&self) -> Option<&'static str> {
+        // native_tls doesn't let us choose our ciphersuites or extensions.
+        // Where it's backed by OpenSSL, we get OpenSSL's defaults, like C
+        // Tor does.  Elsewhere, the platform's TLS library makes us look
+        // like a different program entirely.
+        if cfg!(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "windows"
+        )) {
+            Some(
+                "native-tls uses the platform TLS library here, not OpenSSL, so our \
+                 TLS handshakes won't look like those of other Tor clients.",
+            )
+        } else {
+            None
+        }
+    }
 }
 
 impl NativeTlsProvider {
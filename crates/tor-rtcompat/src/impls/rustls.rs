@@ -12,6 +12,31 @@ use std::{
     sync::Arc,
 };
 
+/// Return the ciphersuites that we offer, in the order that we offer them.
+///
+/// This is the subset of OpenSSL 1.1.1's default client list that rustls
+/// supports, in OpenSSL's order, so that we look as much like C Tor as we
+/// can.
+///
+/// To update this list: capture a ClientHello from a current C Tor client
+/// (for example, with `tshark -Y tls.handshake.type==1 -V`), pick out the
+/// suites that rustls supports, and list them here in the same order.  Then
+/// update `CLIENT_HELLO_SUITES` in the tests below to match.
+fn ciphersuite_profile() -> Vec<&'static rustls::SupportedCipherSuite> {
+    use rustls::ciphersuite::*;
+    vec![
+        &TLS13_AES_256_GCM_SHA384,
+        &TLS13_CHACHA20_POLY1305_SHA256,
+        &TLS13_AES_128_GCM_SHA256,
+        &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ]
+}
+
 /// A [`TlsProvider`] that uses `rustls`.
 ///
 /// It supports wrapping any reasonable stream type that implements `AsyncRead` + `AsyncWrite`.
@@ -66,6 +91,15 @@ where
             _phantom: std::marker::PhantomData,
         }
     }
+
+    fn fingerprint_warning(&self) -> Option<&'static str> {
+        // We can pin our ciphersuites (see `ciphersuite_profile`), but rustls
+        // decides for itself which extensions to send, and in what order.
+        Some(
+            "rustls doesn't let us match the TLS extensions that OpenSSL-based \
+             Tor clients send, so our TLS handshakes may be distinguishable from theirs.",
+        )
+    }
 }
 
 impl RustlsProvider {
@@ -85,6 +119,12 @@ impl RustlsProvider {
             .dangerous()
             .set_certificate_verifier(std::sync::Arc::new(Verifier {}));
 
+        // Make our ClientHello look as much like C Tor's as we can: no SNI,
+        // no ALPN, and the same ciphersuites in the same order.
+        config.enable_sni = false;
+        config.alpn_protocols = Vec::new();
+        config.ciphersuites = ciphersuite_profile();
+
         RustlsProvider {
             config: Arc::new(config),
         }
@@ -240,6 +280,7 @@ fn convert_scheme(
 
 #[cfg(test)]
 mod test {
+    #![allow(clippy::unwrap_used)]
     use super::*;
 
     #[test]
@@ -266,4 +307,39 @@ mod test {
         assert!(convert_scheme(R::RSA_PKCS1_SHA1).is_err());
         assert!(convert_scheme(R::Unknown(0x1337)).is_err());
     }
+
+    /// The ciphersuites that we expect in our ClientHello, in order.
+    ///
+    /// These are the IANA values for the suites in `ciphersuite_profile`,
+    /// followed by the renegotiation SCSV that rustls (like OpenSSL) always
+    /// appends.
+    const CLIENT_HELLO_SUITES: &[u16] = &[
+        0x1302, // TLS13_AES_256_GCM_SHA384
+        0x1303, // TLS13_CHACHA20_POLY1305_SHA256
+        0x1301, // TLS13_AES_128_GCM_SHA256
+        0xc02c, // TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+        0xc030, // TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+        0xcca9, // TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+        0xcca8, // TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+        0xc02b, // TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+        0xc02f, // TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+        0x00ff, // TLS_EMPTY_RENEGOTIATION_INFO_SCSV
+    ];
+
+    #[test]
+    fn client_hello_profile() {
+        use crate::client_hello::{ClientHello, EXT_ALPN, EXT_SERVER_NAME};
+
+        // Have rustls write its ClientHello into a buffer.
+        let provider = RustlsProvider::new();
+        let name = get_dns_name("www.example.com").unwrap();
+        let mut session = rustls::ClientSession::new(&provider.config, name);
+        let mut record = Vec::new();
+        session.write_tls(&mut record).unwrap();
+
+        let hello = ClientHello::from_record(&record[..]).unwrap();
+        assert_eq!(&hello.suites[..], CLIENT_HELLO_SUITES);
+        assert!(!hello.has_extension(EXT_SERVER_NAME));
+        assert!(!hello.has_extension(EXT_ALPN));
+    }
 }
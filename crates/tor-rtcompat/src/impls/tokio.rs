@@ -9,7 +9,11 @@ pub(crate) mod net {
     use async_trait::async_trait;
 
     pub(crate) use tokio_crate::net::{
-        TcpListener as TokioTcpListener, TcpStream as TokioTcpStream,
+        TcpListener as TokioTcpListener, TcpStream as TokioTcpStream, UdpSocket as TokioUdpSocket,
+    };
+    #[cfg(unix)]
+    pub(crate) use tokio_crate::net::{
+        UnixListener as TokioUnixListener, UnixStream as TokioUnixStream,
     };
 
     use futures::io::{AsyncRead, AsyncWrite};
@@ -98,6 +102,107 @@ pub(crate) mod net {
             self.lis.local_addr()
         }
     }
+
+    /// Wrap a Tokio UdpSocket to behave as a traits::UdpSocket.
+    pub struct UdpSocket {
+        /// The underlying socket.
+        pub(super) socket: TokioUdpSocket,
+    }
+
+    #[async_trait]
+    impl traits::UdpSocket for UdpSocket {
+        async fn recv(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+            self.socket.recv_from(buf).await
+        }
+        async fn send(&self, buf: &[u8], target: &SocketAddr) -> IoResult<usize> {
+            self.socket.send_to(buf, target).await
+        }
+        fn local_addr(&self) -> IoResult<SocketAddr> {
+            self.socket.local_addr()
+        }
+    }
+
+    /// Wrapper for Tokio's UnixStream that implements the standard
+    /// AsyncRead and AsyncWrite.
+    #[cfg(unix)]
+    pub struct UnixStream {
+        /// Underlying tokio_util::compat::Compat wrapper.
+        s: Compat<TokioUnixStream>,
+    }
+    #[cfg(unix)]
+    impl From<TokioUnixStream> for UnixStream {
+        fn from(s: TokioUnixStream) -> UnixStream {
+            let s = s.compat();
+            UnixStream { s }
+        }
+    }
+    #[cfg(unix)]
+    impl AsyncRead for UnixStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            Pin::new(&mut self.s).poll_read(cx, buf)
+        }
+    }
+    #[cfg(unix)]
+    impl AsyncWrite for UnixStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            Pin::new(&mut self.s).poll_write(cx, buf)
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.s).poll_flush(cx)
+        }
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.s).poll_close(cx)
+        }
+    }
+
+    /// Wrap a Tokio UnixListener to behave as a traits::UnixListener.
+    #[cfg(unix)]
+    pub struct UnixListener {
+        /// The underlying listener.
+        pub(super) lis: TokioUnixListener,
+    }
+
+    /// Asynchronous stream that yields incoming connections from a
+    /// UnixListener.
+    #[cfg(unix)]
+    pub struct IncomingUnixStreams {
+        /// Reference to the underlying listener.
+        pub(super) lis: TokioUnixListener,
+    }
+
+    #[cfg(unix)]
+    impl futures::stream::Stream for IncomingUnixStreams {
+        type Item = IoResult<UnixStream>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.lis.poll_accept(cx) {
+                Poll::Ready(Ok((s, _))) => Poll::Ready(Some(Ok(s.into()))),
+                Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+    #[cfg(unix)]
+    #[async_trait]
+    impl traits::UnixListener for UnixListener {
+        type UnixStream = UnixStream;
+        type Incoming = IncomingUnixStreams;
+        async fn accept(&self) -> IoResult<Self::UnixStream> {
+            let (stream, _) = self.lis.accept().await?;
+            Ok(stream.into())
+        }
+        fn incoming(self) -> Self::Incoming {
+            IncomingUnixStreams { lis: self.lis }
+        }
+    }
 }
 
 // ==============================
@@ -130,6 +235,28 @@ impl crate::traits::TcpProvider for TokioRuntimeHandle {
     }
 }
 
+#[async_trait]
+impl crate::traits::UdpProvider for TokioRuntimeHandle {
+    type UdpSocket = net::UdpSocket;
+
+    async fn bind(&self, addr: &std::net::SocketAddr) -> IoResult<Self::UdpSocket> {
+        let socket = net::TokioUdpSocket::bind(addr).await?;
+        Ok(net::UdpSocket { socket })
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl crate::traits::UnixProvider for TokioRuntimeHandle {
+    type UnixStream = net::UnixStream;
+    type UnixListener = net::UnixListener;
+
+    async fn listen_unix(&self, path: &std::path::Path) -> IoResult<Self::UnixListener> {
+        let lis = net::TokioUnixListener::bind(path)?;
+        Ok(net::UnixListener { lis })
+    }
+}
+
 /// Create and return a new Tokio multithreaded runtime.
 pub(crate) fn create_runtime() -> IoResult<TokioRuntimeHandle> {
     let mut builder = async_executors::TokioTpBuilder::new();
@@ -46,9 +46,13 @@
 //!  * A runtime is a [`TcpProvider`] if it can make and receive TCP
 //!    connections
 //!  * A runtime is a [`TlsProvider`] if it can make TLS connections.
+//!  * A runtime is a [`UdpProvider`] if it can send and receive UDP
+//!    datagrams.
+//!  * On Unix-like platforms, a runtime is a `UnixProvider` if it can
+//!    accept connections on Unix domain sockets.
 //!
 //! For convenience, the [`Runtime`] trait derives from all the traits
-//! above, plus [`futures::task::Spawn`] and [`Send`].
+//! above except [`UdpProvider`] and `UnixProvider`, plus [`futures::task::Spawn`] and [`Send`].
 //!
 //! You can get a [`Runtime`] in several ways:
 //!
@@ -173,6 +177,13 @@
 pub(crate) mod impls;
 pub mod task;
 
+#[cfg(all(
+    test,
+    any(feature = "native-tls", feature = "rustls"),
+    any(feature = "async-std", feature = "tokio")
+))]
+mod client_hello;
+
 mod compound;
 mod opaque;
 mod timer;
@@ -181,7 +192,10 @@ mod traits;
 use std::io;
 pub use traits::{
     BlockOn, CertifiedConn, Runtime, SleepProvider, TcpListener, TcpProvider, TlsProvider,
+    UdpProvider, UdpSocket,
 };
+#[cfg(unix)]
+pub use traits::{UnixListener, UnixProvider};
 
 pub use timer::{SleepProviderExt, Timeout, TimeoutError};
 
@@ -561,6 +575,34 @@ mod test {
         })
     }
 
+    // Send a datagram from one UDP socket to another, and back.
+    //
+    // NOTE: requires Ipv4 localhost.
+    fn udp_echo<R: Runtime + UdpProvider>(runtime: &R) -> IoResult<()> {
+        let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+        let rt1 = runtime.clone();
+
+        runtime.block_on(async {
+            let sock1 = rt1.bind(&(localhost.into())).await?;
+            let sock2 = rt1.bind(&(localhost.into())).await?;
+            let addr1 = sock1.local_addr()?;
+            let addr2 = sock2.local_addr()?;
+
+            let mut buf = [0_u8; 64];
+            assert_eq!(sock1.send(b"Hello world", &addr2).await?, 11);
+            let (n, from) = sock2.recv(&mut buf[..]).await?;
+            assert_eq!(&buf[..n], b"Hello world");
+            assert_eq!(from, addr1);
+
+            sock2.send(b"Goodbye", &from).await?;
+            let (n, from) = sock1.recv(&mut buf[..]).await?;
+            assert_eq!(&buf[..n], b"Goodbye");
+            assert_eq!(from, addr2);
+
+            Ok(())
+        })
+    }
+
     // Try out our incoming connection stream code.
     //
     // We launch a few connections and make sure that we can read data on
@@ -624,6 +666,10 @@ mod test {
          The password is "abc".
         */
         static PFX_ID: &[u8] = include_bytes!("test.pfx");
+        // The certificate from test.pfx, in DER form.  Generated with
+        //
+        // openssl pkcs12 -in test.pfx -nokeys -clcerts -legacy | openssl x509 -outform DER -out test.der
+        static CERT_DER: &[u8] = include_bytes!("test.der");
         // Note that we need to set a password on the pkcs12 file, since apparently
         // OSX doesn't support pkcs12 with empty passwords. (That was arti#111).
         static PFX_PASSWORD: &str = "abc";
@@ -659,7 +705,7 @@ mod test {
             let mut buf = vec![0_u8; text.len()];
             let conn = runtime.connect(&addr).await?;
             let mut conn = connector.negotiate_unvalidated(conn, "Kan.Aya").await?;
-            assert!(conn.peer_certificate()?.is_some());
+            assert_eq!(conn.peer_certificate()?.as_deref(), Some(CERT_DER));
             conn.write_all(text).await?;
             conn.flush().await?;
             conn.read_exact(&mut buf[..]).await?;
@@ -672,6 +718,46 @@ mod test {
         IoResult::Ok(())
     }
 
+    // Make sure that our TLS connectors don't send SNI or ALPN.
+    //
+    // We listen with a plain TCP socket, read the first record that the
+    // client sends, and then hang up.
+    fn client_hello_profile<R: Runtime>(runtime: &R) -> IoResult<()> {
+        use crate::client_hello::{ClientHello, EXT_ALPN, EXT_SERVER_NAME};
+
+        let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+        let listener = std::net::TcpListener::bind(localhost)?;
+        let addr = listener.local_addr()?;
+
+        let th = std::thread::spawn(move || {
+            use std::io::Read;
+            let (mut con, _addr) = listener.accept()?;
+            let mut header = [0_u8; 5];
+            con.read_exact(&mut header)?;
+            let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+            let mut record = header.to_vec();
+            record.resize(5 + len, 0);
+            con.read_exact(&mut record[5..])?;
+            IoResult::Ok(record)
+        });
+
+        let connector = runtime.tls_connector();
+        runtime.block_on(async {
+            let conn = runtime.connect(&addr).await?;
+            // This will fail once the listener hangs up; we only care about
+            // what we sent.
+            let _ = connector.negotiate_unvalidated(conn, "Kan.Aya").await;
+            IoResult::Ok(())
+        })?;
+
+        let record = th.join().unwrap()?;
+        let hello = ClientHello::from_record(&record[..]).unwrap();
+        assert!(!hello.suites.is_empty());
+        assert!(!hello.has_extension(EXT_SERVER_NAME));
+        assert!(!hello.has_extension(EXT_ALPN));
+        IoResult::Ok(())
+    }
+
     macro_rules! tests_with_runtime {
         { $runtime:expr  => $($id:ident),* $(,)? } => {
             $(
@@ -732,9 +818,64 @@ mod test {
         tiny_wallclock,
         self_connect,
         listener_stream,
+        udp_echo,
     }
 
     tls_runtime_tests! {
         simple_tls,
+        client_hello_profile,
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use super::*;
+        use std::path::PathBuf;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Return a fresh path for a Unix socket in the temporary directory.
+        fn fresh_socket_path() -> PathBuf {
+            static N: AtomicUsize = AtomicUsize::new(0);
+            let n = N.fetch_add(1, Ordering::SeqCst);
+            std::env::temp_dir().join(format!("rtcompat-test-{}-{}.sock", std::process::id(), n))
+        }
+
+        // Listen on a Unix socket, and make sure that we can read data
+        // from connections to it.
+        //
+        // We connect with a blocking socket from a thread, since the
+        // runtime traits don't (yet) let us make outgoing Unix connections.
+        fn unix_listener<R: Runtime + UnixProvider>(runtime: &R) -> IoResult<()> {
+            let path = fresh_socket_path();
+            let listener = runtime.block_on(runtime.listen_unix(&path))?;
+            let mut incoming = listener.incoming();
+
+            let path2 = path.clone();
+            let th = std::thread::spawn(move || {
+                use std::io::Write;
+                for _ in 0_u8..3 {
+                    let mut con = std::os::unix::net::UnixStream::connect(&path2)?;
+                    con.write_all(b"Hello world")?;
+                }
+                IoResult::Ok(())
+            });
+
+            runtime.block_on(async {
+                for _ in 0_u8..3 {
+                    let mut con = incoming.next().await.unwrap()?;
+                    let mut buf = vec![0_u8; 11];
+                    con.read_exact(&mut buf[..]).await?;
+                    assert_eq!(&buf[..], b"Hello world");
+                }
+                IoResult::Ok(())
+            })?;
+
+            th.join().unwrap()?;
+            std::fs::remove_file(&path)?;
+            Ok(())
+        }
+
+        runtime_tests! {
+            unix_listener,
+        }
     }
 }
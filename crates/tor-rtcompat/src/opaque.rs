@@ -46,6 +46,26 @@ macro_rules! implement_opaque_runtime {
         }
     }
 
+    #[async_trait::async_trait]
+    impl $crate::traits::UdpProvider for $t {
+        type UdpSocket = <$mty as $crate::traits::UdpProvider>::UdpSocket;
+        #[inline]
+        async fn bind(&self, addr: &std::net::SocketAddr) -> std::io::Result<Self::UdpSocket> {
+            self.$member.bind(addr).await
+        }
+    }
+
+    #[cfg(unix)]
+    #[async_trait::async_trait]
+    impl $crate::traits::UnixProvider for $t {
+        type UnixStream = <$mty as $crate::traits::UnixProvider>::UnixStream;
+        type UnixListener = <$mty as $crate::traits::UnixProvider>::UnixListener;
+        #[inline]
+        async fn listen_unix(&self, path: &std::path::Path) -> std::io::Result<Self::UnixListener> {
+            self.$member.listen_unix(path).await
+        }
+    }
+
     impl<S> $crate::traits::TlsProvider<S> for $t
     where S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static,
     {
@@ -55,6 +75,10 @@ macro_rules! implement_opaque_runtime {
         fn tls_connector(&self) -> Self::Connector {
             self.$member.tls_connector()
         }
+        #[inline]
+        fn fingerprint_warning(&self) -> Option<&'static str> {
+            <$mty as $crate::traits::TlsProvider<S>>::fingerprint_warning(&self.$member)
+        }
     }
 
     impl std::fmt::Debug for $t {
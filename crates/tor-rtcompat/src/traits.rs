@@ -5,6 +5,8 @@ use futures::task::Spawn;
 use futures::{AsyncRead, AsyncWrite, Future};
 use std::io::Result as IoResult;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
 use std::time::{Duration, Instant, SystemTime};
 
 /// A runtime that we can use to run Tor as a client.
@@ -180,6 +182,79 @@ pub trait TcpListener {
     fn local_addr(&self) -> IoResult<SocketAddr>;
 }
 
+/// Trait for a runtime that can send and receive UDP datagrams.
+///
+/// This is not (yet) part of [`Runtime`], since Tor itself doesn't use UDP:
+/// only a few front-ends (like a DNS port) need it.  All of the runtimes in
+/// this crate implement it.
+#[async_trait]
+pub trait UdpProvider {
+    /// The type of UDP sockets returned by [`Self::bind()`].
+    type UdpSocket: UdpSocket + Send + Sync + Unpin + 'static;
+
+    /// Bind a UDP socket to a given socket address.
+    async fn bind(&self, addr: &SocketAddr) -> IoResult<Self::UdpSocket>;
+}
+
+/// Trait for a locally bound UDP socket.
+///
+/// These objects are returned by instances of [`UdpProvider`].
+#[async_trait]
+pub trait UdpSocket {
+    /// Wait for an incoming datagram; write it into `buf`, and return its
+    /// length and the address that it came from.
+    ///
+    /// If the datagram is longer than `buf`, the excess is discarded.
+    async fn recv(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)>;
+
+    /// Send `buf` as a single datagram to `target`.
+    async fn send(&self, buf: &[u8], target: &SocketAddr) -> IoResult<usize>;
+
+    /// Return the local address that this socket is bound to.
+    fn local_addr(&self) -> IoResult<SocketAddr>;
+}
+
+/// Trait for a runtime that can accept connections on Unix domain sockets.
+///
+/// Like [`UdpProvider`], this is not part of [`Runtime`]: only front-ends
+/// (like a SOCKS port) need it.  All of the runtimes in this crate implement
+/// it on platforms that have Unix domain sockets.
+#[cfg(unix)]
+#[async_trait]
+pub trait UnixProvider {
+    /// The type for the streams returned by [`UnixListener::accept()`].
+    type UnixStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static;
+    /// The type for the listeners returned by [`Self::listen_unix()`].
+    type UnixListener: UnixListener<UnixStream = Self::UnixStream> + Send + Sync + Unpin + 'static;
+
+    /// Open a listener on a Unix domain socket at `path`.
+    ///
+    /// The socket file must not already exist.
+    async fn listen_unix(&self, path: &Path) -> IoResult<Self::UnixListener>;
+}
+
+/// Trait for a local Unix domain socket that accepts incoming streams.
+///
+/// These objects are returned by instances of [`UnixProvider`].  They work
+/// like [`TcpListener`], except that the streams they yield have no
+/// meaningful peer address.
+#[cfg(unix)]
+#[async_trait]
+pub trait UnixListener {
+    /// The type of connections returned by [`Self::accept()`].
+    type UnixStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static;
+
+    /// The type of [`stream::Stream`] returned by [`Self::incoming()`].
+    type Incoming: stream::Stream<Item = IoResult<Self::UnixStream>> + Unpin;
+
+    /// Wait for an incoming stream, and return it.
+    async fn accept(&self) -> IoResult<Self::UnixStream>;
+
+    /// Wrap this listener into a new [`stream::Stream`] that yields
+    /// incoming streams.
+    fn incoming(self) -> Self::Incoming;
+}
+
 /// An object with a peer certificate: typically a TLS connection.
 pub trait CertifiedConn {
     /// Try to return the (DER-encoded) peer certificate for this
@@ -221,10 +296,13 @@ pub trait TlsConnector<S> {
     /// Start a TLS session over the provided TCP stream `stream`.
     ///
     /// Declare `sni_hostname` as the desired hostname, but don't actually check
-    /// whether the hostname in the certificate matches it.  The connector may
-    /// send `sni_hostname` as part of its handshake, if it supports
-    /// [SNI](https://en.wikipedia.org/wiki/Server_Name_Indication) or one of
-    /// the TLS 1.3 equivalents.
+    /// whether the hostname in the certificate matches it.
+    ///
+    /// Tor clients don't send
+    /// [SNI](https://en.wikipedia.org/wiki/Server_Name_Indication), so the
+    /// connectors in this crate never send `sni_hostname` as part of their
+    /// handshake.  (Some TLS libraries still need a syntactically valid
+    /// hostname here.)
     async fn negotiate_unvalidated(&self, stream: S, sni_hostname: &str) -> IoResult<Self::Conn>;
 }
 
@@ -247,4 +325,15 @@ pub trait TlsProvider<S> {
 
     /// Return a TLS connector for use with this runtime.
     fn tls_connector(&self) -> Self::Connector;
+
+    /// Return a warning about how this provider's TLS handshakes may stand
+    /// out from those of other Tor clients, if we know of any way.
+    ///
+    /// Tor clients try to send a uniform TLS ClientHello, so that they are
+    /// harder to pick out of a crowd.  A provider whose TLS library doesn't
+    /// let it control some part of its ClientHello should say so here, so
+    /// that the caller can warn the user.
+    fn fingerprint_warning(&self) -> Option<&'static str> {
+        None
+    }
 }
@@ -17,7 +17,9 @@
 //!
 //! Currently, we support mocking the passage of time (via
 //! [`MockSleepRuntime`]), and impersonating the internet (via
-//! [`MockNetRuntime`]).
+//! [`MockNetRuntime`]).  For tests that shouldn't depend on a real
+//! runtime at all, [`MockRuntime`] simulates both of those, and runs
+//! its tasks on a deterministic single-threaded executor.
 //!
 //! # Examples
 //!
@@ -142,7 +144,9 @@ pub mod net;
 pub mod time;
 
 mod net_runtime;
+mod runtime;
 mod sleep_runtime;
 
 pub use net_runtime::MockNetRuntime;
+pub use runtime::MockRuntime;
 pub use sleep_runtime::MockSleepRuntime;
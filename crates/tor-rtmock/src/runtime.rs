@@ -0,0 +1,337 @@
+//! Declare MockRuntime.
+
+use crate::io::LocalStream;
+use crate::net::{MockNetProvider, MockNetwork};
+use crate::time::MockSleepProvider;
+use tor_rtcompat::{BlockOn, SleepProvider, TcpProvider, TlsProvider};
+
+use async_trait::async_trait;
+use futures::task::{waker, ArcWake, FutureObj, Spawn, SpawnError};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::trace;
+
+/// A completely simulated Runtime, for testing.
+///
+/// Unlike [`MockSleepRuntime`](crate::MockSleepRuntime) and
+/// [`MockNetRuntime`](crate::MockNetRuntime), this type doesn't wrap a
+/// real runtime: it uses a [`MockSleepProvider`] for time, a
+/// [`MockNetProvider`] for the network, and its own single-threaded
+/// executor for tasks.
+///
+/// The executor is deterministic: it runs tasks one at a time, in the
+/// order in which they were woken.  When no task can make progress, it
+/// advances the simulated time to the next pending timeout.  Thus, code
+/// that sleeps for an hour will finish immediately, and (given the same
+/// inputs) will always see the same interleaving of tasks.
+///
+/// Tasks only run while some future is running under
+/// [`block_on`](BlockOn::block_on).
+///
+/// This is *not* for production use.
+#[derive(Clone)]
+pub struct MockRuntime {
+    /// A MockSleepProvider.  Time-related calls get delegated here.
+    sleep: MockSleepProvider,
+    /// A MockNetProvider.  Network-related calls get delegated here.
+    net: MockNetProvider,
+    /// The state of our executor.
+    executor: Arc<Mutex<Executor>>,
+}
+
+/// The ID that we use for the future passed to `block_on`.
+const MAIN_TASK: usize = 0;
+
+/// The shared state for a [`MockRuntime`]'s executor.
+struct Executor {
+    /// The ID to give the next task that we spawn.
+    next_id: usize,
+    /// All the tasks that have been spawned and not yet finished.
+    ///
+    /// A task's entry is `None` while the task is being polled.
+    tasks: HashMap<usize, Option<FutureObj<'static, ()>>>,
+    /// The IDs of the tasks that have been woken, in the order that we
+    /// should poll them.
+    ready: VecDeque<usize>,
+}
+
+impl Executor {
+    /// Mark the task with ID `id` as ready to be polled.
+    fn wake(&mut self, id: usize) {
+        if !self.ready.contains(&id) {
+            self.ready.push_back(id);
+        }
+    }
+}
+
+/// A Waker for a single task in a [`MockRuntime`].
+struct TaskWaker {
+    /// The ID of the task to wake.
+    id: usize,
+    /// The executor to tell about it.
+    executor: Weak<Mutex<Executor>>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if let Some(executor) = arc_self.executor.upgrade() {
+            let mut executor = executor.lock().expect("Poisoned lock for executor");
+            executor.wake(arc_self.id);
+        }
+    }
+}
+
+impl MockRuntime {
+    /// Create a new runtime that uses `net` for its view of the network,
+    /// and a new [`MockSleepProvider`] for its view of time.
+    pub fn new(net: MockNetProvider) -> Self {
+        let executor = Executor {
+            next_id: MAIN_TASK + 1,
+            tasks: HashMap::new(),
+            ready: VecDeque::new(),
+        };
+        MockRuntime {
+            sleep: MockSleepProvider::new(SystemTime::now()),
+            net,
+            executor: Arc::new(Mutex::new(executor)),
+        }
+    }
+
+    /// Return a reference to the [`MockSleepProvider`]
+    pub fn mock_sleep(&self) -> &MockSleepProvider {
+        &self.sleep
+    }
+
+    /// Return a reference to the [`MockNetProvider`]
+    pub fn mock_net(&self) -> &MockNetProvider {
+        &self.net
+    }
+
+    /// See [`MockSleepProvider::advance()`]
+    pub async fn advance(&self, dur: Duration) {
+        self.sleep.advance(dur).await;
+    }
+    /// See [`MockSleepProvider::jump_to()`]
+    pub fn jump_to(&self, new_wallclock: SystemTime) {
+        self.sleep.jump_to(new_wallclock);
+    }
+
+    /// Return a Waker that will mark the task with ID `id` as ready.
+    fn waker_for(&self, id: usize) -> std::task::Waker {
+        waker(Arc::new(TaskWaker {
+            id,
+            executor: Arc::downgrade(&self.executor),
+        }))
+    }
+
+    /// Poll the spawned task with ID `id`, if it still exists.
+    fn poll_task(&self, id: usize) {
+        let task = {
+            let mut executor = self.executor.lock().expect("Poisoned lock for executor");
+            executor.tasks.get_mut(&id).and_then(Option::take)
+        };
+        let mut task = match task {
+            Some(t) => t,
+            None => return,
+        };
+        let waker = self.waker_for(id);
+        let mut cx = Context::from_waker(&waker);
+        let finished = Pin::new(&mut task).poll(&mut cx).is_ready();
+
+        let mut executor = self.executor.lock().expect("Poisoned lock for executor");
+        if finished {
+            trace!("task {} finished", id);
+            executor.tasks.remove(&id);
+        } else {
+            executor.tasks.insert(id, Some(task));
+        }
+    }
+}
+
+impl Default for MockRuntime {
+    fn default() -> Self {
+        MockRuntime::new(MockNetwork::new().builder().provider())
+    }
+}
+
+impl Spawn for MockRuntime {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        let mut executor = self.executor.lock().expect("Poisoned lock for executor");
+        let id = executor.next_id;
+        executor.next_id += 1;
+        executor.tasks.insert(id, Some(future));
+        executor.wake(id);
+        Ok(())
+    }
+}
+
+impl BlockOn for MockRuntime {
+    /// Run `future` to completion, along with any tasks that have been
+    /// spawned on this runtime.
+    ///
+    /// Whenever nothing can make progress, advance the simulated time to
+    /// the next pending timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if nothing can make progress and there are no pending
+    /// timeouts, since in that case `future` can never finish.
+    ///
+    /// This function must not be called from inside a task running on
+    /// the same runtime.
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        futures::pin_mut!(future);
+        let main_waker = self.waker_for(MAIN_TASK);
+        let mut main_cx = Context::from_waker(&main_waker);
+        self.executor
+            .lock()
+            .expect("Poisoned lock for executor")
+            .wake(MAIN_TASK);
+
+        loop {
+            let next = self
+                .executor
+                .lock()
+                .expect("Poisoned lock for executor")
+                .ready
+                .pop_front();
+            match next {
+                Some(MAIN_TASK) => {
+                    if let Poll::Ready(output) = future.as_mut().poll(&mut main_cx) {
+                        return output;
+                    }
+                }
+                Some(id) => self.poll_task(id),
+                None => match self.sleep.time_until_next_timeout() {
+                    Some(duration) => {
+                        trace!("All tasks stalled; advancing by {:?}", duration);
+                        self.sleep.advance_noyield(duration);
+                    }
+                    None => panic!("MockRuntime::block_on: no task can make progress"),
+                },
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TcpProvider for MockRuntime {
+    type TcpStream = <MockNetProvider as TcpProvider>::TcpStream;
+    type TcpListener = <MockNetProvider as TcpProvider>::TcpListener;
+
+    async fn connect(&self, addr: &SocketAddr) -> IoResult<Self::TcpStream> {
+        self.net.connect(addr).await
+    }
+    async fn listen(&self, addr: &SocketAddr) -> IoResult<Self::TcpListener> {
+        self.net.listen(addr).await
+    }
+}
+
+impl TlsProvider<LocalStream> for MockRuntime {
+    type Connector = <MockNetProvider as TlsProvider<LocalStream>>::Connector;
+    type TlsStream = <MockNetProvider as TlsProvider<LocalStream>>::TlsStream;
+    fn tls_connector(&self) -> Self::Connector {
+        self.net.tls_connector()
+    }
+}
+
+impl SleepProvider for MockRuntime {
+    type SleepFuture = crate::time::Sleeping;
+    fn sleep(&self, dur: Duration) -> Self::SleepFuture {
+        self.sleep.sleep(dur)
+    }
+    fn now(&self) -> Instant {
+        self.sleep.now()
+    }
+    fn wallclock(&self) -> SystemTime {
+        self.sleep.wallclock()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::task::SpawnExt;
+    use tor_rtcompat::{SleepProviderExt, TcpListener};
+
+    #[test]
+    fn sleeping_is_instant() {
+        let rt = MockRuntime::default();
+        let start = rt.now();
+        let wallclock = rt.wallclock();
+        let elapsed = rt.block_on(async {
+            rt.sleep(Duration::from_secs(3600)).await;
+            rt.now() - start
+        });
+        assert_eq!(elapsed, Duration::from_secs(3600));
+        assert_eq!(rt.wallclock(), wallclock + elapsed);
+
+        // A timeout fires only when nothing else can make progress.
+        let r = rt.block_on(rt.timeout(Duration::from_secs(10), futures::future::pending::<()>()));
+        assert!(r.is_err());
+        assert_eq!(rt.now() - start, Duration::from_secs(3610));
+    }
+
+    #[test]
+    fn deterministic_order() {
+        let rt = MockRuntime::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for (n, secs) in [(1, 30), (2, 10), (3, 20), (4, 40)] {
+            let rt2 = rt.clone();
+            let log = Arc::clone(&log);
+            rt.spawn(async move {
+                log.lock().unwrap().push(n);
+                rt2.sleep(Duration::from_secs(secs)).await;
+                log.lock().unwrap().push(n * 10);
+            })
+            .unwrap();
+        }
+        rt.block_on(rt.sleep(Duration::from_secs(60)));
+        assert_eq!(&log.lock().unwrap()[..], &[1, 2, 3, 4, 20, 30, 10, 40]);
+    }
+
+    #[test]
+    fn spawn_and_network() {
+        let rt = MockRuntime::new(
+            MockNetwork::new()
+                .builder()
+                .add_address("192.0.2.55".parse().unwrap())
+                .provider(),
+        );
+        let addr: SocketAddr = "192.0.2.55:9999".parse().unwrap();
+
+        let output = rt.block_on(async {
+            let listener = rt.listen(&addr).await.unwrap();
+            let (snd, rcv) = oneshot::channel();
+            rt.spawn(async move {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut output = Vec::new();
+                conn.read_to_end(&mut output).await.unwrap();
+                snd.send(output).unwrap();
+            })
+            .unwrap();
+
+            let mut conn = rt.connect(&addr).await.unwrap();
+            conn.write_all(b"Hello world").await.unwrap();
+            conn.close().await.unwrap();
+            rcv.await.unwrap()
+        });
+        assert_eq!(&output[..], b"Hello world");
+    }
+
+    #[test]
+    #[should_panic]
+    fn deadlock() {
+        let rt = MockRuntime::default();
+        rt.block_on(futures::future::pending::<()>());
+    }
+}
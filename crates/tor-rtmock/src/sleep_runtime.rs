@@ -111,6 +111,9 @@ impl<R: Runtime> TlsProvider<R::TcpStream> for MockSleepRuntime<R> {
     fn tls_connector(&self) -> Self::Connector {
         self.runtime.tls_connector()
     }
+    fn fingerprint_warning(&self) -> Option<&'static str> {
+        self.runtime.fingerprint_warning()
+    }
 }
 
 impl<R: Runtime> SleepProvider for MockSleepRuntime<R> {
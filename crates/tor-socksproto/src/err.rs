@@ -25,6 +25,26 @@ pub enum Error {
     #[error("Unrecognized SOCKS protocol version {0}")]
     BadProtocol(u8),
 
+    /// The SOCKS client sent a SOCKS5 request with a nonzero value in its
+    /// reserved byte.
+    ///
+    /// We only reject these when configured to be strict about it.
+    #[error("Nonzero reserved byte {0} in SOCKS5 request")]
+    BadRsv(u8),
+
+    /// The SOCKS client proposed an authentication method that we don't
+    /// recognize.
+    ///
+    /// We only reject these when configured to be strict about it:
+    /// otherwise, we just pick a method that we do support.
+    #[error("Unrecognized SOCKS5 authentication method {0}")]
+    UnrecognizedAuthMethod(u8),
+
+    /// The SOCKS client sent a message that was longer than we allow, or
+    /// that didn't end before our size limit.
+    #[error("SOCKS request too long ({0} bytes without a complete message)")]
+    RequestTooLong(usize),
+
     /// The SOCKS client tried to use a SOCKS feature that we don't
     /// support at all.
     #[error("SOCKS feature not implemented")]
@@ -48,12 +68,17 @@ impl HasKind for Error {
         use Error as E;
         use ErrorKind as EK;
         match self {
-            E::Decode(tor_bytes::Error::Truncated) => {
+            E::Decode(e) if matches!(e.without_position(), tor_bytes::Error::Truncated) => {
                 // This variant should always get converted before a user can
                 // see it.
                 EK::Internal
             }
-            E::Syntax | E::Decode(_) | E::BadProtocol(_) => EK::LocalProtocolViolation,
+            E::Syntax
+            | E::Decode(_)
+            | E::BadProtocol(_)
+            | E::BadRsv(_)
+            | E::UnrecognizedAuthMethod(_)
+            | E::RequestTooLong(_) => EK::LocalProtocolViolation,
             E::NotImplemented => EK::NotImplemented,
             E::AlreadyFinished(e) => e.kind(),
             E::Bug(e) => e.kind(),
@@ -26,6 +26,93 @@ pub struct SocksHandshake {
     socks5_auth: Option<SocksAuth>,
     /// Completed SOCKS handshake.
     handshake: Option<SocksRequest>,
+    /// If the client sent a complete request that we refused, the reply
+    /// that tells it so.
+    failure_reply: Option<Vec<u8>>,
+    /// How strictly should we check the client's messages?
+    validation: SocksValidation,
+}
+
+/// The longest message that we'll accept from a SOCKS client, when we're
+/// enforcing length limits.
+///
+/// No well-formed SOCKS5 message is this long.  SOCKS4 messages are
+/// NUL-terminated, and so could be arbitrarily long without a limit.
+const MAX_REQUEST_LEN: usize = 1024;
+
+/// Settings to control how strictly a [`SocksHandshake`] checks the
+/// messages that a client sends.
+///
+/// Some SOCKS clients are sloppy about parts of the protocol.  Rejecting
+/// their messages breaks real applications, but accepting them can hide
+/// bugs.  By default, we ignore nonzero reserved bytes and unrecognized
+/// authentication methods, but we enforce length limits.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct SocksValidation {
+    /// If true, reject SOCKS5 requests whose reserved byte is nonzero.
+    reject_nonzero_rsv: bool,
+    /// If true, reject SOCKS5 clients that propose any authentication
+    /// method that we don't recognize, even alongside ones that we support.
+    reject_unknown_auth_methods: bool,
+    /// If true, reject any message longer than [`MAX_REQUEST_LEN`].
+    enforce_length_limit: bool,
+}
+
+impl SocksValidation {
+    /// Return a SocksValidation that rejects everything questionable.
+    pub fn strict() -> Self {
+        SocksValidation {
+            reject_nonzero_rsv: true,
+            reject_unknown_auth_methods: true,
+            enforce_length_limit: true,
+        }
+    }
+
+    /// Return a SocksValidation that accepts anything that we can make
+    /// sense of.
+    pub fn lenient() -> Self {
+        SocksValidation {
+            reject_nonzero_rsv: false,
+            reject_unknown_auth_methods: false,
+            enforce_length_limit: false,
+        }
+    }
+
+    /// Set whether to reject SOCKS5 requests with a nonzero reserved byte.
+    ///
+    /// If we reject them, the handshake fails with [`Error::BadRsv`].
+    pub fn reject_nonzero_rsv(mut self, reject: bool) -> Self {
+        self.reject_nonzero_rsv = reject;
+        self
+    }
+
+    /// Set whether to reject SOCKS5 clients that propose authentication
+    /// methods we don't recognize.
+    ///
+    /// If we reject them, the handshake fails with
+    /// [`Error::UnrecognizedAuthMethod`].
+    pub fn reject_unknown_auth_methods(mut self, reject: bool) -> Self {
+        self.reject_unknown_auth_methods = reject;
+        self
+    }
+
+    /// Set whether to reject over-long messages.
+    ///
+    /// If we reject them, the handshake fails with [`Error::RequestTooLong`].
+    pub fn enforce_length_limit(mut self, enforce: bool) -> Self {
+        self.enforce_length_limit = enforce;
+        self
+    }
+}
+
+impl Default for SocksValidation {
+    fn default() -> Self {
+        SocksValidation {
+            reject_nonzero_rsv: false,
+            reject_unknown_auth_methods: false,
+            enforce_length_limit: true,
+        }
+    }
 }
 
 /// Possible state for a Socks connection.
@@ -65,10 +152,18 @@ pub struct Action {
 impl SocksHandshake {
     /// Construct a new SocksHandshake in its initial state
     pub fn new() -> Self {
+        Self::with_validation(SocksValidation::default())
+    }
+
+    /// Construct a new SocksHandshake in its initial state, checking the
+    /// client's messages as described in `validation`.
+    pub fn with_validation(validation: SocksValidation) -> Self {
         SocksHandshake {
             state: State::Initial,
             socks5_auth: None,
             handshake: None,
+            failure_reply: None,
+            validation,
         }
     }
 
@@ -99,7 +194,16 @@ impl SocksHandshake {
             (_, _) => Err(Error::Syntax),
         };
         match rv {
-            Err(Error::Decode(tor_bytes::Error::Truncated)) => Err(Truncated::new()),
+            Err(Error::Decode(e))
+                if matches!(e.without_position(), tor_bytes::Error::Truncated) =>
+            {
+                if self.validation.enforce_length_limit && input.len() >= MAX_REQUEST_LEN {
+                    self.state = State::Failed;
+                    Ok(Err(Error::RequestTooLong(input.len())))
+                } else {
+                    Err(Truncated::new())
+                }
+            }
             Err(e) => {
                 self.state = State::Failed;
                 Ok(Err(e))
@@ -137,7 +241,7 @@ impl SocksHandshake {
             SocksAddr::Ip(ip4.into())
         };
 
-        let request = SocksRequest::new(version, cmd, addr, port, auth)?;
+        let request = self.new_request(version, cmd, addr, port, auth)?;
 
         self.state = State::Done;
         self.handshake = Some(request);
@@ -166,6 +270,15 @@ impl SocksHandshake {
         let nmethods = r.take_u8()?;
         let methods = r.take(nmethods as usize)?;
 
+        if self.validation.reject_unknown_auth_methods {
+            if let Some(m) = methods
+                .iter()
+                .find(|m| ![USERNAME_PASSWORD, NO_AUTHENTICATION].contains(m))
+            {
+                return Err(Error::UnrecognizedAuthMethod(*m));
+            }
+        }
+
         // Prefer username/password, then none.
         let (next, reply) = if methods.contains(&USERNAME_PASSWORD) {
             (State::Socks5Username, [5, USERNAME_PASSWORD])
@@ -219,16 +332,20 @@ impl SocksHandshake {
             );
         }
         let cmd = r.take_u8()?.into();
-        let _ignore = r.take_u8()?;
+        let rsv = r.take_u8()?;
         let addr = r.extract()?;
         let port = r.take_u16()?;
 
+        if rsv != 0 && self.validation.reject_nonzero_rsv {
+            return Err(Error::BadRsv(rsv));
+        }
+
         let auth = self
             .socks5_auth
             .take()
             .ok_or_else(|| internal!("called s5 without negotiating auth"))?;
 
-        let request = SocksRequest::new(version, cmd, addr, port, auth)?;
+        let request = self.new_request(version, cmd, addr, port, auth)?;
 
         self.state = State::Done;
         self.handshake = Some(request);
@@ -240,11 +357,47 @@ impl SocksHandshake {
         })
     }
 
+    /// Build a [`SocksRequest`] from a complete client request.
+    ///
+    /// If we refuse the request, remember a reply that tells the client
+    /// so, in the client's own version of the protocol.
+    fn new_request(
+        &mut self,
+        version: SocksVersion,
+        cmd: SocksCmd,
+        addr: SocksAddr,
+        port: u16,
+        auth: SocksAuth,
+    ) -> Result<SocksRequest> {
+        SocksRequest::new(version, cmd, addr, port, auth).map_err(|e| {
+            let status = match e {
+                Error::NotImplemented => SocksStatus::COMMAND_NOT_SUPPORTED,
+                _ => SocksStatus::GENERAL_FAILURE,
+            };
+            self.failure_reply = Some(refusal(version, status));
+            e
+        })
+    }
+
     /// Return true if this handshake is finished.
     pub fn finished(&self) -> bool {
         self.state == State::Done
     }
 
+    /// If this handshake failed after the client sent a complete request,
+    /// return the reply that we should send to tell the client so.
+    ///
+    /// Clients expect an answer even to requests that we refuse (for
+    /// example, because they ask for an unsupported command like BIND).
+    /// The reply uses the client's own SOCKS version: for SOCKS4 and
+    /// SOCKS4a, it is the 8-byte "request rejected" reply.
+    ///
+    /// Return None if the handshake hasn't failed, or if it failed before
+    /// the client got as far as sending a request.
+    pub fn failure_reply(&self) -> Option<&[u8]> {
+        self.failure_reply.as_deref()
+    }
+
     /// Consume this handshake's state; if it finished successfully,
     /// return a SocksRequest.
     pub fn into_request(self) -> Option<SocksRequest> {
@@ -252,6 +405,31 @@ impl SocksHandshake {
     }
 }
 
+/// Encode a reply refusing a request in SOCKS version `version`, with the
+/// status `status`.
+///
+/// (We can't use [`SocksRequest::reply`] here, since we don't have a valid
+/// request.)
+fn refusal(version: SocksVersion, status: SocksStatus) -> Vec<u8> {
+    let mut w = Vec::new();
+    match version {
+        SocksVersion::V4 => {
+            w.write_u8(0);
+            w.write_u8(status.into_socks4_status());
+            w.write_u16(0);
+            w.write_u32(0);
+        }
+        SocksVersion::V5 => {
+            w.write_u8(5);
+            w.write_u8(status.into());
+            w.write_u8(0); // reserved.
+            w.write(&SocksAddr::Ip(std::net::Ipv4Addr::UNSPECIFIED.into()));
+            w.write_u16(0);
+        }
+    }
+    w
+}
+
 impl Default for SocksHandshake {
     fn default() -> Self {
         Self::new()
@@ -414,6 +592,87 @@ mod test {
         );
     }
 
+    #[test]
+    fn socks4_refused() {
+        // BIND isn't supported: we refuse it with a SOCKS4 reply.
+        let mut h = SocksHandshake::new();
+        assert!(h.failure_reply().is_none());
+        let r = h.handshake(&hex!("04 02 0050 CB007107 00")[..]);
+        assert!(matches!(r, Ok(Err(Error::NotImplemented))));
+        assert!(!h.finished());
+        assert_eq!(h.failure_reply().unwrap(), hex!("00 5B 0000 00000000"));
+
+        // Neither is a CONNECT to port 0, even with SOCKS4a.
+        let mut h = SocksHandshake::new();
+        let r = h.handshake(&hex!("04 01 0000 00000001 00 6578616d706c652e636f6d00")[..]);
+        assert!(matches!(r, Ok(Err(Error::Syntax))));
+        assert_eq!(h.failure_reply().unwrap(), hex!("00 5B 0000 00000000"));
+
+        // A SOCKS5 client gets a SOCKS5 reply.
+        let mut h = SocksHandshake::new();
+        let _a = h.handshake(&hex!("05 01 00")).unwrap().unwrap();
+        let r = h.handshake(&hex!("05 02 00 01 7f000007 1f90"));
+        assert!(matches!(r, Ok(Err(Error::NotImplemented))));
+        assert_eq!(
+            h.failure_reply().unwrap(),
+            hex!("05 07 00 01 00000000 0000")
+        );
+
+        // If the client never sent a request, we have nothing to say.
+        let mut h = SocksHandshake::new();
+        let r = h.handshake(&hex!("06 01 00"));
+        assert!(r.unwrap().is_err());
+        assert!(h.failure_reply().is_none());
+    }
+
+    #[test]
+    fn socks4_truncated() {
+        let requests: [&[u8]; 3] = [
+            &hex!("04 01 0050 CB007107 00"),
+            &hex!("04 01 0050 CB007107 73776f72646669736800"),
+            &hex!("04 01 01BB 00000001 73776f72646669736800 7777772e6578616d706c652e636f6d00"),
+        ];
+        for req in requests.iter() {
+            // Every proper prefix of a request is just truncated.
+            for n in 1..req.len() {
+                let mut h = SocksHandshake::new();
+                assert!(h.handshake(&req[..n]).is_err());
+                assert!(!h.finished());
+            }
+            // And the whole thing is fine, without reading past its end.
+            let mut h = SocksHandshake::new();
+            let a = h.handshake(req).unwrap().unwrap();
+            assert_eq!(a.drain, req.len());
+        }
+    }
+
+    #[test]
+    fn socks4_junk() {
+        // Feed lots of junk that starts like a SOCKS4 request through the
+        // handshake, and make sure that it never panics or claims to have
+        // used more input than it got.
+        let mut x: u32 = 0x5ca1_ab1e;
+        for len in 1..64 {
+            for _ in 0..64 {
+                let mut msg = vec![4_u8];
+                for _ in 1..len {
+                    // xorshift32
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    // Make NULs common, so that some usernames end.
+                    let b = (x & 0xff) as u8;
+                    msg.push(if b < 0x30 { 0 } else { b });
+                }
+                let mut h = SocksHandshake::new();
+                if let Ok(Ok(a)) = h.handshake(&msg) {
+                    assert!(a.finished);
+                    assert!(a.drain <= msg.len());
+                }
+            }
+        }
+    }
+
     #[test]
     fn socks5_init_noauth() {
         let mut h = SocksHandshake::new();
@@ -562,6 +821,106 @@ mod test {
         assert!(r.unwrap().is_err());
     }
 
+    #[test]
+    fn validation_rsv() {
+        let request = hex!("05 01 07 01 7f000007 1f90");
+
+        // By default, we ignore the reserved byte.
+        for validation in [SocksValidation::default(), SocksValidation::lenient()] {
+            let mut h = SocksHandshake::with_validation(validation);
+            let _a = h.handshake(&hex!("05 01 00")).unwrap().unwrap();
+            let a = h.handshake(&request).unwrap().unwrap();
+            assert!(a.finished);
+            assert_eq!(h.into_request().unwrap().port(), 8080);
+        }
+
+        // When strict, we reject it.
+        for validation in [
+            SocksValidation::strict(),
+            SocksValidation::default().reject_nonzero_rsv(true),
+        ] {
+            let mut h = SocksHandshake::with_validation(validation);
+            let _a = h.handshake(&hex!("05 01 00")).unwrap().unwrap();
+            let r = h.handshake(&request);
+            assert!(matches!(r, Ok(Err(Error::BadRsv(7)))));
+            assert!(!h.finished());
+        }
+
+        // A zero reserved byte is fine even when strict.
+        let mut h = SocksHandshake::with_validation(SocksValidation::strict());
+        let _a = h.handshake(&hex!("05 01 00")).unwrap().unwrap();
+        let a = h
+            .handshake(&hex!("05 01 00 01 7f000007 1f90"))
+            .unwrap()
+            .unwrap();
+        assert!(a.finished);
+    }
+
+    #[test]
+    fn validation_auth_methods() {
+        let init = hex!("05 03 00 02 99");
+
+        for validation in [SocksValidation::default(), SocksValidation::lenient()] {
+            let mut h = SocksHandshake::with_validation(validation);
+            let a = h.handshake(&init).unwrap().unwrap();
+            assert_eq!(a.reply, &[5, 2]);
+            assert_eq!(h.state, State::Socks5Username);
+        }
+
+        for validation in [
+            SocksValidation::strict(),
+            SocksValidation::default().reject_unknown_auth_methods(true),
+        ] {
+            let mut h = SocksHandshake::with_validation(validation);
+            let r = h.handshake(&init);
+            assert!(matches!(r, Ok(Err(Error::UnrecognizedAuthMethod(0x99)))));
+
+            // Only methods we know are fine.
+            let mut h = SocksHandshake::with_validation(validation);
+            let a = h.handshake(&hex!("05 02 00 02")).unwrap().unwrap();
+            assert_eq!(a.reply, &[5, 2]);
+        }
+    }
+
+    #[test]
+    fn validation_length() {
+        // A SOCKS4 request with a username that never ends.
+        let mut request = hex!("04 01 0050 CB007107").to_vec();
+        request.resize(MAX_REQUEST_LEN - 1, b'x');
+
+        for validation in [
+            SocksValidation::default(),
+            SocksValidation::strict(),
+            SocksValidation::lenient(),
+        ] {
+            let mut h = SocksHandshake::with_validation(validation);
+            assert!(h.handshake(&request).is_err()); // truncated.
+        }
+
+        request.push(b'x');
+        for validation in [SocksValidation::default(), SocksValidation::strict()] {
+            let mut h = SocksHandshake::with_validation(validation);
+            let r = h.handshake(&request);
+            assert!(matches!(
+                r,
+                Ok(Err(Error::RequestTooLong(n))) if n == MAX_REQUEST_LEN
+            ));
+        }
+
+        let lenient = SocksValidation::default().enforce_length_limit(false);
+        for validation in [lenient, SocksValidation::lenient()] {
+            let mut h = SocksHandshake::with_validation(validation);
+            assert!(h.handshake(&request).is_err()); // still just truncated.
+
+            // And if the request does end, we accept it.
+            let mut request = request.clone();
+            request.push(0);
+            let a = h.handshake(&request).unwrap().unwrap();
+            assert!(a.finished);
+            assert_eq!(a.drain, MAX_REQUEST_LEN + 1);
+        }
+    }
+
     #[test]
     fn fused_result() {
         let good_socks4a = &hex!("04 01 0050 CB007107 00")[..];
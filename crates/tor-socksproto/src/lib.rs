@@ -91,7 +91,7 @@ mod handshake;
 mod msg;
 
 pub use err::Error;
-pub use handshake::{Action, SocksHandshake};
+pub use handshake::{Action, SocksHandshake, SocksValidation};
 pub use msg::{SocksAddr, SocksAuth, SocksCmd, SocksRequest, SocksStatus, SocksVersion};
 pub use tor_error::Truncated;
 